@@ -0,0 +1,65 @@
+//! Cipher throughput benchmarks
+//!
+//! Run with `cargo bench --bench crypto`.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use rustun::crypto::{Block, CryptoConfig, new_block};
+use std::hint::black_box;
+
+/// Payload sizes: tiny control frame, MTU-sized packet, maximum frame
+const SIZES: [usize; 3] = [64, 1400, 64 * 1024];
+
+fn ciphers() -> Vec<(&'static str, Box<dyn Block>)> {
+    vec![
+        (
+            "aes256",
+            new_block(&CryptoConfig::Aes256("bench".to_string())),
+        ),
+        (
+            "chacha20",
+            new_block(&CryptoConfig::ChaCha20Poly1305("bench".to_string())),
+        ),
+        ("xor", new_block(&CryptoConfig::Xor("bench".to_string()))),
+        ("plain", new_block(&CryptoConfig::Plain)),
+    ]
+}
+
+fn bench_encrypt(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encrypt");
+    for (name, block) in ciphers() {
+        for size in SIZES {
+            let payload = vec![0x5a; size];
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(BenchmarkId::new(name, size), &payload, |b, payload| {
+                b.iter(|| {
+                    let mut data = payload.clone();
+                    block.encrypt(&mut data).unwrap();
+                    black_box(data)
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_decrypt(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decrypt");
+    for (name, block) in ciphers() {
+        for size in SIZES {
+            let mut encrypted = vec![0x5a; size];
+            block.encrypt(&mut encrypted).unwrap();
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(BenchmarkId::new(name, size), &encrypted, |b, encrypted| {
+                b.iter(|| {
+                    let mut data = encrypted.clone();
+                    block.decrypt(&mut data).unwrap();
+                    black_box(data)
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encrypt, bench_decrypt);
+criterion_main!(benches);
@@ -9,7 +9,7 @@
 //! cargo run --example stun_discover -- --port 51258
 //! ```
 
-use rustun::client::p2p::stun::{NatType, StunClient};
+use rustun::client::p2p::stun::{NatType, StunClient, StunSocket};
 use std::time::Duration;
 
 #[derive(clap::Parser, Debug)]
@@ -68,7 +68,14 @@ async fn main() {
 
     // Perform discovery
     println!("⏳ Discovering public address...");
-    match stun_client.discover(args.port).await {
+    let socket = match StunSocket::bind(args.port) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("❌ Failed to bind UDP port {}: {e}", args.port);
+            std::process::exit(1);
+        }
+    };
+    match stun_client.discover(&socket).await {
         Ok(result) => {
             println!("✅ STUN Discovery Successful!\n");
 
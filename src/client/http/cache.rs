@@ -1,8 +1,14 @@
 //! Status cache management
 
-use super::models::StatusResponse;
+use super::models::{PeerEventInfo, StatusResponse};
+use crate::client::p2p::PeerEvent;
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Peer path transitions kept for `/peer-events`
+const MAX_PEER_EVENTS: usize = 100;
 
 /// Global status cache (shared between HTTP server and event loop)
 static STATUS_CACHE: once_cell::sync::Lazy<Arc<RwLock<Option<StatusResponse>>>> =
@@ -24,3 +30,25 @@ pub fn get() -> Option<StatusResponse> {
     let cache = STATUS_CACHE.read().unwrap();
     cache.clone()
 }
+
+/// Most recent peer path transitions, oldest first
+static PEER_EVENTS: once_cell::sync::Lazy<RwLock<VecDeque<PeerEventInfo>>> =
+    once_cell::sync::Lazy::new(|| RwLock::new(VecDeque::with_capacity(MAX_PEER_EVENTS)));
+
+/// Record a peer path transition (called from event loop)
+pub fn record_event(event: PeerEvent) {
+    let at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut events = PEER_EVENTS.write().unwrap();
+    if events.len() == MAX_PEER_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(PeerEventInfo { at, event });
+}
+
+/// Get the recorded peer path transitions, oldest first
+pub fn events() -> Vec<PeerEventInfo> {
+    PEER_EVENTS.read().unwrap().iter().cloned().collect()
+}
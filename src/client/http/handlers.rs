@@ -1,7 +1,7 @@
 //! HTTP request handlers
 
-use super::cache::get_cache;
-use super::models::StatusResponse;
+use super::cache::{self, get_cache};
+use super::models::{PeerEventInfo, StatusResponse};
 use axum::{extract::State, http::StatusCode, response::Json};
 use serde_json;
 
@@ -35,3 +35,8 @@ pub async fn status(State(state): State<AppState>) -> Result<Json<StatusResponse
         None => Err(StatusCode::SERVICE_UNAVAILABLE),
     }
 }
+
+/// Peer path transitions endpoint handler, oldest first
+pub async fn peer_events() -> Json<Vec<PeerEventInfo>> {
+    Json(cache::events())
+}
@@ -1,5 +1,6 @@
 //! HTTP API response models
 
+use crate::client::p2p::{PeerEvent, Protocol};
 use serde::Serialize;
 
 /// Complete status response structure
@@ -10,6 +11,7 @@ pub struct StatusResponse {
     pub relay: RelayStatusInfo,
     pub p2p: P2PStatus,
     pub cluster_peers: Vec<ClusterPeerInfo>,
+    pub route_health: Vec<RouteHealthInfo>,
 }
 
 /// Self/client information
@@ -35,15 +37,26 @@ pub struct TrafficStats {
     /// Send bytes (inbound traffic to device)
     pub send_bytes: u64,
     pub send_bytes_mb: f64,
+    /// Packets from peers dropped for failing IP validation
+    pub invalid_packets: u64,
+    /// Packets dropped because the TUN device fell behind
+    pub dropped_to_device: u64,
+    /// Packets dropped over the download limit
+    pub dropped_over_limit: u64,
 }
 
 /// Relay connection status
 #[derive(Serialize, Debug, Clone)]
 pub struct RelayStatusInfo {
+    /// Whether the relay session is up
+    pub connected: bool,
     pub rx_frames: u64,
     pub rx_errors: u64,
     pub tx_frames: u64,
     pub tx_errors: u64,
+    pub tx_dropped: u64,
+    /// Frames received while the inbound queue was nearly full
+    pub rx_near_full: u64,
 }
 
 /// P2P connection status
@@ -60,6 +73,12 @@ pub struct P2PPeerInfo {
     pub identity: String,
     pub ipv6: Option<IPv6ConnectionInfo>,
     pub stun: Option<STUNConnectionInfo>,
+    /// Path data frames take, `None` while they go through the relay
+    pub path: Option<Protocol>,
+    /// Frames queued to the peer, not sent yet
+    pub queue_depth: usize,
+    /// Frames to the peer dropped because its queue was full
+    pub queue_dropped: u64,
 }
 
 /// IPv6 direct connection information
@@ -85,10 +104,29 @@ pub struct ClusterPeerInfo {
     pub identity: String,
     pub private_ip: String,
     pub ciders: Vec<String>,
-    pub ipv6: Option<String>,
+    pub ipv6: Vec<String>,
     pub ipv6_port: Option<u16>,
     pub stun_ip: Option<String>,
     pub stun_port: Option<u16>,
     pub last_active: u64,
     pub status: String, // "online", "warning", "inactive", "offline"
 }
+
+/// Peer path transition
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerEventInfo {
+    /// Unix time of the transition in seconds
+    pub at: u64,
+    #[serde(flatten)]
+    pub event: PeerEvent,
+}
+
+/// Reachability of a peer CIDR (empty unless route probing is enabled)
+#[derive(Serialize, Debug, Clone)]
+pub struct RouteHealthInfo {
+    pub cidr: String,
+    /// Address probed on behalf of the CIDR
+    pub target: String,
+    pub reachable: bool,
+    pub missed_probes: u32,
+}
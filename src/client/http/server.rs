@@ -1,6 +1,6 @@
 //! HTTP server setup and management
 
-use super::handlers::{AppState, health, status};
+use super::handlers::{AppState, health, peer_events, status};
 use axum::{Router, routing::get};
 
 /// Start the HTTP server
@@ -10,6 +10,7 @@ pub async fn start(port: u16) -> Result<(), Box<dyn std::error::Error + Send + S
     let app = Router::new()
         .route("/status", get(status))
         .route("/health", get(health))
+        .route("/peer-events", get(peer_events))
         .with_state(app_state);
 
     let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{port}")).await?;
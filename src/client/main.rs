@@ -1,18 +1,86 @@
-use crate::client::http::server;
+use crate::client::http::{cache, server};
 use crate::client::p2p::peer::{NewPeersTx, PeerHandler, PeerHandlerApi, SendFrame, SendFrameTx};
-use crate::client::p2p::stun::StunClient;
+use crate::client::p2p::stun::{StunClient, StunProvider, StunRefresh, StunSocket};
+use crate::client::p2p::{UdpListen, check_local_addr};
+use crate::client::ping::run_ping;
+use crate::client::preflight::{HostBackend, preflight};
+use crate::client::presence::PeerPresence;
 use crate::client::prettylog::{get_status, log_startup_banner};
-use crate::client::relay::{RelayHandler, new_relay_handler};
-use crate::client::{Args, P2P_HOLE_PUNCH_PORT, P2P_UDP_PORT};
-use crate::codec::frame::{DataFrame, Frame, HandshakeReplyFrame};
+use crate::client::relay::{RelayHandler, RelayOutboundTx, SendStatus, new_relay_handler};
+use crate::client::reorder::{FlowSequencer, ReorderBuffer};
+use crate::client::route_health::RouteHealth;
+use crate::client::{Args, P2P_HOLE_PUNCH_PORT, P2P_UDP_PORT, STUN_REFRESH_INTERVAL};
+use crate::codec::frame::{
+    DataBatchFrame, DataFrame, Frame, HandshakeReplyFrame, MAX_BATCH_RECORD_LEN, PeerDetail,
+    TunnelMode,
+};
 use crate::crypto::{self, Block};
-use crate::utils::device::DeviceHandler;
+use crate::network::tap::FrameTap;
+use crate::utils::device::{DeviceHandler, DeviceStatus};
+use crate::utils::shaper::Shaper;
+use crate::utils::sys_route::SysRoute;
 use crate::utils::{self, StunAddr};
+use anyhow::Context;
 use clap::Parser;
-use std::sync::Arc;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
-use tokio::sync::mpsc;
-use tokio::time::interval;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, interval, timeout_at};
+
+/// How often the relay connection is checked with `--fail-closed`
+const RELAY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Limits for coalescing relay-bound TUN packets into `DataBatch` frames
+#[derive(Debug, Clone, Copy)]
+struct BatchConfig {
+    /// Most packets in one batch
+    max_packets: usize,
+    /// Longest a packet waits for the batch to fill
+    max_delay: Duration,
+}
+
+/// How tunneled packets are sent and received
+#[derive(Debug, Clone, Copy)]
+struct PacketOptions {
+    /// Copy the packet's DSCP to the outer P2P UDP packet
+    preserve_dscp: bool,
+    /// Longest an out-of-order data frame waits for the ones before it,
+    /// sent frames are numbered for peers doing the same (disabled if not
+    /// set)
+    reorder_window: Option<Duration>,
+    /// Megabits per second sent into the tunnel (unlimited if not set)
+    max_upload_mbps: Option<f64>,
+}
+
+/// Device task state shared by the packets it sends
+struct PacketSender {
+    preserve_dscp: bool,
+    /// Numbers data frames per flow, with reordering enabled
+    sequencer: Option<FlowSequencer>,
+    /// Holds packets back to the upload limit, relay and P2P alike
+    upload: Option<Shaper>,
+}
+
+impl PacketSender {
+    fn new(options: PacketOptions) -> Self {
+        Self {
+            preserve_dscp: options.preserve_dscp,
+            sequencer: options.reorder_window.map(|_| FlowSequencer::new()),
+            upload: options.max_upload_mbps.map(Shaper::new),
+        }
+    }
+
+    /// Give `frame` the next number of its flow, unless it has one
+    fn number(&mut self, frame: &mut DataFrame) {
+        if frame.seq.is_none() {
+            frame.seq = self
+                .sequencer
+                .as_mut()
+                .and_then(|sequencer| sequencer.next(&frame.payload));
+        }
+    }
+}
 
 pub async fn run_client() -> anyhow::Result<()> {
     let args = Args::parse();
@@ -22,6 +90,35 @@ pub async fn run_client() -> anyhow::Result<()> {
     }
 
     log_startup_banner(&args);
+    let stun_client = stun_client(&args);
+    run_client_with(args, Arc::new(stun_client)).await
+}
+
+/// STUN client for the `--stun-server` list, the defaults if none is given
+fn stun_client(args: &Args) -> StunClient {
+    if args.stun_servers.is_empty() {
+        StunClient::new()
+    } else {
+        StunClient::with_servers(args.stun_servers.clone())
+    }
+}
+
+/// Run the client with `stun_provider` as the source of its public address
+///
+/// Tests inject a fixed provider so the P2P setup path runs without
+/// network access.
+pub async fn run_client_with(
+    args: Args,
+    stun_provider: Arc<dyn StunProvider>,
+) -> anyhow::Result<()> {
+    // `--ping` never creates the device
+    if args.preflight || args.ping.is_none() {
+        preflight(&HostBackend, !args.route_dry_run)?;
+    }
+    if args.preflight {
+        println!("✅ Preflight passed: TUN device and routes can be set up");
+        return Ok(());
+    }
 
     // parse crypto configuration
     let crypto_config = match crypto::parse_crypto_config(&args.crypto) {
@@ -33,39 +130,82 @@ pub async fn run_client() -> anyhow::Result<()> {
     let block = crypto::new_block(&crypto_config);
     let crypto_block: Arc<Box<dyn Block>> = Arc::new(block);
 
-    let ipv6 = utils::get_ipv6().await;
-    let stun_result = StunClient::new().discover(P2P_HOLE_PUNCH_PORT).await;
-    let stun = match stun_result {
-        Ok(result) => Some(StunAddr {
-            ip: result.public_ip.to_string(),
-            port: result.public_port,
-        }),
-        Err(_) => None,
+    let tap = match &args.capture {
+        Some(path) => {
+            tracing::info!("Capturing frames to {}", path.display());
+            Some(FrameTap::open(path)?)
+        }
+        None => None,
     };
 
-    // create relay handler
-    let (mut relay_handler, device_config) =
-        match new_relay_handler(&args, crypto_block.clone(), ipv6, P2P_UDP_PORT, stun).await {
-            Ok(result) => result,
-            Err(e) => {
-                anyhow::bail!("Failed to setup client: {e}");
-            }
-        };
+    if let Some(ip) = args.bind_source {
+        check_local_addr(ip).context("invalid bind source")?;
+    }
+    let bind_addrs = [
+        args.p2p_bind_v4.map(IpAddr::V4),
+        args.p2p_bind_v6.map(IpAddr::V6),
+    ];
+    for ip in bind_addrs.into_iter().flatten() {
+        check_local_addr(ip).context("invalid P2P bind address")?;
+    }
+    // refused up front rather than found out once the relay is gone
+    if args.fail_closed && !SysRoute::supports_blackhole() {
+        anyhow::bail!("--fail-closed needs blackhole routes, not supported on this platform");
+    }
 
-    let p2p_handler = if args.enable_p2p {
-        tracing::info!("P2P mode enabled");
+    // STUN discovery maps the very socket hole punching uses
+    let stun_socket = match args.p2p_bind.ipv4() {
+        true => StunSocket::bind_addr(
+            args.p2p_source_v4().unwrap_or(Ipv4Addr::UNSPECIFIED),
+            P2P_HOLE_PUNCH_PORT,
+        )
+        .inspect_err(|e| tracing::warn!("P2P IPv4 UDP bind failed, no STUN mapping: {e}"))
+        .ok(),
+        false => None,
+    };
 
-        let handler = PeerHandler::start_peer_service(
-            crypto_block.clone(),
-            args.identity.clone(),
-            device_config.peer_details.clone(),
-        );
-        Some(handler)
-    } else {
-        tracing::info!("P2P mode disabled, using relay only");
-        None
+    // create relay handler
+    let (mut relay_handler, device_config) = match connect_relay(
+        &args,
+        crypto_block.clone(),
+        stun_provider.clone(),
+        stun_socket.clone(),
+        tap.clone(),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(e) => {
+            anyhow::bail!("Failed to setup client: {e}");
+        }
     };
 
+    // peers share the cluster key, the handshake key may be shared by all tenants
+    let p2p = start_p2p(
+        &args,
+        relay_handler.data_block(),
+        &relay_handler,
+        &device_config.peer_details,
+        stun_socket,
+        tap,
+    )
+    .await;
+
+    if let Some(target) = &args.ping {
+        let p2p_handler = match p2p {
+            P2pSetup::Running(api) => Some(api),
+            _ => None,
+        };
+        return run_ping(
+            &mut relay_handler,
+            p2p_handler,
+            &device_config.peer_details,
+            target,
+            Duration::from_secs(args.ping_timeout),
+        )
+        .await;
+    }
+
     // Check iptables availability if masq is enabled (Linux only)
     #[cfg(target_os = "linux")]
     {
@@ -82,12 +222,17 @@ pub async fn run_client() -> anyhow::Result<()> {
     #[cfg(not(target_os = "linux"))]
     let enable_masq = false;
 
-    let mut dev = match init_device(&device_config, enable_masq).await {
+    // relay and STUN traffic must never be routed into the tunnel
+    let mut protected_hosts = resolve_hosts(std::slice::from_ref(&args.server)).await;
+    protected_hosts.extend(resolve_hosts(stun_provider.servers()).await);
+
+    let mut dev = match init_device(&device_config, enable_masq, &args, protected_hosts).await {
         Ok(d) => d,
         Err(e) => {
             anyhow::bail!("Failed to initialize device: {e}");
         }
     };
+    dev.set_ip_validation(args.ip_validation);
 
     // Start HTTP server if port is specified
     if let Some(http_port) = args.http_port {
@@ -98,17 +243,250 @@ pub async fn run_client() -> anyhow::Result<()> {
         });
     }
 
+    // Probe peer CIDRs if enabled
+    let route_health = match args.route_probe_interval {
+        Some(secs) => {
+            let mut health = RouteHealth::new(
+                &device_config.private_ip,
+                dev.tun_index(),
+                args.route_probe_threshold,
+                Box::new(SysRoute::new().with_dry_run(args.route_dry_run)),
+            )?;
+            health.set_routes(&device_config.peer_details);
+            Some((health, Duration::from_secs(secs.max(1))))
+        }
+        None => None,
+    };
+
     // Run main event loop
-    run_event_loop(&mut relay_handler, p2p_handler, &mut dev).await;
-    Ok(())
+    let presence = PeerPresence::new(&device_config.peer_details)
+        .with_relay_only(args.relay_only_cidrs.clone());
+    let batch = args
+        .batch_size
+        .filter(|&max_packets| max_packets > 1)
+        .map(|max_packets| BatchConfig {
+            max_packets,
+            max_delay: Duration::from_millis(args.batch_delay_ms),
+        });
+    let packets = PacketOptions {
+        preserve_dscp: args.preserve_dscp,
+        reorder_window: args.reorder_window.map(Duration::from_millis),
+        max_upload_mbps: args.max_upload_mbps,
+    };
+    let result = tokio::select! {
+        result = run_event_loop(
+            &mut relay_handler,
+            p2p,
+            &mut dev,
+            presence,
+            batch,
+            route_health,
+            packets,
+        ) => result,
+        _ = shutdown_signal() => {
+            tracing::info!("shutting down");
+            Ok(())
+        }
+    };
+    relay_handler.close().await;
+    dev.lift_fail_closed();
+    dev.lift_dns_kill_switch();
+    result
+}
+
+/// Wait for Ctrl-C, or SIGTERM from a service manager
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                tracing::warn!("SIGTERM not handled: {e}");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Discover our addresses and handshake with the relay server
+///
+/// The initial STUN discovery of `stun_socket` is advertised in the
+/// handshake; with P2P enabled it is refreshed periodically so hole
+/// punching recovers once a failed discovery succeeds again.
+async fn connect_relay(
+    args: &Args,
+    block: Arc<Box<dyn Block>>,
+    stun_provider: Arc<dyn StunProvider>,
+    stun_socket: Option<StunSocket>,
+    tap: Option<FrameTap>,
+) -> anyhow::Result<(RelayHandler, HandshakeReplyFrame)> {
+    // only advertise addresses of the sockets P2P binds
+    let ipv6 = match (args.p2p_bind.ipv6(), args.p2p_source_v6()) {
+        (true, Some(ipv6)) => Some(ipv6),
+        (true, None) => utils::get_ipv6().await,
+        (false, _) => None,
+    };
+    let stun = match &stun_socket {
+        Some(socket) => stun_provider
+            .discover(socket)
+            .await
+            .ok()
+            .map(|result| result.stun_addr()),
+        None => None,
+    };
+    let stun_refresh = stun_socket
+        .filter(|_| args.enable_p2p)
+        .map(|socket| StunRefresh::new(stun_provider, socket, STUN_REFRESH_INTERVAL));
+
+    new_relay_handler(args, block, ipv6, P2P_UDP_PORT, stun, stun_refresh, tap).await
+}
+
+/// P2P waiting for an address peers can reach
+///
+/// Without a public IPv6 address or a STUN mapping every probe and direct
+/// send fails, so the peer service only starts once one of them shows up.
+struct DeferredP2p {
+    block: Arc<Box<dyn Block>>,
+    magic: u32,
+    identity: String,
+    peers: Vec<PeerDetail>,
+    /// Kept current by the relay's `StunRefresh`
+    stun: Arc<RwLock<Option<StunAddr>>>,
+    tap: Option<FrameTap>,
+    max_active_peers: Option<usize>,
+    listen: UdpListen,
+}
+
+impl DeferredP2p {
+    /// Whether peers could reach us directly, given our public IPv6 address
+    fn has_address(&self, ipv6: Option<Ipv6Addr>) -> bool {
+        (self.listen.mode.ipv6() && ipv6.is_some())
+            || (self.listen.mode.ipv4()
+                && self
+                    .stun
+                    .read()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .is_some())
+    }
+
+    fn start(self) -> PeerHandlerApi {
+        PeerHandler::start_peer_service(
+            self.block,
+            self.magic,
+            self.identity,
+            self.peers,
+            self.stun,
+            self.tap,
+            self.max_active_peers,
+            self.listen,
+        )
+    }
+}
+
+/// State of the P2P service
+enum P2pSetup {
+    Running(PeerHandlerApi),
+    /// Enabled, waiting for an address peers can reach
+    Deferred(DeferredP2p),
+    Disabled,
+}
+
+/// Start the P2P service if enabled and we have a reachable address
+async fn start_p2p(
+    args: &Args,
+    block: Arc<Box<dyn Block>>,
+    relay: &RelayHandler,
+    peers: &[PeerDetail],
+    stun_socket: Option<StunSocket>,
+    tap: Option<FrameTap>,
+) -> P2pSetup {
+    if !args.enable_p2p {
+        tracing::info!("P2P mode disabled, using relay only");
+        return P2pSetup::Disabled;
+    }
+    // peers are picked by destination IP, TAP frames only go through the relay
+    if args.tunnel_mode == TunnelMode::Tap {
+        tracing::info!("TAP mode, using relay only");
+        return P2pSetup::Disabled;
+    }
+
+    let p2p = DeferredP2p {
+        block,
+        magic: relay.magic(),
+        identity: args.identity.clone(),
+        peers: peers.to_vec(),
+        stun: relay.stun(),
+        tap,
+        max_active_peers: args.max_active_peers,
+        listen: UdpListen::new(args.p2p_bind)
+            .with_stun_socket(stun_socket)
+            .with_local_addrs(args.p2p_source_v4(), args.p2p_source_v6()),
+    };
+    let ipv6 = relay
+        .get_self_info()
+        .await
+        .and_then(|info| info.ipv6.parse().ok());
+    if p2p.has_address(ipv6) {
+        tracing::info!("P2P mode enabled");
+        P2pSetup::Running(p2p.start())
+    } else {
+        tracing::warn!(
+            "No public IPv6 address and STUN discovery failed, using relay only \
+             until P2P gets an address"
+        );
+        P2pSetup::Deferred(p2p)
+    }
+}
+
+/// Resolve "host:port" addresses to their IPs, skipping failures
+async fn resolve_hosts(addrs: &[String]) -> Vec<IpAddr> {
+    let mut ips = Vec::new();
+    for addr in addrs {
+        match tokio::net::lookup_host(addr.as_str()).await {
+            Ok(resolved) => {
+                for ip in resolved.map(|addr| addr.ip()) {
+                    if !ips.contains(&ip) {
+                        ips.push(ip);
+                    }
+                }
+            }
+            Err(e) => tracing::warn!("Failed to resolve {addr}: {e}"),
+        }
+    }
+    ips
 }
 
 async fn init_device(
     device_config: &HandshakeReplyFrame,
     enable_masq: bool,
+    args: &Args,
+    protected_hosts: Vec<IpAddr>,
 ) -> anyhow::Result<DeviceHandler> {
     tracing::info!("Initializing device with config: {device_config:?}");
     let mut dev = DeviceHandler::new();
+    dev.set_full_tunnel(args.full_tunnel);
+    dev.set_route_dry_run(args.route_dry_run);
+    dev.set_protected_hosts(protected_hosts);
+    dev.set_queue(args.device_queue_size, args.device_drop_policy);
+    dev.set_tunnel_mode(args.tunnel_mode);
+    dev.set_iface_name(args.iface_name.clone());
+    dev.set_download_limit(args.max_download_mbps);
+    #[cfg(target_os = "linux")]
+    dev.set_tun_queues(args.tun_queues.into());
+    dev.set_vpn_dns(args.vpn_dns);
+    dev.set_dns_kill_switch(args.dns_kill_switch.then(|| args.dns_allow.clone()));
+    dev.set_fail_closed(
+        args.fail_closed
+            .then(|| Duration::from_secs(args.fail_closed_grace)),
+    );
     let tun_index = dev.run(device_config, enable_masq).await?;
 
     // Log TUN index (Windows only)
@@ -132,54 +510,120 @@ async fn init_device(
 
 async fn run_event_loop(
     client_handler: &mut RelayHandler,
-    p2p_handler: Option<PeerHandlerApi>,
+    p2p: P2pSetup,
     dev: &mut DeviceHandler,
-) {
+    presence: PeerPresence,
+    batch: Option<BatchConfig>,
+    route_health: Option<(RouteHealth, Duration)>,
+    packets: PacketOptions,
+) -> anyhow::Result<()> {
+    let (running, mut deferred_p2p) = match p2p {
+        P2pSetup::Running(p2p) => (Some(p2p), None),
+        P2pSetup::Deferred(p2p) => (None, Some(p2p)),
+        P2pSetup::Disabled => (None, None),
+    };
     let (
-        p2p_handler_new_peers,
+        mut p2p_handler_new_peers,
         mut p2p_handler_recv_frame,
-        p2p_handler_get_status,
-        p2p_handler_send_frame,
-    ) = match p2p_handler {
+        mut p2p_handler_get_status,
+        mut p2p_handler_send_frame,
+        mut p2p_shutdown,
+        mut p2p_events,
+    ) = match running {
         Some(p) => (
             Some(p.new_peers),
             Some(p.new_frame),
             Some(p.get_status),
             Some(p.send_frame),
+            Some(p.shutdown),
+            Some(p.events),
         ),
-        None => (None, None, None, None),
+        None => (None, None, None, None, None, None),
     };
     let mut refresh_ticker = interval(Duration::from_secs(30));
     let relay_outbound = match client_handler.get_outbound_tx() {
         Some(tx) => tx,
-        None => return,
+        None => return Ok(()),
+    };
+    // route probes and echo replies, the device task owns `relay_outbound`
+    let control_outbound = relay_outbound.clone();
+    let (mut route_health, mut probe_ticker) = match route_health {
+        Some((health, period)) => (Some(health), Some(interval(period))),
+        None => (None, None),
     };
+    let mut relay_check_ticker = dev.fails_closed().then(|| interval(RELAY_CHECK_INTERVAL));
 
     let mut dev_inbound = match dev.get_dev_inbound() {
         Some(dev) => dev,
-        None => return,
+        None => return Ok(()),
     };
 
+    // hands the device task the P2P sender once a deferred service starts
+    let (late_p2p_tx, mut late_p2p_rx) = oneshot::channel::<SendFrameTx>();
+    let mut late_p2p_tx = Some(late_p2p_tx);
+    let device_presence = presence.clone();
+    let mut sender = PacketSender::new(packets);
+    let mut reorder = packets.reorder_window.map(ReorderBuffer::new);
     tokio::spawn(async move {
-        loop {
-            let packet = dev_inbound.recv().await;
-            if let Some(packet) = packet {
-                handle_device_packet(
-                    relay_outbound.clone(),
-                    p2p_handler_send_frame.as_ref(),
-                    packet,
-                )
-                .await;
+        while let Some(packet) = dev_inbound.recv().await {
+            if p2p_handler_send_frame.is_none()
+                && let Ok(tx) = late_p2p_rx.try_recv()
+            {
+                p2p_handler_send_frame = Some(tx);
+            }
+            let p2p = p2p_handler_send_frame.as_ref();
+            match batch {
+                Some(batch) => {
+                    coalesce_device_packets(
+                        &relay_outbound,
+                        p2p,
+                        &device_presence,
+                        &mut dev_inbound,
+                        batch,
+                        packet,
+                        &mut sender,
+                    )
+                    .await
+                }
+                None => {
+                    handle_device_packet(
+                        &relay_outbound,
+                        p2p,
+                        &device_presence,
+                        packet,
+                        &mut sender,
+                    )
+                    .await
+                }
             }
         }
     });
 
     loop {
+        let reorder_deadline = reorder.as_ref().and_then(ReorderBuffer::next_deadline);
         tokio::select! {
             // Server -> TUN device or route update
             frame = client_handler.recv_frame() => {
-                if let Ok(frame) = frame {
-                    handle_relay_frame(frame, p2p_handler_new_peers.as_ref(), dev).await;
+                match frame {
+                    Ok(frame) => {
+                        handle_relay_frame(
+                            frame,
+                            &control_outbound,
+                            p2p_handler_new_peers.as_ref(),
+                            dev,
+                            &presence,
+                            route_health.as_mut(),
+                            reorder.as_mut(),
+                        )
+                        .await;
+                    }
+                    // the relay client gave up, exit rather than spin on the closed queue
+                    Err(e) => {
+                        if let Some(p2p) = p2p_shutdown.take() {
+                            p2p.shutdown().await;
+                        }
+                        anyhow::bail!("relay failed: {e}");
+                    }
                 }
             }
 
@@ -194,13 +638,83 @@ async fn run_event_loop(
                     continue;
                 };
                 tracing::debug!("P2P -> Device: {} bytes", data_frame.payload.len());
-                if let Err(e) = dev.send(data_frame.payload).await {
-                    tracing::error!("Failed to write to device: {e}");
+                deliver_data(dev, route_health.as_mut(), reorder.as_mut(), data_frame).await;
+            }
+
+            // Held data frames whose wait ran out (only if reordering enabled)
+            _ = async {
+                match reorder_deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                if let Some(reorder) = reorder.as_mut() {
+                    for packet in reorder.expire(std::time::Instant::now()) {
+                        write_device(dev, route_health.as_mut(), packet).await;
+                    }
+                }
+            }
+
+            // P2P path transitions -> HTTP status (only if P2P enabled)
+            Some(event) = async {
+                match p2p_events.as_mut() {
+                    Some(rx) => rx.0.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                cache::record_event(event);
+            }
+
+            // Probe peer CIDRs through the relay (only if route probing enabled)
+            _ = async {
+                match probe_ticker.as_mut() {
+                    Some(ticker) => ticker.tick().await,
+                    None => std::future::pending().await,
                 }
+            } => {
+                if let Some(health) = route_health.as_mut() {
+                    for packet in health.probe() {
+                        send_via_relay(&control_outbound, DataFrame { payload: packet, seq: None });
+                    }
+                }
+            }
+
+            // Block the peer CIDRs while the relay is gone (only if fail-closed)
+            _ = async {
+                match relay_check_ticker.as_mut() {
+                    Some(ticker) => ticker.tick().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                dev.update_relay_state(client_handler.is_connected(), std::time::Instant::now());
+            }
+
+            // TUN device lost, exit so the service manager can restart us
+            Some(status) = dev.recv_status() => {
+                let DeviceStatus::Fatal(e) = status;
+                if let Some(p2p) = p2p_shutdown.take() {
+                    p2p.shutdown().await;
+                }
+                anyhow::bail!("TUN device failed: {e}");
             }
 
             // refresh config and status
             _ = refresh_ticker.tick() => {
+                if deferred_p2p.is_some() {
+                    let ipv6 = utils::get_ipv6().await;
+                    if let Some(p2p) = deferred_p2p.take_if(|p2p| p2p.has_address(ipv6)) {
+                        tracing::info!("P2P address found, P2P mode enabled");
+                        let p2p = p2p.start();
+                        p2p_handler_new_peers = Some(p2p.new_peers);
+                        p2p_handler_recv_frame = Some(p2p.new_frame);
+                        p2p_handler_get_status = Some(p2p.get_status);
+                        p2p_shutdown = Some(p2p.shutdown);
+                        p2p_events = Some(p2p.events);
+                        if let Some(tx) = late_p2p_tx.take() {
+                            let _ = tx.send(p2p.send_frame);
+                        }
+                    }
+                }
                 let peer_status = match p2p_handler_get_status.as_ref() {
                     None => None,
                     Some(p) => match p.get().await {
@@ -211,7 +725,11 @@ async fn run_event_loop(
                         }
                     },
                 };
-                get_status(client_handler, peer_status.as_deref(), dev).await;
+                let routes = route_health
+                    .as_ref()
+                    .map(RouteHealth::snapshot)
+                    .unwrap_or_default();
+                get_status(client_handler, peer_status.as_deref(), dev, routes).await;
             }
         }
     }
@@ -219,26 +737,116 @@ async fn run_event_loop(
 
 /// Handle outbound packet from TUN device: try P2P first if available, then fallback to relay.
 async fn handle_device_packet(
-    relay_outbound: mpsc::Sender<Frame>,
+    relay_outbound: &RelayOutboundTx,
     p2p_handler: Option<&SendFrameTx>,
+    presence: &PeerPresence,
     packet: Vec<u8>,
+    sender: &mut PacketSender,
+) {
+    if let Some(mut frame) = route_device_packet(p2p_handler, presence, packet, sender).await {
+        sender.number(&mut frame);
+        send_via_relay(relay_outbound, frame);
+    }
+}
+
+/// Handle a burst of outbound TUN packets, batching the relay-bound ones
+///
+/// Starting from `first`, keeps taking queued packets until the batch holds
+/// `max_packets` or `max_delay` has passed, then sends one `DataBatch` frame.
+/// Batched packets are not numbered for reordering.
+async fn coalesce_device_packets(
+    relay_outbound: &RelayOutboundTx,
+    p2p_handler: Option<&SendFrameTx>,
+    presence: &PeerPresence,
+    dev_inbound: &mut mpsc::Receiver<Vec<u8>>,
+    batch: BatchConfig,
+    first: Vec<u8>,
+    sender: &mut PacketSender,
 ) {
-    let data_frame = DataFrame {
-        payload: packet.clone(),
+    let deadline = Instant::now() + batch.max_delay;
+    let mut frame = DataBatchFrame::default();
+    let mut next = Some(first);
+    while let Some(packet) = next.take() {
+        match route_device_packet(p2p_handler, presence, packet, sender).await {
+            // numbered for P2P, it must keep its number
+            Some(data) if data.seq.is_some() => send_via_relay(relay_outbound, data),
+            Some(data) if data.payload.len() > MAX_BATCH_RECORD_LEN => {
+                send_via_relay(relay_outbound, data)
+            }
+            Some(data) => {
+                if !frame.fits(&data.payload, relay_outbound.max_packet_len()) {
+                    send_batch_via_relay(relay_outbound, std::mem::take(&mut frame));
+                }
+                frame.packets.push(data.payload);
+            }
+            None => {}
+        }
+        if frame.packets.len() >= batch.max_packets {
+            break;
+        }
+        next = timeout_at(deadline, dev_inbound.recv())
+            .await
+            .ok()
+            .flatten();
+    }
+    send_batch_via_relay(relay_outbound, frame);
+}
+
+/// Try to send a TUN packet over P2P
+///
+/// Peers the server reports offline and relay-only destinations skip P2P
+/// and go straight to relay.
+/// With `preserve_dscp` the packet's DSCP is copied to the outer UDP packet.
+/// A packet tried over P2P is numbered first when reordering is enabled,
+/// and keeps its number on the way back. With an upload limit the packet
+/// waits for its share of the rate first.
+///
+/// # Returns
+/// - `Some(frame)` - Frame must go through the relay
+/// - `None` - Packet was handed to P2P
+async fn route_device_packet(
+    p2p_handler: Option<&SendFrameTx>,
+    presence: &PeerPresence,
+    packet: Vec<u8>,
+    sender: &mut PacketSender,
+) -> Option<DataFrame> {
+    if let Some(upload) = sender.upload.as_mut() {
+        upload.wait(packet.len()).await;
+    }
+    let mut data_frame = DataFrame {
+        payload: packet,
+        seq: None,
     };
 
     // Try P2P first if available
-    if let Some(tx) = &p2p_handler {
+    if let Some(tx) = &p2p_handler
+        && !data_frame.invalid()
+    {
         let dst = data_frame.dst();
+        if presence.is_offline(&dst) {
+            tracing::debug!("peer for {dst} is offline, skip P2P");
+            return Some(data_frame);
+        }
+        if presence.is_relay_only(&dst) {
+            tracing::debug!("{dst} is relay only, skip P2P");
+            return Some(data_frame);
+        }
+        sender.number(&mut data_frame);
+        let tos = if sender.preserve_dscp {
+            data_frame.outer_tos()
+        } else {
+            0
+        };
         let frame = SendFrame {
             frame: Frame::Data(data_frame.clone()),
             dst,
+            tos,
         };
 
         match tx.0.send(frame).await {
             Ok(_) => {
-                tracing::debug!("Device -> P2P: {} bytes", packet.len());
-                return;
+                tracing::debug!("Device -> P2P: {} bytes", data_frame.payload.len());
+                return None;
             }
             Err(e) => {
                 tracing::debug!("P2P send failed: {e}, fallback to relay");
@@ -247,23 +855,125 @@ async fn handle_device_packet(
     }
 
     // Fallback to relay (or direct if no P2P)
-    let frame = Frame::Data(DataFrame { payload: packet });
-    if let Err(e) = RelayHandler::send_frame(relay_outbound, frame).await {
-        tracing::error!("Failed to send via relay: {e}");
+    Some(data_frame)
+}
+
+fn send_via_relay(relay_outbound: &RelayOutboundTx, frame: DataFrame) {
+    log_relay_send(RelayHandler::send_frame(relay_outbound, Frame::Data(frame)));
+}
+
+/// Report a relay send that did not go straight to a live session
+fn log_relay_send(result: anyhow::Result<SendStatus>) {
+    match result {
+        Ok(SendStatus::Sent) => {}
+        Ok(SendStatus::Queued) => tracing::debug!("relay down, frame queued for reconnect"),
+        // counted in tx_dropped and shown in the relay status
+        Ok(SendStatus::Dropped) => tracing::debug!("relay outbound queue full, frame dropped"),
+        Err(e) => tracing::error!("Failed to send via relay: {e}"),
+    }
+}
+
+/// Send batched packets, as a plain data frame when there is only one
+fn send_batch_via_relay(relay_outbound: &RelayOutboundTx, mut batch: DataBatchFrame) {
+    let frame = match batch.packets.len() {
+        0 => return,
+        1 => Frame::Data(DataFrame {
+            payload: batch.packets.remove(0),
+            seq: None,
+        }),
+        n => {
+            tracing::debug!("Device -> Relay: batch of {n} packets");
+            Frame::DataBatch(batch)
+        }
+    };
+    log_relay_send(RelayHandler::send_frame(relay_outbound, frame));
+}
+
+/// Write a tunneled packet to the device, unless it answers a route probe
+async fn write_device(
+    dev: &mut DeviceHandler,
+    route_health: Option<&mut RouteHealth>,
+    packet: Vec<u8>,
+) {
+    if let Some(health) = route_health
+        && health.handle_reply(&packet)
+    {
+        return;
+    }
+    if let Err(e) = dev.send(packet).await {
+        tracing::error!("Failed to write to device: {e}");
     }
 }
 
+/// Write a received data frame to the device, in flow order with reordering
+async fn deliver_data(
+    dev: &mut DeviceHandler,
+    mut route_health: Option<&mut RouteHealth>,
+    reorder: Option<&mut ReorderBuffer>,
+    frame: DataFrame,
+) {
+    let packets = match reorder {
+        Some(reorder) => reorder.push(frame.payload, frame.seq, std::time::Instant::now()),
+        None => vec![frame.payload],
+    };
+    for packet in packets {
+        write_device(dev, route_health.as_deref_mut(), packet).await;
+    }
+}
+
+/// Apply the server's view of the peers to routes and presence
+async fn sync_peers(
+    peers: Vec<PeerDetail>,
+    dev: &mut DeviceHandler,
+    presence: &PeerPresence,
+    route_health: Option<&mut RouteHealth>,
+) {
+    presence.update(&peers);
+    if let Some(health) = route_health {
+        health.set_routes(&peers);
+    }
+    dev.reload_route(peers).await;
+}
+
+/// Peer list with `peer` added or replaced after a `PeerJoin`
+fn peers_after_join(mut peers: Vec<PeerDetail>, peer: PeerDetail) -> Vec<PeerDetail> {
+    match peers.iter_mut().find(|p| p.identity == peer.identity) {
+        Some(existing) => *existing = peer,
+        None => peers.push(peer),
+    }
+    peers
+}
+
+/// Peer list with `identity` marked offline after a `PeerLeave`
+///
+/// The peer stays listed with its routes, as in the server's keepalive
+/// replies for configured clients that are not connected.
+fn peers_after_leave(mut peers: Vec<PeerDetail>, identity: &str) -> Vec<PeerDetail> {
+    for peer in peers.iter_mut().filter(|p| p.identity == identity) {
+        peer.last_active = 0;
+    }
+    peers
+}
+
 /// Handle frame received from relay server
 async fn handle_relay_frame(
     frame: Frame,
+    relay_outbound: &RelayOutboundTx,
     p2p_handler: Option<&NewPeersTx>,
     dev: &mut DeviceHandler,
+    presence: &PeerPresence,
+    mut route_health: Option<&mut RouteHealth>,
+    reorder: Option<&mut ReorderBuffer>,
 ) {
     match frame {
         Frame::Data(data_frame) => {
             tracing::debug!("Relay -> Device: {} bytes", data_frame.payload.len());
-            if let Err(e) = dev.send(data_frame.payload).await {
-                tracing::error!("Failed to write to device: {e}");
+            deliver_data(dev, route_health, reorder, data_frame).await;
+        }
+        Frame::DataBatch(batch) => {
+            tracing::debug!("Relay -> Device: batch of {} packets", batch.packets.len());
+            for payload in batch.packets {
+                write_device(dev, route_health.as_deref_mut(), payload).await;
             }
         }
         Frame::KeepAlive(keepalive) => {
@@ -273,13 +983,416 @@ async fn handle_relay_frame(
             );
 
             // Update routes in device handler
-            dev.reload_route(keepalive.peer_details.clone()).await;
+            sync_peers(keepalive.peer_details.clone(), dev, presence, route_health).await;
 
             // Update P2P peer information if P2P is enabled
             if let Some(tx) = p2p_handler {
                 let _ = tx.0.send(keepalive.peer_details).await;
             }
         }
+        Frame::PeerUpdate(update) => {
+            tracing::debug!("Received peer update for {}", update.peer.identity);
+
+            // Only addresses change here; routes follow the next keepalive
+            if let Some(tx) = p2p_handler {
+                let _ = tx.0.send(vec![update.peer]).await;
+            }
+        }
+        Frame::PeerJoin(join) => {
+            tracing::debug!("Peer {} joined", join.peer.identity);
+            let peers = peers_after_join(dev.get_peer_details(), join.peer.clone());
+            sync_peers(peers, dev, presence, route_health).await;
+            if let Some(tx) = p2p_handler {
+                let _ = tx.0.send(vec![join.peer]).await;
+            }
+        }
+        Frame::PeerLeave(leave) => {
+            tracing::debug!("Peer {} left", leave.identity);
+            // offline peers skip the P2P path, so nothing is sent to its
+            // stale addresses until it joins again
+            let peers = peers_after_leave(dev.get_peer_details(), &leave.identity);
+            sync_peers(peers, dev, presence, route_health).await;
+        }
+        Frame::Echo(echo) => {
+            tracing::debug!("Answering relayed echo {} from {}", echo.id, echo.src);
+            if let Err(e) = RelayHandler::send_frame(relay_outbound, Frame::EchoReply(echo.reply()))
+            {
+                tracing::warn!("Failed to answer echo: {e}");
+            }
+        }
         _ => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::frame::PeerDetail;
+    use std::sync::atomic::{AtomicBool, AtomicU64};
+
+    fn ipv4_packet(dst: [u8; 4]) -> Vec<u8> {
+        crate::codec::frame::ipv4_packet([10, 0, 0, 1], dst, 17, &[])
+    }
+
+    fn plain_sender() -> PacketSender {
+        PacketSender::new(PacketOptions {
+            preserve_dscp: false,
+            reorder_window: None,
+            max_upload_mbps: None,
+        })
+    }
+
+    fn peer(private_ip: &str, last_active: u64) -> PeerDetail {
+        PeerDetail {
+            name: private_ip.to_string(),
+            identity: private_ip.to_string(),
+            private_ip: private_ip.to_string(),
+            ciders: vec![],
+            ipv6: vec!["2001:db8::2".to_string()],
+            port: 51258,
+            stun_ip: String::new(),
+            stun_port: 0,
+            nat_type: 0,
+            last_active,
+        }
+    }
+
+    #[test]
+    fn test_peer_events_update_peer_list() {
+        let peers = vec![peer("10.0.0.2", 0), peer("10.0.0.3", 1_700_000_000)];
+
+        let peers = peers_after_join(peers, peer("10.0.0.2", 1_700_000_100));
+        let peers = peers_after_join(peers, peer("10.0.0.4", 1_700_000_200));
+        let active: Vec<_> = peers
+            .iter()
+            .map(|p| (p.identity.as_str(), p.last_active))
+            .collect();
+        assert_eq!(
+            active,
+            [
+                ("10.0.0.2", 1_700_000_100),
+                ("10.0.0.3", 1_700_000_000),
+                ("10.0.0.4", 1_700_000_200)
+            ]
+        );
+
+        let peers = peers_after_leave(peers, "10.0.0.3");
+        let presence = PeerPresence::new(&peers);
+        assert_eq!(peers.len(), 3);
+        assert!(presence.is_offline("10.0.0.3"));
+        assert!(!presence.is_offline("10.0.0.2"));
+    }
+
+    #[tokio::test]
+    async fn test_offline_peer_skips_p2p() {
+        let (relay_tx, mut relay_rx) = mpsc::channel(8);
+        let relay = RelayOutboundTx::new(
+            relay_tx,
+            Arc::new(AtomicU64::new(0)),
+            Arc::new(AtomicBool::new(true)),
+        );
+        let (p2p_tx, mut p2p_rx) = mpsc::channel(8);
+        let p2p = SendFrameTx(p2p_tx);
+        let presence = PeerPresence::new(&[peer("10.0.0.2", 0), peer("10.0.0.3", 1_700_000_000)]);
+
+        handle_device_packet(
+            &relay,
+            Some(&p2p),
+            &presence,
+            ipv4_packet([10, 0, 0, 2]),
+            &mut plain_sender(),
+        )
+        .await;
+        assert!(p2p_rx.try_recv().is_err());
+        assert!(matches!(relay_rx.try_recv(), Ok(Frame::Data(_))));
+
+        handle_device_packet(
+            &relay,
+            Some(&p2p),
+            &presence,
+            ipv4_packet([10, 0, 0, 3]),
+            &mut plain_sender(),
+        )
+        .await;
+        assert_eq!(p2p_rx.try_recv().unwrap().dst, "10.0.0.3");
+        assert!(relay_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relay_only_cidr_skips_p2p() {
+        let (relay_tx, mut relay_rx) = mpsc::channel(8);
+        let relay = RelayOutboundTx::new(
+            relay_tx,
+            Arc::new(AtomicU64::new(0)),
+            Arc::new(AtomicBool::new(true)),
+        );
+        let (p2p_tx, mut p2p_rx) = mpsc::channel(8);
+        let p2p = SendFrameTx(p2p_tx);
+        let presence = PeerPresence::new(&[peer("10.0.0.2", 1_700_000_000)])
+            .with_relay_only(vec!["10.0.0.0/30".parse().unwrap()]);
+
+        for (dst, via_p2p) in [([10, 0, 0, 2], false), ([10, 0, 0, 5], true)] {
+            handle_device_packet(
+                &relay,
+                Some(&p2p),
+                &presence,
+                ipv4_packet(dst),
+                &mut plain_sender(),
+            )
+            .await;
+            assert_eq!(p2p_rx.try_recv().is_ok(), via_p2p);
+            assert_eq!(relay_rx.try_recv().is_ok(), !via_p2p);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_device_packets_batches_relay_packets() {
+        let (relay_tx, mut relay_rx) = mpsc::channel(8);
+        let relay = RelayOutboundTx::new(
+            relay_tx,
+            Arc::new(AtomicU64::new(0)),
+            Arc::new(AtomicBool::new(true)),
+        );
+        let (dev_tx, mut dev_rx) = mpsc::channel(8);
+        for dst in [[10, 0, 0, 3], [10, 0, 0, 4]] {
+            dev_tx.send(ipv4_packet(dst)).await.unwrap();
+        }
+        let batch = BatchConfig {
+            max_packets: 3,
+            max_delay: Duration::from_millis(50),
+        };
+
+        coalesce_device_packets(
+            &relay,
+            None,
+            &PeerPresence::default(),
+            &mut dev_rx,
+            batch,
+            ipv4_packet([10, 0, 0, 2]),
+            &mut plain_sender(),
+        )
+        .await;
+
+        match relay_rx.try_recv().unwrap() {
+            Frame::DataBatch(batch) => {
+                let dsts: Vec<_> = batch.packets.iter().map(|p| p[19]).collect();
+                assert_eq!(dsts, vec![2, 3, 4]);
+            }
+            frame => panic!("unexpected frame {frame}"),
+        }
+        assert!(relay_rx.try_recv().is_err());
+
+        // a lone packet is flushed as a plain data frame after the delay
+        coalesce_device_packets(
+            &relay,
+            None,
+            &PeerPresence::default(),
+            &mut dev_rx,
+            batch,
+            ipv4_packet([10, 0, 0, 5]),
+            &mut plain_sender(),
+        )
+        .await;
+        assert!(matches!(relay_rx.try_recv(), Ok(Frame::Data(_))));
+    }
+
+    #[tokio::test]
+    async fn test_p2p_fallback_keeps_sequence_number() {
+        let (relay_tx, mut relay_rx) = mpsc::channel(8);
+        let relay = RelayOutboundTx::new(
+            relay_tx,
+            Arc::new(AtomicU64::new(0)),
+            Arc::new(AtomicBool::new(true)),
+        );
+        // P2P is gone, packets numbered for it fall back to the relay
+        let (p2p_tx, p2p_rx) = mpsc::channel(8);
+        drop(p2p_rx);
+        let p2p = SendFrameTx(p2p_tx);
+        let presence = PeerPresence::new(&[peer("10.0.0.2", 1_700_000_000)]);
+        let (dev_tx, mut dev_rx) = mpsc::channel(8);
+        dev_tx.send(ipv4_packet([10, 0, 0, 2])).await.unwrap();
+        let batch = BatchConfig {
+            max_packets: 3,
+            max_delay: Duration::from_millis(20),
+        };
+        let mut sender = PacketSender::new(PacketOptions {
+            preserve_dscp: false,
+            reorder_window: Some(Duration::from_millis(20)),
+            max_upload_mbps: None,
+        });
+
+        coalesce_device_packets(
+            &relay,
+            Some(&p2p),
+            &presence,
+            &mut dev_rx,
+            batch,
+            ipv4_packet([10, 0, 0, 2]),
+            &mut sender,
+        )
+        .await;
+        for seq in 0..2 {
+            match relay_rx.try_recv().unwrap() {
+                Frame::Data(frame) => assert_eq!(frame.seq, Some(seq)),
+                frame => panic!("unexpected frame {frame}"),
+            }
+        }
+        assert!(relay_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_p2p_deferred_without_ipv6_or_stun() {
+        use crate::crypto::plain::PlainBlock;
+
+        let args = Args::parse_from(["client", "-s", "127.0.0.1:8080", "-i", "a", "--enable-p2p"]);
+        let block: Arc<Box<dyn Block>> = Arc::new(Box::new(PlainBlock::new()));
+        // neither discovery succeeded: no IPv6 in the self info, no STUN mapping
+        let relay = RelayHandler::new(block.clone());
+        let peers = [peer("10.0.0.2", 1_700_000_000)];
+
+        let P2pSetup::Deferred(deferred) =
+            start_p2p(&args, block, &relay, &peers, None, None).await
+        else {
+            panic!("P2P must not start without an address");
+        };
+        assert!(!deferred.has_address(None));
+
+        // relay-only meanwhile
+        let (relay_tx, mut relay_rx) = mpsc::channel(8);
+        let relay_outbound = RelayOutboundTx::new(
+            relay_tx,
+            Arc::new(AtomicU64::new(0)),
+            Arc::new(AtomicBool::new(true)),
+        );
+        let presence = PeerPresence::new(&peers);
+        handle_device_packet(
+            &relay_outbound,
+            None,
+            &presence,
+            ipv4_packet([10, 0, 0, 2]),
+            &mut plain_sender(),
+        )
+        .await;
+        assert!(matches!(relay_rx.try_recv(), Ok(Frame::Data(_))));
+
+        // a later IPv6 or STUN discovery enables it
+        assert!(deferred.has_address(Some("2001:db8::1".parse().unwrap())));
+        *deferred.stun.write().unwrap() = Some(StunAddr {
+            ip: "198.51.100.7".to_string(),
+            port: 40123,
+            nat_type: 0,
+        });
+        assert!(deferred.has_address(None));
+    }
+
+    #[test]
+    fn test_stun_server_flags_reach_stun_client() {
+        let args = Args::parse_from([
+            "client",
+            "-s",
+            "127.0.0.1:8080",
+            "-i",
+            "a",
+            "--stun-server",
+            "stun.example.com:3478",
+            "--stun-server",
+            "[2001:db8::1]:3478",
+        ]);
+        assert_eq!(
+            stun_client(&args).servers(),
+            ["stun.example.com:3478", "[2001:db8::1]:3478"]
+        );
+
+        let args = Args::parse_from(["client", "-s", "127.0.0.1:8080", "-i", "a"]);
+        assert_eq!(stun_client(&args).servers(), StunClient::new().servers());
+
+        for bad in [
+            "stun.example.com",
+            ":3478",
+            "stun.example.com:0",
+            "stun:port",
+            "::1:3478",
+            "2001:db8::1:3478",
+            "[stun.example.com]:3478",
+        ] {
+            let result = Args::try_parse_from([
+                "client",
+                "-s",
+                "127.0.0.1:8080",
+                "-i",
+                "a",
+                "--stun-server",
+                bad,
+            ]);
+            assert!(result.is_err(), "{bad} should be rejected");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_advertises_injected_stun() {
+        use crate::client::p2p::stun::{NatType, StaticStun, StunDiscoveryResult};
+        use crate::crypto::plain::PlainBlock;
+        use crate::network::tcp_connection::TcpConnection;
+        use crate::network::{ConnRead, ConnWrite};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let relay = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut conn = TcpConnection::from_socket(socket);
+            assert!(matches!(
+                conn.read_frame().await.unwrap(),
+                Frame::Handshake(_)
+            ));
+            conn.write_frame(Frame::HandshakeReply(HandshakeReplyFrame {
+                name: "a".to_string(),
+                private_ip: "10.0.0.1".to_string(),
+                mask: "255.255.255.0".to_string(),
+                gateway: "10.0.0.254".to_string(),
+                ciders: vec![],
+                cider_mapping: Default::default(),
+                peers_version: 0,
+                resume_token: None,
+                resumed: false,
+                peer_details: vec![],
+                version: crate::codec::parser::MIN_VERSION,
+                mode: Default::default(),
+                pad: None,
+            }))
+            .await
+            .unwrap();
+            loop {
+                if let Frame::KeepAlive(keepalive) = conn.read_frame().await.unwrap() {
+                    return keepalive;
+                }
+            }
+        });
+
+        let args = Args::parse_from(["client", "-s", &server, "-i", "a", "--enable-p2p"]);
+        let stun = StaticStun::new(StunDiscoveryResult {
+            public_ip: "198.51.100.7".parse().unwrap(),
+            public_port: 40123,
+            nat_type: NatType::PortRestricted,
+            local_addr: "0.0.0.0:51259".parse().unwrap(),
+        });
+        let block: Arc<Box<dyn Block>> = Arc::new(Box::new(PlainBlock::new()));
+        let socket = StunSocket::bind(0).ok();
+        let (relay_handler, _) = connect_relay(&args, block, Arc::new(stun), socket, None)
+            .await
+            .unwrap();
+
+        let keepalive = tokio::time::timeout(Duration::from_secs(2), relay)
+            .await
+            .expect("keepalive should be sent after the handshake")
+            .unwrap();
+        assert_eq!(keepalive.stun_ip, "198.51.100.7");
+        assert_eq!(keepalive.stun_port, 40123);
+        assert_eq!(keepalive.nat_type, u8::from(NatType::PortRestricted));
+
+        let info = relay_handler.get_self_info().await.unwrap();
+        assert_eq!(
+            (info.stun_ip.as_str(), info.stun_port),
+            ("198.51.100.7", 40123)
+        );
+    }
+}
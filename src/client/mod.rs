@@ -1,10 +1,23 @@
+use crate::codec::frame::{IpValidation, TunnelMode};
+use crate::codec::magic::MagicSource;
+use crate::network::FamilyPreference;
+use crate::utils::device::{DEFAULT_QUEUE_SIZE, DropPolicy};
 use clap::Parser;
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 pub mod http;
 pub mod main;
 pub mod p2p;
+mod ping;
+mod preflight;
+mod presence;
 mod prettylog;
 mod relay;
+mod reorder;
+mod route_health;
+
+pub use relay::{RelayClientConfig, RelayHandler, RelayOutboundTx, SendStatus};
 
 /// Default P2P UDP port for client-to-client direct connections
 ///
@@ -13,6 +26,8 @@ mod relay;
 pub const P2P_UDP_PORT: u16 = 51258;
 
 pub const P2P_HOLE_PUNCH_PORT: u16 = 51259;
+/// Interval between STUN re-discoveries while they succeed
+pub const STUN_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
 
 /// Rustun VPN Client
 #[derive(Parser, Debug)]
@@ -26,10 +41,24 @@ pub struct Args {
     #[arg(short, long)]
     pub identity: String,
 
+    /// Token sent in the handshake for the server's authentication backend
+    #[arg(long)]
+    pub token: Option<String>,
+
     /// Encryption method: plain, aes256:<key>, chacha20:<key>, or xor:<key>
     #[arg(short, long, default_value = "chacha20:rustun")]
     pub crypto: String,
 
+    /// Magic number starting every frame: default, key (derived from
+    /// `--crypto`) or a number like 0x1a2b3c4d, as configured on the server
+    #[arg(long, default_value_t = MagicSource::Default)]
+    pub frame_magic: MagicSource,
+
+    /// Key of our cluster when the server gives it its own, same format as
+    /// `--crypto`, which then only encrypts the handshake
+    #[arg(long)]
+    pub cluster_crypto: Option<String>,
+
     /// Keep-alive interval in seconds
     #[arg(long, default_value = "10")]
     pub keepalive_interval: u64,
@@ -38,10 +67,216 @@ pub struct Args {
     #[arg(long, default_value = "3")]
     pub keepalive_threshold: u8,
 
+    /// Give up after this many handshakes in a row fail (retry forever if
+    /// not set)
+    #[arg(long, value_name = "COUNT")]
+    pub max_handshake_failures: Option<u32>,
+
+    /// Switch the relay connection to a fresh key after this many frames
+    /// (disabled if not specified)
+    #[arg(long, value_name = "COUNT")]
+    pub rekey_after_frames: Option<u64>,
+
+    /// Switch the relay connection to a fresh key after this many seconds
+    /// (disabled if not specified)
+    #[arg(long, value_name = "SECS")]
+    pub rekey_interval: Option<u64>,
+
+    /// Pad relayed data frames up to a multiple of this many bytes, hiding
+    /// packet sizes from observers of the relay connection (disabled if not
+    /// specified or the server does not support it)
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u16).range(16..))]
+    pub pad: Option<u16>,
+
+    /// Seconds allowed to connect to the relay server
+    #[arg(long, default_value = "10")]
+    pub connect_timeout: u64,
+
+    /// Seconds allowed to read one frame from the relay server
+    ///
+    /// Keep it above the keepalive interval, an idle connection only
+    /// receives keepalive replies.
+    #[arg(long, default_value = "20")]
+    pub read_timeout: u64,
+
+    /// Seconds allowed to write frames to the relay server
+    #[arg(long, default_value = "10")]
+    pub write_timeout: u64,
+
+    /// Kernel send buffer size of the relay socket in bytes (OS default if not set)
+    #[arg(long)]
+    pub send_buffer_size: Option<usize>,
+
+    /// Kernel receive buffer size of the relay socket in bytes (OS default if not set)
+    #[arg(long)]
+    pub recv_buffer_size: Option<usize>,
+
     /// Enable P2P direct connection (disabled by default, uses relay only)
     #[arg(long)]
     pub enable_p2p: bool,
 
+    /// Address families P2P binds: dual (IPv6 direct and IPv4 STUN), v4-only
+    /// or v6-only
+    #[arg(long, value_enum, default_value_t = p2p::P2PBindMode::Dual)]
+    pub p2p_bind: p2p::P2PBindMode,
+
+    /// Local IPv4 address the P2P STUN socket binds, e.g. the data NIC of a
+    /// multi-homed host (all addresses if not set)
+    #[arg(long, value_name = "IPV4")]
+    pub p2p_bind_v4: Option<Ipv4Addr>,
+
+    /// Local IPv6 address the P2P direct socket binds, advertised to peers
+    /// instead of the discovered address (all addresses if not set)
+    #[arg(long, value_name = "IPV6")]
+    pub p2p_bind_v6: Option<Ipv6Addr>,
+
+    /// Local address the relay connection and the P2P sockets of its family
+    /// bind, pinning VPN traffic to the uplink owning it (default route if
+    /// not set)
+    #[arg(long, value_name = "IP")]
+    pub bind_source: Option<IpAddr>,
+
+    /// Public IPv6 address of this host peers may also reach it on, besides
+    /// the discovered one, repeat for several
+    #[arg(long = "advertise-ipv6", value_name = "IPV6")]
+    pub advertise_ipv6: Vec<Ipv6Addr>,
+
+    /// Answer DNS queries sent through the tunnel to this address,
+    /// resolving `<identity>.vpn` to the peer's private IP (disabled if not
+    /// set)
+    #[arg(long, value_name = "IP")]
+    pub vpn_dns: Option<Ipv4Addr>,
+
+    /// Route all traffic through a peer advertising 0.0.0.0/0 (full tunnel)
+    #[arg(long)]
+    pub full_tunnel: bool,
+
+    /// Log route and NAT changes instead of applying them
+    #[arg(long)]
+    pub route_dry_run: bool,
+
+    /// Address family dialed first when the server resolves to both: auto
+    /// (resolver order), v4 or v6; the other one still takes over if the
+    /// first does not connect quickly
+    #[arg(long, value_enum, default_value_t = FamilyPreference::Auto)]
+    pub prefer_family: FamilyPreference,
+
+    /// Device type asked from the server: tun (IP packets, routed by IP)
+    /// or tap (Ethernet frames, switched by MAC; relay only)
+    #[arg(long, value_enum, default_value_t = TunnelMode::Tun)]
+    pub tunnel_mode: TunnelMode,
+
+    /// Name of the TUN interface, e.g. for firewall rules (default: picked
+    /// by the system; macOS takes utunN only)
+    #[arg(long, value_name = "NAME")]
+    pub iface_name: Option<String>,
+
+    /// Checks packets from peers must pass to reach the TUN device: none,
+    /// basic or strict (header lengths and IPv4 checksum)
+    #[arg(long, value_enum, default_value_t = IpValidation::Basic)]
+    pub ip_validation: IpValidation,
+
+    /// Packets queued each way between the TUN device and the tunnel
+    #[arg(long, default_value_t = DEFAULT_QUEUE_SIZE)]
+    pub device_queue_size: usize,
+
+    /// Packet dropped when the TUN device falls behind and its queue is
+    /// full: newest (the arriving one) or oldest
+    #[arg(long, value_enum, default_value_t = DropPolicy::Newest)]
+    pub device_drop_policy: DropPolicy,
+
+    /// Most peers kept probed for P2P, the least recently used others stay
+    /// relay-only (unlimited if not specified)
+    #[arg(long)]
+    pub max_active_peers: Option<usize>,
+
+    /// Destination CIDR never tried over P2P, repeat for several
+    #[arg(long = "relay-only-cidr", value_name = "CIDR")]
+    pub relay_only_cidrs: Vec<IpNet>,
+
+    /// Copy the DSCP marking of tunneled packets to the outer P2P UDP packets
+    #[arg(long)]
+    pub preserve_dscp: bool,
+
+    /// Hold out-of-order data frames up to this many milliseconds, numbering
+    /// sent ones for peers doing the same (disabled if not specified)
+    #[arg(long)]
+    pub reorder_window: Option<u64>,
+
+    /// Coalesce up to this many queued TUN packets into one relay frame
+    /// (disabled if not specified)
+    #[arg(long)]
+    pub batch_size: Option<usize>,
+
+    /// Longest time in milliseconds a packet waits for a batch to fill
+    #[arg(long, default_value = "1")]
+    pub batch_delay_ms: u64,
+
+    /// Limit traffic into the tunnel, relay and P2P, to this many megabits
+    /// per second (unlimited if not specified)
+    #[arg(long, value_name = "MBPS", value_parser = parse_mbps)]
+    pub max_upload_mbps: Option<f64>,
+
+    /// Limit traffic out of the tunnel to this many megabits per second,
+    /// dropping packets over it (unlimited if not specified)
+    #[arg(long, value_name = "MBPS", value_parser = parse_mbps)]
+    pub max_download_mbps: Option<f64>,
+
+    /// Probe advertised peer CIDRs every N seconds and withdraw routes
+    /// that stop answering (disabled if not specified)
+    #[arg(long)]
+    pub route_probe_interval: Option<u64>,
+
+    /// Unanswered probes before a peer CIDR route is withdrawn
+    #[arg(long, default_value_t = route_health::DEFAULT_PROBE_THRESHOLD)]
+    pub route_probe_threshold: u32,
+
+    /// Replace the peer CIDR routes with blackhole routes while the relay is
+    /// unreachable, so their traffic never leaks onto the physical network
+    /// (Linux and macOS)
+    #[arg(long)]
+    pub fail_closed: bool,
+
+    /// Seconds the relay may be unreachable before `--fail-closed` blocks
+    /// the routes
+    #[arg(long, default_value = "10", value_name = "SECS")]
+    pub fail_closed_grace: u64,
+
+    /// Block DNS queries to resolvers other than `--vpn-dns` with a firewall
+    /// rule while the tunnel is up, so no lookup leaks onto the physical
+    /// network (Linux, macOS and Windows)
+    #[arg(long, requires = "vpn_dns")]
+    pub dns_kill_switch: bool,
+
+    /// Resolver `--dns-kill-switch` still lets DNS queries reach, e.g. the
+    /// upstream the system resolver forwards names outside `.vpn` to,
+    /// repeat for several
+    #[arg(long = "dns-allow", value_name = "IP", requires = "dns_kill_switch")]
+    pub dns_allow: Vec<Ipv4Addr>,
+
+    /// Check connectivity to the peer with this identity over relay and P2P,
+    /// then exit
+    #[arg(long, value_name = "IDENTITY")]
+    pub ping: Option<String>,
+
+    /// Seconds each path gets to answer `--ping`
+    #[arg(long, default_value = "15")]
+    pub ping_timeout: u64,
+
+    /// Append a JSON line per relay and P2P frame to this file (disabled if
+    /// not specified)
+    #[arg(long, value_name = "FILE")]
+    pub capture: Option<std::path::PathBuf>,
+
+    /// Check that the TUN device and routes can be set up, then exit
+    #[arg(long)]
+    pub preflight: bool,
+
+    /// STUN server for P2P address discovery, repeat for several (default:
+    /// public Google and Twilio servers)
+    #[arg(long = "stun-server", value_name = "HOST:PORT", value_parser = parse_host_port)]
+    pub stun_servers: Vec<String>,
+
     /// HTTP status server port (disabled if not specified)
     #[arg(long)]
     pub http_port: Option<u16>,
@@ -51,4 +286,71 @@ pub struct Args {
     #[cfg(target_os = "linux")]
     #[arg(long)]
     pub masq: bool,
+
+    /// Queues of a multiqueue TUN device, each read and written by its own
+    /// task (Linux only)
+    #[cfg(target_os = "linux")]
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=256))]
+    pub tun_queues: u16,
+}
+
+impl Args {
+    /// Local IPv4 address P2P binds, `--p2p-bind-v4` over `--bind-source`
+    pub fn p2p_source_v4(&self) -> Option<Ipv4Addr> {
+        self.p2p_bind_v4.or(match self.bind_source {
+            Some(IpAddr::V4(ip)) => Some(ip),
+            _ => None,
+        })
+    }
+
+    /// Magic number of our frames, `--frame-magic` resolved against
+    /// `--crypto`
+    pub fn magic(&self) -> anyhow::Result<u32> {
+        let crypto = crate::crypto::parse_crypto_config(&self.crypto)?;
+        self.frame_magic.resolve(&crypto)
+    }
+
+    /// Local IPv6 address P2P binds, `--p2p-bind-v6` over `--bind-source`
+    pub fn p2p_source_v6(&self) -> Option<Ipv6Addr> {
+        self.p2p_bind_v6.or(match self.bind_source {
+            Some(IpAddr::V6(ip)) => Some(ip),
+            _ => None,
+        })
+    }
+}
+
+/// Accept a positive, finite rate in megabits per second
+fn parse_mbps(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(mbps) if mbps.is_finite() && mbps > 0.0 => Ok(mbps),
+        _ => Err(format!("expected a positive rate in Mbps, got {s:?}")),
+    }
+}
+
+/// Accept `host:port` with a non-empty host and a non-zero port
+///
+/// IPv6 addresses must be bracketed, `[addr]:port`.
+fn parse_host_port(s: &str) -> Result<String, String> {
+    let (host, port) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected host:port, got {s:?}"))?;
+    if host.is_empty() {
+        return Err(format!("missing host in {s:?}"));
+    }
+    match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(addr) if addr.parse::<std::net::Ipv6Addr>().is_err() => {
+            return Err(format!("invalid IPv6 address in {s:?}"));
+        }
+        Some(_) => {}
+        None if host.contains(':') => {
+            return Err(format!(
+                "expected [addr]:port for an IPv6 address, got {s:?}"
+            ));
+        }
+        None => {}
+    }
+    match port.parse::<u16>() {
+        Ok(port) if port != 0 => Ok(s.to_string()),
+        _ => Err(format!("invalid port in {s:?}")),
+    }
 }
@@ -1,4 +1,11 @@
-use std::net::SocketAddr;
+use crate::client::p2p::stun::{NatType, StunSocket};
+use crate::client::{P2P_HOLE_PUNCH_PORT, P2P_UDP_PORT};
+use crate::crypto::chacha20::ChaCha20Poly1305Block;
+use crate::crypto::ecdh::KeyPair;
+use anyhow::Context;
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 pub mod peer;
@@ -19,6 +26,193 @@ const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
 /// the connection is considered invalid and data sending will be rejected.
 const CONNECTION_TIMEOUT: Duration = Duration::from_secs(15);
 
+/// Predicted hole punch success rate below which a peer is unlikely to be
+/// reachable through STUN (e.g. both sides behind symmetric NAT)
+const MIN_HOLE_PUNCH_SUCCESS_RATE: f32 = 0.3;
+
+/// Unlikely peers get a hole punch probe at most this often (every sixth
+/// probe round), their traffic stays on the relay meanwhile
+const UNLIKELY_PUNCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Retransmits a control frame gets before waiting for the next probe round
+const CONTROL_RETRIES: u32 = 3;
+
+/// Wait before the first control frame retransmit, doubled for each one after
+///
+/// All retries are spent within 3.5 seconds, well before the next keepalive.
+const CONTROL_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How often outstanding control frames are checked for a due retransmit
+const CONTROL_RETRY_TICK: Duration = Duration::from_millis(100);
+
+/// How often peers are checked for a path that came up or went down
+const PATH_REPORT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Data frames in a row failing a confirmed session key before it is
+/// dropped and agreed again, e.g. after the peer restarted
+const MAX_SESSION_FAILURES: u32 = 8;
+
+/// Delay before a panicked UDP server binds its sockets again
+const UDP_SERVER_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Address families the P2P UDP server binds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum P2PBindMode {
+    /// IPv6 direct and IPv4 STUN sockets, P2P keeps going if only one binds
+    #[default]
+    Dual,
+    /// Only the IPv4 STUN socket, for hosts without an IPv6 stack
+    V4Only,
+    /// Only the IPv6 direct socket
+    V6Only,
+}
+
+impl P2PBindMode {
+    /// Whether the IPv4 STUN socket is bound
+    pub fn ipv4(self) -> bool {
+        self != P2PBindMode::V6Only
+    }
+
+    /// Whether the IPv6 direct socket is bound
+    pub fn ipv6(self) -> bool {
+        self != P2PBindMode::V4Only
+    }
+}
+
+/// Sockets the P2P UDP server listens on
+///
+/// Port 0 binds any free port, `PeerHandlerApi::local_addrs` tells which.
+#[derive(Debug, Clone)]
+pub struct UdpListen {
+    pub mode: P2PBindMode,
+    /// IPv6 direct port, 0 for any
+    pub ipv6_port: u16,
+    /// IPv4 STUN port, 0 for any
+    pub stun_port: u16,
+    /// Socket STUN discovery mapped, used instead of binding `stun_port`
+    pub stun_socket: Option<StunSocket>,
+    /// Local IPv4 address to bind, all addresses if not set
+    pub ipv4_addr: Option<Ipv4Addr>,
+    /// Local IPv6 address to bind, all addresses if not set
+    pub ipv6_addr: Option<Ipv6Addr>,
+}
+
+impl UdpListen {
+    /// The sockets of `mode` on the standard P2P ports
+    pub fn new(mode: P2PBindMode) -> Self {
+        Self {
+            mode,
+            ipv6_port: P2P_UDP_PORT,
+            stun_port: P2P_HOLE_PUNCH_PORT,
+            stun_socket: None,
+            ipv4_addr: None,
+            ipv6_addr: None,
+        }
+    }
+
+    /// Punch holes from the socket STUN discovery mapped
+    pub fn with_stun_socket(mut self, socket: Option<StunSocket>) -> Self {
+        self.stun_socket = socket;
+        self
+    }
+
+    /// Bind the sockets to these local addresses instead of all of them
+    pub fn with_local_addrs(mut self, ipv4: Option<Ipv4Addr>, ipv6: Option<Ipv6Addr>) -> Self {
+        self.ipv4_addr = ipv4;
+        self.ipv6_addr = ipv6;
+        self
+    }
+}
+
+/// Fails unless `ip` is the address of a local interface
+///
+/// The OS only lets a socket bind addresses its interfaces hold, so a
+/// throwaway bind is the portable check.
+pub fn check_local_addr(ip: IpAddr) -> anyhow::Result<()> {
+    std::net::UdpSocket::bind((ip, 0))
+        .map(drop)
+        .with_context(|| format!("{ip} is not an address of a local interface"))
+}
+
+/// Local addresses of the P2P sockets, `None` for a family not bound
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoundAddrs {
+    pub ipv6: Option<SocketAddr>,
+    pub ipv4: Option<SocketAddr>,
+}
+
+/// Path a peer is reached over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Stun,
+    Ipv6,
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Stun => write!(f, "STUN"),
+            Protocol::Ipv6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Change of the path a peer is reached over
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum PeerEvent {
+    /// The peer became reachable over P2P
+    Connected {
+        identity: String,
+        transport: Protocol,
+    },
+    /// Traffic to the peer moved to another path
+    PathChanged {
+        identity: String,
+        from: Protocol,
+        to: Protocol,
+    },
+    /// No path to the peer is fresh any more, traffic falls back to relay
+    Disconnected { identity: String },
+}
+
+impl PeerEvent {
+    /// Event for a peer whose path went from `from` to `to`, `None` if it did
+    /// not change
+    fn transition(identity: &str, from: Option<Protocol>, to: Option<Protocol>) -> Option<Self> {
+        let identity = identity.to_string();
+        match (from, to) {
+            (None, Some(transport)) => Some(PeerEvent::Connected {
+                identity,
+                transport,
+            }),
+            (Some(_), None) => Some(PeerEvent::Disconnected { identity }),
+            (Some(from), Some(to)) if from != to => {
+                Some(PeerEvent::PathChanged { identity, from, to })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PeerEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerEvent::Connected {
+                identity,
+                transport,
+            } => write!(f, "peer {identity} connected over {transport}"),
+            PeerEvent::PathChanged { identity, from, to } => {
+                write!(f, "peer {identity} moved from {from} to {to}")
+            }
+            PeerEvent::Disconnected { identity } => {
+                write!(f, "peer {identity} disconnected, using relay")
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 struct PeerMeta {
     name: String,
@@ -33,11 +227,121 @@ struct PeerMeta {
     /// Traffic destined for these ranges will be routed to this peer
     ciders: Vec<String>,
 
-    /// Resolved socket address combining IPv6 and port ([ipv6]:port)
-    remote_addr: LastActive<Option<SocketAddr>>,
+    /// Advertised IPv6 addresses with the port ([ipv6]:port), each probed
+    /// on its own
+    remote_addrs: Vec<LastActive<SocketAddr>>,
 
     /// Stun socket address
     stun_addr: LastActive<Option<SocketAddr>>,
+
+    /// NAT type of the peer's STUN mapping
+    nat_type: NatType,
+
+    /// Last hole punch probe while `nat_type` made it unlikely to succeed
+    unlikely_punch_at: Option<tokio::time::Instant>,
+
+    /// Last data frame sent to or received from this peer, ranks it
+    /// against `max_active_peers`
+    last_used: Option<Instant>,
+
+    /// Unanswered probes to `remote_addrs` and `stun_addr`
+    ipv6_pending: Option<PendingControl>,
+    stun_pending: Option<PendingControl>,
+
+    /// Key encrypting data frames to and from this peer, the shared relay
+    /// key is used until the exchange completes
+    session: Option<SessionKey>,
+    /// Our half of a key exchange we started, until the peer replies
+    key_exchange: Option<KeyPair>,
+    /// Unanswered `P2PKeyInit`
+    key_pending: Option<PendingControl>,
+
+    /// Path last reported in a `PeerEvent`
+    path: Option<Protocol>,
+}
+
+impl PeerMeta {
+    /// IPv6 address heard from most recently, the first advertised one if
+    /// none was
+    fn remote_addr(&self) -> LastActive<Option<SocketAddr>> {
+        let best = self
+            .remote_addrs
+            .iter()
+            .rev()
+            .max_by_key(|addr| addr.last_active());
+        LastActive {
+            value: best.map(|addr| *addr.get()),
+            last_active: best.and_then(|addr| addr.last_active()),
+        }
+    }
+
+    /// Path data frames take now, the one `send_frame` tries first
+    fn current_path(&self) -> Option<Protocol> {
+        if self.remote_addr().is_fresh() {
+            Some(Protocol::Ipv6)
+        } else if self.stun_addr.is_fresh() {
+            Some(Protocol::Stun)
+        } else {
+            None
+        }
+    }
+}
+
+/// P2P session key agreed with a peer
+#[derive(Clone)]
+struct SessionKey {
+    block: Arc<ChaCha20Poly1305Block>,
+    /// Protocol version of the data frames, agreed in the key exchange
+    version: u8,
+    /// Whether a data frame sealed with it arrived, the shared key is
+    /// refused for data from then on
+    confirmed: bool,
+    /// Data frames in a row that failed it since
+    failures: u32,
+}
+
+impl SessionKey {
+    fn new(block: ChaCha20Poly1305Block, version: u8) -> Self {
+        Self {
+            block: Arc::new(block),
+            version,
+            confirmed: false,
+            failures: 0,
+        }
+    }
+}
+
+impl std::fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SessionKey(..)")
+    }
+}
+
+/// Control frame sent to a peer and not answered yet
+///
+/// Data frames are fire-and-forget, a lost probe would leave the peer
+/// unreachable until the next keepalive round so it is sent again.
+#[derive(Debug, Clone)]
+struct PendingControl {
+    /// Marshaled frame
+    data: Vec<u8>,
+    /// Addresses it went to, all of a peer's candidates for an IPv6 probe
+    addrs: Vec<SocketAddr>,
+    /// Retransmits sent so far
+    retries: u32,
+    /// Paused clock in tests, hence tokio's `Instant`
+    next_at: tokio::time::Instant,
+}
+
+impl PendingControl {
+    fn new(data: Vec<u8>, addrs: Vec<SocketAddr>) -> Self {
+        Self {
+            data,
+            addrs,
+            retries: 0,
+            next_at: tokio::time::Instant::now() + CONTROL_RETRY_BACKOFF,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -55,16 +359,17 @@ impl<T> LastActive<T> {
     pub fn restart(&mut self) {
         self.last_active = Some(Instant::now());
     }
-    pub fn activate(&mut self, value: T) {
-        self.value = value;
-        self.last_active = Some(Instant::now());
-    }
     pub fn get(&self) -> &T {
         &self.value
     }
     pub fn last_active(&self) -> Option<Instant> {
         self.last_active
     }
+    /// Heard from within `CONNECTION_TIMEOUT`
+    pub fn is_fresh(&self) -> bool {
+        self.last_active
+            .is_some_and(|t| t.elapsed() <= CONNECTION_TIMEOUT)
+    }
 }
 
 #[derive(Debug)]
@@ -80,4 +385,13 @@ pub struct PeerStatus {
     /// STUN hole-punched connection info
     pub stun_addr: Option<SocketAddr>,
     pub stun_last_active: Option<Instant>,
+
+    /// Path data frames take, `None` while they go through the relay
+    pub path: Option<Protocol>,
+
+    /// Frames queued to the peer, not sent yet
+    pub queue_depth: usize,
+
+    /// Frames to the peer dropped because its queue was full
+    pub queue_dropped: u64,
 }
@@ -1,22 +1,54 @@
-use crate::client::p2p::udp_server::UDPServer;
+use crate::client::p2p::stun::NatType;
+use crate::client::p2p::udp_server::{OutboundPacket, PEER_QUEUE_SIZE, PeerQueues, UDPServer};
 use crate::client::p2p::{
-    CONNECTION_TIMEOUT, KEEPALIVE_INTERVAL, LastActive, OUTBOUND_BUFFER_SIZE, PeerMeta, PeerStatus,
+    BoundAddrs, CONNECTION_TIMEOUT, CONTROL_RETRIES, CONTROL_RETRY_BACKOFF, CONTROL_RETRY_TICK,
+    KEEPALIVE_INTERVAL, LastActive, MAX_SESSION_FAILURES, MIN_HOLE_PUNCH_SUCCESS_RATE,
+    OUTBOUND_BUFFER_SIZE, PATH_REPORT_INTERVAL, PeerEvent, PeerMeta, PeerStatus, PendingControl,
+    Protocol, SessionKey, UDP_SERVER_RESTART_DELAY, UNLIKELY_PUNCH_INTERVAL, UdpListen,
 };
-use crate::client::{P2P_HOLE_PUNCH_PORT, P2P_UDP_PORT};
-use crate::codec::frame::{Frame, PeerDetail, ProbeHolePunchFrame, ProbeIPv6Frame};
-use crate::codec::parser::Parser;
+use crate::codec::frame::{Frame, P2PKeyFrame, PeerDetail, ProbeHolePunchFrame, ProbeIPv6Frame};
+use crate::codec::parser::{MAX_VERSION, MIN_VERSION, Parser, negotiate_version};
 use crate::crypto::Block;
+use crate::crypto::ecdh::KeyPair;
+use crate::network::tap::{Direction, FrameTap};
+use crate::utils::StunAddr;
+use crate::utils::supervisor::catch_panic;
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
 pub struct PeerHandlerApi {
     pub new_peers: NewPeersTx,
     pub new_frame: NewFrameRx,
     pub send_frame: SendFrameTx,
     pub get_status: GetStatusTx,
+    pub shutdown: PeerShutdown,
+    pub local_addrs: LocalAddrsRx,
+    pub events: PeerEventsRx,
+}
+
+/// Stops the P2P service and releases its UDP ports
+#[derive(Debug)]
+pub struct PeerShutdown {
+    cancel: CancellationToken,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl PeerShutdown {
+    /// Stop the UDP server and peer service tasks and wait for them to exit
+    ///
+    /// The P2P ports can be bound again once this returns.
+    pub async fn shutdown(self) {
+        self.cancel.cancel();
+        for task in self.tasks {
+            let _ = task.await;
+        }
+        tracing::info!("p2p peer service stopped");
+    }
 }
 
 struct PeerHandlerPrivateRxApi {
@@ -27,7 +59,10 @@ struct PeerHandlerPrivateRxApi {
 }
 struct PeerHandlerPrivateTxApi {
     pub new_frame: NewFrameTx,
-    pub outbound_tx: mpsc::Sender<(Vec<u8>, Vec<SocketAddr>)>,
+    pub outbound_tx: mpsc::Sender<OutboundPacket>,
+    /// Frames to one peer, queued apart from the control frames
+    pub peer_queues: PeerQueues,
+    pub events: mpsc::Sender<PeerEvent>,
 }
 
 #[derive(Debug)]
@@ -39,6 +74,8 @@ pub struct NewFrameTx(mpsc::Sender<Frame>);
 #[derive(Debug)]
 pub struct NewFrameRx(pub mpsc::Receiver<Frame>);
 #[derive(Debug)]
+pub struct PeerEventsRx(pub mpsc::Receiver<PeerEvent>);
+#[derive(Debug)]
 pub struct SendFrameTx(pub mpsc::Sender<SendFrame>);
 #[derive(Debug)]
 pub struct SendFrameRx(mpsc::Receiver<SendFrame>);
@@ -46,6 +83,8 @@ pub struct SendFrameRx(mpsc::Receiver<SendFrame>);
 pub struct SendFrame {
     pub frame: Frame,
     pub dst: String,
+    /// ToS / traffic class for the outer UDP packet, 0 for the default
+    pub tos: u8,
 }
 #[derive(Debug)]
 pub struct GetStatusTx(mpsc::Sender<oneshot::Sender<Vec<PeerStatus>>>);
@@ -57,54 +96,168 @@ impl GetStatusTx {
     }
 }
 #[derive(Debug)]
+pub struct LocalAddrsRx(watch::Receiver<Option<BoundAddrs>>);
+impl LocalAddrsRx {
+    /// Addresses the P2P sockets are bound to
+    ///
+    /// Waits for the UDP server to bind, fails if it could not.
+    pub async fn get(&self) -> anyhow::Result<BoundAddrs> {
+        let mut rx = self.0.clone();
+        let addrs = rx
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| anyhow::anyhow!("P2P UDP server stopped before binding"))?;
+        Ok(addrs.expect("waited for the addresses"))
+    }
+}
+#[derive(Debug)]
 pub struct GetStatusRx(mpsc::Receiver<oneshot::Sender<Vec<PeerStatus>>>);
 
 #[derive(Debug)]
 struct PeerSet {
     peers: HashMap<String, PeerMeta>,
+    /// Most peers kept probed (unlimited if not set)
+    max_active: Option<usize>,
 }
 impl PeerSet {
     pub fn new() -> Self {
         Self {
             peers: HashMap::new(),
+            max_active: None,
         }
     }
 
+    /// Sets the most peers kept probed
+    pub fn with_max_active(mut self, max_active: Option<usize>) -> Self {
+        self.max_active = max_active;
+        self
+    }
+
+    /// Peers kept fully probed
+    ///
+    /// With `max_active` set only the most recently used peers are probed,
+    /// the others stay relay-only until traffic for them promotes them.
+    fn probed_peers(&self) -> Vec<&PeerMeta> {
+        let mut peers: Vec<&PeerMeta> = self.peers.values().collect();
+        if let Some(max) = self.max_active
+            && peers.len() > max
+        {
+            peers.sort_by(|a, b| {
+                b.last_used
+                    .cmp(&a.last_used)
+                    .then_with(|| a.identity.cmp(&b.identity))
+            });
+            peers.truncate(max);
+        }
+        peers
+    }
+
     pub fn all_peer_addrs(&self, protocol: Protocol) -> Vec<SocketAddr> {
-        self.peers
-            .values()
-            .filter_map(|p| match protocol {
-                Protocol::Ipv6 => *p.remote_addr.get(),
-                Protocol::Stun => *p.stun_addr.get(),
+        self.probed_peers()
+            .into_iter()
+            .flat_map(|p| match protocol {
+                Protocol::Ipv6 => p
+                    .remote_addrs
+                    .iter()
+                    .map(|addr| *addr.get())
+                    .collect::<Vec<_>>(),
+                Protocol::Stun => p.stun_addr.get().iter().copied().collect(),
             })
             .collect()
     }
 
-    pub fn update_peer_active(&mut self, identity: &str, addr: SocketAddr, protocol: Protocol) {
+    /// STUN addresses to hole punch this probe round
+    ///
+    /// Peers whose predicted success rate against `local_nat` is below
+    /// `MIN_HOLE_PUNCH_SUCCESS_RATE` are probed once every
+    /// `UNLIKELY_PUNCH_INTERVAL` at most, the first time right away.
+    pub fn hole_punch_addrs(&mut self, local_nat: NatType) -> Vec<SocketAddr> {
+        let now = tokio::time::Instant::now();
+        let probed: Vec<String> = self
+            .probed_peers()
+            .into_iter()
+            .map(|p| p.identity.clone())
+            .collect();
+        let mut addrs = Vec::new();
+        for identity in probed {
+            let Some(peer) = self.peers.get_mut(&identity) else {
+                continue;
+            };
+            let Some(addr) = *peer.stun_addr.get() else {
+                continue;
+            };
+            if local_nat.hole_punch_success_rate(&peer.nat_type) < MIN_HOLE_PUNCH_SUCCESS_RATE {
+                // checked against the previous probe, then moved to this one
+                if peer
+                    .unlikely_punch_at
+                    .is_some_and(|at| now.duration_since(at) < UNLIKELY_PUNCH_INTERVAL)
+                {
+                    continue;
+                }
+                peer.unlikely_punch_at = Some(now);
+            }
+            addrs.push(addr);
+        }
+        addrs
+    }
+
+    /// Mark the `protocol` path to `identity` at `addr` alive
+    ///
+    /// Only an address advertised for the peer is promoted, anyone can
+    /// claim its identity from elsewhere.
+    ///
+    /// # Returns
+    /// Whether `addr` is a known address of the peer
+    pub fn update_peer_active(
+        &mut self,
+        identity: &str,
+        addr: SocketAddr,
+        protocol: Protocol,
+    ) -> bool {
         let Some(peer) = self.peers.get_mut(identity) else {
-            return;
+            return false;
         };
         match protocol {
-            Protocol::Stun => peer.stun_addr.activate(Some(addr)),
-            Protocol::Ipv6 => peer.remote_addr.activate(Some(addr)),
+            Protocol::Stun if *peer.stun_addr.get() == Some(addr) => peer.stun_addr.restart(),
+            Protocol::Ipv6 => match peer.remote_addrs.iter_mut().find(|a| *a.get() == addr) {
+                Some(candidate) => candidate.restart(),
+                None => return false,
+            },
+            Protocol::Stun => return false,
         }
+        *peer.pending_mut(protocol) = None;
+        true
     }
-    pub fn update_peer_active_by_addr(&mut self, remote_addr: SocketAddr) {
+    pub fn update_peer_active_by_addr(&mut self, remote_addr: SocketAddr) -> Option<&mut PeerMeta> {
         for peer in self.peers.values_mut() {
             // Check if this is from IPv6 address
-            if *peer.remote_addr.get() == Some(remote_addr) {
-                peer.remote_addr.restart();
+            if let Some(candidate) = peer
+                .remote_addrs
+                .iter_mut()
+                .find(|addr| *addr.get() == remote_addr)
+            {
+                candidate.restart();
+                peer.ipv6_pending = None;
                 tracing::debug!("Updated IPv6 last_active for peer: {}", peer.identity);
-                return;
+                return Some(peer);
             }
             // Check if this is from STUN address
             if *peer.stun_addr.get() == Some(remote_addr) {
                 peer.stun_addr.restart();
+                peer.stun_pending = None;
                 tracing::debug!("Updated STUN last_active for peer: {}", peer.identity);
-                return;
+                return Some(peer);
             }
         }
         tracing::warn!("Received packet from unknown peer address: {}", remote_addr);
+        None
+    }
+
+    /// Record traffic for peer `identity`
+    pub fn mark_used(&mut self, identity: &str) {
+        if let Some(peer) = self.peers.get_mut(identity) {
+            peer.last_used = Some(Instant::now());
+        }
     }
 
     pub fn find_peer_by_ip_locked<'a>(&'a self, dest_ip: &str) -> Option<&'a PeerMeta> {
@@ -116,23 +269,26 @@ impl PeerSet {
             Err(_) => return None,
         };
 
-        for peer in self.peers.values() {
-            // Check exact match with peer's private IP
-            if peer.private_ip == dest_ip {
-                return Some(peer);
-            }
-
-            // Check if destination falls within peer's CIDR ranges
-            for cidr in &peer.ciders {
-                if let Ok(network) = cidr.parse::<IpNet>()
-                    && network.contains(&dest_ip_addr)
-                {
-                    return Some(peer);
-                }
-            }
+        // Exact match with a peer's private IP wins
+        if let Some(peer) = self.peers.values().find(|peer| peer.private_ip == dest_ip) {
+            return Some(peer);
         }
 
-        None
+        // Otherwise the longest CIDR containing the destination, so a
+        // full-tunnel gateway (0.0.0.0/0) never shadows a specific route
+        self.peers
+            .values()
+            .filter_map(|peer| {
+                peer.ciders
+                    .iter()
+                    .filter_map(|cidr| cidr.parse::<IpNet>().ok())
+                    .filter(|network| network.contains(&dest_ip_addr))
+                    .map(|network| network.prefix_len())
+                    .max()
+                    .map(|len| (len, peer))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, peer)| peer)
     }
 
     /// insert or update peers
@@ -148,28 +304,23 @@ impl PeerSet {
         for peer in peer_details {
             match peers.get_mut(&peer.identity) {
                 Some(existing_peer) => {
-                    if !peer.ipv6.is_empty()
-                        && let Some(addr) = parse_address(&peer.identity, &peer.ipv6, peer.port)
-                    {
-                        update_address(existing_peer, addr, Protocol::Ipv6);
+                    let ipv6_remotes = parse_ipv6_addresses(&peer);
+                    if !ipv6_remotes.is_empty() {
+                        update_ipv6_addresses(existing_peer, ipv6_remotes);
                     }
 
                     if !peer.stun_ip.is_empty()
                         && let Some(addr) =
                             parse_address(&peer.identity, &peer.stun_ip, peer.stun_port)
                     {
-                        update_address(existing_peer, addr, Protocol::Stun);
+                        update_stun_address(existing_peer, addr);
                     }
+                    existing_peer.nat_type = peer.nat_type.into();
                 }
                 None => {
-                    let ipv6_remote = parse_address(&peer.identity, &peer.ipv6, peer.port);
-                    if ipv6_remote.is_some() {
-                        tracing::info!(
-                            "Added IPv6 peer: {} at {}:{}",
-                            peer.identity,
-                            peer.ipv6,
-                            peer.port
-                        );
+                    let ipv6_remotes = parse_ipv6_addresses(&peer);
+                    if !ipv6_remotes.is_empty() {
+                        tracing::info!("Added IPv6 peer: {} at {ipv6_remotes:?}", peer.identity);
                     }
 
                     let stun_remote = parse_address(&peer.identity, &peer.stun_ip, peer.stun_port);
@@ -177,8 +328,8 @@ impl PeerSet {
                         tracing::info!(
                             "Added Hole Punch peer: {} at {}:{}",
                             peer.identity,
-                            peer.ipv6,
-                            peer.port
+                            peer.stun_ip,
+                            peer.stun_port
                         );
                     }
 
@@ -190,8 +341,20 @@ impl PeerSet {
                             identity: peer.identity.clone(),
                             private_ip: peer.private_ip.clone(),
                             ciders: peer.ciders.clone(),
-                            remote_addr: LastActive::dormant(ipv6_remote),
+                            remote_addrs: ipv6_remotes
+                                .into_iter()
+                                .map(LastActive::dormant)
+                                .collect(),
                             stun_addr: LastActive::dormant(stun_remote),
+                            nat_type: peer.nat_type.into(),
+                            unlikely_punch_at: None,
+                            last_used: None,
+                            ipv6_pending: None,
+                            stun_pending: None,
+                            session: None,
+                            key_exchange: None,
+                            key_pending: None,
+                            path: None,
                         },
                     );
                 }
@@ -200,9 +363,9 @@ impl PeerSet {
     }
 
     pub fn add_peer(&mut self, p: PeerDetail) {
-        let ipv6_remote = parse_address(&p.identity, &p.ipv6, p.port);
-        if ipv6_remote.is_some() {
-            tracing::info!("Added IPv6 peer: {} at {}:{}", p.identity, p.ipv6, p.port);
+        let ipv6_remotes = parse_ipv6_addresses(&p);
+        if !ipv6_remotes.is_empty() {
+            tracing::info!("Added IPv6 peer: {} at {ipv6_remotes:?}", p.identity);
         }
 
         let stun_remote = parse_address(&p.identity, &p.stun_ip, p.stun_port);
@@ -210,8 +373,8 @@ impl PeerSet {
             tracing::info!(
                 "Added Hole Punch peer: {} at {}:{}",
                 p.identity,
-                p.ipv6,
-                p.port
+                p.stun_ip,
+                p.stun_port
             );
         }
 
@@ -224,22 +387,121 @@ impl PeerSet {
                 identity: p.identity.clone(),
                 private_ip: p.private_ip.clone(),
                 ciders: p.ciders.clone(),
-                remote_addr: LastActive::dormant(ipv6_remote),
+                remote_addrs: ipv6_remotes.into_iter().map(LastActive::dormant).collect(),
                 stun_addr: LastActive::dormant(stun_remote),
+                nat_type: p.nat_type.into(),
+                unlikely_punch_at: None,
+                last_used: None,
+                ipv6_pending: None,
+                stun_pending: None,
+                session: None,
+                key_exchange: None,
+                key_pending: None,
+                path: None,
             },
         );
     }
 
+    /// Expect an answer to the probe `data` sent to `addrs`
+    ///
+    /// Only peers we have not heard from lately get it retransmitted,
+    /// live ones answer the next keepalive round anyway.
+    fn track_probe(&mut self, protocol: Protocol, addrs: &[SocketAddr], data: &[u8]) {
+        for peer in self.peers.values_mut() {
+            let (fresh, candidates) = match protocol {
+                Protocol::Ipv6 => (
+                    peer.remote_addr().is_fresh(),
+                    peer.remote_addrs
+                        .iter()
+                        .map(|addr| *addr.get())
+                        .collect::<Vec<_>>(),
+                ),
+                Protocol::Stun => (
+                    peer.stun_addr.is_fresh(),
+                    peer.stun_addr.get().iter().copied().collect(),
+                ),
+            };
+            if fresh {
+                continue;
+            }
+            let probed: Vec<SocketAddr> = candidates
+                .into_iter()
+                .filter(|addr| addrs.contains(addr))
+                .collect();
+            if !probed.is_empty() {
+                *peer.pending_mut(protocol) = Some(PendingControl::new(data.to_vec(), probed));
+            }
+        }
+    }
+
+    /// Control frames due for a retransmit, dropping those out of retries
+    fn due_retransmits(&mut self) -> Vec<(Vec<u8>, Vec<SocketAddr>)> {
+        let now = tokio::time::Instant::now();
+        let mut due = Vec::new();
+        for peer in self.peers.values_mut() {
+            let identity = &peer.identity;
+            for (kind, slot) in [
+                ("IPv6 probe", &mut peer.ipv6_pending),
+                ("STUN probe", &mut peer.stun_pending),
+                ("key exchange", &mut peer.key_pending),
+            ] {
+                let Some(pending) = slot else {
+                    continue;
+                };
+                if pending.next_at > now {
+                    continue;
+                }
+                if pending.retries == CONTROL_RETRIES {
+                    tracing::debug!("{kind} to {identity} unanswered, giving up");
+                    *slot = None;
+                    continue;
+                }
+                pending.retries += 1;
+                pending.next_at = now + CONTROL_RETRY_BACKOFF * 2u32.pow(pending.retries);
+                due.push((pending.data.clone(), pending.addrs.clone()));
+            }
+        }
+        due
+    }
+
+    /// Identity of the peer sending from `remote`
+    fn identity_by_addr(&self, remote: SocketAddr) -> Option<String> {
+        self.peers
+            .values()
+            .find(|p| {
+                p.remote_addrs.iter().any(|addr| *addr.get() == remote)
+                    || *p.stun_addr.get() == Some(remote)
+            })
+            .map(|p| p.identity.clone())
+    }
+
+    /// Note each peer's current path, returning the changes since last time
+    fn path_transitions(&mut self) -> Vec<PeerEvent> {
+        self.peers
+            .values_mut()
+            .filter_map(|peer| {
+                let path = peer.current_path();
+                let event = PeerEvent::transition(&peer.identity, peer.path, path);
+                peer.path = path;
+                event
+            })
+            .collect()
+    }
+
     pub fn get_status(&self) -> Vec<PeerStatus> {
         let mut result: Vec<PeerStatus> = Vec::new();
         for peer in self.peers.values() {
+            let ipv6 = peer.remote_addr();
             let status = PeerStatus {
                 name: peer.name.clone(),
                 identity: peer.identity.clone(),
-                ipv6_addr: *peer.remote_addr.get(),
-                ipv6_last_active: peer.remote_addr.last_active(),
+                ipv6_addr: *ipv6.get(),
+                ipv6_last_active: ipv6.last_active(),
                 stun_addr: *peer.stun_addr.get(),
                 stun_last_active: peer.stun_addr.last_active(),
+                path: peer.current_path(),
+                queue_depth: 0,
+                queue_dropped: 0,
             };
             result.push(status);
         }
@@ -250,7 +512,13 @@ impl PeerSet {
 pub struct PeerHandler {
     peers: PeerSet,
     block: Arc<Box<dyn Block>>,
+    /// Magic number of P2P frames, the one of the relay connection
+    magic: u32,
     identity: String,
+    /// Our own STUN mapping, refreshed by the relay client
+    local_stun: Arc<RwLock<Option<StunAddr>>>,
+    /// Capture P2P frames (disabled if not set)
+    tap: Option<FrameTap>,
     tx_api: PeerHandlerPrivateTxApi,
 }
 
@@ -260,28 +528,58 @@ enum SendResult {
     Expired(Duration),
     NeverResponded,
     NoAddress,
+    /// The queue to the peer is full, the frame was dropped
+    QueueFull,
 }
 
 impl PeerHandler {
     /// run peer service listen udp socket for p2p
+    #[allow(clippy::too_many_arguments)]
     pub fn start_peer_service(
         block: Arc<Box<dyn Block>>,
+        magic: u32,
         identity: String,
         peer_details: Vec<PeerDetail>,
+        local_stun: Arc<RwLock<Option<StunAddr>>>,
+        tap: Option<FrameTap>,
+        max_active_peers: Option<usize>,
+        listen: UdpListen,
     ) -> PeerHandlerApi {
+        let cancel = CancellationToken::new();
         let (outbound_tx, output_rx) = mpsc::channel(OUTBOUND_BUFFER_SIZE);
         let (inbound_tx, inbound_rx) = mpsc::channel(OUTBOUND_BUFFER_SIZE);
+        let peer_queues = PeerQueues::new(PEER_QUEUE_SIZE);
         let mut udp_server =
-            UDPServer::new(P2P_UDP_PORT, P2P_HOLE_PUNCH_PORT, inbound_tx, output_rx);
-        tokio::spawn(async move {
-            if let Err(e) = udp_server.serve().await {
-                tracing::error!("PeerService error: {e}");
+            UDPServer::new(listen.ipv6_port, listen.stun_port, inbound_tx, output_rx)
+                .with_peer_queues(peer_queues.clone())
+                .with_bind_mode(listen.mode)
+                .with_local_addrs(listen.ipv4_addr, listen.ipv6_addr)
+                .with_cancel(cancel.clone());
+        if let Some(socket) = listen.stun_socket {
+            udp_server = udp_server.with_stun_socket(socket);
+        }
+        let local_addrs = LocalAddrsRx(udp_server.bound_addrs());
+        let udp_task = tokio::spawn(async move {
+            // a panicked server rebinds its sockets, the channels survive
+            loop {
+                match catch_panic(udp_server.serve()).await {
+                    Ok(Ok(())) => break,
+                    Ok(Err(e)) => {
+                        tracing::error!("PeerService error: {e}");
+                        break;
+                    }
+                    Err(panic) => {
+                        tracing::error!("P2P UDP server panicked, restarting: {panic}");
+                        tokio::time::sleep(UDP_SERVER_RESTART_DELAY).await;
+                    }
+                }
             }
         });
         let (new_pears_tx, new_pears_rx) = mpsc::channel(1024);
         let (new_frame_tx, new_frame_rx) = mpsc::channel(1024);
         let (send_frame_tx, send_frame_rx) = mpsc::channel(1024);
         let (get_status_tx, get_status_rx) = mpsc::channel(1024);
+        let (events_tx, events_rx) = mpsc::channel(1024);
         let private_rx_api = PeerHandlerPrivateRxApi {
             new_peers: NewPeersRx(new_pears_rx),
             send_frame: SendFrameRx(send_frame_rx),
@@ -289,18 +587,27 @@ impl PeerHandler {
             inbound_rx,
         };
         let mut this = Self {
-            peers: PeerSet::new(),
+            peers: PeerSet::new().with_max_active(max_active_peers),
             block,
+            magic,
             identity,
+            local_stun,
+            tap,
             tx_api: PeerHandlerPrivateTxApi {
                 new_frame: NewFrameTx(new_frame_tx),
                 outbound_tx,
+                peer_queues,
+                events: events_tx,
             },
         };
         this.rewrite_peers(peer_details);
-        tokio::spawn(async move {
-            if let Err(e) = this.run_peer_service(private_rx_api).await {
-                tracing::error!("peer service failed: {e}");
+        let service_cancel = cancel.clone();
+        let service_task = tokio::spawn(async move {
+            match catch_panic(this.run_peer_service(private_rx_api, service_cancel)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => tracing::error!("peer service failed: {e}"),
+                // its channels close with it, traffic falls back to relay
+                Err(panic) => tracing::error!("peer service panicked, P2P stopped: {panic}"),
             }
         });
         tracing::info!("Running p2p peer service");
@@ -309,10 +616,23 @@ impl PeerHandler {
             new_frame: NewFrameRx(new_frame_rx),
             send_frame: SendFrameTx(send_frame_tx),
             get_status: GetStatusTx(get_status_tx),
+            shutdown: PeerShutdown {
+                cancel,
+                tasks: vec![udp_task, service_task],
+            },
+            local_addrs,
+            events: PeerEventsRx(events_rx),
         }
     }
-    async fn run_peer_service(mut self, rx_api: PeerHandlerPrivateRxApi) -> anyhow::Result<()> {
+
+    async fn run_peer_service(
+        mut self,
+        rx_api: PeerHandlerPrivateRxApi,
+        cancel: CancellationToken,
+    ) -> anyhow::Result<()> {
         let mut send_probes_interval = tokio::time::interval(KEEPALIVE_INTERVAL);
+        let mut retransmit_interval = tokio::time::interval(CONTROL_RETRY_TICK);
+        let mut report_paths_interval = tokio::time::interval(PATH_REPORT_INTERVAL);
         let PeerHandlerPrivateRxApi {
             mut new_peers,
             mut send_frame,
@@ -322,14 +642,22 @@ impl PeerHandler {
 
         loop {
             tokio::select! {
+                _ = cancel.cancelled() => return Ok(()),
                 _ = send_probes_interval.tick() => {
                     self.send_probes().await;
                 }
+                _ = retransmit_interval.tick() => {
+                    self.retransmit_control().await;
+                }
+                // paths come up on received frames and expire with time
+                _ = report_paths_interval.tick() => {
+                    self.report_paths();
+                }
                 Some(peer_details) = new_peers.0.recv() => {
                     self.insert_or_update(peer_details);
                 }
                 Some(sf) = send_frame.0.recv() => {
-                    if let Err(e) = self.send_frame(sf.frame, &sf.dst).await {
+                    if let Err(e) = self.send_frame(sf.frame, &sf.dst, sf.tos).await {
                         tracing::warn!("send_frame failed: {e}");
                     }
                 }
@@ -346,12 +674,22 @@ impl PeerHandler {
         }
     }
 
+    /// Log and publish the peers whose path changed
+    fn report_paths(&mut self) {
+        for event in self.peers.path_transitions() {
+            tracing::info!("{event}");
+            if let Err(e) = self.tx_api.events.try_send(event) {
+                tracing::debug!("peer event dropped: {e}");
+            }
+        }
+    }
+
     /// rewrite peers with new peers details for route
     ///
     /// this function will update peer's ipv6 and stun address
     ///
     fn rewrite_peers(&mut self, peer_details: Vec<PeerDetail>) {
-        self.peers = PeerSet::new();
+        self.peers = PeerSet::new().with_max_active(self.peers.max_active);
         for p in peer_details {
             self.peers.add_peer(p);
         }
@@ -363,15 +701,30 @@ impl PeerHandler {
 
     /// recv_frame to recv from local p2p socket to get peers frame
     ///
-    /// only support for ProbeIPv6, ProbeStun, Data
+    /// only support for ProbeIPv6, ProbeStun, Echo, Data
     ///
     /// **ProbeIPv6**
     /// - update last_active, this is for p2p send_frame healthy checker
     /// - remote address, most of the time this is not changed.
+    /// - answered with our own probe on first contact, so the peer stops
+    ///   retransmitting and can reach us without waiting for our next round
+    ///
+    /// **Echo**
+    /// - answered with an EchoReply straight back to the sender
+    ///
+    /// **P2PKeyInit / P2PKeyReply**
+    /// - X25519 exchange of the session key for data frames, see `start_key_exchange`
     async fn recv_frame(&mut self, msg: (Vec<u8>, SocketAddr)) -> anyhow::Result<()> {
         let (buf, remote) = msg;
 
-        let (frame, _) = Parser::unmarshal(&buf, self.block.as_ref().as_ref())?;
+        let sender = Parser::carries_data(&buf)
+            .then(|| self.peers.identity_by_addr(remote))
+            .flatten();
+        let frame = match sender {
+            Some(identity) => self.open_data(&identity, &buf, remote).await?,
+            None => Parser::unmarshal_frame(&buf, self.block.as_ref().as_ref(), self.magic)?.0,
+        };
+        self.capture(Direction::In, remote, &frame);
 
         match frame {
             Frame::ProbeIPv6(probe) => {
@@ -379,62 +732,309 @@ impl PeerHandler {
                     "Received probe ipv6 from peer {} at {remote}",
                     probe.identity
                 );
-                self.peers
-                    .update_peer_active(&probe.identity, remote, Protocol::Ipv6);
+                self.answer_first_probe(&probe.identity, remote, Protocol::Ipv6)
+                    .await?;
             }
             Frame::ProbeHolePunch(probe) => {
                 tracing::info!(
                     "Received probe hole punch from peer {} at {remote}",
                     probe.identity
                 );
-                self.peers
-                    .update_peer_active(&probe.identity, remote, Protocol::Stun);
+                self.answer_first_probe(&probe.identity, remote, Protocol::Stun)
+                    .await?;
             }
-            _ => {
+            Frame::P2PKeyInit(init) => self.accept_key_exchange(init, remote).await?,
+            Frame::P2PKeyReply(reply) => self.finish_key_exchange(reply)?,
+            Frame::Echo(echo) => {
+                tracing::debug!("Received echo {} from {remote}", echo.id);
                 self.peers.update_peer_active_by_addr(remote);
+                let reply = Frame::EchoReply(echo.reply());
+                self.capture(Direction::Out, remote, &reply);
+                let reply = Parser::marshal_frame(
+                    reply,
+                    self.block.as_ref().as_ref(),
+                    MIN_VERSION,
+                    None,
+                    self.magic,
+                )?;
+                self.tx_api
+                    .outbound_tx
+                    .send((reply, vec![remote], 0))
+                    .await?;
+            }
+            _ => {
+                if let Some(peer) = self.peers.update_peer_active_by_addr(remote) {
+                    peer.last_used = Some(Instant::now());
+                }
                 let _ = self.tx_api.new_frame.0.send(frame).await;
             }
         }
         Ok(())
     }
 
+    /// Open a data frame from peer `identity`
+    ///
+    /// The peer may still seal with the shared key until a frame under the
+    /// session key arrived, from then on only the session key is accepted.
+    /// `MAX_SESSION_FAILURES` frames in a row failing it drop the session
+    /// and a new one is agreed, the peer most likely restarted.
+    async fn open_data(
+        &mut self,
+        identity: &str,
+        buf: &[u8],
+        remote: SocketAddr,
+    ) -> anyhow::Result<Frame> {
+        let shared = self.block.as_ref().as_ref();
+        let Some(peer) = self.peers.peers.get_mut(identity) else {
+            return Ok(Parser::unmarshal_frame(buf, shared, self.magic)?.0);
+        };
+        let Some(session) = peer.session.as_mut() else {
+            return Ok(Parser::unmarshal_frame(buf, shared, self.magic)?.0);
+        };
+        let e = match Parser::unmarshal_frame(buf, session.block.as_ref(), self.magic) {
+            Ok((frame, _)) => {
+                session.confirmed = true;
+                session.failures = 0;
+                return Ok(frame);
+            }
+            Err(_) if !session.confirmed => {
+                return Ok(Parser::unmarshal_frame(buf, shared, self.magic)?.0);
+            }
+            Err(e) => e,
+        };
+        session.failures += 1;
+        if session.failures < MAX_SESSION_FAILURES {
+            return Err(e.into());
+        }
+        tracing::warn!("P2P session key with {identity} keeps failing, agreeing a new one");
+        peer.session = None;
+        if self.identity.as_str() < identity && peer.key_pending.is_none() {
+            self.start_key_exchange(identity, remote).await?;
+        }
+        Err(e.into())
+    }
+
+    /// Mark the peer active, answering the probe if we had not heard from it
+    ///
+    /// First contact also drops the session key, the peer may have
+    /// restarted meanwhile and a new one is agreed.
+    async fn answer_first_probe(
+        &mut self,
+        identity: &str,
+        remote: SocketAddr,
+        protocol: Protocol,
+    ) -> anyhow::Result<()> {
+        let Some(peer) = self.peers.peers.get_mut(identity) else {
+            return Ok(());
+        };
+        if !peer.knows_addr(remote, protocol) {
+            tracing::debug!("probe of {identity} from unknown address {remote} ignored");
+            return Ok(());
+        }
+        let first_contact = match protocol {
+            Protocol::Ipv6 => !peer.remote_addr().is_fresh(),
+            Protocol::Stun => !peer.stun_addr.is_fresh(),
+        };
+        if first_contact {
+            peer.session = None;
+        }
+        let start_key_exchange = self.identity.as_str() < identity
+            && peer.session.is_none()
+            && peer.key_pending.is_none();
+        self.peers.update_peer_active(identity, remote, protocol);
+        if first_contact {
+            let reply = probe_frame(&self.identity, protocol);
+            self.capture(Direction::Out, remote, &reply);
+            let reply = Parser::marshal_frame(
+                reply,
+                self.block.as_ref().as_ref(),
+                MIN_VERSION,
+                None,
+                self.magic,
+            )?;
+            self.tx_api
+                .outbound_tx
+                .send((reply, vec![remote], 0))
+                .await?;
+            if start_key_exchange {
+                self.start_key_exchange(identity, remote).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a `P2PKeyInit` with a fresh key pair to peer `identity`
+    ///
+    /// The peer with the lower identity starts the exchange once a path is
+    /// up. The init is retransmitted like a probe until the reply arrives.
+    async fn start_key_exchange(
+        &mut self,
+        identity: &str,
+        remote: SocketAddr,
+    ) -> anyhow::Result<()> {
+        let Some(peer) = self.peers.peers.get_mut(identity) else {
+            return Ok(());
+        };
+        let pair = KeyPair::generate();
+        let init = Frame::P2PKeyInit(P2PKeyFrame {
+            identity: self.identity.clone(),
+            public_key: pair.public,
+            max_version: MAX_VERSION,
+        });
+        if let Some(tap) = &self.tap {
+            tap.record("p2p", Direction::Out, Some(remote), &init);
+        }
+        let init = Parser::marshal_frame(
+            init,
+            self.block.as_ref().as_ref(),
+            MIN_VERSION,
+            None,
+            self.magic,
+        )?;
+        peer.key_exchange = Some(pair);
+        peer.key_pending = Some(PendingControl::new(init.clone(), vec![remote]));
+        tracing::debug!("Starting P2P key exchange with {identity}");
+        self.tx_api
+            .outbound_tx
+            .send((init, vec![remote], 0))
+            .await?;
+        Ok(())
+    }
+
+    /// Answer a `P2PKeyInit` and switch to the derived session key
+    async fn accept_key_exchange(
+        &mut self,
+        init: P2PKeyFrame,
+        remote: SocketAddr,
+    ) -> anyhow::Result<()> {
+        let Some(peer) = self.peers.peers.get_mut(&init.identity) else {
+            anyhow::bail!("key exchange from unknown peer {}", init.identity);
+        };
+        // both started at once, the lower identity's exchange wins
+        if peer.key_exchange.is_some() && self.identity < init.identity {
+            tracing::debug!("Ignoring crossed key exchange from {}", init.identity);
+            return Ok(());
+        }
+
+        let pair = KeyPair::generate();
+        let session = pair.derive(&init.public_key, &self.identity, &init.identity)?;
+        peer.session = Some(SessionKey::new(
+            session,
+            negotiate_version(MAX_VERSION, init.max_version),
+        ));
+        peer.key_exchange = None;
+        peer.key_pending = None;
+        tracing::info!("P2P session key agreed with {}", init.identity);
+
+        let reply = Frame::P2PKeyReply(P2PKeyFrame {
+            identity: self.identity.clone(),
+            public_key: pair.public,
+            max_version: MAX_VERSION,
+        });
+        self.capture(Direction::Out, remote, &reply);
+        let reply = Parser::marshal_frame(
+            reply,
+            self.block.as_ref().as_ref(),
+            MIN_VERSION,
+            None,
+            self.magic,
+        )?;
+        self.tx_api
+            .outbound_tx
+            .send((reply, vec![remote], 0))
+            .await?;
+        Ok(())
+    }
+
+    /// Derive the session key from the reply to our `P2PKeyInit`
+    fn finish_key_exchange(&mut self, reply: P2PKeyFrame) -> anyhow::Result<()> {
+        let Some(peer) = self.peers.peers.get_mut(&reply.identity) else {
+            anyhow::bail!("key exchange reply from unknown peer {}", reply.identity);
+        };
+        // a late duplicate of a reply already used
+        let Some(pair) = peer.key_exchange.take() else {
+            return Ok(());
+        };
+        peer.key_pending = None;
+        let session = pair.derive(&reply.public_key, &self.identity, &reply.identity)?;
+        peer.session = Some(SessionKey::new(
+            session,
+            negotiate_version(MAX_VERSION, reply.max_version),
+        ));
+        tracing::info!("P2P session key agreed with {}", reply.identity);
+        Ok(())
+    }
+
+    /// Send unanswered control frames again
+    async fn retransmit_control(&mut self) {
+        for (data, addrs) in self.peers.due_retransmits() {
+            tracing::debug!("Retransmitting control frame to {addrs:?}");
+            if let Err(e) = self.tx_api.outbound_tx.send((data, addrs.clone(), 0)).await {
+                tracing::warn!("Failed to retransmit control frame to {addrs:?}: {e:?}");
+            }
+        }
+    }
+
     /// send_frame tries to get peers that contains dest_ip in ciders or private_ip
     ///
     /// firstly try ipv6 direct, if peers is healthy(base on last_active)
     ///
     /// secondary try p2p hole punch, if peers is healthy(base on stun_last_active)
     ///
-    async fn send_frame(&self, frame: Frame, dest_ip: &str) -> anyhow::Result<()> {
-        let peer = self
+    async fn send_frame(&mut self, frame: Frame, dest_ip: &str, tos: u8) -> anyhow::Result<()> {
+        let peer_identity = self
             .peers
             .find_peer_by_ip_locked(dest_ip)
-            .ok_or_else(|| anyhow::anyhow!("No peer found for destination"))?;
+            .ok_or_else(|| anyhow::anyhow!("No peer found for destination"))?
+            .identity
+            .clone();
+        // traffic keeps the peer probed, or gets it probed again
+        self.peers.mark_used(&peer_identity);
+        let peer = &self.peers.peers[&peer_identity];
+        let ipv6 = peer.remote_addr();
 
-        if peer.remote_addr.get().is_none() && peer.stun_addr.get().is_none() {
+        if ipv6.get().is_none() && peer.stun_addr.get().is_none() {
             return Err(anyhow::anyhow!(
                 "Peer {} has no available address (IPv6 or STUN)",
                 peer.identity
             ));
         }
-        let peer_identity = peer.identity.clone();
 
-        // Marshal frame once for potential multiple attempts
-        let data = Parser::marshal(frame, self.block.as_ref().as_ref())?;
-        let outbound_tx = &self.tx_api.outbound_tx;
+        // Marshal frame once for potential multiple attempts, keep it for
+        // the capture record of the attempt that goes out
+        // tunneled packets use the session key once the peer agreed one
+        let captured = self.tap.is_some().then(|| frame.clone());
+        let session = match frame {
+            Frame::Data(_) | Frame::DataBatch(_) => peer.session.clone(),
+            _ => None,
+        };
+        let block: &dyn Block = match &session {
+            Some(session) => session.block.as_ref(),
+            None => self.block.as_ref().as_ref(),
+        };
+        let version = session
+            .as_ref()
+            .map_or(MIN_VERSION, |session| session.version);
+        // a UDP datagram stays below 64KB whatever the version
+        Parser::check_packet_len(&frame, Parser::max_packet_len(block, MIN_VERSION))?;
+        let data = Parser::marshal_frame(frame, block, version, None, self.magic)?;
 
         // Attempt 1: Try IPv6 direct connection
         match self
             .try_send_via(
-                outbound_tx,
                 &data,
-                *peer.remote_addr.get(),
-                peer.remote_addr.last_active(),
+                *ipv6.get(),
+                ipv6.last_active(),
                 &peer_identity,
                 "IPv6",
+                tos,
             )
             .await
         {
-            SendResult::Success => return Ok(()),
+            SendResult::Success => {
+                self.capture_sent(captured.as_ref(), *ipv6.get());
+                return Ok(());
+            }
             SendResult::Expired(elapsed) => {
                 tracing::debug!(
                     "IPv6 connection to {peer_identity} expired ({elapsed:?} ago), trying STUN"
@@ -446,21 +1046,29 @@ impl PeerHandler {
             SendResult::NoAddress => {
                 // No IPv6 address, try STUN
             }
+            SendResult::QueueFull => {
+                // counted per peer and shown in its status
+                tracing::debug!("queue to peer {peer_identity} full, frame dropped");
+                return Ok(());
+            }
         }
 
         // Attempt 2: Try STUN address
         match self
             .try_send_via(
-                outbound_tx,
                 &data,
                 *peer.stun_addr.get(),
                 peer.stun_addr.last_active(),
                 &peer_identity,
                 "STUN",
+                tos,
             )
             .await
         {
-            SendResult::Success => Ok(()),
+            SendResult::Success => {
+                self.capture_sent(captured.as_ref(), *peer.stun_addr.get());
+                Ok(())
+            }
             SendResult::Expired(elapsed) => Err(anyhow::anyhow!(
                 "Peer {peer_identity} STUN connection also expired ({elapsed:?} ago)"
             )),
@@ -470,17 +1078,22 @@ impl PeerHandler {
             SendResult::NoAddress => Err(anyhow::anyhow!(
                 "Failed to send to peer {peer_identity}: IPv6 unavailable/expired, STUN unavailable/expired"
             )),
+            SendResult::QueueFull => {
+                tracing::debug!("queue to peer {peer_identity} full, frame dropped");
+                Ok(())
+            }
         }
     }
 
+    /// Queue `data` to `addr` of the peer, unless it is not known to be live
     async fn try_send_via(
         &self,
-        outbound_tx: &mpsc::Sender<(Vec<u8>, Vec<SocketAddr>)>,
         data: &[u8],
         addr: Option<SocketAddr>,
         last_active: Option<Instant>,
         peer_identity: &str,
         protocol: &str,
+        tos: u8,
     ) -> SendResult {
         // Check if address exists
         let addr = match addr {
@@ -499,76 +1112,147 @@ impl PeerHandler {
             return SendResult::Expired(elapsed);
         }
 
-        // Connection is valid, send the packet
-        match outbound_tx.send((data.to_vec(), vec![addr])).await {
-            Ok(_) => {
-                tracing::debug!("Sent frame to peer {peer_identity} via {protocol}: {addr}");
-                SendResult::Success
-            }
-            Err(e) => {
-                tracing::error!("Failed to send via {protocol}: {e}");
-                SendResult::NeverResponded // Treat send error as connection problem
-            }
+        // Connection is valid, queue the packet behind the peer's others
+        if self
+            .tx_api
+            .peer_queues
+            .push(peer_identity, (data.to_vec(), vec![addr], tos))
+        {
+            tracing::debug!("Sent frame to peer {peer_identity} via {protocol}: {addr}");
+            SendResult::Success
+        } else {
+            SendResult::QueueFull
         }
     }
 
     fn get_status(&self) -> Vec<PeerStatus> {
-        self.peers.get_status()
+        let mut status = self.peers.get_status();
+        for peer in &mut status {
+            peer.queue_depth = self.tx_api.peer_queues.depth(&peer.identity);
+            peer.queue_dropped = self.tx_api.peer_queues.dropped(&peer.identity);
+        }
+        status
+    }
+
+    fn capture(&self, dir: Direction, remote: SocketAddr, frame: &Frame) {
+        if let Some(tap) = &self.tap {
+            tap.record("p2p", dir, Some(remote), frame);
+        }
     }
 
-    async fn send_probes(&self) {
+    fn capture_sent(&self, frame: Option<&Frame>, addr: Option<SocketAddr>) {
+        if let (Some(frame), Some(addr)) = (frame, addr) {
+            self.capture(Direction::Out, addr, frame);
+        }
+    }
+
+    async fn send_probes(&mut self) {
         let outbound_tx = &self.tx_api.outbound_tx;
 
         let block = &self.block;
+        let magic = self.magic;
         let identity = &self.identity;
+        let tap = self.tap.as_ref();
 
         // Send IPv6 probes
-        send_probes(&self.peers, outbound_tx, block, identity, Protocol::Ipv6).await;
+        let ipv6_addrs = self.peers.all_peer_addrs(Protocol::Ipv6);
+        let ipv6_probe = send_probes(
+            ipv6_addrs.clone(),
+            outbound_tx,
+            block,
+            magic,
+            identity,
+            tap,
+            Protocol::Ipv6,
+        )
+        .await;
 
-        // Send STUN hole punch probes
-        send_probes(&self.peers, outbound_tx, block, identity, Protocol::Stun).await;
+        // Send STUN hole punch probes, sparing peers we are unlikely to reach
+        let local_nat = self
+            .local_stun
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map_or(NatType::Unknown, |stun| stun.nat_type.into());
+        let stun_addrs = self.peers.hole_punch_addrs(local_nat);
+        let stun_probe = send_probes(
+            stun_addrs.clone(),
+            outbound_tx,
+            block,
+            magic,
+            identity,
+            tap,
+            Protocol::Stun,
+        )
+        .await;
+
+        if let Some(probe) = ipv6_probe {
+            self.peers.track_probe(Protocol::Ipv6, &ipv6_addrs, &probe);
+        }
+        if let Some(probe) = stun_probe {
+            self.peers.track_probe(Protocol::Stun, &stun_addrs, &probe);
+        }
     }
 }
 
+/// Probe `peer_addrs`, returns the marshaled probe once it is sent
 async fn send_probes(
-    peers: &PeerSet,
-    outbound_tx: &mpsc::Sender<(Vec<u8>, Vec<SocketAddr>)>,
+    peer_addrs: Vec<SocketAddr>,
+    outbound_tx: &mpsc::Sender<OutboundPacket>,
     block: &Arc<Box<dyn Block>>,
+    magic: u32,
     identity: &str,
+    tap: Option<&FrameTap>,
     protocol: Protocol,
-) {
-    let peer_addrs = peers.all_peer_addrs(protocol);
-
+) -> Option<Vec<u8>> {
     // Skip if no peers have this type of address
     if peer_addrs.is_empty() {
-        return;
+        return None;
     }
 
     // Create appropriate probe frame
-    let probe_frame = match protocol {
-        Protocol::Ipv6 => Frame::ProbeIPv6(ProbeIPv6Frame {
-            identity: identity.to_string(),
-        }),
-        Protocol::Stun => Frame::ProbeHolePunch(ProbeHolePunchFrame {
-            identity: identity.to_string(),
-        }),
-    };
+    let probe_frame = probe_frame(identity, protocol);
+
+    if let Some(tap) = tap {
+        for addr in &peer_addrs {
+            tap.record("p2p", Direction::Out, Some(*addr), &probe_frame);
+        }
+    }
 
     // Marshal once, reuse for all peers
-    let probe_data = match Parser::marshal(probe_frame, block.as_ref().as_ref()) {
+    let probe_data = match Parser::marshal_frame(
+        probe_frame,
+        block.as_ref().as_ref(),
+        MIN_VERSION,
+        None,
+        magic,
+    ) {
         Ok(data) => data,
         Err(e) => {
             tracing::error!("Failed to marshal {protocol} probe: {e}");
-            return;
+            return None;
         }
     };
 
     // Send to all peers
     let peer_addrs_display = format!("{peer_addrs:?}");
-    if let Err(e) = outbound_tx.send((probe_data.clone(), peer_addrs)).await {
+    if let Err(e) = outbound_tx.send((probe_data.clone(), peer_addrs, 0)).await {
         tracing::warn!("Failed to send {protocol} probe to {peer_addrs_display}: {e:?}");
+        None
     } else {
         tracing::info!("Sent {protocol} probe to {peer_addrs_display:?}");
+        Some(probe_data)
+    }
+}
+
+fn probe_frame(identity: &str, protocol: Protocol) -> Frame {
+    match protocol {
+        Protocol::Ipv6 => Frame::ProbeIPv6(ProbeIPv6Frame {
+            identity: identity.to_string(),
+        }),
+        Protocol::Stun => Frame::ProbeHolePunch(ProbeHolePunchFrame {
+            identity: identity.to_string(),
+        }),
     }
 }
 
@@ -588,39 +1272,804 @@ fn parse_address(identity: &str, ip: &str, port: u16) -> Option<SocketAddr> {
     Some(addr)
 }
 
-fn update_address(peer: &mut PeerMeta, new_addr: SocketAddr, protocol: Protocol) {
-    let old_addr = match protocol {
-        Protocol::Stun => *peer.stun_addr.get(),
-        Protocol::Ipv6 => *peer.remote_addr.get(),
-    };
+/// Every IPv6 address `peer` advertises, with its port
+fn parse_ipv6_addresses(peer: &PeerDetail) -> Vec<SocketAddr> {
+    peer.ipv6
+        .iter()
+        .filter_map(|ipv6| parse_address(&peer.identity, ipv6, peer.port))
+        .collect()
+}
+
+fn update_stun_address(peer: &mut PeerMeta, new_addr: SocketAddr) {
+    let old_addr = *peer.stun_addr.get();
 
     if old_addr != Some(new_addr) {
         tracing::info!(
-            "Update {protocol} address for peer {}: {} -> {new_addr}",
+            "Update {} address for peer {}: {} -> {new_addr}",
+            Protocol::Stun,
             peer.identity,
             old_addr
                 .map(|a| a.to_string())
                 .unwrap_or_else(|| "None".to_string()),
         );
 
-        let new_addr = LastActive::dormant(Some(new_addr));
+        peer.stun_addr = LastActive::dormant(Some(new_addr));
+        peer.stun_pending = None;
+        drop_session(peer);
+    }
+}
+
+/// Switch the peer to the IPv6 addresses it advertises now
+///
+/// Addresses still advertised keep when they were last heard from, new
+/// ones wait for an answer to a probe.
+fn update_ipv6_addresses(peer: &mut PeerMeta, new_addrs: Vec<SocketAddr>) {
+    let old_addrs: Vec<SocketAddr> = peer.remote_addrs.iter().map(|addr| *addr.get()).collect();
+    if old_addrs == new_addrs {
+        return;
+    }
+    tracing::info!(
+        "Update {} addresses for peer {}: {old_addrs:?} -> {new_addrs:?}",
+        Protocol::Ipv6,
+        peer.identity,
+    );
+
+    let mut old = std::mem::take(&mut peer.remote_addrs);
+    peer.remote_addrs = new_addrs
+        .into_iter()
+        .map(|addr| match old.iter().position(|a| *a.get() == addr) {
+            Some(i) => old.swap_remove(i),
+            None => LastActive::dormant(addr),
+        })
+        .collect();
+    peer.ipv6_pending = None;
+    drop_session(peer);
+}
+
+/// Forget the session key of a peer that moved, a new one is agreed on
+/// first contact at the new address
+fn drop_session(peer: &mut PeerMeta) {
+    peer.session = None;
+    peer.key_exchange = None;
+    peer.key_pending = None;
+}
+
+impl PeerMeta {
+    /// Whether `addr` is advertised for the peer's `protocol` path
+    fn knows_addr(&self, addr: SocketAddr, protocol: Protocol) -> bool {
         match protocol {
-            Protocol::Stun => peer.stun_addr = new_addr,
-            Protocol::Ipv6 => peer.remote_addr = new_addr,
+            Protocol::Stun => *self.stun_addr.get() == Some(addr),
+            Protocol::Ipv6 => self.remote_addrs.iter().any(|a| *a.get() == addr),
         }
     }
-}
 
-#[derive(Debug, Clone, Copy)]
-enum Protocol {
-    Stun,
-    Ipv6,
+    /// Unanswered probe to the address of `protocol`
+    fn pending_mut(&mut self, protocol: Protocol) -> &mut Option<PendingControl> {
+        match protocol {
+            Protocol::Stun => &mut self.stun_pending,
+            Protocol::Ipv6 => &mut self.ipv6_pending,
+        }
+    }
 }
-impl std::fmt::Display for Protocol {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Protocol::Stun => write!(f, "STUN"),
-            Protocol::Ipv6 => write!(f, "IPv6"),
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::p2p::P2PBindMode;
+    use crate::codec::frame::{DataFrame, EchoFrame};
+    use crate::codec::magic::DEFAULT_MAGIC;
+    use crate::crypto::plain::PlainBlock;
+
+    fn peer_detail(identity: &str, private_ip: &str, ciders: &[&str]) -> PeerDetail {
+        PeerDetail {
+            name: identity.to_string(),
+            identity: identity.to_string(),
+            private_ip: private_ip.to_string(),
+            ciders: ciders.iter().map(|c| c.to_string()).collect(),
+            ipv6: vec!["2001:db8::2".to_string()],
+            port: 51258,
+            stun_ip: "203.0.113.7".to_string(),
+            stun_port: 40000,
+            nat_type: 0,
+            last_active: 0,
+        }
+    }
+
+    fn handler() -> PeerHandler {
+        let (new_frame_tx, _) = mpsc::channel(1);
+        let (outbound_tx, _) = mpsc::channel(1);
+        PeerHandler {
+            peers: PeerSet::new(),
+            block: Arc::new(Box::new(PlainBlock::new())),
+            magic: DEFAULT_MAGIC,
+            identity: "me".to_string(),
+            local_stun: Arc::new(RwLock::new(None)),
+            tap: None,
+            tx_api: PeerHandlerPrivateTxApi {
+                new_frame: NewFrameTx(new_frame_tx),
+                outbound_tx,
+                peer_queues: PeerQueues::new(PEER_QUEUE_SIZE),
+                events: mpsc::channel(1).0,
+            },
+        }
+    }
+
+    fn bindable(addrs: BoundAddrs) -> bool {
+        std::net::UdpSocket::bind(addrs.ipv6.unwrap()).is_ok()
+            && std::net::UdpSocket::bind(addrs.ipv4.unwrap()).is_ok()
+    }
+
+    /// Both sockets, the IPv6 one on `ipv6_port` (0 for any)
+    fn listen_on(ipv6_port: u16) -> UdpListen {
+        UdpListen {
+            mode: P2PBindMode::Dual,
+            ipv6_port,
+            stun_port: 0,
+            stun_socket: None,
+            ipv4_addr: None,
+            ipv6_addr: None,
+        }
+    }
+
+    /// Handler on ephemeral ports
+    fn start_handler(identity: &str, peers: Vec<PeerDetail>) -> PeerHandlerApi {
+        PeerHandler::start_peer_service(
+            Arc::new(Box::new(PlainBlock::new())),
+            DEFAULT_MAGIC,
+            identity.to_string(),
+            peers,
+            Arc::new(RwLock::new(None)),
+            None,
+            None,
+            listen_on(0),
+        )
+    }
+
+    /// Peer reachable over IPv6 loopback at `port`
+    fn loopback_peer(identity: &str, private_ip: &str, port: u16) -> PeerDetail {
+        PeerDetail {
+            ipv6: vec!["::1".to_string()],
+            port,
+            stun_ip: String::new(),
+            stun_port: 0,
+            ..peer_detail(identity, private_ip, &[])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_releases_ports() {
+        let api = start_handler("me", vec![peer_detail("b", "10.0.0.2", &[])]);
+        let addrs = api.local_addrs.get().await.unwrap();
+        assert_ne!(addrs.ipv6.unwrap().port(), 0);
+        assert_ne!(addrs.ipv4.unwrap().port(), 0);
+        assert!(!bindable(addrs));
+
+        api.shutdown.shutdown().await;
+        assert!(bindable(addrs));
+        assert!(api.send_frame.0.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_handlers_on_ephemeral_ports_exchange_data() {
+        let a = start_handler("a", vec![]);
+        let a_port = a.local_addrs.get().await.unwrap().ipv6.unwrap().port();
+        let b = start_handler("b", vec![]);
+        let b_port = b.local_addrs.get().await.unwrap().ipv6.unwrap().port();
+        assert_ne!(a_port, b_port);
+
+        a.new_peers
+            .0
+            .send(vec![loopback_peer("b", "10.0.0.2", b_port)])
+            .await
+            .unwrap();
+        a.get_status.get().await.unwrap();
+
+        // restart b on its port knowing a, its first probe goes out right away
+        b.shutdown.shutdown().await;
+        let mut b = PeerHandler::start_peer_service(
+            Arc::new(Box::new(PlainBlock::new())),
+            DEFAULT_MAGIC,
+            "b".to_string(),
+            vec![loopback_peer("a", "10.0.0.1", a_port)],
+            Arc::new(RwLock::new(None)),
+            None,
+            None,
+            listen_on(b_port),
+        );
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let status = a.get_status.get().await.unwrap();
+                if status.iter().any(|p| p.ipv6_last_active.is_some()) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("b should reach a over IPv6");
+
+        let frame = Frame::Data(DataFrame {
+            payload: vec![1, 2, 3],
+            seq: None,
+        });
+        a.send_frame
+            .0
+            .send(SendFrame {
+                frame,
+                dst: "10.0.0.2".to_string(),
+                tos: 0,
+            })
+            .await
+            .unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), b.new_frame.0.recv())
+            .await
+            .expect("b should receive the frame")
+            .unwrap();
+        match received {
+            Frame::Data(data) => assert_eq!(data.payload, vec![1, 2, 3]),
+            frame => panic!("unexpected frame {frame}"),
+        }
+
+        a.shutdown.shutdown().await;
+        b.shutdown.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_echo_answered_to_sender() {
+        let mut handler = handler();
+        let (outbound_tx, mut outbound_rx) = mpsc::channel(1);
+        handler.tx_api.outbound_tx = outbound_tx;
+        let block = PlainBlock::new();
+        let echo = Frame::Echo(EchoFrame {
+            id: 9,
+            sent_at: 1_700_000_000_000_009,
+            src: String::new(),
+            dst: String::new(),
+        });
+        let remote: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+
+        handler
+            .recv_frame((Parser::marshal(echo, &block).unwrap(), remote))
+            .await
+            .unwrap();
+
+        let (buf, dsts, _) = outbound_rx.try_recv().unwrap();
+        assert_eq!(dsts, vec![remote]);
+        match Parser::unmarshal(&buf, &block).unwrap().0 {
+            Frame::EchoReply(reply) => {
+                assert_eq!(reply.id, 9);
+                assert_eq!(reply.sent_at, 1_700_000_000_000_009);
+            }
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    /// Destinations of the packets queued on `rx`
+    fn sent_to(rx: &mut mpsc::Receiver<OutboundPacket>) -> Vec<SocketAddr> {
+        let mut dsts = Vec::new();
+        while let Ok((_, packet_dsts, _)) = rx.try_recv() {
+            dsts.extend(packet_dsts);
+        }
+        dsts
+    }
+
+    #[tokio::test]
+    async fn test_probe_from_unknown_address_ignored() {
+        let mut handler = handler();
+        let (outbound_tx, mut outbound_rx) = mpsc::channel(8);
+        handler.tx_api.outbound_tx = outbound_tx;
+        handler.rewrite_peers(vec![peer_detail("b", "10.0.0.2", &[])]);
+        let block = PlainBlock::new();
+
+        // someone claiming to be b from addresses the server never gave us
+        for (frame, from) in [
+            (probe_frame("b", Protocol::Ipv6), "[2001:db8::66]:51258"),
+            (probe_frame("b", Protocol::Stun), "198.51.100.66:40000"),
+        ] {
+            let from: SocketAddr = from.parse().unwrap();
+            handler
+                .recv_frame((Parser::marshal(frame, &block).unwrap(), from))
+                .await
+                .unwrap();
+        }
+        let b = &handler.peers.peers["b"];
+        assert_eq!(b.remote_addrs.len(), 1);
+        assert!(!b.remote_addr().is_fresh());
+        assert_eq!(
+            *b.stun_addr.get(),
+            Some("203.0.113.7:40000".parse().unwrap())
+        );
+        assert!(!b.stun_addr.is_fresh());
+        assert!(sent_to(&mut outbound_rx).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lost_probe_retransmitted() {
+        let mut handler = handler();
+        let (outbound_tx, mut outbound_rx) = mpsc::channel(8);
+        handler.tx_api.outbound_tx = outbound_tx;
+        handler.rewrite_peers(vec![peer_detail("b", "10.0.0.2", &[])]);
+        let ipv6: SocketAddr = "[2001:db8::2]:51258".parse().unwrap();
+        let stun: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+
+        // the first round of probes is lost
+        handler.send_probes().await;
+        assert_eq!(sent_to(&mut outbound_rx), vec![ipv6, stun]);
+        handler.retransmit_control().await;
+        assert!(sent_to(&mut outbound_rx).is_empty());
+
+        tokio::time::advance(CONTROL_RETRY_BACKOFF).await;
+        handler.retransmit_control().await;
+        let mut resent = sent_to(&mut outbound_rx);
+        resent.sort();
+        assert_eq!(resent, vec![stun, ipv6]);
+
+        // b answers the retransmit, long before the next keepalive round
+        let probe = Frame::ProbeIPv6(ProbeIPv6Frame {
+            identity: "b".to_string(),
+        });
+        let block = PlainBlock::new();
+        handler
+            .recv_frame((Parser::marshal(probe, &block).unwrap(), ipv6))
+            .await
+            .unwrap();
+        assert!(handler.peers.peers["b"].remote_addr().is_fresh());
+        // first contact is answered so b can reach us too
+        let (buf, dsts, _) = outbound_rx.try_recv().unwrap();
+        assert_eq!(dsts, vec![ipv6]);
+        assert!(matches!(
+            Parser::unmarshal(&buf, &block).unwrap().0,
+            Frame::ProbeIPv6(probe) if probe.identity == "me"
+        ));
+
+        // only the unanswered hole punch keeps being retried, then given up
+        let mut stun_retries = 1;
+        for _ in 0..10 {
+            tokio::time::advance(CONTROL_RETRY_BACKOFF * 4).await;
+            handler.retransmit_control().await;
+            let resent = sent_to(&mut outbound_rx);
+            assert!(resent.iter().all(|dst| *dst == stun));
+            stun_retries += resent.len() as u32;
+        }
+        assert_eq!(stun_retries, CONTROL_RETRIES);
+        assert!(handler.peers.peers["b"].stun_pending.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_key_exchange_derives_session_key() {
+        let block = PlainBlock::new();
+        let a_addr: SocketAddr = "[::1]:1001".parse().unwrap();
+        let b_addr: SocketAddr = "[::1]:1002".parse().unwrap();
+        let probe = |identity: &str| {
+            let probe = Frame::ProbeIPv6(ProbeIPv6Frame {
+                identity: identity.to_string(),
+            });
+            Parser::marshal(probe, &block).unwrap()
+        };
+
+        let mut a = handler();
+        a.identity = "a".to_string();
+        a.rewrite_peers(vec![loopback_peer("b", "10.0.0.2", 1002)]);
+        let (a_tx, mut a_out) = mpsc::channel(8);
+        a.tx_api.outbound_tx = a_tx;
+
+        let mut b = handler();
+        b.identity = "b".to_string();
+        b.rewrite_peers(vec![loopback_peer("a", "10.0.0.1", 1001)]);
+        let (b_tx, mut b_out) = mpsc::channel(8);
+        b.tx_api.outbound_tx = b_tx;
+        let (frame_tx, mut b_frames) = mpsc::channel(8);
+        b.tx_api.new_frame = NewFrameTx(frame_tx);
+
+        // a hears from b first, answers and starts the exchange as the
+        // lower identity
+        a.recv_frame((probe("b"), b_addr)).await.unwrap();
+        let (answer, _, _) = a_out.try_recv().unwrap();
+        let (init, dsts, _) = a_out.try_recv().unwrap();
+        assert_eq!(dsts, vec![b_addr]);
+        assert!(matches!(
+            Parser::unmarshal(&init, &block).unwrap().0,
+            Frame::P2PKeyInit(_)
+        ));
+
+        b.recv_frame((answer, a_addr)).await.unwrap();
+        // b only answers probes, it leaves the exchange to a
+        assert!(matches!(
+            Parser::unmarshal(&b_out.try_recv().unwrap().0, &block)
+                .unwrap()
+                .0,
+            Frame::ProbeIPv6(_)
+        ));
+        assert!(b_out.try_recv().is_err());
+
+        b.recv_frame((init, a_addr)).await.unwrap();
+        let (reply, dsts, _) = b_out.try_recv().unwrap();
+        assert_eq!(dsts, vec![a_addr]);
+        assert!(b.peers.peers["a"].session.is_some());
+        a.recv_frame((reply, b_addr)).await.unwrap();
+        assert!(a.peers.peers["b"].session.is_some());
+        assert!(a.peers.peers["b"].key_pending.is_none());
+
+        // data now travels under the session key, not the shared one
+        let data = Frame::Data(DataFrame {
+            payload: vec![10, 20, 30],
+            seq: None,
+        });
+        a.send_frame(data, "10.0.0.2", 0).await.unwrap();
+        let (sealed, _, _) = a.tx_api.peer_queues.try_pop().unwrap();
+        assert!(matches!(
+            Parser::unmarshal(&sealed, &block).unwrap().0,
+            Frame::Data(d) if d.payload != vec![10, 20, 30]
+        ));
+        b.recv_frame((sealed, a_addr)).await.unwrap();
+        match b_frames.try_recv().unwrap() {
+            Frame::Data(data) => assert_eq!(data.payload, vec![10, 20, 30]),
+            frame => panic!("unexpected frame {frame}"),
+        }
+
+        // with the session key in use the shared one no longer opens data
+        let plain = || {
+            let plain = Frame::Data(DataFrame {
+                payload: vec![7],
+                seq: None,
+            });
+            Parser::marshal(plain, &block).unwrap()
+        };
+        assert!(b.recv_frame((plain(), a_addr)).await.is_err());
+        assert!(b_frames.try_recv().is_err());
+        assert!(b.peers.peers["a"].session.is_some());
+
+        // a restarted peer seals with the shared key until a new session
+        // is agreed, the stale one is dropped after repeated failures
+        for _ in 1..MAX_SESSION_FAILURES {
+            assert!(b.recv_frame((plain(), a_addr)).await.is_err());
+        }
+        assert!(b.peers.peers["a"].session.is_none());
+        b.recv_frame((plain(), a_addr)).await.unwrap();
+        assert!(matches!(b_frames.try_recv().unwrap(), Frame::Data(d) if d.payload == vec![7]));
+    }
+
+    #[tokio::test]
+    async fn test_shared_key_accepted_until_session_confirmed() {
+        let block = PlainBlock::new();
+        let a_addr: SocketAddr = "[::1]:1001".parse().unwrap();
+        let mut b = handler();
+        b.identity = "b".to_string();
+        b.rewrite_peers(vec![loopback_peer("a", "10.0.0.1", 1001)]);
+        let (b_tx, _b_out) = mpsc::channel(8);
+        b.tx_api.outbound_tx = b_tx;
+        let (frame_tx, mut b_frames) = mpsc::channel(8);
+        b.tx_api.new_frame = NewFrameTx(frame_tx);
+        let pair = KeyPair::generate();
+        let init = Frame::P2PKeyInit(P2PKeyFrame {
+            identity: "a".to_string(),
+            public_key: pair.public,
+            max_version: MAX_VERSION,
+        });
+        b.recv_frame((Parser::marshal(init, &block).unwrap(), a_addr))
+            .await
+            .unwrap();
+        assert!(b.peers.peers["a"].session.is_some());
+
+        // the reply may not have reached a yet
+        let plain = Frame::Data(DataFrame {
+            payload: vec![7],
+            seq: None,
+        });
+        b.recv_frame((Parser::marshal(plain, &block).unwrap(), a_addr))
+            .await
+            .unwrap();
+        assert!(matches!(b_frames.try_recv().unwrap(), Frame::Data(d) if d.payload == vec![7]));
+
+        // a moving drops the session
+        b.insert_or_update(vec![loopback_peer("a", "10.0.0.1", 1003)]);
+        assert!(b.peers.peers["a"].session.is_none());
+    }
+
+    #[test]
+    fn test_rewrite_peers_replaces_peer_set() {
+        let mut handler = handler();
+        handler.rewrite_peers(vec![
+            peer_detail("a", "10.0.0.2", &["192.168.1.0/24"]),
+            peer_detail("b", "10.0.0.3", &[]),
+        ]);
+        let a = handler.peers.find_peer_by_ip_locked("192.168.1.9").unwrap();
+        assert_eq!(a.identity, "a");
+        assert_eq!(
+            *a.remote_addr().get(),
+            Some("[2001:db8::2]:51258".parse().unwrap())
+        );
+        assert_eq!(
+            *a.stun_addr.get(),
+            Some("203.0.113.7:40000".parse().unwrap())
+        );
+
+        handler.rewrite_peers(vec![peer_detail("b", "10.0.0.3", &[])]);
+        assert!(
+            handler
+                .peers
+                .find_peer_by_ip_locked("192.168.1.9")
+                .is_none()
+        );
+        assert_eq!(
+            handler
+                .peers
+                .find_peer_by_ip_locked("10.0.0.3")
+                .unwrap()
+                .identity,
+            "b"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_symmetric_peers_probed_less_often() {
+        let mut handler = handler();
+        let (outbound_tx, mut outbound_rx) = mpsc::channel(8);
+        handler.tx_api.outbound_tx = outbound_tx;
+        *handler.local_stun.write().unwrap() = Some(StunAddr {
+            ip: "198.51.100.7".to_string(),
+            port: 40123,
+            nat_type: NatType::Symmetric.into(),
+        });
+        let mut symmetric = peer_detail("a", "10.0.0.2", &[]);
+        symmetric.ipv6 = vec![];
+        symmetric.stun_ip = "203.0.113.2".to_string();
+        symmetric.nat_type = NatType::Symmetric.into();
+        let mut full_cone = peer_detail("b", "10.0.0.3", &[]);
+        full_cone.ipv6 = vec![];
+        full_cone.stun_ip = "203.0.113.3".to_string();
+        full_cone.nat_type = NatType::FullCone.into();
+        handler.rewrite_peers(vec![symmetric, full_cone]);
+
+        let symmetric_addr: SocketAddr = "203.0.113.2:40000".parse().unwrap();
+        let full_cone_addr: SocketAddr = "203.0.113.3:40000".parse().unwrap();
+        // symmetric <-> symmetric is below the threshold, symmetric <-> full cone is not
+        assert!(
+            NatType::Symmetric.hole_punch_success_rate(&NatType::Symmetric)
+                < MIN_HOLE_PUNCH_SUCCESS_RATE
+        );
+        let mut sent = || {
+            let mut dsts = sent_to(&mut outbound_rx);
+            dsts.sort();
+            dsts
+        };
+
+        // the first round tries everyone, the next ones spare the symmetric peer
+        handler.send_probes().await;
+        assert_eq!(sent(), vec![symmetric_addr, full_cone_addr]);
+        for _ in 1..6 {
+            tokio::time::advance(KEEPALIVE_INTERVAL).await;
+            handler.send_probes().await;
+            assert_eq!(sent(), vec![full_cone_addr]);
         }
+        tokio::time::advance(UNLIKELY_PUNCH_INTERVAL - 5 * KEEPALIVE_INTERVAL).await;
+        handler.send_probes().await;
+        assert_eq!(sent(), vec![symmetric_addr, full_cone_addr]);
+
+        // from behind a full cone NAT both peers are worth probing every round
+        assert_eq!(handler.peers.hole_punch_addrs(NatType::FullCone).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_responsive_ipv6_address_used() {
+        let mut handler = handler();
+        let (outbound_tx, mut outbound_rx) = mpsc::channel(8);
+        handler.tx_api.outbound_tx = outbound_tx;
+        let mut peer = peer_detail("b", "10.0.0.2", &[]);
+        peer.ipv6 = vec!["2001:db8::2".to_string(), "2001:db8::3".to_string()];
+        peer.stun_ip = String::new();
+        handler.rewrite_peers(vec![peer]);
+        let silent: SocketAddr = "[2001:db8::2]:51258".parse().unwrap();
+        let responsive: SocketAddr = "[2001:db8::3]:51258".parse().unwrap();
+
+        // every advertised address is probed
+        handler.send_probes().await;
+        assert_eq!(sent_to(&mut outbound_rx), vec![silent, responsive]);
+
+        // only the second one answers
+        let probe = Frame::ProbeIPv6(ProbeIPv6Frame {
+            identity: "b".to_string(),
+        });
+        let block = PlainBlock::new();
+        handler
+            .recv_frame((Parser::marshal(probe, &block).unwrap(), responsive))
+            .await
+            .unwrap();
+        assert_eq!(sent_to(&mut outbound_rx), vec![responsive]);
+
+        let data = Frame::Data(DataFrame {
+            payload: vec![0x45; 20],
+            seq: None,
+        });
+        handler.send_frame(data, "10.0.0.2", 0).await.unwrap();
+        let (_, dsts, _) = handler.tx_api.peer_queues.try_pop().unwrap();
+        assert_eq!(dsts, vec![responsive]);
+
+        // the answering address stays live when b advertises its
+        // addresses again in another order
+        let mut peer = peer_detail("b", "10.0.0.2", &[]);
+        peer.ipv6 = vec!["2001:db8::3".to_string(), "2001:db8::2".to_string()];
+        peer.stun_ip = String::new();
+        handler.insert_or_update(vec![peer]);
+        assert_eq!(
+            *handler.peers.peers["b"].remote_addr().get(),
+            Some(responsive)
+        );
+        assert!(handler.peers.peers["b"].remote_addr().is_fresh());
+    }
+
+    #[tokio::test]
+    async fn test_only_most_active_peers_probed() {
+        let mut handler = handler();
+        handler.peers = PeerSet::new().with_max_active(Some(2));
+        let (outbound_tx, mut outbound_rx) = mpsc::channel(4);
+        handler.tx_api.outbound_tx = outbound_tx;
+        for (i, identity) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            let mut peer = peer_detail(identity, &format!("10.0.0.{}", i + 2), &[]);
+            peer.ipv6 = vec![format!("2001:db8::{}", i + 2)];
+            handler.peers.add_peer(peer);
+        }
+
+        // traffic from c, then to a: both outrank the idle b and d
+        let c: SocketAddr = "[2001:db8::4]:51258".parse().unwrap();
+        let data = Frame::Data(crate::codec::frame::DataFrame {
+            payload: vec![0x45; 20],
+            seq: None,
+        });
+        let block = PlainBlock::new();
+        handler
+            .recv_frame((Parser::marshal(data.clone(), &block).unwrap(), c))
+            .await
+            .unwrap();
+        let _ = handler.send_frame(data, "10.0.0.2", 0).await;
+
+        handler.send_probes().await;
+        let (_, mut probed, _) = outbound_rx.try_recv().unwrap();
+        probed.sort();
+        let expected: Vec<SocketAddr> = vec![
+            "[2001:db8::2]:51258".parse().unwrap(),
+            "[2001:db8::4]:51258".parse().unwrap(),
+        ];
+        assert_eq!(probed, expected);
+    }
+
+    #[test]
+    fn test_relay_reconnect_keeps_live_peer() {
+        let mut handler = handler();
+        handler.rewrite_peers(vec![peer_detail("b", "10.0.0.2", &[])]);
+        let ipv6: SocketAddr = "[2001:db8::2]:51258".parse().unwrap();
+        let stun: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        handler.peers.update_peer_active("b", ipv6, Protocol::Ipv6);
+        handler.peers.update_peer_active("b", stun, Protocol::Stun);
+        let last_active = |handler: &PeerHandler| {
+            let peer = &handler.peers.peers["b"];
+            (peer.remote_addrs[0].last_active, peer.stun_addr.last_active)
+        };
+        let before = last_active(&handler);
+        assert!(before.0.is_some() && before.1.is_some());
+
+        // the list of the fresh relay session names b at the same addresses
+        handler.insert_or_update(vec![peer_detail("b", "10.0.0.2", &[])]);
+        assert_eq!(last_active(&handler), before);
+        assert_eq!(
+            handler.peers.peers["b"].current_path(),
+            Some(Protocol::Ipv6)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backlog_to_one_peer_does_not_block_others() {
+        let mut handler = handler();
+        handler.tx_api.peer_queues = PeerQueues::new(4);
+        let mut c = peer_detail("c", "10.0.0.3", &[]);
+        c.ipv6 = vec!["2001:db8::3".to_string()];
+        handler.rewrite_peers(vec![peer_detail("b", "10.0.0.2", &[]), c]);
+        let b_addr: SocketAddr = "[2001:db8::2]:51258".parse().unwrap();
+        let c_addr: SocketAddr = "[2001:db8::3]:51258".parse().unwrap();
+        handler
+            .peers
+            .update_peer_active("b", b_addr, Protocol::Ipv6);
+        handler
+            .peers
+            .update_peer_active("c", c_addr, Protocol::Ipv6);
+        let data = || {
+            Frame::Data(DataFrame {
+                payload: vec![0x45; 20],
+                seq: None,
+            })
+        };
+
+        // nothing drains the queues, b's fills up and drops the rest
+        for _ in 0..4 {
+            handler.send_frame(data(), "10.0.0.2", 0).await.unwrap();
+        }
+        handler.send_frame(data(), "10.0.0.2", 0).await.unwrap();
+        // c has a queue of its own
+        handler.send_frame(data(), "10.0.0.3", 0).await.unwrap();
+
+        let status = |handler: &PeerHandler, identity: &str| {
+            handler
+                .get_status()
+                .into_iter()
+                .find(|peer| peer.identity == identity)
+                .unwrap()
+        };
+        let depth = |handler: &PeerHandler, identity: &str| status(handler, identity).queue_depth;
+        assert_eq!(depth(&handler, "b"), 4);
+        assert_eq!(depth(&handler, "c"), 1);
+        assert_eq!(status(&handler, "b").queue_dropped, 1);
+        assert_eq!(status(&handler, "c").queue_dropped, 0);
+
+        // c's frame is not behind b's backlog
+        let drained: Vec<_> = std::iter::from_fn(|| handler.tx_api.peer_queues.try_pop())
+            .map(|(_, dsts, _)| dsts[0])
+            .collect();
+        assert_eq!(drained, vec![b_addr, c_addr, b_addr, b_addr, b_addr]);
+        assert_eq!(depth(&handler, "b"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_peer_path_transitions() {
+        let mut handler = handler();
+        let (events_tx, mut events_rx) = mpsc::channel(16);
+        handler.tx_api.events = events_tx;
+        handler.peers.add_peer(peer_detail("b", "10.0.0.2", &[]));
+        let ipv6: SocketAddr = "[2001:db8::2]:51258".parse().unwrap();
+        let stun: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let stale = || Instant::now().checked_sub(CONNECTION_TIMEOUT * 2);
+        let identity = "b".to_string();
+
+        handler.peers.update_peer_active("b", ipv6, Protocol::Ipv6);
+        handler.report_paths();
+        assert_eq!(
+            events_rx.try_recv().unwrap(),
+            PeerEvent::Connected {
+                identity: identity.clone(),
+                transport: Protocol::Ipv6
+            }
+        );
+
+        // IPv6 is still preferred, nothing changes for the data path
+        handler.peers.update_peer_active("b", stun, Protocol::Stun);
+        handler.report_paths();
+        assert!(events_rx.try_recv().is_err());
+
+        handler.peers.peers.get_mut("b").unwrap().remote_addrs[0].last_active = stale();
+        handler.report_paths();
+        assert_eq!(
+            events_rx.try_recv().unwrap(),
+            PeerEvent::PathChanged {
+                identity: identity.clone(),
+                from: Protocol::Ipv6,
+                to: Protocol::Stun
+            }
+        );
+
+        handler
+            .peers
+            .peers
+            .get_mut("b")
+            .unwrap()
+            .stun_addr
+            .last_active = stale();
+        handler.report_paths();
+        assert_eq!(
+            events_rx.try_recv().unwrap(),
+            PeerEvent::Disconnected {
+                identity: identity.clone()
+            }
+        );
+        handler.report_paths();
+        assert!(events_rx.try_recv().is_err());
+
+        handler.peers.update_peer_active("b", stun, Protocol::Stun);
+        handler.report_paths();
+        assert_eq!(
+            events_rx.try_recv().unwrap(),
+            PeerEvent::Connected {
+                identity,
+                transport: Protocol::Stun
+            }
+        );
     }
 }
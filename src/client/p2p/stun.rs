@@ -4,9 +4,44 @@
 //! to discover the client's public IP address and NAT type, which is essential for
 //! P2P connection establishment.
 
+use crate::utils::StunAddr;
 use anyhow::{Context, Result};
-use std::net::{IpAddr, SocketAddr};
+use async_trait::async_trait;
+use chacha20poly1305::aead::{OsRng, rand_core::RngCore};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+/// First retry delay after a failed re-discovery
+const MIN_REFRESH_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Delay before an unanswered binding request is sent again
+const REQUEST_RETRY: Duration = Duration::from_millis(500);
+
+/// STUN message header fields and attributes (RFC 5389)
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+type TransactionId = [u8; 12];
+
+/// Source of the client's public address
+#[async_trait]
+pub trait StunProvider: Send + Sync {
+    /// Discover the public mapping of `socket`
+    async fn discover(&self, socket: &StunSocket) -> Result<StunDiscoveryResult>;
+
+    /// Servers queried, in "host:port" format, to keep out of the tunnel
+    fn servers(&self) -> &[String] {
+        &[]
+    }
+}
 
 /// NAT type classifications based on RFC 3489 and RFC 5780
 ///
@@ -71,6 +106,33 @@ impl NatType {
     }
 }
 
+/// Wire code of a NAT type, as carried in keepalives and peer details
+impl From<NatType> for u8 {
+    fn from(nat_type: NatType) -> u8 {
+        match nat_type {
+            NatType::Unknown => 0,
+            NatType::OpenInternet => 1,
+            NatType::FullCone => 2,
+            NatType::RestrictedCone => 3,
+            NatType::PortRestricted => 4,
+            NatType::Symmetric => 5,
+        }
+    }
+}
+
+impl From<u8> for NatType {
+    fn from(code: u8) -> NatType {
+        match code {
+            1 => NatType::OpenInternet,
+            2 => NatType::FullCone,
+            3 => NatType::RestrictedCone,
+            4 => NatType::PortRestricted,
+            5 => NatType::Symmetric,
+            _ => NatType::Unknown,
+        }
+    }
+}
+
 /// Result of STUN discovery containing public address and NAT information
 #[derive(Debug, Clone)]
 pub struct StunDiscoveryResult {
@@ -92,6 +154,15 @@ impl StunDiscoveryResult {
     pub fn public_addr(&self) -> SocketAddr {
         SocketAddr::new(self.public_ip, self.public_port)
     }
+
+    /// The mapping as advertised to the relay server
+    pub fn stun_addr(&self) -> StunAddr {
+        StunAddr {
+            ip: self.public_ip.to_string(),
+            port: self.public_port,
+            nat_type: self.nat_type.into(),
+        }
+    }
 }
 
 /// STUN client for NAT discovery and public address resolution
@@ -141,6 +212,11 @@ impl StunClient {
         }
     }
 
+    /// STUN servers queried, in "host:port" format
+    pub fn servers(&self) -> &[String] {
+        &self.stun_servers
+    }
+
     /// Sets the timeout for STUN requests
     ///
     /// # Arguments
@@ -157,34 +233,28 @@ impl StunClient {
     /// until one succeeds.
     ///
     /// # Arguments
-    /// * `local_port` - Local UDP port to bind to (use 0 for automatic)
+    /// * `socket` - Socket whose public mapping to discover
     ///
     /// # Returns
-    /// * `Ok((IpAddr, u16))` - Public IP and port
+    /// * `Ok((SocketAddr, IpAddr, u16))` - Local address, public IP and port
     /// * `Err` - If all STUN servers fail or timeout
     ///
     /// # Example
     /// ```rust,ignore
-    /// let (public_ip, public_port) = stun_client
-    ///     .discover_public_address(0)
+    /// let (_, public_ip, public_port) = stun_client
+    ///     .discover_public_address(&StunSocket::bind(0)?)
     ///     .await?;
     /// println!("Public address: {}:{}", public_ip, public_port);
     /// ```
     pub async fn discover_public_address(
         &self,
-        local_port: u16,
+        socket: &StunSocket,
     ) -> Result<(SocketAddr, IpAddr, u16)> {
-        let local_addr = if local_port == 0 {
-            "0.0.0.0:0"
-        } else {
-            &format!("0.0.0.0:{}", local_port)
-        };
-
         // Try each STUN server until one succeeds
         for stun_server in &self.stun_servers {
             tracing::debug!("Querying STUN server: {}", stun_server);
 
-            match self.query_stun_server(local_addr, stun_server).await {
+            match self.query_stun_server(socket, stun_server).await {
                 Ok((local, ip, port)) => {
                     tracing::info!(
                         "STUN discovery successful via {}: {}:{}",
@@ -211,7 +281,8 @@ impl StunClient {
     /// 2. NAT type detection using RFC 5780 tests
     ///
     /// # Arguments
-    /// * `local_port` - Local UDP port to bind to
+    /// * `socket` - Socket whose public mapping to discover, the one peers
+    ///   punch to
     ///
     /// # Returns
     /// * `Ok(StunDiscoveryResult)` - Complete discovery result
@@ -219,14 +290,14 @@ impl StunClient {
     ///
     /// # Example
     /// ```rust,ignore
-    /// let result = stun_client.discover(51258).await?;
+    /// let result = stun_client.discover(&StunSocket::bind(51259)?).await?;
     /// println!("Public: {}", result.public_addr());
     /// println!("NAT Type: {:?}", result.nat_type);
     /// ```
-    pub async fn discover(&self, local_port: u16) -> Result<StunDiscoveryResult> {
+    pub async fn discover(&self, socket: &StunSocket) -> Result<StunDiscoveryResult> {
         // Step 1: Discover public address
         let (local_addr, public_ip, public_port) = self
-            .discover_public_address(local_port)
+            .discover_public_address(socket)
             .await
             .context("Failed to discover public address")?;
 
@@ -257,23 +328,14 @@ impl StunClient {
         })
     }
 
-    /// Queries a single STUN server using the stunclient library
+    /// Queries a single STUN server from `socket`
     async fn query_stun_server(
         &self,
-        local_addr: &str,
+        socket: &StunSocket,
         stun_server: &str,
     ) -> Result<(SocketAddr, IpAddr, u16)> {
-        use std::net::UdpSocket;
-
-        // Create UDP socket
-        let socket = UdpSocket::bind(local_addr).context("Failed to bind UDP socket")?;
-        let local_addr = socket.local_addr()?;
-        // Set socket timeout
-        socket
-            .set_read_timeout(Some(self.timeout))
-            .context("Failed to set socket timeout")?;
-
-        // Resolve STUN server address (may be hostname or IP)
+        // Resolve STUN server address (may be hostname or IP), the socket
+        // is IPv4 so only an IPv4 server can answer it
         let server_addr: SocketAddr = if let Ok(addr) = stun_server.parse() {
             // Already a valid SocketAddr
             addr
@@ -284,20 +346,22 @@ impl StunClient {
                 .await
                 .context("Failed to resolve STUN server hostname")?;
             addrs
-                .next()
-                .context("No addresses resolved for STUN server")?
+                .find(SocketAddr::is_ipv4)
+                .context("No IPv4 addresses resolved for STUN server")?
         };
+        anyhow::ensure!(
+            server_addr.is_ipv4(),
+            "STUN server {server_addr} is not reachable from the IPv4 P2P socket"
+        );
 
-        // Create STUN client
-        let stun_client = stunclient::StunClient::new(server_addr);
-
-        // Query external address
-        let external_addr =
-            tokio::task::spawn_blocking(move || stun_client.query_external_address(&socket))
-                .await
-                .context("STUN query task panicked")?
-                .context("Failed to get external address")?;
+        let external_addr = socket
+            .query(server_addr, self.timeout)
+            .await
+            .context("Failed to get external address")?;
 
+        // the socket listens on all interfaces, NAT detection compares the
+        // address of the one towards the server
+        let local_addr = SocketAddr::new(outbound_ip(server_addr)?, socket.local_addr()?.port());
         Ok((local_addr, external_addr.ip(), external_addr.port()))
     }
 
@@ -362,8 +426,325 @@ impl Default for StunClient {
     }
 }
 
+#[async_trait]
+impl StunProvider for StunClient {
+    async fn discover(&self, socket: &StunSocket) -> Result<StunDiscoveryResult> {
+        StunClient::discover(self, socket).await
+    }
+
+    fn servers(&self) -> &[String] {
+        StunClient::servers(self)
+    }
+}
+
+/// Provider reporting a fixed mapping without any network I/O
+///
+/// Makes the P2P setup path deterministic in tests.
+#[derive(Debug, Clone)]
+pub struct StaticStun {
+    result: StunDiscoveryResult,
+}
+
+impl StaticStun {
+    pub fn new(result: StunDiscoveryResult) -> Self {
+        Self { result }
+    }
+}
+
+#[async_trait]
+impl StunProvider for StaticStun {
+    async fn discover(&self, _socket: &StunSocket) -> Result<StunDiscoveryResult> {
+        Ok(self.result.clone())
+    }
+}
+
+/// IPv4 UDP socket shared by STUN discovery and P2P hole punching
+///
+/// Behind a NAT with port-dependent mapping only the mapping discovered
+/// from the punching socket itself is the one peers can reach. Binding
+/// answers are matched to their query by transaction id: while the P2P
+/// server reads the socket it hands them over through `take_response`,
+/// otherwise the query reads the socket itself.
+#[derive(Debug, Clone)]
+pub struct StunSocket {
+    inner: Arc<SharedSocket>,
+}
+
+#[derive(Debug)]
+struct SharedSocket {
+    socket: UdpSocket,
+    /// Queries waiting for their binding answer
+    pending: Mutex<HashMap<TransactionId, oneshot::Sender<SocketAddr>>>,
+    /// Whether the P2P server reads the socket
+    served: AtomicBool,
+}
+
+impl StunSocket {
+    /// Bind `0.0.0.0:port` with `SO_REUSEADDR` (0 for any)
+    pub fn bind(port: u16) -> Result<Self> {
+        Self::bind_addr(Ipv4Addr::UNSPECIFIED, port)
+    }
+
+    /// Bind `ip:port` with `SO_REUSEADDR` (port 0 for any)
+    pub fn bind_addr(ip: Ipv4Addr, port: u16) -> Result<Self> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((ip, port)).into())?;
+        Ok(Self {
+            inner: Arc::new(SharedSocket {
+                socket: UdpSocket::from_std(socket.into())?,
+                pending: Mutex::new(HashMap::new()),
+                served: AtomicBool::new(false),
+            }),
+        })
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.inner.socket
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    /// Public address of the socket as seen by `server`
+    ///
+    /// The binding request is resent every 500ms until answered or `timeout`
+    /// passes.
+    pub async fn query(&self, server: SocketAddr, timeout: Duration) -> Result<SocketAddr> {
+        let mut id = TransactionId::default();
+        OsRng.fill_bytes(&mut id);
+        let (answer_tx, answer_rx) = oneshot::channel();
+        self.pending().insert(id, answer_tx);
+        let result = tokio::time::timeout(timeout, self.exchange(server, id, answer_rx)).await;
+        self.pending().remove(&id);
+        result.map_err(|_| anyhow::anyhow!("STUN server {server} did not answer"))?
+    }
+
+    async fn exchange(
+        &self,
+        server: SocketAddr,
+        id: TransactionId,
+        mut answer_rx: oneshot::Receiver<SocketAddr>,
+    ) -> Result<SocketAddr> {
+        let request = binding_request(&id);
+        let mut retry = tokio::time::interval(REQUEST_RETRY);
+        let mut buf = [0u8; 512];
+        loop {
+            let served = self.inner.served.load(Ordering::Relaxed);
+            tokio::select! {
+                _ = retry.tick() => {
+                    self.inner.socket.send_to(&request, server).await?;
+                }
+                answer = &mut answer_rx => return Ok(answer?),
+                // nobody else reads the socket, other traffic is dropped
+                received = self.inner.socket.recv_from(&mut buf), if !served => {
+                    let (len, _) = received?;
+                    self.take_response(&buf[..len]);
+                }
+            }
+        }
+    }
+
+    /// Hand a received binding answer to the query waiting for it
+    ///
+    /// # Returns
+    /// `true` if `packet` answered a pending query and was consumed
+    pub fn take_response(&self, packet: &[u8]) -> bool {
+        let Some((id, mapped)) = parse_binding_response(packet) else {
+            return false;
+        };
+        match self.pending().remove(&id) {
+            Some(answer_tx) => {
+                let _ = answer_tx.send(mapped);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Leave reading the socket to the P2P server until the guard is dropped
+    pub(crate) fn serve(&self) -> ServedGuard {
+        self.inner.served.store(true, Ordering::Relaxed);
+        ServedGuard(self.clone())
+    }
+
+    fn pending(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<TransactionId, oneshot::Sender<SocketAddr>>> {
+        self.inner.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Marks a `StunSocket` read by the P2P server
+pub(crate) struct ServedGuard(StunSocket);
+
+impl Drop for ServedGuard {
+    fn drop(&mut self) {
+        self.0.inner.served.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Local address of the interface packets to `server` leave through
+fn outbound_ip(server: SocketAddr) -> Result<IpAddr> {
+    let probe = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    probe.connect(server)?;
+    Ok(probe.local_addr()?.ip())
+}
+
+fn binding_request(id: &TransactionId) -> [u8; 20] {
+    let mut request = [0u8; 20];
+    request[0..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request[8..20].copy_from_slice(id);
+    request
+}
+
+/// Transaction id and mapped address of a binding success response
+fn parse_binding_response(msg: &[u8]) -> Option<(TransactionId, SocketAddr)> {
+    if msg.len() < 20
+        || msg[0..2] != BINDING_SUCCESS.to_be_bytes()
+        || msg[4..8] != MAGIC_COOKIE.to_be_bytes()
+    {
+        return None;
+    }
+    let id: TransactionId = msg[8..20].try_into().ok()?;
+    let len = u16::from_be_bytes([msg[2], msg[3]]) as usize;
+    let mut attrs = msg.get(20..20 + len)?;
+    let mut mapped = None;
+    while attrs.len() >= 4 {
+        let kind = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let value_len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs.get(4..4 + value_len)?;
+        match kind {
+            ATTR_XOR_MAPPED_ADDRESS => return Some((id, decode_address(value, Some(&id))?)),
+            ATTR_MAPPED_ADDRESS => mapped = decode_address(value, None),
+            _ => {}
+        }
+        // attributes are padded to 4 bytes
+        attrs = attrs
+            .get((4 + value_len).next_multiple_of(4)..)
+            .unwrap_or_default();
+    }
+    Some((id, mapped?))
+}
+
+/// Decode a MAPPED-ADDRESS value, or an XOR-MAPPED-ADDRESS one of the
+/// transaction `xor_id`
+fn decode_address(value: &[u8], xor_id: Option<&TransactionId>) -> Option<SocketAddr> {
+    let mut key = [0u8; 16];
+    if let Some(id) = xor_id {
+        key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        key[4..].copy_from_slice(id);
+    }
+    let port = u16::from_be_bytes([value.get(2)? ^ key[0], value.get(3)? ^ key[1]]);
+    let ip = match value.get(1)? {
+        0x01 => {
+            let mut octets: [u8; 4] = value.get(4..8)?.try_into().ok()?;
+            octets.iter_mut().zip(key).for_each(|(b, k)| *b ^= k);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        0x02 => {
+            let mut octets: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            octets.iter_mut().zip(key).for_each(|(b, k)| *b ^= k);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Periodic STUN re-discovery
+///
+/// Keeps the advertised public address current and recovers it after a
+/// failed discovery, retrying with exponential backoff (from 5s up to
+/// `interval`) while discovery keeps failing.
+#[derive(Clone)]
+pub struct StunRefresh {
+    provider: Arc<dyn StunProvider>,
+    socket: StunSocket,
+    /// Delay between discoveries while they succeed
+    interval: Duration,
+    /// First retry delay after a failure
+    min_backoff: Duration,
+    /// Current retry delay
+    backoff: Duration,
+}
+
+impl StunRefresh {
+    pub fn new(provider: Arc<dyn StunProvider>, socket: StunSocket, interval: Duration) -> Self {
+        Self {
+            provider,
+            socket,
+            interval,
+            min_backoff: MIN_REFRESH_BACKOFF,
+            backoff: MIN_REFRESH_BACKOFF,
+        }
+    }
+
+    /// Sets the first retry delay after a failed discovery
+    pub fn with_min_backoff(mut self, min_backoff: Duration) -> Self {
+        self.min_backoff = min_backoff;
+        self.backoff = min_backoff;
+        self
+    }
+
+    /// Keep `stun` current until the process exits
+    ///
+    /// The first attempt follows the initial discovery by one `interval`
+    /// if that succeeded, by the minimum backoff otherwise. A failed
+    /// attempt keeps the last known address.
+    pub async fn run(mut self, stun: Arc<RwLock<Option<StunAddr>>>) {
+        let mut delay = if stun.read().unwrap_or_else(|e| e.into_inner()).is_some() {
+            self.interval
+        } else {
+            self.min_backoff
+        };
+        loop {
+            tokio::time::sleep(delay).await;
+            let (fresh, next) = self.refresh().await;
+            delay = next;
+            let Some(fresh) = fresh else {
+                continue;
+            };
+            let mut current = stun.write().unwrap_or_else(|e| e.into_inner());
+            if current.as_ref() != Some(&fresh) {
+                let prev = match current.as_ref() {
+                    Some(prev) => prev.to_string(),
+                    None => "None".to_string(),
+                };
+                tracing::info!("STUN address updated: {prev} -> {fresh}");
+                *current = Some(fresh);
+            }
+        }
+    }
+
+    /// Run one discovery
+    ///
+    /// # Returns
+    /// The discovered address (`None` on failure) and the delay before the
+    /// next attempt
+    pub async fn refresh(&mut self) -> (Option<StunAddr>, Duration) {
+        match self.provider.discover(&self.socket).await {
+            Ok(result) => {
+                self.backoff = self.min_backoff;
+                (Some(result.stun_addr()), self.interval)
+            }
+            Err(e) => {
+                let delay = self.backoff.min(self.interval);
+                tracing::warn!("STUN re-discovery failed, retrying in {delay:?}: {e:#}");
+                self.backoff = (self.backoff * 2).min(self.interval);
+                (None, delay)
+            }
+        }
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     #[test]
@@ -390,11 +771,126 @@ mod tests {
         assert!(NatType::FullCone.hole_punch_success_rate(&NatType::FullCone) > 0.9);
     }
 
+    /// Fails a fixed number of times, then reports 203.0.113.5:40000
+    struct FlakyProvider {
+        failures: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl StunProvider for FlakyProvider {
+        async fn discover(&self, socket: &StunSocket) -> Result<StunDiscoveryResult> {
+            if self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                anyhow::bail!("All STUN servers failed");
+            }
+            Ok(StunDiscoveryResult {
+                public_ip: "203.0.113.5".parse().unwrap(),
+                public_port: 40000,
+                nat_type: NatType::FullCone,
+                local_addr: socket.local_addr()?,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresh_backs_off_until_discovery_recovers() {
+        let provider = Arc::new(FlakyProvider { failures: 3.into() });
+        let socket = StunSocket::bind(0).unwrap();
+        let mut refresh = StunRefresh::new(provider, socket, Duration::from_secs(30))
+            .with_min_backoff(Duration::from_secs(10));
+
+        let mut delays = vec![];
+        for _ in 0..3 {
+            let (stun, delay) = refresh.refresh().await;
+            assert!(stun.is_none());
+            delays.push(delay);
+        }
+        // doubling, capped at the refresh interval
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_secs(10),
+                Duration::from_secs(20),
+                Duration::from_secs(30)
+            ]
+        );
+
+        let (stun, delay) = refresh.refresh().await;
+        let stun = stun.unwrap();
+        assert_eq!((stun.ip.as_str(), stun.port), ("203.0.113.5", 40000));
+        assert_eq!(delay, Duration::from_secs(30));
+        // the backoff starts over after a success
+        assert_eq!(refresh.backoff, Duration::from_secs(10));
+    }
+
+    /// Binding success answer mapping the request `id` to `mapped`
+    pub(crate) fn binding_response(id: &[u8], mapped: SocketAddr) -> Vec<u8> {
+        let SocketAddr::V4(mapped) = mapped else {
+            panic!("IPv4 mapping expected");
+        };
+        let cookie = MAGIC_COOKIE.to_be_bytes();
+        let mut msg = BINDING_SUCCESS.to_be_bytes().to_vec();
+        msg.extend(12u16.to_be_bytes());
+        msg.extend(cookie);
+        msg.extend(id);
+        msg.extend(ATTR_XOR_MAPPED_ADDRESS.to_be_bytes());
+        msg.extend(8u16.to_be_bytes());
+        msg.extend([0, 0x01]);
+        msg.extend((mapped.port() ^ 0x2112).to_be_bytes());
+        msg.extend(mapped.ip().octets().iter().zip(cookie).map(|(b, k)| b ^ k));
+        msg
+    }
+
+    /// STUN server answering each binding request with its source address
+    pub(crate) async fn fake_stun_server() -> SocketAddr {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, from)) = server.recv_from(&mut buf).await {
+                if len == 20 && buf[0..2] == BINDING_REQUEST.to_be_bytes() {
+                    let answer = binding_response(&buf[8..20], from);
+                    let _ = server.send_to(&answer, from).await;
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_parse_binding_response() {
+        let id = [7u8; 12];
+        let mapped: SocketAddr = "203.0.113.5:40000".parse().unwrap();
+        let msg = binding_response(&id, mapped);
+        assert_eq!(parse_binding_response(&msg), Some((id, mapped)));
+
+        // the request itself, truncated answers and data frames are no answer
+        assert_eq!(parse_binding_response(&binding_request(&id)), None);
+        assert_eq!(parse_binding_response(&msg[..msg.len() - 1]), None);
+        assert_eq!(parse_binding_response(&[0u8; 64]), None);
+    }
+
+    #[tokio::test]
+    async fn test_query_maps_socket() {
+        let server = fake_stun_server().await;
+        let socket = StunSocket::bind(0).unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        let mapped = socket.query(server, Duration::from_secs(2)).await.unwrap();
+        assert_eq!(mapped, SocketAddr::from(([127, 0, 0, 1], port)));
+        assert!(socket.pending().is_empty());
+    }
+
     #[tokio::test]
     #[ignore] // Requires network access
     async fn test_stun_discovery() {
         let client = StunClient::new();
-        let result = client.discover_public_address(0).await;
+        let result = client
+            .discover_public_address(&StunSocket::bind(0).unwrap())
+            .await;
 
         // This test is ignored by default as it requires internet access
         // Run with: cargo test test_stun_discovery -- --ignored
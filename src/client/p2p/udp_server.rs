@@ -1,6 +1,11 @@
-use std::net::SocketAddr;
+use crate::client::p2p::stun::StunSocket;
+use crate::client::p2p::{BoundAddrs, P2PBindMode};
+use std::collections::{HashMap, VecDeque};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
+use tokio::sync::{Notify, mpsc, watch};
+use tokio_util::sync::CancellationToken;
 
 /// UDP packet buffer size
 ///
@@ -9,6 +14,105 @@ use tokio::sync::mpsc;
 /// - Control frames (handshake, keepalive, etc.)
 const BUFFER_SIZE: usize = 2048;
 
+/// Encrypted frame, its destinations and the ToS / traffic class byte to
+/// send it with (0 for the default marking)
+pub(crate) type OutboundPacket = (Vec<u8>, Vec<SocketAddr>, u8);
+
+/// Packets queued per peer, its new ones are dropped once full
+pub(crate) const PEER_QUEUE_SIZE: usize = 256;
+
+/// Outbound packets queued per peer, drained in turn
+///
+/// On one shared channel a backlog to a congested peer delays the packets to
+/// every other. Each peer gets a bounded queue of its own instead, and `pop`
+/// takes one packet from each peer with packets queued in turn.
+#[derive(Clone)]
+pub(crate) struct PeerQueues {
+    inner: Arc<PeerQueuesInner>,
+}
+
+struct PeerQueuesInner {
+    queues: Mutex<FairQueues>,
+    ready: Notify,
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct FairQueues {
+    packets: HashMap<String, VecDeque<OutboundPacket>>,
+    /// Peers with packets queued, the next to send first
+    turns: VecDeque<String>,
+    /// Packets dropped per peer because its queue was full
+    dropped: HashMap<String, u64>,
+}
+
+impl PeerQueues {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(PeerQueuesInner {
+                queues: Mutex::new(FairQueues::default()),
+                ready: Notify::new(),
+                capacity: capacity.max(1),
+            }),
+        }
+    }
+
+    /// Queue `packet` to `peer`
+    ///
+    /// # Returns
+    /// `false` if the queue of `peer` is full and the packet was dropped
+    pub(crate) fn push(&self, peer: &str, packet: OutboundPacket) -> bool {
+        let mut queues = self.inner.queues.lock().unwrap_or_else(|e| e.into_inner());
+        let queue = queues.packets.entry(peer.to_string()).or_default();
+        if queue.len() >= self.inner.capacity {
+            *queues.dropped.entry(peer.to_string()).or_default() += 1;
+            return false;
+        }
+        queue.push_back(packet);
+        if queue.len() == 1 {
+            queues.turns.push_back(peer.to_string());
+        }
+        drop(queues);
+        self.inner.ready.notify_one();
+        true
+    }
+
+    /// Packets queued to `peer`
+    pub(crate) fn depth(&self, peer: &str) -> usize {
+        let queues = self.inner.queues.lock().unwrap_or_else(|e| e.into_inner());
+        queues.packets.get(peer).map_or(0, VecDeque::len)
+    }
+
+    /// Packets to `peer` dropped so far because its queue was full
+    pub(crate) fn dropped(&self, peer: &str) -> u64 {
+        let queues = self.inner.queues.lock().unwrap_or_else(|e| e.into_inner());
+        queues.dropped.get(peer).copied().unwrap_or(0)
+    }
+
+    /// Wait for the next packet, of the peer whose turn it is
+    async fn pop(&self) -> OutboundPacket {
+        loop {
+            if let Some(packet) = self.try_pop() {
+                return packet;
+            }
+            self.inner.ready.notified().await;
+        }
+    }
+
+    pub(crate) fn try_pop(&self) -> Option<OutboundPacket> {
+        let mut queues = self.inner.queues.lock().unwrap_or_else(|e| e.into_inner());
+        let peer = queues.turns.pop_front()?;
+        let queue = queues.packets.get_mut(&peer)?;
+        let packet = queue.pop_front();
+        if queue.is_empty() {
+            queues.packets.remove(&peer);
+        } else {
+            queues.turns.push_back(peer);
+        }
+        packet
+    }
+}
+
 /// Dual-stack UDP server for P2P communication
 ///
 /// This server manages two UDP sockets simultaneously:
@@ -56,15 +160,55 @@ pub struct UDPServer {
     ///
     /// PeerHandler sends encrypted packets through this channel.
     /// The server selects the appropriate socket based on destination address type.
-    output_rx: mpsc::Receiver<(Vec<u8>, Vec<SocketAddr>)>,
+    output_rx: mpsc::Receiver<OutboundPacket>,
+
+    /// Frames PeerHandler sends to a peer, queued per peer
+    ///
+    /// Probes and other control frames stay on `output_rx`.
+    peer_queues: PeerQueues,
+
+    /// ToS currently set on the IPv4 and IPv6 sockets
+    tos_ipv4: u8,
+    tos_ipv6: u8,
+
+    /// Address families to bind
+    bind_mode: P2PBindMode,
+
+    /// Local addresses the IPv4 and IPv6 sockets bind
+    ipv4_addr: Ipv4Addr,
+    ipv6_addr: Ipv6Addr,
+
+    /// Stops `serve` and closes both sockets when cancelled
+    cancel: CancellationToken,
+
+    /// Addresses the sockets are bound to, set by `serve`
+    bound_addrs: watch::Sender<Option<BoundAddrs>>,
+
+    /// IPv4 socket STUN discovery already mapped, bound on `stun_port` if unset
+    stun_socket: Option<StunSocket>,
+}
+
+/// Mark packets sent from `socket` with the given ToS / traffic class
+fn set_socket_tos(socket: &UdpSocket, ipv6: bool, tos: u8) -> std::io::Result<()> {
+    let socket = socket2::SockRef::from(socket);
+    if !ipv6 {
+        return socket.set_tos_v4(tos as u32);
+    }
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    return socket.set_tclass_v6(tos as u32);
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "IPv6 traffic class not supported on this platform",
+    ))
 }
 
 impl UDPServer {
     /// Create a new UDP server for dual-stack P2P communication
     ///
     /// # Arguments
-    /// * `listen_port` - IPv6 UDP port to bind (typically 51258)
-    /// * `stun_port` - IPv4 UDP port for STUN hole punching (typically 51259)
+    /// * `listen_port` - IPv6 UDP port to bind (typically 51258, 0 for any)
+    /// * `stun_port` - IPv4 UDP port for STUN hole punching (typically 51259, 0 for any)
     /// * `input_tx` - Channel to send received packets to PeerHandler
     /// * `output_rx` - Channel to receive outbound packets from PeerHandler
     ///
@@ -79,26 +223,86 @@ impl UDPServer {
         listen_port: u16,
         stun_port: u16,
         input_tx: mpsc::Sender<(Vec<u8>, SocketAddr)>,
-        output_rx: mpsc::Receiver<(Vec<u8>, Vec<SocketAddr>)>,
+        output_rx: mpsc::Receiver<OutboundPacket>,
     ) -> Self {
         UDPServer {
             listen_port,
             stun_port,
             input_tx,
             output_rx,
+            peer_queues: PeerQueues::new(PEER_QUEUE_SIZE),
+            tos_ipv4: 0,
+            tos_ipv6: 0,
+            bind_mode: P2PBindMode::Dual,
+            ipv4_addr: Ipv4Addr::UNSPECIFIED,
+            ipv6_addr: Ipv6Addr::UNSPECIFIED,
+            cancel: CancellationToken::new(),
+            bound_addrs: watch::channel(None).0,
+            stun_socket: None,
         }
     }
 
+    /// Punch holes from `socket` instead of binding `stun_port`
+    ///
+    /// Peers then reach us at the mapping STUN discovered for the socket.
+    pub(crate) fn with_stun_socket(mut self, socket: StunSocket) -> Self {
+        self.stun_socket = Some(socket);
+        self
+    }
+
+    /// Send the packets queued to peers in `queues`
+    pub(crate) fn with_peer_queues(mut self, queues: PeerQueues) -> Self {
+        self.peer_queues = queues;
+        self
+    }
+
+    /// Bind only the sockets of `mode`
+    pub(crate) fn with_bind_mode(mut self, mode: P2PBindMode) -> Self {
+        self.bind_mode = mode;
+        self
+    }
+
+    /// Bind the sockets to these local addresses, all addresses if not set
+    ///
+    /// On a multi-homed host this keeps P2P traffic on one interface. A
+    /// socket given by `with_stun_socket` is used as bound.
+    pub(crate) fn with_local_addrs(
+        mut self,
+        ipv4: Option<Ipv4Addr>,
+        ipv6: Option<Ipv6Addr>,
+    ) -> Self {
+        self.ipv4_addr = ipv4.unwrap_or(Ipv4Addr::UNSPECIFIED);
+        self.ipv6_addr = ipv6.unwrap_or(Ipv6Addr::UNSPECIFIED);
+        self
+    }
+
+    /// Stop serving once `cancel` is cancelled
+    pub(crate) fn with_cancel(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Local addresses, `None` until `serve` has bound the sockets
+    ///
+    /// Tells the ports picked when binding port 0.
+    pub(crate) fn bound_addrs(&self) -> watch::Receiver<Option<BoundAddrs>> {
+        self.bound_addrs.subscribe()
+    }
+
     /// Start the UDP server loop
     ///
-    /// This method binds both IPv4 and IPv6 sockets and enters an infinite loop
-    /// to handle bidirectional packet forwarding.
+    /// This method binds the IPv4 and IPv6 sockets of the bind mode and enters
+    /// an infinite loop to handle bidirectional packet forwarding.
     ///
     /// # Behavior
     ///
-    /// 1. Binds IPv6 socket on `[::]:<listen_port>` (all IPv6 interfaces)
-    /// 2. Binds IPv4 socket on `0.0.0.0:<stun_port>` (all IPv4 interfaces)
-    /// 3. Concurrently handles:
+    /// 1. Binds IPv6 socket on `[::]:<listen_port>` (all IPv6 interfaces,
+    ///    or the address of `with_local_addrs`)
+    /// 2. Binds IPv4 socket on `0.0.0.0:<stun_port>` (all IPv4 interfaces,
+    ///    or the address of `with_local_addrs`)
+    /// 3. Carries on with the other socket if one fails to bind, e.g. on a
+    ///    host without an IPv6 stack
+    /// 4. Concurrently handles:
     ///    - Outbound packets: Routes to IPv4 or IPv6 socket based on destination
     ///    - IPv6 inbound packets: Forwards to PeerHandler via channel
     ///    - IPv4 inbound packets: Forwards to PeerHandler via channel
@@ -106,48 +310,99 @@ impl UDPServer {
     /// # Errors
     ///
     /// Returns error if:
-    /// - Socket binding fails (port already in use, permission denied, etc.)
+    /// - No socket could be bound (port already in use, permission denied, etc.)
     /// - Socket receive operation fails (network error, etc.)
     ///
     /// # Note
     ///
-    /// This method never returns under normal operation. It only exits on error.
+    /// This method only returns on error or once the server is cancelled,
+    /// both sockets are closed by then.
     pub async fn serve(&mut self) -> anyhow::Result<()> {
         // Bind IPv6 socket for direct connections
         // [::] means all IPv6 interfaces (equivalent to 0.0.0.0 for IPv4)
-        let socket_ipv6 = UdpSocket::bind(format!("[::]:{}", self.listen_port)).await?;
-        tracing::info!("P2P IPv6 UDP listening on {}", socket_ipv6.local_addr()?);
+        let socket_ipv6 = if self.bind_mode.ipv6() {
+            match UdpSocket::bind((self.ipv6_addr, self.listen_port)).await {
+                Ok(socket) => {
+                    tracing::info!("P2P IPv6 UDP listening on {}", socket.local_addr()?);
+                    Some(socket)
+                }
+                Err(e) => {
+                    tracing::warn!("P2P IPv6 UDP bind failed, IPv6 direct disabled: {e}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
 
         // Bind IPv4 socket for STUN hole punching
-        // This socket uses the port discovered by STUN client
-        let socket_ipv4 = UdpSocket::bind(format!("0.0.0.0:{}", self.stun_port)).await?;
-        tracing::info!(
-            "P2P IPv4 UDP (STUN) listening on {}",
-            socket_ipv4.local_addr()?
-        );
+        // STUN discovery shares this socket, so the mapping it found is the
+        // one peers punch to
+        let socket_ipv4 = if self.bind_mode.ipv4() {
+            let socket = match self.stun_socket.clone() {
+                Some(socket) => Ok(socket),
+                None => StunSocket::bind_addr(self.ipv4_addr, self.stun_port),
+            };
+            match socket {
+                Ok(socket) => {
+                    tracing::info!("P2P IPv4 UDP (STUN) listening on {}", socket.local_addr()?);
+                    Some(socket)
+                }
+                Err(e) => {
+                    tracing::warn!("P2P IPv4 UDP bind failed, hole punching disabled: {e}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        if socket_ipv6.is_none() && socket_ipv4.is_none() {
+            anyhow::bail!("no P2P UDP socket could be bound");
+        }
+        // binding answers now arrive here, passed on in `handle_inbound`
+        let stun_socket = socket_ipv4;
+        let _served = stun_socket.as_ref().map(StunSocket::serve);
+        let socket_ipv4 = stun_socket.as_ref().map(StunSocket::socket);
+        self.bound_addrs.send_replace(Some(BoundAddrs {
+            ipv6: socket_ipv6.as_ref().and_then(|s| s.local_addr().ok()),
+            ipv4: socket_ipv4.as_ref().and_then(|s| s.local_addr().ok()),
+        }));
 
         // Separate buffers for each socket to avoid data races
         let mut buf_ipv6 = vec![0u8; BUFFER_SIZE];
         let mut buf_ipv4 = vec![0u8; BUFFER_SIZE];
+        let peer_queues = self.peer_queues.clone();
 
         loop {
             tokio::select! {
+                _ = self.cancel.cancelled() => {
+                    tracing::info!("P2P UDP server stopped");
+                    return Ok(());
+                }
+
                 // Handle outbound packets: PeerHandler -> Network
                 // PeerHandler decides the destination, we just route to the right socket
-                Some((data, remote)) = self.output_rx.recv() => {
-                    self.handle_outbound(&socket_ipv6, &socket_ipv4, &data, remote).await;
+                Some((data, remote, tos)) = self.output_rx.recv() => {
+                    self.handle_outbound(socket_ipv6.as_ref(), socket_ipv4, &data, remote, tos).await;
+                }
+
+                // One packet of the next peer in turn, so a backlog to one
+                // peer never holds up the others
+                (data, remote, tos) = peer_queues.pop() => {
+                    self.handle_outbound(socket_ipv6.as_ref(), socket_ipv4, &data, remote, tos).await;
                 }
 
                 // Handle IPv6 inbound packets: Network -> PeerHandler
                 // Direct P2P connections or responses to our keepalives
-                result = socket_ipv6.recv_from(&mut buf_ipv6) => {
-                    self.handle_inbound(result, &mut buf_ipv6, "IPv6").await?
+                result = recv_from(socket_ipv6.as_ref(), &mut buf_ipv6) => {
+                    self.handle_inbound(result, &mut buf_ipv6, "IPv6", None).await?
                 }
 
                 // Handle IPv4 inbound packets: Network -> PeerHandler
                 // STUN-hole-punched connections or responses
-                result = socket_ipv4.recv_from(&mut buf_ipv4) => {
-                    self.handle_inbound(result, &mut buf_ipv4, "IPv4").await?
+                result = recv_from(socket_ipv4, &mut buf_ipv4) => {
+                    self.handle_inbound(result, &mut buf_ipv4, "IPv4", stun_socket.as_ref()).await?
                 }
             }
         }
@@ -159,13 +414,15 @@ impl UDPServer {
     ///
     /// - IPv4 destination -> Use IPv4 socket (STUN port)
     /// - IPv6 destination -> Use IPv6 socket (direct connection port)
+    /// - No socket for the family -> Packet is dropped
     ///
     /// # Arguments
     ///
-    /// * `socket_ipv6` - IPv6 UDP socket reference
-    /// * `socket_ipv4` - IPv4 UDP socket reference
+    /// * `socket_ipv6` - IPv6 UDP socket reference, if bound
+    /// * `socket_ipv4` - IPv4 UDP socket reference, if bound
     /// * `data` - Encrypted packet payload to send
     /// * `remote` - Destination address (can be IPv4 or IPv6)
+    /// * `tos` - ToS / traffic class to mark the packet with
     ///
     /// # Error Handling
     ///
@@ -175,19 +432,32 @@ impl UDPServer {
     /// - One failed send shouldn't affect other connections
     /// - PeerHandler will detect connection failure via keepalive timeout
     async fn handle_outbound(
-        &self,
-        socket_ipv6: &UdpSocket,
-        socket_ipv4: &UdpSocket,
+        &mut self,
+        socket_ipv6: Option<&UdpSocket>,
+        socket_ipv4: Option<&UdpSocket>,
         data: &[u8],
         remotes: Vec<SocketAddr>,
+        tos: u8,
     ) {
         for remote in &remotes {
             // Select socket based on destination address family
-            let (socket, protocol) = if remote.is_ipv4() {
-                (socket_ipv4, "IPv4")
+            let (socket, protocol, current_tos) = if remote.is_ipv4() {
+                (socket_ipv4, "IPv4", &mut self.tos_ipv4)
             } else {
-                (socket_ipv6, "IPv6")
+                (socket_ipv6, "IPv6", &mut self.tos_ipv6)
             };
+            let Some(socket) = socket else {
+                tracing::debug!("No {protocol} socket bound, dropping packet to {remote}");
+                continue;
+            };
+
+            // the marking is per socket, only touch it when it changes
+            if *current_tos != tos {
+                match set_socket_tos(socket, remote.is_ipv6(), tos) {
+                    Ok(()) => *current_tos = tos,
+                    Err(e) => tracing::debug!("Failed to set {protocol} ToS {tos:#04x}: {e}"),
+                }
+            }
 
             if let Err(e) = socket.send_to(data, remote).await {
                 tracing::error!("Failed to send {protocol} packet to {remote}: {e:?}");
@@ -208,6 +478,7 @@ impl UDPServer {
     /// * `result` - Result from `socket.recv_from()` call
     /// * `buffer` - Buffer that received the packet data
     /// * `protocol` - Protocol name ("IPv4" or "IPv6") for logging
+    /// * `stun` - Socket whose STUN binding answers to hand to discovery
     ///
     /// # Return Value
     ///
@@ -226,9 +497,14 @@ impl UDPServer {
         result: std::io::Result<(usize, SocketAddr)>,
         buffer: &mut [u8],
         protocol: &str,
+        stun: Option<&StunSocket>,
     ) -> anyhow::Result<()> {
         match result {
             Ok((len, remote)) => {
+                if stun.is_some_and(|stun| stun.take_response(&buffer[..len])) {
+                    return Ok(());
+                }
+
                 // Copy only the received bytes (not the entire buffer)
                 let packet = buffer[..len].to_vec();
 
@@ -249,3 +525,137 @@ impl UDPServer {
         }
     }
 }
+
+/// Receive on `socket`, or never if it is not bound
+async fn recv_from(
+    socket: Option<&UdpSocket>,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_set_socket_tos() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        set_socket_tos(&socket, false, 0xb8).unwrap();
+        assert_eq!(socket2::SockRef::from(&socket).tos_v4().unwrap(), 0xb8);
+    }
+
+    #[tokio::test]
+    async fn test_ipv4_serves_when_ipv6_bind_fails() {
+        // holding the IPv6 port makes the server's bind fail
+        let taken = std::net::UdpSocket::bind("[::]:0").unwrap();
+        let ipv6_port = taken.local_addr().unwrap().port();
+
+        let (input_tx, mut input_rx) = mpsc::channel(1);
+        let (output_tx, output_rx) = mpsc::channel(1);
+        let mut server = UDPServer::new(ipv6_port, 0, input_tx, output_rx);
+        let mut bound = server.bound_addrs();
+        tokio::spawn(async move { server.serve().await });
+        let addrs = bound.wait_for(Option::is_some).await.unwrap().unwrap();
+        assert_eq!(addrs.ipv6, None);
+        let stun_port = addrs.ipv4.unwrap().port();
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"in", ("127.0.0.1", stun_port)).await.unwrap();
+        let (data, from) = input_rx.recv().await.unwrap();
+        assert_eq!(data, b"in");
+        assert_eq!(from, peer.local_addr().unwrap());
+
+        // IPv6 destinations are dropped, IPv4 ones still go out
+        let ipv6_dst = "[::1]:9".parse().unwrap();
+        output_tx
+            .send((b"out".to_vec(), vec![ipv6_dst, from], 0))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let (len, src) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"out");
+        assert_eq!(src.port(), stun_port);
+    }
+
+    #[tokio::test]
+    async fn test_v4_only_skips_ipv6() {
+        let (input_tx, _input_rx) = mpsc::channel(1);
+        let (_output_tx, output_rx) = mpsc::channel(1);
+        let mut server =
+            UDPServer::new(0, 0, input_tx, output_rx).with_bind_mode(P2PBindMode::V4Only);
+        let mut bound = server.bound_addrs();
+        tokio::spawn(async move { server.serve().await });
+        let addrs = bound.wait_for(Option::is_some).await.unwrap().unwrap();
+        assert_eq!(addrs.ipv6, None);
+        assert!(addrs.ipv4.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_binds_requested_local_addrs() {
+        use crate::client::p2p::check_local_addr;
+
+        let (input_tx, _input_rx) = mpsc::channel(1);
+        let (_output_tx, output_rx) = mpsc::channel(1);
+        let mut server = UDPServer::new(0, 0, input_tx, output_rx)
+            .with_local_addrs(Some(Ipv4Addr::LOCALHOST), Some(Ipv6Addr::LOCALHOST));
+        let mut bound = server.bound_addrs();
+        tokio::spawn(async move { server.serve().await });
+        let addrs = bound.wait_for(Option::is_some).await.unwrap().unwrap();
+        assert_eq!(addrs.ipv4.unwrap().ip(), Ipv4Addr::LOCALHOST);
+        assert_eq!(addrs.ipv6.unwrap().ip(), Ipv6Addr::LOCALHOST);
+
+        assert!(check_local_addr(Ipv4Addr::LOCALHOST.into()).is_ok());
+        // TEST-NET-1, held by no interface
+        let e = check_local_addr(Ipv4Addr::new(192, 0, 2, 1).into()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "192.0.2.1 is not an address of a local interface"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stun_discovery_shares_punching_socket() {
+        use crate::client::p2p::stun::tests::fake_stun_server;
+        use std::time::Duration;
+
+        let stun_server = fake_stun_server().await;
+        let socket = StunSocket::bind(0).unwrap();
+        let before = socket
+            .query(stun_server, Duration::from_secs(2))
+            .await
+            .unwrap();
+
+        let (input_tx, mut input_rx) = mpsc::channel(1);
+        let (output_tx, output_rx) = mpsc::channel(1);
+        let mut server = UDPServer::new(0, 0, input_tx, output_rx)
+            .with_bind_mode(P2PBindMode::V4Only)
+            .with_stun_socket(socket.clone());
+        let mut bound = server.bound_addrs();
+        tokio::spawn(async move { server.serve().await });
+        let addrs = bound.wait_for(Option::is_some).await.unwrap().unwrap();
+        assert_eq!(addrs.ipv4, Some(socket.local_addr().unwrap()));
+
+        // re-discovery while the server reads the socket gets its answer
+        let mapped = socket
+            .query(stun_server, Duration::from_secs(2))
+            .await
+            .unwrap();
+        assert_eq!(mapped, before);
+        assert!(input_rx.try_recv().is_err());
+
+        // peers see P2P frames from the discovered mapping
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        output_tx
+            .send((b"punch".to_vec(), vec![peer.local_addr().unwrap()], 0))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let (len, src) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"punch");
+        assert_eq!(src, mapped);
+    }
+}
@@ -0,0 +1,244 @@
+//! One-shot connectivity check against a single peer
+//!
+//! With `--ping <identity>` the client handshakes as usual, then sends `Echo`
+//! frames to the target through the relay and, when P2P is enabled, over the
+//! direct path. It prints which paths answered and their round trip time,
+//! and exits without creating the TUN device.
+
+use crate::client::p2p::peer::{NewFrameRx, PeerHandlerApi, SendFrame, SendFrameTx};
+use crate::client::relay::{RelayHandler, RelayOutboundTx};
+use crate::codec::frame::{EchoFrame, Frame, PeerDetail};
+use async_trait::async_trait;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{Instant, interval, sleep_until};
+
+/// How often an unanswered echo is resent
+///
+/// The P2P path only carries frames once the peers exchanged probes, so
+/// early echoes on it are expected to get lost.
+const ECHO_RETRY: Duration = Duration::from_millis(500);
+
+/// Current time in microseconds since the UNIX epoch
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// A way of reaching the target peer
+#[async_trait]
+trait EchoPath: Send {
+    /// Path name shown in the report
+    fn name(&self) -> &'static str;
+
+    /// Send an echo towards the target
+    async fn send(&mut self, echo: EchoFrame) -> anyhow::Result<()>;
+
+    /// Next frame coming back on this path, `None` once it is closed
+    async fn recv(&mut self) -> Option<Frame>;
+}
+
+/// Echoes forwarded by the relay server to the target's private IP
+struct RelayPath<'a> {
+    outbound: RelayOutboundTx,
+    handler: &'a mut RelayHandler,
+    dst: String,
+}
+
+#[async_trait]
+impl EchoPath for RelayPath<'_> {
+    fn name(&self) -> &'static str {
+        "relay"
+    }
+
+    async fn send(&mut self, mut echo: EchoFrame) -> anyhow::Result<()> {
+        echo.dst = self.dst.clone();
+        RelayHandler::send_frame(&self.outbound, Frame::Echo(echo)).map(|_| ())
+    }
+
+    async fn recv(&mut self) -> Option<Frame> {
+        self.handler.recv_frame().await.ok()
+    }
+}
+
+/// Echoes sent straight to the target by the P2P peer service
+struct P2pPath {
+    send_frame: SendFrameTx,
+    new_frame: NewFrameRx,
+    dst: String,
+}
+
+#[async_trait]
+impl EchoPath for P2pPath {
+    fn name(&self) -> &'static str {
+        "p2p"
+    }
+
+    async fn send(&mut self, echo: EchoFrame) -> anyhow::Result<()> {
+        let frame = SendFrame {
+            frame: Frame::Echo(echo),
+            dst: self.dst.clone(),
+            tos: 0,
+        };
+        Ok(self.send_frame.0.send(frame).await?)
+    }
+
+    async fn recv(&mut self) -> Option<Frame> {
+        self.new_frame.0.recv().await
+    }
+}
+
+/// Outcome of pinging over one path
+#[derive(Debug)]
+struct PathResult {
+    name: &'static str,
+    /// Round trip of the first answered echo, `None` if none was answered
+    rtt: Option<Duration>,
+}
+
+/// Send echoes over `path` until one is answered or `timeout` passes
+async fn ping_path(path: &mut impl EchoPath, timeout: Duration) -> PathResult {
+    let deadline = Instant::now() + timeout;
+    let mut retry = interval(ECHO_RETRY);
+    let mut last_id = 0;
+    let rtt = loop {
+        tokio::select! {
+            _ = sleep_until(deadline) => break None,
+            _ = retry.tick() => {
+                last_id += 1;
+                let echo = EchoFrame {
+                    id: last_id,
+                    sent_at: now_micros(),
+                    src: String::new(),
+                    dst: String::new(),
+                };
+                if let Err(e) = path.send(echo).await {
+                    tracing::debug!("{} echo {last_id} not sent: {e}", path.name());
+                }
+            }
+            frame = path.recv() => match frame {
+                Some(Frame::EchoReply(reply)) if (1..=last_id).contains(&reply.id) => {
+                    break Some(Duration::from_micros(now_micros().saturating_sub(reply.sent_at)));
+                }
+                Some(_) => {}
+                None => break None,
+            },
+        }
+    };
+    PathResult {
+        name: path.name(),
+        rtt,
+    }
+}
+
+fn print_result(result: &PathResult, timeout: Duration) {
+    match result.rtt {
+        Some(rtt) => println!(
+            "  {:<6} reply in {:.1} ms",
+            result.name,
+            rtt.as_secs_f64() * 1000.0
+        ),
+        None => println!("  {:<6} no reply within {timeout:?}", result.name),
+    }
+}
+
+/// Ping peer `target` over every available path and report the results
+///
+/// # Returns
+/// - `Ok(())` - At least one path answered
+/// - `Err` - Unknown peer, or no path answered within `timeout`
+pub async fn run_ping(
+    relay: &mut RelayHandler,
+    p2p: Option<PeerHandlerApi>,
+    peers: &[PeerDetail],
+    target: &str,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let peer = peers
+        .iter()
+        .find(|p| p.identity == target)
+        .ok_or_else(|| anyhow::anyhow!("peer {target} is not in this cluster"))?;
+    let outbound = relay
+        .get_outbound_tx()
+        .ok_or_else(|| anyhow::anyhow!("relay client not running"))?;
+
+    println!("PING {} ({})", peer.identity, peer.private_ip);
+    let mut relay_path = RelayPath {
+        outbound,
+        handler: relay,
+        dst: peer.private_ip.clone(),
+    };
+    let mut p2p_path = p2p.map(|p2p| P2pPath {
+        send_frame: p2p.send_frame,
+        new_frame: p2p.new_frame,
+        dst: peer.private_ip.clone(),
+    });
+    let (relay_result, p2p_result) = tokio::join!(ping_path(&mut relay_path, timeout), async {
+        match p2p_path.as_mut() {
+            Some(path) => Some(ping_path(path, timeout).await),
+            None => None,
+        }
+    });
+
+    print_result(&relay_result, timeout);
+    match &p2p_result {
+        Some(result) => print_result(result, timeout),
+        None => println!("  {:<6} skipped, P2P is disabled", "p2p"),
+    }
+
+    let answered = relay_result.rtt.is_some() || p2p_result.is_some_and(|r| r.rtt.is_some());
+    if !answered {
+        anyhow::bail!("{target} did not answer on any path");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::frame::EchoReplyFrame;
+    use tokio::sync::mpsc;
+
+    /// P2P path to a mock peer answering after `delay`, if it answers at all
+    fn mock_peer(delay: Option<Duration>) -> P2pPath {
+        let (send_tx, mut send_rx) = mpsc::channel::<SendFrame>(8);
+        let (frame_tx, frame_rx) = mpsc::channel(8);
+        tokio::spawn(async move {
+            while let Some(sf) = send_rx.recv().await {
+                let (Frame::Echo(echo), Some(delay)) = (sf.frame, delay) else {
+                    continue;
+                };
+                tokio::time::sleep(delay).await;
+                let reply = EchoReplyFrame {
+                    id: echo.id,
+                    sent_at: echo.sent_at,
+                    dst: String::new(),
+                };
+                let _ = frame_tx.send(Frame::EchoReply(reply)).await;
+            }
+        });
+        P2pPath {
+            send_frame: SendFrameTx(send_tx),
+            new_frame: NewFrameRx(frame_rx),
+            dst: "10.0.0.2".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_reports_rtt_from_echoing_peer() {
+        let mut path = mock_peer(Some(Duration::from_millis(20)));
+        let result = ping_path(&mut path, Duration::from_secs(2)).await;
+        assert_eq!(result.name, "p2p");
+        let rtt = result.rtt.expect("peer answered");
+        assert!(rtt >= Duration::from_millis(20), "rtt {rtt:?}");
+        assert!(rtt < Duration::from_secs(2), "rtt {rtt:?}");
+    }
+
+    #[tokio::test]
+    async fn test_ping_times_out_on_silent_peer() {
+        let mut path = mock_peer(None);
+        let result = ping_path(&mut path, Duration::from_millis(100)).await;
+        assert!(result.rtt.is_none());
+    }
+}
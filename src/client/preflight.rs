@@ -0,0 +1,157 @@
+//! Privilege checks run before the client touches the network
+//!
+//! Creating the TUN device and editing routes need root (or Administrator
+//! and Wintun on Windows). Without them the client used to fail deep in
+//! device setup, after connecting to the relay. `preflight` tries both up
+//! front and fails with a hint on how to fix it.
+
+/// Host operations probed by `preflight`
+pub trait PreflightBackend {
+    /// Create a TUN device and tear it down again
+    fn probe_tun(&self) -> anyhow::Result<()>;
+
+    /// Check that the routing table may be modified
+    fn probe_routes(&self) -> anyhow::Result<()>;
+}
+
+/// Probes the real host
+pub struct HostBackend;
+
+impl PreflightBackend for HostBackend {
+    fn probe_tun(&self) -> anyhow::Result<()> {
+        // not brought up and dropped right away, so no address or route is set
+        let dev = tun::create(&tun::Configuration::default())?;
+        drop(dev);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn probe_routes(&self) -> anyhow::Result<()> {
+        let status = std::fs::read_to_string("/proc/self/status")?;
+        if !has_cap_net_admin(&status) {
+            anyhow::bail!("missing CAP_NET_ADMIN");
+        }
+        Ok(())
+    }
+
+    /// Route changes need the same privileges as the TUN device
+    #[cfg(not(target_os = "linux"))]
+    fn probe_routes(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether the `CapEff` line of `/proc/<pid>/status` grants CAP_NET_ADMIN
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn has_cap_net_admin(status: &str) -> bool {
+    const CAP_NET_ADMIN: u32 = 12;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .is_some_and(|caps| caps & (1 << CAP_NET_ADMIN) != 0)
+}
+
+/// How to get the privileges the client needs on this platform
+fn privilege_hint() -> &'static str {
+    if cfg!(target_os = "windows") {
+        "run as Administrator and place wintun.dll (https://www.wintun.net/) next to the binary"
+    } else if cfg!(target_os = "linux") {
+        "run as root, or grant CAP_NET_ADMIN: sudo setcap cap_net_admin+ep <path to client>"
+    } else {
+        "run as root (sudo)"
+    }
+}
+
+/// Check that the TUN device and routes can be set up
+///
+/// # Arguments
+/// - `backend` - Host operations, `HostBackend` outside of tests
+/// - `check_routes` - Also check route privileges, off for `--route-dry-run`
+///
+/// # Returns
+/// - `Ok(())` - The client has the privileges it needs
+/// - `Err` - What failed and how to fix it
+pub fn preflight(backend: &dyn PreflightBackend, check_routes: bool) -> anyhow::Result<()> {
+    if let Err(e) = backend.probe_tun() {
+        anyhow::bail!("cannot create a TUN device: {e}\n{}", privilege_hint());
+    }
+    if check_routes && let Err(e) = backend.probe_routes() {
+        anyhow::bail!("cannot modify routes: {e}\n{}", privilege_hint());
+    }
+    tracing::debug!("preflight passed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    struct MockBackend {
+        tun: Option<&'static str>,
+        routes: Option<&'static str>,
+        route_probes: Cell<u32>,
+    }
+
+    impl MockBackend {
+        /// Backend failing the probes given an error message
+        fn new(tun: Option<&'static str>, routes: Option<&'static str>) -> Self {
+            Self {
+                tun,
+                routes,
+                route_probes: Cell::new(0),
+            }
+        }
+    }
+
+    impl PreflightBackend for MockBackend {
+        fn probe_tun(&self) -> anyhow::Result<()> {
+            match self.tun {
+                Some(e) => anyhow::bail!("{e}"),
+                None => Ok(()),
+            }
+        }
+
+        fn probe_routes(&self) -> anyhow::Result<()> {
+            self.route_probes.set(self.route_probes.get() + 1);
+            match self.routes {
+                Some(e) => anyhow::bail!("{e}"),
+                None => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_preflight_reports_actionable_errors() {
+        assert!(preflight(&MockBackend::new(None, None), true).is_ok());
+
+        let e = preflight(
+            &MockBackend::new(Some("Operation not permitted"), None),
+            true,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(e.starts_with("cannot create a TUN device: Operation not permitted"));
+        assert!(e.ends_with(privilege_hint()));
+
+        let e = preflight(&MockBackend::new(None, Some("missing CAP_NET_ADMIN")), true)
+            .unwrap_err()
+            .to_string();
+        assert!(e.starts_with("cannot modify routes: missing CAP_NET_ADMIN"));
+
+        // dry-run routing needs no route privileges
+        let backend = MockBackend::new(None, Some("missing CAP_NET_ADMIN"));
+        assert!(preflight(&backend, false).is_ok());
+        assert_eq!(backend.route_probes.get(), 0);
+    }
+
+    #[test]
+    fn test_cap_net_admin_from_proc_status() {
+        let status = |caps: &str| format!("Name:\tclient\nCapPrm:\t{caps}\nCapEff:\t{caps}\n");
+        assert!(has_cap_net_admin(&status("000001ffffffffff")));
+        assert!(has_cap_net_admin(&status("0000000000001000")));
+        assert!(!has_cap_net_admin(&status("0000000000000000")));
+        assert!(!has_cap_net_admin("Name:\tclient\n"));
+    }
+}
@@ -0,0 +1,121 @@
+//! Peer online state as reported by the server, and destinations kept off P2P
+
+use crate::codec::frame::PeerDetail;
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+/// A peer the server has no connection to
+#[derive(Debug)]
+struct OfflinePeer {
+    private_ip: String,
+    ciders: Vec<IpNet>,
+}
+
+/// Peers the server reports as offline
+///
+/// Built from the `last_active` of handshake and keepalive peer details,
+/// where 0 means the peer is not connected to the server. Shared with the
+/// device task so packets for offline peers skip the P2P path.
+#[derive(Debug, Clone, Default)]
+pub struct PeerPresence {
+    offline: Arc<RwLock<Vec<OfflinePeer>>>,
+    /// Destinations always sent through the relay
+    relay_only: Arc<Vec<IpNet>>,
+}
+
+impl PeerPresence {
+    pub fn new(peer_details: &[PeerDetail]) -> Self {
+        let presence = Self::default();
+        presence.update(peer_details);
+        presence
+    }
+
+    /// Send packets for `cidrs` through the relay even when P2P is up
+    pub fn with_relay_only(mut self, cidrs: Vec<IpNet>) -> Self {
+        self.relay_only = Arc::new(cidrs);
+        self
+    }
+
+    /// Replace the offline set with the server's latest peer list
+    pub fn update(&self, peer_details: &[PeerDetail]) {
+        let offline = peer_details
+            .iter()
+            .filter(|peer| peer.last_active == 0)
+            .map(|peer| OfflinePeer {
+                private_ip: peer.private_ip.clone(),
+                ciders: peer.ciders.iter().filter_map(|c| c.parse().ok()).collect(),
+            })
+            .collect();
+        *self.offline.write().unwrap_or_else(|e| e.into_inner()) = offline;
+    }
+
+    /// Check whether `dst` routes to a peer the server reports offline
+    pub fn is_offline(&self, dst: &str) -> bool {
+        let dst_ip = dst.parse::<IpAddr>().ok();
+        self.offline
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .any(|peer| {
+                peer.private_ip == dst
+                    || dst_ip.is_some_and(|ip| peer.ciders.iter().any(|c| c.contains(&ip)))
+            })
+    }
+
+    /// Check whether `dst` must never be tried over P2P
+    pub fn is_relay_only(&self, dst: &str) -> bool {
+        dst.parse::<IpAddr>()
+            .is_ok_and(|ip| self.relay_only.iter().any(|c| c.contains(&ip)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(private_ip: &str, ciders: &[&str], last_active: u64) -> PeerDetail {
+        PeerDetail {
+            name: private_ip.to_string(),
+            identity: private_ip.to_string(),
+            private_ip: private_ip.to_string(),
+            ciders: ciders.iter().map(|c| c.to_string()).collect(),
+            ipv6: vec![],
+            port: 0,
+            stun_ip: String::new(),
+            stun_port: 0,
+            nat_type: 0,
+            last_active,
+        }
+    }
+
+    #[test]
+    fn test_offline_by_private_ip_and_cidr() {
+        let presence = PeerPresence::new(&[
+            peer("10.0.0.2", &["192.168.2.0/24"], 0),
+            peer("10.0.0.3", &["192.168.3.0/24"], 1_700_000_000),
+        ]);
+
+        assert!(presence.is_offline("10.0.0.2"));
+        assert!(presence.is_offline("192.168.2.10"));
+        assert!(!presence.is_offline("10.0.0.3"));
+        assert!(!presence.is_offline("192.168.3.10"));
+        assert!(!presence.is_offline("10.0.0.9"));
+
+        presence.update(&[peer("10.0.0.2", &["192.168.2.0/24"], 1_700_000_000)]);
+        assert!(!presence.is_offline("10.0.0.2"));
+    }
+
+    #[test]
+    fn test_relay_only_cidrs() {
+        let presence = PeerPresence::new(&[]).with_relay_only(vec![
+            "192.168.9.0/24".parse().unwrap(),
+            "fd00:9::/64".parse().unwrap(),
+        ]);
+        assert!(presence.is_relay_only("192.168.9.20"));
+        assert!(presence.is_relay_only("fd00:9::1"));
+        assert!(!presence.is_relay_only("192.168.10.20"));
+        assert!(!presence.is_relay_only("not an ip"));
+        assert!(!PeerPresence::default().is_relay_only("192.168.9.20"));
+    }
+}
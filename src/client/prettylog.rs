@@ -5,7 +5,7 @@
 use crate::client::Args;
 use crate::client::http::cache;
 use crate::client::http::{
-    ClusterPeerInfo, IPv6ConnectionInfo, P2PPeerInfo, P2PStatus, RelayStatusInfo,
+    ClusterPeerInfo, IPv6ConnectionInfo, P2PPeerInfo, P2PStatus, RelayStatusInfo, RouteHealthInfo,
     STUNConnectionInfo, StatusResponse, TrafficStats,
 };
 use crate::client::p2p::PeerStatus;
@@ -33,7 +33,7 @@ pub fn log_handshake_success(config: &HandshakeReplyFrame) {
         for (idx, peer) in config.peer_details.iter().enumerate() {
             println!("  [{}] Identity: {}", idx + 1, peer.identity);
             println!("      Private IP: {}", peer.private_ip);
-            println!("      IPv6: {}", peer.ipv6);
+            println!("      IPv6: {}", peer.ipv6.join(", "));
             println!("      CIDR ranges: {}", peer.ciders.join(", "));
         }
     }
@@ -41,7 +41,12 @@ pub fn log_handshake_success(config: &HandshakeReplyFrame) {
     println!("Ready to forward traffic");
 }
 
-pub async fn get_status(relay: &RelayHandler, peer: Option<&[PeerStatus]>, dev: &DeviceHandler) {
+pub async fn get_status(
+    relay: &RelayHandler,
+    peer: Option<&[PeerStatus]>,
+    dev: &DeviceHandler,
+    route_health: Vec<RouteHealthInfo>,
+) {
     println!("\n╔══════════════════════════════════════════════════════════════════════╗");
     println!("║                        CONNECTION STATUS                             ║");
     println!("╚══════════════════════════════════════════════════════════════════════╝");
@@ -50,17 +55,21 @@ pub async fn get_status(relay: &RelayHandler, peer: Option<&[PeerStatus]>, dev:
     // device receive is the traffic outbound
     println!("Receive Bytes: {}MB", dev.tx_bytes / 1024 / 1024);
     println!("Send Bytes: {}MB", dev.rx_bytes / 1024 / 1024);
+    println!("Invalid Packets Dropped: {}", dev.invalid_packets);
+    println!("Packets Dropped To Device: {}", dev.dropped_to_device);
+    println!("Packets Over Download Limit: {}", dev.dropped_over_limit);
 
     // Relay Status
     let relay_status = relay.get_status();
-    println!("\n📡 Relay Connection (TCP)");
+    let relay_state = if relay_status.connected { "up" } else { "down" };
+    println!("\n📡 Relay Connection (TCP, {relay_state})");
     println!(
-        "   ├─ RX Frames:  {} (Errors: {})",
-        relay_status.rx_frame, relay_status.rx_error
+        "   ├─ RX Frames:  {} (Errors: {}, Near full: {})",
+        relay_status.rx_frame, relay_status.rx_error, relay_status.rx_near_full
     );
     println!(
-        "   └─ TX Frames:  {} (Errors: {})",
-        relay_status.tx_frame, relay_status.tx_error
+        "   └─ TX Frames:  {} (Errors: {}, Dropped: {})",
+        relay_status.tx_frame, relay_status.tx_error, relay_status.tx_dropped
     );
 
     // P2P Status
@@ -157,11 +166,8 @@ pub async fn get_status(relay: &RelayHandler, peer: Option<&[PeerStatus]>, dev:
                 println!("   {continuation}    ├─ Routes: {}", peer.ciders.join(", "));
             }
 
-            if !peer.ipv6.is_empty() {
-                println!(
-                    "   {continuation}    ├─ IPv6: [{}]:{}",
-                    peer.ipv6, peer.port
-                );
+            for ipv6 in &peer.ipv6 {
+                println!("   {continuation}    ├─ IPv6: [{ipv6}]:{}", peer.port);
             }
 
             if !peer.stun_ip.is_empty() {
@@ -176,10 +182,28 @@ pub async fn get_status(relay: &RelayHandler, peer: Option<&[PeerStatus]>, dev:
         }
     }
 
+    // Peer CIDR reachability (only when route probing is enabled)
+    if !route_health.is_empty() {
+        println!("\n🩺 Route Health: {} routes", route_health.len());
+        for (idx, route) in route_health.iter().enumerate() {
+            let prefix = if idx == route_health.len() - 1 {
+                "└─"
+            } else {
+                "├─"
+            };
+            let state = if route.reachable {
+                "✅ Reachable".to_string()
+            } else {
+                format!("❌ Withdrawn ({} missed probes)", route.missed_probes)
+            };
+            println!("   {prefix} {} via {}: {state}", route.cidr, route.target);
+        }
+    }
+
     println!();
 
     // Update HTTP cache
-    let status = match build_status_response(relay, peer, dev).await {
+    let status = match build_status_response(relay, peer, dev, route_health).await {
         Ok(s) => s,
         Err(e) => {
             eprintln!("failed: {e}");
@@ -194,6 +218,7 @@ pub async fn build_status_response(
     relay: &RelayHandler,
     peer: Option<&[PeerStatus]>,
     dev: &DeviceHandler,
+    route_health: Vec<RouteHealthInfo>,
 ) -> anyhow::Result<StatusResponse> {
     // Self information from relay
     let self_info = relay.get_self_info().await;
@@ -204,6 +229,9 @@ pub async fn build_status_response(
         receive_bytes_mb: dev.tx_bytes as f64 / 1024.0 / 1024.0,
         send_bytes: dev.rx_bytes as u64,
         send_bytes_mb: dev.rx_bytes as f64 / 1024.0 / 1024.0,
+        invalid_packets: dev.invalid_packets as u64,
+        dropped_to_device: dev.dropped_to_device as u64,
+        dropped_over_limit: dev.dropped_over_limit as u64,
     };
 
     // Relay status
@@ -213,6 +241,9 @@ pub async fn build_status_response(
         rx_errors: relay_status.rx_error,
         tx_frames: relay_status.tx_frame,
         tx_errors: relay_status.tx_error,
+        tx_dropped: relay_status.tx_dropped,
+        rx_near_full: relay_status.rx_near_full,
+        connected: relay_status.connected,
     };
 
     // P2P status
@@ -247,6 +278,9 @@ pub async fn build_status_response(
                 identity: status.identity.clone(),
                 ipv6,
                 stun,
+                path: status.path,
+                queue_depth: status.queue_depth,
+                queue_dropped: status.queue_dropped,
             });
         }
 
@@ -289,11 +323,7 @@ pub async fn build_status_response(
                 identity: peer.identity,
                 private_ip: peer.private_ip,
                 ciders: peer.ciders,
-                ipv6: if peer.ipv6.is_empty() {
-                    None
-                } else {
-                    Some(peer.ipv6)
-                },
+                ipv6: peer.ipv6,
                 ipv6_port: if peer.port > 0 { Some(peer.port) } else { None },
                 stun_ip: if peer.stun_ip.is_empty() {
                     None
@@ -317,5 +347,6 @@ pub async fn build_status_response(
         relay,
         p2p,
         cluster_peers,
+        route_health,
     })
 }
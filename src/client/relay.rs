@@ -1,38 +1,129 @@
 use crate::client::Args;
 use crate::client::http::SelfInfo;
+use crate::client::p2p::stun::StunRefresh;
 use crate::client::prettylog::log_handshake_success;
-use crate::codec::frame::{Frame, HandshakeFrame, HandshakeReplyFrame, KeepAliveFrame};
+use crate::codec::frame::{
+    CloseFrame, Frame, HandshakeFrame, HandshakeReplyFrame, KeepAliveFrame, PeerJoinFrame,
+    REJECT_UNAUTHORIZED, RekeyFrame, TunnelMode,
+};
+use crate::codec::magic::DEFAULT_MAGIC;
+use crate::codec::parser::{
+    MAX_PAYLOAD_LEN, MAX_VERSION, MIN_VERSION, Parser, REKEY_VERSION, negotiate_version,
+};
 use crate::crypto::Block;
-use crate::network::{ConnManage, ConnectionConfig, TCPConnectionConfig, create_connection};
-use crate::utils::{self, StunAddr};
-use std::net::{Ipv6Addr, SocketAddr};
+use crate::crypto::rekey::{RekeyPolicy, Rekeyer};
+use crate::error::RustunError;
+use crate::network::tap::FrameTap;
+use crate::network::{
+    ConnManage, ConnTimeouts, ConnectionConfig, FamilyPreference, MAX_WRITE_BATCH, SocketBuffers,
+    TCPConnectionConfig, create_connection, drain_batch,
+};
+use crate::utils::supervisor::{catch_panic, supervise};
+use crate::utils::{self, Ipv6Lookup, StunAddr};
+use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::ops::ControlFlow;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::time::Instant;
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
 use tokio::time::{Duration, interval};
+use tokio_util::sync::CancellationToken;
 
 const CHANNEL_BUFFER_SIZE: usize = 1000;
 const CONFIG_CHANNEL_SIZE: usize = 10;
+/// Delay before reconnecting after the relay connection is lost
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Most frames kept for replay while the server is overdue
+const MAX_UNACKED_FRAMES: usize = 256;
+/// Delay before a panicked STUN refresh task starts over
+const STUN_REFRESH_RESTART_DELAY: Duration = Duration::from_secs(5);
+/// Longest `RelayHandler::close` waits for the server to be told
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[derive(Clone)]
 pub struct RelayClientConfig {
     pub server_addr: String,
+    /// Addresses `server_addr` resolved to at startup, dialed on every
+    /// reconnect instead of looking it up again (looked up each time if
+    /// empty)
+    pub server_addrs: Vec<SocketAddr>,
     pub keepalive_interval: Duration,
     pub outbound_buffer_size: usize,
+    /// Server frames queued for the device and P2P loops
+    pub inbound_buffer_size: usize,
     pub keep_alive_thresh: u8,
     pub identity: String,
+    pub token: Option<String>,
     pub ipv6: Option<Ipv6Addr>,
+    /// IPv6 addresses advertised besides `ipv6`, on the same `port`
+    pub extra_ipv6: Vec<Ipv6Addr>,
+    /// Re-discover `ipv6` periodically, off when P2P is bound to it
+    pub refresh_ipv6: bool,
     pub port: u16,
     pub stun: Option<StunAddr>,
+    /// Periodic STUN re-discovery (disabled if not set)
+    pub stun_refresh: Option<StunRefresh>,
+    /// Capture relay frames (disabled if not set)
+    pub tap: Option<FrameTap>,
+    /// Connect, read and write timeouts of the relay connection
+    pub timeouts: ConnTimeouts,
+    /// Send and receive buffer sizes of the relay socket
+    pub socket_buffers: SocketBuffers,
+    /// Key of our cluster, switched to after the handshake (the handshake
+    /// key if not set)
+    pub cluster_block: Option<Arc<Box<dyn Block>>>,
+    /// Address family dialed first when the server has both
+    pub prefer_family: FamilyPreference,
+    /// Local address the relay connection is made from (any if not set)
+    pub bind_source: Option<IpAddr>,
+    /// Tunnel mode asked for in the handshake
+    pub mode: TunnelMode,
+    /// Bucket size data frames are padded to, with servers supporting it
+    /// (unpadded if not set)
+    pub pad: Option<u16>,
+    pub reconnect_delay: Duration,
+    /// Consecutive failed handshakes after which the client gives up
+    /// (retry forever if not set)
+    pub max_handshake_failures: Option<u32>,
+    /// When the connection switches to a fresh key, with servers speaking
+    /// `REKEY_VERSION`
+    pub rekey: RekeyPolicy,
+    /// Magic number starting every frame, as configured on the server
+    pub magic: u32,
 }
 
 pub struct RelayClient {
     cfg: RelayClientConfig,
+    /// Public STUN address advertised in keepalives, kept current by
+    /// `StunRefresh`
+    stun: Arc<RwLock<Option<StunAddr>>>,
     outbound_rx: mpsc::Receiver<Frame>,
     inbound_tx: mpsc::Sender<Frame>,
+    /// Frames forwarded while the inbound queue was nearly full
+    rx_near_full: Arc<AtomicU64>,
+    /// Whether a handshaken session with the server is up
+    connected: Arc<AtomicBool>,
+    /// Largest packet the current session carries, shared with
+    /// `RelayOutboundTx`
+    max_packet_len: Arc<AtomicUsize>,
+    /// Peer list version last received, acknowledged in keepalives
+    peers_version: u64,
+    /// Token of the last session, presented to resume it on reconnect
+    resume_token: Option<String>,
+    /// Sessions handshaken so far, the ones after the first are reconnects
+    sessions: u64,
+    /// Protocol version of the current session
+    version: u8,
+    /// Data frames written while the server was overdue, replayed on the
+    /// next session if this one turns out dead
+    unacked: VecDeque<Frame>,
     block: Arc<Box<dyn Block>>,
+    /// Cancelled on clean shutdown, the session then says goodbye
+    shutdown: CancellationToken,
 }
 
 impl RelayClient {
@@ -42,14 +133,33 @@ impl RelayClient {
         inbound_tx: mpsc::Sender<Frame>,
         block: Arc<Box<dyn Block>>,
     ) -> Self {
+        let session_block = cfg.cluster_block.as_ref().unwrap_or(&block);
+        let max_packet_len = Parser::max_packet_len(session_block.as_ref().as_ref(), MIN_VERSION);
         Self {
+            stun: Arc::new(RwLock::new(cfg.stun.clone())),
             cfg,
             outbound_rx,
             inbound_tx,
+            rx_near_full: Arc::new(AtomicU64::new(0)),
+            connected: Arc::new(AtomicBool::new(false)),
+            max_packet_len: Arc::new(AtomicUsize::new(max_packet_len)),
+            peers_version: 0,
+            resume_token: None,
+            sessions: 0,
+            version: MIN_VERSION,
+            unacked: VecDeque::new(),
             block,
+            shutdown: CancellationToken::new(),
         }
     }
 
+    /// Close the session with a `Close` frame and stop reconnecting once
+    /// `shutdown` is cancelled
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
     pub async fn run(&mut self, mut conn: Box<dyn ConnManage>) -> anyhow::Result<()> {
         let mut keepalive_ticker = interval(self.cfg.keepalive_interval);
         let mut keepalive_wait: u8 = 0;
@@ -59,14 +169,40 @@ impl RelayClient {
         ipv6_update_ticker.tick().await; // Skip first immediate tick
 
         let mut current_ipv6: Option<Ipv6Addr> = self.cfg.ipv6;
-        let stun = self.cfg.stun.clone();
+
+        let mut rekeyer = (self.cfg.rekey.is_enabled() && self.version >= REKEY_VERSION)
+            .then(|| Rekeyer::new(self.cfg.rekey, self.session_block()));
 
         let mut last_active = Instant::now();
-        let timeout_secs =
-            (self.cfg.keep_alive_thresh - 1) as u64 * self.cfg.keepalive_interval.as_secs();
+        let missed = self.cfg.keep_alive_thresh.saturating_sub(1).max(1);
+        let timeout = self.cfg.keepalive_interval * missed as u32;
+
+        // a silent server most likely never got what we wrote it last
+        if !self.unacked.is_empty() {
+            tracing::info!(
+                "resending {} frames of the last session",
+                self.unacked.len()
+            );
+            conn.write_frames(self.unacked.iter().cloned().collect())
+                .await?;
+            self.unacked.clear();
+        }
+
         loop {
             tokio::select! {
+                // the server drops us from routing right away
+                _ = self.shutdown.cancelled() => {
+                    let close = Frame::Close(CloseFrame {
+                        reason: "client shutdown".to_string(),
+                    });
+                    if let Err(e) = conn.write_frame(close).await {
+                        tracing::debug!("close frame not sent: {e}");
+                    }
+                    break;
+                }
+
                 _ = keepalive_ticker.tick() => {
+                    let stun = self.stun.read().unwrap_or_else(|e| e.into_inner()).clone();
                     if let ControlFlow::Break(_) = self
                         .keep_alive(
                             &mut conn,
@@ -74,32 +210,57 @@ impl RelayClient {
                             current_ipv6.map(|ipv6| SocketAddr::new(ipv6.into(), self.cfg.port)),
                             stun.as_ref(),
                             last_active,
-                            timeout_secs,
+                            timeout,
                         )
                         .await
                     {
                         break;
                     }
+                    if let ControlFlow::Break(_) = Self::maybe_rekey(&mut conn, rekeyer.as_mut()).await {
+                        break;
+                    }
                 }
 
                 // Periodic IPv6 address update check
-                _ = ipv6_update_ticker.tick() => {
+                _ = ipv6_update_ticker.tick(), if self.cfg.refresh_ipv6 => {
                     tracing::debug!("ipv6 update tick");
-                    if let Some(new_ipv6) = utils::get_ipv6().await {
-                        let curr_display = match current_ipv6 {
-                            None => "None".to_string(),
-                            Some(ipv6) => ipv6.to_string(),
-                        };
-                        tracing::info!("IPv6 address updated: {curr_display} -> {new_ipv6}");
-                        current_ipv6 = Some(new_ipv6);
-                    } else {
-                        tracing::debug!("Failed to retrieve IPv6 address during update check");
+                    let lookup = utils::lookup_ipv6().await;
+                    match lookup {
+                        Ipv6Lookup::Global(new_ipv6) => {
+                            let curr_display = match current_ipv6 {
+                                None => "None".to_string(),
+                                Some(ipv6) => ipv6.to_string(),
+                            };
+                            tracing::info!("IPv6 address updated: {curr_display} -> {new_ipv6}");
+                        }
+                        Ipv6Lookup::NonGlobal => {
+                            if let Some(ipv6) = current_ipv6 {
+                                tracing::info!("IPv6 address {ipv6} no longer global, not advertising it");
+                            }
+                        }
+                        Ipv6Lookup::Failed => {
+                            tracing::debug!("Failed to retrieve IPv6 address during update check");
+                        }
                     }
-                    // TODO：get stun port
+                    current_ipv6 = lookup.apply(current_ipv6);
+                    // the STUN address is refreshed by its own task, see `StunRefresh`
                 }
 
                 // inbound
                 result = conn.read_frame() => {
+                    if let Some(rekeyer) = rekeyer.as_mut() {
+                        rekeyer.count(1);
+                        if let Ok(Frame::Rekey(answer)) = &result {
+                            match rekeyer.finish(&answer.public_key, &self.cfg.identity) {
+                                Ok(block) => {
+                                    conn.set_block(block);
+                                    tracing::info!("relay connection rekeyed");
+                                }
+                                // the server switched already, its next frame fails the session
+                                Err(e) => tracing::warn!("rekey failed: {e}"),
+                            }
+                        }
+                    }
                     if let ControlFlow::Break(_) = self.read_frame(&mut keepalive_wait, &mut last_active, result).await {
                         break;
                     }
@@ -112,10 +273,22 @@ impl RelayClient {
                     }
 
                     let now = Instant::now();
-                    if let Err(e) = conn.write_frame(frame.unwrap()).await {
+                    let frames = drain_batch(frame.unwrap(), &mut self.outbound_rx, MAX_WRITE_BATCH);
+                    if last_active.elapsed() > self.cfg.keepalive_interval {
+                        self.hold_unacked(&frames);
+                    }
+                    if let Some(rekeyer) = rekeyer.as_mut() {
+                        rekeyer.count(frames.len());
+                    }
+                    if let Err(e) = conn.write_frames(frames).await {
+                        // reconnect now, later frames wait in the outbound queue
                         tracing::error!("device => server write frame: {e}");
+                        break;
                     }
                     tracing::debug!("send to server cost {}", now.elapsed().as_millis());
+                    if let ControlFlow::Break(_) = Self::maybe_rekey(&mut conn, rekeyer.as_mut()).await {
+                        break;
+                    }
                 }
             }
         }
@@ -129,29 +302,49 @@ impl RelayClient {
         &mut self,
         keepalive_wait: &mut u8,
         last_active: &mut Instant,
-        result: anyhow::Result<Frame>,
+        result: Result<Frame, RustunError>,
     ) -> ControlFlow<()> {
         *last_active = Instant::now();
         match result {
             Ok(frame) => {
                 tracing::debug!("received frame {frame}");
+                self.unacked.clear();
                 let beg = Instant::now();
                 match frame {
                     Frame::KeepAlive(keepalive) => {
                         *keepalive_wait = keepalive_wait.saturating_sub(1);
 
                         tracing::debug!("Received keepalive from server");
-                        if let Err(e) = self.inbound_tx.send(Frame::KeepAlive(keepalive)).await {
+                        // the server omits the peer list we already have
+                        if keepalive.peers_version != 0
+                            && keepalive.peers_version == self.peers_version
+                        {
+                            return ControlFlow::Continue(());
+                        }
+                        self.peers_version = keepalive.peers_version;
+                        if let Err(e) = self.forward(Frame::KeepAlive(keepalive)).await {
                             tracing::error!("Failed to forward keepalive: {e}");
                             return ControlFlow::Break(());
                         }
                     }
                     Frame::Data(data) => {
-                        if let Err(e) = self.inbound_tx.send(Frame::Data(data)).await {
+                        if let Err(e) = self.forward(Frame::Data(data)).await {
                             tracing::error!("server => device inbound: {e}");
                             return ControlFlow::Break(());
                         }
                     }
+                    frame @ (Frame::PeerUpdate(_) | Frame::PeerJoin(_) | Frame::PeerLeave(_)) => {
+                        if let Err(e) = self.forward(frame).await {
+                            tracing::error!("Failed to forward peer event: {e}");
+                            return ControlFlow::Break(());
+                        }
+                    }
+                    frame @ (Frame::Echo(_) | Frame::EchoReply(_)) => {
+                        if let Err(e) = self.forward(frame).await {
+                            tracing::error!("Failed to forward echo: {e}");
+                            return ControlFlow::Break(());
+                        }
+                    }
                     _ => {}
                 }
                 tracing::debug!("handle frame cost {}", beg.elapsed().as_millis());
@@ -164,6 +357,79 @@ impl RelayClient {
         ControlFlow::Continue(())
     }
 
+    /// Key the connection uses after the handshake
+    fn session_block(&self) -> Arc<Box<dyn Block>> {
+        self.cfg
+            .cluster_block
+            .clone()
+            .unwrap_or_else(|| self.block.clone())
+    }
+
+    /// Ask the server for a fresh key once the current one is used up
+    ///
+    /// Checked after writes and on keepalive ticks, which bounds how late an
+    /// expired key is noticed.
+    async fn maybe_rekey(
+        conn: &mut Box<dyn ConnManage>,
+        rekeyer: Option<&mut Rekeyer>,
+    ) -> ControlFlow<()> {
+        let Some(rekeyer) = rekeyer.filter(|r| r.is_due(Instant::now())) else {
+            return ControlFlow::Continue(());
+        };
+        let public_key = rekeyer.start();
+        if let Err(e) = conn
+            .write_frame(Frame::Rekey(RekeyFrame { public_key }))
+            .await
+        {
+            tracing::error!("Failed to send rekey: {e}");
+            return ControlFlow::Break(());
+        }
+        tracing::debug!("rekey requested");
+        ControlFlow::Continue(())
+    }
+
+    /// Queue a server frame for the device, counting near-full queues
+    ///
+    /// Waits for room when the queue is full, which also pauses keepalives,
+    /// so `rx_near_full` warns before that happens.
+    async fn forward(&mut self, frame: Frame) -> Result<(), mpsc::error::SendError<Frame>> {
+        if self.inbound_tx.capacity() <= self.inbound_tx.max_capacity() / 10 {
+            self.rx_near_full.fetch_add(1, Ordering::Relaxed);
+            tracing::debug!(
+                "relay inbound queue nearly full, {} slots left",
+                self.inbound_tx.capacity()
+            );
+        }
+        self.inbound_tx.send(frame).await
+    }
+
+    /// Keep the data frames of `frames` until the server is heard from
+    ///
+    /// The oldest are dropped beyond `MAX_UNACKED_FRAMES`.
+    fn hold_unacked(&mut self, frames: &[Frame]) {
+        let data = frames
+            .iter()
+            .filter(|frame| matches!(frame, Frame::Data(_) | Frame::DataBatch(_)));
+        self.unacked.extend(data.cloned());
+        let excess = self.unacked.len().saturating_sub(MAX_UNACKED_FRAMES);
+        self.unacked.drain(..excess);
+    }
+
+    /// IPv6 addresses for the keepalive, the discovered one first
+    fn advertised_ipv6(&self, current_ipv6: Option<SocketAddr>) -> Vec<String> {
+        let mut addrs: Vec<String> = current_ipv6
+            .map(|ipv6| ipv6.ip().to_string())
+            .into_iter()
+            .collect();
+        for extra in &self.cfg.extra_ipv6 {
+            let extra = extra.to_string();
+            if !addrs.contains(&extra) {
+                addrs.push(extra);
+            }
+        }
+        addrs
+    }
+
     async fn keep_alive(
         &mut self,
         conn: &mut Box<dyn ConnManage + 'static>,
@@ -171,9 +437,9 @@ impl RelayClient {
         current_ipv6: Option<SocketAddr>,
         stun: Option<&StunAddr>,
         last_active: Instant,
-        timeout_secs: u64,
+        timeout: Duration,
     ) -> ControlFlow<()> {
-        if last_active.elapsed().as_secs() > timeout_secs {
+        if last_active.elapsed() > timeout {
             tracing::warn!("keepalive threshold {:?} exceeded", last_active.elapsed());
             return ControlFlow::Break(());
         }
@@ -181,16 +447,20 @@ impl RelayClient {
         let keepalive_frame = Frame::KeepAlive(KeepAliveFrame {
             name: "".to_string(),
             identity: self.cfg.identity.clone(),
-            ipv6: current_ipv6
-                .map(|ipv6| ipv6.ip().to_string())
-                .unwrap_or_default(),
-            port: current_ipv6.map(|ipv6| ipv6.port()).unwrap_or_default(),
+            ipv6: self.advertised_ipv6(current_ipv6),
+            port: match current_ipv6 {
+                Some(ipv6) => ipv6.port(),
+                None if !self.cfg.extra_ipv6.is_empty() => self.cfg.port,
+                None => 0,
+            },
             #[allow(clippy::unwrap_or_default)]
             stun_ip: stun
                 .as_ref()
                 .map(|stun| stun.ip.clone())
                 .unwrap_or(String::new()),
             stun_port: stun.as_ref().map(|stun| stun.port).unwrap_or(0),
+            nat_type: stun.as_ref().map(|stun| stun.nat_type).unwrap_or(0),
+            peers_version: self.peers_version,
             peer_details: vec![], // Client doesn't need to send peer info
         });
 
@@ -210,31 +480,70 @@ impl RelayClient {
         ControlFlow::Continue(())
     }
 
-    async fn connect(&self) -> anyhow::Result<Box<dyn ConnManage>> {
-        create_connection(
+    async fn connect(&self) -> Result<Box<dyn ConnManage>, RustunError> {
+        let conn = create_connection(
             ConnectionConfig::TCP(TCPConnectionConfig {
                 server_addr: self.cfg.server_addr.clone(),
+                tap: self.cfg.tap.clone(),
+                timeouts: self.cfg.timeouts,
+                socket_buffers: self.cfg.socket_buffers,
+                prefer_family: self.cfg.prefer_family,
+                bind_source: self.cfg.bind_source,
+                resolved: self.cfg.server_addrs.clone(),
+                magic: self.cfg.magic,
             }),
             self.block.clone(),
         )
-        .await
+        .await?;
+        Ok(conn)
     }
 
+    /// Send our handshake and wait for the server's reply
+    ///
+    /// An identity or token the server does not accept is reported as
+    /// `RustunError::Unauthorized`. Older servers close the connection
+    /// without replying instead, which is `RustunError::Closed` like a
+    /// server going away mid-handshake.
     async fn handshake(
         &self,
         conn: &mut Box<dyn ConnManage>,
-    ) -> anyhow::Result<HandshakeReplyFrame> {
+    ) -> Result<HandshakeReplyFrame, RustunError> {
         conn.write_frame(Frame::Handshake(HandshakeFrame {
             identity: self.cfg.identity.clone(),
+            token: self.cfg.token.clone(),
+            max_version: MAX_VERSION,
+            resume_token: self.resume_token.clone(),
+            mode: self.cfg.mode,
+            pad: self.cfg.pad,
         }))
         .await?;
 
-        let frame = conn.read_frame().await?;
-        if let Frame::HandshakeReply(frame) = frame {
-            return Ok(frame);
+        match conn.read_frame().await {
+            Ok(Frame::HandshakeReply(frame)) if frame.mode != self.cfg.mode => {
+                Err(RustunError::Refused(format!(
+                    "server granted a {:?} tunnel, {:?} was asked",
+                    frame.mode, self.cfg.mode
+                )))
+            }
+            Ok(Frame::HandshakeReply(frame)) => {
+                conn.set_version(negotiate_version(MAX_VERSION, frame.version));
+                // servers without padding could not read padded frames
+                conn.set_padding(frame.pad.map(usize::from));
+                if let Some(block) = &self.cfg.cluster_block {
+                    conn.set_block(block.clone());
+                }
+                Ok(frame)
+            }
+            Ok(Frame::HandshakeReject(frame)) if frame.reason == REJECT_UNAUTHORIZED => {
+                Err(RustunError::Unauthorized(self.cfg.identity.clone()))
+            }
+            Ok(Frame::HandshakeReject(frame)) => Err(RustunError::Rejected(frame.reason)),
+            Ok(frame) => Err(RustunError::Other(anyhow::anyhow!(
+                "unexpected {} frame when handshaking",
+                frame.type_name()
+            ))),
+            Err(e) => Err(e),
         }
-
-        Err(anyhow::anyhow!("invalid frame"))
     }
 }
 
@@ -244,36 +553,121 @@ pub struct RelayStatus {
     pub rx_frame: u64,
     pub tx_frame: u64,
     pub tx_error: u64,
+    /// Frames dropped because the relay outbound queue was full
+    pub tx_dropped: u64,
+    /// Frames received while the inbound queue was at least 90% full
+    pub rx_near_full: u64,
+    /// Whether the relay session is up, frames queue while it is down
+    pub connected: bool,
+}
+
+/// What became of a frame handed to [`RelayHandler::send_frame`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendStatus {
+    /// Queued to the live relay session
+    Sent,
+    /// Queued while the relay is down, sent once it reconnects
+    Queued,
+    /// Discarded because the outbound queue was full, counted in `tx_dropped`
+    Dropped,
+}
+
+/// Sending half of the relay outbound queue
+///
+/// Sends never wait for room in the queue: when the relay connection is slow
+/// or stalled and the queue is full, the new frame is dropped and counted so
+/// that the caller keeps servicing the TUN device and P2P paths.
+///
+/// The queue outlives individual relay connections, so the same sender keeps
+/// working across reconnects and frames sent while reconnecting are delivered
+/// once the new connection is up. `is_connected` tells whether frames are
+/// flowing or waiting for that reconnect.
+#[derive(Clone, Debug)]
+pub struct RelayOutboundTx {
+    tx: mpsc::Sender<Frame>,
+    tx_dropped: Arc<AtomicU64>,
+    connected: Arc<AtomicBool>,
+    /// Data frames above this size are dropped, see [`Parser::check_packet_len`]
+    max_packet_len: Arc<AtomicUsize>,
+}
+
+impl RelayOutboundTx {
+    pub(crate) fn new(
+        tx: mpsc::Sender<Frame>,
+        tx_dropped: Arc<AtomicU64>,
+        connected: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            tx,
+            tx_dropped,
+            connected,
+            max_packet_len: Arc::new(AtomicUsize::new(MAX_PAYLOAD_LEN)),
+        }
+    }
+
+    /// Drop data frames carrying more than `max_packet_len` bytes, updated
+    /// by each session for the version it negotiated
+    pub(crate) fn with_max_packet_len(mut self, max_packet_len: Arc<AtomicUsize>) -> Self {
+        self.max_packet_len = max_packet_len;
+        self
+    }
+
+    /// Largest packet a data frame to the relay server may carry
+    pub fn max_packet_len(&self) -> usize {
+        self.max_packet_len.load(Ordering::Relaxed)
+    }
+
+    /// Whether the relay session is up
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
 }
 
 pub struct RelayHandler {
-    outbound_tx: Option<mpsc::Sender<Frame>>,
+    outbound_tx: Option<RelayOutboundTx>,
+    tx_dropped: Arc<AtomicU64>,
+    rx_near_full: Arc<AtomicU64>,
+    connected: Arc<AtomicBool>,
     inbound_rx: mpsc::Receiver<Frame>,
-    inbound_tx: mpsc::Sender<Frame>,
     block: Arc<Box<dyn Block>>,
     metrics: RelayStatus,
     // Self information
     config: Option<RelayClientConfig>,
     handshake_reply: Arc<RwLock<Option<HandshakeReplyFrame>>>,
+    stun: Arc<RwLock<Option<StunAddr>>>,
+    /// Why the relay client gave up reconnecting, set once it has
+    fatal: Arc<RwLock<Option<String>>>,
+    /// Ends the relay client, see `close`
+    shutdown: CancellationToken,
+    /// Task running the relay sessions
+    session: Option<JoinHandle<()>>,
 }
 
 impl RelayHandler {
     pub fn new(block: Arc<Box<dyn Block>>) -> RelayHandler {
-        let (inbound_tx, inbound_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+        // replaced by the configured queue in `run_client`
+        let (_, inbound_rx) = mpsc::channel(1);
         RelayHandler {
             outbound_tx: None,
+            tx_dropped: Arc::new(AtomicU64::new(0)),
+            rx_near_full: Arc::new(AtomicU64::new(0)),
+            connected: Arc::new(AtomicBool::new(false)),
             inbound_rx,
-            inbound_tx,
             block,
             metrics: Default::default(),
             config: None,
             handshake_reply: Arc::new(RwLock::new(None)),
+            stun: Arc::new(RwLock::new(None)),
+            fatal: Arc::new(RwLock::new(None)),
+            shutdown: CancellationToken::new(),
+            session: None,
         }
     }
 
     /// Get self information
     pub async fn get_self_info(&self) -> Option<SelfInfo> {
         let reply_guard = self.handshake_reply.read().unwrap();
+        let stun = self.stun.read().unwrap().clone();
         match (&self.config, reply_guard.as_ref()) {
             (Some(cfg), Some(reply)) => Some(SelfInfo {
                 identity: cfg.identity.clone(),
@@ -283,12 +677,11 @@ impl RelayHandler {
                 ciders: reply.ciders.clone(),
                 ipv6: cfg.ipv6.map(|ipv6| ipv6.to_string()).unwrap_or_default(),
                 port: cfg.port,
-                stun_ip: cfg
-                    .stun
+                stun_ip: stun
                     .as_ref()
                     .map(|stun| stun.ip.clone())
-                    .unwrap_or(String::new()),
-                stun_port: cfg.stun.as_ref().map(|stun| stun.port).unwrap_or(0),
+                    .unwrap_or_default(),
+                stun_port: stun.as_ref().map(|stun| stun.port).unwrap_or(0),
             }),
             _ => None,
         }
@@ -303,37 +696,121 @@ impl RelayHandler {
         self.config = Some(cfg.clone());
 
         let (outbound_tx, outbound_rx) = mpsc::channel(cfg.outbound_buffer_size);
-        let mut client = RelayClient::new(
-            cfg.clone(),
-            outbound_rx,
-            self.inbound_tx.clone(),
-            self.block.clone(),
+        let (inbound_tx, inbound_rx) = mpsc::channel(cfg.inbound_buffer_size);
+        self.inbound_rx = inbound_rx;
+        let mut client = RelayClient::new(cfg.clone(), outbound_rx, inbound_tx, self.block.clone())
+            .with_shutdown(self.shutdown.clone());
+        self.rx_near_full = client.rx_near_full.clone();
+        self.connected = client.connected.clone();
+        self.outbound_tx = Some(
+            RelayOutboundTx::new(outbound_tx, self.tx_dropped.clone(), self.connected.clone())
+                .with_max_packet_len(client.max_packet_len.clone()),
         );
-        self.outbound_tx = Some(outbound_tx);
+        self.stun = client.stun.clone();
+        if let Some(refresh) = cfg.stun_refresh.clone() {
+            let stun = client.stun.clone();
+            supervise("STUN refresh", STUN_REFRESH_RESTART_DELAY, move || {
+                refresh.clone().run(stun.clone())
+            });
+        }
 
         // Store handshake reply when received
         let handshake_reply = self.handshake_reply.clone();
+        let fatal = self.fatal.clone();
 
-        tokio::spawn(async move {
+        // giving up drops the client and with it the inbound queue, which
+        // ends `recv_frame` with the reason
+        let shutdown = self.shutdown.clone();
+        self.session = Some(tokio::spawn(async move {
+            let mut handshake_failures = 0;
             loop {
-                run_client_session(&on_ready, &mut client, &handshake_reply).await;
-                tokio::time::sleep(Duration::from_secs(5)).await;
+                let session = run_client_session(&on_ready, &mut client, &handshake_reply);
+                match catch_panic(session).await {
+                    Ok(SessionEnd::HandshakeFailed(e)) => {
+                        handshake_failures += 1;
+                        if let Some(reason) =
+                            give_up_reason(&e, handshake_failures, cfg.max_handshake_failures)
+                        {
+                            tracing::error!("giving up on the relay server: {reason}");
+                            *fatal.write().unwrap() = Some(reason);
+                            return;
+                        }
+                    }
+                    Ok(SessionEnd::Established) => handshake_failures = 0,
+                    Ok(SessionEnd::ConnectFailed) => {}
+                    Err(panic) => {
+                        tracing::error!("relay session panicked, reconnecting: {panic}");
+                        client.connected.store(false, Ordering::Relaxed);
+                    }
+                }
+                if shutdown.is_cancelled() {
+                    return;
+                }
+                tokio::time::sleep(cfg.reconnect_delay).await;
             }
-        });
+        }));
     }
 
-    pub fn get_outbound_tx(&self) -> Option<mpsc::Sender<Frame>> {
+    /// Leave the relay server on purpose
+    ///
+    /// A connected session sends a `Close` frame, so the server stops
+    /// routing to us at once. Gives up after `CLOSE_TIMEOUT`, e.g. while
+    /// reconnecting.
+    pub async fn close(&mut self) {
+        self.shutdown.cancel();
+        let Some(mut session) = self.session.take() else {
+            return;
+        };
+        if tokio::time::timeout(CLOSE_TIMEOUT, &mut session)
+            .await
+            .is_err()
+        {
+            session.abort();
+        }
+    }
+
+    /// Whether a session with the relay server is up
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Why the relay client gave up reconnecting, if it has
+    pub fn fatal(&self) -> Option<String> {
+        self.fatal.read().unwrap().clone()
+    }
+
+    /// Our STUN mapping as currently advertised to the server
+    pub fn stun(&self) -> Arc<RwLock<Option<StunAddr>>> {
+        self.stun.clone()
+    }
+
+    pub fn get_outbound_tx(&self) -> Option<RelayOutboundTx> {
         self.outbound_tx.clone()
     }
 
-    pub async fn send_frame(outbound_tx: mpsc::Sender<Frame>, frame: Frame) -> anyhow::Result<()> {
-        // self.metrics.tx_frame += 1;
-        let result = outbound_tx.send(frame).await;
-        match result {
-            Ok(()) => Ok(()),
-            Err(e) => {
-                // self.metrics.tx_error += 1;
-                Err(anyhow::anyhow!("device=> server fail {e:?}"))
+    /// Queue a frame for the relay server without waiting
+    ///
+    /// Uses a drop-new policy: if the outbound queue is full the frame is
+    /// discarded, `tx_dropped` is incremented and [`SendStatus::Dropped`] is
+    /// returned. While the relay is down frames wait for the next session and
+    /// [`SendStatus::Queued`] is returned; the queue fills and later frames
+    /// are dropped the same way. Errors mean the frame can never be sent.
+    pub fn send_frame(outbound_tx: &RelayOutboundTx, frame: Frame) -> anyhow::Result<SendStatus> {
+        // an oversized packet would overflow the frame length and corrupt the stream
+        Parser::check_packet_len(&frame, outbound_tx.max_packet_len())?;
+        let status = if outbound_tx.is_connected() {
+            SendStatus::Sent
+        } else {
+            SendStatus::Queued
+        };
+        match outbound_tx.tx.try_send(frame) {
+            Ok(()) => Ok(status),
+            Err(TrySendError::Full(_)) => {
+                outbound_tx.tx_dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(SendStatus::Dropped)
+            }
+            Err(TrySendError::Closed(_)) => {
+                Err(anyhow::anyhow!("device=> server fail for closed channel"))
             }
         }
     }
@@ -347,13 +824,61 @@ impl RelayHandler {
             }
             None => {
                 self.metrics.rx_error += 1;
-                Err(anyhow::anyhow!("server => device fail for closed channel"))
+                match self.fatal() {
+                    Some(reason) => Err(anyhow::anyhow!("relay client stopped: {reason}")),
+                    None => Err(anyhow::anyhow!("server => device fail for closed channel")),
+                }
             }
         }
     }
 
+    /// Key of the traffic after the handshake, shared by the peers of our
+    /// cluster
+    pub fn data_block(&self) -> Arc<Box<dyn Block>> {
+        self.config
+            .as_ref()
+            .and_then(|cfg| cfg.cluster_block.clone())
+            .unwrap_or_else(|| self.block.clone())
+    }
+
+    /// Magic number of our frames, shared by the peers of our cluster
+    pub fn magic(&self) -> u32 {
+        self.config.as_ref().map_or(DEFAULT_MAGIC, |cfg| cfg.magic)
+    }
+
     pub fn get_status(&self) -> RelayStatus {
-        self.metrics.clone()
+        RelayStatus {
+            tx_dropped: self.tx_dropped.load(Ordering::Relaxed),
+            rx_near_full: self.rx_near_full.load(Ordering::Relaxed),
+            connected: self.connected.load(Ordering::Relaxed),
+            ..self.metrics.clone()
+        }
+    }
+}
+
+/// How a relay session ended
+enum SessionEnd {
+    /// The server could not be reached
+    ConnectFailed,
+    /// The server did not accept our handshake
+    HandshakeFailed(RustunError),
+    /// The session was up and is lost now
+    Established,
+}
+
+/// Why to stop reconnecting after `failures` handshakes in a row failed,
+/// the last with `e`
+///
+/// A refused or unauthorized handshake would fail the same way again. Older
+/// servers reject an unknown identity by closing the connection, which a
+/// restarting server does too, so that only counts towards `max_failures`.
+fn give_up_reason(e: &RustunError, failures: u32, max_failures: Option<u32>) -> Option<String> {
+    match e {
+        RustunError::Refused(_) | RustunError::Unauthorized(_) => Some(e.to_string()),
+        _ if max_failures.is_some_and(|max| failures >= max) => {
+            Some(format!("{failures} handshakes failed in a row, last: {e}"))
+        }
+        _ => None,
     }
 }
 
@@ -361,24 +886,74 @@ async fn run_client_session(
     on_ready: &mpsc::Sender<HandshakeReplyFrame>,
     client: &mut RelayClient,
     handshake_reply: &Arc<RwLock<Option<HandshakeReplyFrame>>>,
-) {
+) -> SessionEnd {
     let mut conn = match client.connect().await {
         Ok(socket) => socket,
         Err(e) => {
             tracing::error!("connect error: {e}");
-            return;
+            return SessionEnd::ConnectFailed;
         }
     };
 
     let frame = match client.handshake(&mut conn).await {
         Ok(frame) => frame,
         Err(e) => {
-            tracing::warn!("handshake fail {e:?}, reconnecting");
-            return;
+            match &e {
+                RustunError::Closed => tracing::error!(
+                    "server closed the connection during the handshake, check identity {} and token",
+                    client.cfg.identity
+                ),
+                // given up on by the caller
+                RustunError::Refused(_) | RustunError::Unauthorized(_) => {
+                    tracing::error!("handshake fail: {e}")
+                }
+                e => tracing::warn!("handshake fail {e:?}, reconnecting"),
+            }
+            return SessionEnd::HandshakeFailed(e);
         }
     };
 
     tracing::info!("Handshake complete with {} peers", frame.peer_details.len());
+    client.peers_version = frame.peers_version;
+    client.resume_token = frame.resume_token.clone();
+    client.version = negotiate_version(MAX_VERSION, frame.version);
+    client.max_packet_len.store(
+        Parser::max_packet_len(client.session_block().as_ref().as_ref(), client.version),
+        Ordering::Relaxed,
+    );
+    client.sessions += 1;
+    if frame.resumed {
+        // the server only sent the peers that changed, applied like joins
+        for peer in frame.peer_details.clone() {
+            if let Err(e) = client
+                .forward(Frame::PeerJoin(PeerJoinFrame { peer }))
+                .await
+            {
+                tracing::error!("Failed to forward resumed peer: {e}");
+                return SessionEnd::Established;
+            }
+        }
+    } else if client.sessions > 1 && (frame.peers_version != 0 || !frame.peer_details.is_empty()) {
+        // the full list of a fresh session, applied like a keepalive: routes
+        // reload and P2P merges it, keeping the state of live peers. Later
+        // keepalives leave it out as we are at its version now. An empty
+        // unversioned list follows in the first keepalive reply instead.
+        let keepalive = KeepAliveFrame {
+            name: String::new(),
+            identity: String::new(),
+            ipv6: vec![],
+            port: 0,
+            stun_ip: String::new(),
+            stun_port: 0,
+            nat_type: 0,
+            peers_version: frame.peers_version,
+            peer_details: frame.peer_details.clone(),
+        };
+        if let Err(e) = client.forward(Frame::KeepAlive(keepalive)).await {
+            tracing::error!("Failed to forward reconnected peers: {e}");
+            return SessionEnd::Established;
+        }
+    }
 
     // Store handshake reply in handler
     {
@@ -390,9 +965,12 @@ async fn run_client_session(
         tracing::error!("on ready send fail: {e}");
     }
 
+    client.connected.store(true, Ordering::Relaxed);
     let result = client.run(conn).await;
+    client.connected.store(false, Ordering::Relaxed);
 
     tracing::warn!("run client fail {result:?}, reconnecting");
+    SessionEnd::Established
 }
 
 pub async fn new_relay_handler(
@@ -401,16 +979,58 @@ pub async fn new_relay_handler(
     ipv6: Option<Ipv6Addr>,
     port: u16,
     stun: Option<StunAddr>,
+    stun_refresh: Option<StunRefresh>,
+    tap: Option<FrameTap>,
 ) -> anyhow::Result<(RelayHandler, HandshakeReplyFrame)> {
+    let cluster_block: Option<Arc<Box<dyn Block>>> = match &args.cluster_crypto {
+        Some(crypto_str) => match crate::crypto::parse_crypto_config(crypto_str) {
+            Ok(cfg) => Some(Arc::new(crate::crypto::new_block(&cfg))),
+            Err(e) => anyhow::bail!("Invalid cluster crypto configuration: {e}"),
+        },
+        None => None,
+    };
+    // the DNS kill switch may leave the server's name unresolvable later
+    let server_addrs = match args.dns_kill_switch {
+        true => tokio::net::lookup_host(&args.server).await?.collect(),
+        false => vec![],
+    };
     let client_config = RelayClientConfig {
         server_addr: args.server.clone(),
+        server_addrs,
         keepalive_interval: Duration::from_secs(args.keepalive_interval),
         outbound_buffer_size: CHANNEL_BUFFER_SIZE,
+        inbound_buffer_size: CHANNEL_BUFFER_SIZE,
         keep_alive_thresh: args.keepalive_threshold,
         identity: args.identity.clone(),
+        token: args.token.clone(),
         ipv6,
+        extra_ipv6: args.advertise_ipv6.clone(),
+        refresh_ipv6: args.p2p_source_v6().is_none(),
         port,
         stun,
+        stun_refresh,
+        tap,
+        timeouts: ConnTimeouts {
+            connect: Duration::from_secs(args.connect_timeout),
+            read: Duration::from_secs(args.read_timeout),
+            write: Duration::from_secs(args.write_timeout),
+        },
+        socket_buffers: SocketBuffers {
+            send: args.send_buffer_size,
+            recv: args.recv_buffer_size,
+        },
+        cluster_block,
+        prefer_family: args.prefer_family,
+        bind_source: args.bind_source,
+        mode: args.tunnel_mode,
+        pad: args.pad,
+        reconnect_delay: RECONNECT_DELAY,
+        max_handshake_failures: args.max_handshake_failures,
+        rekey: RekeyPolicy {
+            max_frames: args.rekey_after_frames,
+            max_age: args.rekey_interval.map(Duration::from_secs),
+        },
+        magic: args.magic()?,
     };
 
     let mut handler = RelayHandler::new(block);
@@ -420,9 +1040,667 @@ pub async fn new_relay_handler(
     let device_config = config_ready_rx
         .recv()
         .await
-        .ok_or_else(|| anyhow::anyhow!("Failed to receive device config from server"))?;
+        .ok_or_else(|| match handler.fatal() {
+            Some(reason) => {
+                anyhow::anyhow!("Failed to receive device config from server: {reason}")
+            }
+            None => anyhow::anyhow!("Failed to receive device config from server"),
+        })?;
 
     log_handshake_success(&device_config);
 
     Ok((handler, device_config))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::p2p::stun::{NatType, StunDiscoveryResult, StunProvider, StunSocket};
+    use crate::codec::frame::{DataFrame, HandshakeRejectFrame, PeerDetail};
+    use crate::crypto::plain::PlainBlock;
+    use crate::network::tcp_connection::TcpConnection;
+    use crate::network::{ConnRead, ConnWrite};
+
+    fn data_frame() -> Frame {
+        Frame::Data(DataFrame {
+            payload: vec![0x45; 20],
+            seq: None,
+        })
+    }
+
+    /// Client config for a server listening on `listener`, tests override
+    /// what they exercise
+    fn test_config(listener: &tokio::net::TcpListener) -> RelayClientConfig {
+        RelayClientConfig {
+            server_addr: listener.local_addr().unwrap().to_string(),
+            server_addrs: vec![],
+            keepalive_interval: Duration::from_secs(60),
+            outbound_buffer_size: 16,
+            inbound_buffer_size: 16,
+            keep_alive_thresh: 3,
+            identity: "a".to_string(),
+            token: None,
+            ipv6: None,
+            extra_ipv6: vec![],
+            refresh_ipv6: true,
+            port: 0,
+            stun: None,
+            stun_refresh: None,
+            reconnect_delay: Duration::from_millis(10),
+            max_handshake_failures: None,
+            rekey: Default::default(),
+            tap: None,
+            timeouts: Default::default(),
+            socket_buffers: Default::default(),
+            cluster_block: None,
+            prefer_family: Default::default(),
+            bind_source: None,
+            mode: Default::default(),
+            pad: None,
+            magic: DEFAULT_MAGIC,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_frame_drops_when_full() {
+        let mut handler = RelayHandler::new(Arc::new(Box::new(PlainBlock::new())));
+        let (tx, mut rx) = mpsc::channel(2);
+        let outbound =
+            RelayOutboundTx::new(tx, handler.tx_dropped.clone(), handler.connected.clone());
+        handler.outbound_tx = Some(outbound.clone());
+        let send = || RelayHandler::send_frame(&outbound, data_frame()).unwrap();
+
+        assert_eq!(send(), SendStatus::Queued);
+        assert_eq!(send(), SendStatus::Queued);
+
+        // Nobody drains the queue: further sends are dropped instead of waiting
+        for _ in 0..3 {
+            assert_eq!(send(), SendStatus::Dropped);
+        }
+        assert_eq!(handler.get_status().tx_dropped, 3);
+
+        // Draining makes room again
+        rx.recv().await.unwrap();
+        assert_eq!(send(), SendStatus::Queued);
+        assert_eq!(handler.get_status().tx_dropped, 3);
+    }
+
+    #[tokio::test]
+    async fn test_send_frame_rejects_oversized_packet() {
+        let handler = RelayHandler::new(Arc::new(Box::new(PlainBlock::new())));
+        let (tx, mut rx) = mpsc::channel(2);
+        let outbound =
+            RelayOutboundTx::new(tx, handler.tx_dropped.clone(), handler.connected.clone())
+                .with_max_packet_len(Arc::new(AtomicUsize::new(1500)));
+
+        let frame = Frame::Data(DataFrame {
+            payload: vec![0x45; 1501],
+            seq: None,
+        });
+        let e = RelayHandler::send_frame(&outbound, frame).unwrap_err();
+        assert!(e.to_string().starts_with("packet too large for MTU"));
+        assert!(rx.try_recv().is_err());
+
+        assert!(RelayHandler::send_frame(&outbound, data_frame()).is_ok());
+        assert!(rx.try_recv().is_ok());
+    }
+
+    async fn accept_handshake(listener: &tokio::net::TcpListener) -> TcpConnection {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut conn = TcpConnection::from_socket(socket);
+        assert!(matches!(
+            conn.read_frame().await.unwrap(),
+            Frame::Handshake(_)
+        ));
+        conn
+    }
+
+    async fn reply_handshake(conn: &mut TcpConnection) {
+        reply_handshake_with_peers(conn, vec![]).await;
+    }
+
+    async fn reply_handshake_with_peers(conn: &mut TcpConnection, peer_details: Vec<PeerDetail>) {
+        conn.write_frame(Frame::HandshakeReply(handshake_reply(peer_details)))
+            .await
+            .unwrap();
+    }
+
+    fn handshake_reply(peer_details: Vec<PeerDetail>) -> HandshakeReplyFrame {
+        HandshakeReplyFrame {
+            name: "a".to_string(),
+            private_ip: "10.0.0.1".to_string(),
+            mask: "255.255.255.0".to_string(),
+            gateway: "10.0.0.254".to_string(),
+            ciders: vec![],
+            cider_mapping: Default::default(),
+            peers_version: 0,
+            resume_token: None,
+            resumed: false,
+            peer_details,
+            version: crate::codec::parser::MIN_VERSION,
+            mode: Default::default(),
+            pad: None,
+        }
+    }
+
+    async fn read_data(conn: &mut TcpConnection) -> Vec<u8> {
+        loop {
+            match conn.read_frame().await.unwrap() {
+                Frame::Data(frame) => return frame.payload,
+                Frame::KeepAlive(_) => continue,
+                frame => panic!("unexpected frame {frame}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frames_flow_after_reconnect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = test_config(&listener);
+        let mut handler = RelayHandler::new(Arc::new(Box::new(PlainBlock::new())));
+        let (ready_tx, mut ready_rx) = mpsc::channel(CONFIG_CHANNEL_SIZE);
+        handler.run_client(cfg, ready_tx);
+        let outbound = handler.get_outbound_tx().unwrap();
+
+        let mut conn = accept_handshake(&listener).await;
+        reply_handshake(&mut conn).await;
+        ready_rx.recv().await.unwrap();
+        RelayHandler::send_frame(
+            &outbound,
+            Frame::Data(DataFrame {
+                payload: vec![1],
+                seq: None,
+            }),
+        )
+        .unwrap();
+        assert_eq!(read_data(&mut conn).await, vec![1]);
+
+        // Server drops the connection, the client reconnects on its own
+        conn.close().await;
+        drop(conn);
+        let mut conn = accept_handshake(&listener).await;
+
+        // Sent while the client is still handshaking: queued, not lost
+        RelayHandler::send_frame(
+            &outbound,
+            Frame::Data(DataFrame {
+                payload: vec![2],
+                seq: None,
+            }),
+        )
+        .unwrap();
+        reply_handshake(&mut conn).await;
+        assert_eq!(read_data(&mut conn).await, vec![2]);
+
+        RelayHandler::send_frame(
+            &outbound,
+            Frame::Data(DataFrame {
+                payload: vec![3],
+                seq: None,
+            }),
+        )
+        .unwrap();
+        assert_eq!(read_data(&mut conn).await, vec![3]);
+    }
+
+    #[tokio::test]
+    async fn test_wide_session_carries_packets_over_64kb() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = test_config(&listener);
+        let mut handler = RelayHandler::new(Arc::new(Box::new(PlainBlock::new())));
+        let (ready_tx, mut ready_rx) = mpsc::channel(CONFIG_CHANNEL_SIZE);
+        handler.run_client(cfg, ready_tx);
+        let outbound = handler.get_outbound_tx().unwrap();
+        let packet = vec![0x45; 100 << 10];
+        let frame = || {
+            Frame::Data(DataFrame {
+                payload: packet.clone(),
+                seq: None,
+            })
+        };
+        // version 1 frames cannot carry it
+        assert!(RelayHandler::send_frame(&outbound, frame()).is_err());
+
+        let mut conn = accept_handshake(&listener).await;
+        conn.write_frame(Frame::HandshakeReply(HandshakeReplyFrame {
+            version: MAX_VERSION,
+            ..handshake_reply(vec![])
+        }))
+        .await
+        .unwrap();
+        ready_rx.recv().await.unwrap();
+        RelayHandler::send_frame(&outbound, frame()).unwrap();
+        assert_eq!(read_data(&mut conn).await, packet);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_forwards_fresh_peer_list() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = test_config(&listener);
+        let peer = PeerDetail {
+            name: "b".to_string(),
+            identity: "b".to_string(),
+            private_ip: "10.0.0.2".to_string(),
+            ciders: vec![],
+            ipv6: vec!["2001:db8::2".to_string()],
+            port: 51258,
+            stun_ip: String::new(),
+            stun_port: 0,
+            nat_type: 0,
+            last_active: 0,
+        };
+        let mut handler = RelayHandler::new(Arc::new(Box::new(PlainBlock::new())));
+        let (ready_tx, mut ready_rx) = mpsc::channel(CONFIG_CHANNEL_SIZE);
+        handler.run_client(cfg, ready_tx);
+
+        // the first list is the device config, nothing to forward
+        let mut conn = accept_handshake(&listener).await;
+        reply_handshake_with_peers(&mut conn, vec![peer.clone()]).await;
+        assert_eq!(ready_rx.recv().await.unwrap().peer_details.len(), 1);
+
+        conn.close().await;
+        drop(conn);
+        let mut conn = accept_handshake(&listener).await;
+        reply_handshake_with_peers(&mut conn, vec![peer]).await;
+        let frame = tokio::time::timeout(Duration::from_secs(2), handler.recv_frame())
+            .await
+            .unwrap()
+            .unwrap();
+        match frame {
+            Frame::KeepAlive(keepalive) => {
+                assert_eq!(keepalive.peer_details.len(), 1);
+                assert_eq!(keepalive.peer_details[0].identity, "b");
+            }
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frames_to_silent_server_resent_after_reconnect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = RelayClientConfig {
+            keepalive_interval: Duration::from_millis(50),
+            ..test_config(&listener)
+        };
+        let mut handler = RelayHandler::new(Arc::new(Box::new(PlainBlock::new())));
+        let (ready_tx, mut ready_rx) = mpsc::channel(CONFIG_CHANNEL_SIZE);
+        handler.run_client(cfg, ready_tx);
+        let outbound = handler.get_outbound_tx().unwrap();
+
+        // the server handshakes, then neither reads nor answers
+        let mut silent = accept_handshake(&listener).await;
+        reply_handshake(&mut silent).await;
+        ready_rx.recv().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(70)).await;
+        for n in [1, 2] {
+            RelayHandler::send_frame(
+                &outbound,
+                Frame::Data(DataFrame {
+                    payload: vec![n],
+                    seq: None,
+                }),
+            )
+            .unwrap();
+        }
+
+        // past the keepalive threshold the client reconnects on its own
+        let mut conn = accept_handshake(&listener).await;
+        reply_handshake(&mut conn).await;
+        assert_eq!(read_data(&mut conn).await, vec![1]);
+        assert_eq!(read_data(&mut conn).await, vec![2]);
+        drop(silent);
+    }
+
+    async fn wait_connected(outbound: &RelayOutboundTx, connected: bool) {
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while outbound.is_connected() != connected {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("relay state should change");
+    }
+
+    #[tokio::test]
+    async fn test_connected_state_follows_session() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = test_config(&listener);
+        let mut handler = RelayHandler::new(Arc::new(Box::new(PlainBlock::new())));
+        let (ready_tx, mut ready_rx) = mpsc::channel(CONFIG_CHANNEL_SIZE);
+        handler.run_client(cfg, ready_tx);
+        let outbound = handler.get_outbound_tx().unwrap();
+
+        // down until the handshake completes
+        let mut conn = accept_handshake(&listener).await;
+        assert!(!outbound.is_connected());
+        assert!(!handler.get_status().connected);
+        reply_handshake(&mut conn).await;
+        ready_rx.recv().await.unwrap();
+        wait_connected(&outbound, true).await;
+        assert!(handler.get_status().connected);
+        let sent = RelayHandler::send_frame(
+            &outbound,
+            Frame::Data(DataFrame {
+                payload: vec![6],
+                seq: None,
+            }),
+        );
+        assert_eq!(sent.unwrap(), SendStatus::Sent);
+        assert_eq!(read_data(&mut conn).await, vec![6]);
+
+        // down once the server hangs up, frames queue for the next session
+        conn.close().await;
+        drop(conn);
+        wait_connected(&outbound, false).await;
+        assert!(!handler.get_status().connected);
+        let queued = RelayHandler::send_frame(
+            &outbound,
+            Frame::Data(DataFrame {
+                payload: vec![7],
+                seq: None,
+            }),
+        );
+        assert_eq!(queued.unwrap(), SendStatus::Queued);
+
+        let mut conn = accept_handshake(&listener).await;
+        reply_handshake(&mut conn).await;
+        assert_eq!(read_data(&mut conn).await, vec![7]);
+        wait_connected(&outbound, true).await;
+    }
+
+    #[tokio::test]
+    async fn test_inbound_burst_does_not_stall_keepalives() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = RelayClientConfig {
+            keepalive_interval: Duration::from_millis(20),
+            inbound_buffer_size: 100,
+            keep_alive_thresh: 100,
+            ..test_config(&listener)
+        };
+        let mut handler = RelayHandler::new(Arc::new(Box::new(PlainBlock::new())));
+        let (ready_tx, mut ready_rx) = mpsc::channel(CONFIG_CHANNEL_SIZE);
+        handler.run_client(cfg, ready_tx);
+
+        let mut conn = accept_handshake(&listener).await;
+        reply_handshake(&mut conn).await;
+        ready_rx.recv().await.unwrap();
+
+        // nobody reads the inbound queue during the burst
+        let burst: Vec<_> = (0..95)
+            .map(|n| {
+                Frame::Data(DataFrame {
+                    payload: vec![n],
+                    seq: None,
+                })
+            })
+            .collect();
+        conn.write_frames(burst).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            let mut keepalives = 0;
+            while keepalives < 3 {
+                if let Frame::KeepAlive(_) = conn.read_frame().await.unwrap() {
+                    keepalives += 1;
+                }
+            }
+        })
+        .await
+        .expect("keepalives should keep flowing during the burst");
+
+        for n in 0..95 {
+            match handler.recv_frame().await.unwrap() {
+                Frame::Data(data) => assert_eq!(data.payload, vec![n]),
+                frame => panic!("unexpected frame {frame}"),
+            }
+        }
+        // the last 5 frames found at most 10 free slots
+        assert_eq!(handler.get_status().rx_near_full, 5);
+    }
+
+    /// Fails the first discovery, then reports 203.0.113.5:40000
+    struct RecoveringStun {
+        attempts: AtomicU64,
+    }
+
+    #[async_trait::async_trait]
+    impl StunProvider for RecoveringStun {
+        async fn discover(&self, socket: &StunSocket) -> anyhow::Result<StunDiscoveryResult> {
+            if self.attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                anyhow::bail!("All STUN servers failed");
+            }
+            Ok(StunDiscoveryResult {
+                public_ip: "203.0.113.5".parse().unwrap(),
+                public_port: 40000,
+                nat_type: NatType::PortRestricted,
+                local_addr: socket.local_addr()?,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_advertises_recovered_stun() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let provider = Arc::new(RecoveringStun {
+            attempts: AtomicU64::new(0),
+        });
+        let cfg = RelayClientConfig {
+            keepalive_interval: Duration::from_millis(20),
+            keep_alive_thresh: 100,
+            port: 0,
+            // initial discovery failed
+            stun_refresh: Some(
+                StunRefresh::new(
+                    provider.clone(),
+                    StunSocket::bind(0).unwrap(),
+                    Duration::from_secs(60),
+                )
+                .with_min_backoff(Duration::from_millis(10)),
+            ),
+            ..test_config(&listener)
+        };
+        let mut handler = RelayHandler::new(Arc::new(Box::new(PlainBlock::new())));
+        let (ready_tx, mut ready_rx) = mpsc::channel(CONFIG_CHANNEL_SIZE);
+        handler.run_client(cfg, ready_tx);
+
+        let mut conn = accept_handshake(&listener).await;
+        reply_handshake(&mut conn).await;
+        ready_rx.recv().await.unwrap();
+
+        let keepalive = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                if let Frame::KeepAlive(keepalive) = conn.read_frame().await.unwrap()
+                    && !keepalive.stun_ip.is_empty()
+                {
+                    return keepalive;
+                }
+            }
+        })
+        .await
+        .expect("recovered STUN address should be advertised");
+        assert_eq!(keepalive.stun_ip, "203.0.113.5");
+        assert_eq!(keepalive.stun_port, 40000);
+        assert!(provider.attempts.load(Ordering::SeqCst) >= 2);
+
+        let info = handler.get_self_info().await.unwrap();
+        assert_eq!(
+            (info.stun_ip.as_str(), info.stun_port),
+            ("203.0.113.5", 40000)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hang_up_during_handshake_is_closed() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = RelayClientConfig {
+            token: Some("wrong".to_string()),
+            ..test_config(&listener)
+        };
+        let (_outbound_tx, outbound_rx) = mpsc::channel(1);
+        let (inbound_tx, _inbound_rx) = mpsc::channel(1);
+        let client = RelayClient::new(
+            cfg,
+            outbound_rx,
+            inbound_tx,
+            Arc::new(Box::new(PlainBlock::new())),
+        );
+
+        // the server reads the handshake and hangs up without a reply
+        tokio::spawn(async move {
+            let mut conn = accept_handshake(&listener).await;
+            conn.close().await;
+        });
+        let mut conn = client.connect().await.unwrap();
+        match client.handshake(&mut conn).await {
+            Err(RustunError::Closed) => {}
+            result => panic!("unexpected result {result:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_gives_up_after_max_handshake_failures() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = RelayClientConfig {
+            token: Some("wrong".to_string()),
+            max_handshake_failures: Some(3),
+            ..test_config(&listener)
+        };
+
+        // the server rejects every handshake
+        let attempts = Arc::new(AtomicU64::new(0));
+        let server_attempts = attempts.clone();
+        tokio::spawn(async move {
+            loop {
+                let mut conn = accept_handshake(&listener).await;
+                server_attempts.fetch_add(1, Ordering::Relaxed);
+                conn.close().await;
+            }
+        });
+
+        let mut handler = RelayHandler::new(Arc::new(Box::new(PlainBlock::new())));
+        let (on_ready, mut ready) = mpsc::channel(1);
+        handler.run_client(cfg, on_ready);
+
+        // the client stops without ever getting ready
+        let ready = tokio::time::timeout(Duration::from_secs(5), ready.recv()).await;
+        assert!(ready.unwrap().is_none());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+        assert!(handler.fatal().unwrap().starts_with("3 handshakes failed"));
+        let e = handler.recv_frame().await.unwrap_err();
+        assert!(e.to_string().ends_with("last: EOF"));
+    }
+
+    #[tokio::test]
+    async fn test_client_gives_up_when_unauthorized() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = RelayClientConfig {
+            token: Some("wrong".to_string()),
+            ..test_config(&listener)
+        };
+        assert_eq!(cfg.max_handshake_failures, None);
+
+        let attempts = Arc::new(AtomicU64::new(0));
+        let server_attempts = attempts.clone();
+        tokio::spawn(async move {
+            loop {
+                let mut conn = accept_handshake(&listener).await;
+                server_attempts.fetch_add(1, Ordering::Relaxed);
+                conn.write_frame(Frame::HandshakeReject(HandshakeRejectFrame {
+                    reason: REJECT_UNAUTHORIZED.to_string(),
+                }))
+                .await
+                .unwrap();
+            }
+        });
+
+        let mut handler = RelayHandler::new(Arc::new(Box::new(PlainBlock::new())));
+        let (on_ready, mut ready) = mpsc::channel(1);
+        handler.run_client(cfg, on_ready);
+
+        let ready = tokio::time::timeout(Duration::from_secs(5), ready.recv()).await;
+        assert!(ready.unwrap().is_none());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+        assert!(handler.fatal().unwrap().ends_with("unauthorized"));
+    }
+
+    /// Read one raw frame off `socket`, header included
+    async fn read_raw_frame(socket: &mut tokio::net::TcpStream) -> (Frame, Vec<u8>) {
+        use tokio::io::AsyncReadExt;
+        let mut buf = Vec::new();
+        loop {
+            if let Ok((frame, len)) =
+                crate::codec::parser::Parser::unmarshal(&buf, &PlainBlock::new())
+            {
+                buf.truncate(len);
+                return (frame, buf);
+            }
+            let mut chunk = [0u8; 1024];
+            let n = socket.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "connection closed");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Handshake against a server speaking up to `server_max`, returning the
+    /// version byte of the first frame the client sends afterwards
+    async fn negotiated_version(server_max: u8) -> u8 {
+        use tokio::io::AsyncWriteExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = test_config(&listener);
+        let (_outbound_tx, outbound_rx) = mpsc::channel(1);
+        let (inbound_tx, _inbound_rx) = mpsc::channel(1);
+        let client = RelayClient::new(
+            cfg,
+            outbound_rx,
+            inbound_tx,
+            Arc::new(Box::new(PlainBlock::new())),
+        );
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let max_version = match read_raw_frame(&mut socket).await.0 {
+                Frame::Handshake(hs) => hs.max_version,
+                frame => panic!("unexpected frame {frame}"),
+            };
+            assert_eq!(max_version, MAX_VERSION);
+            let reply = HandshakeReplyFrame {
+                name: "a".to_string(),
+                private_ip: "10.0.0.1".to_string(),
+                mask: "255.255.255.0".to_string(),
+                gateway: "10.0.0.254".to_string(),
+                ciders: vec![],
+                cider_mapping: Default::default(),
+                peers_version: 0,
+                resume_token: None,
+                resumed: false,
+                peer_details: vec![],
+                version: negotiate_version(server_max, max_version),
+                mode: Default::default(),
+                pad: None,
+            };
+            let buf = crate::codec::parser::Parser::marshal(
+                Frame::HandshakeReply(reply),
+                &PlainBlock::new(),
+            )
+            .unwrap();
+            socket.write_all(&buf).await.unwrap();
+            let (frame, raw) = read_raw_frame(&mut socket).await;
+            assert!(matches!(frame, Frame::Data(_)));
+            raw[4]
+        });
+
+        let mut conn = client.connect().await.unwrap();
+        client.handshake(&mut conn).await.unwrap();
+        conn.write_frame(data_frame()).await.unwrap();
+        server.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_handshake_negotiates_version() {
+        use crate::codec::parser::MIN_VERSION;
+        // a v1-only server keeps the client on v1
+        assert_eq!(negotiated_version(MIN_VERSION).await, MIN_VERSION);
+        // both ends on the latest version use it
+        assert_eq!(negotiated_version(MAX_VERSION).await, MAX_VERSION);
+    }
+}
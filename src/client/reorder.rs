@@ -0,0 +1,266 @@
+//! Per-flow sequence numbers and the reorder buffer in front of the device
+//!
+//! A flow switching between P2P and the relay mid-stream has packets in
+//! flight on both paths, and the faster path overtakes the slower one. The
+//! sender numbers the data frames of each flow (source and destination IP)
+//! with a `FlowSequencer`, the receiver's `ReorderBuffer` holds frames that
+//! arrive ahead of a missing one for a short window and hands them on in
+//! order. A gap that is still open when the window expires is skipped, late
+//! frames are delivered as they come.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+/// Flows tracked before the table starts over
+const MAX_FLOWS: usize = 4096;
+/// Frames held per flow, the gap is skipped when more arrive
+const MAX_HELD: usize = 64;
+/// Frames this far behind the expected one mean the sender started over
+const RESTART_GAP: u64 = 1024;
+/// Flows without traffic for this long are forgotten
+const FLOW_IDLE: Duration = Duration::from_secs(60);
+
+/// Source and destination address of an IP packet
+type Flow = (IpAddr, IpAddr);
+
+fn flow_of(packet: &[u8]) -> Option<Flow> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let src: [u8; 4] = packet[12..16].try_into().ok()?;
+            let dst: [u8; 4] = packet[16..20].try_into().ok()?;
+            Some((Ipv4Addr::from(src).into(), Ipv4Addr::from(dst).into()))
+        }
+        6 if packet.len() >= 40 => {
+            let src: [u8; 16] = packet[8..24].try_into().ok()?;
+            let dst: [u8; 16] = packet[24..40].try_into().ok()?;
+            Some((Ipv6Addr::from(src).into(), Ipv6Addr::from(dst).into()))
+        }
+        _ => None,
+    }
+}
+
+/// Numbers the packets sent on each flow
+#[derive(Debug, Default)]
+pub struct FlowSequencer {
+    next: HashMap<Flow, u64>,
+}
+
+impl FlowSequencer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sequence number of `packet` in its flow, `None` if not an IP packet
+    pub fn next(&mut self, packet: &[u8]) -> Option<u64> {
+        let flow = flow_of(packet)?;
+        if self.next.len() >= MAX_FLOWS && !self.next.contains_key(&flow) {
+            // the receivers take the restarted counters for new senders
+            self.next.clear();
+        }
+        let next = self.next.entry(flow).or_default();
+        let seq = *next;
+        *next += 1;
+        Some(seq)
+    }
+}
+
+/// Receive state of one flow
+#[derive(Debug)]
+struct FlowState {
+    /// Sequence number expected next
+    next: u64,
+    /// Frames that arrived ahead of `next`
+    held: BTreeMap<u64, Vec<u8>>,
+    /// When the gap before the held frames is skipped
+    deadline: Option<Instant>,
+    last_seen: Instant,
+}
+
+impl FlowState {
+    /// Hand on the held frames following `next` without a gap
+    fn release_ready(&mut self, out: &mut Vec<Vec<u8>>) {
+        while let Some(packet) = self.held.remove(&self.next) {
+            out.push(packet);
+            self.next += 1;
+        }
+    }
+
+    /// Give up on the missing frames before the first held one
+    fn skip_gap(&mut self, out: &mut Vec<Vec<u8>>) {
+        if let Some((&first, _)) = self.held.first_key_value() {
+            self.next = first;
+            self.release_ready(out);
+        }
+    }
+
+    /// Start the window for the frames still held
+    fn rearm(&mut self, now: Instant, window: Duration) {
+        self.deadline = match self.held.is_empty() {
+            true => None,
+            false => Some(self.deadline.unwrap_or(now + window)),
+        };
+    }
+}
+
+/// Puts sequenced data frames back in order, per flow
+#[derive(Debug)]
+pub struct ReorderBuffer {
+    /// Longest a frame waits for the ones before it
+    window: Duration,
+    flows: HashMap<Flow, FlowState>,
+}
+
+impl ReorderBuffer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            flows: HashMap::new(),
+        }
+    }
+
+    /// Take in a received packet, returns the packets to deliver now
+    ///
+    /// Packets without a sequence number pass straight through.
+    pub fn push(&mut self, packet: Vec<u8>, seq: Option<u64>, now: Instant) -> Vec<Vec<u8>> {
+        let Some((seq, flow)) = seq.zip(flow_of(&packet)) else {
+            return vec![packet];
+        };
+        if self.flows.len() >= MAX_FLOWS && !self.flows.contains_key(&flow) {
+            self.forget_idle(now);
+        }
+        let Some(state) = self.flows.get_mut(&flow) else {
+            self.flows.insert(
+                flow,
+                FlowState {
+                    next: seq + 1,
+                    held: BTreeMap::new(),
+                    deadline: None,
+                    last_seen: now,
+                },
+            );
+            return vec![packet];
+        };
+        state.last_seen = now;
+
+        let mut out = Vec::new();
+        if seq == state.next {
+            out.push(packet);
+            state.next += 1;
+            state.release_ready(&mut out);
+            // the frames after the next gap wait their own window
+            state.deadline = None;
+        } else if seq > state.next {
+            state.held.insert(seq, packet);
+            if state.held.len() > MAX_HELD {
+                state.skip_gap(&mut out);
+                state.deadline = None;
+            }
+        } else if state.next - seq > RESTART_GAP {
+            tracing::debug!("flow {flow:?} started over at {seq}");
+            out.extend(std::mem::take(&mut state.held).into_values());
+            out.push(packet);
+            state.next = seq + 1;
+            state.deadline = None;
+        } else {
+            // late, its gap was skipped already
+            out.push(packet);
+        }
+        state.rearm(now, self.window);
+        out
+    }
+
+    /// Packets whose wait ran out by `now`, in flow order
+    pub fn expire(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        for state in self.flows.values_mut() {
+            if state.deadline.is_some_and(|deadline| deadline <= now) {
+                state.skip_gap(&mut out);
+                state.deadline = None;
+                state.rearm(now, self.window);
+            }
+        }
+        self.forget_idle(now);
+        out
+    }
+
+    /// When `expire` has packets to hand on next
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.flows.values().filter_map(|state| state.deadline).min()
+    }
+
+    fn forget_idle(&mut self, now: Instant) {
+        self.flows.retain(|_, state| {
+            !state.held.is_empty() || now.duration_since(state.last_seen) < FLOW_IDLE
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::frame::ipv4_packet;
+
+    const WINDOW: Duration = Duration::from_millis(20);
+
+    /// IPv4 packet from 10.0.0.1 to `dst` tagged with `n`
+    fn packet(dst: u8, n: u8) -> Vec<u8> {
+        ipv4_packet([10, 0, 0, 1], [10, 0, 0, dst], 17, &[n])
+    }
+
+    fn tags(packets: Vec<Vec<u8>>) -> Vec<u8> {
+        packets.iter().map(|packet| packet[20]).collect()
+    }
+
+    #[test]
+    fn test_sequencer_counts_per_flow() {
+        let mut sequencer = FlowSequencer::new();
+        assert_eq!(sequencer.next(&packet(2, 0)), Some(0));
+        assert_eq!(sequencer.next(&packet(2, 0)), Some(1));
+        assert_eq!(sequencer.next(&packet(3, 0)), Some(0));
+        assert_eq!(sequencer.next(&[0x45, 0]), None);
+    }
+
+    #[test]
+    fn test_out_of_order_frames_released_in_order() {
+        let mut buffer = ReorderBuffer::new(WINDOW);
+        let now = Instant::now();
+
+        assert_eq!(tags(buffer.push(packet(2, 0), Some(0), now)), [0]);
+        // 3 and 2 overtake 1, 1 frees them all
+        assert!(buffer.push(packet(2, 3), Some(3), now).is_empty());
+        assert!(buffer.push(packet(2, 2), Some(2), now).is_empty());
+        assert_eq!(buffer.next_deadline(), Some(now + WINDOW));
+        // other flows and unsequenced frames do not wait
+        assert_eq!(tags(buffer.push(packet(3, 9), Some(7), now)), [9]);
+        assert_eq!(tags(buffer.push(packet(2, 8), None, now)), [8]);
+        assert_eq!(tags(buffer.push(packet(2, 1), Some(1), now)), [1, 2, 3]);
+        assert_eq!(buffer.next_deadline(), None);
+        assert_eq!(tags(buffer.push(packet(2, 4), Some(4), now)), [4]);
+    }
+
+    #[test]
+    fn test_gap_skipped_after_window() {
+        let mut buffer = ReorderBuffer::new(WINDOW);
+        let now = Instant::now();
+        buffer.push(packet(2, 0), Some(0), now);
+        assert!(buffer.push(packet(2, 2), Some(2), now).is_empty());
+        assert!(buffer.expire(now + WINDOW / 2).is_empty());
+
+        assert_eq!(tags(buffer.expire(now + WINDOW)), [2]);
+        assert_eq!(buffer.next_deadline(), None);
+        // the lost frame turning up late still gets through
+        assert_eq!(tags(buffer.push(packet(2, 1), Some(1), now + WINDOW)), [1]);
+        assert_eq!(tags(buffer.push(packet(2, 3), Some(3), now + WINDOW)), [3]);
+    }
+
+    #[test]
+    fn test_restarted_sender_resyncs() {
+        let mut buffer = ReorderBuffer::new(WINDOW);
+        let now = Instant::now();
+        buffer.push(packet(2, 0), Some(5000), now);
+        assert_eq!(tags(buffer.push(packet(2, 1), Some(0), now)), [1]);
+        assert!(buffer.push(packet(2, 3), Some(2), now).is_empty());
+        assert_eq!(tags(buffer.push(packet(2, 2), Some(1), now)), [2, 3]);
+    }
+}
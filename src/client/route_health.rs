@@ -0,0 +1,365 @@
+//! Reachability probing for peer CIDRs
+//!
+//! A peer may advertise a CIDR whose hosts are down, in which case tunneled
+//! traffic is silently blackholed. `RouteHealth` periodically sends an ICMP
+//! echo through the tunnel to the first host of each advertised IPv4 CIDR,
+//! withdraws the system route after several unanswered probes and reinstates
+//! it once replies come back.
+
+use crate::client::http::RouteHealthInfo;
+use crate::codec::frame::{PeerDetail, ipv4_packet};
+use crate::utils::sys_route::RouteTable;
+use ipnet::Ipv4Net;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+
+/// Default number of unanswered probes before a route is withdrawn
+pub const DEFAULT_PROBE_THRESHOLD: u32 = 3;
+
+const IPV4_HDR_LEN: usize = 20;
+const ICMP_HDR_LEN: usize = 8;
+const ICMP_PROTO: u8 = 1;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const PROBE_PAYLOAD: &[u8] = b"rustun-probe";
+
+/// Probe state of one advertised CIDR
+#[derive(Debug)]
+struct ProbedRoute {
+    /// Address probed on behalf of the CIDR
+    target: Ipv4Addr,
+    /// Whether the system route is currently installed
+    reachable: bool,
+    /// Consecutive probes without reply
+    missed: u32,
+    /// Whether the last probe is still unanswered
+    awaiting: bool,
+}
+
+/// Tracks reachability of peer CIDRs and installs/withdraws their routes
+pub struct RouteHealth {
+    /// VPN address probes are sent from
+    src: Ipv4Addr,
+    /// ICMP identifier marking our probes
+    ident: u16,
+    /// Sequence number of the current probe round, the only one replies
+    /// are matched against
+    seq: u16,
+    /// Unanswered probes before a route is withdrawn
+    threshold: u32,
+    /// Gateway used for the system routes
+    gateway: String,
+    /// TUN interface index (Windows only)
+    tun_index: Option<i32>,
+    /// System routing table
+    table: Box<dyn RouteTable>,
+    /// Probed CIDRs keyed by CIDR string
+    routes: BTreeMap<String, ProbedRoute>,
+}
+
+impl RouteHealth {
+    /// Create a route health tracker
+    ///
+    /// # Arguments
+    /// - `private_ip` - Local VPN address, used as probe source and route gateway
+    /// - `tun_index` - TUN interface index (Windows only)
+    /// - `threshold` - Unanswered probes before a route is withdrawn
+    /// - `table` - System routing table
+    pub fn new(
+        private_ip: &str,
+        tun_index: Option<i32>,
+        threshold: u32,
+        table: Box<dyn RouteTable>,
+    ) -> anyhow::Result<Self> {
+        let src = private_ip
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid IP address: {private_ip}"))?;
+        Ok(Self {
+            src,
+            ident: std::process::id() as u16,
+            seq: 0,
+            threshold: threshold.max(1),
+            gateway: private_ip.to_string(),
+            tun_index,
+            table,
+            routes: BTreeMap::new(),
+        })
+    }
+
+    /// Sync the probed CIDRs with the routes advertised by peers
+    ///
+    /// New CIDRs start out reachable, as the device handler has just
+    /// installed their routes. IPv6 CIDRs and default routes are not probed.
+    pub fn set_routes(&mut self, peers: &[PeerDetail]) {
+        let mut routes = BTreeMap::new();
+        for cidr in peers.iter().flat_map(|peer| &peer.ciders) {
+            if let Some(route) = self.routes.remove(cidr) {
+                routes.insert(cidr.clone(), route);
+                continue;
+            }
+            let Ok(net) = cidr.parse::<Ipv4Net>() else {
+                continue;
+            };
+            // default routes are split on install and have no representative host
+            if net.prefix_len() == 0 {
+                continue;
+            }
+            let target = net.hosts().next().unwrap_or(net.addr());
+            routes.insert(
+                cidr.clone(),
+                ProbedRoute {
+                    target,
+                    reachable: true,
+                    missed: 0,
+                    awaiting: false,
+                },
+            );
+        }
+        self.routes = routes;
+    }
+
+    /// Start a probe round
+    ///
+    /// Counts the previous round's unanswered probes, withdrawing routes
+    /// that reached the threshold, and returns the echo requests to send
+    /// through the tunnel.
+    pub fn probe(&mut self) -> Vec<Vec<u8>> {
+        self.seq = self.seq.wrapping_add(1);
+        let mut packets = Vec::with_capacity(self.routes.len());
+        for (cidr, route) in self.routes.iter_mut() {
+            if route.awaiting {
+                route.missed += 1;
+                if route.reachable && route.missed >= self.threshold {
+                    tracing::warn!(
+                        "Route {cidr} unreachable ({} via {} missed {} probes), withdrawing",
+                        route.target,
+                        self.gateway,
+                        route.missed
+                    );
+                    match self
+                        .table
+                        .del(vec![cidr.clone()], self.gateway.clone(), self.tun_index)
+                    {
+                        Ok(_) => route.reachable = false,
+                        Err(e) => tracing::error!("Failed to delete route {cidr}: {e}"),
+                    }
+                }
+            }
+            route.awaiting = true;
+            packets.push(echo_request(self.src, route.target, self.ident, self.seq));
+        }
+        packets
+    }
+
+    /// Handle a packet received from the tunnel
+    ///
+    /// Only a reply to the current round's probe of a target still
+    /// awaiting it is ours; a ping of our own process or a late reply
+    /// goes to the device.
+    ///
+    /// # Returns
+    /// - `true` - The packet was a reply to our probe and must not reach the device
+    /// - `false` - Regular traffic
+    pub fn handle_reply(&mut self, packet: &[u8]) -> bool {
+        let Some((from, seq)) = echo_reply(packet, self.ident) else {
+            return false;
+        };
+        if seq != self.seq {
+            return false;
+        }
+        let mut matched = false;
+        for (cidr, route) in self.routes.iter_mut() {
+            if route.target != from || !route.awaiting {
+                continue;
+            }
+            matched = true;
+            route.awaiting = false;
+            route.missed = 0;
+            if !route.reachable {
+                tracing::info!("Route {cidr} reachable again, reinstating");
+                match self
+                    .table
+                    .add(vec![cidr.clone()], self.gateway.clone(), self.tun_index)
+                {
+                    Ok(_) => route.reachable = true,
+                    Err(e) => tracing::error!("Failed to add route {cidr}: {e}"),
+                }
+            }
+        }
+        matched
+    }
+
+    /// Current health of every probed CIDR
+    pub fn snapshot(&self) -> Vec<RouteHealthInfo> {
+        self.routes
+            .iter()
+            .map(|(cidr, route)| RouteHealthInfo {
+                cidr: cidr.clone(),
+                target: route.target.to_string(),
+                reachable: route.reachable,
+                missed_probes: route.missed,
+            })
+            .collect()
+    }
+}
+
+/// Internet checksum (RFC 1071)
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Build an IPv4 ICMP echo request
+fn echo_request(src: Ipv4Addr, dst: Ipv4Addr, ident: u16, seq: u16) -> Vec<u8> {
+    let mut icmp = vec![0u8; ICMP_HDR_LEN + PROBE_PAYLOAD.len()];
+    icmp[0] = ICMP_ECHO_REQUEST;
+    icmp[4..6].copy_from_slice(&ident.to_be_bytes());
+    icmp[6..8].copy_from_slice(&seq.to_be_bytes());
+    icmp[ICMP_HDR_LEN..].copy_from_slice(PROBE_PAYLOAD);
+    let sum = checksum(&icmp);
+    icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+
+    ipv4_packet(src, dst, ICMP_PROTO, &icmp)
+}
+
+/// Source address and sequence number of an ICMP echo reply to one of our
+/// probes carrying `ident`, if `packet` is one
+fn echo_reply(packet: &[u8], ident: u16) -> Option<(Ipv4Addr, u16)> {
+    if packet.len() < IPV4_HDR_LEN || packet[0] >> 4 != 4 || packet[9] != ICMP_PROTO {
+        return None;
+    }
+    let ihl = ((packet[0] & 0x0f) as usize) * 4;
+    let icmp = packet.get(ihl..)?;
+    if icmp.len() < ICMP_HDR_LEN
+        || icmp[0] != ICMP_ECHO_REPLY
+        || u16::from_be_bytes([icmp[4], icmp[5]]) != ident
+        || &icmp[ICMP_HDR_LEN..] != PROBE_PAYLOAD
+    {
+        return None;
+    }
+    let from = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    Some((from, u16::from_be_bytes([icmp[6], icmp[7]])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Routing table recording calls instead of running commands
+    #[derive(Clone, Default)]
+    struct RecordingTable(Arc<Mutex<Vec<String>>>);
+
+    impl RouteTable for RecordingTable {
+        fn add(&self, dsts: Vec<String>, gateway: String, _: Option<i32>) -> anyhow::Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("add {} via {gateway}", dsts.join(",")));
+            Ok(())
+        }
+
+        fn del(&self, dsts: Vec<String>, gateway: String, _: Option<i32>) -> anyhow::Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("del {} via {gateway}", dsts.join(",")));
+            Ok(())
+        }
+    }
+
+    fn peer(ciders: &[&str]) -> PeerDetail {
+        PeerDetail {
+            name: "peer".to_string(),
+            identity: "peer".to_string(),
+            private_ip: "10.0.0.2".to_string(),
+            ciders: ciders.iter().map(|c| c.to_string()).collect(),
+            ipv6: vec![],
+            port: 0,
+            stun_ip: String::new(),
+            stun_port: 0,
+            nat_type: 0,
+            last_active: 0,
+        }
+    }
+
+    /// Turn an echo request into the reply the target host would send
+    fn reply_to(request: &[u8]) -> Vec<u8> {
+        let mut reply = request.to_vec();
+        reply[12..16].copy_from_slice(&request[16..20]);
+        reply[16..20].copy_from_slice(&request[12..16]);
+        reply[IPV4_HDR_LEN] = ICMP_ECHO_REPLY;
+        reply
+    }
+
+    #[test]
+    fn test_echo_request_is_well_formed() {
+        let packet = echo_request(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(192, 168, 1, 1),
+            7,
+            1,
+        );
+        assert_eq!(checksum(&packet[..IPV4_HDR_LEN]), 0);
+        assert_eq!(checksum(&packet[IPV4_HDR_LEN..]), 0);
+        assert_eq!(echo_reply(&packet, 7), None);
+        assert_eq!(
+            echo_reply(&reply_to(&packet), 7),
+            Some((Ipv4Addr::new(192, 168, 1, 1), 1))
+        );
+        assert_eq!(echo_reply(&reply_to(&packet), 8), None);
+    }
+
+    #[test]
+    fn test_route_withdrawn_when_unreachable_and_reinstated() {
+        let table = RecordingTable::default();
+        let mut health = RouteHealth::new("10.0.0.1", None, 2, Box::new(table.clone())).unwrap();
+        health.set_routes(&[peer(&["192.168.1.0/24", "fd00::/64"])]);
+
+        // An answered probe keeps the route installed
+        let probes = health.probe();
+        assert_eq!(probes.len(), 1);
+        assert_eq!(&probes[0][16..20], &[192, 168, 1, 1]);
+        assert!(health.handle_reply(&reply_to(&probes[0])));
+
+        // Two unanswered probes withdraw it, once
+        let late = health.probe().remove(0);
+        health.probe();
+        assert!(table.0.lock().unwrap().is_empty());
+        health.probe();
+        let request = health.probe().remove(0);
+        assert_eq!(
+            *table.0.lock().unwrap(),
+            vec!["del 192.168.1.0/24 via 10.0.0.1"]
+        );
+        assert!(!health.snapshot()[0].reachable);
+
+        // A reply to an earlier round is not ours, one to the current is
+        // and reinstates it
+        assert!(!health.handle_reply(&reply_to(&late)));
+        assert!(!health.snapshot()[0].reachable);
+        assert!(health.handle_reply(&reply_to(&request)));
+        assert_eq!(
+            *table.0.lock().unwrap(),
+            vec![
+                "del 192.168.1.0/24 via 10.0.0.1",
+                "add 192.168.1.0/24 via 10.0.0.1"
+            ]
+        );
+        assert!(health.snapshot()[0].reachable);
+
+        // Regular traffic is left alone, as is a second reply to the probe
+        // or a ping of our own with the same identifier
+        assert!(!health.handle_reply(&request));
+        assert!(!health.handle_reply(&reply_to(&request)));
+        let mut ping = reply_to(&health.probe().remove(0));
+        ping[IPV4_HDR_LEN + ICMP_HDR_LEN] ^= 0xff;
+        assert!(!health.handle_reply(&ping));
+    }
+}
@@ -13,7 +13,7 @@ use std::fmt::Display;
 /// from raw byte streams, including incomplete data, invalid format, and
 /// cryptographic failures.
 #[derive(Debug)]
-pub(crate) enum FrameError {
+pub enum FrameError {
     /// Buffer is too short to contain a complete frame
     ///
     /// Occurs when:
@@ -35,6 +35,12 @@ pub(crate) enum FrameError {
     /// This indicates corrupted data or protocol mismatch.
     Invalid,
 
+    /// Frame declares a length larger than the receiver accepts
+    ///
+    /// Rejected as soon as the header is seen so that a peer cannot make
+    /// the receiver buffer an oversized payload.
+    TooLong,
+
     /// Payload decryption failed
     ///
     /// Wraps the underlying cryptographic error. This can occur when:
@@ -55,6 +61,7 @@ impl Display for FrameError {
         match self {
             FrameError::TooShort => "stream ended early".fmt(fmt),
             FrameError::Invalid => "invalid frame".fmt(fmt),
+            FrameError::TooLong => "frame too long".fmt(fmt),
             FrameError::DecryptionFailed(e) => write!(fmt, "decryption failed: {e}"),
         }
     }
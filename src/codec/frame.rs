@@ -19,6 +19,7 @@ use crate::codec::errors::FrameError;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::net::Ipv4Addr;
 
 /// Frame type identifiers
 ///
@@ -40,6 +41,62 @@ pub(crate) enum FrameType {
     ProbeIPv6 = 6,
     /// Probing hole punch
     ProbeHolePunch = 7,
+    /// Several tunneled data packets in one frame (Type 8)
+    DataBatch = 8,
+    /// Server push of a peer's changed address (Type 9)
+    PeerUpdate = 9,
+    /// Connectivity check request (Type 10)
+    Echo = 10,
+    /// Connectivity check response (Type 11)
+    EchoReply = 11,
+    /// P2P session key exchange request (Type 12)
+    P2PKeyInit = 12,
+    /// P2P session key exchange response (Type 13)
+    P2PKeyReply = 13,
+    /// Server push of a peer that connected (Type 14)
+    PeerJoin = 14,
+    /// Server push of a peer that disconnected (Type 15)
+    PeerLeave = 15,
+    /// Tunneled data packet with its flow sequence number (Type 16)
+    SeqData = 16,
+    /// Relay connection key rotation, request and response (Type 17)
+    Rekey = 17,
+    /// Server refusal of a client handshake (Type 18)
+    HandshakeReject = 18,
+    /// Client leaving on purpose, sent before it closes (Type 19)
+    Close = 19,
+    /// Tunneled data packet padded up to a bucket size (Type 20)
+    PaddedData = 20,
+    /// `SeqData` padded up to a bucket size (Type 21)
+    PaddedSeqData = 21,
+}
+
+impl FrameType {
+    /// Name of the type, as `Frame::type_name` has it
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            FrameType::Handshake => "handshake",
+            FrameType::HandshakeReply => "handshake_reply",
+            FrameType::KeepAlive => "keepalive",
+            FrameType::Data => "data",
+            FrameType::ProbeIPv6 => "probe_ipv6",
+            FrameType::ProbeHolePunch => "probe_hole_punch",
+            FrameType::DataBatch => "data_batch",
+            FrameType::PeerUpdate => "peer_update",
+            FrameType::Echo => "echo",
+            FrameType::EchoReply => "echo_reply",
+            FrameType::P2PKeyInit => "p2p_key_init",
+            FrameType::P2PKeyReply => "p2p_key_reply",
+            FrameType::PeerJoin => "peer_join",
+            FrameType::PeerLeave => "peer_leave",
+            FrameType::SeqData => "seq_data",
+            FrameType::Rekey => "rekey",
+            FrameType::HandshakeReject => "handshake_reject",
+            FrameType::Close => "close",
+            FrameType::PaddedData => "padded_data",
+            FrameType::PaddedSeqData => "padded_seq_data",
+        }
+    }
 }
 
 impl TryFrom<u8> for FrameType {
@@ -61,16 +118,35 @@ impl TryFrom<u8> for FrameType {
             0x04 => Ok(FrameType::HandshakeReply),
             0x06 => Ok(FrameType::ProbeIPv6),
             0x07 => Ok(FrameType::ProbeHolePunch),
+            0x08 => Ok(FrameType::DataBatch),
+            0x09 => Ok(FrameType::PeerUpdate),
+            0x0a => Ok(FrameType::Echo),
+            0x0b => Ok(FrameType::EchoReply),
+            0x0c => Ok(FrameType::P2PKeyInit),
+            0x0d => Ok(FrameType::P2PKeyReply),
+            0x0e => Ok(FrameType::PeerJoin),
+            0x0f => Ok(FrameType::PeerLeave),
+            0x10 => Ok(FrameType::SeqData),
+            0x11 => Ok(FrameType::Rekey),
+            0x12 => Ok(FrameType::HandshakeReject),
+            0x13 => Ok(FrameType::Close),
+            0x14 => Ok(FrameType::PaddedData),
+            0x15 => Ok(FrameType::PaddedSeqData),
             _ => Err(FrameError::Invalid),
         }
     }
 }
 
-/// Frame header length in bytes
+/// Frame header length in bytes, the shortest header of all versions
 ///
 /// Header format: Magic(4) + Version(1) + Type(1) + PayloadLen(2) = 8 bytes
 pub(crate) const HDR_LEN: usize = 8;
 
+/// Header length of frames from `WIDE_LEN_VERSION` on, with a wider payload length
+///
+/// Header format: Magic(4) + Version(1) + Type(1) + PayloadLen(4) = 10 bytes
+pub(crate) const HDR_LEN_V2: usize = 10;
+
 /// Protocol frame enum
 ///
 /// Represents all possible frame types in the VPN protocol. Each variant contains
@@ -88,6 +164,87 @@ pub enum Frame {
     Data(DataFrame),
     ProbeIPv6(ProbeIPv6Frame),
     ProbeHolePunch(ProbeHolePunchFrame),
+    /// Several tunneled IP packets coalesced into one frame
+    DataBatch(DataBatchFrame),
+    /// A peer's connection details changed
+    PeerUpdate(PeerUpdateFrame),
+    /// Connectivity check, answered with `EchoReply`
+    Echo(EchoFrame),
+    /// Answer to an `Echo`
+    EchoReply(EchoReplyFrame),
+    /// Starts a P2P session key exchange
+    P2PKeyInit(P2PKeyFrame),
+    /// Completes a P2P session key exchange
+    P2PKeyReply(P2PKeyFrame),
+    /// A peer of the cluster connected
+    PeerJoin(PeerJoinFrame),
+    /// A peer of the cluster disconnected
+    PeerLeave(PeerLeaveFrame),
+    /// Switches the relay connection to a fresh key
+    Rekey(RekeyFrame),
+    /// Server response to a handshake it turned down
+    HandshakeReject(HandshakeRejectFrame),
+    /// Ends the relay connection without waiting for it to time out
+    Close(CloseFrame),
+}
+
+impl Frame {
+    /// Short frame type name, used as the `frame_type` tracing field
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Frame::Handshake(_) => "handshake",
+            Frame::HandshakeReply(_) => "handshake_reply",
+            Frame::KeepAlive(_) => "keepalive",
+            Frame::Data(_) => "data",
+            Frame::ProbeIPv6(_) => "probe_ipv6",
+            Frame::ProbeHolePunch(_) => "probe_hole_punch",
+            Frame::DataBatch(_) => "data_batch",
+            Frame::PeerUpdate(_) => "peer_update",
+            Frame::Echo(_) => "echo",
+            Frame::EchoReply(_) => "echo_reply",
+            Frame::P2PKeyInit(_) => "p2p_key_init",
+            Frame::P2PKeyReply(_) => "p2p_key_reply",
+            Frame::PeerJoin(_) => "peer_join",
+            Frame::PeerLeave(_) => "peer_leave",
+            Frame::Rekey(_) => "rekey",
+            Frame::HandshakeReject(_) => "handshake_reject",
+            Frame::Close(_) => "close",
+        }
+    }
+
+    /// Whether the frame keeps the session or the peer list going, so it is
+    /// written ahead of queued data frames
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self,
+            Frame::KeepAlive(_)
+                | Frame::ProbeIPv6(_)
+                | Frame::ProbeHolePunch(_)
+                | Frame::PeerUpdate(_)
+                | Frame::PeerJoin(_)
+                | Frame::PeerLeave(_)
+                | Frame::Rekey(_)
+                | Frame::Close(_)
+        )
+    }
+
+    /// Drops all but the first IPv6 address of every address list, for
+    /// peers from before `IPV6_LIST_VERSION` that read a single one
+    pub(crate) fn keep_first_ipv6(&mut self) {
+        let peers = match self {
+            Frame::KeepAlive(frame) => {
+                frame.ipv6.truncate(1);
+                &mut frame.peer_details
+            }
+            Frame::HandshakeReply(frame) => &mut frame.peer_details,
+            Frame::PeerUpdate(frame) => std::slice::from_mut(&mut frame.peer),
+            Frame::PeerJoin(frame) => std::slice::from_mut(&mut frame.peer),
+            _ => return,
+        };
+        for peer in peers {
+            peer.ipv6.truncate(1);
+        }
+    }
 }
 
 impl Display for Frame {
@@ -107,12 +264,33 @@ impl Display for Frame {
             }
             Frame::KeepAlive(frame) => write!(
                 f,
-                "keepalive, ipv6 {}:{} stun: {}:{}",
+                "keepalive, ipv6 {:?}:{} stun: {}:{}",
                 frame.ipv6, frame.port, frame.stun_ip, frame.stun_port,
             ),
             Frame::Data(frame) => write!(f, "data with payload size {}", frame.payload.len()),
             Frame::ProbeIPv6(frame) => write!(f, "{} probe ipv6", frame.identity),
             Frame::ProbeHolePunch(frame) => write!(f, "{} probe hole punch", frame.identity),
+            Frame::DataBatch(frame) => {
+                write!(f, "data batch with {} packets", frame.packets.len())
+            }
+            Frame::PeerUpdate(frame) => write!(
+                f,
+                "peer update for {}, ipv6 {:?}:{} stun: {}:{}",
+                frame.peer.identity,
+                frame.peer.ipv6,
+                frame.peer.port,
+                frame.peer.stun_ip,
+                frame.peer.stun_port,
+            ),
+            Frame::Echo(frame) => write!(f, "echo {} to {:?}", frame.id, frame.dst),
+            Frame::EchoReply(frame) => write!(f, "echo reply {} to {:?}", frame.id, frame.dst),
+            Frame::P2PKeyInit(frame) => write!(f, "{} p2p key init", frame.identity),
+            Frame::P2PKeyReply(frame) => write!(f, "{} p2p key reply", frame.identity),
+            Frame::PeerJoin(frame) => write!(f, "peer {} joined", frame.peer.identity),
+            Frame::PeerLeave(frame) => write!(f, "peer {} left", frame.identity),
+            Frame::Rekey(_) => write!(f, "rekey"),
+            Frame::HandshakeReject(frame) => write!(f, "handshake rejected: {}", frame.reason),
+            Frame::Close(frame) => write!(f, "close: {}", frame.reason),
         }
     }
 }
@@ -137,8 +315,60 @@ pub struct HandshakeFrame {
     /// - Look up network configuration (private IP, CIDR ranges)
     /// - Determine cluster membership for multi-tenancy
     pub identity: String,
+
+    /// Credential checked by the server's authentication backend
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+
+    /// Newest protocol version the client speaks
+    #[serde(default = "min_version")]
+    pub max_version: u8,
+
+    /// Token of the previous session, to resume it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume_token: Option<String>,
+
+    /// What the client's data frames carry
+    #[serde(default)]
+    pub mode: TunnelMode,
+
+    /// Bucket size the client pads its data frames to, asking the server
+    /// to pad its own the same
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pad: Option<u16>,
+}
+
+/// What tunneled data frames carry, negotiated in the handshake
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelMode {
+    /// IP packets of a TUN device, routed by destination IP (L3)
+    #[default]
+    Tun,
+    /// Ethernet frames of a TAP device, switched by destination MAC (L2)
+    Tap,
+}
+
+/// Version of peers from before version negotiation
+fn min_version() -> u8 {
+    crate::codec::parser::MIN_VERSION
+}
+
+/// Handshake refusal sent by the server instead of a `HandshakeReply`
+///
+/// E.g. for a `private_ip` another online client of the cluster already
+/// uses, or `REJECT_UNAUTHORIZED` for an unknown identity or wrong token.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HandshakeRejectFrame {
+    pub reason: String,
 }
 
+/// `HandshakeRejectFrame::reason` for an identity or token the server does
+/// not accept, which retrying will not change
+pub const REJECT_UNAUTHORIZED: &str = "unauthorized";
+
 /// Handshake reply frame sent by server in response to client handshake
 ///
 /// Contains the network configuration for the client and information about
@@ -173,6 +403,33 @@ pub struct HandshakeReplyFrame {
     /// Each PeerDetail contains routing information for a peer node,
     /// allowing this client to establish routes to other VPN members
     pub peer_details: Vec<PeerDetail>,
+
+    /// Protocol version of the frames following the handshake
+    #[serde(default = "min_version")]
+    pub version: u8,
+
+    /// Version of `peer_details`, echoed back in the client's keepalives
+    #[serde(default)]
+    pub peers_version: u64,
+
+    /// Token to resume this session with after a reconnect (not set if the
+    /// server does not support resumption)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume_token: Option<String>,
+
+    /// Whether a previous session was resumed, `peer_details` then only
+    /// holds the peers that changed since
+    #[serde(default)]
+    pub resumed: bool,
+
+    /// Tunnel mode granted, servers before negotiation only run `Tun`
+    #[serde(default)]
+    pub mode: TunnelMode,
+
+    /// Bucket size data frames are padded to both ways, servers without
+    /// padding leave it unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pad: Option<u16>,
 }
 
 /// Routing information for a peer node
@@ -195,14 +452,53 @@ pub struct PeerDetail {
     /// Traffic destined for these ranges will be routed through this peer
     pub ciders: Vec<String>,
 
-    /// IPv6 is public ip address of their peer
-    pub ipv6: String,
+    /// Public IPv6 addresses of the peer, all reachable on `port`
+    #[serde(default, with = "ipv6_list")]
+    pub ipv6: Vec<String>,
 
     pub port: u16,
 
     pub stun_ip: String,
     pub stun_port: u16,
     pub last_active: u64,
+
+    /// NAT type code of the peer's STUN mapping (0 = unknown)
+    #[serde(default)]
+    pub nat_type: u8,
+}
+
+/// IPv6 address lists on the wire
+///
+/// Peers from before multiple addresses send and expect a single string,
+/// so a list of one address (or none) is still written as a string and
+/// a string is read as a list of one.
+mod ipv6_list {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    pub fn serialize<S: Serializer>(addrs: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+        match addrs {
+            [] => serializer.serialize_str(""),
+            [addr] => serializer.serialize_str(addr),
+            addrs => addrs.serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<String>, D::Error> {
+        Ok(match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(addr) if addr.is_empty() => vec![],
+            OneOrMany::One(addr) => vec![addr],
+            OneOrMany::Many(addrs) => addrs,
+        })
+    }
 }
 
 /// Keep-alive frame for connection health monitoring
@@ -225,8 +521,9 @@ pub struct KeepAliveFrame {
     /// Peer identity (unique identifier)
     pub identity: String,
 
-    /// Public IPv6 address
-    pub ipv6: String,
+    /// Public IPv6 addresses
+    #[serde(default, with = "ipv6_list")]
+    pub ipv6: Vec<String>,
 
     /// UDP port for P2P connections
     pub port: u16,
@@ -235,9 +532,125 @@ pub struct KeepAliveFrame {
 
     pub stun_port: u16,
 
+    /// NAT type code detected with the STUN mapping (0 = unknown)
+    #[serde(default)]
+    pub nat_type: u8,
+
+    /// Peer list the client applied, or the server's current one in replies
+    ///
+    /// The server leaves `peer_details` empty in replies when the client is
+    /// already at the current version. 0 is a peer that does not track
+    /// versions and always gets, or sends, the full list.
+    #[serde(default)]
+    pub peers_version: u64,
+
     pub peer_details: Vec<PeerDetail>,
 }
 
+/// Peer update pushed by the server
+///
+/// Sent to every other member of the cluster as soon as a peer's IPv6 or
+/// STUN address changes, so P2P paths do not wait for the next keepalive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerUpdateFrame {
+    pub peer: PeerDetail,
+}
+
+/// Peer join pushed by the server
+///
+/// Sent to every other member of the cluster when a client completes its
+/// handshake. Addresses are empty until the peer's first keepalive, which
+/// follows as a `PeerUpdate`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerJoinFrame {
+    pub peer: PeerDetail,
+}
+
+/// Peer leave pushed by the server
+///
+/// Sent to every other member of the cluster once the last connection of
+/// a client is gone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerLeaveFrame {
+    pub identity: String,
+}
+
+/// Key rotation of the relay connection
+///
+/// The client sends its ephemeral public key, the server answers with its
+/// own and both switch to the key derived from them. Sealed with the key
+/// being replaced, see `crypto::rekey`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RekeyFrame {
+    pub public_key: [u8; 32],
+}
+
+/// Goodbye of a client shutting down
+///
+/// The server deregisters the client as soon as it reads it, instead of
+/// routing to the connection until a read fails or times out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloseFrame {
+    pub reason: String,
+}
+
+/// Echo request for connectivity checks
+///
+/// The receiver answers with an `EchoReply` carrying the same `id` and
+/// `sent_at`, so the sender can match the reply and measure the round trip.
+///
+/// Direct P2P echoes leave `src` and `dst` empty. Through the relay, `dst` is
+/// the private IP of the peer that should answer and the server fills in
+/// `src` before forwarding; an echo without `dst` is answered by the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EchoFrame {
+    /// Sequence number chosen by the sender
+    pub id: u64,
+    /// Send time in microseconds since the UNIX epoch
+    pub sent_at: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub src: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub dst: String,
+}
+
+impl EchoFrame {
+    /// Builds the reply, addressed back to the sender through the relay
+    pub fn reply(&self) -> EchoReplyFrame {
+        EchoReplyFrame {
+            id: self.id,
+            sent_at: self.sent_at,
+            dst: self.src.clone(),
+        }
+    }
+}
+
+/// Echo response, relayed back to `dst` when it is set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EchoReplyFrame {
+    /// `id` of the answered echo
+    pub id: u64,
+    /// `sent_at` of the answered echo
+    pub sent_at: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub dst: String,
+}
+
+/// Ephemeral X25519 public key for a P2P session key exchange
+///
+/// Sent directly between peers and sealed with the relay key like every
+/// control frame; data frames switch to the derived key once both sides
+/// have it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct P2PKeyFrame {
+    pub identity: String,
+    pub public_key: [u8; 32],
+    /// Newest protocol version the sender speaks, data frames of the
+    /// session use the version both speak
+    #[serde(default = "min_version")]
+    pub max_version: u8,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProbeIPv6Frame {
     pub identity: String,
@@ -266,6 +679,12 @@ pub struct DataFrame {
     /// - Transport layer header (TCP/UDP/etc.)
     /// - Application data
     pub payload: Vec<u8>,
+
+    /// Position of the packet in its flow, for the receiver's reorder
+    /// buffer
+    ///
+    /// Only frames of version 3 and later carry it, older ones drop it.
+    pub seq: Option<u64>,
 }
 
 impl DataFrame {
@@ -280,6 +699,35 @@ impl DataFrame {
         self.payload.len() < 20
     }
 
+    /// Checks the IP packet at the given validation `level`
+    ///
+    /// # Returns
+    /// * `true` if the packet may be forwarded
+    /// * `false` if it is malformed and must be dropped
+    pub fn validate_ip_packet(&self, level: IpValidation) -> bool {
+        let packet = &self.payload;
+        match level {
+            IpValidation::None => true,
+            IpValidation::Basic => !self.invalid() && matches!(self.version(), 4 | 6),
+            IpValidation::Strict if self.invalid() => false,
+            IpValidation::Strict => match self.version() {
+                4 => {
+                    let ihl = (packet[0] & 0x0f) as usize * 4;
+                    let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+                    ihl >= 20
+                        && ihl <= packet.len()
+                        && total_len == packet.len()
+                        && ipv4_checksum(&packet[..ihl]) == 0
+                }
+                6 => {
+                    packet.len() >= 40
+                        && u16::from_be_bytes([packet[4], packet[5]]) as usize + 40 == packet.len()
+                }
+                _ => false,
+            },
+        }
+    }
+
     /// Extracts the IP version from the packet header
     ///
     /// Reads the first 4 bits of the IP header which indicate the version.
@@ -292,6 +740,35 @@ impl DataFrame {
         (self.payload[0] >> 4) as i32
     }
 
+    /// Extracts the DSCP marking from the packet header
+    ///
+    /// Upper six bits of the IPv4 ToS byte or of the IPv6 traffic class.
+    ///
+    /// # Returns
+    /// * `Some(dscp)` for IPv4 and IPv6 packets
+    /// * `None` if the payload is too short or not an IP packet
+    pub fn dscp(&self) -> Option<u8> {
+        if self.payload.len() < 2 {
+            return None;
+        }
+        match self.payload[0] >> 4 {
+            4 => Some(self.payload[1] >> 2),
+            6 => {
+                let traffic_class = (self.payload[0] << 4) | (self.payload[1] >> 4);
+                Some(traffic_class >> 2)
+            }
+            _ => None,
+        }
+    }
+
+    /// ToS / traffic class byte carrying the packet's DSCP on the outer
+    /// UDP packet
+    ///
+    /// ECN bits are left clear, they belong to the outer hop.
+    pub fn outer_tos(&self) -> u8 {
+        self.dscp().map_or(0, |dscp| dscp << 2)
+    }
+
     /// Extracts the destination IP address from the packet
     ///
     /// Reads bytes 16-19 of the IPv4 header (destination address field).
@@ -309,6 +786,33 @@ impl DataFrame {
         )
     }
 
+    /// Extracts the destination MAC address of an Ethernet frame
+    ///
+    /// Only meaningful in `TunnelMode::Tap`, where the payload is an
+    /// Ethernet frame instead of an IP packet.
+    ///
+    /// # Returns
+    /// * `Some(mac)` - Bytes 0-5 of the Ethernet header
+    /// * `None` - Payload is shorter than an Ethernet header
+    pub fn dst_mac(&self) -> Option<MacAddr> {
+        if self.payload.len() < ETHERNET_HEADER_LEN {
+            return None;
+        }
+        self.payload[0..6].try_into().ok()
+    }
+
+    /// Extracts the source MAC address of an Ethernet frame
+    ///
+    /// # Returns
+    /// * `Some(mac)` - Bytes 6-11 of the Ethernet header
+    /// * `None` - Payload is shorter than an Ethernet header
+    pub fn src_mac(&self) -> Option<MacAddr> {
+        if self.payload.len() < ETHERNET_HEADER_LEN {
+            return None;
+        }
+        self.payload[6..12].try_into().ok()
+    }
+
     /// Extracts the source IP address from the packet
     ///
     /// Reads bytes 12-15 of the IPv4 header (source address field).
@@ -326,3 +830,284 @@ impl DataFrame {
         )
     }
 }
+
+/// Ethernet MAC address
+pub type MacAddr = [u8; 6];
+
+/// Destination, source and EtherType of an Ethernet frame
+pub const ETHERNET_HEADER_LEN: usize = 14;
+
+/// Whether `mac` is a broadcast or multicast address, delivered to every
+/// port of a switch
+pub fn is_group_mac(mac: &MacAddr) -> bool {
+    mac[0] & 0x01 != 0
+}
+
+/// `mac` in the usual colon separated hex notation
+pub fn format_mac(mac: &MacAddr) -> String {
+    mac.iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// How thoroughly tunneled IP packets are checked before they are forwarded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IpValidation {
+    /// Forward anything
+    ///
+    /// The server still needs the 20 bytes holding the IPv4 destination to
+    /// route a packet; shorter ones have no route, they do not fail a check.
+    None,
+    /// An IPv4 or IPv6 packet of at least 20 bytes
+    #[default]
+    Basic,
+    /// Also a consistent header: IHL, total or payload length matching the
+    /// packet and a correct IPv4 header checksum
+    Strict,
+}
+
+/// Internet checksum of an IPv4 header
+///
+/// Zero for a header carrying a correct checksum.
+pub fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// IPv4 packet from `src` to `dst` carrying `data` as `protocol`
+///
+/// The header has no options, a TTL of 64 and a correct checksum. Panics
+/// if the packet would not fit the 16-bit total length.
+pub fn ipv4_packet(
+    src: impl Into<Ipv4Addr>,
+    dst: impl Into<Ipv4Addr>,
+    protocol: u8,
+    data: &[u8],
+) -> Vec<u8> {
+    let total_len = u16::try_from(20 + data.len()).expect("IPv4 packet too long");
+    let mut packet = vec![0u8; 20];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+    packet[8] = 64;
+    packet[9] = protocol;
+    packet[12..16].copy_from_slice(&src.into().octets());
+    packet[16..20].copy_from_slice(&dst.into().octets());
+    let checksum = ipv4_checksum(&packet);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// Largest packet a `DataBatchFrame` record holds, longer ones go alone
+pub const MAX_BATCH_RECORD_LEN: usize = u16::MAX as usize;
+
+/// Several tunneled IP packets sent as one frame
+///
+/// Cuts the per-frame overhead (marshal, encrypt, syscall) at high packet
+/// rates. Each packet is encoded as a 2-byte big-endian length followed by
+/// the packet bytes, and the whole payload is encrypted once.
+#[derive(Debug, Clone, Default)]
+pub struct DataBatchFrame {
+    pub packets: Vec<Vec<u8>>,
+}
+
+impl DataBatchFrame {
+    /// Size of the encoded payload before encryption
+    pub fn encoded_len(&self) -> usize {
+        self.packets.iter().map(|p| 2 + p.len()).sum()
+    }
+
+    /// Checks if `packet` can be added without the encoded batch exceeding
+    /// `max_len`, the largest packet a frame of the session carries
+    pub fn fits(&self, packet: &[u8], max_len: usize) -> bool {
+        packet.len() <= MAX_BATCH_RECORD_LEN && self.encoded_len() + 2 + packet.len() <= max_len
+    }
+
+    /// Encodes the packets as length-prefixed records
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        for packet in &self.packets {
+            buf.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+            buf.extend_from_slice(packet);
+        }
+        buf
+    }
+
+    /// Decodes length-prefixed records
+    ///
+    /// # Returns
+    /// * `Err(FrameError::Invalid)` if a record is truncated
+    pub(crate) fn decode(mut buf: &[u8]) -> Result<Self, FrameError> {
+        let mut packets = Vec::new();
+        while !buf.is_empty() {
+            if buf.len() < 2 {
+                return Err(FrameError::Invalid);
+            }
+            let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+            if buf.len() < 2 + len {
+                return Err(FrameError::Invalid);
+            }
+            packets.push(buf[2..2 + len].to_vec());
+            buf = &buf[2 + len..];
+        }
+        Ok(Self { packets })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Keepalive from `identity` with no addresses and no peer list
+    pub(crate) fn keepalive(identity: &str) -> KeepAliveFrame {
+        KeepAliveFrame {
+            name: identity.to_string(),
+            identity: identity.to_string(),
+            ipv6: vec![],
+            port: 0,
+            stun_ip: String::new(),
+            stun_port: 0,
+            nat_type: 0,
+            peers_version: 0,
+            peer_details: vec![],
+        }
+    }
+
+    #[test]
+    fn test_dscp_from_ipv4_and_ipv6_headers() {
+        // IPv4, ToS 0xb9: DSCP 46 (EF) with ECN bits set
+        let mut ipv4 = vec![0u8; 20];
+        ipv4[0] = 0x45;
+        ipv4[1] = 0xb9;
+        let frame = DataFrame {
+            payload: ipv4,
+            seq: None,
+        };
+        assert_eq!(frame.dscp(), Some(46));
+        assert_eq!(frame.outer_tos(), 0xb8);
+
+        // IPv6, traffic class 0x28: DSCP 10 (AF11) split across bytes 0 and 1
+        let mut ipv6 = vec![0u8; 40];
+        ipv6[0] = 0x62;
+        ipv6[1] = 0x80;
+        let frame = DataFrame {
+            payload: ipv6,
+            seq: None,
+        };
+        assert_eq!(frame.dscp(), Some(10));
+        assert_eq!(frame.outer_tos(), 0x28);
+
+        let frame = DataFrame {
+            payload: vec![0x00, 0xff],
+            seq: None,
+        };
+        assert_eq!(frame.dscp(), None);
+        assert_eq!(frame.outer_tos(), 0);
+    }
+
+    #[test]
+    fn test_ethernet_addresses() {
+        let mut payload = vec![0xff; 6];
+        payload.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+        payload.extend_from_slice(&[0x08, 0x06]);
+        let frame = DataFrame { payload, seq: None };
+        assert_eq!(frame.dst_mac(), Some([0xff; 6]));
+        assert_eq!(frame.src_mac(), Some([0x02, 0, 0, 0, 0, 0x01]));
+        assert!(is_group_mac(&frame.dst_mac().unwrap()));
+        assert!(!is_group_mac(&frame.src_mac().unwrap()));
+
+        let frame = DataFrame {
+            payload: vec![0; ETHERNET_HEADER_LEN - 1],
+            seq: None,
+        };
+        assert_eq!(frame.dst_mac(), None);
+        assert_eq!(frame.src_mac(), None);
+    }
+
+    #[test]
+    fn test_ipv6_list_compatible_with_single_address() {
+        let mut peer: PeerDetail = serde_json::from_value(serde_json::json!({
+            "name": "b",
+            "identity": "b",
+            "private_ip": "10.0.0.2",
+            "ciders": [],
+            "ipv6": "2001:db8::2",
+            "port": 51258,
+            "stun_ip": "",
+            "stun_port": 0,
+            "last_active": 0,
+        }))
+        .unwrap();
+        assert_eq!(peer.ipv6, vec!["2001:db8::2"]);
+        // older peers read a single address back as a string
+        assert_eq!(serde_json::to_value(&peer).unwrap()["ipv6"], "2001:db8::2");
+
+        peer.ipv6.push("2001:db8::3".to_string());
+        let json = serde_json::to_value(&peer).unwrap();
+        assert_eq!(
+            json["ipv6"],
+            serde_json::json!(["2001:db8::2", "2001:db8::3"])
+        );
+        let back: PeerDetail = serde_json::from_value(json).unwrap();
+        assert_eq!(back.ipv6, peer.ipv6);
+
+        peer.ipv6.clear();
+        let json = serde_json::to_value(&peer).unwrap();
+        assert_eq!(json["ipv6"], "");
+        let back: PeerDetail = serde_json::from_value(json).unwrap();
+        assert!(back.ipv6.is_empty());
+    }
+
+    #[test]
+    fn test_validate_ip_packet_levels() {
+        let valid = DataFrame {
+            payload: ipv4_packet([10, 0, 0, 1], [10, 0, 0, 2], 17, b"hello"),
+            seq: None,
+        };
+        for level in [
+            IpValidation::None,
+            IpValidation::Basic,
+            IpValidation::Strict,
+        ] {
+            assert!(valid.validate_ip_packet(level), "{level:?}");
+        }
+
+        let mut corrupt = valid.clone();
+        corrupt.payload[10] ^= 0xff;
+        assert!(corrupt.validate_ip_packet(IpValidation::Basic));
+        assert!(!corrupt.validate_ip_packet(IpValidation::Strict));
+
+        let mut truncated = valid.clone();
+        truncated.payload.pop();
+        assert!(truncated.validate_ip_packet(IpValidation::Basic));
+        assert!(!truncated.validate_ip_packet(IpValidation::Strict));
+
+        let mut bad_ihl = valid.clone();
+        bad_ihl.payload[0] = 0x44;
+        assert!(!bad_ihl.validate_ip_packet(IpValidation::Strict));
+
+        let mut ipv6 = vec![0u8; 48];
+        ipv6[0] = 0x60;
+        ipv6[5] = 8;
+        let ipv6 = DataFrame {
+            payload: ipv6,
+            seq: None,
+        };
+        assert!(ipv6.validate_ip_packet(IpValidation::Strict));
+
+        let garbage = DataFrame {
+            payload: vec![0xff; 4],
+            seq: None,
+        };
+        assert!(garbage.validate_ip_packet(IpValidation::None));
+        assert!(!garbage.validate_ip_packet(IpValidation::Basic));
+    }
+}
@@ -0,0 +1,173 @@
+//! Frame codec for tokio_util framed streams
+//!
+//! Wraps `Parser` so frames can be read from and written to any
+//! `AsyncRead`/`AsyncWrite` with `Framed::new(stream, FrameCodec::new(block))`.
+
+use crate::codec::errors::FrameError;
+use crate::codec::frame::Frame;
+use crate::codec::magic::DEFAULT_MAGIC;
+use crate::codec::parser::{MAX_FRAME_LEN, MIN_VERSION, Parser};
+use crate::crypto::Block;
+use bytes::{Buf, BytesMut};
+use std::sync::Arc;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Encoder/decoder for rustun frames
+#[derive(Clone)]
+pub struct FrameCodec {
+    /// Crypto block for encryption/decryption
+    block: Arc<Box<dyn Block>>,
+    /// Largest frame (header + payload) accepted when decoding
+    max_frame_size: usize,
+    /// Magic number frames are encoded and expected with
+    magic: u32,
+}
+
+impl FrameCodec {
+    /// Create a codec using the given crypto block
+    pub fn new(block: Arc<Box<dyn Block>>) -> Self {
+        Self {
+            block,
+            max_frame_size: MAX_FRAME_LEN,
+            magic: DEFAULT_MAGIC,
+        }
+    }
+
+    /// Set the largest frame accepted when decoding
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Set the magic number frames are encoded and expected with
+    pub fn with_magic(mut self, magic: u32) -> Self {
+        self.magic = magic;
+        self
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<Frame>> {
+        if let Some(len) = Parser::declared_len(src, self.magic) {
+            if len > self.max_frame_size {
+                return Err(FrameError::TooLong.into());
+            }
+            if src.len() < len {
+                src.reserve(len - src.len());
+                return Ok(None);
+            }
+        }
+
+        match Parser::unmarshal_frame(src, self.block.as_ref().as_ref(), self.magic) {
+            Ok((frame, total_len)) => {
+                src.advance(total_len);
+                Ok(Some(frame))
+            }
+            Err(FrameError::TooShort) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> anyhow::Result<()> {
+        let buf = Parser::marshal_frame(
+            frame,
+            self.block.as_ref().as_ref(),
+            MIN_VERSION,
+            None,
+            self.magic,
+        )?;
+        dst.extend_from_slice(&buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::frame::{DataFrame, ProbeIPv6Frame};
+    use crate::crypto::plain::PlainBlock;
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    fn codec() -> FrameCodec {
+        FrameCodec::new(Arc::new(Box::new(PlainBlock::new())))
+    }
+
+    fn data(payload: &[u8]) -> Frame {
+        Frame::Data(DataFrame {
+            payload: payload.to_vec(),
+            seq: None,
+        })
+    }
+
+    fn probe() -> Frame {
+        Frame::ProbeIPv6(ProbeIPv6Frame {
+            identity: "client-a".to_string(),
+        })
+    }
+
+    fn marshal(frame: Frame) -> Vec<u8> {
+        Parser::marshal(frame, &PlainBlock::new()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_decode_partial_reads() {
+        let first = marshal(data(&[1, 2, 3, 4, 5]));
+        let second = marshal(probe());
+
+        // Split inside the header, inside the payload and across frames
+        let mut stream = first.clone();
+        stream.extend_from_slice(&second);
+        let (a, rest) = stream.split_at(3);
+        let (b, rest) = rest.split_at(7);
+        let (c, d) = rest.split_at(first.len() - 10 + 2);
+        let io = tokio_test::io::Builder::new()
+            .read(a)
+            .read(b)
+            .read(c)
+            .read(d)
+            .build();
+
+        let mut framed = FramedRead::new(io, codec());
+        match framed.next().await.unwrap().unwrap() {
+            Frame::Data(frame) => assert_eq!(frame.payload, vec![1, 2, 3, 4, 5]),
+            frame => panic!("unexpected frame {frame}"),
+        }
+        match framed.next().await.unwrap().unwrap() {
+            Frame::ProbeIPv6(frame) => assert_eq!(frame.identity, "client-a"),
+            frame => panic!("unexpected frame {frame}"),
+        }
+        assert!(framed.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_encode_writes_marshaled_frames() {
+        let io = tokio_test::io::Builder::new()
+            .write(&marshal(data(&[9, 9])))
+            .write(&marshal(probe()))
+            .build();
+
+        let mut framed = FramedWrite::new(io, codec());
+        framed.send(data(&[9, 9])).await.unwrap();
+        framed.send(probe()).await.unwrap();
+    }
+
+    #[test]
+    fn test_decode_rejects_oversized_frame() {
+        let mut codec = codec().with_max_frame_size(16);
+        let mut buf = BytesMut::from(&marshal(data(&[0u8; 32]))[..12]);
+
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FrameError>(),
+            Some(FrameError::TooLong)
+        ));
+    }
+}
@@ -0,0 +1,151 @@
+//! Magic number starting every frame
+//!
+//! A fixed magic makes the protocol trivial to fingerprint. A deployment
+//! may derive its own from the shared key, an HMAC nobody without the key
+//! can predict, or pick one. The server and its clients must be configured
+//! alike, frames with another magic are garbage to them.
+
+use crate::crypto::CryptoConfig;
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Magic number of frames unless configured otherwise
+pub const DEFAULT_MAGIC: u32 = 0x91929394;
+
+/// HMAC message the key-derived magic is computed over
+const MAGIC_LABEL: &[u8] = b"rustun frame magic";
+
+/// Magic number derived from the shared key of a deployment
+pub fn derive(key: &str) -> u32 {
+    let mac = hmac_sha256(key.as_bytes(), MAGIC_LABEL);
+    u32::from_be_bytes([mac[0], mac[1], mac[2], mac[3]])
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK_LEN: usize = 64;
+    let mut padded = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        padded[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        padded[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(padded.map(|b| b ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(padded.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+/// Where the magic number of a deployment comes from
+///
+/// Written as `default`, `key` or a number like `0x1a2b3c4d`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum MagicSource {
+    /// `DEFAULT_MAGIC`, understood by every version
+    #[default]
+    Default,
+    /// Derived from the key of the crypto configuration
+    Key,
+    /// Given explicitly
+    Fixed(u32),
+}
+
+impl MagicSource {
+    /// The magic number, taking the key of `crypto` for `Key`
+    ///
+    /// # Returns
+    /// * `Err` - `Key` with a crypto configuration without a key
+    pub fn resolve(self, crypto: &CryptoConfig) -> anyhow::Result<u32> {
+        match self {
+            MagicSource::Default => Ok(DEFAULT_MAGIC),
+            MagicSource::Fixed(magic) => Ok(magic),
+            MagicSource::Key => match crypto.key() {
+                Some(key) => Ok(derive(key)),
+                None => anyhow::bail!("a key-derived frame magic needs an encryption key"),
+            },
+        }
+    }
+}
+
+impl FromStr for MagicSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(MagicSource::Default),
+            "key" => Ok(MagicSource::Key),
+            _ => s
+                .strip_prefix("0x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .map(MagicSource::Fixed)
+                .ok_or_else(|| {
+                    format!("expected default, key or a hex number like 0x1a2b3c4d, got {s:?}")
+                }),
+        }
+    }
+}
+
+impl TryFrom<String> for MagicSource {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<MagicSource> for String {
+    fn from(source: MagicSource) -> Self {
+        source.to_string()
+    }
+}
+
+impl Display for MagicSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MagicSource::Default => write!(f, "default"),
+            MagicSource::Key => write!(f, "key"),
+            MagicSource::Fixed(magic) => write!(f, "{magic:#010x}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_matches_rfc_4231() {
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        let hex: String = mac.iter().map(|b| format!("{b:02x}")).collect();
+        assert_eq!(
+            hex,
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_magic_source_parsed_and_resolved() {
+        let crypto = CryptoConfig::ChaCha20Poly1305("rustun".to_string());
+        assert_eq!("default".parse(), Ok(MagicSource::Default));
+        assert_eq!(
+            "0x1a2b3c4d"
+                .parse::<MagicSource>()
+                .unwrap()
+                .resolve(&crypto)
+                .unwrap(),
+            0x1a2b3c4d
+        );
+        assert!("1a2b3c4d".parse::<MagicSource>().is_err());
+        assert_eq!(MagicSource::Fixed(0x1a2b3c4d).to_string(), "0x1a2b3c4d");
+
+        let derived = MagicSource::Key.resolve(&crypto).unwrap();
+        assert_eq!(derived, derive("rustun"));
+        assert_ne!(derived, DEFAULT_MAGIC);
+        assert_ne!(derived, derive("other key"));
+        assert!(MagicSource::Key.resolve(&CryptoConfig::Plain).is_err());
+    }
+}
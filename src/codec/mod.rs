@@ -1,3 +1,6 @@
 pub mod errors;
 pub mod frame;
+pub mod frame_codec;
+pub mod magic;
 pub mod parser;
+pub mod stats;
@@ -6,56 +6,228 @@
 
 use crate::codec::errors::FrameError;
 use crate::codec::frame::*;
+use crate::codec::magic::DEFAULT_MAGIC;
+use crate::codec::stats::FrameStats;
 use crate::crypto::Block;
 use anyhow::Context;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 
-/// Protocol magic number for frame validation
-const MAGIC: u32 = 0x91929394;
-/// Protocol version
-const VERSION: u8 = 0x01;
+/// Oldest protocol version, spoken until the handshake picks another
+pub const MIN_VERSION: u8 = 0x01;
+/// Newest protocol version we speak
+///
+/// Version 2 keeps the version 1 wire format, its clients all answer
+/// echoes. Version 3 widens the header's
+/// payload length from 2 to 4 bytes, lifting the 64KB frame limit of
+/// version 1, and adds sequenced data frames. Version 4 rekeys relay
+/// connections. Version 5 pushes peer join and leave events. Version 6
+/// lists several IPv6 addresses per peer.
+pub const MAX_VERSION: u8 = 0x06;
+/// First version whose clients are known to answer `Echo` frames
+pub const ECHO_VERSION: u8 = 0x02;
+/// First version with the 4-byte payload length
+const WIDE_LEN_VERSION: u8 = 0x03;
+/// First version whose data frames carry `DataFrame::seq`
+const SEQ_VERSION: u8 = 0x03;
+/// Bytes of the sequence number before the packet of a `SeqData` frame
+const SEQ_LEN: usize = 8;
+/// First version whose relay connections accept `Rekey` frames
+pub const REKEY_VERSION: u8 = 0x04;
+/// First version whose clients are pushed `PeerJoin` and `PeerLeave` frames
+pub const PEER_EVENTS_VERSION: u8 = 0x05;
+/// First version reading a list of IPv6 addresses per peer
+pub const IPV6_LIST_VERSION: u8 = 0x06;
+/// Bytes of the content length before the content of a padded data frame
+const PAD_LEN: usize = 4;
+
+/// Version used with a peer supporting up to `peer_max`
+///
+/// Peers from before version negotiation advertise nothing, read as
+/// `MIN_VERSION`.
+pub fn negotiate_version(own_max: u8, peer_max: u8) -> u8 {
+    own_max.min(peer_max).max(MIN_VERSION)
+}
+
+/// Largest frame accepted by default: a wide header plus 256KB of payload
+///
+/// Four times the largest IP packet, enough for a large-MTU packet with
+/// its encryption, sequence and padding overhead, while bounding what a
+/// single client can make the server buffer.
+pub(crate) const MAX_FRAME_LEN: usize = HDR_LEN_V2 + (256 << 10);
+
+/// Largest frame payload of version 1, after encryption
+pub const MAX_PAYLOAD_LEN: usize = u16::MAX as usize;
+
+/// Fields of a frame header
+struct Header {
+    frame_type: u8,
+    /// Length of the header itself, depends on `version`
+    len: usize,
+    payload_len: usize,
+}
 
 pub struct Parser;
 
 impl Parser {
-    /// Unmarshals (deserializes) a frame from raw bytes
+    /// Largest tunneled packet a frame in `version` encrypted with `block`
+    /// can carry
     ///
-    /// Parses the frame header, validates it, extracts and decrypts the payload,
-    /// and deserializes it into the appropriate Frame variant.
+    /// Wide frames are held to `MAX_FRAME_LEN`, what peers read by default,
+    /// leaving room for a sequence number.
+    pub fn max_packet_len(block: &dyn Block, version: u8) -> usize {
+        if version >= WIDE_LEN_VERSION {
+            MAX_FRAME_LEN - HDR_LEN_V2 - SEQ_LEN - block.overhead()
+        } else {
+            MAX_PAYLOAD_LEN - block.overhead()
+        }
+    }
+
+    /// Rejects data frames carrying more than `max_packet_len` bytes
     ///
-    /// # Arguments
-    /// * `buf` - Raw byte buffer containing the frame
-    /// * `block` - Cipher block for payload decryption
+    /// Control frames always fit and are not checked.
     ///
     /// # Returns
-    /// * `Ok((Frame, usize))` - Parsed frame and total bytes consumed
-    /// * `Err` - If frame is invalid, too short, or decryption/parsing fails
-    pub fn unmarshal(buf: &[u8], block: &dyn Block) -> anyhow::Result<(Frame, usize)> {
-        if buf.len() < HDR_LEN {
-            return Err(FrameError::TooShort.into());
+    /// * `Ok(())` - The frame fits in one frame once encrypted
+    /// * `Err` - The packet is too large for the frame limit and must be dropped
+    pub fn check_packet_len(frame: &Frame, max_packet_len: usize) -> anyhow::Result<()> {
+        let len = match frame {
+            Frame::Data(data) => data.payload.len(),
+            Frame::DataBatch(batch) => batch.encoded_len(),
+            _ => return Ok(()),
+        };
+        if len > max_packet_len {
+            anyhow::bail!(
+                "packet too large for MTU: {len} bytes, a frame carries at most {max_packet_len}"
+            );
         }
+        Ok(())
+    }
+
+    /// Reads the total frame length declared by a header starting with
+    /// `magic`
+    ///
+    /// # Returns
+    /// * `Some(usize)` - Header plus declared payload length
+    /// * `None` - Buffer is shorter than a header or the header is invalid
+    pub(crate) fn declared_len(buf: &[u8], magic: u32) -> Option<usize> {
+        let header = Self::read_header(buf, magic).ok()?;
+        Some(header.len + header.payload_len)
+    }
 
+    /// Header length of frames in `version`
+    pub(crate) fn header_len(version: u8) -> usize {
+        if version >= WIDE_LEN_VERSION {
+            HDR_LEN_V2
+        } else {
+            HDR_LEN
+        }
+    }
+
+    /// Reads and validates the header at the start of `buf`
+    ///
+    /// # Returns
+    /// * `Err(FrameError::TooShort)` - Buffer does not hold the whole header yet
+    /// * `Err(FrameError::Invalid)` - Not `expected_magic` or unknown version
+    fn read_header(buf: &[u8], expected_magic: u32) -> Result<Header, FrameError> {
+        if buf.len() < HDR_LEN {
+            return Err(FrameError::TooShort);
+        }
         let magic = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
         let version = buf[4];
-        let cmd = buf[5];
-        let payload_size = u16::from_be_bytes([buf[6], buf[7]]);
-
-        if !Self::validate(magic, version, payload_size, buf) {
+        if !Self::validate(magic, expected_magic, version) {
             tracing::debug!(
-                "validate header fail: magic = {} version={} payload_size={} buf size={}",
+                "validate header fail: magic = {} version={} buf size={}",
                 magic,
                 version,
-                payload_size,
                 buf.len()
             );
-            return Err(FrameError::Invalid.into());
+            return Err(FrameError::Invalid);
         }
+        let len = Self::header_len(version);
+        if buf.len() < len {
+            return Err(FrameError::TooShort);
+        }
+        let payload_len = match len {
+            HDR_LEN_V2 => u32::from_be_bytes([buf[6], buf[7], buf[8], buf[9]]) as usize,
+            _ => u16::from_be_bytes([buf[6], buf[7]]) as usize,
+        };
+        Ok(Header {
+            frame_type: buf[5],
+            len,
+            payload_len,
+        })
+    }
 
-        let total_len = HDR_LEN + payload_size as usize;
-        let payload = &mut buf[HDR_LEN..total_len].to_vec();
+    /// Finds the offset of the first `magic` number in a buffer
+    ///
+    /// Used to resynchronize a stream after a corrupted header: the bytes
+    /// before the returned offset cannot start a frame and can be dropped.
+    ///
+    /// # Returns
+    /// * `Some(usize)` - Offset of the first magic number
+    /// * `None` - No complete magic number in the buffer
+    pub fn find_next_magic(buf: &[u8], magic: u32) -> Option<usize> {
+        let magic = magic.to_be_bytes();
+        buf.windows(magic.len()).position(|w| w == magic)
+    }
 
-        let frame_type = FrameType::try_from(cmd)?;
+    /// Unmarshals (deserializes) a frame from raw bytes
+    ///
+    /// Parses the frame header, validates it, extracts and decrypts the payload,
+    /// and deserializes it into the appropriate Frame variant. The header must
+    /// start with `DEFAULT_MAGIC`, see [`Parser::unmarshal_frame`] for others.
+    ///
+    /// # Arguments
+    /// * `buf` - Raw byte buffer containing the frame
+    /// * `block` - Cipher block for payload decryption
+    ///
+    /// # Returns
+    /// * `Ok((Frame, usize))` - Parsed frame and total bytes consumed
+    /// * `Err(FrameError::TooShort)` - Buffer does not hold the whole frame yet
+    /// * `Err(FrameError::Invalid)` - Header is wrong, or the payload does not
+    ///   decode as its frame type
+    /// * `Err(FrameError::DecryptionFailed)` - Payload does not decrypt with
+    ///   `block`, usually a key mismatch
+    pub fn unmarshal(buf: &[u8], block: &dyn Block) -> Result<(Frame, usize), FrameError> {
+        Self::unmarshal_frame(buf, block, DEFAULT_MAGIC)
+    }
+
+    /// Unmarshals a frame starting with `magic`, counting it in `stats` if
+    /// given
+    ///
+    /// Only frames that decode are counted. See [`Parser::unmarshal`].
+    pub fn unmarshal_counted(
+        buf: &[u8],
+        block: &dyn Block,
+        magic: u32,
+        stats: Option<&FrameStats>,
+    ) -> Result<(Frame, usize), FrameError> {
+        let result = Self::unmarshal_frame(buf, block, magic);
+        if let Some(stats) = stats
+            && result.is_ok()
+        {
+            stats.record_received(buf[5]);
+        }
+        result
+    }
+
+    /// Unmarshals a frame of a deployment using `magic`
+    ///
+    /// See [`Parser::unmarshal`].
+    pub(crate) fn unmarshal_frame(
+        buf: &[u8],
+        block: &dyn Block,
+        magic: u32,
+    ) -> Result<(Frame, usize), FrameError> {
+        let header = Self::read_header(buf, magic)?;
+        let total_len = header.len + header.payload_len;
+        if buf.len() < total_len {
+            return Err(FrameError::TooShort);
+        }
+        let payload = &mut buf[header.len..total_len].to_vec();
+
+        let frame_type = FrameType::try_from(header.frame_type)?;
         match frame_type {
             FrameType::Handshake => {
                 let hs: HandshakeFrame = Self::decrypt_and_deserialize(payload, block)?;
@@ -67,18 +239,48 @@ impl Parser {
                 Ok((Frame::HandshakeReply(reply), total_len))
             }
 
+            FrameType::HandshakeReject => {
+                let reject: HandshakeRejectFrame = Self::decrypt_and_deserialize(payload, block)?;
+                Ok((Frame::HandshakeReject(reject), total_len))
+            }
+
             FrameType::KeepAlive => {
                 let keepalive: KeepAliveFrame = Self::decrypt_and_deserialize(payload, block)?;
                 Ok((Frame::KeepAlive(keepalive), total_len))
             }
 
-            FrameType::Data => {
+            FrameType::Data | FrameType::PaddedData => {
                 block
                     .decrypt(payload)
                     .map_err(FrameError::DecryptionFailed)?;
+                if matches!(frame_type, FrameType::PaddedData) {
+                    Self::unpad(payload)?;
+                }
                 Ok((
                     Frame::Data(DataFrame {
                         payload: payload.to_vec(),
+                        seq: None,
+                    }),
+                    total_len,
+                ))
+            }
+
+            FrameType::SeqData | FrameType::PaddedSeqData => {
+                block
+                    .decrypt(payload)
+                    .map_err(FrameError::DecryptionFailed)?;
+                if matches!(frame_type, FrameType::PaddedSeqData) {
+                    Self::unpad(payload)?;
+                }
+                if payload.len() < SEQ_LEN {
+                    return Err(FrameError::Invalid);
+                }
+                let packet = payload.split_off(SEQ_LEN);
+                let seq = u64::from_be_bytes(payload[..].try_into().unwrap());
+                Ok((
+                    Frame::Data(DataFrame {
+                        payload: packet,
+                        seq: Some(seq),
                     }),
                     total_len,
                 ))
@@ -93,20 +295,118 @@ impl Parser {
                 let probe: ProbeHolePunchFrame = Self::decrypt_and_deserialize(payload, block)?;
                 Ok((Frame::ProbeHolePunch(probe), total_len))
             }
+
+            FrameType::DataBatch => {
+                block
+                    .decrypt(payload)
+                    .map_err(FrameError::DecryptionFailed)?;
+                let batch = DataBatchFrame::decode(payload)?;
+                Ok((Frame::DataBatch(batch), total_len))
+            }
+
+            FrameType::PeerUpdate => {
+                let update: PeerUpdateFrame = Self::decrypt_and_deserialize(payload, block)?;
+                Ok((Frame::PeerUpdate(update), total_len))
+            }
+
+            FrameType::Echo => {
+                let echo: EchoFrame = Self::decrypt_and_deserialize(payload, block)?;
+                Ok((Frame::Echo(echo), total_len))
+            }
+
+            FrameType::EchoReply => {
+                let reply: EchoReplyFrame = Self::decrypt_and_deserialize(payload, block)?;
+                Ok((Frame::EchoReply(reply), total_len))
+            }
+
+            FrameType::P2PKeyInit => {
+                let init: P2PKeyFrame = Self::decrypt_and_deserialize(payload, block)?;
+                Ok((Frame::P2PKeyInit(init), total_len))
+            }
+
+            FrameType::P2PKeyReply => {
+                let reply: P2PKeyFrame = Self::decrypt_and_deserialize(payload, block)?;
+                Ok((Frame::P2PKeyReply(reply), total_len))
+            }
+
+            FrameType::PeerJoin => {
+                let join: PeerJoinFrame = Self::decrypt_and_deserialize(payload, block)?;
+                Ok((Frame::PeerJoin(join), total_len))
+            }
+
+            FrameType::PeerLeave => {
+                let leave: PeerLeaveFrame = Self::decrypt_and_deserialize(payload, block)?;
+                Ok((Frame::PeerLeave(leave), total_len))
+            }
+
+            FrameType::Rekey => {
+                let rekey: RekeyFrame = Self::decrypt_and_deserialize(payload, block)?;
+                Ok((Frame::Rekey(rekey), total_len))
+            }
+
+            FrameType::Close => {
+                let close: CloseFrame = Self::decrypt_and_deserialize(payload, block)?;
+                Ok((Frame::Close(close), total_len))
+            }
         }
     }
 
+    /// Whether a marshaled frame carries tunneled packets (`Data` or
+    /// `DataBatch`)
+    pub(crate) fn carries_data(buf: &[u8]) -> bool {
+        buf.len() >= HDR_LEN
+            && matches!(
+                FrameType::try_from(buf[5]),
+                Ok(FrameType::Data
+                    | FrameType::DataBatch
+                    | FrameType::SeqData
+                    | FrameType::PaddedData
+                    | FrameType::PaddedSeqData)
+            )
+    }
+
+    /// Pads data frame `content` with zeros up to a multiple of `bucket`
+    /// bytes, after a length prefix to strip them with
+    ///
+    /// Sealed afterwards, the peer sees bucket sized payloads only.
+    fn pad(content: &mut Vec<u8>, bucket: usize) -> anyhow::Result<()> {
+        let len = u32::try_from(content.len())
+            .map_err(|_| anyhow::anyhow!("data frame of {} bytes too large", content.len()))?;
+        let padded = (PAD_LEN + content.len()).div_ceil(bucket.max(1)) * bucket.max(1);
+        let mut buf = Vec::with_capacity(padded);
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.append(content);
+        buf.resize(padded, 0);
+        *content = buf;
+        Ok(())
+    }
+
+    /// Strips the padding `pad` added, in place
+    ///
+    /// # Returns
+    /// * `Err(FrameError::Invalid)` - The length prefix is missing or longer
+    ///   than the padded content
+    fn unpad(padded: &mut Vec<u8>) -> Result<(), FrameError> {
+        let prefix = padded.get(..PAD_LEN).ok_or(FrameError::Invalid)?;
+        let len = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+        if len > padded.len() - PAD_LEN {
+            return Err(FrameError::Invalid);
+        }
+        padded.truncate(PAD_LEN + len);
+        padded.drain(..PAD_LEN);
+        Ok(())
+    }
+
     /// Validates frame header
     ///
-    /// Checks magic number, version, and ensures complete frame is in buffer.
+    /// Checks magic number and version.
     ///
     /// # Arguments
-    /// * `magic` - Magic number from header (should be 0x91929394)
-    /// * `version` - Protocol version (should be 0x01)
-    /// * `payload_size` - Payload length from header
-    /// * `buf` - Complete buffer to verify size
-    fn validate(magic: u32, version: u8, payload_size: u16, buf: &[u8]) -> bool {
-        magic == MAGIC && version == VERSION && (payload_size as usize + HDR_LEN) <= buf.len()
+    /// * `magic` - Magic number from header
+    /// * `expected_magic` - Magic of this deployment, see `codec::magic`
+    /// * `version` - Protocol version (`MIN_VERSION` to `MAX_VERSION`)
+    fn validate(magic: u32, expected_magic: u32, version: u8) -> bool {
+        magic == expected_magic && (MIN_VERSION..=MAX_VERSION).contains(&version)
     }
 
     /// Decrypts and deserializes JSON payload
@@ -123,11 +423,11 @@ impl Parser {
     fn decrypt_and_deserialize<T: DeserializeOwned>(
         payload: &mut Vec<u8>,
         block: &dyn Block,
-    ) -> anyhow::Result<T> {
+    ) -> Result<T, FrameError> {
         block
             .decrypt(payload)
             .map_err(FrameError::DecryptionFailed)?;
-        serde_json::from_slice(payload).map_err(|_| FrameError::Invalid.into())
+        serde_json::from_slice(payload).map_err(|_| FrameError::Invalid)
     }
 
     /// Serializes and encrypts JSON payload
@@ -156,27 +456,48 @@ impl Parser {
 
     /// Builds a frame header
     ///
-    /// Creates the 8-byte frame header with magic, version, frame type, and payload length.
+    /// Creates the frame header with magic, version, frame type, and payload
+    /// length, 2 bytes wide before `WIDE_LEN_VERSION` and 4 bytes from it on.
     ///
     /// # Arguments
+    /// * `magic` - Magic number of the deployment
+    /// * `version` - Protocol version of the frame
     /// * `frame_type` - Type of frame
     /// * `payload_len` - Length of payload in bytes
     ///
     /// # Returns
-    /// Header bytes (8 bytes total)
-    fn build_header(frame_type: FrameType, payload_len: u16) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(HDR_LEN + payload_len as usize);
-        buf.extend_from_slice(&MAGIC.to_be_bytes());
-        buf.push(VERSION);
+    /// * `Ok(Vec<u8>)` - Header bytes (8 or 10 bytes)
+    /// * `Err` - The payload length does not fit the header of `version`
+    fn build_header(
+        magic: u32,
+        version: u8,
+        frame_type: FrameType,
+        payload_len: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let too_large = || {
+            anyhow::anyhow!(
+                "payload of {payload_len} bytes too large for a version {version} frame"
+            )
+        };
+        let mut buf = Vec::with_capacity(Self::header_len(version) + payload_len);
+        buf.extend_from_slice(&magic.to_be_bytes());
+        buf.push(version);
         buf.push(frame_type as u8);
-        buf.extend_from_slice(&payload_len.to_be_bytes());
-        buf
+        if version >= WIDE_LEN_VERSION {
+            let len = u32::try_from(payload_len).map_err(|_| too_large())?;
+            buf.extend_from_slice(&len.to_be_bytes());
+        } else {
+            let len = u16::try_from(payload_len).map_err(|_| too_large())?;
+            buf.extend_from_slice(&len.to_be_bytes());
+        }
+        Ok(buf)
     }
 
     /// Marshals (serializes) a frame into raw bytes
     ///
     /// Serializes the frame data to JSON, encrypts the payload, and builds
-    /// the frame header with the complete frame structure.
+    /// the frame header with the complete frame structure. The header starts
+    /// with `DEFAULT_MAGIC`, see [`Parser::marshal_frame`] for others.
     ///
     /// # Arguments
     /// * `frame` - Frame to serialize
@@ -186,11 +507,62 @@ impl Parser {
     /// * `Ok(Vec<u8>)` - Complete frame bytes (header + encrypted payload)
     /// * `Err` - If serialization or encryption fails
     pub fn marshal(frame: Frame, block: &dyn Block) -> anyhow::Result<Vec<u8>> {
+        Self::marshal_version(frame, block, MIN_VERSION)
+    }
+
+    /// Marshals a frame for a connection that negotiated `version`
+    ///
+    /// See [`Parser::marshal`].
+    pub fn marshal_version(
+        frame: Frame,
+        block: &dyn Block,
+        version: u8,
+    ) -> anyhow::Result<Vec<u8>> {
+        Self::marshal_frame(frame, block, version, None, DEFAULT_MAGIC)
+    }
+
+    /// Marshals a frame in `version` starting with `magic`, counting it in
+    /// `stats` if given
+    ///
+    /// Data frames are padded up to a multiple of `pad` bytes if set, for
+    /// peers that asked for it in the handshake. See [`Parser::marshal`].
+    pub fn marshal_counted(
+        frame: Frame,
+        block: &dyn Block,
+        version: u8,
+        pad: Option<usize>,
+        magic: u32,
+        stats: Option<&FrameStats>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let buf = Self::marshal_frame(frame, block, version, pad, magic)?;
+        if let Some(stats) = stats {
+            stats.record_sent(buf[5]);
+        }
+        Ok(buf)
+    }
+
+    /// Marshals a frame in `version` for a deployment using `magic`
+    ///
+    /// See [`Parser::marshal_counted`].
+    pub(crate) fn marshal_frame(
+        frame: Frame,
+        block: &dyn Block,
+        version: u8,
+        pad: Option<usize>,
+        magic: u32,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut frame = frame;
+        // the handshake reply always goes out in `MIN_VERSION`, the server
+        // trims it for the version it negotiated instead
+        if version < IPV6_LIST_VERSION && !matches!(frame, Frame::HandshakeReply(_)) {
+            frame.keep_first_ipv6();
+        }
         match frame {
             Frame::Handshake(hs) => {
                 let payload =
                     Self::serialize_and_encrypt(&hs, block, "failed to marshal handshake")?;
-                let mut buf = Self::build_header(FrameType::Handshake, payload.len() as u16);
+                let mut buf =
+                    Self::build_header(magic, version, FrameType::Handshake, payload.len())?;
                 buf.extend_from_slice(&payload);
                 Ok(buf)
             }
@@ -201,7 +573,20 @@ impl Parser {
                     block,
                     "failed to marshal handshake reply",
                 )?;
-                let mut buf = Self::build_header(FrameType::HandshakeReply, payload.len() as u16);
+                let mut buf =
+                    Self::build_header(magic, version, FrameType::HandshakeReply, payload.len())?;
+                buf.extend_from_slice(&payload);
+                Ok(buf)
+            }
+
+            Frame::HandshakeReject(reject) => {
+                let payload = Self::serialize_and_encrypt(
+                    &reject,
+                    block,
+                    "failed to marshal handshake reject",
+                )?;
+                let mut buf =
+                    Self::build_header(magic, version, FrameType::HandshakeReject, payload.len())?;
                 buf.extend_from_slice(&payload);
                 Ok(buf)
             }
@@ -209,14 +594,42 @@ impl Parser {
             Frame::KeepAlive(keepalive) => {
                 let payload =
                     Self::serialize_and_encrypt(&keepalive, block, "failed to marshal keepalive")?;
-                let mut buf = Self::build_header(FrameType::KeepAlive, payload.len() as u16);
+                let mut buf =
+                    Self::build_header(magic, version, FrameType::KeepAlive, payload.len())?;
                 buf.extend_from_slice(&payload);
                 Ok(buf)
             }
 
             Frame::Data(mut data) => {
+                // peers before `SEQ_VERSION` get the packet alone
+                let frame_type = match data.seq {
+                    Some(seq) if version >= SEQ_VERSION => {
+                        let mut payload = Vec::with_capacity(SEQ_LEN + data.payload.len());
+                        payload.extend_from_slice(&seq.to_be_bytes());
+                        payload.append(&mut data.payload);
+                        data.payload = payload;
+                        FrameType::SeqData
+                    }
+                    _ => FrameType::Data,
+                };
+                let frame_type = match pad {
+                    Some(bucket) => {
+                        Self::pad(&mut data.payload, bucket)?;
+                        match frame_type {
+                            FrameType::SeqData => FrameType::PaddedSeqData,
+                            _ => FrameType::PaddedData,
+                        }
+                    }
+                    None => frame_type,
+                };
                 block.encrypt(&mut data.payload)?;
-                let mut buf = Self::build_header(FrameType::Data, data.payload.len() as u16);
+                let payload_len = data.payload.len();
+                let mut buf =
+                    Self::build_header(magic, version, frame_type, payload_len).map_err(|_| {
+                        anyhow::anyhow!(
+                            "packet too large for MTU: {payload_len} bytes once encrypted"
+                        )
+                    })?;
                 buf.extend_from_slice(&data.payload);
                 Ok(buf)
             }
@@ -224,7 +637,8 @@ impl Parser {
             Frame::ProbeIPv6(frame) => {
                 let payload =
                     Self::serialize_and_encrypt(&frame, block, "failed to marshal probe ipv6")?;
-                let mut buf = Self::build_header(FrameType::ProbeIPv6, payload.len() as u16);
+                let mut buf =
+                    Self::build_header(magic, version, FrameType::ProbeIPv6, payload.len())?;
                 buf.extend_from_slice(&payload);
                 Ok(buf)
             }
@@ -235,10 +649,603 @@ impl Parser {
                     block,
                     "failed to marshal probe hole punch",
                 )?;
-                let mut buf = Self::build_header(FrameType::ProbeHolePunch, payload.len() as u16);
+                let mut buf =
+                    Self::build_header(magic, version, FrameType::ProbeHolePunch, payload.len())?;
+                buf.extend_from_slice(&payload);
+                Ok(buf)
+            }
+
+            Frame::DataBatch(batch) => {
+                let mut payload = batch.encode();
+                block.encrypt(&mut payload)?;
+                let mut buf =
+                    Self::build_header(magic, version, FrameType::DataBatch, payload.len())?;
+                buf.extend_from_slice(&payload);
+                Ok(buf)
+            }
+
+            Frame::PeerUpdate(update) => {
+                let payload =
+                    Self::serialize_and_encrypt(&update, block, "failed to marshal peer update")?;
+                let mut buf =
+                    Self::build_header(magic, version, FrameType::PeerUpdate, payload.len())?;
+                buf.extend_from_slice(&payload);
+                Ok(buf)
+            }
+
+            Frame::Echo(echo) => {
+                let payload = Self::serialize_and_encrypt(&echo, block, "failed to marshal echo")?;
+                let mut buf = Self::build_header(magic, version, FrameType::Echo, payload.len())?;
+                buf.extend_from_slice(&payload);
+                Ok(buf)
+            }
+
+            Frame::EchoReply(reply) => {
+                let payload =
+                    Self::serialize_and_encrypt(&reply, block, "failed to marshal echo reply")?;
+                let mut buf =
+                    Self::build_header(magic, version, FrameType::EchoReply, payload.len())?;
+                buf.extend_from_slice(&payload);
+                Ok(buf)
+            }
+
+            Frame::P2PKeyInit(init) => {
+                let payload =
+                    Self::serialize_and_encrypt(&init, block, "failed to marshal p2p key init")?;
+                let mut buf =
+                    Self::build_header(magic, version, FrameType::P2PKeyInit, payload.len())?;
+                buf.extend_from_slice(&payload);
+                Ok(buf)
+            }
+
+            Frame::P2PKeyReply(reply) => {
+                let payload =
+                    Self::serialize_and_encrypt(&reply, block, "failed to marshal p2p key reply")?;
+                let mut buf =
+                    Self::build_header(magic, version, FrameType::P2PKeyReply, payload.len())?;
+                buf.extend_from_slice(&payload);
+                Ok(buf)
+            }
+
+            Frame::PeerJoin(join) => {
+                let payload =
+                    Self::serialize_and_encrypt(&join, block, "failed to marshal peer join")?;
+                let mut buf =
+                    Self::build_header(magic, version, FrameType::PeerJoin, payload.len())?;
+                buf.extend_from_slice(&payload);
+                Ok(buf)
+            }
+
+            Frame::PeerLeave(leave) => {
+                let payload =
+                    Self::serialize_and_encrypt(&leave, block, "failed to marshal peer leave")?;
+                let mut buf =
+                    Self::build_header(magic, version, FrameType::PeerLeave, payload.len())?;
+                buf.extend_from_slice(&payload);
+                Ok(buf)
+            }
+
+            Frame::Rekey(rekey) => {
+                let payload =
+                    Self::serialize_and_encrypt(&rekey, block, "failed to marshal rekey")?;
+                let mut buf = Self::build_header(magic, version, FrameType::Rekey, payload.len())?;
+                buf.extend_from_slice(&payload);
+                Ok(buf)
+            }
+
+            Frame::Close(close) => {
+                let payload =
+                    Self::serialize_and_encrypt(&close, block, "failed to marshal close")?;
+                let mut buf = Self::build_header(magic, version, FrameType::Close, payload.len())?;
                 buf.extend_from_slice(&payload);
                 Ok(buf)
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::frame::tests as frame_tests;
+    use crate::crypto::plain::PlainBlock;
+
+    fn data_frame(payload: &[u8]) -> Vec<u8> {
+        Parser::marshal(
+            Frame::Data(DataFrame {
+                payload: payload.to_vec(),
+                seq: None,
+            }),
+            &PlainBlock::new(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_oversized_packet_rejected() {
+        let block = crate::crypto::new_block(&crate::crypto::CryptoConfig::ChaCha20Poly1305(
+            "rustun".to_string(),
+        ));
+        let max = Parser::max_packet_len(block.as_ref(), MIN_VERSION);
+        assert_eq!(max, MAX_PAYLOAD_LEN - 28);
+        let data = |len| {
+            Frame::Data(DataFrame {
+                payload: vec![0x45; len],
+                seq: None,
+            })
+        };
+
+        // the largest packet still round-trips
+        assert!(Parser::check_packet_len(&data(max), max).is_ok());
+        let buf = Parser::marshal(data(max), block.as_ref()).unwrap();
+        let (frame, len) = Parser::unmarshal(&buf, block.as_ref()).unwrap();
+        assert_eq!(len, buf.len());
+        assert!(matches!(frame, Frame::Data(frame) if frame.payload.len() == max));
+
+        let e = Parser::check_packet_len(&data(max + 1), max).unwrap_err();
+        assert!(e.to_string().starts_with("packet too large for MTU"));
+        // marshal refuses instead of truncating the header length
+        let e = Parser::marshal(data(max + 1), block.as_ref()).unwrap_err();
+        assert!(e.to_string().starts_with("packet too large for MTU"));
+    }
+
+    #[test]
+    fn test_find_next_magic_after_garbage() {
+        let mut buf = vec![0x00, 0x91, 0x92, 0x93, 0xff, 0x91, 0x92];
+        buf.extend_from_slice(&data_frame(&[1, 2, 3]));
+
+        let offset = Parser::find_next_magic(&buf, DEFAULT_MAGIC).unwrap();
+        assert_eq!(offset, 7);
+
+        let (frame, len) = Parser::unmarshal(&buf[offset..], &PlainBlock::new()).unwrap();
+        assert_eq!(len, buf.len() - offset);
+        match frame {
+            Frame::Data(frame) => assert_eq!(frame.payload, vec![1, 2, 3]),
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    #[test]
+    fn test_find_next_magic_at_start_and_missing() {
+        assert_eq!(
+            Parser::find_next_magic(&data_frame(&[]), DEFAULT_MAGIC),
+            Some(0)
+        );
+        assert_eq!(
+            Parser::find_next_magic(&[0x91, 0x92, 0x93], DEFAULT_MAGIC),
+            None
+        );
+        assert_eq!(Parser::find_next_magic(&[], DEFAULT_MAGIC), None);
+    }
+
+    #[test]
+    fn test_key_derived_magic() {
+        let block = crate::crypto::new_block(&crate::crypto::CryptoConfig::ChaCha20Poly1305(
+            "rustun".to_string(),
+        ));
+        let derived = crate::codec::magic::derive("rustun");
+        let frame = || {
+            Frame::Data(DataFrame {
+                payload: b"packet".to_vec(),
+                seq: None,
+            })
+        };
+
+        let buf =
+            Parser::marshal_frame(frame(), block.as_ref(), MAX_VERSION, None, derived).unwrap();
+        assert_eq!(buf[..4], derived.to_be_bytes());
+        match Parser::unmarshal_frame(&buf, block.as_ref(), derived).unwrap() {
+            (Frame::Data(data), len) if len == buf.len() => assert_eq!(data.payload, b"packet"),
+            (frame, _) => panic!("unexpected frame {frame}"),
+        }
+
+        // neither side takes the other's frames
+        let default =
+            Parser::marshal_frame(frame(), block.as_ref(), MAX_VERSION, None, DEFAULT_MAGIC)
+                .unwrap();
+        assert!(matches!(
+            Parser::unmarshal_frame(&default, block.as_ref(), derived),
+            Err(FrameError::Invalid)
+        ));
+        assert!(matches!(
+            Parser::unmarshal_frame(&buf, block.as_ref(), DEFAULT_MAGIC),
+            Err(FrameError::Invalid)
+        ));
+    }
+
+    #[test]
+    fn test_padded_data_round_trip() {
+        let block = crate::crypto::new_block(&crate::crypto::CryptoConfig::ChaCha20Poly1305(
+            "rustun".to_string(),
+        ));
+        let header_len = Parser::header_len(MAX_VERSION);
+        for len in [0, 1, 251, 252, 253, 600, 1400] {
+            for seq in [None, Some(7)] {
+                let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                let frame = Frame::Data(DataFrame {
+                    payload: payload.clone(),
+                    seq,
+                });
+                let buf = Parser::marshal_counted(
+                    frame,
+                    block.as_ref(),
+                    MAX_VERSION,
+                    Some(256),
+                    DEFAULT_MAGIC,
+                    None,
+                )
+                .unwrap();
+                let sealed = buf.len() - header_len - block.overhead();
+                assert_eq!(sealed % 256, 0, "{len} bytes padded to {sealed}");
+                assert!(sealed >= len + PAD_LEN);
+
+                let (frame, read) = Parser::unmarshal(&buf, block.as_ref()).unwrap();
+                assert_eq!(read, buf.len());
+                match frame {
+                    Frame::Data(data) => {
+                        assert_eq!(data.payload, payload);
+                        assert_eq!(data.seq, seq);
+                    }
+                    frame => panic!("unexpected frame {frame}"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_padding_longer_than_frame_rejected() {
+        let mut content = b"packet".to_vec();
+        Parser::pad(&mut content, 64).unwrap();
+        assert_eq!(content.len(), 64);
+        content[..PAD_LEN].copy_from_slice(&61u32.to_be_bytes());
+        assert!(matches!(
+            Parser::unpad(&mut content),
+            Err(FrameError::Invalid)
+        ));
+        assert!(matches!(
+            Parser::unpad(&mut vec![0, 0]),
+            Err(FrameError::Invalid)
+        ));
+    }
+
+    fn peer_detail() -> PeerDetail {
+        PeerDetail {
+            name: "office".to_string(),
+            identity: "office-gw".to_string(),
+            private_ip: "10.0.0.2".to_string(),
+            ciders: vec!["192.168.1.0/24".to_string()],
+            ipv6: vec!["2001:db8::2".to_string()],
+            port: 51258,
+            stun_ip: "203.0.113.7".to_string(),
+            stun_port: 40000,
+            nat_type: 0,
+            last_active: 1_700_000_000,
+        }
+    }
+
+    fn assert_peer_detail(peer: &PeerDetail) {
+        let expected = peer_detail();
+        assert_eq!(peer.name, expected.name);
+        assert_eq!(peer.identity, expected.identity);
+        assert_eq!(peer.private_ip, expected.private_ip);
+        assert_eq!(peer.ciders, expected.ciders);
+        assert_eq!(peer.ipv6, expected.ipv6);
+        assert_eq!(peer.port, expected.port);
+        assert_eq!(peer.stun_ip, expected.stun_ip);
+        assert_eq!(peer.stun_port, expected.stun_port);
+        assert_eq!(peer.last_active, expected.last_active);
+        assert_eq!(peer.nat_type, expected.nat_type);
+    }
+
+    #[test]
+    fn test_peer_details_round_trip() {
+        let block = PlainBlock::new();
+        let reply = Frame::HandshakeReply(HandshakeReplyFrame {
+            name: "laptop".to_string(),
+            private_ip: "10.0.0.1".to_string(),
+            mask: "255.255.255.0".to_string(),
+            gateway: "10.0.0.254".to_string(),
+            ciders: vec![],
+            cider_mapping: Default::default(),
+            peers_version: 0,
+            resume_token: None,
+            resumed: false,
+            peer_details: vec![peer_detail()],
+            version: MIN_VERSION,
+            mode: Default::default(),
+            pad: None,
+        });
+        let buf = Parser::marshal(reply, &block).unwrap();
+        match Parser::unmarshal(&buf, &block).unwrap().0 {
+            Frame::HandshakeReply(reply) => {
+                assert_eq!(reply.peer_details.len(), 1);
+                assert_peer_detail(&reply.peer_details[0]);
+            }
+            frame => panic!("unexpected frame {frame}"),
+        }
+
+        let keepalive = Frame::KeepAlive(KeepAliveFrame {
+            peer_details: vec![peer_detail()],
+            ..frame_tests::keepalive("laptop")
+        });
+        let buf = Parser::marshal(keepalive, &block).unwrap();
+        match Parser::unmarshal(&buf, &block).unwrap().0 {
+            Frame::KeepAlive(keepalive) => {
+                assert_eq!(keepalive.peer_details.len(), 1);
+                assert_peer_detail(&keepalive.peer_details[0]);
+            }
+            frame => panic!("unexpected frame {frame}"),
+        }
+
+        let update = Frame::PeerUpdate(PeerUpdateFrame {
+            peer: peer_detail(),
+        });
+        let buf = Parser::marshal(update, &block).unwrap();
+        match Parser::unmarshal(&buf, &block).unwrap().0 {
+            Frame::PeerUpdate(update) => assert_peer_detail(&update.peer),
+            frame => panic!("unexpected frame {frame}"),
+        }
+
+        let join = Frame::PeerJoin(PeerJoinFrame {
+            peer: peer_detail(),
+        });
+        let buf = Parser::marshal(join, &block).unwrap();
+        match Parser::unmarshal(&buf, &block).unwrap().0 {
+            Frame::PeerJoin(join) => assert_peer_detail(&join.peer),
+            frame => panic!("unexpected frame {frame}"),
+        }
+
+        let leave = Frame::PeerLeave(PeerLeaveFrame {
+            identity: "office-gw".to_string(),
+        });
+        let buf = Parser::marshal(leave, &block).unwrap();
+        match Parser::unmarshal(&buf, &block).unwrap().0 {
+            Frame::PeerLeave(leave) => assert_eq!(leave.identity, "office-gw"),
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    #[test]
+    fn test_ipv6_list_sent_from_its_version() {
+        let block = PlainBlock::new();
+        let update = || {
+            let mut peer = peer_detail();
+            peer.ipv6 = vec!["2001:db8::2".to_string(), "2001:db8::3".to_string()];
+            Frame::PeerUpdate(PeerUpdateFrame { peer })
+        };
+        let buf = Parser::marshal_version(update(), &block, IPV6_LIST_VERSION).unwrap();
+        match Parser::unmarshal(&buf, &block).unwrap().0 {
+            Frame::PeerUpdate(update) => assert_eq!(update.peer.ipv6.len(), 2),
+            frame => panic!("unexpected frame {frame}"),
+        }
+
+        // older peers read the first address as a plain string
+        let buf = Parser::marshal_version(update(), &block, IPV6_LIST_VERSION - 1).unwrap();
+        let payload = String::from_utf8_lossy(&buf);
+        assert!(payload.contains(r#""ipv6":"2001:db8::2""#), "{payload}");
+        match Parser::unmarshal(&buf, &block).unwrap().0 {
+            Frame::PeerUpdate(update) => assert_eq!(update.peer.ipv6, vec!["2001:db8::2"]),
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    #[test]
+    fn test_data_batch_round_trip() {
+        let packets = vec![vec![0x45; 20], vec![0x45; 1400], vec![0x60; 40]];
+        for block in [
+            crate::crypto::new_block(&crate::crypto::CryptoConfig::Plain),
+            crate::crypto::new_block(&crate::crypto::CryptoConfig::ChaCha20Poly1305(
+                "rustun".to_string(),
+            )),
+        ] {
+            let buf = Parser::marshal(
+                Frame::DataBatch(DataBatchFrame {
+                    packets: packets.clone(),
+                }),
+                block.as_ref(),
+            )
+            .unwrap();
+
+            let (frame, len) = Parser::unmarshal(&buf, block.as_ref()).unwrap();
+            assert_eq!(len, buf.len());
+            match frame {
+                Frame::DataBatch(batch) => assert_eq!(batch.packets, packets),
+                frame => panic!("unexpected frame {frame}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_echo_round_trip() {
+        let block = crate::crypto::new_block(&crate::crypto::CryptoConfig::ChaCha20Poly1305(
+            "rustun".to_string(),
+        ));
+        let echo = EchoFrame {
+            id: 42,
+            sent_at: 1_700_000_000_123_456,
+            src: "10.0.0.1".to_string(),
+            dst: "10.0.0.2".to_string(),
+        };
+        let buf = Parser::marshal(Frame::Echo(echo.clone()), block.as_ref()).unwrap();
+        let decoded = match Parser::unmarshal(&buf, block.as_ref()).unwrap() {
+            (Frame::Echo(decoded), len) if len == buf.len() => decoded,
+            (frame, _) => panic!("unexpected frame {frame}"),
+        };
+        assert_eq!(decoded.id, 42);
+        assert_eq!(decoded.sent_at, 1_700_000_000_123_456);
+        assert_eq!(decoded.src, "10.0.0.1");
+        assert_eq!(decoded.dst, "10.0.0.2");
+
+        let buf = Parser::marshal(Frame::EchoReply(decoded.reply()), block.as_ref()).unwrap();
+        match Parser::unmarshal(&buf, block.as_ref()).unwrap().0 {
+            Frame::EchoReply(reply) => {
+                assert_eq!(reply.id, echo.id);
+                assert_eq!(reply.sent_at, echo.sent_at);
+                assert_eq!(reply.dst, "10.0.0.1");
+            }
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    #[test]
+    fn test_data_batch_truncated_record_is_invalid() {
+        let mut payload = DataBatchFrame {
+            packets: vec![vec![1, 2, 3]],
+        }
+        .encode();
+        payload.pop();
+        assert!(matches!(
+            DataBatchFrame::decode(&payload),
+            Err(FrameError::Invalid)
+        ));
+    }
+
+    #[test]
+    fn test_unmarshal_incomplete_vs_invalid() {
+        let frame = data_frame(&[0u8; 32]);
+        let err = Parser::unmarshal(&frame[..frame.len() - 1], &PlainBlock::new()).unwrap_err();
+        assert!(matches!(err, FrameError::TooShort));
+
+        let mut bad = frame.clone();
+        bad[0] = 0;
+        let err = Parser::unmarshal(&bad, &PlainBlock::new()).unwrap_err();
+        assert!(matches!(err, FrameError::Invalid));
+    }
+
+    #[test]
+    fn test_version_range() {
+        assert_eq!(negotiate_version(MAX_VERSION, MAX_VERSION), MAX_VERSION);
+        assert_eq!(negotiate_version(MAX_VERSION, MIN_VERSION), MIN_VERSION);
+        assert_eq!(negotiate_version(MIN_VERSION, MAX_VERSION), MIN_VERSION);
+        assert_eq!(negotiate_version(MAX_VERSION, 0), MIN_VERSION);
+
+        let frame = Frame::Data(DataFrame {
+            payload: vec![1, 2, 3],
+            seq: None,
+        });
+        for version in MIN_VERSION..=MAX_VERSION {
+            let buf = Parser::marshal_version(frame.clone(), &PlainBlock::new(), version).unwrap();
+            assert_eq!(buf[4], version);
+            assert!(matches!(
+                Parser::unmarshal(&buf, &PlainBlock::new()).unwrap().0,
+                Frame::Data(_)
+            ));
+        }
+        let buf = Parser::marshal_version(frame, &PlainBlock::new(), MAX_VERSION + 1).unwrap();
+        assert!(matches!(
+            Parser::unmarshal(&buf, &PlainBlock::new()),
+            Err(FrameError::Invalid)
+        ));
+    }
+
+    #[test]
+    fn test_jumbo_payload_needs_wide_len() {
+        let block = PlainBlock::new();
+        let jumbo = Frame::Data(DataFrame {
+            payload: vec![0x45; 200 * 1024],
+            seq: None,
+        });
+
+        let buf = Parser::marshal_version(jumbo.clone(), &block, WIDE_LEN_VERSION).unwrap();
+        assert_eq!(buf.len(), HDR_LEN_V2 + 200 * 1024);
+        assert_eq!(Parser::declared_len(&buf, DEFAULT_MAGIC), Some(buf.len()));
+        let (frame, len) = Parser::unmarshal(&buf, &block).unwrap();
+        assert_eq!(len, buf.len());
+        assert!(matches!(frame, Frame::Data(frame) if frame.payload.len() == 200 * 1024));
+        // the wide length is only known once the whole header is in
+        assert!(matches!(
+            Parser::unmarshal(&buf[..HDR_LEN], &block),
+            Err(FrameError::TooShort)
+        ));
+
+        // the short header of versions 1 and 2 cannot describe it
+        for version in [MIN_VERSION, WIDE_LEN_VERSION - 1] {
+            let e = Parser::marshal_version(jumbo.clone(), &block, version).unwrap_err();
+            assert!(e.to_string().starts_with("packet too large for MTU"));
+        }
+    }
+
+    #[test]
+    fn test_v1_frames_keep_short_header() {
+        let block = PlainBlock::new();
+        let buf = data_frame(&[1, 2, 3]);
+        assert_eq!(buf[4], MIN_VERSION);
+        assert_eq!(buf.len(), HDR_LEN + 3);
+        assert_eq!(&buf[6..8], &3u16.to_be_bytes());
+        // version 2 shipped with the version 1 header
+        let v2 = Parser::marshal_version(
+            Frame::Data(DataFrame {
+                payload: vec![1, 2, 3],
+                seq: None,
+            }),
+            &block,
+            2,
+        )
+        .unwrap();
+        assert_eq!(v2.len(), HDR_LEN + 3);
+
+        // short and wide headers interleave on one stream
+        let mut stream = buf.clone();
+        stream.extend(
+            Parser::marshal_version(
+                Frame::Data(DataFrame {
+                    payload: vec![4],
+                    seq: None,
+                }),
+                &block,
+                WIDE_LEN_VERSION,
+            )
+            .unwrap(),
+        );
+        stream.extend_from_slice(&buf);
+        let mut payloads = Vec::new();
+        let mut rest = stream.as_slice();
+        while !rest.is_empty() {
+            let (frame, len) = Parser::unmarshal(rest, &block).unwrap();
+            if let Frame::Data(data) = frame {
+                payloads.push(data.payload);
+            }
+            rest = &rest[len..];
+        }
+        assert_eq!(payloads, vec![vec![1, 2, 3], vec![4], vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn test_seq_carried_from_v3() {
+        let block = PlainBlock::new();
+        let frame = || {
+            Frame::Data(DataFrame {
+                payload: vec![1, 2, 3],
+                seq: Some(7),
+            })
+        };
+        let buf = Parser::marshal_version(frame(), &block, SEQ_VERSION).unwrap();
+        assert_eq!(buf[5], FrameType::SeqData as u8);
+        match Parser::unmarshal(&buf, &block).unwrap().0 {
+            Frame::Data(data) => {
+                assert_eq!(data.payload, vec![1, 2, 3]);
+                assert_eq!(data.seq, Some(7));
+            }
+            frame => panic!("unexpected frame {frame}"),
+        }
+
+        // older peers get a plain data frame
+        let buf = Parser::marshal_version(frame(), &block, 2).unwrap();
+        assert_eq!(buf[5], FrameType::Data as u8);
+        match Parser::unmarshal(&buf, &block).unwrap().0 {
+            Frame::Data(data) => assert_eq!(data.seq, None),
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    #[test]
+    fn test_handshake_without_version_is_v1() {
+        let hs: HandshakeFrame = serde_json::from_str(r#"{"identity":"a","token":null}"#).unwrap();
+        assert_eq!(hs.max_version, MIN_VERSION);
+        let reply: HandshakeReplyFrame = serde_json::from_str(
+            r#"{"name":"a","private_ip":"10.0.0.1","mask":"255.255.255.0","gateway":"10.0.0.254","ciders":[],"cider_mapping":{},"peer_details":[]}"#,
+        )
+        .unwrap();
+        assert_eq!(reply.version, MIN_VERSION);
+    }
+}
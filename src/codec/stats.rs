@@ -0,0 +1,152 @@
+//! Frame counters by frame type
+//!
+//! A `FrameStats` handed to the parser counts every frame it marshals or
+//! unmarshals, keyed by the type byte of the header. Stats of a connection
+//! can roll up into a parent, e.g. server-wide totals.
+
+use crate::codec::frame::FrameType;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// One slot per possible type byte below this, the types in use all are
+const TYPE_SLOTS: usize = 32;
+
+/// Frames sent and received of one type
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameCounts {
+    pub sent: u64,
+    pub received: u64,
+}
+
+/// Frames counted by type, shared by the connection and its observers
+#[derive(Debug, Default)]
+pub struct FrameStats {
+    sent: [AtomicU64; TYPE_SLOTS],
+    received: [AtomicU64; TYPE_SLOTS],
+    /// Also counts every frame counted here
+    parent: Option<Arc<FrameStats>>,
+}
+
+impl FrameStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stats also counting into `parent`
+    pub fn with_parent(parent: Arc<FrameStats>) -> Self {
+        Self {
+            parent: Some(parent),
+            ..Self::default()
+        }
+    }
+
+    /// Count a marshaled frame of type byte `frame_type`
+    pub(crate) fn record_sent(&self, frame_type: u8) {
+        if let Some(slot) = self.sent.get(frame_type as usize) {
+            slot.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(parent) = &self.parent {
+            parent.record_sent(frame_type);
+        }
+    }
+
+    /// Count an unmarshaled frame of type byte `frame_type`
+    pub(crate) fn record_received(&self, frame_type: u8) {
+        if let Some(slot) = self.received.get(frame_type as usize) {
+            slot.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(parent) = &self.parent {
+            parent.record_received(frame_type);
+        }
+    }
+
+    /// Counts of the frame types seen so far, by type name
+    pub fn snapshot(&self) -> BTreeMap<&'static str, FrameCounts> {
+        (0..TYPE_SLOTS)
+            .filter_map(|slot| {
+                let counts = FrameCounts {
+                    sent: self.sent[slot].load(Ordering::Relaxed),
+                    received: self.received[slot].load(Ordering::Relaxed),
+                };
+                if counts == FrameCounts::default() {
+                    return None;
+                }
+                let name = FrameType::try_from(slot as u8).ok()?.name();
+                Some((name, counts))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::frame::tests as frame_tests;
+    use crate::codec::frame::{DataFrame, EchoFrame, Frame};
+    use crate::codec::magic::DEFAULT_MAGIC;
+    use crate::codec::parser::{MIN_VERSION, Parser};
+    use crate::crypto::plain::PlainBlock;
+
+    fn keepalive() -> Frame {
+        Frame::KeepAlive(frame_tests::keepalive("a"))
+    }
+
+    #[test]
+    fn test_counts_by_frame_type() {
+        let total = Arc::new(FrameStats::new());
+        let stats = FrameStats::with_parent(total.clone());
+        let block = PlainBlock::new();
+
+        let frames = [
+            keepalive(),
+            Frame::Data(DataFrame {
+                payload: vec![0x45; 20],
+                seq: None,
+            }),
+            keepalive(),
+            Frame::Echo(EchoFrame {
+                id: 1,
+                src: "a".to_string(),
+                dst: "b".to_string(),
+                sent_at: 0,
+            }),
+            keepalive(),
+        ];
+        let mut wire = Vec::new();
+        for frame in frames {
+            wire.extend(
+                Parser::marshal_counted(
+                    frame,
+                    &block,
+                    MIN_VERSION,
+                    None,
+                    DEFAULT_MAGIC,
+                    Some(&stats),
+                )
+                .unwrap(),
+            );
+        }
+        let mut buf = wire.as_slice();
+        while !buf.is_empty() {
+            let (_, len) =
+                Parser::unmarshal_counted(buf, &block, DEFAULT_MAGIC, Some(&stats)).unwrap();
+            buf = &buf[len..];
+        }
+        // uncounted parsing leaves the stats alone
+        Parser::unmarshal(&wire, &block).unwrap();
+
+        let counts = |n| FrameCounts {
+            sent: n,
+            received: n,
+        };
+        let expected = BTreeMap::from([
+            ("data", counts(1)),
+            ("echo", counts(1)),
+            ("keepalive", counts(3)),
+        ]);
+        assert_eq!(stats.snapshot(), expected);
+        assert_eq!(total.snapshot(), expected);
+    }
+}
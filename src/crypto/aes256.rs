@@ -122,6 +122,11 @@ impl Block for Aes256Block {
 
         Ok(())
     }
+
+    /// 12 byte nonce and 16 byte tag
+    fn overhead(&self) -> usize {
+        28
+    }
 }
 
 #[cfg(test)]
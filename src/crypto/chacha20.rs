@@ -123,6 +123,11 @@ impl Block for ChaCha20Poly1305Block {
 
         Ok(())
     }
+
+    /// 12 byte nonce and 16 byte tag
+    fn overhead(&self) -> usize {
+        28
+    }
 }
 
 #[cfg(test)]
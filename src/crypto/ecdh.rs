@@ -0,0 +1,134 @@
+//! X25519 key agreement for P2P session keys
+//!
+//! Two peers exchange ephemeral public keys (sealed by the relay key) and
+//! derive the same ChaCha20-Poly1305 key for their direct path. A fresh key
+//! pair per exchange keeps earlier P2P traffic safe if the relay key leaks.
+//! Relay connections rekey the same way, see `rekey`.
+
+use crate::crypto::chacha20::ChaCha20Poly1305Block;
+use chacha20poly1305::aead::{OsRng, rand_core::RngCore};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use sha2::{Digest, Sha256};
+
+/// Domain separation for the session key derivation
+const KDF_LABEL: &[u8] = b"rustun-p2p-session-v1";
+/// Domain separation for relay connection keys
+const REKEY_LABEL: &[u8] = b"rustun-relay-rekey-v1";
+
+/// Ephemeral X25519 key pair, used for a single exchange
+pub struct KeyPair {
+    secret: [u8; 32],
+    /// Sent to the peer
+    pub public: [u8; 32],
+}
+
+impl std::fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+impl KeyPair {
+    /// Generates a random key pair
+    pub fn generate() -> Self {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        Self::from_secret(secret)
+    }
+
+    fn from_secret(secret: [u8; 32]) -> Self {
+        let public = MontgomeryPoint::mul_base_clamped(secret).to_bytes();
+        Self { secret, public }
+    }
+
+    /// Derives the session cipher shared with the owner of `peer_public`
+    ///
+    /// Both sides get the same key whichever of them is `identity`: the
+    /// identities are hashed in sorted order.
+    ///
+    /// # Arguments
+    /// * `peer_public` - Public key received from the peer
+    /// * `identity` - Our identity
+    /// * `peer_identity` - The peer's identity
+    ///
+    /// # Returns
+    /// * `Err` if the peer sent a low order point, which gives no secret
+    pub fn derive(
+        &self,
+        peer_public: &[u8; 32],
+        identity: &str,
+        peer_identity: &str,
+    ) -> anyhow::Result<ChaCha20Poly1305Block> {
+        let (first, second) = if identity < peer_identity {
+            (identity, peer_identity)
+        } else {
+            (peer_identity, identity)
+        };
+        self.derive_labeled(KDF_LABEL, peer_public, [first, second])
+            .ok_or_else(|| anyhow::anyhow!("peer {peer_identity} sent an invalid public key"))
+    }
+
+    /// Derives the next key of the relay connection of client `identity`
+    ///
+    /// # Returns
+    /// * `Err` if the other side sent a low order point
+    pub fn derive_rekey(
+        &self,
+        peer_public: &[u8; 32],
+        identity: &str,
+    ) -> anyhow::Result<ChaCha20Poly1305Block> {
+        self.derive_labeled(REKEY_LABEL, peer_public, [identity])
+            .ok_or_else(|| anyhow::anyhow!("rekey of {identity} sent an invalid public key"))
+    }
+
+    fn derive_labeled<const N: usize>(
+        &self,
+        label: &[u8],
+        peer_public: &[u8; 32],
+        ids: [&str; N],
+    ) -> Option<ChaCha20Poly1305Block> {
+        let shared = MontgomeryPoint(*peer_public).mul_clamped(self.secret);
+        if shared.to_bytes() == [0u8; 32] {
+            return None;
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(label);
+        hasher.update(shared.as_bytes());
+        for id in ids {
+            hasher.update((id.len() as u32).to_be_bytes());
+            hasher.update(id.as_bytes());
+        }
+        Some(ChaCha20Poly1305Block::new(&hasher.finalize().into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Block;
+
+    #[test]
+    fn test_both_sides_derive_the_same_key() {
+        let (a, b) = (KeyPair::generate(), KeyPair::generate());
+        let key_a = a.derive(&b.public, "a", "b").unwrap();
+        let key_b = b.derive(&a.public, "b", "a").unwrap();
+
+        let mut data = b"direct path".to_vec();
+        key_a.encrypt(&mut data).unwrap();
+        key_b.decrypt(&mut data).unwrap();
+        assert_eq!(data, b"direct path");
+
+        // another exchange gives another key
+        let key_c = KeyPair::generate().derive(&b.public, "a", "b").unwrap();
+        key_a.encrypt(&mut data).unwrap();
+        assert!(key_c.decrypt(&mut data).is_err());
+    }
+
+    #[test]
+    fn test_low_order_public_key_rejected() {
+        let pair = KeyPair::from_secret([7; 32]);
+        assert!(pair.derive(&[0; 32], "a", "b").is_err());
+    }
+}
@@ -5,10 +5,15 @@
 //! - ChaCha20-Poly1305: Modern AEAD cipher (fast, secure)
 //! - XOR: Simple stream cipher for lightweight encryption
 //! - Plain: No encryption (passthrough mode)
+//!
+//! `ecdh` derives per-peer P2P session keys on top of them, `rekey`
+//! rotates the key of a relay connection.
 
 pub mod aes256;
 pub mod chacha20;
+pub mod ecdh;
 pub mod plain;
+pub mod rekey;
 pub mod xor;
 
 use crate::crypto::aes256::Aes256Block;
@@ -16,6 +21,7 @@ use crate::crypto::chacha20::ChaCha20Poly1305Block;
 use crate::crypto::plain::PlainBlock;
 use crate::crypto::xor::XorBlock;
 use serde::{Deserialize, Serialize};
+use std::time::Instant;
 
 /// Core encryption/decryption trait
 ///
@@ -42,6 +48,48 @@ pub trait Block: Send + Sync {
     /// * `Ok(())` on success
     /// * `Err` if decryption fails
     fn decrypt(&self, data: &mut Vec<u8>) -> anyhow::Result<()>;
+
+    /// Bytes `encrypt` adds to a payload
+    fn overhead(&self) -> usize {
+        0
+    }
+
+    /// Measures encrypt/decrypt throughput of this cipher
+    ///
+    /// See [`throughput`].
+    fn benchmark(&self, size: usize, iters: usize) -> f64 {
+        measure(self, size, iters)
+    }
+}
+
+/// Measures encrypt/decrypt throughput of a cipher block
+///
+/// Encrypts and decrypts a `size` byte payload `iters` times, which lets
+/// embedders probe the ciphers at runtime and pick the fastest one.
+///
+/// # Arguments
+/// * `block` - Cipher block to measure
+/// * `size` - Payload size in bytes
+/// * `iters` - Number of encrypt/decrypt round trips
+///
+/// # Returns
+/// * Payload bytes per second, or 0.0 if the cipher fails
+pub fn throughput(block: &dyn Block, size: usize, iters: usize) -> f64 {
+    measure(block, size, iters)
+}
+
+fn measure<B: Block + ?Sized>(block: &B, size: usize, iters: usize) -> f64 {
+    let payload = vec![0x5a; size];
+    let start = Instant::now();
+    for _ in 0..iters {
+        let mut data = payload.clone();
+        if block.encrypt(&mut data).is_err() || block.decrypt(&mut data).is_err() {
+            return 0.0;
+        }
+        std::hint::black_box(&data);
+    }
+    let elapsed = start.elapsed().as_secs_f64().max(f64::MIN_POSITIVE);
+    (size * iters) as f64 / elapsed
 }
 
 /// Factory function to create cipher blocks from configuration
@@ -100,6 +148,18 @@ pub enum CryptoConfig {
     Xor(String),
 }
 
+impl CryptoConfig {
+    /// Key of the cipher, `None` for `Plain`
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            CryptoConfig::Aes256(key)
+            | CryptoConfig::ChaCha20Poly1305(key)
+            | CryptoConfig::Xor(key) => Some(key),
+            CryptoConfig::Plain => None,
+        }
+    }
+}
+
 pub fn parse_crypto_config(crypto_str: &str) -> anyhow::Result<CryptoConfig> {
     let parts: Vec<&str> = crypto_str.splitn(2, ':').collect();
 
@@ -129,3 +189,23 @@ pub fn parse_crypto_config(crypto_str: &str) -> anyhow::Result<CryptoConfig> {
         ),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throughput_positive_for_each_cipher() {
+        let configs = [
+            CryptoConfig::Aes256("rustun".to_string()),
+            CryptoConfig::ChaCha20Poly1305("rustun".to_string()),
+            CryptoConfig::Xor("rustun".to_string()),
+            CryptoConfig::Plain,
+        ];
+        for cfg in &configs {
+            let block = new_block(cfg);
+            assert!(throughput(block.as_ref(), 1400, 16) > 0.0, "{cfg:?}");
+        }
+        assert!(PlainBlock::new().benchmark(64, 16) > 0.0);
+    }
+}
@@ -0,0 +1,241 @@
+//! Key rotation of relay connections
+//!
+//! After `RekeyPolicy` frames or time under one key, the client starts an
+//! X25519 exchange sealed with the current key. The server answers and
+//! switches right away, the client switches when the answer arrives. Both
+//! end up with the same fresh ChaCha20-Poly1305 key, unrelated to the old
+//! one.
+//!
+//! Frames the client wrote before it read the answer still use the old
+//! key, so the connection keeps reading with it until the first frame under
+//! the new key arrives (see `RekeyedBlock`).
+
+use crate::crypto::Block;
+use crate::crypto::ecdh::KeyPair;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Public key of an X25519 exchange
+type PublicKey = [u8; 32];
+
+/// When a relay connection switches to a fresh key, never if both unset
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RekeyPolicy {
+    /// Frames written and read under one key
+    pub max_frames: Option<u64>,
+    /// Longest a key is used
+    pub max_age: Option<Duration>,
+}
+
+impl RekeyPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.max_frames.is_some() || self.max_age.is_some()
+    }
+}
+
+/// Connection block of a switched key, still reading the old one
+///
+/// Writes use the new key. Reads try it first and fall back to the old key
+/// until a frame decrypts with the new one: the peer writes in order, once
+/// it switched no old frame follows.
+pub struct RekeyedBlock {
+    key: Arc<Box<dyn Block>>,
+    old: Arc<Box<dyn Block>>,
+    /// Whether the peer is known to have switched
+    switched: AtomicBool,
+}
+
+impl RekeyedBlock {
+    pub fn new(key: Arc<Box<dyn Block>>, old: Arc<Box<dyn Block>>) -> Self {
+        Self {
+            key,
+            old,
+            switched: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Block for RekeyedBlock {
+    fn encrypt(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        self.key.encrypt(data)
+    }
+
+    fn decrypt(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        if self.switched.load(Ordering::Relaxed) {
+            return self.key.decrypt(data);
+        }
+        let mut attempt = data.clone();
+        if self.key.decrypt(&mut attempt).is_ok() {
+            self.switched.store(true, Ordering::Relaxed);
+            *data = attempt;
+            return Ok(());
+        }
+        self.old.decrypt(data)
+    }
+
+    fn overhead(&self) -> usize {
+        self.key.overhead().max(self.old.overhead())
+    }
+}
+
+/// Key rotation state of one relay connection
+pub struct Rekeyer {
+    policy: RekeyPolicy,
+    /// Key in use, without the fallback of `RekeyedBlock`
+    key: Arc<Box<dyn Block>>,
+    /// Frames since the last switch
+    frames: u64,
+    since: Instant,
+    /// Our half of the exchange we started, until answered
+    pending: Option<KeyPair>,
+}
+
+impl Rekeyer {
+    /// Rotation of a connection currently using `key`
+    pub fn new(policy: RekeyPolicy, key: Arc<Box<dyn Block>>) -> Self {
+        Self {
+            policy,
+            key,
+            frames: 0,
+            since: Instant::now(),
+            pending: None,
+        }
+    }
+
+    /// Count frames written or read under the current key
+    pub fn count(&mut self, frames: usize) {
+        self.frames += frames as u64;
+    }
+
+    /// Whether an exchange should start now
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.pending.is_none()
+            && (self.policy.max_frames.is_some_and(|max| self.frames >= max)
+                || self.deadline().is_some_and(|deadline| deadline <= now))
+    }
+
+    /// When the current key gets too old
+    pub fn deadline(&self) -> Option<Instant> {
+        self.policy.max_age.map(|age| self.since + age)
+    }
+
+    /// Start an exchange, returns the public key to send
+    pub fn start(&mut self) -> PublicKey {
+        let pair = KeyPair::generate();
+        let public = pair.public;
+        self.pending = Some(pair);
+        public
+    }
+
+    /// Complete our exchange with the answer of the server
+    ///
+    /// # Returns
+    /// * The block to read and write the following frames with
+    /// * `Err` if no exchange was started or the answer is invalid
+    pub fn finish(
+        &mut self,
+        peer_public: &[u8; 32],
+        identity: &str,
+    ) -> anyhow::Result<Arc<Box<dyn Block>>> {
+        let pair = self
+            .pending
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("rekey answer without a request"))?;
+        let key = pair.derive_rekey(peer_public, identity)?;
+        Ok(self.switch(key))
+    }
+
+    /// Answer the exchange started by client `identity`
+    ///
+    /// # Returns
+    /// * The public key to send back, sealed with the current key
+    /// * The block to read and write the frames after the answer with
+    pub fn answer(
+        &mut self,
+        peer_public: &[u8; 32],
+        identity: &str,
+    ) -> anyhow::Result<(PublicKey, Arc<Box<dyn Block>>)> {
+        let pair = KeyPair::generate();
+        let key = pair.derive_rekey(peer_public, identity)?;
+        Ok((pair.public, self.switch(key)))
+    }
+
+    fn switch(&mut self, key: impl Block + 'static) -> Arc<Box<dyn Block>> {
+        let key: Arc<Box<dyn Block>> = Arc::new(Box::new(key));
+        let old = std::mem::replace(&mut self.key, key.clone());
+        self.frames = 0;
+        self.since = Instant::now();
+        Arc::new(Box::new(RekeyedBlock::new(key, old)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::chacha20::ChaCha20Poly1305Block;
+
+    fn sealed(block: &dyn Block, text: &[u8]) -> Vec<u8> {
+        let mut data = text.to_vec();
+        block.encrypt(&mut data).unwrap();
+        data
+    }
+
+    fn opened(block: &dyn Block, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        block.decrypt(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_rekey_after_frame_threshold() {
+        let initial: Arc<Box<dyn Block>> =
+            Arc::new(Box::new(ChaCha20Poly1305Block::from_string("relay")));
+        let policy = RekeyPolicy {
+            max_frames: Some(3),
+            max_age: None,
+        };
+        let mut client = Rekeyer::new(policy, initial.clone());
+        let mut server = Rekeyer::new(RekeyPolicy::default(), initial.clone());
+
+        client.count(2);
+        assert!(!client.is_due(Instant::now()));
+        client.count(1);
+        assert!(client.is_due(Instant::now()));
+
+        let request = client.start();
+        assert!(!client.is_due(Instant::now()));
+        let before = sealed(initial.as_ref().as_ref(), b"before");
+        let (answer, server_block) = server.answer(&request, "client-a").unwrap();
+        let client_block = client.finish(&answer, "client-a").unwrap();
+        assert!(!client.is_due(Instant::now()));
+
+        // written by the client before it read the answer
+        assert_eq!(
+            opened(server_block.as_ref().as_ref(), before).unwrap(),
+            b"before"
+        );
+        let after = sealed(client_block.as_ref().as_ref(), b"after");
+        assert!(opened(initial.as_ref().as_ref(), after.clone()).is_err());
+        assert_eq!(
+            opened(server_block.as_ref().as_ref(), after).unwrap(),
+            b"after"
+        );
+        // the old key is done with once the new one is in use
+        let late = sealed(initial.as_ref().as_ref(), b"late");
+        assert!(opened(server_block.as_ref().as_ref(), late).is_err());
+
+        let reply = sealed(server_block.as_ref().as_ref(), b"reply");
+        assert_eq!(
+            opened(client_block.as_ref().as_ref(), reply).unwrap(),
+            b"reply"
+        );
+    }
+
+    #[test]
+    fn test_answer_without_request_rejected() {
+        let key: Arc<Box<dyn Block>> = Arc::new(Box::new(ChaCha20Poly1305Block::new(&[1; 32])));
+        let mut client = Rekeyer::new(RekeyPolicy::default(), key);
+        assert!(!client.is_due(Instant::now()));
+        assert!(client.finish(&KeyPair::generate().public, "a").is_err());
+    }
+}
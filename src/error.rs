@@ -0,0 +1,107 @@
+//! Crate error type for the connection hot paths
+//!
+//! Most of the crate reports errors through `anyhow`. Connections, the
+//! server handler and the relay client return `RustunError` instead, so
+//! callers can act on the kind of failure (retry a timeout, give up on a
+//! rejected identity) without matching on messages. It converts into
+//! `anyhow::Error` with `?`, and `RustunError::from` recovers the kind from
+//! an `anyhow::Error` that wraps one of the sources below.
+
+use crate::codec::errors::FrameError;
+use std::time::Duration;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RustunError {
+    /// Socket I/O failed
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// An operation did not complete in time
+    #[error("{op} timed out after {after:?}")]
+    Timeout { op: &'static str, after: Duration },
+
+    /// The remote side closed the connection between frames
+    #[error("EOF")]
+    Closed,
+
+    /// The byte stream does not hold a valid frame
+    #[error(transparent)]
+    FrameParse(FrameError),
+
+    /// Encryption or decryption failed, usually a key mismatch
+    #[error("crypto error: {0}")]
+    Crypto(anyhow::Error),
+
+    /// The server rejected the identity or token
+    #[error("{0} unauthorized")]
+    Unauthorized(String),
+
+    /// The server will never accept the handshake as sent
+    #[error("handshake refused: {0}")]
+    Refused(String),
+
+    /// The server turned the handshake down for now, e.g. on an IP conflict
+    #[error("handshake rejected: {0}")]
+    Rejected(String),
+
+    /// An internal channel closed because the task behind it is gone
+    #[error("{0} channel closed")]
+    ChannelClosed(&'static str),
+
+    /// Any other failure
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<FrameError> for RustunError {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::DecryptionFailed(e) => RustunError::Crypto(e),
+            e => RustunError::FrameParse(e),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RustunError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<RustunError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<FrameError>() {
+            Ok(e) => return e.into(),
+            Err(e) => e,
+        };
+        match e.downcast::<std::io::Error>() {
+            Ok(e) => RustunError::Io(e),
+            Err(e) => RustunError::Other(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kind_survives_anyhow() {
+        let e: anyhow::Error = RustunError::Unauthorized("a".to_string()).into();
+        assert!(matches!(RustunError::from(e), RustunError::Unauthorized(id) if id == "a"));
+
+        let e: anyhow::Error = FrameError::TooLong.into();
+        assert!(matches!(
+            RustunError::from(e),
+            RustunError::FrameParse(FrameError::TooLong)
+        ));
+
+        let e: anyhow::Error = FrameError::DecryptionFailed(anyhow::anyhow!("bad tag")).into();
+        assert!(matches!(RustunError::from(e), RustunError::Crypto(_)));
+
+        let e: anyhow::Error = std::io::Error::from(std::io::ErrorKind::ConnectionReset).into();
+        assert!(matches!(RustunError::from(e), RustunError::Io(_)));
+
+        let e = RustunError::from(anyhow::anyhow!("something else"));
+        assert!(matches!(e, RustunError::Other(_)));
+        assert_eq!(e.to_string(), "something else");
+    }
+}
@@ -1,6 +1,7 @@
 pub mod client;
 pub mod codec;
 pub mod crypto;
+pub mod error;
 pub mod network;
 pub mod server;
 pub mod utils;
@@ -1,7 +1,14 @@
+use crate::codec::frame::{Frame, MacAddr, PeerLeaveFrame, TunnelMode, format_mac, is_group_mac};
+use crate::codec::parser::PEER_EVENTS_VERSION;
+use crate::codec::stats::{FrameCounts, FrameStats};
 use crate::network::{ConnectionMeta, StunAddr};
-use std::collections::HashMap;
-use std::sync::RwLock;
-use std::time::{SystemTime, UNIX_EPOCH};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// Get current Unix timestamp in seconds
 #[inline]
@@ -12,21 +19,309 @@ fn now_timestamp() -> u64 {
         .as_secs()
 }
 
+/// How long the answers of a global CIDR owner may follow a flow into
+/// the cluster that opened it
+const GLOBAL_FLOW_TTL: Duration = Duration::from_secs(300);
+/// Flows into global CIDRs tracked at once
+const MAX_GLOBAL_FLOWS: usize = 65536;
+
+/// MAC addresses learned behind one client at most, frames to any more
+/// are flooded
+const MAX_MACS_PER_CLIENT: usize = 256;
+/// A learned MAC not sending for this long is forgotten
+const MAC_AGE: Duration = Duration::from_secs(300);
+
+/// Identities whose registrations are counted at once, the one that
+/// registered least recently is forgotten for a new one
+const MAX_REGISTRATIONS: usize = 65536;
+
+/// Read-only view of a connection for operators
+///
+/// Same as `ConnectionMeta` without the outbound channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionSummary {
+    pub cluster: String,
+    pub identity: String,
+    pub private_ip: String,
+    pub ipv6: Vec<String>,
+    pub port: u16,
+    pub stun: Option<StunAddr>,
+    pub last_active: u64,
+    pub connected_at: u64,
+    pub reconnect_count: u64,
+    /// Frames dropped because the client's queue was full
+    pub tx_dropped: u64,
+    /// Frames exchanged with the client by type
+    pub frames: BTreeMap<&'static str, FrameCounts>,
+}
+
+impl From<&ConnectionMeta> for ConnectionSummary {
+    fn from(meta: &ConnectionMeta) -> Self {
+        Self {
+            cluster: meta.cluster.clone(),
+            identity: meta.identity.clone(),
+            private_ip: meta.private_ip.clone(),
+            ipv6: meta.ipv6.clone(),
+            port: meta.port,
+            stun: meta.stun.clone(),
+            last_active: meta.last_active,
+            connected_at: meta.connected_at,
+            reconnect_count: meta.reconnect_count,
+            tx_dropped: meta.tx_dropped.load(Ordering::Relaxed),
+            frames: meta.frame_stats.snapshot(),
+        }
+    }
+}
+
+/// How `get_connection` picks between clients routing the same prefix
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutePolicy {
+    /// The first registered client owns the route
+    #[default]
+    First,
+    /// The client with the most recent keepalive takes the route and keeps
+    /// it until it stops keepaliving, a recovered client does not take it
+    /// back
+    ActiveStandby,
+    /// Spread flows over the clients, the packets of a flow (addresses,
+    /// protocol and ports) all take the same client
+    RoundRobin,
+}
+
+/// Hash of the flow an IPv4 packet belongs to, its destination if it is none
+fn flow_hash(dst: &str, packet: &[u8]) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    if packet.len() >= 20 && packet[0] >> 4 == 4 {
+        let ihl = usize::from(packet[0] & 0x0f) * 4;
+        let protocol = packet[9];
+        // source, destination and protocol
+        packet[12..20].hash(&mut hasher);
+        protocol.hash(&mut hasher);
+        // TCP and UDP ports
+        if matches!(protocol, 6 | 17) && packet.len() >= ihl + 4 {
+            packet[ihl..ihl + 4].hash(&mut hasher);
+        }
+    } else {
+        dst.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Push a peer event to the other members of a cluster
+///
+/// A peer with a full queue catches up on its next keepalive, and so do
+/// peers whose version predates these events.
+pub(crate) fn notify_peers(others: Vec<ConnectionMeta>, frame: Frame) {
+    for other in others {
+        if matches!(frame, Frame::PeerJoin(_) | Frame::PeerLeave(_))
+            && other.version < PEER_EVENTS_VERSION
+        {
+            continue;
+        }
+        if let Err(e) = other.sender_for(&frame).try_send(frame.clone()) {
+            tracing::debug!("{} to {} dropped: {e}", frame.type_name(), other.identity);
+        }
+    }
+}
+
+/// Where a MAC address was last seen
+struct MacEntry {
+    /// Client sending from it
+    identity: String,
+    seen: Instant,
+}
+
+impl MacEntry {
+    fn is_fresh(&self) -> bool {
+        self.seen.elapsed() < MAC_AGE
+    }
+}
+
+/// Frames held for a client that dropped its connection
+struct OfflineQueue {
+    /// Last known connection, used to match destinations
+    meta: ConnectionMeta,
+    /// Undelivered frames, oldest first
+    frames: VecDeque<Frame>,
+    /// Buffered frames are discarded after this instant
+    expires_at: Instant,
+}
+
 pub struct ConnectionManager {
     /// Cluster-based connections map (tenant isolation)
     /// key: cluster name -> value: connections in this cluster
     cluster_connections: RwLock<HashMap<String, Vec<ConnectionMeta>>>,
+    /// Frames for clients in their reconnect grace window
+    /// key: (cluster, identity)
+    offline_queues: RwLock<HashMap<(String, String), OfflineQueue>>,
+    /// Maximum frames buffered per offline client, 0 disables buffering
+    offline_buffer_size: usize,
+    /// How long frames are kept for an offline client
+    offline_buffer_ttl: Duration,
+    /// Connections silent for longer get no routed traffic, zero disables
+    peer_ttl: Duration,
+    /// Selection between connections matching a destination equally
+    route_policy: RoutePolicy,
+    /// Client holding each prefix under `RoutePolicy::ActiveStandby`
+    /// key: (cluster, prefix) -> value: identity
+    active_routes: RwLock<HashMap<(String, String), String>>,
+    /// Peer list version of each cluster with the fingerprint it was issued for
+    peers_versions: RwLock<HashMap<String, (u64, u64)>>,
+    /// Tunneled packets dropped for failing IP validation
+    invalid_packets: AtomicU64,
+    /// Frames exchanged with all clients by type, parent of the stats of
+    /// each connection
+    frame_stats: Arc<FrameStats>,
+    /// Times each (cluster, identity) registered and when it last did,
+    /// kept across disconnects
+    registrations: RwLock<HashMap<(String, String), (u64, Instant)>>,
+    /// Most identities in `registrations`
+    max_registrations: usize,
+    /// CIDRs reachable from every cluster
+    global_cidrs: Vec<IpNet>,
+    /// Clusters that sent to a global address, so its owner's answers go
+    /// back to them only
+    /// key: (global address, sender address) -> value: (cluster, last sent)
+    global_flows: RwLock<HashMap<(String, String), (String, Instant)>>,
+    /// MAC addresses learned from TAP clients
+    /// key: cluster name -> value: MAC -> client sending from it
+    mac_tables: RwLock<HashMap<String, HashMap<MacAddr, MacEntry>>>,
 }
 
 impl ConnectionManager {
     pub fn new() -> Self {
         Self {
             cluster_connections: RwLock::new(HashMap::new()),
+            offline_queues: RwLock::new(HashMap::new()),
+            offline_buffer_size: 0,
+            offline_buffer_ttl: Duration::ZERO,
+            peer_ttl: Duration::ZERO,
+            route_policy: RoutePolicy::First,
+            active_routes: RwLock::new(HashMap::new()),
+            peers_versions: RwLock::new(HashMap::new()),
+            invalid_packets: AtomicU64::new(0),
+            frame_stats: Arc::new(FrameStats::new()),
+            registrations: RwLock::new(HashMap::new()),
+            max_registrations: MAX_REGISTRATIONS,
+            global_cidrs: Vec::new(),
+            global_flows: RwLock::new(HashMap::new()),
+            mac_tables: RwLock::new(HashMap::new()),
         }
     }
 
-    pub fn add_connection(&self, meta: ConnectionMeta) {
+    /// Buffer frames for clients that briefly go offline
+    ///
+    /// Up to `size` of the most recent undelivered frames are kept per
+    /// client for `ttl` and flushed when the same identity reconnects.
+    ///
+    /// # Arguments
+    /// - `size` - Frames kept per client, 0 disables buffering
+    /// - `ttl` - Grace window after which buffered frames are dropped
+    pub fn with_offline_buffer(mut self, size: usize, ttl: Duration) -> Self {
+        self.offline_buffer_size = size;
+        self.offline_buffer_ttl = ttl;
+        self
+    }
+
+    /// Stop routing to clients that stopped keepaliving
+    ///
+    /// A connection whose last keepalive is older than `ttl` is skipped by
+    /// `get_connection` even if its TCP session lingers. Zero disables the
+    /// check.
+    pub fn with_peer_ttl(mut self, ttl: Duration) -> Self {
+        self.peer_ttl = ttl;
+        self
+    }
+
+    /// Choose how clients advertising the same prefix share traffic
+    pub fn with_route_policy(mut self, policy: RoutePolicy) -> Self {
+        self.route_policy = policy;
+        self
+    }
+
+    /// Share `cidrs` with all clusters
+    ///
+    /// Destinations in these CIDRs are routed to their owner whatever its
+    /// cluster, see `get_global_connection`.
+    pub fn with_global_cidrs(mut self, cidrs: Vec<IpNet>) -> Self {
+        self.global_cidrs = cidrs;
+        self
+    }
+
+    /// Check whether `ip` is in a CIDR shared by all clusters
+    pub fn is_global(&self, ip: &str) -> bool {
+        ip.parse::<IpAddr>()
+            .is_ok_and(|ip| self.global_cidrs.iter().any(|cidr| cidr.contains(&ip)))
+    }
+
+    /// Whether `meta` kept alive within the peer TTL
+    pub fn is_live(&self, meta: &ConnectionMeta) -> bool {
+        self.peer_ttl.is_zero()
+            || now_timestamp().saturating_sub(meta.last_active) <= self.peer_ttl.as_secs()
+    }
+
+    /// Register a connection after its handshake
+    ///
+    /// Refused while another online client of the cluster uses the same
+    /// private IP, checked under the lock the connection is added with so
+    /// two handshakes cannot both claim it. `identity` reconnecting does
+    /// not conflict with its old connection.
+    ///
+    /// # Returns
+    /// - `Ok(others)` - Other connections in the cluster, to be told about
+    ///   the new peer
+    /// - `Err(owner)` - Identity of the client using the private IP
+    pub fn add_connection(&self, mut meta: ConnectionMeta) -> Result<Vec<ConnectionMeta>, String> {
         let cluster = meta.cluster.clone();
+        // buffer_frame locks offline_queues before cluster_connections
+        let mut queues = self
+            .offline_queues
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut cluster_map = self
+            .cluster_connections
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        let connections = cluster_map.entry(cluster.clone()).or_default();
+        if let Some(owner) = connections.iter().find(|conn| {
+            conn.identity != meta.identity
+                && conn.private_ip == meta.private_ip
+                && !conn.outbound_tx.is_closed()
+                && self.is_live(conn)
+        }) {
+            return Err(owner.identity.clone());
+        }
+
+        meta.connected_at = now_timestamp();
+        meta.reconnect_count = {
+            let mut registrations = self
+                .registrations
+                .write()
+                .unwrap_or_else(|e| e.into_inner());
+            let key = (cluster.clone(), meta.identity.clone());
+            if registrations.len() >= self.max_registrations
+                && !registrations.contains_key(&key)
+                && let Some(oldest) = registrations
+                    .iter()
+                    .min_by_key(|(_, (_, at))| *at)
+                    .map(|(key, _)| key.clone())
+            {
+                registrations.remove(&oldest);
+            }
+            let (count, at) = registrations.entry(key).or_insert((0, Instant::now()));
+            *count += 1;
+            *at = Instant::now();
+            *count - 1
+        };
+        if meta.reconnect_count > 0 {
+            tracing::debug!(
+                "{} reconnected {} times",
+                meta.identity,
+                meta.reconnect_count
+            );
+        }
 
         tracing::debug!(
             "Add connection: cluster={}, identity={}",
@@ -34,25 +329,107 @@ impl ConnectionManager {
             meta.cluster
         );
 
-        self.cluster_connections
+        let queued = queues
+            .remove(&(cluster, meta.identity.clone()))
+            .filter(|queue| queue.expires_at > Instant::now());
+        if let Some(queue) = queued {
+            tracing::debug!(
+                "flush {} buffered frames to {}",
+                queue.frames.len(),
+                meta.identity
+            );
+            for frame in queue.frames {
+                if let Err(e) = meta.outbound_tx.try_send(frame) {
+                    tracing::warn!("flush buffered frame to {} failed: {e}", meta.identity);
+                }
+            }
+        }
+
+        let others = connections
+            .iter()
+            .filter(|c| c.identity != meta.identity)
+            .cloned()
+            .collect();
+        connections.push(meta);
+        Ok(others)
+    }
+
+    /// Buffer a frame for a client that just went offline
+    ///
+    /// Matches `dst` against clients in their grace window, or against a
+    /// live connection whose channel just closed.
+    ///
+    /// # Returns
+    /// - `true` - Frame was buffered for delivery on reconnect
+    /// - `false` - Buffering is disabled or no client matches `dst`
+    pub fn buffer_frame(&self, cluster: &str, dst: &str, frame: Frame) -> bool {
+        if self.offline_buffer_size == 0 {
+            return false;
+        }
+
+        let now = Instant::now();
+        let mut queues = self
+            .offline_queues
             .write()
-            .unwrap_or_else(|e| e.into_inner())
-            .entry(cluster)
-            .or_default()
-            .push(meta);
+            .unwrap_or_else(|e| e.into_inner());
+        queues.retain(|_, queue| queue.expires_at > now);
+
+        let key = queues
+            .iter()
+            .find(|((c, _), queue)| c == cluster && queue.meta.match_dst(dst.to_string()))
+            .map(|(key, _)| key.clone());
+        let key = match key {
+            Some(key) => key,
+            None => {
+                let Some(meta) = self.get_connection(cluster, dst) else {
+                    return false;
+                };
+                let key = (meta.cluster.clone(), meta.identity.clone());
+                queues.insert(
+                    key.clone(),
+                    OfflineQueue {
+                        meta,
+                        frames: VecDeque::new(),
+                        expires_at: now + self.offline_buffer_ttl,
+                    },
+                );
+                key
+            }
+        };
+
+        let Some(queue) = queues.get_mut(&key) else {
+            return false;
+        };
+        if queue.frames.len() >= self.offline_buffer_size {
+            queue.frames.pop_front();
+        }
+        queue.frames.push_back(frame);
+        true
     }
 
-    pub fn del_connection(&self, identity: String) {
+    /// Unregister a connection of `identity`
+    ///
+    /// # Returns
+    /// * `Some(Vec<ConnectionMeta>)` - Other connections in the cluster if this was the identity's last connection
+    /// * `None` - If a newer connection of the identity remains or none was found
+    pub fn del_connection(&self, identity: String) -> Option<Vec<ConnectionMeta>> {
         let mut cluster_map = self
             .cluster_connections
             .write()
             .unwrap_or_else(|e| e.into_inner());
 
         let mut cluster_to_remove = None;
+        let mut removed = None;
+        let mut others = None;
 
         for (cluster, connections) in cluster_map.iter_mut() {
             if let Some(pos) = connections.iter().position(|c| c.identity == identity) {
-                connections.remove(pos);
+                let meta = connections.remove(pos);
+                // a reconnect may register before the old handler exits
+                if !connections.iter().any(|c| c.identity == identity) {
+                    removed = Some(meta);
+                    others = Some(connections.clone());
+                }
                 tracing::debug!(
                     "Removed connection: cluster={}, identity={}",
                     cluster,
@@ -69,19 +446,363 @@ impl ConnectionManager {
         if let Some(cluster) = cluster_to_remove {
             cluster_map.remove(&cluster);
         }
+        // buffer_frame locks offline_queues before cluster_connections
+        drop(cluster_map);
+
+        if let Some(meta) = removed {
+            self.forget_macs(&meta.cluster, &identity);
+            if self.offline_buffer_size > 0 {
+                self.start_grace_window(meta);
+            }
+        }
+        others
+    }
+
+    /// Forcibly disconnect every connection of `identity`
+    ///
+    /// Drops the registered outbound channels, which ends their handlers,
+    /// discards frames buffered for the identity and tells the remaining
+    /// members of its clusters it left. Unlike `del_connection` no grace
+    /// window is started.
+    ///
+    /// # Returns
+    /// Number of connections removed
+    pub fn disconnect(&self, identity: &str) -> usize {
+        self.disconnect_from(None, identity)
+    }
+
+    /// Forcibly disconnect `identity` from `cluster`
+    ///
+    /// Same as `disconnect`, limited to one cluster.
+    ///
+    /// # Returns
+    /// Whether a connection was closed
+    pub fn kick(&self, cluster: &str, identity: &str) -> bool {
+        self.disconnect_from(Some(cluster), identity) > 0
+    }
+
+    /// Disconnect `identity` from `cluster`, or from every cluster if
+    /// `None`
+    fn disconnect_from(&self, cluster: Option<&str>, identity: &str) -> usize {
+        let in_scope = |name: &str| cluster.is_none_or(|cluster| cluster == name);
+        self.offline_queues
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|(name, id), _| id != identity || !in_scope(name));
+
+        let mut cluster_map = self
+            .cluster_connections
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut removed = 0;
+        let mut left = Vec::new();
+        cluster_map.retain(|name, connections| {
+            if !in_scope(name) {
+                return true;
+            }
+            let before = connections.len();
+            connections.retain(|c| c.identity != identity);
+            if connections.len() < before {
+                tracing::info!("Disconnected {identity} from cluster {name}");
+                removed += before - connections.len();
+                left.push((name.clone(), connections.clone()));
+            }
+            !connections.is_empty()
+        });
+        drop(cluster_map);
+
+        for (name, others) in left {
+            self.forget_macs(&name, identity);
+            notify_peers(
+                others,
+                Frame::PeerLeave(PeerLeaveFrame {
+                    identity: identity.to_string(),
+                }),
+            );
+        }
+        removed
+    }
+
+    /// Start buffering frames for a client that just disconnected
+    fn start_grace_window(&self, meta: ConnectionMeta) {
+        let expires_at = Instant::now() + self.offline_buffer_ttl;
+        let key = (meta.cluster.clone(), meta.identity.clone());
+        self.offline_queues
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(key)
+            .and_modify(|queue| queue.expires_at = expires_at)
+            .or_insert_with(|| OfflineQueue {
+                meta,
+                frames: VecDeque::new(),
+                expires_at,
+            });
+    }
+
+    /// Find the connection routing `dst`
+    ///
+    /// A client's own private IP wins over CIDRs, then the longest matching
+    /// prefix, so a gateway advertising `0.0.0.0/0` only gets traffic no
+    /// other client claims. Connections past the peer TTL are skipped, and
+    /// the route policy picks between clients matching equally.
+    pub fn get_connection(&self, cluster: &str, dst: &str) -> Option<ConnectionMeta> {
+        self.get_flow_connection(cluster, dst, &[])
     }
 
-    pub fn get_connection(&self, cluster: &str, dst: &String) -> Option<ConnectionMeta> {
+    /// Find the connection routing `packet` to `dst`
+    ///
+    /// Same as `get_connection`, with `RoutePolicy::RoundRobin` picking
+    /// by the packet's flow rather than its destination.
+    pub fn get_flow_connection(
+        &self,
+        cluster: &str,
+        dst: &str,
+        packet: &[u8],
+    ) -> Option<ConnectionMeta> {
         let guard = self
             .cluster_connections
             .read()
             .unwrap_or_else(|e| e.into_inner());
-        guard.get(cluster).and_then(|connections| {
-            connections
+        self.pick_route(guard.get(cluster)?.iter(), dst, flow_hash(dst, packet))
+    }
+
+    /// Find the connection routing `dst` in any cluster
+    ///
+    /// Only for traffic to a global CIDR, everything else stays within its
+    /// cluster, answers included.
+    pub fn get_global_connection(&self, dst: &str) -> Option<ConnectionMeta> {
+        let guard = self
+            .cluster_connections
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        self.pick_route(guard.values().flatten(), dst, flow_hash(dst, &[]))
+    }
+
+    /// Remember that `src` of `cluster` sent to the global address `dst`
+    ///
+    /// Answers from `dst` to `src` are then routed into `cluster`, see
+    /// `global_flow_cluster`.
+    pub fn record_global_flow(&self, cluster: &str, src: &str, dst: &str) {
+        let mut flows = self.global_flows.write().unwrap_or_else(|e| e.into_inner());
+        let key = (dst.to_string(), src.to_string());
+        if flows.len() >= MAX_GLOBAL_FLOWS && !flows.contains_key(&key) {
+            flows.retain(|_, (_, sent)| sent.elapsed() < GLOBAL_FLOW_TTL);
+            if flows.len() >= MAX_GLOBAL_FLOWS {
+                tracing::debug!("global flow table full, {src} -> {dst} not tracked");
+                return;
+            }
+        }
+        flows.insert(key, (cluster.to_string(), Instant::now()));
+    }
+
+    /// Cluster whose `dst` recently sent to the global address `src`
+    pub fn global_flow_cluster(&self, src: &str, dst: &str) -> Option<String> {
+        let flows = self.global_flows.read().unwrap_or_else(|e| e.into_inner());
+        flows
+            .get(&(src.to_string(), dst.to_string()))
+            .filter(|(_, sent)| sent.elapsed() < GLOBAL_FLOW_TTL)
+            .map(|(cluster, _)| cluster.clone())
+    }
+
+    /// Live connection of `connections` routing `dst` most specifically,
+    /// ties broken by the route policy
+    fn pick_route<'a>(
+        &self,
+        connections: impl Iterator<Item = &'a ConnectionMeta>,
+        dst: &str,
+        flow: u64,
+    ) -> Option<ConnectionMeta> {
+        let matches: Vec<_> = connections
+            .filter(|conn| self.is_live(conn))
+            .filter_map(|conn| conn.match_len(dst).map(|len| (len, conn)))
+            .collect();
+        let best = matches.iter().map(|(len, _)| *len).max()?;
+        let candidates: Vec<_> = matches
+            .into_iter()
+            .filter(|(len, _)| *len == best)
+            .map(|(_, conn)| conn)
+            .collect();
+
+        let conn = match self.route_policy {
+            // a private IP is nobody else's
+            _ if best == u8::MAX => candidates[0],
+            RoutePolicy::First => candidates[0],
+            RoutePolicy::ActiveStandby => self.active_of(&candidates, dst, best)?,
+            RoutePolicy::RoundRobin => candidates[(flow % candidates.len() as u64) as usize],
+        };
+        Some(conn.clone())
+    }
+
+    /// Client of `candidates` holding the prefix of length `len` around
+    /// `dst`
+    ///
+    /// The holder keeps it while it is among the candidates, after that the
+    /// one with the freshest keepalive takes over.
+    fn active_of<'a>(
+        &self,
+        candidates: &[&'a ConnectionMeta],
+        dst: &str,
+        len: u8,
+    ) -> Option<&'a ConnectionMeta> {
+        let prefix = IpNet::new(dst.parse().ok()?, len).ok()?.trunc();
+        let key = (candidates[0].cluster.clone(), prefix.to_string());
+        let held = |active: &HashMap<(String, String), String>| {
+            let holder = active.get(&key)?;
+            candidates
                 .iter()
-                .find(|conn| conn.match_dst(dst.to_owned()))
-                .cloned()
-        })
+                .find(|conn| conn.identity == *holder)
+                .copied()
+        };
+        // every routed packet asks, only a change of holder needs the write lock
+        if let Some(conn) = held(&self.active_routes.read().unwrap_or_else(|e| e.into_inner())) {
+            return Some(conn);
+        }
+        let mut active = self
+            .active_routes
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        // another packet may have picked the holder in between
+        if let Some(conn) = held(&active) {
+            return Some(conn);
+        }
+        // keep the first registered connection on ties
+        let conn = candidates
+            .iter()
+            .rev()
+            .max_by_key(|conn| conn.last_active)
+            .copied()?;
+        tracing::info!("{} now holds {prefix}", conn.identity);
+        active.insert(key, conn.identity.clone());
+        Some(conn)
+    }
+
+    /// Remember that `mac` is behind the TAP client `identity`
+    ///
+    /// Broadcast and multicast sources are never learned. A MAC showing up
+    /// behind another client only moves there once the client it was
+    /// learned behind is gone or stopped sending from it for `MAC_AGE`.
+    /// A client learns `MAX_MACS_PER_CLIENT` addresses at most.
+    pub fn learn_mac(&self, cluster: &str, mac: MacAddr, identity: &str) {
+        if is_group_mac(&mac) {
+            return;
+        }
+        // disconnect locks cluster_connections before mac_tables
+        let connections = self
+            .cluster_connections
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut tables = self.mac_tables.write().unwrap_or_else(|e| e.into_inner());
+        let table = tables.entry(cluster.to_string()).or_default();
+        let now = Instant::now();
+        let previous = match table.get_mut(&mac) {
+            Some(entry) if entry.identity == identity => {
+                entry.seen = now;
+                return;
+            }
+            previous => previous,
+        };
+        if let Some(entry) = previous {
+            let owner_live = connections.get(cluster).is_some_and(|conns| {
+                conns.iter().any(|conn| {
+                    conn.identity == entry.identity
+                        && !conn.outbound_tx.is_closed()
+                        && self.is_live(conn)
+                })
+            });
+            if entry.is_fresh() && owner_live {
+                tracing::debug!(
+                    "{} sent by {identity}, still behind {}",
+                    format_mac(&mac),
+                    entry.identity
+                );
+                return;
+            }
+        }
+
+        table.retain(|_, entry| entry.is_fresh());
+        if table
+            .values()
+            .filter(|entry| entry.identity == identity)
+            .count()
+            >= MAX_MACS_PER_CLIENT
+        {
+            tracing::debug!(
+                "{identity} has {MAX_MACS_PER_CLIENT} MACs, {} not learned",
+                format_mac(&mac)
+            );
+            return;
+        }
+        let entry = MacEntry {
+            identity: identity.to_string(),
+            seen: now,
+        };
+        if let Some(previous) = table.insert(mac, entry) {
+            tracing::debug!(
+                "{} moved from {} to {identity}",
+                format_mac(&mac),
+                previous.identity
+            );
+        } else {
+            tracing::debug!("learned {} behind {identity}", format_mac(&mac));
+        }
+    }
+
+    /// Forget the MAC addresses learned behind `identity`
+    fn forget_macs(&self, cluster: &str, identity: &str) {
+        let mut tables = self.mac_tables.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(table) = tables.get_mut(cluster) {
+            table.retain(|_, entry| entry.identity != identity);
+            if table.is_empty() {
+                tables.remove(cluster);
+            }
+        }
+    }
+
+    /// TAP connections an Ethernet frame from `src_identity` to `dst` is
+    /// switched to
+    ///
+    /// A learned unicast destination goes to its client only; broadcast,
+    /// multicast and unknown destinations are flooded to every other live
+    /// TAP client of the cluster.
+    pub fn get_l2_connections(
+        &self,
+        cluster: &str,
+        src_identity: &str,
+        dst: MacAddr,
+    ) -> Vec<ConnectionMeta> {
+        let owner = match is_group_mac(&dst) {
+            true => None,
+            false => self
+                .mac_tables
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .get(cluster)
+                .and_then(|table| table.get(&dst))
+                .filter(|entry| entry.is_fresh())
+                .map(|entry| entry.identity.clone()),
+        };
+        let guard = self
+            .cluster_connections
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        let Some(connections) = guard.get(cluster) else {
+            return Vec::new();
+        };
+        let ports = || {
+            connections.iter().filter(|conn| {
+                conn.mode == TunnelMode::Tap && conn.identity != src_identity && self.is_live(conn)
+            })
+        };
+        if let Some(owner) = owner {
+            // the destination is on the sender's own side
+            if owner == src_identity {
+                return Vec::new();
+            }
+            if let Some(conn) = ports().find(|conn| conn.identity == owner) {
+                return vec![conn.clone()];
+            }
+        }
+        ports().cloned().collect()
     }
 
     pub fn get_connection_by_identity(
@@ -114,7 +835,7 @@ impl ConnectionManager {
         cluster: &str,
         identity: &String,
         ciders: Vec<String>,
-        ipv6: String,
+        ipv6: Vec<String>,
         port: u16,
         stun: StunAddr,
     ) -> Option<Vec<ConnectionMeta>> {
@@ -160,7 +881,7 @@ impl ConnectionManager {
                 None => "None".to_string(),
             };
             tracing::info!(
-                "Updated connection info for {identity}: {}:{} -> {}:{} stun: {prev_stun} -> {stun}",
+                "Updated connection info for {identity}: {:?}:{} -> {:?}:{} stun: {prev_stun} -> {stun}",
                 prev_conn.ipv6,
                 prev_conn.port,
                 ipv6,
@@ -178,6 +899,51 @@ impl ConnectionManager {
         None
     }
 
+    /// Count a tunneled packet dropped for failing IP validation
+    pub fn record_invalid_packet(&self) {
+        self.invalid_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Tunneled packets dropped for failing IP validation
+    pub fn invalid_packets(&self) -> u64 {
+        self.invalid_packets.load(Ordering::Relaxed)
+    }
+
+    /// Stats for a new connection, counting into the server-wide totals
+    pub fn connection_frame_stats(&self) -> Arc<FrameStats> {
+        Arc::new(FrameStats::with_parent(self.frame_stats.clone()))
+    }
+
+    /// Frames exchanged with all clients since the server started, by type
+    pub fn frame_counts(&self) -> BTreeMap<&'static str, FrameCounts> {
+        self.frame_stats.snapshot()
+    }
+
+    /// Version of a cluster's peer list
+    ///
+    /// The version starts at 1 and is bumped whenever `fingerprint` differs
+    /// from the one of the previous call, so clients holding the current
+    /// version can be spared the full list.
+    pub fn peers_version(&self, cluster: &str, fingerprint: u64) -> u64 {
+        let mut versions = self
+            .peers_versions
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        match versions.get_mut(cluster) {
+            Some((version, last)) => {
+                if *last != fingerprint {
+                    *version += 1;
+                    *last = fingerprint;
+                }
+                *version
+            }
+            None => {
+                versions.insert(cluster.to_string(), (1, fingerprint));
+                1
+            }
+        }
+    }
+
     pub fn dump_connection_info(&self) -> Vec<ConnectionMeta> {
         let mut result = Vec::new();
         let guard = self
@@ -191,6 +957,33 @@ impl ConnectionManager {
         }
         result
     }
+
+    /// List every connection, sorted by cluster then identity
+    pub fn list_connections(&self) -> Vec<ConnectionSummary> {
+        let guard = self
+            .cluster_connections
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut result: Vec<ConnectionSummary> = guard
+            .values()
+            .flatten()
+            .map(ConnectionSummary::from)
+            .collect();
+        result.sort_by(|a, b| (&a.cluster, &a.identity).cmp(&(&b.cluster, &b.identity)));
+        result
+    }
+
+    /// Number of connections in each cluster
+    pub fn count_by_cluster(&self) -> BTreeMap<String, usize> {
+        let guard = self
+            .cluster_connections
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        guard
+            .iter()
+            .map(|(cluster, connections)| (cluster.clone(), connections.len()))
+            .collect()
+    }
 }
 
 impl Default for ConnectionManager {
@@ -198,3 +991,555 @@ impl Default for ConnectionManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::frame::{DataFrame, ipv4_packet};
+    use tokio::sync::mpsc;
+    use tokio::sync::mpsc::error::TryRecvError;
+
+    fn meta(identity: &str, private_ip: &str, outbound_tx: mpsc::Sender<Frame>) -> ConnectionMeta {
+        meta_in("test", identity, private_ip, outbound_tx)
+    }
+
+    fn meta_in(
+        cluster: &str,
+        identity: &str,
+        private_ip: &str,
+        outbound_tx: mpsc::Sender<Frame>,
+    ) -> ConnectionMeta {
+        ConnectionMeta {
+            cluster: cluster.to_string(),
+            ciders: vec!["192.168.1.0/24".to_string()],
+            ..ConnectionMeta::for_test(identity, private_ip, outbound_tx)
+        }
+    }
+
+    fn data(n: u8) -> Frame {
+        Frame::Data(DataFrame {
+            payload: vec![n],
+            seq: None,
+        })
+    }
+
+    fn payload(frame: Frame) -> Vec<u8> {
+        match frame {
+            Frame::Data(frame) => frame.payload,
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    fn identities(metas: Vec<ConnectionMeta>) -> Vec<String> {
+        metas.into_iter().map(|meta| meta.identity).collect()
+    }
+
+    #[test]
+    fn test_add_and_del_return_cluster_peers() {
+        let manager = ConnectionManager::new();
+        let (tx, _rx) = mpsc::channel(8);
+        assert!(
+            manager
+                .add_connection(meta("a", "10.0.0.1", tx.clone()))
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            manager
+                .add_connection(meta_in("other", "x", "10.0.0.9", tx.clone()))
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            identities(
+                manager
+                    .add_connection(meta("b", "10.0.0.2", tx.clone()))
+                    .unwrap()
+            ),
+            ["a"]
+        );
+
+        // a reconnect registered before the old handler exits is no leave
+        manager.add_connection(meta("a", "10.0.0.1", tx)).unwrap();
+        assert!(manager.del_connection("a".to_string()).is_none());
+        assert_eq!(
+            manager.del_connection("a".to_string()).map(identities),
+            Some(vec!["b".to_string()])
+        );
+        assert!(manager.del_connection("a".to_string()).is_none());
+    }
+
+    #[test]
+    fn test_add_refuses_private_ip_in_use() {
+        let manager = ConnectionManager::new();
+        let (tx, _rx) = mpsc::channel(8);
+        manager
+            .add_connection(meta("a", "10.0.0.1", tx.clone()))
+            .unwrap();
+
+        assert_eq!(
+            manager
+                .add_connection(meta("b", "10.0.0.1", tx.clone()))
+                .unwrap_err(),
+            "a"
+        );
+        assert!(
+            manager
+                .get_connection("test", "10.0.0.1")
+                .is_some_and(|c| c.identity == "a")
+        );
+        // the owner reconnecting, and other clusters, do not conflict
+        manager
+            .add_connection(meta("a", "10.0.0.1", tx.clone()))
+            .unwrap();
+        manager
+            .add_connection(meta_in("other", "b", "10.0.0.1", tx))
+            .unwrap();
+
+        // an owner that went away frees the address
+        let (gone, gone_rx) = mpsc::channel(8);
+        manager.add_connection(meta("c", "10.0.0.3", gone)).unwrap();
+        drop(gone_rx);
+        let (tx, _rx) = mpsc::channel(8);
+        manager.add_connection(meta("d", "10.0.0.3", tx)).unwrap();
+    }
+
+    #[test]
+    fn test_kick_closes_connection() {
+        let manager = ConnectionManager::new();
+        let (tx, mut rx) = mpsc::channel(8);
+        manager.add_connection(meta("a", "10.0.0.1", tx)).unwrap();
+        let (tx, mut peer_rx) = mpsc::channel(8);
+        manager.add_connection(meta("b", "10.0.0.2", tx)).unwrap();
+        let (tx, _other_rx) = mpsc::channel(8);
+        manager
+            .add_connection(meta_in("other", "a", "10.0.0.1", tx))
+            .unwrap();
+
+        assert!(!manager.kick("test", "c"));
+        assert!(manager.kick("test", "a"));
+        assert!(matches!(
+            rx.try_recv(),
+            Err(mpsc::error::TryRecvError::Disconnected)
+        ));
+        // the peers left behind are told it is gone
+        assert!(matches!(peer_rx.try_recv(), Ok(Frame::PeerLeave(leave)) if leave.identity == "a"));
+        assert!(
+            manager
+                .get_connection_by_identity("test", &"a".to_string())
+                .is_none()
+        );
+        assert!(!manager.kick("test", "a"));
+        // the same identity in another cluster is untouched
+        assert!(
+            manager
+                .get_connection_by_identity("other", &"a".to_string())
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_peers_version_bumped_on_change() {
+        let manager = ConnectionManager::new();
+        assert_eq!(manager.peers_version("test", 7), 1);
+        assert_eq!(manager.peers_version("test", 7), 1);
+        assert_eq!(manager.peers_version("other", 9), 1);
+        assert_eq!(manager.peers_version("test", 8), 2);
+        assert_eq!(manager.peers_version("test", 7), 3);
+        assert_eq!(manager.peers_version("other", 9), 1);
+    }
+
+    #[test]
+    fn test_buffered_frames_flushed_on_reconnect() {
+        let manager = ConnectionManager::new().with_offline_buffer(2, Duration::from_secs(5));
+        let (tx, _rx) = mpsc::channel(8);
+        manager.add_connection(meta("a", "10.0.0.1", tx)).unwrap();
+        manager.del_connection("a".to_string());
+
+        assert!(manager.buffer_frame("test", "10.0.0.1", data(1)));
+        assert!(manager.buffer_frame("test", "192.168.1.7", data(2)));
+        assert!(manager.buffer_frame("test", "10.0.0.1", data(3)));
+        assert!(!manager.buffer_frame("other", "10.0.0.1", data(4)));
+        assert!(!manager.buffer_frame("test", "10.0.0.9", data(5)));
+
+        let (tx, mut rx) = mpsc::channel(8);
+        manager.add_connection(meta("a", "10.0.0.1", tx)).unwrap();
+
+        // oldest frame was evicted by the ring buffer
+        assert_eq!(payload(rx.try_recv().unwrap()), vec![2]);
+        assert_eq!(payload(rx.try_recv().unwrap()), vec![3]);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_reconnect_count_per_identity() {
+        let manager = ConnectionManager::new();
+        let (tx, _rx) = mpsc::channel(8);
+        let before = now_timestamp();
+        manager
+            .add_connection(meta("a", "10.0.0.1", tx.clone()))
+            .unwrap();
+        let conn = manager
+            .get_connection_by_identity("test", &"a".to_string())
+            .unwrap();
+        assert!(conn.connected_at >= before);
+        assert_eq!(conn.reconnect_count, 0);
+
+        manager.del_connection("a".to_string());
+        manager
+            .add_connection(meta("a", "10.0.0.1", tx.clone()))
+            .unwrap();
+        manager
+            .add_connection(meta_in("other", "a", "10.0.0.1", tx.clone()))
+            .unwrap();
+        let conn = manager
+            .get_connection_by_identity("test", &"a".to_string())
+            .unwrap();
+        assert_eq!(conn.reconnect_count, 1);
+        // counted per cluster
+        let conn = manager
+            .get_connection_by_identity("other", &"a".to_string())
+            .unwrap();
+        assert_eq!(conn.reconnect_count, 0);
+    }
+
+    #[test]
+    fn test_registrations_capped() {
+        let mut manager = ConnectionManager::new();
+        manager.max_registrations = 2;
+        let (tx, _rx) = mpsc::channel(8);
+        let reconnect = |identity: &str, ip: &str| {
+            manager.del_connection(identity.to_string());
+            manager
+                .add_connection(meta(identity, ip, tx.clone()))
+                .unwrap();
+            manager
+                .get_connection_by_identity("test", &identity.to_string())
+                .unwrap()
+                .reconnect_count
+        };
+
+        assert_eq!(reconnect("a", "10.0.0.1"), 0);
+        assert_eq!(reconnect("b", "10.0.0.2"), 0);
+        assert_eq!(reconnect("a", "10.0.0.1"), 1);
+        // c pushes out b, which registered least recently
+        assert_eq!(reconnect("c", "10.0.0.3"), 0);
+        assert_eq!(manager.registrations.read().unwrap().len(), 2);
+        assert_eq!(reconnect("a", "10.0.0.1"), 2);
+        assert_eq!(reconnect("b", "10.0.0.2"), 0);
+    }
+
+    #[test]
+    fn test_buffer_frame_for_closed_live_connection() {
+        let manager = ConnectionManager::new().with_offline_buffer(4, Duration::from_secs(5));
+        let (tx, rx) = mpsc::channel(8);
+        manager.add_connection(meta("a", "10.0.0.1", tx)).unwrap();
+        drop(rx);
+
+        assert!(manager.buffer_frame("test", "10.0.0.1", data(1)));
+        manager.del_connection("a".to_string());
+
+        let (tx, mut rx) = mpsc::channel(8);
+        manager.add_connection(meta("a", "10.0.0.1", tx)).unwrap();
+        assert_eq!(payload(rx.try_recv().unwrap()), vec![1]);
+    }
+
+    #[test]
+    fn test_buffered_frames_expire() {
+        let manager = ConnectionManager::new().with_offline_buffer(4, Duration::ZERO);
+        let (tx, _rx) = mpsc::channel(8);
+        manager.add_connection(meta("a", "10.0.0.1", tx)).unwrap();
+        manager.del_connection("a".to_string());
+        assert!(!manager.buffer_frame("test", "10.0.0.1", data(1)));
+    }
+
+    #[test]
+    fn test_offline_buffer_disabled_by_default() {
+        let manager = ConnectionManager::new();
+        let (tx, _rx) = mpsc::channel(8);
+        manager.add_connection(meta("a", "10.0.0.1", tx)).unwrap();
+        manager.del_connection("a".to_string());
+        assert!(!manager.buffer_frame("test", "10.0.0.1", data(1)));
+    }
+
+    #[test]
+    fn test_get_connection_prefers_most_specific_route() {
+        let manager = ConnectionManager::new();
+        let (tx, _rx) = mpsc::channel(8);
+        let mut gateway = meta("gateway", "10.0.0.1", tx.clone());
+        gateway.ciders = vec!["0.0.0.0/0".to_string()];
+        manager.add_connection(gateway).unwrap();
+        manager.add_connection(meta("a", "10.0.0.2", tx)).unwrap();
+
+        let route = |dst: &str| manager.get_connection("test", dst).map(|c| c.identity);
+        assert_eq!(route("10.0.0.2").as_deref(), Some("a"));
+        assert_eq!(route("192.168.1.7").as_deref(), Some("a"));
+        assert_eq!(route("8.8.8.8").as_deref(), Some("gateway"));
+    }
+
+    #[test]
+    fn test_get_connection_skips_stale_peers() {
+        let manager = ConnectionManager::new().with_peer_ttl(Duration::from_secs(30));
+        let (tx, _rx) = mpsc::channel(8);
+        // both claim 192.168.1.0/24, the stale one registered first
+        let mut stale = meta("stale", "10.0.0.1", tx.clone());
+        stale.last_active = now_timestamp() - 60;
+        let mut fresh = meta("fresh", "10.0.0.2", tx);
+        fresh.last_active = now_timestamp();
+        assert!(!manager.is_live(&stale));
+        assert!(manager.is_live(&fresh));
+        manager.add_connection(stale).unwrap();
+        manager.add_connection(fresh).unwrap();
+
+        let route = |dst: &str| manager.get_connection("test", dst).map(|c| c.identity);
+        assert_eq!(route("192.168.1.7").as_deref(), Some("fresh"));
+        assert_eq!(route("10.0.0.2").as_deref(), Some("fresh"));
+        assert_eq!(route("10.0.0.1"), None);
+
+        // a keepalive brings the stale client back
+        manager.update_connection_info(
+            "test",
+            &"stale".to_string(),
+            vec!["192.168.1.0/24".to_string()],
+            vec![],
+            0,
+            StunAddr {
+                ip: String::new(),
+                port: 0,
+                nat_type: 0,
+            },
+        );
+        assert_eq!(route("10.0.0.1").as_deref(), Some("stale"));
+    }
+
+    /// Two gateways for 192.168.1.0/24, `b` keepalived more recently
+    fn ha_pair(policy: RoutePolicy) -> ConnectionManager {
+        let manager = ConnectionManager::new()
+            .with_peer_ttl(Duration::from_secs(30))
+            .with_route_policy(policy);
+        let (tx, _rx) = mpsc::channel(8);
+        let mut a = meta("a", "10.0.0.1", tx.clone());
+        a.last_active = now_timestamp() - 10;
+        let mut b = meta("b", "10.0.0.2", tx);
+        b.last_active = now_timestamp();
+        manager.add_connection(a).unwrap();
+        manager.add_connection(b).unwrap();
+        manager
+    }
+
+    fn set_last_active(manager: &ConnectionManager, identity: &str, last_active: u64) {
+        let mut guard = manager.cluster_connections.write().unwrap();
+        for conn in guard.get_mut("test").unwrap() {
+            if conn.identity == identity {
+                conn.last_active = last_active;
+            }
+        }
+    }
+
+    #[test]
+    fn test_active_standby_picks_freshest() {
+        let manager = ha_pair(RoutePolicy::ActiveStandby);
+        let route = || {
+            manager
+                .get_connection("test", "192.168.1.7")
+                .unwrap()
+                .identity
+        };
+        assert_eq!(route(), "b");
+        assert_eq!(route(), "b");
+
+        // a keepaliving more recently does not take the route
+        set_last_active(&manager, "a", now_timestamp() + 5);
+        assert_eq!(route(), "b");
+
+        // b stops keepaliving, a takes over
+        set_last_active(&manager, "b", now_timestamp() - 60);
+        assert_eq!(route(), "a");
+        // private IPs are never shared
+        assert_eq!(
+            manager
+                .get_connection("test", "10.0.0.2")
+                .map(|c| c.identity),
+            None
+        );
+        // and keeps the route once b is back
+        set_last_active(&manager, "b", now_timestamp() + 10);
+        assert_eq!(route(), "a");
+    }
+
+    /// UDP packet from 10.0.0.9:`sport` to 192.168.1.7:53
+    fn udp_packet(sport: u16) -> Vec<u8> {
+        let mut udp = vec![0u8; 8];
+        udp[0..2].copy_from_slice(&sport.to_be_bytes());
+        udp[2..4].copy_from_slice(&53u16.to_be_bytes());
+        ipv4_packet([10, 0, 0, 9], [192, 168, 1, 7], 17, &udp)
+    }
+
+    #[test]
+    fn test_round_robin_spreads_flows_and_fails_over() {
+        let manager = ha_pair(RoutePolicy::RoundRobin);
+        let route = |sport: u16| {
+            manager
+                .get_flow_connection("test", "192.168.1.7", &udp_packet(sport))
+                .unwrap()
+                .identity
+        };
+        let picks: Vec<_> = (1000..1032).map(route).collect();
+        assert!(picks.iter().any(|pick| pick == "a"));
+        assert!(picks.iter().any(|pick| pick == "b"));
+        // a flow sticks to its client
+        for (sport, pick) in (1000..1032).zip(&picks) {
+            assert_eq!(&route(sport), pick);
+        }
+        // the own private IP is not load-balanced
+        assert_eq!(
+            manager.get_connection("test", "10.0.0.1").unwrap().identity,
+            "a"
+        );
+
+        set_last_active(&manager, "a", now_timestamp() - 60);
+        assert!((1000..1032).all(|sport| route(sport) == "b"));
+    }
+
+    #[test]
+    fn test_first_policy_keeps_first_registered() {
+        let manager = ha_pair(RoutePolicy::First);
+        let route = || {
+            manager
+                .get_connection("test", "192.168.1.7")
+                .unwrap()
+                .identity
+        };
+        assert!((0..4).all(|_| route() == "a"));
+    }
+
+    #[test]
+    fn test_disconnect_closes_outbound_channel() {
+        let manager = ConnectionManager::new().with_offline_buffer(8, Duration::from_secs(5));
+        let (tx_a, mut rx_a) = mpsc::channel(8);
+        let (tx_b, mut rx_b) = mpsc::channel(8);
+        manager.add_connection(meta("a", "10.0.0.1", tx_a)).unwrap();
+        manager.add_connection(meta("b", "10.0.0.2", tx_b)).unwrap();
+
+        assert_eq!(manager.disconnect("a"), 1);
+        assert_eq!(manager.disconnect("a"), 0);
+
+        assert!(
+            rx_a.try_recv()
+                .is_err_and(|e| e == TryRecvError::Disconnected)
+        );
+        assert!(matches!(rx_b.try_recv(), Ok(Frame::PeerLeave(leave)) if leave.identity == "a"));
+        assert!(rx_b.try_recv().is_err_and(|e| e == TryRecvError::Empty));
+        // no grace window for a forced disconnect
+        assert!(!manager.buffer_frame("test", "10.0.0.1", data(1)));
+    }
+
+    #[test]
+    fn test_list_connections_and_count_by_cluster() {
+        let manager = ConnectionManager::new();
+        let (tx, _rx) = mpsc::channel(8);
+        manager
+            .add_connection(meta_in("blue", "b", "10.0.1.2", tx.clone()))
+            .unwrap();
+        manager
+            .add_connection(meta_in("red", "c", "10.0.2.1", tx.clone()))
+            .unwrap();
+        manager
+            .add_connection(meta_in("blue", "a", "10.0.1.1", tx.clone()))
+            .unwrap();
+        let stun = StunAddr {
+            ip: "1.2.3.4".to_string(),
+            port: 3478,
+            nat_type: 0,
+        };
+        manager.update_connection_info(
+            "blue",
+            &"a".to_string(),
+            vec![],
+            vec!["2001:db8::1".to_string()],
+            51258,
+            stun.clone(),
+        );
+
+        let summaries = manager.list_connections();
+        let ids: Vec<_> = summaries
+            .iter()
+            .map(|s| (s.cluster.as_str(), s.identity.as_str()))
+            .collect();
+        assert_eq!(ids, vec![("blue", "a"), ("blue", "b"), ("red", "c")]);
+        assert_eq!(summaries[0].private_ip, "10.0.1.1");
+        assert_eq!(summaries[0].ipv6, vec!["2001:db8::1"]);
+        assert_eq!(summaries[0].port, 51258);
+        assert_eq!(summaries[0].stun, Some(stun));
+        assert!(summaries[0].last_active > 0);
+        assert_eq!(summaries[1].stun, None);
+
+        let counts = manager.count_by_cluster();
+        assert_eq!(counts.get("blue"), Some(&2));
+        assert_eq!(counts.get("red"), Some(&1));
+
+        manager.del_connection("c".to_string());
+        assert_eq!(manager.count_by_cluster().get("red"), None);
+    }
+
+    #[test]
+    fn test_l2_switching_follows_learned_macs() {
+        let manager = ConnectionManager::new();
+        let (tx, _rx) = mpsc::channel(1);
+        for (identity, ip) in [("a", "10.0.0.1"), ("b", "10.0.0.2"), ("c", "10.0.0.3")] {
+            let mut meta = meta(identity, ip, tx.clone());
+            meta.mode = TunnelMode::Tap;
+            manager.add_connection(meta).unwrap();
+        }
+        manager
+            .add_connection(meta("tun", "10.0.0.4", tx.clone()))
+            .unwrap();
+        let ports = |src: &str, dst: MacAddr| {
+            let mut ids: Vec<_> = manager
+                .get_l2_connections("test", src, dst)
+                .into_iter()
+                .map(|conn| conn.identity)
+                .collect();
+            ids.sort();
+            ids
+        };
+        let mac = [0x02, 0, 0, 0, 0, 0x0b];
+
+        // unknown and broadcast destinations flood the other TAP clients
+        assert_eq!(ports("a", mac), ["b", "c"]);
+        manager.learn_mac("test", [0xff; 6], "b");
+        assert_eq!(ports("a", [0xff; 6]), ["b", "c"]);
+
+        manager.learn_mac("test", mac, "b");
+        assert_eq!(ports("a", mac), ["b"]);
+        assert!(ports("b", mac).is_empty());
+
+        // a MAC does not move away from a live client sending from it
+        manager.learn_mac("test", mac, "c");
+        assert_eq!(ports("a", mac), ["b"]);
+        // it moves with its host once aged out, and is forgotten once the
+        // client leaves
+        age_macs(&manager);
+        assert_eq!(ports("a", mac), ["b", "c"]);
+        manager.learn_mac("test", mac, "c");
+        assert_eq!(ports("a", mac), ["c"]);
+        manager.del_connection("c".to_string());
+        assert_eq!(ports("a", mac), ["b"]);
+
+        // a client cannot fill the table
+        for i in 0..=MAX_MACS_PER_CLIENT {
+            manager.learn_mac("test", [0x02, 1, 0, 0, (i >> 8) as u8, i as u8], "a");
+        }
+        // learned ones stay on a's side, the one past the cap is flooded
+        assert!(ports("a", [0x02, 1, 0, 0, 0, 0]).is_empty());
+        assert_eq!(ports("a", [0x02, 1, 0, 0, 1, 0]), ["b"]);
+    }
+
+    /// Backdate every learned MAC past `MAC_AGE`
+    fn age_macs(manager: &ConnectionManager) {
+        let mut tables = manager.mac_tables.write().unwrap();
+        for entry in tables.values_mut().flat_map(|table| table.values_mut()) {
+            entry.seen -= MAC_AGE;
+        }
+    }
+}
@@ -1,11 +1,15 @@
 pub mod connection_manager;
+pub mod tap;
 pub mod tcp_connection;
 pub mod tcp_listener;
 
-use crate::codec::frame::Frame;
+use crate::codec::frame::{Frame, TunnelMode};
+use crate::codec::stats::FrameStats;
 use crate::crypto::Block;
+use crate::error::RustunError;
 use crate::network::ListenerConfig::TCP;
-use crate::network::tcp_connection::TcpConnection;
+use crate::network::tap::FrameTap;
+use crate::network::tcp_connection::{DEFAULT_READ_TIMEOUT, DEFAULT_WRITE_TIMEOUT, TcpConnection};
 use crate::network::tcp_listener::TCPListener;
 use crate::utils::StunAddr;
 use async_trait::async_trait;
@@ -14,23 +18,118 @@ use std::fmt::Display;
 use std::io;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
 use std::time::Duration;
-use tokio::net::TcpStream;
+use tokio::net::{TcpSocket, TcpStream};
 use tokio::sync::mpsc;
+use tokio::task::JoinSet;
 use tokio::time::timeout;
 
 /// Default timeout for TCP connection establishment
 const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Head start of a connection attempt over the next one (RFC 8305)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Most queued frames written with a single flush
+pub(crate) const MAX_WRITE_BATCH: usize = 64;
+
+/// Collect `first` and the frames already queued behind it
+///
+/// Never waits: stops at `max` frames or once the channel is empty, so
+/// callers can hand the result to `ConnWrite::write_frames`.
+pub(crate) fn drain_batch(first: Frame, rx: &mut mpsc::Receiver<Frame>, max: usize) -> Vec<Frame> {
+    let mut frames = vec![first];
+    while frames.len() < max {
+        match rx.try_recv() {
+            Ok(frame) => frames.push(frame),
+            Err(_) => break,
+        }
+    }
+    frames
+}
+
+/// Next frames to write, control frames ahead of the data backlog
+///
+/// Waits for a frame on either channel, `control` selected first, then
+/// batches up to `max` frames without waiting, again control ones first.
+/// `None` once `data` is closed, `control` closing alone goes unnoticed.
+pub(crate) async fn recv_prioritized(
+    control: &mut mpsc::Receiver<Frame>,
+    data: &mut mpsc::Receiver<Frame>,
+    max: usize,
+) -> Option<Vec<Frame>> {
+    let first = tokio::select! {
+        biased;
+        Some(frame) = control.recv() => frame,
+        frame = data.recv() => frame?,
+    };
+    let mut frames = drain_batch(first, control, max);
+    while frames.len() < max {
+        match data.try_recv() {
+            Ok(frame) => frames.push(frame),
+            Err(_) => break,
+        }
+    }
+    Some(frames)
+}
+
+/// Span carrying the structured tracing fields of a frame
+///
+/// Lets operators filter by type, e.g. `RUST_LOG=rustun[{frame_type=data}]=debug`.
+/// `payload_len` is left for the caller to record once known. Disabled
+/// spans cost next to nothing when debug logging is off.
+pub(crate) fn frame_span(frame: &Frame) -> tracing::Span {
+    tracing::debug_span!(
+        "frame",
+        frame_type = frame.type_name(),
+        payload_len = tracing::field::Empty
+    )
+}
+
 #[async_trait]
 pub trait ConnRead: Send + Sync {
-    async fn read_frame(&mut self) -> anyhow::Result<Frame>;
+    async fn read_frame(&mut self) -> Result<Frame, RustunError>;
 }
 
 #[async_trait]
 pub trait ConnWrite: Send + Sync {
-    async fn write_frame(&mut self, frame: Frame) -> anyhow::Result<()>;
+    async fn write_frame(&mut self, frame: Frame) -> Result<(), RustunError>;
+
+    /// Write several frames in order, flushing once
+    ///
+    /// The default writes them one at a time.
+    async fn write_frames(&mut self, frames: Vec<Frame>) -> Result<(), RustunError> {
+        for frame in frames {
+            self.write_frame(frame).await?;
+        }
+        Ok(())
+    }
+
     async fn close(&mut self);
+
+    /// Write the following frames with protocol `version`, negotiated in
+    /// the handshake
+    ///
+    /// The default keeps writing `MIN_VERSION` frames.
+    fn set_version(&mut self, _version: u8) {}
+
+    /// Pad the following data frames up to a multiple of `bucket` bytes,
+    /// agreed on in the handshake
+    ///
+    /// The default writes them unpadded.
+    fn set_padding(&mut self, _bucket: Option<usize>) {}
+
+    /// Encrypt and decrypt the following frames with `block`
+    ///
+    /// Switches from the handshake key to the key of the client's cluster.
+    /// The default keeps the block the connection was created with.
+    fn set_block(&mut self, _block: Arc<Box<dyn Block>>) {}
+
+    /// Count the frames read and written from now on in `stats`
+    ///
+    /// The default counts nothing.
+    fn set_frame_stats(&mut self, _stats: Arc<FrameStats>) {}
 }
 
 #[async_trait]
@@ -46,6 +145,16 @@ pub trait ConnManage: ConnRead + ConnWrite + HasPeerAddr {}
 /// Implementations handle binding to addresses and accepting new connections.
 #[async_trait]
 pub trait Listener: Send + Sync {
+    /// Bind the listening socket without accepting connections yet
+    ///
+    /// Called by `listen_and_serve` when not done before; calling it first
+    /// lets the caller learn the bound address, e.g. when binding port 0.
+    ///
+    /// # Returns
+    /// - `Ok(SocketAddr)` - Local address the listener is bound to
+    /// - `Err` - Address resolution or bind failure
+    async fn bind(&mut self) -> anyhow::Result<SocketAddr>;
+
     /// Start listening and serving connections
     ///
     /// Binds to the configured address and begins accepting connections.
@@ -96,10 +205,29 @@ pub struct ConnectionMeta {
     pub ciders: Vec<String>,
     /// Channel for sending outbound frames to this client
     pub(crate) outbound_tx: mpsc::Sender<Frame>,
-    pub ipv6: String,
+    /// Channel for control frames to this client, written ahead of the
+    /// frames queued in `outbound_tx`
+    pub(crate) control_tx: mpsc::Sender<Frame>,
+    /// Frames for this client dropped because its queue was full, shared
+    /// by all copies of the meta
+    pub tx_dropped: Arc<AtomicU64>,
+    /// Frames exchanged with this client by type, shared by all copies of
+    /// the meta
+    pub frame_stats: Arc<FrameStats>,
+    /// What the client's data frames carry, granted at handshake
+    pub mode: TunnelMode,
+    /// Protocol version negotiated at handshake
+    pub version: u8,
+    pub ipv6: Vec<String>,
     pub port: u16,
     pub stun: Option<StunAddr>,
     pub last_active: u64,
+    /// When the connection registered (Unix timestamp in seconds), set by
+    /// `ConnectionManager::add_connection`
+    pub connected_at: u64,
+    /// Times the identity registered again since the server started, set
+    /// by `ConnectionManager::add_connection`
+    pub reconnect_count: u64,
 }
 
 impl PartialEq<ConnectionMeta> for &ConnectionMeta {
@@ -109,6 +237,16 @@ impl PartialEq<ConnectionMeta> for &ConnectionMeta {
 }
 
 impl ConnectionMeta {
+    /// Channel `frame` is queued on for this client, `control_tx` for
+    /// control frames and `outbound_tx` for the rest
+    pub(crate) fn sender_for(&self, frame: &Frame) -> &mpsc::Sender<Frame> {
+        if frame.is_control() {
+            &self.control_tx
+        } else {
+            &self.outbound_tx
+        }
+    }
+
     pub fn dump(&self) -> String {
         format!(
             "{},{},{},{}",
@@ -127,24 +265,58 @@ impl ConnectionMeta {
     /// - `true` if destination should be routed through this connection
     /// - `false` otherwise
     pub fn match_dst(&self, dst: String) -> bool {
+        self.match_len(&dst).is_some()
+    }
+
+    /// How specifically a destination matches this connection
+    ///
+    /// # Returns
+    /// - `Some(len)` - Longest matching CIDR prefix, or `u8::MAX` for the private IP
+    /// - `None` - Destination is not routed through this connection
+    pub fn match_len(&self, dst: &str) -> Option<u8> {
         if self.private_ip == dst {
-            return true;
+            return Some(u8::MAX);
         }
 
-        let dst_ip = match dst.parse::<IpAddr>() {
-            Ok(ip) => ip,
-            Err(_) => return false,
-        };
+        let dst_ip = dst.parse::<IpAddr>().ok()?;
+        self.ciders
+            .iter()
+            .filter_map(|cidr| cidr.parse::<IpNet>().ok())
+            .filter(|network| network.contains(&dst_ip))
+            .map(|network| network.prefix_len())
+            .max()
+    }
+}
 
-        for cidr in &self.ciders {
-            if let Ok(network) = cidr.parse::<IpNet>()
-                && network.contains(&dst_ip)
-            {
-                return true;
-            }
+#[cfg(test)]
+impl ConnectionMeta {
+    /// Connection of `identity` at `private_ip` in cluster "test", all its
+    /// frames queued on `outbound_tx`
+    pub(crate) fn for_test(
+        identity: &str,
+        private_ip: &str,
+        outbound_tx: mpsc::Sender<Frame>,
+    ) -> Self {
+        Self {
+            cluster: "test".to_string(),
+            identity: identity.to_string(),
+            private_ip: private_ip.to_string(),
+            mask: "255.255.255.0".to_string(),
+            gateway: "10.0.0.254".to_string(),
+            ciders: vec![],
+            control_tx: outbound_tx.clone(),
+            outbound_tx,
+            tx_dropped: Default::default(),
+            frame_stats: Default::default(),
+            mode: Default::default(),
+            version: crate::codec::parser::MAX_VERSION,
+            ipv6: vec![],
+            port: 0,
+            stun: None,
+            last_active: 0,
+            connected_at: 0,
+            reconnect_count: 0,
         }
-
-        false
     }
 }
 
@@ -158,6 +330,14 @@ impl Display for ConnectionMeta {
 pub struct TCPListenerConfig {
     /// Address to bind the listener to (e.g., "0.0.0.0:8080")
     pub(crate) listen_addr: String,
+    /// Listen backlog for pending connections
+    pub(crate) backlog: u32,
+    /// Largest frame accepted on each connection
+    pub(crate) max_frame_size: usize,
+    /// Buffer sizes of accepted sockets
+    pub(crate) socket_buffers: SocketBuffers,
+    /// Magic number of the frames on each connection
+    pub(crate) magic: u32,
 }
 
 /// Configuration for network listener
@@ -179,12 +359,89 @@ pub fn create_listener(
     block: Arc<Box<dyn Block>>,
 ) -> anyhow::Result<Box<dyn Listener>> {
     match config {
-        TCP(config) => Ok(Box::new(TCPListener::new(config.listen_addr, block))),
+        TCP(config) => Ok(Box::new(
+            TCPListener::new(config.listen_addr, block)
+                .with_backlog(config.backlog)
+                .with_max_frame_size(config.max_frame_size)
+                .with_socket_buffers(config.socket_buffers)
+                .with_magic(config.magic),
+        )),
+    }
+}
+
+/// Kernel buffer sizes of a relay socket, OS defaults when unset
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SocketBuffers {
+    /// `SO_SNDBUF` in bytes
+    pub send: Option<usize>,
+    /// `SO_RCVBUF` in bytes
+    pub recv: Option<usize>,
+}
+
+/// Prepare a relay socket for tunnel traffic
+///
+/// Disables Nagle's algorithm, which would hold small frames such as
+/// interactive SSH packets for up to 40ms, and applies `buffers`.
+pub(crate) fn tune_socket(stream: &TcpStream, buffers: SocketBuffers) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let socket = socket2::SockRef::from(stream);
+    if let Some(size) = buffers.send {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = buffers.recv {
+        socket.set_recv_buffer_size(size)?;
+    }
+    Ok(())
+}
+
+/// Timeouts of an outgoing connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnTimeouts {
+    /// Time allowed to establish the connection (default: 10s)
+    pub connect: Duration,
+    /// Time allowed to read one frame (default: 20s)
+    pub read: Duration,
+    /// Time allowed to write and flush frames (default: 10s)
+    pub write: Duration,
+}
+
+impl Default for ConnTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: DEFAULT_CONNECT_TIMEOUT,
+            read: DEFAULT_READ_TIMEOUT,
+            write: DEFAULT_WRITE_TIMEOUT,
+        }
     }
 }
 
+/// Address family dialed first when the server resolves to both
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FamilyPreference {
+    /// The family the resolver returned first
+    #[default]
+    Auto,
+    /// IPv4, IPv6 only starts after the attempt delay
+    V4,
+    /// IPv6, IPv4 only starts after the attempt delay
+    V6,
+}
+
 pub struct TCPConnectionConfig {
     pub(crate) server_addr: String,
+    /// Capture the connection's frames (disabled if not set)
+    pub(crate) tap: Option<FrameTap>,
+    pub(crate) timeouts: ConnTimeouts,
+    pub(crate) socket_buffers: SocketBuffers,
+    pub(crate) prefer_family: FamilyPreference,
+    /// Local address the connection is made from, pinning it to the uplink
+    /// owning it (the one of the default route if not set)
+    pub(crate) bind_source: Option<IpAddr>,
+    /// Addresses `server_addr` resolved to ahead, dialed instead of looking
+    /// it up (looked up on every connect if empty)
+    pub(crate) resolved: Vec<SocketAddr>,
+    /// Magic number of the connection's frames
+    pub(crate) magic: u32,
 }
 
 pub enum ConnectionConfig {
@@ -196,22 +453,185 @@ pub async fn create_connection(
     block: Arc<Box<dyn Block>>,
 ) -> anyhow::Result<Box<dyn ConnManage>> {
     match config {
-        ConnectionConfig::TCP(config) => {
-            // Connect with timeout
-            let connect_result = timeout(
-                DEFAULT_CONNECT_TIMEOUT,
-                TcpStream::connect(&config.server_addr),
-            )
-            .await;
-
-            match connect_result {
-                Ok(Ok(stream)) => {
-                    let conn = TcpConnection::new(stream, block.clone());
-                    Ok(Box::new(conn))
-                }
-                Ok(Err(e)) => Err(e.into()),
-                Err(_) => Err(anyhow::anyhow!("connection timeout")),
+        ConnectionConfig::TCP(config) => Ok(Box::new(connect_tcp(config, block).await?)),
+    }
+}
+
+/// Order resolved addresses for Happy Eyeballs (RFC 8305)
+///
+/// Families alternate starting with the preferred one, so a dead path of
+/// one family holds the other back by one attempt delay at most.
+pub(crate) fn interleave_families(
+    addrs: Vec<SocketAddr>,
+    prefer: FamilyPreference,
+) -> Vec<SocketAddr> {
+    let ipv6_first = match prefer {
+        FamilyPreference::Auto => addrs.first().is_some_and(|addr| addr.is_ipv6()),
+        FamilyPreference::V4 => false,
+        FamilyPreference::V6 => true,
+    };
+    let (ipv6, ipv4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6());
+    let (mut first, mut second) = match ipv6_first {
+        true => (ipv6.into_iter(), ipv4.into_iter()),
+        false => (ipv4.into_iter(), ipv6.into_iter()),
+    };
+    let mut ordered = Vec::new();
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connect to whichever of `addrs` answers first
+///
+/// Attempts start in order, each `attempt_delay` after the previous one or
+/// as soon as it failed, while earlier ones keep running. The first
+/// connected stream wins and the other attempts are dropped.
+///
+/// # Returns
+/// - `Ok(TcpStream)` - The first established connection
+/// - `Err` - Error of the last attempt when all of them failed
+pub(crate) async fn race_connect<F, Fut>(
+    addrs: Vec<SocketAddr>,
+    attempt_delay: Duration,
+    connect: F,
+) -> io::Result<TcpStream>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<TcpStream>> + Send + 'static,
+{
+    let mut pending = addrs.into_iter().peekable();
+    let mut attempts = JoinSet::new();
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address to connect to");
+    loop {
+        if let Some(addr) = pending.next() {
+            tracing::debug!("connecting to {addr}");
+            attempts.spawn(connect(addr));
+        }
+        if attempts.is_empty() {
+            return Err(last_error);
+        }
+        tokio::select! {
+            Some(result) = attempts.join_next() => match result {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => last_error = e,
+                Err(e) => last_error = io::Error::other(e),
+            },
+            _ = tokio::time::sleep(attempt_delay), if pending.peek().is_some() => {}
+        }
+    }
+}
+
+/// Connect to `addr` from local address `source`, bound before connecting
+async fn connect_from(source: IpAddr, addr: SocketAddr) -> io::Result<TcpStream> {
+    let socket = match source {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind(SocketAddr::new(source, 0))?;
+    socket.connect(addr).await
+}
+
+/// Connect to `config.server_addr` within the connect timeout
+///
+/// Both address families are raced, Happy Eyeballs style, so a broken
+/// IPv6 (or IPv4) path does not stall the connection. The returned
+/// connection uses the configured read and write timeouts.
+pub async fn connect_tcp(
+    config: TCPConnectionConfig,
+    block: Arc<Box<dyn Block>>,
+) -> anyhow::Result<TcpConnection> {
+    let connect = async {
+        let resolved = match config.resolved.is_empty() {
+            true => tokio::net::lookup_host(&config.server_addr)
+                .await?
+                .collect(),
+            false => config.resolved.clone(),
+        };
+        let addrs: Vec<SocketAddr> = resolved
+            .into_iter()
+            // a source address only reaches servers of its family
+            .filter(|addr| {
+                config
+                    .bind_source
+                    .is_none_or(|source| source.is_ipv4() == addr.is_ipv4())
+            })
+            .collect();
+        let addrs = interleave_families(addrs, config.prefer_family);
+        match config.bind_source {
+            Some(source) => {
+                race_connect(addrs, CONNECTION_ATTEMPT_DELAY, move |addr| {
+                    connect_from(source, addr)
+                })
+                .await
             }
+            None => race_connect(addrs, CONNECTION_ATTEMPT_DELAY, TcpStream::connect).await,
         }
+    };
+    let connect_result = timeout(config.timeouts.connect, connect).await;
+
+    match connect_result {
+        Ok(Ok(stream)) => {
+            if let Err(e) = tune_socket(&stream, config.socket_buffers) {
+                tracing::warn!("failed to tune relay socket: {e}");
+            }
+            let mut conn = TcpConnection::new(stream, block);
+            conn.set_read_timeout(config.timeouts.read);
+            conn.set_write_timeout(config.timeouts.write);
+            conn.set_magic(config.magic);
+            if let Some(tap) = config.tap {
+                conn.set_tap(tap);
+            }
+            Ok(conn)
+        }
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(anyhow::anyhow!("connection timeout")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::frame::DataFrame;
+    use crate::codec::frame::tests::keepalive;
+
+    #[tokio::test]
+    async fn test_keepalive_written_ahead_of_data_backlog() {
+        let (data_tx, mut data_rx) = mpsc::channel(256);
+        let (control_tx, mut control_rx) = mpsc::channel(8);
+        let backlog = 200;
+        for n in 0..backlog {
+            data_tx
+                .try_send(Frame::Data(DataFrame {
+                    payload: vec![n as u8; 64],
+                    seq: None,
+                }))
+                .unwrap();
+        }
+        control_tx
+            .try_send(Frame::KeepAlive(keepalive("a")))
+            .unwrap();
+
+        let mut written = vec![];
+        while written.len() <= backlog {
+            let frames = recv_prioritized(&mut control_rx, &mut data_rx, MAX_WRITE_BATCH)
+                .await
+                .unwrap();
+            assert!(frames.len() <= MAX_WRITE_BATCH);
+            written.extend(frames);
+        }
+        assert!(matches!(written[0], Frame::KeepAlive(_)));
+        assert_eq!(written.len(), backlog + 1);
+        assert!(written[1..].iter().all(|frame| !frame.is_control()));
+
+        // the data sender gone ends the session, the control one does not
+        drop(data_tx);
+        assert!(
+            recv_prioritized(&mut control_rx, &mut data_rx, MAX_WRITE_BATCH)
+                .await
+                .is_none()
+        );
     }
 }
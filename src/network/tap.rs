@@ -0,0 +1,166 @@
+//! Frame capture for protocol debugging
+//!
+//! A `FrameTap` appends one JSON line per frame read or written:
+//!
+//! ```text
+//! {"ts_us":1700000000123456,"link":"relay","dir":"out","peer":"203.0.113.1:8080","frame_type":"data","payload_len":84,"src":"10.0.0.1","dst":"10.0.0.2"}
+//! ```
+//!
+//! Connections keep an `Option<FrameTap>`, so capture costs a single branch
+//! per frame when it is disabled. The file is written by a `LogWriter`
+//! thread, records it cannot keep up with are dropped.
+
+use crate::codec::frame::{DataFrame, Frame};
+use crate::utils::log_writer::LogWriter;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Direction of a captured frame
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// One line of the capture file
+#[derive(Serialize)]
+struct CaptureRecord<'a> {
+    /// Capture time in microseconds since the UNIX epoch
+    ts_us: u64,
+    /// Transport the frame used, "relay" or "p2p"
+    link: &'a str,
+    dir: Direction,
+    /// Remote socket address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer: Option<SocketAddr>,
+    frame_type: &'static str,
+    /// Decrypted payload length of data frames
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_len: Option<usize>,
+    /// Inner packet source and destination of IPv4 data frames
+    #[serde(skip_serializing_if = "Option::is_none")]
+    src: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dst: Option<String>,
+}
+
+/// Shared writer for the capture file
+///
+/// Cheap to clone; all clones append to the same file.
+#[derive(Clone)]
+pub struct FrameTap {
+    out: LogWriter,
+}
+
+impl FrameTap {
+    /// Open `path` for capture, appending to an existing file
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(Self {
+            out: LogWriter::open(path, "frame capture")?,
+        })
+    }
+
+    /// Wait until the records so far are in the file
+    pub fn flush(&self) {
+        self.out.flush();
+    }
+
+    /// Append a record for `frame`
+    pub fn record(&self, link: &str, dir: Direction, peer: Option<SocketAddr>, frame: &Frame) {
+        let data = match frame {
+            Frame::Data(data) => Some(data),
+            _ => None,
+        };
+        let ipv4 = data.filter(|data| !data.invalid() && data.version() == 4);
+        let payload_len = match frame {
+            Frame::Data(data) => Some(data.payload.len()),
+            Frame::DataBatch(batch) => Some(batch.encoded_len()),
+            _ => None,
+        };
+        let record = CaptureRecord {
+            ts_us: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64,
+            link,
+            dir,
+            peer,
+            frame_type: frame.type_name(),
+            payload_len,
+            src: ipv4.map(DataFrame::src),
+            dst: ipv4.map(DataFrame::dst),
+        };
+
+        let Ok(mut line) = serde_json::to_vec(&record) else {
+            return;
+        };
+        line.push(b'\n');
+        self.out.write(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::frame::ipv4_packet;
+    use crate::codec::frame::tests::keepalive;
+    use crate::network::tcp_connection::TcpConnection;
+    use crate::network::{ConnRead, ConnWrite};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_capture_records_frames_both_ways() {
+        let path = std::env::temp_dir().join(format!("rustun-tap-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut client = TcpConnection::from_socket(client);
+        let tap = FrameTap::open(&path).unwrap();
+        client.set_tap(tap.clone());
+        let mut server = TcpConnection::from_socket(server);
+
+        let packet = ipv4_packet([10, 0, 0, 1], [10, 0, 0, 2], 17, &[]);
+        client
+            .write_frame(Frame::Data(DataFrame {
+                payload: packet,
+                seq: None,
+            }))
+            .await
+            .unwrap();
+        server.read_frame().await.unwrap();
+        server
+            .write_frame(Frame::KeepAlive(keepalive("server")))
+            .await
+            .unwrap();
+        client.read_frame().await.unwrap();
+
+        tap.flush();
+        let records: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(records.len(), 2);
+        let peer = listener.local_addr().unwrap().to_string();
+        assert_eq!(records[0]["dir"], "out");
+        assert_eq!(records[0]["link"], "relay");
+        assert_eq!(records[0]["peer"], peer.as_str());
+        assert_eq!(records[0]["frame_type"], "data");
+        assert_eq!(records[0]["payload_len"], 20);
+        assert_eq!(records[0]["src"], "10.0.0.1");
+        assert_eq!(records[0]["dst"], "10.0.0.2");
+        assert_eq!(records[1]["dir"], "in");
+        assert_eq!(records[1]["frame_type"], "keepalive");
+        assert!(records[1].get("payload_len").is_none());
+        assert!(records[1]["ts_us"].as_u64().unwrap() >= records[0]["ts_us"].as_u64().unwrap());
+    }
+}
@@ -1,8 +1,13 @@
+use crate::codec::errors::FrameError;
 use crate::codec::frame::Frame;
-use crate::codec::parser::Parser;
+use crate::codec::magic::DEFAULT_MAGIC;
+use crate::codec::parser::{MAX_FRAME_LEN, MIN_VERSION, Parser};
+use crate::codec::stats::FrameStats;
 use crate::crypto::Block;
 use crate::crypto::plain::PlainBlock;
-use crate::network::{ConnManage, ConnRead, ConnWrite, HasPeerAddr};
+use crate::error::RustunError;
+use crate::network::tap::{Direction, FrameTap};
+use crate::network::{ConnManage, ConnRead, ConnWrite, HasPeerAddr, frame_span};
 use async_trait::async_trait;
 use bytes::{Buf, BytesMut};
 use std::io;
@@ -14,9 +19,9 @@ use tokio::net::TcpStream;
 use tokio::time::{Instant, timeout};
 
 /// Default timeout for read operations
-const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(20);
+pub(crate) const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(20);
 /// Default timeout for write operations
-const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+pub(crate) const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// TCP connection wrapper with frame parsing and encryption
 ///
@@ -30,8 +35,22 @@ pub struct TcpConnection {
     read_timeout: Duration,
     /// Input buffer for incomplete frames
     input_stream: BytesMut,
+    /// Largest frame (header + payload) accepted from the peer
+    max_frame_size: usize,
+    /// Skip to the next magic on a corrupted header instead of failing
+    resync: bool,
     /// Crypto block for encryption/decryption
     block: Arc<Box<dyn Block>>,
+    /// Protocol version of written frames
+    version: u8,
+    /// Bucket size written data frames are padded to, if any
+    pad: Option<usize>,
+    /// Magic number frames are written and expected with
+    magic: u32,
+    /// Frame capture with the cached peer address, if enabled
+    tap: Option<(FrameTap, Option<SocketAddr>)>,
+    /// Counts of the frames read and written, if enabled
+    stats: Option<Arc<FrameStats>>,
 }
 
 impl TcpConnection {
@@ -46,7 +65,14 @@ impl TcpConnection {
             write_timeout: DEFAULT_WRITE_TIMEOUT,
             read_timeout: DEFAULT_READ_TIMEOUT,
             input_stream: BytesMut::with_capacity(4096),
+            max_frame_size: MAX_FRAME_LEN,
+            resync: true,
             block,
+            version: MIN_VERSION,
+            pad: None,
+            magic: DEFAULT_MAGIC,
+            tap: None,
+            stats: None,
         }
     }
 
@@ -62,7 +88,14 @@ impl TcpConnection {
             write_timeout: DEFAULT_WRITE_TIMEOUT,
             read_timeout: DEFAULT_READ_TIMEOUT,
             input_stream: BytesMut::with_capacity(4096),
+            max_frame_size: MAX_FRAME_LEN,
+            resync: true,
             block: Arc::new(Box::new(PlainBlock::new())),
+            version: MIN_VERSION,
+            pad: None,
+            magic: DEFAULT_MAGIC,
+            tap: None,
+            stats: None,
         }
     }
 
@@ -82,6 +115,49 @@ impl TcpConnection {
         self.write_timeout = timeout;
     }
 
+    /// Set the largest frame accepted from the peer
+    ///
+    /// Frames whose header declares a larger size are rejected before
+    /// their payload is buffered.
+    ///
+    /// # Arguments
+    /// - `size` - Maximum frame size in bytes (header included)
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.max_frame_size = size;
+    }
+
+    /// Enable or disable resynchronization on corrupted headers
+    ///
+    /// When enabled (the default), bytes up to the next magic number are
+    /// discarded and reading continues. When disabled, a corrupted header
+    /// closes the connection with `FrameError::Invalid`.
+    pub fn set_resync(&mut self, resync: bool) {
+        self.resync = resync;
+    }
+
+    /// Set the magic number frames are written and expected with
+    ///
+    /// Both ends must use the same, see `codec::magic`.
+    pub fn set_magic(&mut self, magic: u32) {
+        self.magic = magic;
+    }
+
+    /// Capture every frame read or written to `tap`
+    pub fn set_tap(&mut self, tap: FrameTap) {
+        self.tap = Some((tap, self.socket.peer_addr().ok()));
+    }
+
+    fn capture(&self, dir: Direction, frame: &Frame) {
+        if let Some((tap, peer)) = &self.tap {
+            tap.record("relay", dir, *peer, frame);
+        }
+    }
+
+    /// Get current maximum frame size
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
     /// Get current read timeout
     pub fn read_timeout(&self) -> Duration {
         self.read_timeout
@@ -92,6 +168,25 @@ impl TcpConnection {
         self.write_timeout
     }
 
+    /// Write marshaled bytes and flush within the write timeout
+    async fn write_buf(&mut self, buf: &[u8]) -> Result<(), RustunError> {
+        let write_result = timeout(self.write_timeout, async {
+            self.socket.write_all(buf).await?;
+            self.socket.flush().await?;
+            Ok::<(), std::io::Error>(())
+        })
+        .await;
+
+        match write_result {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err(RustunError::Timeout {
+                op: "write",
+                after: self.write_timeout,
+            }),
+        }
+    }
+
     /// Parse a complete frame from the input buffer
     ///
     /// Attempts to parse a frame from buffered data. If successful,
@@ -100,33 +195,94 @@ impl TcpConnection {
     /// # Returns
     /// - `Ok(Some(Frame))` - Successfully parsed frame
     /// - `Ok(None)` - Incomplete data, need more bytes
-    /// - `Err` - Parse error (invalid frame format)
-    fn parse_frame(&mut self) -> anyhow::Result<Option<Frame>> {
-        let result = Parser::unmarshal(self.input_stream.as_ref(), self.block.as_ref().as_ref());
+    /// - `Err` - The buffered frame is invalid or does not decrypt
+    fn parse_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+        let result = Parser::unmarshal_counted(
+            self.input_stream.as_ref(),
+            self.block.as_ref().as_ref(),
+            self.magic,
+            self.stats.as_deref(),
+        );
         match result {
             Ok((frame, total_len)) => {
+                let header_len = Parser::header_len(self.input_stream[4]);
                 self.input_stream.advance(total_len);
+                let span = frame_span(&frame);
+                span.record("payload_len", total_len - header_len);
+                span.in_scope(|| tracing::debug!("read frame"));
+                self.capture(Direction::In, &frame);
                 Ok(Some(frame))
             }
+            Err(FrameError::TooShort) => Ok(None),
             Err(e) => Err(e),
         }
     }
+
+    /// Discard buffered bytes after a parse error
+    ///
+    /// A bad header means the stream lost sync, so everything up to the
+    /// next magic number is dropped. A good header with a bad payload
+    /// (unknown type or decoding failure) only drops that one frame.
+    ///
+    /// # Returns
+    /// - `false` - Header is corrupted and resync is disabled
+    fn skip_invalid(&mut self, err: &FrameError) -> bool {
+        let skip = match Parser::declared_len(&self.input_stream, self.magic) {
+            Some(len) => len,
+            None if !self.resync => return false,
+            None => Parser::find_next_magic(&self.input_stream[1..], self.magic)
+                .map(|pos| pos + 1)
+                // keep a possibly split magic at the tail
+                .unwrap_or(self.input_stream.len().saturating_sub(3)),
+        };
+        tracing::warn!("discarding {skip} bytes after parse error: {err}");
+        self.input_stream.advance(skip);
+        true
+    }
 }
 
 #[async_trait]
 impl ConnRead for TcpConnection {
-    async fn read_frame(&mut self) -> anyhow::Result<Frame> {
+    async fn read_frame(&mut self) -> Result<Frame, RustunError> {
         let deadline = Instant::now() + self.read_timeout;
+        let timed_out = RustunError::Timeout {
+            op: "read",
+            after: self.read_timeout,
+        };
 
         loop {
             if Instant::now() > deadline {
-                return Err(anyhow::anyhow!("read timeout"));
+                return Err(timed_out);
             }
 
-            if let Ok(frame) = self.parse_frame()
-                && let Some(frame) = frame
+            match self.parse_frame() {
+                Ok(Some(frame)) => return Ok(frame),
+                Ok(None) => {}
+                Err(FrameError::DecryptionFailed(e)) => {
+                    // every later frame fails the same way
+                    self.close().await;
+                    return Err(RustunError::Crypto(
+                        e.context("frame does not decrypt, crypto keys differ?"),
+                    ));
+                }
+                Err(e) => {
+                    if !self.skip_invalid(&e) {
+                        self.close().await;
+                        return Err(e.into());
+                    }
+                    continue;
+                }
+            }
+
+            if let Some(len) = Parser::declared_len(&self.input_stream, self.magic)
+                && len > self.max_frame_size
             {
-                return Ok(frame);
+                tracing::warn!(
+                    "frame of {len} bytes exceeds limit {}, closing",
+                    self.max_frame_size
+                );
+                self.close().await;
+                return Err(FrameError::TooLong.into());
             }
 
             let remaining = deadline.saturating_duration_since(Instant::now());
@@ -137,16 +293,20 @@ impl ConnRead for TcpConnection {
             match read_result {
                 Ok(Ok(0)) => {
                     return if self.input_stream.is_empty() {
-                        Err(anyhow::anyhow!("EOF"))
+                        Err(RustunError::Closed)
                     } else {
-                        Err(anyhow::anyhow!("connection reset by peer"))
+                        Err(io::Error::new(
+                            io::ErrorKind::ConnectionReset,
+                            "connection reset by peer",
+                        )
+                        .into())
                     };
                 }
                 Ok(Ok(n)) => {
                     tracing::debug!("read {n} bytes")
                 }
                 Ok(Err(e)) => return Err(e.into()),
-                Err(_) => return Err(anyhow::anyhow!("read timeout")),
+                Err(_) => return Err(timed_out),
             }
         }
     }
@@ -154,32 +314,88 @@ impl ConnRead for TcpConnection {
 
 #[async_trait]
 impl ConnWrite for TcpConnection {
-    async fn write_frame(&mut self, frame: Frame) -> anyhow::Result<()> {
-        let result = Parser::marshal(frame, self.block.as_ref().as_ref());
+    async fn write_frame(&mut self, frame: Frame) -> Result<(), RustunError> {
+        let span = frame_span(&frame);
+        self.capture(Direction::Out, &frame);
+        let result = Parser::marshal_counted(
+            frame,
+            self.block.as_ref().as_ref(),
+            self.version,
+            self.pad,
+            self.magic,
+            self.stats.as_deref(),
+        );
         let buf = match result {
             Ok(buf) => buf,
             Err(e) => {
-                return Err(e);
+                return Err(e.into());
             }
         };
+        span.record("payload_len", buf.len() - Parser::header_len(self.version));
+        span.in_scope(|| tracing::debug!("write frame"));
 
-        let write_result = timeout(self.write_timeout, async {
-            self.socket.write_all(buf.as_slice()).await?;
-            self.socket.flush().await?;
-            Ok::<(), std::io::Error>(())
-        })
-        .await;
+        self.write_buf(&buf).await
+    }
 
-        match write_result {
-            Ok(Ok(())) => Ok(()),
-            Ok(Err(e)) => Err(e.into()),
-            Err(_) => Err(anyhow::anyhow!("write timeout")),
+    /// Marshal all frames into one buffer, then write and flush it once
+    ///
+    /// A frame that fails to marshal is logged and dropped without
+    /// affecting the others; the error is only returned when no frame
+    /// could be marshaled.
+    async fn write_frames(&mut self, frames: Vec<Frame>) -> Result<(), RustunError> {
+        let mut buf = Vec::new();
+        let mut last_err = None;
+        for frame in frames {
+            let span = frame_span(&frame);
+            self.capture(Direction::Out, &frame);
+            match Parser::marshal_counted(
+                frame,
+                self.block.as_ref().as_ref(),
+                self.version,
+                self.pad,
+                self.magic,
+                self.stats.as_deref(),
+            ) {
+                Ok(frame_buf) => {
+                    span.record(
+                        "payload_len",
+                        frame_buf.len() - Parser::header_len(self.version),
+                    );
+                    span.in_scope(|| tracing::debug!("write frame"));
+                    buf.extend_from_slice(&frame_buf);
+                }
+                Err(e) => {
+                    span.in_scope(|| tracing::warn!("dropping frame from batch: {e}"));
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        match last_err {
+            Some(e) if buf.is_empty() => Err(e.into()),
+            _ => self.write_buf(&buf).await,
         }
     }
 
     async fn close(&mut self) {
         let _ = self.socket.shutdown().await;
     }
+
+    fn set_version(&mut self, version: u8) {
+        self.version = version;
+    }
+
+    fn set_padding(&mut self, bucket: Option<usize>) {
+        self.pad = bucket;
+    }
+
+    fn set_block(&mut self, block: Arc<Box<dyn Block>>) {
+        self.block = block;
+    }
+
+    fn set_frame_stats(&mut self, stats: Arc<FrameStats>) {
+        self.stats = Some(stats);
+    }
 }
 
 impl HasPeerAddr for TcpConnection {
@@ -189,3 +405,416 @@ impl HasPeerAddr for TcpConnection {
 }
 
 impl ConnManage for TcpConnection {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::frame::HDR_LEN;
+    use tokio::net::TcpListener;
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_connect_applies_custom_timeouts() {
+        use crate::crypto::plain::PlainBlock;
+        use crate::network::{ConnTimeouts, TCPConnectionConfig, connect_tcp};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let timeouts = ConnTimeouts {
+            connect: Duration::from_secs(3),
+            read: Duration::from_secs(45),
+            write: Duration::from_secs(7),
+        };
+        let config = TCPConnectionConfig {
+            server_addr: listener.local_addr().unwrap().to_string(),
+            tap: None,
+            timeouts,
+            socket_buffers: Default::default(),
+            prefer_family: Default::default(),
+            bind_source: None,
+            resolved: vec![],
+            magic: DEFAULT_MAGIC,
+        };
+        let conn = connect_tcp(config, Arc::new(Box::new(PlainBlock::new())))
+            .await
+            .unwrap();
+        assert_eq!(conn.read_timeout(), Duration::from_secs(45));
+        assert_eq!(conn.write_timeout(), Duration::from_secs(7));
+
+        let defaults = ConnTimeouts::default();
+        assert_eq!(defaults.read, DEFAULT_READ_TIMEOUT);
+        assert_eq!(defaults.write, DEFAULT_WRITE_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_connect_dials_resolved_addrs() {
+        use crate::crypto::plain::PlainBlock;
+        use crate::network::{TCPConnectionConfig, connect_tcp};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // never looked up
+        let config = TCPConnectionConfig {
+            server_addr: "relay.invalid:8080".to_string(),
+            tap: None,
+            timeouts: Default::default(),
+            socket_buffers: Default::default(),
+            prefer_family: Default::default(),
+            bind_source: None,
+            resolved: vec![listener.local_addr().unwrap()],
+            magic: DEFAULT_MAGIC,
+        };
+        connect_tcp(config, Arc::new(Box::new(PlainBlock::new())))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_tunes_socket() {
+        use crate::crypto::plain::PlainBlock;
+        use crate::network::{SocketBuffers, TCPConnectionConfig, connect_tcp};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TCPConnectionConfig {
+            server_addr: listener.local_addr().unwrap().to_string(),
+            tap: None,
+            timeouts: Default::default(),
+            socket_buffers: SocketBuffers {
+                send: Some(128 * 1024),
+                recv: Some(96 * 1024),
+            },
+            prefer_family: Default::default(),
+            bind_source: None,
+            resolved: vec![],
+            magic: DEFAULT_MAGIC,
+        };
+        let conn = connect_tcp(config, Arc::new(Box::new(PlainBlock::new())))
+            .await
+            .unwrap();
+        assert!(conn.socket.nodelay().unwrap());
+        // the kernel may round the sizes up, Linux doubles them
+        let socket = socket2::SockRef::from(&conn.socket);
+        assert!(socket.send_buffer_size().unwrap() >= 128 * 1024);
+        assert!(socket.recv_buffer_size().unwrap() >= 96 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_connect_from_bind_source() {
+        use crate::crypto::plain::PlainBlock;
+        use crate::network::{TCPConnectionConfig, connect_tcp};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // 127.0.0.1 is the only loopback address every platform has, the
+        // source gets a port of its own
+        let source: std::net::IpAddr = "127.0.0.1".parse().unwrap();
+        let config = TCPConnectionConfig {
+            server_addr: listener.local_addr().unwrap().to_string(),
+            tap: None,
+            timeouts: Default::default(),
+            socket_buffers: Default::default(),
+            prefer_family: Default::default(),
+            bind_source: Some(source),
+            resolved: vec![],
+            magic: DEFAULT_MAGIC,
+        };
+        let conn = connect_tcp(config, Arc::new(Box::new(PlainBlock::new())))
+            .await
+            .unwrap();
+        let local = conn.socket.local_addr().unwrap();
+        assert_eq!(local.ip(), source);
+        assert_ne!(local.port(), listener.local_addr().unwrap().port());
+        let (_, client) = listener.accept().await.unwrap();
+        assert_eq!(client, local);
+    }
+
+    #[test]
+    fn test_families_interleaved_from_preferred() {
+        use crate::network::{FamilyPreference, interleave_families};
+
+        let addrs: Vec<SocketAddr> = ["[2001:db8::1]:80", "[2001:db8::2]:80", "192.0.2.1:80"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let ordered = |prefer| -> Vec<String> {
+            interleave_families(addrs.clone(), prefer)
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect()
+        };
+        assert_eq!(
+            ordered(FamilyPreference::Auto),
+            ["[2001:db8::1]:80", "192.0.2.1:80", "[2001:db8::2]:80"]
+        );
+        assert_eq!(
+            ordered(FamilyPreference::V4),
+            ["192.0.2.1:80", "[2001:db8::1]:80", "[2001:db8::2]:80"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dead_ipv6_falls_back_to_ipv4() {
+        use crate::network::{FamilyPreference, interleave_families, race_connect};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ipv4 = listener.local_addr().unwrap();
+        // the host resolves to a blackholed IPv6 address first
+        let dead: SocketAddr = "[2001:db8::dead]:443".parse().unwrap();
+        let addrs = interleave_families(vec![dead, ipv4], FamilyPreference::Auto);
+        assert_eq!(addrs[0], dead);
+
+        let started = std::time::Instant::now();
+        let stream = race_connect(addrs, Duration::from_millis(50), |addr| async move {
+            if addr.is_ipv6() {
+                // a dead path never answers
+                std::future::pending().await
+            } else {
+                TcpStream::connect(addr).await
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), ipv4);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // all attempts failing reports the last error
+        let refused = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = refused.local_addr().unwrap();
+        drop(refused);
+        assert!(
+            race_connect(vec![closed], Duration::from_millis(50), TcpStream::connect)
+                .await
+                .is_err()
+        );
+    }
+
+    fn header(frame_type: u8, payload_len: u16) -> Vec<u8> {
+        let mut buf = vec![0x91, 0x92, 0x93, 0x94, 0x01, frame_type];
+        buf.extend_from_slice(&payload_len.to_be_bytes());
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_rejected_promptly() {
+        let (mut client, server) = pair().await;
+        let mut conn = TcpConnection::from_socket(server);
+        conn.set_max_frame_size(2048);
+
+        // Declare a 60000 byte payload but only dribble a few bytes
+        let mut buf = header(3, 60000);
+        buf.extend_from_slice(&[0u8; 16]);
+        client.write_all(&buf).await.unwrap();
+
+        let result = timeout(Duration::from_secs(1), conn.read_frame())
+            .await
+            .expect("oversized frame should be rejected without waiting");
+        let err = result.unwrap_err();
+        assert!(matches!(err, RustunError::FrameParse(FrameError::TooLong)));
+        assert!(conn.input_stream.len() <= conn.max_frame_size());
+    }
+
+    #[tokio::test]
+    async fn test_resync_after_corrupt_prefix() {
+        let (mut client, server) = pair().await;
+        let mut conn = TcpConnection::from_socket(server);
+
+        let payload = vec![0x45; 64];
+        let frame = Parser::marshal(
+            Frame::Data(crate::codec::frame::DataFrame {
+                payload: payload.clone(),
+                seq: None,
+            }),
+            &PlainBlock::new(),
+        )
+        .unwrap();
+
+        // Garbage, a partial magic and a header with a bad version
+        let mut buf = vec![0xde, 0xad, 0xbe, 0xef, 0x00, 0x91, 0x92, 0x93];
+        buf.extend_from_slice(&[0x91, 0x92, 0x93, 0x94, 0x7f, 0x03, 0x00, 0x04]);
+        buf.extend_from_slice(&frame);
+        client.write_all(&buf).await.unwrap();
+
+        let frame = timeout(Duration::from_secs(1), conn.read_frame())
+            .await
+            .expect("resync should not stall")
+            .unwrap();
+        match frame {
+            Frame::Data(frame) => assert_eq!(frame.payload, payload),
+            frame => panic!("unexpected frame {frame}"),
+        }
+        assert!(conn.input_stream.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_header_closes_without_resync() {
+        let (mut client, server) = pair().await;
+        let mut conn = TcpConnection::from_socket(server);
+        conn.set_resync(false);
+
+        let mut buf = vec![0xde, 0xad, 0xbe, 0xef, 0x01, 0x03, 0x00, 0x00];
+        buf.extend_from_slice(&header(3, 0));
+        client.write_all(&buf).await.unwrap();
+
+        let err = conn.read_frame().await.unwrap_err();
+        assert!(matches!(err, RustunError::FrameParse(FrameError::Invalid)));
+    }
+
+    #[tokio::test]
+    async fn test_undecodable_frame_is_skipped() {
+        let (mut client, server) = pair().await;
+        let mut conn = TcpConnection::from_socket(server);
+
+        // Valid header for a handshake whose payload is not JSON
+        let mut buf = header(1, 4);
+        buf.extend_from_slice(b"oops");
+        buf.extend_from_slice(
+            &Parser::marshal(
+                Frame::Data(crate::codec::frame::DataFrame {
+                    payload: vec![1, 2, 3],
+                    seq: None,
+                }),
+                &PlainBlock::new(),
+            )
+            .unwrap(),
+        );
+        client.write_all(&buf).await.unwrap();
+
+        match conn.read_frame().await.unwrap() {
+            Frame::Data(frame) => assert_eq!(frame.payload, vec![1, 2, 3]),
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frame_within_limit_is_read() {
+        let (client, server) = pair().await;
+        let mut writer = TcpConnection::from_socket(client);
+        let mut reader = TcpConnection::from_socket(server);
+        reader.set_max_frame_size(2048);
+
+        let payload = vec![0x45; 1400];
+        writer
+            .write_frame(Frame::Data(crate::codec::frame::DataFrame {
+                payload: payload.clone(),
+                seq: None,
+            }))
+            .await
+            .unwrap();
+
+        match reader.read_frame().await.unwrap() {
+            Frame::Data(frame) => assert_eq!(frame.payload, payload),
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_frames_delivers_batch_in_order() {
+        use crate::codec::frame::{DataBatchFrame, DataFrame};
+
+        let (client, server) = pair().await;
+        let mut writer = TcpConnection::from_socket(client);
+        let mut reader = TcpConnection::from_socket(server);
+
+        let data = |byte: u8| {
+            Frame::Data(DataFrame {
+                payload: vec![byte; 32],
+                seq: None,
+            })
+        };
+        // Too large to marshal, dropped without failing the others
+        let oversized = Frame::DataBatch(DataBatchFrame {
+            packets: vec![vec![0; 40_000], vec![0; 40_000]],
+        });
+        writer
+            .write_frames(vec![data(1), data(2), oversized, data(3)])
+            .await
+            .unwrap();
+
+        for byte in 1..=3 {
+            match reader.read_frame().await.unwrap() {
+                Frame::Data(frame) => assert_eq!(frame.payload, vec![byte; 32]),
+                frame => panic!("unexpected frame {frame}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_eof_and_timeout_are_distinguished() {
+        let (client, server) = pair().await;
+        let mut conn = TcpConnection::from_socket(server);
+        conn.set_read_timeout(Duration::from_millis(50));
+
+        let err = conn.read_frame().await.unwrap_err();
+        assert!(
+            matches!(err, RustunError::Timeout { op: "read", .. }),
+            "{err}"
+        );
+
+        drop(client);
+        let err = conn.read_frame().await.unwrap_err();
+        assert!(matches!(err, RustunError::Closed), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_split_frame_is_reassembled() {
+        let (mut client, server) = pair().await;
+        let mut conn = TcpConnection::from_socket(server);
+        let buf = Parser::marshal(
+            Frame::Data(crate::codec::frame::DataFrame {
+                payload: vec![7; 32],
+                seq: None,
+            }),
+            &PlainBlock::new(),
+        )
+        .unwrap();
+
+        // header and half the payload first: too short, keep reading
+        client.write_all(&buf[..HDR_LEN + 16]).await.unwrap();
+        let read = tokio::spawn(async move { conn.read_frame().await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        client.write_all(&buf[HDR_LEN + 16..]).await.unwrap();
+
+        match read.await.unwrap().unwrap() {
+            Frame::Data(frame) => assert_eq!(frame.payload, vec![7; 32]),
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_key_mismatch_closes_with_crypto_error() {
+        use crate::crypto::chacha20::ChaCha20Poly1305Block;
+
+        let (client, server) = pair().await;
+        let mut writer = TcpConnection::new(
+            client,
+            Arc::new(Box::new(ChaCha20Poly1305Block::new(&[1; 32]))),
+        );
+        let mut reader = TcpConnection::new(
+            server,
+            Arc::new(Box::new(ChaCha20Poly1305Block::new(&[2; 32]))),
+        );
+
+        writer
+            .write_frame(Frame::Data(crate::codec::frame::DataFrame {
+                payload: vec![0x45; 20],
+                seq: None,
+            }))
+            .await
+            .unwrap();
+        let err = reader.read_frame().await.unwrap_err();
+        assert!(matches!(err, RustunError::Crypto(_)), "{err}");
+
+        // the reader hung up on us
+        let mut buf = [0u8; 1];
+        let n = timeout(Duration::from_secs(1), writer.socket.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+}
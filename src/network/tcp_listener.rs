@@ -1,8 +1,12 @@
+use crate::codec::magic::DEFAULT_MAGIC;
+use crate::codec::parser::MAX_FRAME_LEN;
 use crate::crypto::Block;
 use crate::network::tcp_connection::TcpConnection;
-use crate::network::{ConnManage, Listener};
+use crate::network::{ConnManage, Listener, SocketBuffers, tune_socket};
 use async_trait::async_trait;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::io::ErrorKind;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
@@ -12,12 +16,23 @@ use tokio::sync::mpsc::Receiver;
 /// Default queue size for new connection channel
 const DEFAULT_ON_CONNECTION_QUEUE: usize = 1024;
 
+/// Default listen backlog for pending connections
+pub(crate) const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
 /// TCP listener implementation
 ///
 /// Handles TCP connection acceptance with exponential backoff retry logic.
 pub struct TCPListener {
     /// Address to bind to
     addr: String,
+    /// Maximum number of pending connections queued by the OS
+    backlog: u32,
+    /// Largest frame accepted on each connection
+    max_frame_size: usize,
+    /// Buffer sizes of accepted sockets
+    socket_buffers: SocketBuffers,
+    /// Magic number of the frames on each connection
+    magic: u32,
     /// Underlying tokio TCP listener
     listener: Option<TcpListener>,
     /// Channel sender for broadcasting new connections
@@ -35,17 +50,46 @@ impl TCPListener {
     pub fn new(addr: String, block: Arc<Box<dyn Block>>) -> Self {
         TCPListener {
             addr,
+            backlog: DEFAULT_LISTEN_BACKLOG,
+            max_frame_size: MAX_FRAME_LEN,
+            socket_buffers: SocketBuffers::default(),
+            magic: DEFAULT_MAGIC,
             listener: None,
             on_conn_tx: None,
             block,
         }
     }
 
+    /// Set the listen backlog used when binding
+    pub fn with_backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Set the largest frame accepted on each connection
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Set the send and receive buffer sizes of accepted sockets
+    pub fn with_socket_buffers(mut self, socket_buffers: SocketBuffers) -> Self {
+        self.socket_buffers = socket_buffers;
+        self
+    }
+
+    /// Set the magic number of the frames on each connection
+    pub fn with_magic(mut self, magic: u32) -> Self {
+        self.magic = magic;
+        self
+    }
+
     /// Accept a new TCP connection with exponential backoff
     ///
     /// Retries on transient errors with backoff starting at 1s, doubling
     /// up to 64s before giving up. Only retries on temporary errors like
-    /// too many open files.
+    /// too many open files. Accepted sockets get `TCP_NODELAY` and the
+    /// configured buffer sizes.
     ///
     /// # Returns
     /// - `Ok(TcpStream)` - Accepted connection
@@ -59,7 +103,12 @@ impl TCPListener {
 
         loop {
             match listener.accept().await {
-                Ok((socket, _)) => return Ok(socket),
+                Ok((socket, _)) => {
+                    if let Err(e) = tune_socket(&socket, self.socket_buffers) {
+                        tracing::warn!("failed to tune accepted socket: {e}");
+                    }
+                    return Ok(socket);
+                }
                 Err(err) => {
                     // Only retry on transient errors
                     match err.kind() {
@@ -87,25 +136,62 @@ impl TCPListener {
 
 #[async_trait]
 impl Listener for TCPListener {
+    /// Bind the listening socket without accepting connections yet
+    ///
+    /// Binding the IPv6 unspecified address (`[::]`) is dual-stack and
+    /// accepts IPv4 clients as well.
+    async fn bind(&mut self) -> anyhow::Result<SocketAddr> {
+        let addr = tokio::net::lookup_host(self.addr.as_str())
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("no address resolved for {}", self.addr))?;
+
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        // `[::]` should also accept IPv4 clients regardless of the platform default
+        if addr.is_ipv6() && addr.ip().is_unspecified() {
+            socket.set_only_v6(false)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog as i32)?;
+
+        let listener = TcpListener::from_std(socket.into())?;
+        let local_addr = listener.local_addr()?;
+        self.listener = Some(listener);
+        Ok(local_addr)
+    }
+
     /// Bind to address and start accepting connections
     ///
     /// Runs in a loop, accepting connections and sending them to subscribers
-    /// via the channel. Continues accepting even if sending fails.
+    /// via the channel. Connection setup runs on its own task so a slow
+    /// subscriber never blocks `accept`. Continues accepting even if sending fails.
     async fn listen_and_serve(&mut self) -> anyhow::Result<()> {
-        let listener = TcpListener::bind(self.addr.clone()).await?;
+        if self.listener.is_none() {
+            self.bind().await?;
+        }
         tracing::info!("Server listening on {}", self.addr);
-        self.listener = Some(listener);
 
         loop {
             let socket = self.accept().await;
             match socket {
                 Ok(socket) => {
-                    let conn = TcpConnection::new(socket, self.block.clone());
-                    if let Some(tx) = &self.on_conn_tx
-                        && let Err(e) = tx.send(Box::new(conn)).await
-                    {
-                        tracing::warn!("Failed to send new connection: {e}");
-                    }
+                    let Some(tx) = self.on_conn_tx.clone() else {
+                        continue;
+                    };
+                    let block = self.block.clone();
+                    let max_frame_size = self.max_frame_size;
+                    let magic = self.magic;
+                    tokio::spawn(async move {
+                        let mut conn = TcpConnection::new(socket, block);
+                        conn.set_max_frame_size(max_frame_size);
+                        conn.set_magic(magic);
+                        if let Err(e) = tx.send(Box::new(conn)).await {
+                            tracing::warn!("Failed to send new connection: {e}");
+                        }
+                    });
                 }
                 Err(e) => {
                     tracing::error!("Accept error: {e}");
@@ -135,3 +221,87 @@ impl Listener for TCPListener {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::plain::PlainBlock;
+
+    #[tokio::test]
+    async fn test_concurrent_connections_are_delivered() {
+        let mut listener = TCPListener::new(
+            "127.0.0.1:0".to_string(),
+            Arc::new(Box::new(PlainBlock::new())),
+        )
+        .with_backlog(128);
+        let addr = listener.bind().await.unwrap();
+        let mut on_conn_rx = listener.subscribe_on_conn().await.unwrap();
+        tokio::spawn(async move {
+            let _ = listener.listen_and_serve().await;
+        });
+
+        const CLIENTS: usize = 64;
+        let mut clients = Vec::new();
+        for _ in 0..CLIENTS {
+            clients.push(tokio::spawn(TcpStream::connect(addr)));
+        }
+        let mut streams = Vec::new();
+        for client in clients {
+            streams.push(client.await.unwrap().unwrap());
+        }
+
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut count = 0;
+            while count < CLIENTS {
+                on_conn_rx.recv().await.unwrap();
+                count += 1;
+            }
+            count
+        })
+        .await
+        .expect("all connections should be delivered");
+        assert_eq!(received, CLIENTS);
+    }
+
+    #[tokio::test]
+    async fn test_accepted_socket_is_tuned() {
+        let mut listener = TCPListener::new(
+            "127.0.0.1:0".to_string(),
+            Arc::new(Box::new(PlainBlock::new())),
+        )
+        .with_socket_buffers(SocketBuffers {
+            send: Some(128 * 1024),
+            recv: None,
+        });
+        let addr = listener.bind().await.unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let accepted = listener.accept().await.unwrap();
+        assert!(accepted.nodelay().unwrap());
+        assert!(
+            socket2::SockRef::from(&accepted)
+                .send_buffer_size()
+                .unwrap()
+                >= 128 * 1024
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unspecified_ipv6_accepts_ipv4_and_ipv6() {
+        let mut listener =
+            TCPListener::new("[::]:0".to_string(), Arc::new(Box::new(PlainBlock::new())));
+        let port = listener.bind().await.unwrap().port();
+        let mut on_conn_rx = listener.subscribe_on_conn().await.unwrap();
+        tokio::spawn(async move {
+            let _ = listener.listen_and_serve().await;
+        });
+
+        for ip in ["127.0.0.1", "::1"] {
+            let addr = SocketAddr::new(ip.parse().unwrap(), port);
+            let _stream = TcpStream::connect(addr).await.unwrap();
+            tokio::time::timeout(Duration::from_secs(5), on_conn_rx.recv())
+                .await
+                .expect("connection should be delivered")
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Client authentication backends
+//!
+//! The handler asks an `AuthBackend` for the configuration of every client
+//! that completes a handshake. `ClientManager` is the default in-memory
+//! backend; `HttpAuthBackend` delegates the decision to an external service
+//! (LDAP/OIDC/database gateways and the like).
+
+use crate::server::client_manager::{ClientConfig, ClientManager};
+use crate::server::config::AuthConfig;
+use async_trait::async_trait;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Decides whether a client may join and with which configuration
+#[async_trait]
+pub trait AuthBackend: Send + Sync {
+    /// Authenticate a client handshake
+    ///
+    /// # Arguments
+    /// - `identity` - Identity sent in the handshake
+    /// - `token` - Credential sent in the handshake, if any
+    ///
+    /// # Returns
+    /// - `Some(ClientConfig)` - Access granted with this configuration
+    /// - `None` - Access denied
+    async fn authenticate(&self, identity: &str, token: Option<&str>) -> Option<ClientConfig>;
+}
+
+/// Static in-memory authentication: any configured identity is granted
+#[async_trait]
+impl AuthBackend for ClientManager {
+    async fn authenticate(&self, identity: &str, _token: Option<&str>) -> Option<ClientConfig> {
+        self.get_client(&identity.to_string())
+    }
+}
+
+/// Request body posted to the authentication endpoint
+#[derive(Serialize, Debug)]
+struct AuthRequest<'a> {
+    identity: &'a str,
+    token: Option<&'a str>,
+}
+
+/// Authentication against an HTTP endpoint
+///
+/// Posts `{"identity": ..., "token": ...}` to the configured URL. A `200`
+/// response carrying a `ClientConfig` JSON body grants access; any other
+/// status, an unreadable body or a request failure denies it.
+pub struct HttpAuthBackend {
+    /// Authentication endpoint URL
+    url: String,
+    /// Bearer token sent to the endpoint
+    api_token: Option<String>,
+    /// Deadline for the whole request
+    timeout: Duration,
+    client: reqwest::Client,
+}
+
+impl HttpAuthBackend {
+    pub fn new(config: &AuthConfig) -> Self {
+        Self {
+            url: config.url.clone(),
+            api_token: config.api_token.clone(),
+            timeout: Duration::from_secs(config.timeout),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    async fn request(&self, identity: &str, token: Option<&str>) -> anyhow::Result<ClientConfig> {
+        let body = serde_json::to_vec(&AuthRequest { identity, token })?;
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(body)
+            .timeout(self.timeout);
+        if let Some(api_token) = &self.api_token {
+            request = request.bearer_auth(api_token);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        if status != 200 {
+            anyhow::bail!("auth endpoint returned {status}");
+        }
+        let body = response.bytes().await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+#[async_trait]
+impl AuthBackend for HttpAuthBackend {
+    async fn authenticate(&self, identity: &str, token: Option<&str>) -> Option<ClientConfig> {
+        match self.request(identity, token).await {
+            Ok(config) if config.identity == identity => Some(config),
+            Ok(config) => {
+                tracing::warn!(
+                    "auth endpoint answered {} for {identity}, denying",
+                    config.identity
+                );
+                None
+            }
+            Err(e) => {
+                tracing::debug!("{identity} denied by auth endpoint: {e}");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Json, Router, http::StatusCode, routing::post};
+
+    /// Endpoint granting identity "a" with token "secret"
+    async fn auth_endpoint(
+        Json(body): Json<serde_json::Value>,
+    ) -> Result<Json<ClientConfig>, StatusCode> {
+        if body["identity"] == "a" && body["token"] == "secret" {
+            Ok(Json(ClientConfig::for_test("a", "10.0.0.1")))
+        } else {
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_backend_grants_and_denies() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/auth", post(auth_endpoint));
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let backend = HttpAuthBackend::new(&AuthConfig {
+            url: format!("http://{addr}/auth"),
+            api_token: None,
+            timeout: 5,
+        });
+        let granted = backend.authenticate("a", Some("secret")).await.unwrap();
+        assert_eq!(granted.private_ip, "10.0.0.1");
+        assert!(backend.authenticate("a", Some("wrong")).await.is_none());
+        assert!(backend.authenticate("a", None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_manager_backend() {
+        let manager = ClientManager::new();
+        manager.add_clients_config(vec![ClientConfig::for_test("a", "10.0.0.1")]);
+        assert!(manager.authenticate("a", None).await.is_some());
+        assert!(manager.authenticate("b", None).await.is_none());
+    }
+}
@@ -1,6 +1,7 @@
+use crate::network::connection_manager::ConnectionManager;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ClientConfig {
@@ -17,6 +18,23 @@ pub struct ClientConfig {
     pub cider_mapping: HashMap<String, String>,
 }
 
+#[cfg(test)]
+impl ClientConfig {
+    /// Client `identity` at `private_ip` in cluster "test", routing no CIDRs
+    pub(crate) fn for_test(identity: &str, private_ip: &str) -> Self {
+        Self {
+            name: identity.to_string(),
+            cluster: "test".to_string(),
+            identity: identity.to_string(),
+            private_ip: private_ip.to_string(),
+            mask: "255.255.255.0".to_string(),
+            gateway: "10.0.0.254".to_string(),
+            ciders: vec![],
+            cider_mapping: HashMap::new(),
+        }
+    }
+}
+
 pub struct ClientManager {
     /// clients
     /// - key: client identity
@@ -27,6 +45,15 @@ pub struct ClientManager {
     /// - key: cluster
     /// - value: clients at the same cluster
     cluster_clients: RwLock<HashMap<String, Vec<ClientConfig>>>,
+
+    /// Clients granted by an external auth backend, kept apart from the
+    /// configuration so rewrites leave them in place
+    /// - key: client identity
+    /// - value: configuration the backend answered
+    registered: RwLock<HashMap<String, ClientConfig>>,
+
+    /// Live connections, drained of clients removed by a config rewrite
+    connection_manager: Option<Arc<ConnectionManager>>,
 }
 
 impl ClientManager {
@@ -34,9 +61,18 @@ impl ClientManager {
         Self {
             clients: RwLock::new(HashMap::new()),
             cluster_clients: RwLock::new(HashMap::new()),
+            registered: RwLock::new(HashMap::new()),
+            connection_manager: None,
         }
     }
 
+    /// Disconnect clients from `connection_manager` once they are removed
+    /// from the configuration
+    pub fn with_connection_manager(mut self, connection_manager: Arc<ConnectionManager>) -> Self {
+        self.connection_manager = Some(connection_manager);
+        self
+    }
+
     pub fn add_clients_config(&self, clients: Vec<ClientConfig>) {
         let mut clients_map = self.clients.write().unwrap_or_else(|e| e.into_inner());
 
@@ -55,6 +91,10 @@ impl ClientManager {
         }
     }
 
+    /// Replace the whole configuration
+    ///
+    /// Live connections of identities missing from `clients` are torn down
+    /// so deauthorized clients stop routing traffic.
     pub fn rewrite_clients_config(&self, clients: Vec<ClientConfig>) {
         let mut clients_map = self.clients.write().unwrap_or_else(|e| e.into_inner());
         let mut cluster_map = self
@@ -73,41 +113,97 @@ impl ClientManager {
                 .push(client.clone());
         }
 
+        let removed: Vec<String> = clients_map
+            .keys()
+            .filter(|identity| !new_clients_map.contains_key(*identity))
+            .cloned()
+            .collect();
         *clients_map = new_clients_map;
         *cluster_map = new_cluster_map;
+        drop(cluster_map);
+        drop(clients_map);
+
+        if let Some(connection_manager) = &self.connection_manager {
+            for identity in removed {
+                if connection_manager.disconnect(&identity) > 0 {
+                    tracing::info!("{identity} removed from config, connection drained");
+                }
+            }
+        }
     }
 
-    pub fn get_cluster_clients_exclude(&self, identity: &String) -> Vec<ClientConfig> {
+    /// Record a client an external auth backend granted
+    ///
+    /// Its peers then learn about it the way they do about configured
+    /// clients. Configured identities are left to the configuration.
+    pub fn register_client(&self, client: ClientConfig) {
         let clients_map = self.clients.read().unwrap_or_else(|e| e.into_inner());
+        if clients_map.contains_key(&client.identity) {
+            return;
+        }
+        tracing::debug!("register client {client:?}");
+        self.registered
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(client.identity.clone(), client);
+    }
 
-        let cluster = match clients_map.get(identity) {
-            Some(client) => &client.cluster,
-            None => return Vec::new(),
-        };
-
+    /// Configured and registered clients of `cluster` other than `identity`
+    pub fn get_cluster_clients_exclude(
+        &self,
+        cluster: &str,
+        identity: &String,
+    ) -> Vec<ClientConfig> {
+        let clients_map = self.clients.read().unwrap_or_else(|e| e.into_inner());
         let cluster_map = self
             .cluster_clients
             .read()
             .unwrap_or_else(|e| e.into_inner());
+        let registered = self.registered.read().unwrap_or_else(|e| e.into_inner());
 
-        cluster_map
-            .get(cluster)
-            .map(|clients| {
-                clients
-                    .iter()
-                    .filter(|c| c.identity != *identity)
-                    .cloned()
-                    .collect()
-            })
-            .unwrap_or_default()
+        let configured = cluster_map.get(cluster).into_iter().flatten();
+        let granted = registered
+            .values()
+            .filter(|c| c.cluster == cluster && !clients_map.contains_key(&c.identity));
+        configured
+            .chain(granted)
+            .filter(|c| c.identity != *identity)
+            .cloned()
+            .collect()
+    }
+
+    /// Every configured or registered client, sorted by cluster then
+    /// identity
+    pub fn list_clients(&self) -> Vec<ClientConfig> {
+        let clients_map = self.clients.read().unwrap_or_else(|e| e.into_inner());
+        let registered = self.registered.read().unwrap_or_else(|e| e.into_inner());
+        let mut result: Vec<ClientConfig> = clients_map
+            .values()
+            .chain(
+                registered
+                    .values()
+                    .filter(|c| !clients_map.contains_key(&c.identity)),
+            )
+            .cloned()
+            .collect();
+        result.sort_by(|a, b| (&a.cluster, &a.identity).cmp(&(&b.cluster, &b.identity)));
+        result
     }
 
     pub fn get_client(&self, identity: &String) -> Option<ClientConfig> {
-        self.clients
+        let configured = self
+            .clients
             .read()
             .unwrap_or_else(|e| e.into_inner())
             .get(identity)
-            .cloned()
+            .cloned();
+        configured.or_else(|| {
+            self.registered
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .get(identity)
+                .cloned()
+        })
     }
 }
 
@@ -116,3 +212,76 @@ impl Default for ClientManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::frame::Frame;
+    use crate::network::ConnectionMeta;
+    use tokio::sync::mpsc;
+    use tokio::sync::mpsc::error::TryRecvError;
+
+    #[test]
+    fn test_rewrite_drains_removed_clients() {
+        let connection_manager = Arc::new(ConnectionManager::new());
+        let manager = ClientManager::new().with_connection_manager(connection_manager.clone());
+        let (a, b) = (
+            ClientConfig::for_test("a", "10.0.0.1"),
+            ClientConfig::for_test("b", "10.0.0.2"),
+        );
+        manager.add_clients_config(vec![a.clone(), b.clone()]);
+
+        let (tx_a, mut rx_a) = mpsc::channel(8);
+        let (tx_b, mut rx_b) = mpsc::channel(8);
+        connection_manager
+            .add_connection(ConnectionMeta::for_test("a", "10.0.0.1", tx_a))
+            .unwrap();
+        connection_manager
+            .add_connection(ConnectionMeta::for_test("b", "10.0.0.2", tx_b))
+            .unwrap();
+
+        manager.rewrite_clients_config(vec![a]);
+
+        assert!(
+            rx_b.try_recv()
+                .is_err_and(|e| e == TryRecvError::Disconnected)
+        );
+        assert!(matches!(rx_a.try_recv(), Ok(Frame::PeerLeave(leave)) if leave.identity == "b"));
+        assert!(manager.get_client(&"b".to_string()).is_none());
+        let identities: Vec<_> = connection_manager
+            .list_connections()
+            .into_iter()
+            .map(|c| c.identity)
+            .collect();
+        assert_eq!(identities, vec!["a"]);
+    }
+
+    #[test]
+    fn test_registered_clients_listed_as_peers() {
+        let manager = ClientManager::new();
+        manager.add_clients_config(vec![ClientConfig::for_test("a", "10.0.0.1")]);
+        manager.register_client(ClientConfig::for_test("b", "10.0.0.2"));
+
+        let peers = |identity: &str| -> Vec<String> {
+            manager
+                .get_cluster_clients_exclude("test", &identity.to_string())
+                .into_iter()
+                .map(|c| c.identity)
+                .collect()
+        };
+        assert_eq!(peers("a"), vec!["b"]);
+        assert_eq!(peers("b"), vec!["a"]);
+
+        // a rewrite of the configuration keeps what the backend granted
+        manager.rewrite_clients_config(vec![ClientConfig::for_test("a", "10.0.0.1")]);
+        assert_eq!(peers("a"), vec!["b"]);
+
+        // configured clients are not registered over
+        manager.register_client(ClientConfig::for_test("a", "10.0.0.9"));
+        assert_eq!(
+            manager.get_client(&"a".to_string()).unwrap().private_ip,
+            "10.0.0.1"
+        );
+        assert_eq!(manager.list_clients().len(), 2);
+    }
+}
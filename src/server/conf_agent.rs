@@ -1,12 +1,13 @@
 use crate::network::connection_manager::ConnectionManager;
 use crate::server::client_manager::{ClientConfig, ClientManager};
-use crate::server::config::ConfAgentConfig;
+use crate::server::config::{self, ConfAgentConfig};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::fs;
-use tokio::time::{Duration, interval};
+use tokio::sync::oneshot;
+use tokio::time::{Duration, interval, interval_at};
 
 /// Connection update request for backend API
 #[derive(Serialize, Debug)]
@@ -53,22 +54,27 @@ impl ConfAgent {
     }
 
     /// Start the conf-agent service
-    pub async fn start(self: Arc<Self>) -> anyhow::Result<()> {
+    ///
+    /// `ready` fires once the initial route fetch is done, see
+    /// `initial_fetch`; the server only accepts clients from then on so
+    /// none is refused for routes not fetched yet.
+    pub async fn start(self: Arc<Self>, ready: oneshot::Sender<()>) -> anyhow::Result<()> {
         tracing::info!("Starting conf-agent");
         tracing::info!("Control plane URL: {}", self.config.control_plane_url);
         tracing::info!("Routes file: {}", self.config.routes_file);
         tracing::info!("Poll interval: {}s", self.config.poll_interval);
 
         // Initial fetch and report
-        if let Err(e) = self.fetch_and_update_routes().await {
-            tracing::error!("Failed to fetch routes: {e:?}");
-        }
+        self.initial_fetch().await;
+        let _ = ready.send(());
         if let Err(e) = self.report_connections().await {
             tracing::error!("Failed to report connections: {e:?}");
         }
 
         // Periodic tasks: route fetching and connection reporting
-        let mut route_ticker = interval(Duration::from_secs(self.config.poll_interval));
+        let poll_interval = Duration::from_secs(self.config.poll_interval);
+        let mut route_ticker =
+            interval_at(tokio::time::Instant::now() + poll_interval, poll_interval);
         let mut report_ticker = interval(Duration::from_secs(self.config.report_interval));
 
         loop {
@@ -87,6 +93,46 @@ impl ConfAgent {
         }
     }
 
+    /// Fetch the routes, retrying up to `initial_fetch_attempts` times
+    ///
+    /// Once every attempt failed the routes file is loaded instead, and
+    /// stays in use until the control plane answers a periodic fetch.
+    ///
+    /// # Returns
+    /// Whether the routes came from the control plane
+    async fn initial_fetch(&self) -> bool {
+        let attempts = self.config.initial_fetch_attempts.max(1);
+        let retry = Duration::from_secs(self.config.initial_fetch_retry);
+        for attempt in 1..=attempts {
+            match self.fetch_and_update_routes().await {
+                Ok(()) => return true,
+                Err(e) if attempt < attempts => {
+                    tracing::warn!(
+                        "Failed to fetch routes ({attempt}/{attempts}), retrying in {retry:?}: {e:?}"
+                    );
+                    tokio::time::sleep(retry).await;
+                }
+                Err(e) => tracing::error!("Failed to fetch routes ({attempt}/{attempts}): {e:?}"),
+            }
+        }
+
+        match config::load_routes(&self.routes_file) {
+            Ok(routes) => {
+                tracing::warn!(
+                    "Control plane unreachable, serving {} routes from {}",
+                    routes.len(),
+                    self.routes_file
+                );
+                self.client_manager.rewrite_clients_config(routes);
+            }
+            Err(e) => tracing::error!(
+                "Control plane unreachable and routes file {} unreadable: {e:?}",
+                self.routes_file
+            ),
+        }
+        false
+    }
+
     /// Report connections from connection manager
     async fn report_connections(&self) -> anyhow::Result<()> {
         // Get connections from connection manager
@@ -249,3 +295,104 @@ async fn http_json(
     };
     Ok(body)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Json, Router, http::StatusCode, routing::get};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn client_config(identity: &str) -> ClientConfig {
+        ClientConfig {
+            cluster: "1".to_string(),
+            ..ClientConfig::for_test(identity, "10.0.0.1")
+        }
+    }
+
+    /// Control plane failing its first `failures` route fetches, then
+    /// granting identity "fetched"
+    async fn control_plane(failures: usize) -> (String, Arc<AtomicUsize>) {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let app = Router::new().route(
+            "/api/sync/clients",
+            get(move || {
+                let counter = counter.clone();
+                async move {
+                    if counter.fetch_add(1, Ordering::SeqCst) < failures {
+                        return Err(StatusCode::SERVICE_UNAVAILABLE);
+                    }
+                    Ok(Json(vec![client_config("fetched")]))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        (format!("http://{addr}"), fetches)
+    }
+
+    /// Agent over a routes file granting identity "on-disk"
+    fn agent(url: String, attempts: u32, name: &str) -> (Arc<ConfAgent>, Arc<ClientManager>) {
+        let routes_file = std::env::temp_dir()
+            .join(format!("rustun-routes-{name}-{}.json", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        std::fs::write(
+            &routes_file,
+            serde_json::to_string(&vec![client_config("on-disk")]).unwrap(),
+        )
+        .unwrap();
+        let client_manager = Arc::new(ClientManager::new());
+        let agent = ConfAgent::new(
+            ConfAgentConfig {
+                control_plane_url: url,
+                api_token: None,
+                routes_file: routes_file.clone(),
+                poll_interval: 3600,
+                report_interval: 3600,
+                initial_fetch_attempts: attempts,
+                initial_fetch_retry: 0,
+            },
+            client_manager.clone(),
+            Arc::new(ConnectionManager::new()),
+            routes_file,
+        );
+        (Arc::new(agent), client_manager)
+    }
+
+    #[tokio::test]
+    async fn test_ready_waits_for_control_plane_to_recover() {
+        let (url, fetches) = control_plane(2).await;
+        let (agent, client_manager) = agent(url, 5, "recover");
+        let (ready_tx, ready_rx) = oneshot::channel();
+        tokio::spawn(agent.clone().start(ready_tx));
+
+        tokio::time::timeout(Duration::from_secs(5), ready_rx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+        assert!(client_manager.get_client(&"fetched".to_string()).is_some());
+        let _ = std::fs::remove_file(&agent.routes_file);
+    }
+
+    #[tokio::test]
+    async fn test_ready_falls_back_to_routes_file() {
+        let (url, fetches) = control_plane(usize::MAX).await;
+        let (agent, client_manager) = agent(url, 3, "fallback");
+        let (ready_tx, ready_rx) = oneshot::channel();
+        tokio::spawn(agent.clone().start(ready_tx));
+
+        tokio::time::timeout(Duration::from_secs(5), ready_rx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+        assert!(client_manager.get_client(&"on-disk".to_string()).is_some());
+        assert!(client_manager.get_client(&"fetched".to_string()).is_none());
+        let _ = std::fs::remove_file(&agent.routes_file);
+    }
+}
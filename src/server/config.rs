@@ -1,23 +1,105 @@
+use crate::codec::frame::IpValidation;
+use crate::codec::magic::MagicSource;
 use crate::crypto::CryptoConfig;
+use crate::network::connection_manager::RoutePolicy;
 use crate::server::client_manager::ClientConfig;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub server_config: ServerConfig,
     pub crypto_config: CryptoConfig,
+    /// Keys of clusters not using `crypto_config` after the handshake
+    #[serde(default)]
+    pub cluster_crypto: HashMap<String, CryptoConfig>,
     pub route_config: RouteConfig,
     #[serde(default)]
     pub conf_agent: Option<ConfAgentConfig>,
+    /// External authentication endpoint (default: clients from the routes file)
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ServerConfig {
     pub listen_addr: String,
+    /// Listen backlog for pending connections (default: 1024)
+    #[serde(default = "default_listen_backlog")]
+    pub listen_backlog: u32,
+    /// Maximum number of concurrent client connections (default: unlimited)
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Largest frame in bytes accepted from a client (default: 262154)
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+    /// Frames buffered per client while it reconnects (default: 0, disabled)
+    #[serde(default)]
+    pub offline_buffer_size: usize,
+    /// Seconds buffered frames are kept for a reconnecting client (default: 5)
+    #[serde(default = "default_offline_buffer_ttl")]
+    pub offline_buffer_ttl: u64,
+    /// Seconds a new connection has to send its handshake (default: 10)
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: u64,
+    /// Seconds without a keepalive after which a client is treated as
+    /// offline for routing (default: 0, disabled)
+    #[serde(default)]
+    pub peer_ttl: u64,
+    /// How clients advertising the same CIDR share it (default: first)
+    #[serde(default)]
+    pub route_policy: RoutePolicy,
+    /// Seconds a disconnected client may resume its session, getting only
+    /// the peer list changes (default: 30, 0 disables)
+    #[serde(default = "default_resume_ttl")]
+    pub resume_ttl: u64,
+    /// CIDRs reachable from every cluster, e.g. shared DNS or monitoring
+    /// (default: none)
+    #[serde(default)]
+    pub global_cidrs: Vec<String>,
+    /// Checks tunneled packets must pass to be routed (default: basic)
+    #[serde(default)]
+    pub ip_validation: IpValidation,
+    /// Grant clients asking for a TAP tunnel, switching their Ethernet
+    /// frames by MAC (default: false, every client gets a TUN tunnel)
+    #[serde(default)]
+    pub allow_tap: bool,
+    /// `SO_SNDBUF` of client sockets in bytes (OS default if not specified)
+    #[serde(default)]
+    pub send_buffer_size: Option<usize>,
+    /// `SO_RCVBUF` of client sockets in bytes (OS default if not specified)
+    #[serde(default)]
+    pub recv_buffer_size: Option<usize>,
+    /// HTTP metrics server port on 127.0.0.1 (disabled if not specified)
+    #[serde(default)]
+    pub http_port: Option<u16>,
+    /// Bearer token of the HTTP admin endpoints (disabled if not specified)
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// Magic number starting every frame: `default`, `key` (derived from
+    /// `crypto_config`) or a number like `"0x1a2b3c4d"`, clients must use the
+    /// same (default: default)
+    #[serde(default)]
+    pub frame_magic: MagicSource,
+    /// NDJSON audit trail of routed data frames (disabled if not specified)
+    #[serde(default)]
+    pub access_log: Option<AccessLogConfig>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AccessLogConfig {
+    /// File the records are appended to
+    pub path: String,
+    /// Log one data frame in this many (default: 1, every frame)
+    #[serde(default = "default_access_log_sample")]
+    pub sample: u32,
+    /// Records written per second at most (default: unlimited)
+    #[serde(default)]
+    pub max_per_second: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ConfAgentConfig {
     /// Control plane API URL
     pub control_plane_url: String,
@@ -26,12 +108,59 @@ pub struct ConfAgentConfig {
     pub api_token: Option<String>,
     /// Routes file path to update
     pub routes_file: String,
-    /// Poll interval in seconds for fetching routes
+    /// Poll interval in seconds for fetching routes (default: 60)
     #[serde(default = "default_poll_interval")]
     pub poll_interval: u64,
     /// Connection reporting interval in seconds (default: 30)
     #[serde(default = "default_report_interval")]
     pub report_interval: u64,
+    /// Route fetches tried at startup before clients are served from the
+    /// routes file instead (default: 5)
+    #[serde(default = "default_initial_fetch_attempts")]
+    pub initial_fetch_attempts: u32,
+    /// Seconds between those startup fetches (default: 2)
+    #[serde(default = "default_initial_fetch_retry")]
+    pub initial_fetch_retry: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AuthConfig {
+    /// Endpoint receiving `{"identity", "token"}` and answering a client config
+    pub url: String,
+    /// API token sent as bearer authorization
+    #[serde(default)]
+    pub api_token: Option<String>,
+    /// Request timeout in seconds (default: 10)
+    #[serde(default = "default_auth_timeout")]
+    pub timeout: u64,
+}
+
+fn default_access_log_sample() -> u32 {
+    1
+}
+
+fn default_listen_backlog() -> u32 {
+    1024
+}
+
+fn default_max_frame_size() -> usize {
+    crate::codec::parser::MAX_FRAME_LEN
+}
+
+fn default_offline_buffer_ttl() -> u64 {
+    5
+}
+
+fn default_handshake_timeout() -> u64 {
+    10
+}
+
+fn default_resume_ttl() -> u64 {
+    30
+}
+
+fn default_auth_timeout() -> u64 {
+    10
 }
 
 fn default_poll_interval() -> u64 {
@@ -42,14 +171,131 @@ fn default_report_interval() -> u64 {
     30
 }
 
-#[derive(Debug, Deserialize)]
+fn default_initial_fetch_attempts() -> u32 {
+    5
+}
+
+fn default_initial_fetch_retry() -> u64 {
+    2
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct RouteConfig {
     pub routes_file: String,
 }
 
+/// Keys whose values are never logged
+const SECRET_KEYS: [&str; 4] = [
+    "crypto_config",
+    "cluster_crypto",
+    "api_token",
+    "admin_token",
+];
+
+impl Config {
+    /// Check values serde accepts but the server cannot run with
+    ///
+    /// # Returns
+    /// `Err` listing every problem found, not just the first
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut errors = Vec::new();
+        let server = &self.server_config;
+        if server.listen_addr.trim().is_empty() {
+            errors.push("server_config.listen_addr is empty".to_string());
+        }
+        if server.max_frame_size == 0 {
+            errors.push("server_config.max_frame_size must be positive".to_string());
+        }
+        if server.handshake_timeout == 0 {
+            errors.push("server_config.handshake_timeout must be positive".to_string());
+        }
+        if let Err(e) = server.frame_magic.resolve(&self.crypto_config) {
+            errors.push(format!("server_config.frame_magic: {e}"));
+        }
+        if let Some(log) = &server.access_log {
+            if log.path.trim().is_empty() {
+                errors.push("server_config.access_log.path is empty".to_string());
+            }
+            if log.sample == 0 {
+                errors.push("server_config.access_log.sample must be positive".to_string());
+            }
+            if log.max_per_second == Some(0) {
+                errors.push("server_config.access_log.max_per_second must be positive".to_string());
+            }
+        }
+        for cidr in &server.global_cidrs {
+            if let Err(e) = cidr.parse::<ipnet::IpNet>() {
+                errors.push(format!(
+                    "server_config.global_cidrs: invalid CIDR {cidr:?}: {e}"
+                ));
+            }
+        }
+        if self.route_config.routes_file.trim().is_empty() {
+            errors.push("route_config.routes_file is empty".to_string());
+        }
+        if let Some(agent) = &self.conf_agent {
+            if agent.control_plane_url.trim().is_empty() {
+                errors.push("conf_agent.control_plane_url is empty".to_string());
+            }
+            if agent.poll_interval == 0 {
+                errors.push("conf_agent.poll_interval must be positive".to_string());
+            }
+            if agent.report_interval == 0 {
+                errors.push("conf_agent.report_interval must be positive".to_string());
+            }
+        }
+        if let Some(auth) = &self.auth {
+            if auth.url.trim().is_empty() {
+                errors.push("auth.url is empty".to_string());
+            }
+            if auth.timeout == 0 {
+                errors.push("auth.timeout must be positive".to_string());
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => anyhow::bail!("invalid config: {}", errors.join("; ")),
+        }
+    }
+
+    /// The configuration in effect, defaults filled in and secrets masked
+    pub fn effective(&self) -> anyhow::Result<String> {
+        let mut value = toml::Value::try_from(self)?;
+        mask_secrets(&mut value);
+        Ok(toml::to_string(&value)?)
+    }
+}
+
+/// Replace the strings under `SECRET_KEYS`, keeping unkeyed ciphers visible
+fn mask_secrets(value: &mut toml::Value) {
+    let Some(table) = value.as_table_mut() else {
+        return;
+    };
+    for (key, value) in table.iter_mut() {
+        match SECRET_KEYS.contains(&key.as_str()) {
+            true => mask_strings(value),
+            false => mask_secrets(value),
+        }
+    }
+}
+
+fn mask_strings(value: &mut toml::Value) {
+    match value {
+        toml::Value::String(secret) => *secret = "***".to_string(),
+        toml::Value::Table(table) => table.iter_mut().for_each(|(_, value)| mask_strings(value)),
+        _ => {}
+    }
+}
+
+/// Parse and validate the server configuration file
 pub fn load_main(path: &str) -> anyhow::Result<Config> {
     let content = fs::read_to_string(path)?;
-    let config: Config = toml::from_str(&content)?;
+    parse_main(&content)
+}
+
+fn parse_main(content: &str) -> anyhow::Result<Config> {
+    let config: Config = toml::from_str(content)?;
+    config.validate()?;
     Ok(config)
 }
 
@@ -58,3 +304,55 @@ pub fn load_routes(path: &str) -> anyhow::Result<Vec<ClientConfig>> {
     let clients: Vec<ClientConfig> = serde_json::from_str(&content)?;
     Ok(clients)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL: &str = r#"
+[server_config]
+listen_addr = "0.0.0.0:8080"
+
+[crypto_config]
+chacha20poly1305 = "rustun"
+
+[route_config]
+routes_file = "./etc/routes.json"
+"#;
+
+    #[test]
+    fn test_minimal_config_gets_defaults() {
+        let config = parse_main(MINIMAL).unwrap();
+        let server = &config.server_config;
+        assert_eq!(server.listen_backlog, 1024);
+        assert_eq!(server.max_frame_size, crate::codec::parser::MAX_FRAME_LEN);
+        assert_eq!(server.handshake_timeout, 10);
+        assert_eq!(server.resume_ttl, 30);
+        assert_eq!(server.ip_validation, IpValidation::Basic);
+        assert!(config.conf_agent.is_none() && config.auth.is_none());
+
+        let effective = config.effective().unwrap();
+        assert!(effective.contains("listen_backlog = 1024"), "{effective}");
+        assert!(!effective.contains("rustun"), "{effective}");
+    }
+
+    #[test]
+    fn test_invalid_config_reports_every_error() {
+        let content = MINIMAL.replace(r#""0.0.0.0:8080""#, r#""""#)
+            + r#"
+[conf_agent]
+control_plane_url = "https://control.example.com"
+routes_file = "./etc/routes.json"
+poll_interval = 0
+"#;
+        let content = content.replace(
+            "[crypto_config]",
+            "global_cidrs = [\"10.0.0.0/33\"]\n\n[crypto_config]",
+        );
+        let err = parse_main(&content).unwrap_err().to_string();
+        assert!(err.contains("listen_addr is empty"), "{err}");
+        assert!(err.contains("invalid CIDR \"10.0.0.0/33\""), "{err}");
+        assert!(err.contains("poll_interval must be positive"), "{err}");
+        assert!(!err.contains("report_interval"), "{err}");
+    }
+}
@@ -1,17 +1,44 @@
 use crate::codec::frame::Frame::HandshakeReply;
 use crate::codec::frame::{
-    DataFrame, Frame, HandshakeFrame, HandshakeReplyFrame, KeepAliveFrame, PeerDetail,
+    DataFrame, Frame, HandshakeFrame, HandshakeRejectFrame, HandshakeReplyFrame, IpValidation,
+    KeepAliveFrame, PeerDetail, PeerJoinFrame, PeerLeaveFrame, PeerUpdateFrame,
+    REJECT_UNAUTHORIZED, RekeyFrame, TunnelMode, format_mac,
 };
+use crate::codec::magic::DEFAULT_MAGIC;
+use crate::codec::parser::{ECHO_VERSION, IPV6_LIST_VERSION, MAX_VERSION, negotiate_version};
+use crate::codec::stats::FrameStats;
 use crate::crypto::Block;
+use crate::crypto::plain::PlainBlock;
+use crate::crypto::rekey::{RekeyPolicy, Rekeyer};
+use crate::error::RustunError;
 use crate::network::ConnectionMeta;
-use crate::network::connection_manager::ConnectionManager;
-use crate::network::{ConnManage, ListenerConfig, TCPListenerConfig, create_listener};
-use crate::server::client_manager::ClientManager;
+use crate::network::connection_manager::{ConnectionManager, notify_peers};
+use crate::network::{
+    ConnManage, Listener, ListenerConfig, MAX_WRITE_BATCH, SocketBuffers, TCPListenerConfig,
+    create_listener, frame_span, recv_prioritized,
+};
+use crate::server::access_log::{AccessAction, AccessLog, AccessRecord};
+use crate::server::auth::AuthBackend;
+use crate::server::client_manager::{ClientConfig, ClientManager};
 use crate::server::config::ServerConfig;
+use crate::server::resumption::ResumptionStore;
+use crate::server::router::{DefaultRouter, Router};
 use crate::utils::StunAddr;
+use crate::utils::supervisor::catch_panic;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+/// Cipher blocks of the clusters with their own key, by cluster name
+pub type ClusterBlocks = HashMap<String, Arc<Box<dyn Block>>>;
 
 /// Get current Unix timestamp in seconds
 #[inline]
@@ -23,12 +50,120 @@ fn now_timestamp() -> u64 {
 }
 
 const OUTBOUND_BUFFER_SIZE: usize = 1000;
+/// Control frames queued for a client, see `ConnectionMeta::control_tx`
+const CONTROL_BUFFER_SIZE: usize = 64;
+
+/// Default time a new connection has to send its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keepalives tolerated before the handshake
+///
+/// A client racing its first keepalive ahead of the handshake is harmless,
+/// anything beyond a few is not a client we talk to.
+const MAX_PREMATURE_KEEPALIVES: usize = 4;
+
+/// 64-bit FNV-1a, unlike `DefaultHasher` its output does not change
+/// between Rust releases
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hash of what clients use from a peer list
+///
+/// `last_active` only counts as online or offline, so keepalives alone do
+/// not change the peer list version.
+fn peers_fingerprint(peers: &[&PeerDetail]) -> u64 {
+    let mut hasher = Fnv1a::default();
+    for peer in peers {
+        peer.name.hash(&mut hasher);
+        peer.identity.hash(&mut hasher);
+        peer.private_ip.hash(&mut hasher);
+        peer.ciders.hash(&mut hasher);
+        peer.ipv6.hash(&mut hasher);
+        peer.port.hash(&mut hasher);
+        peer.stun_ip.hash(&mut hasher);
+        peer.stun_port.hash(&mut hasher);
+        peer.nat_type.hash(&mut hasher);
+        (peer.last_active != 0).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Peers of `current` that a client holding `known` lacks or has outdated
+///
+/// `None` if a peer of `known` is gone, the client then needs the full list.
+fn peers_delta(known: &[PeerDetail], current: &[PeerDetail]) -> Option<Vec<PeerDetail>> {
+    if known
+        .iter()
+        .any(|old| !current.iter().any(|peer| peer.identity == old.identity))
+    {
+        return None;
+    }
+    let delta = current
+        .iter()
+        .filter(|peer| {
+            !known.iter().any(|old| {
+                old.identity == peer.identity
+                    && peers_fingerprint(&[old]) == peers_fingerprint(&[peer])
+            })
+        })
+        .cloned()
+        .collect();
+    Some(delta)
+}
 
 pub struct Server {
     server_config: ServerConfig,
     connection_manager: Arc<ConnectionManager>,
     client_manager: Arc<ClientManager>,
+    /// Decides which clients may join, `client_manager` by default
+    auth: Arc<dyn AuthBackend>,
+    /// Picks the destination of tunneled packets, `DefaultRouter` by default
+    router: Arc<dyn Router>,
+    /// Handshake key, and the key of clusters without their own
     block: Arc<Box<dyn Block>>,
+    cluster_blocks: Arc<ClusterBlocks>,
+    /// Number of connections currently being served
+    active_connections: Arc<AtomicUsize>,
+    /// Listener bound ahead of `run`
+    listener: Option<Box<dyn Listener>>,
+    /// Ends `run` when cancelled
+    shutdown: CancellationToken,
+    /// Sessions of recently disconnected clients
+    resumption: Arc<ResumptionStore>,
+    /// Audit trail of routed data frames, if enabled
+    access_log: Option<AccessLog>,
+    /// Magic number of the frames clients send and are sent
+    magic: u32,
+}
+
+/// A slot in the server's connection limit
+///
+/// Held by the handler task and released when the handler finishes,
+/// whether or not the client completed the handshake.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl Server {
@@ -38,32 +173,108 @@ impl Server {
         connection_manager: Arc<ConnectionManager>,
         block: Arc<Box<dyn Block>>,
     ) -> Self {
+        let resumption = Arc::new(ResumptionStore::new(Duration::from_secs(
+            server_config.resume_ttl,
+        )));
         Server {
             server_config,
+            resumption,
+            router: Arc::new(DefaultRouter::new(connection_manager.clone())),
             connection_manager,
+            auth: client_manager.clone(),
             client_manager,
             block,
+            cluster_blocks: Default::default(),
+            active_connections: Arc::new(AtomicUsize::new(0)),
+            listener: None,
+            shutdown: CancellationToken::new(),
+            access_log: None,
+            magic: DEFAULT_MAGIC,
         }
     }
+
+    /// Encrypt the traffic of these clusters with their own key
+    ///
+    /// Handshakes still use the server key, the only one a client can be
+    /// read with before it said which cluster it belongs to.
+    pub fn with_cluster_blocks(mut self, cluster_blocks: ClusterBlocks) -> Self {
+        self.cluster_blocks = Arc::new(cluster_blocks);
+        self
+    }
+
+    /// Authenticate clients with `auth` instead of the routes file
+    ///
+    /// Peer lists sent to clients still come from the `ClientManager`.
+    pub fn with_auth_backend(mut self, auth: Arc<dyn AuthBackend>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Route tunneled packets with `router` instead of `DefaultRouter`
+    ///
+    /// TAP clients are still switched by MAC address.
+    pub fn with_router(mut self, router: Arc<dyn Router>) -> Self {
+        self.router = router;
+        self
+    }
+
+    /// Record the data frames clients send, and how they were routed, in
+    /// `access_log`
+    pub fn with_access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Frame clients' connections with `magic` instead of `DEFAULT_MAGIC`
+    pub fn with_magic(mut self, magic: u32) -> Self {
+        self.magic = magic;
+        self
+    }
+
+    /// Stop serving once `shutdown` is cancelled
+    ///
+    /// `run` then closes the listener, disconnects the clients and returns.
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
 }
 
 impl Server {
-    pub async fn run(&mut self) -> anyhow::Result<()> {
+    /// Bind the listen address ahead of `run`
+    ///
+    /// # Returns
+    /// - `Ok(SocketAddr)` - Bound address, with the port picked when
+    ///   listening on port 0
+    /// - `Err` - Address resolution or bind failure
+    pub async fn bind(&mut self) -> anyhow::Result<SocketAddr> {
         // only for tcp now, may support multi listener type
         let listener_config = ListenerConfig::TCP(TCPListenerConfig {
             listen_addr: self.server_config.listen_addr.clone(),
+            backlog: self.server_config.listen_backlog,
+            max_frame_size: self.server_config.max_frame_size,
+            socket_buffers: SocketBuffers {
+                send: self.server_config.send_buffer_size,
+                recv: self.server_config.recv_buffer_size,
+            },
+            magic: self.magic,
         });
-        let listener = create_listener(listener_config, self.block.clone());
+        let mut listener = create_listener(listener_config, self.block.clone())?;
+        let addr = listener.bind().await?;
+        self.listener = Some(listener);
+        Ok(addr)
+    }
 
-        let mut listener = match listener {
-            Ok(listener) => listener,
-            Err(err) => {
-                return Err(err);
-            }
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        if self.listener.is_none() {
+            self.bind().await?;
+        }
+        let Some(mut listener) = self.listener.take() else {
+            anyhow::bail!("listener not bound");
         };
 
         let mut on_conn_rx = listener.subscribe_on_conn().await?;
-        tokio::spawn(async move {
+        let listen_task = tokio::spawn(async move {
             let err = listener.listen_and_serve().await;
             if err.is_err() {
                 tracing::error!("Server listening error: {:?}", err);
@@ -71,86 +282,302 @@ impl Server {
         });
 
         loop {
-            let conn = on_conn_rx.recv().await;
-            if let Some(conn) = conn {
-                let _ = self.handle_conn(conn);
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                conn = on_conn_rx.recv() => {
+                    if let Some(conn) = conn
+                        && let Err(e) = self.handle_conn(conn)
+                    {
+                        tracing::warn!("dropping connection: {e:#}");
+                    }
+                }
             }
         }
+
+        // dropping the listener releases the port
+        listen_task.abort();
+        let _ = listen_task.await;
+        for client in self.connection_manager.list_connections() {
+            self.connection_manager
+                .kick(&client.cluster, &client.identity);
+        }
+        tracing::info!("server stopped");
+        Ok(())
     }
 
     fn handle_conn(&self, mut conn: Box<dyn ConnManage>) -> anyhow::Result<()> {
-        let peer_addr = conn.peer_addr().unwrap();
-        tracing::debug!("new connection from {}", conn.peer_addr().unwrap());
+        // the client may already have reset the connection
+        let peer_addr = conn.peer_addr().context("no peer address")?;
+        tracing::debug!("new connection from {}", peer_addr);
+
+        let Some(slot) = self.acquire_slot() else {
+            tracing::warn!(
+                "connection limit {:?} reached, refusing {}",
+                self.server_config.max_connections,
+                peer_addr
+            );
+            tokio::spawn(async move {
+                conn.close().await;
+            });
+            return Ok(());
+        };
 
         let mut handler = Handler::new(
             self.connection_manager.clone(),
             self.client_manager.clone(),
+            self.auth.clone(),
             conn,
+        )
+        .with_handshake_timeout(Duration::from_secs(self.server_config.handshake_timeout))
+        .with_handshake_block(self.block.clone())
+        .with_router(self.router.clone())
+        .with_cluster_blocks(self.cluster_blocks.clone())
+        .with_ip_validation(self.server_config.ip_validation)
+        .with_allow_tap(self.server_config.allow_tap)
+        .with_resumption(self.resumption.clone())
+        .with_access_log(self.access_log.clone());
+        let span = tracing::info_span!(
+            "client",
+            %peer_addr,
+            identity = tracing::field::Empty,
+            cluster = tracing::field::Empty
+        );
+        tokio::task::spawn(
+            async move {
+                let _slot = slot;
+                match catch_panic(handler.run()).await {
+                    Ok(e) => tracing::debug!("client {:?} handler stop with {:?}", peer_addr, e),
+                    Err(panic) => {
+                        tracing::error!("client {peer_addr} handler panicked: {panic}");
+                        // dropping the handler closes the connection
+                        handler.leave();
+                    }
+                }
+            }
+            .instrument(span),
         );
-        tokio::task::spawn(async move {
-            let e = handler.run().await;
-            tracing::debug!("client {:?} handler stop with {:?}", peer_addr, e);
-        });
         Ok(())
     }
+
+    /// Reserve a connection slot, or `None` if `max_connections` is reached
+    fn acquire_slot(&self) -> Option<ConnectionSlot> {
+        let max = self.server_config.max_connections.unwrap_or(usize::MAX);
+        self.active_connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()?;
+        Some(ConnectionSlot(self.active_connections.clone()))
+    }
 }
 
 pub struct Handler {
     connection_manager: Arc<ConnectionManager>,
     client_manager: Arc<ClientManager>,
+    auth: Arc<dyn AuthBackend>,
+    router: Arc<dyn Router>,
     conn: Box<dyn ConnManage>,
-    outbound_tx: mpsc::Sender<Frame>,
+    /// Outbound sender, handed to the connection manager at handshake
+    outbound_tx: Option<mpsc::Sender<Frame>>,
+    /// Sender for control frames, a clone is handed to the connection
+    /// manager at handshake and our own replies go through it; only the
+    /// connection manager keeps `outbound_tx` open, so `disconnect` ends the
+    /// handler
+    control_tx: mpsc::Sender<Frame>,
     outbound_rx: mpsc::Receiver<Frame>,
+    /// Control frames, written ahead of the data queued in `outbound_rx`
+    control_rx: mpsc::Receiver<Frame>,
     cluster: Option<String>,
+    /// Configuration granted at handshake
+    client: Option<ClientConfig>,
+    /// Time the client has to send its handshake
+    handshake_timeout: Duration,
+    /// Key the listener handshakes with, kept by clusters without their own
+    handshake_block: Arc<Box<dyn Block>>,
+    /// Keys switched to once the handshake names the cluster
+    cluster_blocks: Arc<ClusterBlocks>,
+    /// Answers the client's key rotations, set at handshake
+    rekeyer: Option<Rekeyer>,
+    /// Checks tunneled packets must pass to be routed
+    ip_validation: IpValidation,
+    /// Whether clients asking for a TAP tunnel get one
+    allow_tap: bool,
+    /// Tunnel mode granted at handshake
+    mode: TunnelMode,
+    /// Sessions of recently disconnected clients
+    resumption: Arc<ResumptionStore>,
+    /// Token the client may resume this session with
+    resume_token: Option<String>,
+    /// Frames exchanged with the client by type
+    frame_stats: Arc<FrameStats>,
+    /// Audit trail of routed data frames, if enabled
+    access_log: Option<AccessLog>,
 }
 
 impl Handler {
     pub fn new(
         connection_manager: Arc<ConnectionManager>,
         client_manager: Arc<ClientManager>,
-        conn: Box<dyn ConnManage>,
+        auth: Arc<dyn AuthBackend>,
+        mut conn: Box<dyn ConnManage>,
     ) -> Handler {
         let (tx, rx) = mpsc::channel(OUTBOUND_BUFFER_SIZE);
+        let (control_tx, control_rx) = mpsc::channel(CONTROL_BUFFER_SIZE);
+        let frame_stats = connection_manager.connection_frame_stats();
+        conn.set_frame_stats(frame_stats.clone());
         Self {
+            router: Arc::new(DefaultRouter::new(connection_manager.clone())),
             connection_manager,
             client_manager,
+            auth,
             conn,
             outbound_rx: rx,
-            outbound_tx: tx,
+            control_rx,
+            control_tx,
+            outbound_tx: Some(tx),
             cluster: None,
+            client: None,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            handshake_block: Arc::new(Box::new(PlainBlock::new())),
+            cluster_blocks: Default::default(),
+            rekeyer: None,
+            ip_validation: IpValidation::default(),
+            allow_tap: false,
+            mode: TunnelMode::default(),
+            resumption: Arc::new(ResumptionStore::new(Duration::ZERO)),
+            resume_token: None,
+            frame_stats,
+            access_log: None,
         }
     }
 
-    pub async fn run(&mut self) -> anyhow::Result<()> {
+    /// Sets the time the client has to send its handshake
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Sets the strategy picking the destination of tunneled packets
+    pub fn with_router(mut self, router: Arc<dyn Router>) -> Self {
+        self.router = router;
+        self
+    }
+
+    /// Sets the key the connection was created with
+    pub fn with_handshake_block(mut self, block: Arc<Box<dyn Block>>) -> Self {
+        self.handshake_block = block;
+        self
+    }
+
+    /// Sets the keys of the clusters with their own key
+    pub fn with_cluster_blocks(mut self, cluster_blocks: Arc<ClusterBlocks>) -> Self {
+        self.cluster_blocks = cluster_blocks;
+        self
+    }
+
+    /// Sets the checks tunneled packets must pass to be routed
+    pub fn with_ip_validation(mut self, level: IpValidation) -> Self {
+        self.ip_validation = level;
+        self
+    }
+
+    /// Grants TAP tunnels to the clients asking for one
+    pub fn with_allow_tap(mut self, allow_tap: bool) -> Self {
+        self.allow_tap = allow_tap;
+        self
+    }
+
+    /// Sets the audit trail of routed data frames
+    pub fn with_access_log(mut self, access_log: Option<AccessLog>) -> Self {
+        self.access_log = access_log;
+        self
+    }
+
+    /// Sets the sessions clients may resume
+    pub fn with_resumption(mut self, resumption: Arc<ResumptionStore>) -> Self {
+        self.resumption = resumption;
+        self
+    }
+
+    pub async fn run(&mut self) -> Result<(), RustunError> {
         // handshake
-        let hs = match self.handle_handshake().await {
-            Ok(hs) => hs,
-            Err(e) => return Err(e),
-        };
+        let hs = self.handle_handshake().await?;
 
         // validate client identity
-        let client_config = match self.client_manager.get_client(&hs.identity) {
+        let client_config = match self
+            .auth
+            .authenticate(&hs.identity, hs.token.as_deref())
+            .await
+        {
             Some(c) => c,
             None => {
                 tracing::debug!("{} unauthorized", hs.identity);
-                return Ok(());
+                self.conn
+                    .write_frame(Frame::HandshakeReject(HandshakeRejectFrame {
+                        reason: REJECT_UNAUTHORIZED.to_string(),
+                    }))
+                    .await?;
+                return Err(RustunError::Unauthorized(hs.identity));
+            }
+        };
+        // reply handshake with other clients info, only the changes for
+        // a client resuming a recent session
+        let (mut peers_version, others) =
+            self.versioned_others(client_config.cluster.as_str(), &client_config);
+        let delta = hs
+            .resume_token
+            .as_deref()
+            .and_then(|token| {
+                self.resumption
+                    .resume(token, &client_config.cluster, &hs.identity)
+            })
+            .and_then(|known| peers_delta(&known, &others));
+        let mut resumed = delta.is_some();
+        if let Some(delta) = &delta {
+            tracing::info!(
+                "{} resumed its session, {} peers changed",
+                hs.identity,
+                delta.len()
+            );
+        }
+        let mut route_items = delta.unwrap_or(others);
+        // the reply is sealed with the handshake key, which other tenants
+        // hold too: a cluster with its own key gets its peers in the reply
+        // to the keepalive following the handshake, under that key
+        if self.cluster_blocks.contains_key(&client_config.cluster) {
+            route_items.clear();
+            peers_version = 0;
+            resumed = false;
+        }
+        self.resume_token = self.resumption.issue();
+        let version = negotiate_version(MAX_VERSION, hs.max_version);
+        self.mode = match hs.mode {
+            TunnelMode::Tap if !self.allow_tap => {
+                tracing::warn!("{} asked for a TAP tunnel, TAP is not allowed", hs.identity);
+                TunnelMode::Tun
             }
+            mode => mode,
         };
+        let pad = hs.pad.filter(|&bucket| bucket > 0);
 
-        // reply handshake with other clients info
-        let route_items = self.build_others(client_config.cluster.as_str(), &hs.identity);
-
-        self.conn
-            .write_frame(HandshakeReply(HandshakeReplyFrame {
-                name: client_config.name.clone(),
-                private_ip: client_config.private_ip.clone(),
-                mask: client_config.mask.clone(),
-                gateway: client_config.gateway.clone(),
-                ciders: client_config.ciders.clone(),
-                cider_mapping: client_config.cider_mapping.clone(),
-                peer_details: route_items,
-            }))
-            .await?;
+        let mut reply = HandshakeReply(HandshakeReplyFrame {
+            name: client_config.name.clone(),
+            private_ip: client_config.private_ip.clone(),
+            mask: client_config.mask.clone(),
+            gateway: client_config.gateway.clone(),
+            ciders: client_config.ciders.clone(),
+            cider_mapping: client_config.cider_mapping.clone(),
+            peer_details: route_items,
+            version,
+            peers_version,
+            resume_token: self.resume_token.clone(),
+            resumed,
+            mode: self.mode,
+            pad,
+        });
+        if version < IPV6_LIST_VERSION {
+            reply.keep_first_ipv6();
+        }
 
         let meta = ConnectionMeta {
             cluster: client_config.cluster.clone(),
@@ -159,26 +586,107 @@ impl Handler {
             mask: client_config.mask.clone(),
             gateway: client_config.gateway.clone(),
             ciders: client_config.ciders.clone(),
-            outbound_tx: self.outbound_tx.clone(),
-            ipv6: "".to_string(), // Do not set, it will be set in the keepalive frame
+            outbound_tx: self
+                .outbound_tx
+                .take()
+                .ok_or_else(|| RustunError::Other(anyhow::anyhow!("handler already registered")))?,
+            control_tx: self.control_tx.clone(),
+            tx_dropped: Default::default(),
+            frame_stats: self.frame_stats.clone(),
+            mode: self.mode,
+            version,
+            ipv6: vec![], // Do not set, it will be set in the keepalive frame
             port: 0,
             stun: None,
             last_active: now_timestamp(),
+            connected_at: 0,
+            reconnect_count: 0,
         };
         tracing::debug!("handshake completed with {:?}", meta);
 
+        // registered ahead of the reply, a client whose private IP is taken
+        // is refused before it got one
+        let others = match self.connection_manager.add_connection(meta) {
+            Ok(others) => others,
+            Err(owner) => {
+                tracing::warn!(
+                    "{} configured with {}, in use by {owner}",
+                    hs.identity,
+                    client_config.private_ip
+                );
+                let reason = "ip conflict".to_string();
+                self.conn
+                    .write_frame(Frame::HandshakeReject(HandshakeRejectFrame {
+                        reason: reason.clone(),
+                    }))
+                    .await?;
+                return Err(RustunError::Rejected(reason));
+            }
+        };
+        // clients granted by an external backend are peers like configured
+        // ones, once nothing can turn the handshake down anymore
+        self.client_manager.register_client(client_config.clone());
+
         // Store cluster for routing
         self.cluster = Some(client_config.cluster.clone());
-        self.connection_manager.add_connection(meta);
+        self.client = Some(client_config.clone());
+        let span = tracing::Span::current();
+        span.record("identity", hs.identity.as_str());
+        span.record("cluster", client_config.cluster.as_str());
+        notify_peers(
+            others,
+            Frame::PeerJoin(PeerJoinFrame {
+                peer: PeerDetail {
+                    name: client_config.name.clone(),
+                    identity: client_config.identity.clone(),
+                    private_ip: client_config.private_ip.clone(),
+                    ciders: client_config.ciders.clone(),
+                    ipv6: vec![],
+                    port: 0,
+                    stun_ip: String::new(),
+                    stun_port: 0,
+                    last_active: now_timestamp(),
+                    nat_type: 0,
+                },
+            }),
+        );
+
+        if let Err(e) = self.conn.write_frame(reply).await {
+            self.leave();
+            return Err(e);
+        }
+        // the reply itself goes out in the oldest version, clients before
+        // negotiation could not read it otherwise
+        self.conn.set_version(version);
+        self.conn.set_padding(pad.map(usize::from));
+        // so does the key, the client switches once it read the reply
+        let block = match self.cluster_blocks.get(&client_config.cluster) {
+            Some(block) => {
+                self.conn.set_block(block.clone());
+                block.clone()
+            }
+            None => self.handshake_block.clone(),
+        };
+        // the client decides when to rotate
+        self.rekeyer = Some(Rekeyer::new(RekeyPolicy::default(), block));
 
         loop {
             tokio::select! {
                 // read frame
                 result = self.conn.read_frame() => {
                     match result {
+                        // no more traffic to a client that is going away
+                        Ok(Frame::Close(close)) => {
+                            tracing::info!("{} closed the connection: {}", hs.identity, close.reason);
+                            break;
+                        }
                         Ok(frame) => {
-                            tracing::debug!("received frame: {}", frame);
-                            self.handle_frame(frame).await;
+                            let span = frame_span(&frame);
+                            if let Frame::Data(data) = &frame {
+                                span.record("payload_len", data.payload.len());
+                            }
+                            span.in_scope(|| tracing::debug!("received frame: {}", frame));
+                            self.handle_frame(frame).instrument(span).await;
                         }
                         Err(e) => {
                             tracing::error!("read {} failed: {:?}", hs.identity, e);
@@ -187,36 +695,86 @@ impl Handler {
                     }
                 }
 
-                // write frame
-                frame = self.outbound_rx.recv() => {
-                    if let Some(frame) = frame {
-                        tracing::debug!("send frame {}", frame);
-                        if let Err(e) = self.conn.write_frame(frame).await {
-                            tracing::debug!("connection closed with {e:?}");
-                            break;
-                        };
-                    }
+                // write frame, control frames ahead of the data backlog
+                frames = recv_prioritized(&mut self.control_rx, &mut self.outbound_rx, MAX_WRITE_BATCH) => {
+                    let Some(frames) = frames else {
+                        tracing::info!("{} disconnected by server", hs.identity);
+                        break;
+                    };
+                    tracing::debug!("send {} frames, first {}", frames.len(), frames[0]);
+                    if let Err(e) = self.conn.write_frames(frames).await {
+                        tracing::debug!("connection closed with {e:?}");
+                        break;
+                    };
                 }
             }
         }
 
-        tracing::debug!("delete client {}", hs.identity);
-        self.connection_manager.del_connection(hs.identity);
+        self.leave();
         Ok(())
     }
 
-    async fn handle_handshake(&mut self) -> anyhow::Result<HandshakeFrame> {
-        let frame = self.conn.read_frame().await;
-        match frame {
-            Ok(frame) => {
-                tracing::debug!("handshake: {}", frame);
-                if let Frame::Handshake(handshake) = frame {
-                    Ok(handshake)
-                } else {
-                    Err(anyhow::anyhow!("unexpected frame type when handshaking"))
+    /// Deregister the client once its connection ended
+    ///
+    /// The session stays resumable and the other peers are told it left.
+    /// Does nothing before the handshake registered the client.
+    fn leave(&mut self) {
+        let Some(client) = self.client.take() else {
+            return;
+        };
+        if let Some(token) = self.resume_token.take() {
+            let peers = self.build_others(&client.cluster, &client.identity);
+            self.resumption
+                .suspend(token, &client.cluster, &client.identity, peers);
+        }
+        tracing::debug!("delete client {}", client.identity);
+        if let Some(others) = self
+            .connection_manager
+            .del_connection(client.identity.clone())
+        {
+            notify_peers(
+                others,
+                Frame::PeerLeave(PeerLeaveFrame {
+                    identity: client.identity,
+                }),
+            );
+        }
+    }
+
+    /// Wait for the client's handshake
+    ///
+    /// Up to `MAX_PREMATURE_KEEPALIVES` keepalives sent ahead of it are
+    /// ignored; any other frame, or no handshake within
+    /// `handshake_timeout`, fails the connection.
+    async fn handle_handshake(&mut self) -> Result<HandshakeFrame, RustunError> {
+        let deadline = tokio::time::Instant::now() + self.handshake_timeout;
+        let mut premature = 0;
+        loop {
+            let frame = tokio::time::timeout_at(deadline, self.conn.read_frame())
+                .await
+                .map_err(|_| RustunError::Timeout {
+                    op: "handshake",
+                    after: self.handshake_timeout,
+                })??;
+            tracing::debug!("handshake: {}", frame);
+            match frame {
+                Frame::Handshake(handshake) => return Ok(handshake),
+                Frame::KeepAlive(_) if premature < MAX_PREMATURE_KEEPALIVES => {
+                    premature += 1;
+                    tracing::debug!("ignoring keepalive received before the handshake");
+                }
+                Frame::KeepAlive(_) => {
+                    return Err(RustunError::Other(anyhow::anyhow!(
+                        "more than {MAX_PREMATURE_KEEPALIVES} keepalives before the handshake"
+                    )));
+                }
+                frame => {
+                    return Err(RustunError::Other(anyhow::anyhow!(
+                        "unexpected {} frame when handshaking",
+                        frame.type_name()
+                    )));
                 }
             }
-            Err(e) => Err(e),
         }
     }
 
@@ -227,34 +785,89 @@ impl Handler {
     ///
     fn build_others(&self, cluster: &str, my_id: &String) -> Vec<PeerDetail> {
         // reply handshake with other clients info
-        let others = self.client_manager.get_cluster_clients_exclude(my_id);
+        let others = self
+            .client_manager
+            .get_cluster_clients_exclude(cluster, my_id);
         others
             .iter()
-            .map(|client| {
-                let (ipv6, port, stun, last_active) = match self
-                    .connection_manager
-                    .get_connection_by_identity(cluster, &client.identity)
-                {
-                    Some(c) => (c.ipv6, c.port, c.stun.clone(), c.last_active),
-                    None => ("".to_string(), 0, None, 0),
+            .map(|client| self.build_peer(cluster, client))
+            .collect()
+    }
+
+    fn build_peer(&self, cluster: &str, client: &ClientConfig) -> PeerDetail {
+        let (ipv6, port, stun, last_active) = match self
+            .connection_manager
+            .get_connection_by_identity(cluster, &client.identity)
+        {
+            // a stale client keeps its addresses but shows as offline
+            Some(c) => {
+                let last_active = if self.connection_manager.is_live(&c) {
+                    c.last_active
+                } else {
+                    0
                 };
+                (c.ipv6, c.port, c.stun.clone(), last_active)
+            }
+            None => (vec![], 0, None, 0),
+        };
 
-                PeerDetail {
-                    name: client.name.clone(),
-                    identity: client.identity.clone(),
-                    private_ip: client.private_ip.clone(),
-                    ciders: client.ciders.clone(),
-                    ipv6,
-                    port,
-                    stun_ip: stun
-                        .as_ref()
-                        .map(|stun| stun.ip.clone())
-                        .unwrap_or(String::new()),
-                    stun_port: stun.map(|stun| stun.port).unwrap_or(0),
-                    last_active,
-                }
-            })
-            .collect()
+        PeerDetail {
+            name: client.name.clone(),
+            identity: client.identity.clone(),
+            private_ip: client.private_ip.clone(),
+            ciders: client.ciders.clone(),
+            ipv6,
+            port,
+            stun_ip: stun
+                .as_ref()
+                .map(|stun| stun.ip.clone())
+                .unwrap_or(String::new()),
+            stun_port: stun.as_ref().map(|stun| stun.port).unwrap_or(0),
+            last_active,
+            nat_type: stun.map(|stun| stun.nat_type).unwrap_or(0),
+        }
+    }
+
+    /// Other peers of `me` with the cluster's peer list version
+    ///
+    /// The version covers the whole cluster, `me` included, so every member
+    /// agrees on it.
+    fn versioned_others(&self, cluster: &str, me: &ClientConfig) -> (u64, Vec<PeerDetail>) {
+        let others = self.build_others(cluster, &me.identity);
+        let me = self.build_peer(cluster, me);
+        let mut peers: Vec<_> = others.iter().chain([&me]).collect();
+        peers.sort_by(|a, b| a.identity.cmp(&b.identity));
+        let version = self
+            .connection_manager
+            .peers_version(cluster, peers_fingerprint(&peers));
+        (version, others)
+    }
+
+    /// Answer the client's key rotation and switch to the new key
+    ///
+    /// The answer is sealed with the old key, the frames after it with the
+    /// new one.
+    async fn handle_rekey(&mut self, rekey: RekeyFrame) {
+        let (Some(rekeyer), Some(client)) = (self.rekeyer.as_mut(), self.client.as_ref()) else {
+            return;
+        };
+        let (public_key, block) = match rekeyer.answer(&rekey.public_key, &client.identity) {
+            Ok(answer) => answer,
+            Err(e) => {
+                tracing::warn!("rejecting rekey: {e}");
+                return;
+            }
+        };
+        if let Err(e) = self
+            .conn
+            .write_frame(Frame::Rekey(RekeyFrame { public_key }))
+            .await
+        {
+            tracing::debug!("rekey answer failed: {e}");
+            return;
+        }
+        self.conn.set_block(block);
+        tracing::info!("relay connection rekeyed");
     }
 
     async fn handle_frame(&mut self, frame: Frame) {
@@ -266,6 +879,38 @@ impl Handler {
             Frame::Data(frame) => {
                 self.handle_data_frame(frame).await;
             }
+
+            // packets may go to different clients, route each one
+            Frame::DataBatch(batch) => {
+                for payload in batch.packets {
+                    self.handle_data_frame(DataFrame { payload, seq: None })
+                        .await;
+                }
+            }
+
+            // echoes between peers, the server stamps who is asking
+            Frame::Echo(mut echo) if !echo.dst.is_empty() => {
+                echo.src = self
+                    .client
+                    .as_ref()
+                    .map(|c| c.private_ip.clone())
+                    .unwrap_or_default();
+                let dst = echo.dst.clone();
+                self.forward_frame(&dst, Frame::Echo(echo));
+            }
+            Frame::EchoReply(reply) if !reply.dst.is_empty() => {
+                let dst = reply.dst.clone();
+                self.forward_frame(&dst, Frame::EchoReply(reply));
+            }
+            // an echo for the server itself measures the relay round trip
+            Frame::Echo(echo) => {
+                if let Err(e) = self.conn.write_frame(Frame::EchoReply(echo.reply())).await {
+                    tracing::debug!("echo reply failed: {e}");
+                }
+            }
+            Frame::Rekey(rekey) => {
+                self.handle_rekey(rekey).await;
+            }
             _ => {
                 tracing::warn!("unknown frame: {:?}", frame);
             }
@@ -273,40 +918,190 @@ impl Handler {
     }
 
     async fn handle_data_frame(&mut self, frame: DataFrame) {
+        if self.mode == TunnelMode::Tap {
+            self.handle_ethernet_frame(frame);
+            return;
+        }
+        if !frame.validate_ip_packet(self.ip_validation) {
+            self.connection_manager.record_invalid_packet();
+            tracing::debug!("drop packet failing {:?} validation", self.ip_validation);
+            self.log_filtered(&frame);
+            return;
+        }
+        // with validation off, anything holding a destination address is
+        // routed by it
         if frame.invalid() {
-            tracing::warn!("receive invalid ip packet");
+            if self.ip_validation == IpValidation::None {
+                tracing::debug!("no destination in {} byte packet", frame.payload.len());
+            } else {
+                tracing::warn!("receive invalid ip packet");
+            }
+            self.log_filtered(&frame);
             return;
         }
-        if frame.version() != 4 {
+        if frame.version() != 4 && self.ip_validation != IpValidation::None {
             tracing::warn!("receive invalid ipv4 packet");
+            self.log_filtered(&frame);
             return;
         }
         tracing::debug!("on data: {} => {}", frame.src(), frame.dst());
         let dst_ip = frame.dst();
-        let cluster = match &self.cluster {
-            Some(c) => c,
-            None => {
-                tracing::error!("cluster not set");
-                return;
-            }
+        let (Some(cluster), Some(client)) = (&self.cluster, &self.client) else {
+            tracing::error!("cluster not set");
+            return;
         };
-        let dst_client = self.connection_manager.get_connection(cluster, &dst_ip);
+        let dst_client = self
+            .router
+            .route(client, &frame.src(), &dst_ip, &frame.payload)
+            .and_then(|(cluster, identity)| {
+                self.connection_manager
+                    .get_connection_by_identity(&cluster, &identity)
+            });
 
-        // route within cluster (tenant isolation)
+        // taken for the access log before the frame moves on
+        let flow = self
+            .access_log
+            .is_some()
+            .then(|| (frame.src(), frame.payload.len()));
 
-        if let Some(dst_client) = dst_client {
-            let result = dst_client.outbound_tx.send(Frame::Data(frame)).await;
-            if result.is_err() {
-                tracing::warn!("dst client {} not online", dst_ip);
-            }
+        // never wait on a slow destination, it would stall this client too
+        let (routed_to, action) = if let Some(dst_client) = dst_client {
+            let action = match dst_client.outbound_tx.try_send(Frame::Data(frame)) {
+                Ok(()) => AccessAction::Forwarded,
+                Err(TrySendError::Full(_)) => {
+                    dst_client.tx_dropped.fetch_add(1, Ordering::Relaxed);
+                    tracing::debug!("dst client {} queue full, frame dropped", dst_ip);
+                    AccessAction::Dropped
+                }
+                Err(TrySendError::Closed(frame)) => {
+                    if self
+                        .connection_manager
+                        .buffer_frame(cluster, &dst_ip, frame)
+                    {
+                        AccessAction::Forwarded
+                    } else {
+                        tracing::warn!("dst client {} not online", dst_ip);
+                        AccessAction::Dropped
+                    }
+                }
+            };
+            (Some(dst_client.identity), action)
+        } else if self
+            .connection_manager
+            .buffer_frame(cluster, &dst_ip, Frame::Data(frame))
+        {
+            (None, AccessAction::Forwarded)
         } else {
             tracing::warn!("no route to {} in cluster {}", dst_ip, cluster);
+            (None, AccessAction::Dropped)
+        };
+        if let Some((src_ip, bytes)) = flow {
+            self.log_flow(AccessRecord {
+                src_ip: Some(src_ip),
+                dst_ip: Some(dst_ip),
+                bytes,
+                routed_to_identity: routed_to.as_deref(),
+                action,
+                ..Default::default()
+            });
+        }
+    }
+
+    /// Log a packet failing the checks, with its addresses if it has them
+    fn log_filtered(&self, frame: &DataFrame) {
+        if self.access_log.is_none() {
+            return;
+        }
+        let has_addrs = !frame.invalid();
+        self.log_flow(AccessRecord {
+            src_ip: has_addrs.then(|| frame.src()),
+            dst_ip: has_addrs.then(|| frame.dst()),
+            bytes: frame.payload.len(),
+            action: AccessAction::Filtered,
+            ..Default::default()
+        });
+    }
+
+    /// Append `record` of this client to the access log, if enabled
+    fn log_flow(&self, record: AccessRecord) {
+        let (Some(log), Some(client)) = (&self.access_log, &self.client) else {
+            return;
+        };
+        log.record(&AccessRecord {
+            cluster: &client.cluster,
+            src_identity: &client.identity,
+            ..record
+        });
+    }
+
+    /// Switch an Ethernet frame of a TAP client by its destination MAC
+    ///
+    /// The source MAC is learned first, so answers find their way back.
+    fn handle_ethernet_frame(&self, frame: DataFrame) {
+        let (Some(dst), Some(src)) = (frame.dst_mac(), frame.src_mac()) else {
+            self.connection_manager.record_invalid_packet();
+            tracing::debug!("drop truncated ethernet frame");
+            return;
+        };
+        let (Some(cluster), Some(client)) = (&self.cluster, &self.client) else {
+            tracing::error!("cluster not set");
+            return;
+        };
+        tracing::debug!("on ethernet: {} => {}", format_mac(&src), format_mac(&dst));
+        self.connection_manager
+            .learn_mac(cluster, src, &client.identity);
+
+        let ports = self
+            .connection_manager
+            .get_l2_connections(cluster, &client.identity, dst);
+        if ports.is_empty() {
+            tracing::debug!(
+                "no TAP client for {} in cluster {cluster}",
+                format_mac(&dst)
+            );
+        }
+        for port in ports {
+            if let Err(e) = port.outbound_tx.try_send(Frame::Data(frame.clone())) {
+                if let TrySendError::Full(_) = e {
+                    port.tx_dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                tracing::debug!("drop frame to {}: {e}", port.identity);
+            }
+        }
+    }
+
+    /// Route a control frame to the client owning `dst_ip`, dropping it if
+    /// that client is offline or its queue is full
+    fn forward_frame(&self, dst_ip: &str, frame: Frame) {
+        let Some(cluster) = &self.cluster else {
+            tracing::error!("cluster not set");
+            return;
+        };
+        let Some(dst_client) = self.connection_manager.get_connection(cluster, dst_ip) else {
+            tracing::debug!("no route to {dst_ip} in cluster {cluster}, drop {frame}");
+            return;
+        };
+        // older clients fail on the frame type and drop the session
+        if matches!(frame, Frame::Echo(_) | Frame::EchoReply(_))
+            && dst_client.version < ECHO_VERSION
+        {
+            tracing::debug!(
+                "{dst_ip} speaks version {}, drop {frame}",
+                dst_client.version
+            );
+            return;
+        }
+        if let Err(e) = dst_client.sender_for(&frame).try_send(frame) {
+            if let TrySendError::Full(_) = e {
+                dst_client.tx_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            tracing::debug!("drop frame to {dst_ip}: {e}");
         }
     }
 
     async fn handle_keepalive_frame(&mut self, frame: KeepAliveFrame) {
         tracing::info!(
-            "on keepalive from {} {}:{} {}:{}",
+            "on keepalive from {} {:?}:{} {}:{}",
             frame.identity,
             frame.ipv6,
             frame.port,
@@ -314,43 +1109,1600 @@ impl Handler {
             frame.stun_port
         );
 
-        let client = self.client_manager.get_client(&frame.identity);
-        let mut name = String::new();
-        if let Some(client) = client {
-            let stun = StunAddr {
-                ip: frame.stun_ip.clone(),
-                port: frame.stun_port,
-            };
-            let _ = self.connection_manager.update_connection_info(
-                &client.cluster,
-                &frame.identity,
-                client.ciders.clone(),
-                frame.ipv6.clone(),
-                frame.port,
-                stun,
+        // the frame only speaks for the identity this connection handshook as
+        let Some(own) = self.client.clone() else {
+            return;
+        };
+        if frame.identity != own.identity {
+            tracing::warn!(
+                "{} sent a keepalive as {}, ignored",
+                own.identity,
+                frame.identity
             );
-            name = client.name.clone();
+            return;
         }
 
-        // Reply keepalive with full peer details for route sync
-        let peer_details = if let Some(cluster) = &self.cluster {
-            self.build_others(cluster, &frame.identity)
-        } else {
-            vec![]
+        // prefer the routes file so reloads apply, clients granted by an
+        // external backend keep their handshake configuration
+        let client = self.client_manager.get_client(&own.identity).unwrap_or(own);
+        let stun = StunAddr {
+            ip: frame.stun_ip.clone(),
+            port: frame.stun_port,
+            nat_type: frame.nat_type,
+        };
+        let others = self.connection_manager.update_connection_info(
+            &client.cluster,
+            &client.identity,
+            client.ciders.clone(),
+            frame.ipv6.clone(),
+            frame.port,
+            stun,
+        );
+        // the address changed, tell the rest of the cluster right away
+        if let Some(others) = others {
+            let update = Frame::PeerUpdate(PeerUpdateFrame {
+                peer: PeerDetail {
+                    name: client.name.clone(),
+                    identity: client.identity.clone(),
+                    private_ip: client.private_ip.clone(),
+                    ciders: client.ciders.clone(),
+                    ipv6: frame.ipv6.clone(),
+                    port: frame.port,
+                    stun_ip: frame.stun_ip.clone(),
+                    stun_port: frame.stun_port,
+                    last_active: now_timestamp(),
+                    nat_type: frame.nat_type,
+                },
+            });
+            notify_peers(others, update);
+        }
+
+        // Reply keepalive with full peer details for route sync, unless the
+        // client already has the current list
+        let (peers_version, peer_details) = match &self.cluster {
+            Some(cluster) => {
+                let (version, others) = self.versioned_others(cluster, &client);
+                if frame.peers_version == version {
+                    (version, vec![])
+                } else {
+                    (version, others)
+                }
+            }
+            None => (0, vec![]),
         };
 
         let reply_frame = Frame::KeepAlive(KeepAliveFrame {
-            name: name.clone(),
-            identity: frame.identity,
+            name: client.name,
+            identity: client.identity,
             ipv6: frame.ipv6,
             port: frame.port,
             stun_ip: frame.stun_ip,
             stun_port: frame.stun_port,
+            nat_type: frame.nat_type,
+            peers_version,
             peer_details,
         });
 
-        if let Err(e) = self.outbound_tx.send(reply_frame).await {
-            tracing::error!("reply keepalive frame failed with {e:?}");
+        // written right away, peer events may have filled the control queue
+        if let Err(e) = self.conn.write_frame(reply_frame).await {
+            tracing::debug!("reply keepalive frame failed with {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::frame::tests as frame_tests;
+    use crate::codec::frame::{CloseFrame, EchoFrame, EchoReplyFrame, MacAddr, ipv4_packet};
+    use crate::crypto::plain::PlainBlock;
+    use crate::network::tcp_connection::TcpConnection;
+    use crate::network::{ConnRead, ConnWrite};
+    use crate::server::client_manager::ClientConfig;
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn server_config() -> ServerConfig {
+        ServerConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            listen_backlog: 16,
+            max_connections: None,
+            max_frame_size: crate::codec::parser::MAX_FRAME_LEN,
+            offline_buffer_size: 0,
+            offline_buffer_ttl: 5,
+            handshake_timeout: 10,
+            resume_ttl: 30,
+            global_cidrs: vec![],
+            peer_ttl: 0,
+            route_policy: Default::default(),
+            ip_validation: Default::default(),
+            allow_tap: false,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            http_port: None,
+            admin_token: None,
+            frame_magic: Default::default(),
+            access_log: None,
+        }
+    }
+
+    fn new_server(cfg: ServerConfig, clients: Vec<ClientConfig>) -> Server {
+        let client_manager = Arc::new(ClientManager::new());
+        client_manager.add_clients_config(clients);
+        Server::new(
+            cfg,
+            client_manager,
+            Arc::new(ConnectionManager::new()),
+            Arc::new(Box::new(PlainBlock::new())),
+        )
+    }
+
+    /// Connect a client socket and hand the accepted side to the server
+    async fn connect(server: &Server, listener: &TcpListener) -> TcpConnection {
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        server
+            .handle_conn(Box::new(TcpConnection::from_socket(accepted)))
+            .unwrap();
+        TcpConnection::from_socket(client)
+    }
+
+    async fn handshake(conn: &mut TcpConnection, identity: &str) -> Result<Frame, RustunError> {
+        conn.write_frame(Frame::Handshake(HandshakeFrame {
+            identity: identity.to_string(),
+            token: None,
+            max_version: MAX_VERSION,
+            resume_token: None,
+            mode: Default::default(),
+            pad: None,
+        }))
+        .await?;
+        conn.read_frame().await
+    }
+
+    /// Handler on an accepted socket that has not handshaken yet
+    async fn pending_handler(timeout: Duration) -> (Handler, TcpConnection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        let client_manager = Arc::new(ClientManager::new());
+        let handler = Handler::new(
+            Arc::new(ConnectionManager::new()),
+            client_manager.clone(),
+            client_manager,
+            Box::new(TcpConnection::from_socket(accepted)),
+        )
+        .with_handshake_timeout(timeout);
+        (handler, TcpConnection::from_socket(client))
+    }
+
+    fn handshake_frame(identity: &str) -> Frame {
+        Frame::Handshake(HandshakeFrame {
+            identity: identity.to_string(),
+            token: None,
+            max_version: MAX_VERSION,
+            resume_token: None,
+            mode: Default::default(),
+            pad: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_handshake_received_first() {
+        let (mut handler, mut client) = pending_handler(Duration::from_secs(2)).await;
+        client.write_frame(handshake_frame("a")).await.unwrap();
+        assert_eq!(handler.handle_handshake().await.unwrap().identity, "a");
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_before_handshake_is_ignored() {
+        let (mut handler, mut client) = pending_handler(Duration::from_secs(2)).await;
+        client
+            .write_frames(vec![keepalive("a", "", 0), handshake_frame("a")])
+            .await
+            .unwrap();
+        assert_eq!(handler.handle_handshake().await.unwrap().identity, "a");
+    }
+
+    #[tokio::test]
+    async fn test_too_many_keepalives_before_handshake() {
+        let (mut handler, mut client) = pending_handler(Duration::from_secs(2)).await;
+        let frames = vec![keepalive("a", "", 0); MAX_PREMATURE_KEEPALIVES + 1];
+        client.write_frames(frames).await.unwrap();
+        let err = handler.handle_handshake().await.unwrap_err();
+        assert!(
+            err.to_string().contains("keepalives before the handshake"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handshake_deadline() {
+        let (mut handler, mut client) = pending_handler(Duration::from_millis(50)).await;
+        client.write_frame(keepalive("a", "", 0)).await.unwrap();
+        let err = handler.handle_handshake().await.unwrap_err();
+        assert!(
+            matches!(err, RustunError::Timeout { op: "handshake", after } if after == Duration::from_millis(50)),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handshake_reply_carries_negotiated_version() {
+        use crate::codec::parser::MIN_VERSION;
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        for (identity, max_version) in [("a", MAX_VERSION), ("b", MIN_VERSION)] {
+            let mut conn = connect(&server, &listener).await;
+            conn.write_frame(Frame::Handshake(HandshakeFrame {
+                identity: identity.to_string(),
+                token: None,
+                max_version,
+                resume_token: None,
+                mode: Default::default(),
+                pad: None,
+            }))
+            .await
+            .unwrap();
+            match conn.read_frame().await.unwrap() {
+                Frame::HandshakeReply(reply) => assert_eq!(reply.version, max_version),
+                frame => panic!("unexpected frame {frame}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_refuses_extra_clients() {
+        let mut cfg = server_config();
+        cfg.max_connections = Some(2);
+        let server = new_server(
+            cfg,
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+                ClientConfig::for_test("c", "10.0.0.3"),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut a = connect(&server, &listener).await;
+        let mut b = connect(&server, &listener).await;
+        let mut c = connect(&server, &listener).await;
+
+        assert!(matches!(
+            handshake(&mut a, "a").await.unwrap(),
+            Frame::HandshakeReply(_)
+        ));
+        assert!(matches!(
+            handshake(&mut b, "b").await.unwrap(),
+            Frame::HandshakeReply(_)
+        ));
+        let _ = c
+            .write_frame(Frame::Handshake(HandshakeFrame {
+                identity: "c".to_string(),
+                token: None,
+                max_version: MAX_VERSION,
+                resume_token: None,
+                mode: Default::default(),
+                pad: None,
+            }))
+            .await;
+        assert!(c.read_frame().await.is_err());
+        assert_eq!(server.active_connections.load(Ordering::Relaxed), 2);
+    }
+
+    async fn resume_handshake(
+        conn: &mut TcpConnection,
+        identity: &str,
+        resume_token: Option<String>,
+    ) -> HandshakeReplyFrame {
+        conn.write_frame(Frame::Handshake(HandshakeFrame {
+            identity: identity.to_string(),
+            token: None,
+            max_version: MAX_VERSION,
+            resume_token,
+            mode: Default::default(),
+            pad: None,
+        }))
+        .await
+        .unwrap();
+        match conn.read_frame().await.unwrap() {
+            Frame::HandshakeReply(reply) => reply,
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    /// Wait until `identity` is registered, or no longer is
+    async fn wait_registered(server: &Server, identity: &str, registered: bool) {
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while server
+                .connection_manager
+                .get_connection_by_identity("test", &identity.to_string())
+                .is_some()
+                != registered
+            {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("registration should change");
+    }
+
+    /// Drop the connection of `identity` once registered, wait for its end
+    async fn hang_up(server: &Server, conn: TcpConnection, identity: &str) {
+        wait_registered(server, identity, true).await;
+        drop(conn);
+        wait_registered(server, identity, false).await;
+    }
+
+    #[tokio::test]
+    async fn test_resumed_handshake_sends_only_changes() {
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+                ClientConfig::for_test("c", "10.0.0.3"),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut a = connect(&server, &listener).await;
+        let reply = resume_handshake(&mut a, "a", None).await;
+        assert!(!reply.resumed);
+        assert_eq!(reply.peer_details.len(), 2);
+        let token = reply.resume_token.expect("a fresh session gets a token");
+        hang_up(&server, a, "a").await;
+
+        // b comes online while a is away
+        let mut b = connect(&server, &listener).await;
+        resume_handshake(&mut b, "b", None).await;
+        wait_registered(&server, "b", true).await;
+
+        let mut a = connect(&server, &listener).await;
+        let reply = resume_handshake(&mut a, "a", Some(token.clone())).await;
+        assert!(reply.resumed);
+        let changed: Vec<_> = reply.peer_details.iter().map(|p| &p.identity).collect();
+        assert_eq!(changed, ["b"]);
+        assert!(reply.resume_token.is_some_and(|next| next != token));
+        hang_up(&server, a, "a").await;
+
+        // a token is only good once
+        let mut a = connect(&server, &listener).await;
+        let reply = resume_handshake(&mut a, "a", Some(token)).await;
+        assert!(!reply.resumed);
+        assert_eq!(reply.peer_details.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_expired_resume_token_gets_full_list() {
+        let mut server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        );
+        server.resumption = Arc::new(ResumptionStore::new(Duration::from_millis(50)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut a = connect(&server, &listener).await;
+        let token = resume_handshake(&mut a, "a", None).await.resume_token;
+        hang_up(&server, a, "a").await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut a = connect(&server, &listener).await;
+        let reply = resume_handshake(&mut a, "a", token).await;
+        assert!(!reply.resumed);
+        assert_eq!(reply.peer_details.len(), 1);
+        assert_eq!(reply.private_ip, "10.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_ends_handler() {
+        let server = new_server(
+            server_config(),
+            vec![ClientConfig::for_test("a", "10.0.0.1")],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        assert!(matches!(
+            handshake(&mut a, "a").await.unwrap(),
+            Frame::HandshakeReply(_)
+        ));
+
+        // the reply is written just before the connection is registered
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while server.connection_manager.disconnect("a") == 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("connection should be registered");
+        let read = tokio::time::timeout(std::time::Duration::from_secs(1), a.read_frame())
+            .await
+            .expect("handler should close the connection");
+        assert!(read.is_err());
+    }
+
+    fn keepalive(identity: &str, stun_ip: &str, stun_port: u16) -> Frame {
+        Frame::KeepAlive(KeepAliveFrame {
+            stun_ip: stun_ip.to_string(),
+            stun_port,
+            ..frame_tests::keepalive(identity)
+        })
+    }
+
+    /// Send a keepalive and wait for its reply, skipping pushed updates
+    async fn exchange_keepalive(conn: &mut TcpConnection, frame: Frame) -> KeepAliveFrame {
+        conn.write_frame(frame).await.unwrap();
+        loop {
+            match conn.read_frame().await.unwrap() {
+                Frame::KeepAlive(reply) => return reply,
+                Frame::PeerUpdate(_) | Frame::PeerJoin(_) | Frame::PeerLeave(_) => continue,
+                frame => panic!("unexpected frame {frame}"),
+            }
+        }
+    }
+
+    fn echo(id: u64, dst: &str) -> Frame {
+        Frame::Echo(EchoFrame {
+            id,
+            sent_at: 1_700_000_000_000_000 + id,
+            src: String::new(),
+            dst: dst.to_string(),
+        })
+    }
+
+    /// Next frame that is not a pushed peer update, join or leave
+    async fn read_skipping_updates(conn: &mut TcpConnection) -> Frame {
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            loop {
+                match conn.read_frame().await.unwrap() {
+                    Frame::PeerUpdate(_) | Frame::PeerJoin(_) | Frame::PeerLeave(_) => continue,
+                    frame => return frame,
+                }
+            }
+        })
+        .await
+        .expect("frame should arrive")
+    }
+
+    async fn read_echo_reply(conn: &mut TcpConnection) -> EchoReplyFrame {
+        match read_skipping_updates(conn).await {
+            Frame::EchoReply(reply) => reply,
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_frame_deregisters_at_once() {
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        let mut b = connect(&server, &listener).await;
+        handshake(&mut a, "a").await.unwrap();
+        handshake(&mut b, "b").await.unwrap();
+        wait_registered(&server, "a", true).await;
+
+        // the connection stays open, the frame alone ends the session
+        a.write_frame(Frame::Close(CloseFrame {
+            reason: "client shutdown".to_string(),
+        }))
+        .await
+        .unwrap();
+        wait_registered(&server, "a", false).await;
+        let left = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                if let Frame::PeerLeave(leave) = b.read_frame().await.unwrap() {
+                    return leave.identity;
+                }
+            }
+        })
+        .await
+        .expect("b should be told a left");
+        assert_eq!(left, "a");
+        // b's join may still be queued for a
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while a.read_frame().await.is_ok() {}
+        })
+        .await
+        .expect("server should close the connection");
+    }
+
+    #[tokio::test]
+    async fn test_server_answers_echo() {
+        let server = new_server(
+            server_config(),
+            vec![ClientConfig::for_test("a", "10.0.0.1")],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        handshake(&mut a, "a").await.unwrap();
+
+        a.write_frame(echo(7, "")).await.unwrap();
+        let reply = read_echo_reply(&mut a).await;
+        assert_eq!(reply.id, 7);
+        assert_eq!(reply.sent_at, 1_700_000_000_000_007);
+        assert_eq!(reply.dst, "");
+    }
+
+    #[tokio::test]
+    async fn test_echo_relayed_between_peers() {
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        let mut b = connect(&server, &listener).await;
+        handshake(&mut a, "a").await.unwrap();
+        handshake(&mut b, "b").await.unwrap();
+        exchange_keepalive(&mut b, keepalive("b", "", 0)).await;
+
+        a.write_frame(echo(3, "10.0.0.2")).await.unwrap();
+        let frame = read_skipping_updates(&mut b).await;
+        let Frame::Echo(forwarded) = frame else {
+            panic!("unexpected frame {frame}");
+        };
+        assert_eq!(forwarded.id, 3);
+        assert_eq!(forwarded.src, "10.0.0.1");
+
+        b.write_frame(Frame::EchoReply(forwarded.reply()))
+            .await
+            .unwrap();
+        let reply = read_echo_reply(&mut a).await;
+        assert_eq!(reply.id, 3);
+        assert_eq!(reply.sent_at, 1_700_000_000_000_003);
+    }
+
+    #[tokio::test]
+    async fn test_echo_not_forwarded_to_clients_predating_it() {
+        use crate::codec::parser::MIN_VERSION;
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        a.write_frame(Frame::Handshake(HandshakeFrame {
+            identity: "a".to_string(),
+            token: None,
+            max_version: MIN_VERSION,
+            resume_token: None,
+            mode: Default::default(),
+            pad: None,
+        }))
+        .await
+        .unwrap();
+        a.read_frame().await.unwrap();
+        exchange_keepalive(&mut a, keepalive("a", "", 0)).await;
+        let mut b = connect(&server, &listener).await;
+        handshake(&mut b, "b").await.unwrap();
+
+        // the echo is held back, the packet after it still arrives
+        b.write_frame(echo(5, "10.0.0.1")).await.unwrap();
+        b.write_frame(Frame::Data(DataFrame {
+            payload: crate::codec::frame::ipv4_packet([10, 0, 0, 2], [10, 0, 0, 1], 17, &[]),
+            seq: None,
+        }))
+        .await
+        .unwrap();
+        let frame = read_skipping_updates(&mut a).await;
+        assert!(matches!(frame, Frame::Data(_)), "unexpected frame {frame}");
+    }
+
+    #[tokio::test]
+    async fn test_strict_validation_drops_corrupt_packets() {
+        let mut cfg = server_config();
+        cfg.ip_validation = IpValidation::Strict;
+        let server = new_server(
+            cfg,
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        let mut b = connect(&server, &listener).await;
+        handshake(&mut a, "a").await.unwrap();
+        handshake(&mut b, "b").await.unwrap();
+        exchange_keepalive(&mut b, keepalive("b", "", 0)).await;
+
+        let packet = crate::codec::frame::ipv4_packet([10, 0, 0, 1], [10, 0, 0, 2], 17, &[]);
+        let mut corrupt = packet.clone();
+        corrupt[11] ^= 0xff;
+
+        for payload in [corrupt, packet.clone()] {
+            a.write_frame(Frame::Data(DataFrame { payload, seq: None }))
+                .await
+                .unwrap();
+        }
+        match read_skipping_updates(&mut b).await {
+            Frame::Data(data) => assert_eq!(data.payload, packet),
+            frame => panic!("unexpected frame {frame}"),
+        }
+        assert_eq!(server.connection_manager.invalid_packets(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_validation_routes_by_destination_only() {
+        let mut cfg = server_config();
+        cfg.ip_validation = IpValidation::None;
+        let server = new_server(
+            cfg,
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        let mut b = connect(&server, &listener).await;
+        handshake(&mut a, "a").await.unwrap();
+        handshake(&mut b, "b").await.unwrap();
+        exchange_keepalive(&mut b, keepalive("b", "", 0)).await;
+
+        // neither an IPv4 nor an IPv6 version, but a destination to route by
+        let mut packet = vec![0xffu8; 20];
+        packet[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        for payload in [vec![0xff; 4], packet.clone()] {
+            a.write_frame(Frame::Data(DataFrame { payload, seq: None }))
+                .await
+                .unwrap();
+        }
+        match read_skipping_updates(&mut b).await {
+            Frame::Data(data) => assert_eq!(data.payload, packet),
+            frame => panic!("unexpected frame {frame}"),
+        }
+        assert_eq!(server.connection_manager.invalid_packets(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_access_log_records_routed_frames() {
+        let path = std::env::temp_dir().join(format!("rustun-access-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let access_log = AccessLog::open(&crate::server::config::AccessLogConfig {
+            path: path.to_string_lossy().into_owned(),
+            sample: 1,
+            max_per_second: None,
+        })
+        .unwrap();
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        )
+        .with_access_log(access_log.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        let mut b = connect(&server, &listener).await;
+        handshake(&mut a, "a").await.unwrap();
+        handshake(&mut b, "b").await.unwrap();
+        exchange_keepalive(&mut b, keepalive("b", "", 0)).await;
+
+        let packet = |dst| ipv4_packet([10, 0, 0, 1], [10, 0, 0, dst], 17, &[0; 4]);
+        // too short to be an IP packet, no client at .9, then b
+        for payload in [vec![0x45; 8], packet(9), packet(2)] {
+            a.write_frame(Frame::Data(DataFrame { payload, seq: None }))
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            read_skipping_updates(&mut b).await,
+            Frame::Data(_)
+        ));
+
+        access_log.flush();
+        let records: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(records.len(), 3);
+        for record in &records {
+            assert_eq!(record["cluster"], "test");
+            assert_eq!(record["src_identity"], "a");
+            assert!(record["timestamp"].as_u64().unwrap() > 0);
+        }
+        assert_eq!(records[0]["action"], "filtered");
+        assert_eq!(records[0]["bytes"], 8);
+        assert!(records[0]["src_ip"].is_null());
+        assert_eq!(records[1]["action"], "dropped");
+        assert_eq!(records[1]["dst_ip"], "10.0.0.9");
+        assert!(records[1]["routed_to_identity"].is_null());
+        assert_eq!(records[2]["action"], "forwarded");
+        assert_eq!(records[2]["src_ip"], "10.0.0.1");
+        assert_eq!(records[2]["dst_ip"], "10.0.0.2");
+        assert_eq!(records[2]["bytes"], 24);
+        assert_eq!(records[2]["routed_to_identity"], "b");
+    }
+
+    #[tokio::test]
+    async fn test_padding_negotiated_per_client() {
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        let mut b = connect(&server, &listener).await;
+        a.write_frame(Frame::Handshake(HandshakeFrame {
+            pad: Some(64),
+            ..match handshake_frame("a") {
+                Frame::Handshake(hs) => hs,
+                _ => unreachable!(),
+            }
+        }))
+        .await
+        .unwrap();
+        match a.read_frame().await.unwrap() {
+            Frame::HandshakeReply(reply) => assert_eq!(reply.pad, Some(64)),
+            frame => panic!("unexpected frame {frame}"),
+        }
+        a.set_version(MAX_VERSION);
+        a.set_padding(Some(64));
+        let stats = Arc::new(FrameStats::new());
+        a.set_frame_stats(stats.clone());
+        match handshake(&mut b, "b").await.unwrap() {
+            Frame::HandshakeReply(reply) => assert_eq!(reply.pad, None),
+            frame => panic!("unexpected frame {frame}"),
+        }
+        b.set_version(MAX_VERSION);
+        exchange_keepalive(&mut a, keepalive("a", "", 0)).await;
+        exchange_keepalive(&mut b, keepalive("b", "", 0)).await;
+
+        let packet = |src, dst| ipv4_packet([10, 0, 0, src], [10, 0, 0, dst], 17, &[]);
+        a.write_frame(Frame::Data(DataFrame {
+            payload: packet(1, 2),
+            seq: None,
+        }))
+        .await
+        .unwrap();
+        match read_skipping_updates(&mut b).await {
+            Frame::Data(data) => assert_eq!(data.payload, packet(1, 2)),
+            frame => panic!("unexpected frame {frame}"),
+        }
+        b.write_frame(Frame::Data(DataFrame {
+            payload: packet(2, 1),
+            seq: None,
+        }))
+        .await
+        .unwrap();
+        match read_skipping_updates(&mut a).await {
+            Frame::Data(data) => assert_eq!(data.payload, packet(2, 1)),
+            frame => panic!("unexpected frame {frame}"),
+        }
+        // padded both ways on the connection of a only
+        let counts = stats.snapshot()["padded_data"];
+        assert_eq!((counts.sent, counts.received), (1, 1));
+    }
+
+    /// Whether a data frame arrives within 200ms
+    async fn receives_data(conn: &mut TcpConnection) -> bool {
+        tokio::time::timeout(Duration::from_millis(200), async {
+            while !matches!(conn.read_frame().await, Ok(Frame::Data(_))) {}
+        })
+        .await
+        .is_ok()
+    }
+
+    /// Minimal IPv4 Ethernet frame from `src` to `dst`
+    fn ethernet_frame(dst: MacAddr, src: MacAddr) -> Frame {
+        let mut payload = dst.to_vec();
+        payload.extend_from_slice(&src);
+        payload.extend_from_slice(&[0x08, 0x00]);
+        payload.resize(60, 0);
+        Frame::Data(DataFrame { payload, seq: None })
+    }
+
+    async fn tap_handshake(conn: &mut TcpConnection, identity: &str) -> TunnelMode {
+        conn.write_frame(Frame::Handshake(HandshakeFrame {
+            identity: identity.to_string(),
+            token: None,
+            max_version: MAX_VERSION,
+            resume_token: None,
+            mode: TunnelMode::Tap,
+            pad: None,
+        }))
+        .await
+        .unwrap();
+        match conn.read_frame().await.unwrap() {
+            Frame::HandshakeReply(reply) => reply.mode,
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tap_refused_unless_allowed() {
+        let server = new_server(
+            server_config(),
+            vec![ClientConfig::for_test("a", "10.0.0.1")],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        assert_eq!(tap_handshake(&mut a, "a").await, TunnelMode::Tun);
+    }
+
+    #[tokio::test]
+    async fn test_tap_frames_switched_by_mac() {
+        let mut cfg = server_config();
+        cfg.allow_tap = true;
+        let clients = ["a", "b", "c", "d"]
+            .iter()
+            .enumerate()
+            .map(|(i, identity)| ClientConfig::for_test(identity, &format!("10.0.0.{}", i + 1)))
+            .collect();
+        let server = new_server(cfg, clients);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut conns = Vec::new();
+        for identity in ["a", "b", "c"] {
+            let mut conn = connect(&server, &listener).await;
+            assert_eq!(tap_handshake(&mut conn, identity).await, TunnelMode::Tap);
+            exchange_keepalive(&mut conn, keepalive(identity, "", 0)).await;
+            conns.push(conn);
+        }
+        // a TUN client of the cluster never sees Ethernet frames
+        let mut d = connect(&server, &listener).await;
+        handshake(&mut d, "d").await.unwrap();
+        exchange_keepalive(&mut d, keepalive("d", "", 0)).await;
+        let (mut c, mut b, mut a) = (
+            conns.pop().unwrap(),
+            conns.pop().unwrap(),
+            conns.pop().unwrap(),
+        );
+        let (mac_a, mac_b, mac_c) = (
+            [0x02, 0, 0, 0, 0, 0x0a],
+            [0x02, 0, 0, 0, 0, 0x0b],
+            [0x02, 0, 0, 0, 0, 0x0c],
+        );
+
+        // b is not learned yet, its frame is flooded
+        a.write_frame(ethernet_frame(mac_b, mac_a)).await.unwrap();
+        assert!(receives_data(&mut b).await);
+        assert!(receives_data(&mut c).await);
+
+        // the answer goes to the learned a only, and teaches b's MAC
+        b.write_frame(ethernet_frame(mac_a, mac_b)).await.unwrap();
+        assert!(receives_data(&mut a).await);
+        assert!(!receives_data(&mut c).await);
+        a.write_frame(ethernet_frame(mac_b, mac_a)).await.unwrap();
+        assert!(receives_data(&mut b).await);
+        assert!(!receives_data(&mut c).await);
+
+        // broadcasts reach every other TAP client
+        c.write_frame(ethernet_frame([0xff; 6], mac_c))
+            .await
+            .unwrap();
+        assert!(receives_data(&mut a).await);
+        assert!(receives_data(&mut b).await);
+        assert!(!receives_data(&mut d).await);
+    }
+
+    fn ipv4_frame(src: [u8; 4], dst: [u8; 4]) -> Frame {
+        Frame::Data(DataFrame {
+            payload: ipv4_packet(src, dst, 17, &[]),
+            seq: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_global_cidr_reachable_across_clusters() {
+        let mut dns = ClientConfig::for_test("dns", "10.0.0.1");
+        dns.ciders = vec!["172.30.0.0/24".to_string()];
+        let db = ClientConfig::for_test("db", "10.0.0.2");
+        let mut guest = ClientConfig::for_test("guest", "10.1.0.1");
+        guest.cluster = "other".to_string();
+        // same address as guest in a third tenant
+        let mut twin = ClientConfig::for_test("twin", "10.1.0.1");
+        twin.cluster = "third".to_string();
+        let client_manager = Arc::new(ClientManager::new());
+        client_manager.add_clients_config(vec![dns, db, guest, twin]);
+        let server = Server::new(
+            server_config(),
+            client_manager,
+            Arc::new(
+                ConnectionManager::new().with_global_cidrs(vec!["172.30.0.0/24".parse().unwrap()]),
+            ),
+            Arc::new(Box::new(PlainBlock::new())),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut conns = Vec::new();
+        for identity in ["dns", "db", "guest", "twin"] {
+            let mut conn = connect(&server, &listener).await;
+            handshake(&mut conn, identity).await.unwrap();
+            exchange_keepalive(&mut conn, keepalive(identity, "", 0)).await;
+            conns.push(conn);
+        }
+        let (mut twin, mut guest, mut db, mut dns) = (
+            conns.pop().unwrap(),
+            conns.pop().unwrap(),
+            conns.pop().unwrap(),
+            conns.pop().unwrap(),
+        );
+
+        // the shared service cannot reach into a cluster unasked
+        dns.write_frame(ipv4_frame([172, 30, 0, 53], [10, 1, 0, 1]))
+            .await
+            .unwrap();
+        assert!(!receives_data(&mut guest).await);
+        assert!(!receives_data(&mut twin).await);
+
+        // the shared service answers the other cluster
+        guest
+            .write_frame(ipv4_frame([10, 1, 0, 1], [172, 30, 0, 53]))
+            .await
+            .unwrap();
+        assert!(matches!(
+            read_skipping_updates(&mut dns).await,
+            Frame::Data(_)
+        ));
+        dns.write_frame(ipv4_frame([172, 30, 0, 53], [10, 1, 0, 1]))
+            .await
+            .unwrap();
+        assert!(matches!(
+            read_skipping_updates(&mut guest).await,
+            Frame::Data(_)
+        ));
+        assert!(!receives_data(&mut twin).await);
+
+        // everything else stays within its cluster
+        guest
+            .write_frame(ipv4_frame([10, 1, 0, 1], [10, 0, 0, 2]))
+            .await
+            .unwrap();
+        dns.write_frame(ipv4_frame([10, 0, 0, 1], [10, 1, 0, 1]))
+            .await
+            .unwrap();
+        assert!(!receives_data(&mut db).await);
+        assert!(!receives_data(&mut guest).await);
+    }
+
+    /// Sends everything to one client
+    struct FixedRouter(&'static str);
+
+    impl Router for FixedRouter {
+        fn route(&self, _: &ClientConfig, _: &str, _: &str, _: &[u8]) -> Option<(String, String)> {
+            Some(("test".to_string(), self.0.to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_router_overrides_default() {
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+                ClientConfig::for_test("c", "10.0.0.3"),
+            ],
+        )
+        .with_router(Arc::new(FixedRouter("c")));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut conns = Vec::new();
+        for identity in ["a", "b", "c"] {
+            let mut conn = connect(&server, &listener).await;
+            handshake(&mut conn, identity).await.unwrap();
+            exchange_keepalive(&mut conn, keepalive(identity, "", 0)).await;
+            conns.push(conn);
+        }
+        let (mut c, mut b, mut a) = (
+            conns.pop().unwrap(),
+            conns.pop().unwrap(),
+            conns.pop().unwrap(),
+        );
+
+        a.write_frame(ipv4_frame([10, 0, 0, 1], [10, 0, 0, 2]))
+            .await
+            .unwrap();
+        assert!(receives_data(&mut c).await);
+        assert!(!receives_data(&mut b).await);
+    }
+
+    #[tokio::test]
+    async fn test_full_destination_queue_drops_without_blocking() {
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        );
+        // b never reads, its queue is already full
+        let (slow_tx, _slow_rx) = mpsc::channel(1);
+        slow_tx
+            .try_send(Frame::Data(DataFrame {
+                payload: vec![],
+                seq: None,
+            }))
+            .unwrap();
+        let tx_dropped = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        server
+            .connection_manager
+            .add_connection(ConnectionMeta {
+                tx_dropped: tx_dropped.clone(),
+                ..ConnectionMeta::for_test("b", "10.0.0.2", slow_tx)
+            })
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        handshake(&mut a, "a").await.unwrap();
+        let packet = ipv4_packet([10, 0, 0, 1], [10, 0, 0, 2], 17, &[]);
+        for _ in 0..3 {
+            a.write_frame(Frame::Data(DataFrame {
+                payload: packet.clone(),
+                seq: None,
+            }))
+            .await
+            .unwrap();
+        }
+
+        // a's handler is still serving it
+        tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            exchange_keepalive(&mut a, keepalive("a", "", 0)),
+        )
+        .await
+        .expect("handler should not block on the full destination");
+        assert_eq!(tx_dropped.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_stun_change_pushes_peer_update() {
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        let mut b = connect(&server, &listener).await;
+        handshake(&mut a, "a").await.unwrap();
+        handshake(&mut b, "b").await.unwrap();
+        // a keepalive reply proves b's handler is registered
+        exchange_keepalive(&mut b, keepalive("b", "198.51.100.2", 4000)).await;
+
+        exchange_keepalive(&mut a, keepalive("a", "203.0.113.1", 5000)).await;
+        // unchanged address, nothing pushed
+        exchange_keepalive(&mut a, keepalive("a", "203.0.113.1", 5000)).await;
+        exchange_keepalive(&mut a, keepalive("a", "203.0.113.1", 6000)).await;
+
+        let mut read_update = async || {
+            tokio::time::timeout(std::time::Duration::from_secs(1), b.read_frame())
+                .await
+                .expect("peer update should be pushed")
+                .unwrap()
+        };
+        for port in [5000, 6000] {
+            match read_update().await {
+                Frame::PeerUpdate(update) => {
+                    assert_eq!(update.peer.identity, "a");
+                    assert_eq!(update.peer.private_ip, "10.0.0.1");
+                    assert_eq!(update.peer.stun_ip, "203.0.113.1");
+                    assert_eq!(update.peer.stun_port, port);
+                }
+                frame => panic!("unexpected frame {frame}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_for_another_identity_ignored() {
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        let mut b = connect(&server, &listener).await;
+        handshake(&mut a, "a").await.unwrap();
+        handshake(&mut b, "b").await.unwrap();
+        exchange_keepalive(&mut b, keepalive("b", "198.51.100.2", 4000)).await;
+
+        // a claims b's address, only its own keepalive is answered
+        a.write_frame(keepalive("b", "203.0.113.9", 7000))
+            .await
+            .unwrap();
+        let reply = exchange_keepalive(&mut a, keepalive("a", "", 0)).await;
+        assert_eq!(reply.identity, "a");
+
+        let b_meta = server
+            .connection_manager
+            .get_connection_by_identity("test", &"b".to_string())
+            .unwrap();
+        let stun = b_meta.stun.unwrap();
+        assert_eq!((stun.ip.as_str(), stun.port), ("198.51.100.2", 4000));
+    }
+
+    #[tokio::test]
+    async fn test_join_and_leave_pushed_to_cluster() {
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        handshake(&mut a, "a").await.unwrap();
+        // a keepalive reply proves a's handler is registered
+        exchange_keepalive(&mut a, keepalive("a", "", 0)).await;
+
+        let mut b = connect(&server, &listener).await;
+        handshake(&mut b, "b").await.unwrap();
+        let mut read_event = async || {
+            tokio::time::timeout(std::time::Duration::from_secs(1), a.read_frame())
+                .await
+                .expect("peer event should be pushed")
+                .unwrap()
+        };
+        match read_event().await {
+            Frame::PeerJoin(join) => {
+                assert_eq!(join.peer.identity, "b");
+                assert_eq!(join.peer.private_ip, "10.0.0.2");
+                assert_ne!(join.peer.last_active, 0);
+            }
+            frame => panic!("unexpected frame {frame}"),
+        }
+
+        b.close().await;
+        match read_event().await {
+            Frame::PeerLeave(leave) => assert_eq!(leave.identity, "b"),
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_join_not_pushed_to_clients_predating_it() {
+        use crate::codec::parser::MIN_VERSION;
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        a.write_frame(Frame::Handshake(HandshakeFrame {
+            identity: "a".to_string(),
+            token: None,
+            max_version: MIN_VERSION,
+            resume_token: None,
+            mode: Default::default(),
+            pad: None,
+        }))
+        .await
+        .unwrap();
+        a.read_frame().await.unwrap();
+        exchange_keepalive(&mut a, keepalive("a", "", 0)).await;
+
+        let mut b = connect(&server, &listener).await;
+        handshake(&mut b, "b").await.unwrap();
+        // b is registered, and its join handed out, once it gets a reply
+        exchange_keepalive(&mut b, keepalive("b", "", 0)).await;
+
+        // a learns of b from its keepalive reply instead
+        a.write_frame(keepalive("a", "", 0)).await.unwrap();
+        loop {
+            match a.read_frame().await.unwrap() {
+                Frame::KeepAlive(reply) => {
+                    assert!(reply.peer_details.iter().any(|peer| peer.identity == "b"));
+                    break;
+                }
+                Frame::PeerUpdate(_) => continue,
+                frame => panic!("unexpected frame {frame}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cluster_keys_isolate_tenants() {
+        use crate::codec::parser::Parser;
+        use crate::crypto::CryptoConfig;
+
+        let key = |key: &str| -> Arc<Box<dyn Block>> {
+            Arc::new(crate::crypto::new_block(&CryptoConfig::ChaCha20Poly1305(
+                key.to_string(),
+            )))
+        };
+        let (alpha, beta) = (key("alpha-key"), key("beta-key"));
+        let mut a = ClientConfig::for_test("a", "10.0.0.1");
+        a.cluster = "alpha".to_string();
+        let mut b = ClientConfig::for_test("b", "10.0.1.1");
+        b.cluster = "beta".to_string();
+        let server = new_server(server_config(), vec![a, b]).with_cluster_blocks(HashMap::from([
+            ("alpha".to_string(), alpha.clone()),
+            ("beta".to_string(), beta.clone()),
+        ]));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        // handshakes use the server key, later frames the cluster key
+        let mut conn_a = connect(&server, &listener).await;
+        let reply = handshake(&mut conn_a, "a").await.unwrap();
+        assert!(matches!(reply, Frame::HandshakeReply(_)));
+        conn_a.set_block(alpha.clone());
+        exchange_keepalive(&mut conn_a, keepalive("a", "", 0)).await;
+
+        // a client holding another tenant's key is not understood
+        let mut conn_b = connect(&server, &listener).await;
+        handshake(&mut conn_b, "b").await.unwrap();
+        conn_b.set_block(alpha.clone());
+        conn_b.set_read_timeout(Duration::from_millis(300));
+        conn_b.write_frame(keepalive("b", "", 0)).await.unwrap();
+        assert!(conn_b.read_frame().await.is_err());
+
+        let frame = Parser::marshal(keepalive("b", "", 0), beta.as_ref().as_ref()).unwrap();
+        assert!(Parser::unmarshal(&frame, alpha.as_ref().as_ref()).is_err());
+        assert!(Parser::unmarshal(&frame, beta.as_ref().as_ref()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_cluster_key_peers_not_in_handshake_reply() {
+        let alpha: Arc<Box<dyn Block>> = Arc::new(crate::crypto::new_block(
+            &crate::crypto::CryptoConfig::ChaCha20Poly1305("alpha-key".to_string()),
+        ));
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        )
+        .with_cluster_blocks(HashMap::from([("test".to_string(), alpha.clone())]));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        handshake(&mut a, "a").await.unwrap();
+
+        // b's reply travels under the handshake key, so it lists nobody
+        let mut b = connect(&server, &listener).await;
+        match handshake(&mut b, "b").await.unwrap() {
+            Frame::HandshakeReply(reply) => {
+                assert!(reply.peer_details.is_empty());
+                assert_eq!(reply.peers_version, 0);
+            }
+            frame => panic!("unexpected frame {frame}"),
         }
+        b.set_block(alpha);
+        let reply = exchange_keepalive(&mut b, keepalive("b", "", 0)).await;
+        let peers: Vec<_> = reply
+            .peer_details
+            .iter()
+            .map(|p| p.identity.as_str())
+            .collect();
+        assert_eq!(peers, vec!["a"]);
+        assert_ne!(reply.peers_version, 0);
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_omits_current_peer_list() {
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.2"),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        let mut b = connect(&server, &listener).await;
+        let handshake_version = match handshake(&mut a, "a").await.unwrap() {
+            Frame::HandshakeReply(reply) => reply.peers_version,
+            frame => panic!("unexpected frame {frame}"),
+        };
+        handshake(&mut b, "b").await.unwrap();
+        exchange_keepalive(&mut b, keepalive("b", "198.51.100.2", 4000)).await;
+
+        let with_version = |version| {
+            let Frame::KeepAlive(mut frame) = keepalive("a", "", 0) else {
+                unreachable!()
+            };
+            frame.peers_version = version;
+            Frame::KeepAlive(frame)
+        };
+
+        // b came online since the handshake, a is behind
+        let reply = exchange_keepalive(&mut a, with_version(handshake_version)).await;
+        assert!(reply.peers_version > handshake_version);
+        assert_eq!(reply.peer_details.len(), 1);
+        assert_eq!(reply.peer_details[0].stun_port, 4000);
+        let current = reply.peers_version;
+
+        let reply = exchange_keepalive(&mut a, with_version(current)).await;
+        assert_eq!(reply.peers_version, current);
+        assert!(reply.peer_details.is_empty());
+
+        // a client not tracking versions always gets the full list
+        let reply = exchange_keepalive(&mut a, with_version(0)).await;
+        assert_eq!(reply.peers_version, current);
+        assert_eq!(reply.peer_details.len(), 1);
+
+        // b's new address is a new version
+        exchange_keepalive(&mut b, keepalive("b", "198.51.100.2", 4001)).await;
+        let reply = exchange_keepalive(&mut a, with_version(current)).await;
+        assert!(reply.peers_version > current);
+        assert_eq!(reply.peer_details[0].stun_port, 4001);
+    }
+
+    #[test]
+    fn test_fingerprint_hash_is_fnv1a() {
+        let hash = |bytes: &[u8]| {
+            let mut hasher = Fnv1a::default();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    /// Backend granting "a", "c" and "d" holding token "secret", "d" with
+    /// the private IP of "a"
+    struct TokenAuth;
+
+    #[async_trait::async_trait]
+    impl AuthBackend for TokenAuth {
+        async fn authenticate(&self, identity: &str, token: Option<&str>) -> Option<ClientConfig> {
+            let private_ip = match identity {
+                "a" => "10.0.0.9",
+                "c" => "10.0.0.10",
+                "d" => "10.0.0.9",
+                _ => return None,
+            };
+            (token == Some("secret")).then(|| ClientConfig::for_test(identity, private_ip))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_backend_decides_handshake() {
+        // no routes file entries: only the backend can grant access
+        let server = new_server(server_config(), vec![]).with_auth_backend(Arc::new(TokenAuth));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut granted = connect(&server, &listener).await;
+        granted
+            .write_frame(Frame::Handshake(HandshakeFrame {
+                identity: "a".to_string(),
+                token: Some("secret".to_string()),
+                max_version: MAX_VERSION,
+                resume_token: None,
+                mode: Default::default(),
+                pad: None,
+            }))
+            .await
+            .unwrap();
+        match granted.read_frame().await.unwrap() {
+            Frame::HandshakeReply(reply) => assert_eq!(reply.private_ip, "10.0.0.9"),
+            frame => panic!("unexpected frame {frame}"),
+        }
+
+        let mut denied = connect(&server, &listener).await;
+        denied
+            .write_frame(Frame::Handshake(HandshakeFrame {
+                identity: "b".to_string(),
+                token: Some("wrong".to_string()),
+                max_version: MAX_VERSION,
+                resume_token: None,
+                mode: Default::default(),
+                pad: None,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            denied.read_frame().await.unwrap(),
+            Frame::HandshakeReject(reject) if reject.reason == REJECT_UNAUTHORIZED
+        ));
+
+        // the backend's clients are listed to each other
+        let mut c = connect(&server, &listener).await;
+        c.write_frame(Frame::Handshake(HandshakeFrame {
+            identity: "c".to_string(),
+            token: Some("secret".to_string()),
+            max_version: MAX_VERSION,
+            resume_token: None,
+            mode: Default::default(),
+            pad: None,
+        }))
+        .await
+        .unwrap();
+        match c.read_frame().await.unwrap() {
+            Frame::HandshakeReply(reply) => {
+                let peers: Vec<_> = reply.peer_details.iter().map(|p| &p.identity).collect();
+                assert_eq!(peers, vec!["a"]);
+            }
+            frame => panic!("unexpected frame {frame}"),
+        }
+
+        // a granted client turned down later is not registered
+        let mut d = connect(&server, &listener).await;
+        d.write_frame(Frame::Handshake(HandshakeFrame {
+            identity: "d".to_string(),
+            token: Some("secret".to_string()),
+            max_version: MAX_VERSION,
+            resume_token: None,
+            mode: Default::default(),
+            pad: None,
+        }))
+        .await
+        .unwrap();
+        assert!(matches!(
+            d.read_frame().await.unwrap(),
+            Frame::HandshakeReject(reject) if reject.reason == "ip conflict"
+        ));
+        assert!(server.client_manager.get_client(&"d".to_string()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_connection_slot_released_when_handler_ends() {
+        let mut cfg = server_config();
+        cfg.max_connections = Some(1);
+        let server = new_server(cfg, vec![]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        // unknown identity: the handler exits right after the handshake
+        let mut a = connect(&server, &listener).await;
+        let _ = handshake(&mut a, "unknown").await;
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while server.active_connections.load(Ordering::Relaxed) != 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("slot should be released");
+        assert!(server.acquire_slot().is_some());
+    }
+
+    /// Log sink shared with a test subscriber
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLogs {
+        fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frame_tracing_fields() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::new(
+                "rustun=info,rustun[{frame_type=data}]=debug",
+            ))
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let server = new_server(
+            server_config(),
+            vec![ClientConfig::for_test("a", "10.0.0.1")],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        handshake(&mut a, "a").await.unwrap();
+
+        a.write_frame(keepalive("a", "", 0)).await.unwrap();
+        a.write_frame(Frame::Data(DataFrame {
+            payload: ipv4_packet([10, 0, 0, 1], [10, 0, 0, 9], 17, &[]),
+            seq: None,
+        }))
+        .await
+        .unwrap();
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while !logs.contents().contains("no route to 10.0.0.9") {
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("data frame should be logged");
+
+        let output = logs.contents();
+        let line = output
+            .lines()
+            .find(|line| line.contains("read frame"))
+            .expect("read event for the data frame");
+        assert!(line.contains(r#"identity="a""#), "{line}");
+        assert!(line.contains(r#"cluster="test""#), "{line}");
+        assert!(line.contains(r#"frame_type="data""#), "{line}");
+        assert!(line.contains("payload_len=20"), "{line}");
+        // debug is only enabled inside data frame spans
+        assert!(
+            !output
+                .lines()
+                .any(|line| line.contains("DEBUG") && line.contains(r#"frame_type="keepalive""#)),
+            "{output}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_identity_is_unauthorized() {
+        let (mut handler, mut client) = pending_handler(Duration::from_secs(2)).await;
+        client.write_frame(handshake_frame("nobody")).await.unwrap();
+        match handler.run().await {
+            Err(RustunError::Unauthorized(identity)) => assert_eq!(identity, "nobody"),
+            result => panic!("unexpected result {result:?}"),
+        }
+        match client.read_frame().await.unwrap() {
+            Frame::HandshakeReject(reject) => assert_eq!(reject.reason, REJECT_UNAUTHORIZED),
+            frame => panic!("unexpected frame {frame}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_private_ip_rejected() {
+        let server = new_server(
+            server_config(),
+            vec![
+                ClientConfig::for_test("a", "10.0.0.1"),
+                ClientConfig::for_test("b", "10.0.0.1"),
+            ],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut a = connect(&server, &listener).await;
+        handshake(&mut a, "a").await.unwrap();
+        exchange_keepalive(&mut a, keepalive("a", "", 0)).await;
+
+        let mut b = connect(&server, &listener).await;
+        match handshake(&mut b, "b").await.unwrap() {
+            Frame::HandshakeReject(reject) => assert_eq!(reject.reason, "ip conflict"),
+            frame => panic!("unexpected frame {frame}"),
+        }
+        assert!(b.read_frame().await.is_err());
+
+        // the owner of the address reconnecting is no conflict
+        let mut again = connect(&server, &listener).await;
+        assert!(matches!(
+            handshake(&mut again, "a").await.unwrap(),
+            Frame::HandshakeReply(_)
+        ));
     }
 }
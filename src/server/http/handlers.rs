@@ -0,0 +1,203 @@
+//! HTTP request handlers
+
+use super::models::{
+    ConnectionInfo, HealthResponse, KickRequest, KickResponse, MetricsResponse, RouteInfo,
+};
+use crate::network::connection_manager::ConnectionManager;
+use crate::server::client_manager::ClientManager;
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::{extract::State, response::Json};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Shared state for the HTTP server
+#[derive(Clone)]
+pub struct AppState {
+    connection_manager: Arc<ConnectionManager>,
+    client_manager: Arc<ClientManager>,
+    /// Bearer token of the admin endpoints
+    admin_token: Option<String>,
+}
+
+impl AppState {
+    pub fn new(
+        connection_manager: Arc<ConnectionManager>,
+        client_manager: Arc<ClientManager>,
+    ) -> Self {
+        Self {
+            connection_manager,
+            client_manager,
+            admin_token: None,
+        }
+    }
+
+    /// Accept admin requests carrying `token`
+    pub fn with_admin_token(mut self, token: Option<String>) -> Self {
+        self.admin_token = token;
+        self
+    }
+
+    /// Whether `headers` carry the admin bearer token
+    fn is_admin(&self, headers: &HeaderMap) -> bool {
+        let Some(expected) = self.admin_token.as_deref() else {
+            return false;
+        };
+        let Some(token) = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+        // compare in constant time so the token cannot be guessed byte by byte
+        token.len() == expected.len()
+            && token
+                .bytes()
+                .zip(expected.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+/// Health check endpoint
+pub async fn health(State(state): State<AppState>) -> Json<HealthResponse> {
+    let connections = state.connection_manager.count_by_cluster().values().sum();
+    Json(HealthResponse {
+        status: "ok".to_string(),
+        service: "rustun-server".to_string(),
+        connections,
+    })
+}
+
+/// Metrics endpoint handler
+pub async fn metrics(State(state): State<AppState>) -> Json<MetricsResponse> {
+    let clusters = state.connection_manager.count_by_cluster();
+    Json(MetricsResponse {
+        total_connections: clusters.values().sum(),
+        clusters,
+        invalid_packets: state.connection_manager.invalid_packets(),
+        frames: state.connection_manager.frame_counts(),
+    })
+}
+
+/// Connections endpoint handler
+pub async fn connections(State(state): State<AppState>) -> Json<Vec<ConnectionInfo>> {
+    Json(
+        state
+            .connection_manager
+            .list_connections()
+            .into_iter()
+            .map(ConnectionInfo::from)
+            .collect(),
+    )
+}
+
+/// Kick endpoint handler
+///
+/// Disconnects a client by cluster and identity, see
+/// `ConnectionManager::kick`. Requests without the admin token get 401.
+pub async fn kick(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<KickRequest>,
+) -> Result<Json<KickResponse>, StatusCode> {
+    if !state.is_admin(&headers) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let kicked = state
+        .connection_manager
+        .kick(&request.cluster, &request.identity);
+    Ok(Json(KickResponse { kicked }))
+}
+
+/// Routes endpoint handler
+///
+/// Every configured client per cluster, with its CIDRs and whether it is
+/// currently connected.
+pub async fn routes(State(state): State<AppState>) -> Json<BTreeMap<String, Vec<RouteInfo>>> {
+    let mut clusters: BTreeMap<String, Vec<RouteInfo>> = BTreeMap::new();
+    for client in state.client_manager.list_clients() {
+        let conn = state
+            .connection_manager
+            .get_connection_by_identity(&client.cluster, &client.identity);
+        let route = RouteInfo {
+            identity: client.identity,
+            private_ip: client.private_ip,
+            ciders: client.ciders,
+            online: conn.is_some(),
+            ipv6: conn.as_ref().map(|c| c.ipv6.clone()).unwrap_or_default(),
+            stun: conn
+                .as_ref()
+                .and_then(|c| c.stun.as_ref())
+                .map(|stun| format!("{}:{}", stun.ip, stun.port)),
+            last_active: conn.as_ref().map(|c| c.last_active).unwrap_or(0),
+            connected_at: conn.as_ref().map(|c| c.connected_at).unwrap_or(0),
+            reconnect_count: conn.map(|c| c.reconnect_count).unwrap_or(0),
+        };
+        clusters.entry(client.cluster).or_default().push(route);
+    }
+    Json(clusters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::ConnectionMeta;
+    use crate::server::client_manager::ClientConfig;
+    use crate::utils::StunAddr;
+    use tokio::sync::mpsc;
+
+    fn client(cluster: &str, identity: &str, private_ip: &str, ciders: &[&str]) -> ClientConfig {
+        ClientConfig {
+            cluster: cluster.to_string(),
+            ciders: ciders.iter().map(|c| c.to_string()).collect(),
+            ..ClientConfig::for_test(identity, private_ip)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routes_join_config_and_connections() {
+        let connection_manager = Arc::new(ConnectionManager::new());
+        let client_manager = Arc::new(ClientManager::new());
+        let gw = client("office", "gw", "10.0.0.1", &["192.168.1.0/24"]);
+        client_manager.add_clients_config(vec![
+            gw.clone(),
+            client("office", "laptop", "10.0.0.2", &[]),
+            client("lab", "nas", "10.1.0.1", &["172.16.0.0/16"]),
+        ]);
+        let (tx, _rx) = mpsc::channel(1);
+        connection_manager
+            .add_connection(ConnectionMeta {
+                cluster: gw.cluster.clone(),
+                ciders: gw.ciders.clone(),
+                ipv6: vec!["2001:db8::1".to_string()],
+                port: 51258,
+                stun: Some(StunAddr {
+                    ip: "203.0.113.1".to_string(),
+                    port: 40000,
+                    nat_type: 0,
+                }),
+                last_active: 1_700_000_000,
+                ..ConnectionMeta::for_test(&gw.identity, &gw.private_ip, tx)
+            })
+            .unwrap();
+
+        let Json(routes) = routes(State(AppState::new(connection_manager, client_manager))).await;
+        let json = serde_json::to_value(&routes).unwrap();
+
+        assert_eq!(json["office"][0]["identity"], "gw");
+        assert_eq!(json["office"][0]["online"], true);
+        assert_eq!(json["office"][0]["ciders"][0], "192.168.1.0/24");
+        assert_eq!(json["office"][0]["ipv6"][0], "2001:db8::1");
+        assert_eq!(json["office"][0]["stun"], "203.0.113.1:40000");
+        assert_eq!(json["office"][0]["last_active"], 1_700_000_000);
+
+        assert_eq!(json["office"][1]["identity"], "laptop");
+        assert_eq!(json["office"][1]["online"], false);
+        assert!(json["office"][1]["stun"].is_null());
+
+        assert_eq!(json["lab"][0]["identity"], "nas");
+        assert_eq!(json["lab"][0]["online"], false);
+        assert_eq!(json["lab"][0]["ciders"][0], "172.16.0.0/16");
+    }
+}
@@ -0,0 +1,6 @@
+mod handlers;
+pub mod models;
+pub mod server;
+
+// Re-export commonly used types
+pub use models::*;
@@ -0,0 +1,107 @@
+//! HTTP API response models
+
+use crate::codec::stats::FrameCounts;
+use crate::network::connection_manager::ConnectionSummary;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Health check response
+#[derive(Serialize, Debug, Clone)]
+pub struct HealthResponse {
+    pub status: String,
+    pub service: String,
+    /// Number of connected clients
+    pub connections: usize,
+}
+
+/// Metrics response
+#[derive(Serialize, Debug, Clone)]
+pub struct MetricsResponse {
+    /// Number of connected clients
+    pub total_connections: usize,
+    /// Connected clients per cluster
+    pub clusters: BTreeMap<String, usize>,
+    /// Tunneled packets dropped for failing IP validation
+    pub invalid_packets: u64,
+    /// Frames exchanged with all clients since the server started, by type
+    pub frames: BTreeMap<&'static str, FrameCounts>,
+}
+
+/// Connected client information
+#[derive(Serialize, Debug, Clone)]
+pub struct ConnectionInfo {
+    pub cluster: String,
+    pub identity: String,
+    pub private_ip: String,
+    pub ipv6: Vec<String>,
+    pub port: u16,
+    pub stun_ip: String,
+    pub stun_port: u16,
+    /// Last keepalive (Unix timestamp in seconds)
+    pub last_active: u64,
+    /// When the connection registered (Unix timestamp in seconds)
+    pub connected_at: u64,
+    /// Times the client connected again since the server started
+    pub reconnect_count: u64,
+    /// Frames dropped because the client's queue was full
+    pub tx_dropped: u64,
+    /// Frames exchanged with the client by type
+    pub frames: BTreeMap<&'static str, FrameCounts>,
+}
+
+impl From<ConnectionSummary> for ConnectionInfo {
+    fn from(summary: ConnectionSummary) -> Self {
+        let (stun_ip, stun_port) = summary
+            .stun
+            .map(|stun| (stun.ip, stun.port))
+            .unwrap_or_default();
+        Self {
+            cluster: summary.cluster,
+            identity: summary.identity,
+            private_ip: summary.private_ip,
+            ipv6: summary.ipv6,
+            port: summary.port,
+            stun_ip,
+            stun_port,
+            last_active: summary.last_active,
+            connected_at: summary.connected_at,
+            reconnect_count: summary.reconnect_count,
+            tx_dropped: summary.tx_dropped,
+            frames: summary.frames,
+        }
+    }
+}
+
+/// Kick request body
+#[derive(Deserialize, Debug, Clone)]
+pub struct KickRequest {
+    pub cluster: String,
+    pub identity: String,
+}
+
+/// Kick response
+#[derive(Serialize, Debug, Clone)]
+pub struct KickResponse {
+    /// Whether a connection was closed
+    pub kicked: bool,
+}
+
+/// Routing entry of a configured client
+#[derive(Serialize, Debug, Clone)]
+pub struct RouteInfo {
+    pub identity: String,
+    pub private_ip: String,
+    /// CIDR ranges routed to this client
+    pub ciders: Vec<String>,
+    /// Whether the client is connected
+    pub online: bool,
+    pub ipv6: Vec<String>,
+    /// Public STUN address as "ip:port"
+    pub stun: Option<String>,
+    /// Last keepalive (Unix timestamp in seconds, 0 when offline)
+    pub last_active: u64,
+    /// When the client connected (Unix timestamp in seconds, 0 when offline)
+    pub connected_at: u64,
+    /// Times the client connected again since the server started
+    pub reconnect_count: u64,
+}
@@ -0,0 +1,120 @@
+//! HTTP server setup and management
+
+use super::handlers::{AppState, connections, health, kick, metrics, routes};
+use crate::network::connection_manager::ConnectionManager;
+use crate::server::client_manager::ClientManager;
+use axum::{
+    Router,
+    routing::{get, post},
+};
+use std::sync::Arc;
+
+/// Build the HTTP router
+///
+/// The admin endpoints are only served with an `admin_token`.
+fn router(
+    connection_manager: Arc<ConnectionManager>,
+    client_manager: Arc<ClientManager>,
+    admin_token: Option<String>,
+) -> Router {
+    let mut router = Router::new()
+        .route("/health", get(health))
+        .route("/metrics", get(metrics))
+        .route("/connections", get(connections))
+        .route("/routes", get(routes));
+    if admin_token.is_some() {
+        router = router.route("/admin/kick", post(kick));
+    }
+    router
+        .with_state(AppState::new(connection_manager, client_manager).with_admin_token(admin_token))
+}
+
+/// Start the HTTP server
+pub async fn start(
+    port: u16,
+    connection_manager: Arc<ConnectionManager>,
+    client_manager: Arc<ClientManager>,
+    admin_token: Option<String>,
+) -> anyhow::Result<()> {
+    let app = router(connection_manager, client_manager, admin_token);
+
+    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{port}")).await?;
+    tracing::info!("HTTP metrics server listening on http://127.0.0.1:{port}/metrics");
+
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::ConnectionMeta;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode, header};
+    use tokio::sync::mpsc;
+    use tower::ServiceExt;
+
+    fn kick_request(token: Option<&str>) -> Request<Body> {
+        let mut request =
+            Request::post("/admin/kick").header(header::CONTENT_TYPE, "application/json");
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        request
+            .body(Body::from(r#"{"cluster":"office","identity":"laptop"}"#))
+            .unwrap()
+    }
+
+    async fn kicked(router: &Router, token: &str) -> bool {
+        let response = router
+            .clone()
+            .oneshot(kick_request(Some(token)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()["kicked"]
+            .as_bool()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admin_kick() {
+        let connection_manager = Arc::new(ConnectionManager::new());
+        let (tx, mut rx) = mpsc::channel(1);
+        connection_manager
+            .add_connection(ConnectionMeta {
+                cluster: "office".to_string(),
+                ..ConnectionMeta::for_test("laptop", "10.0.0.2", tx)
+            })
+            .unwrap();
+        let client_manager = Arc::new(ClientManager::new());
+
+        // not served without an admin token
+        let response = router(connection_manager.clone(), client_manager.clone(), None)
+            .oneshot(kick_request(Some("secret")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let router = router(
+            connection_manager.clone(),
+            client_manager,
+            Some("secret".to_string()),
+        );
+        for token in [None, Some("wrong")] {
+            let response = router.clone().oneshot(kick_request(token)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        assert!(
+            rx.try_recv()
+                .is_err_and(|e| e == mpsc::error::TryRecvError::Empty)
+        );
+
+        assert!(kicked(&router, "secret").await);
+        assert!(rx.recv().await.is_none());
+        assert!(!kicked(&router, "secret").await);
+    }
+}
@@ -1,25 +1,57 @@
 use crate::network::connection_manager::ConnectionManager;
+use crate::server::access_log::AccessLog;
+use crate::server::auth::HttpAuthBackend;
 use crate::server::client_manager::ClientManager;
 use crate::server::conf_agent::ConfAgent;
 use crate::server::config;
 use crate::server::config_watcher::ConfigWatcher;
 use crate::server::handler::Server;
+use crate::server::http;
 use crate::{crypto, utils};
 use std::sync::Arc;
+use std::time::Duration;
 
 pub async fn run_server() -> anyhow::Result<()> {
     let args = std::env::args().collect::<Vec<String>>();
-    let cfg = config::load_main(args.get(1).unwrap_or(&"server.toml".to_string())).unwrap();
+    let cfg = config::load_main(args.get(1).unwrap_or(&"server.toml".to_string()))?;
+    let magic = cfg.server_config.frame_magic.resolve(&cfg.crypto_config)?;
 
     if let Err(e) = utils::init_tracing() {
         anyhow::bail!("Failed to initialize logging: {e}");
     }
+    match cfg.effective() {
+        Ok(effective) => tracing::info!("effective config:\n{effective}"),
+        Err(e) => tracing::warn!("failed to render effective config: {e}"),
+    }
 
     let routes_file = cfg.route_config.routes_file.clone();
     let client_routes = config::load_routes(routes_file.as_str()).unwrap();
-    tracing::debug!("config: {cfg:?}, routes: {client_routes:?}");
+    tracing::debug!("routes: {client_routes:?}");
+
+    let global_cidrs = cfg
+        .server_config
+        .global_cidrs
+        .iter()
+        .map(|cidr| {
+            cidr.parse()
+                .map_err(|e| anyhow::anyhow!("invalid global CIDR {cidr}: {e}"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
-    let client_manager = Arc::new(ClientManager::new());
+    // Create connection manager
+    let connection_manager = Arc::new(
+        ConnectionManager::new()
+            .with_offline_buffer(
+                cfg.server_config.offline_buffer_size,
+                Duration::from_secs(cfg.server_config.offline_buffer_ttl),
+            )
+            .with_peer_ttl(Duration::from_secs(cfg.server_config.peer_ttl))
+            .with_route_policy(cfg.server_config.route_policy)
+            .with_global_cidrs(global_cidrs),
+    );
+
+    let client_manager =
+        Arc::new(ClientManager::new().with_connection_manager(connection_manager.clone()));
     client_manager.add_clients_config(client_routes.clone());
 
     // load dynamic client configurations
@@ -27,9 +59,29 @@ pub async fn run_server() -> anyhow::Result<()> {
     watcher.reload();
 
     let block = crypto::new_block(&cfg.crypto_config);
+    let cluster_blocks = cfg
+        .cluster_crypto
+        .iter()
+        .map(|(cluster, crypto_config)| {
+            tracing::info!("cluster {cluster} uses its own key");
+            (cluster.clone(), Arc::new(crypto::new_block(crypto_config)))
+        })
+        .collect();
 
-    // Create connection manager
-    let connection_manager = Arc::new(ConnectionManager::new());
+    // Start HTTP metrics server if configured
+    if let Some(http_port) = cfg.server_config.http_port {
+        let connection_manager = connection_manager.clone();
+        let client_manager = client_manager.clone();
+        let admin_token = cfg.server_config.admin_token.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                http::server::start(http_port, connection_manager, client_manager, admin_token)
+                    .await
+            {
+                tracing::error!("HTTP server error: {e:?}");
+            }
+        });
+    }
 
     // Create conf-agent if configured
     if let Some(ref conf_agent_config) = cfg.conf_agent {
@@ -40,13 +92,16 @@ pub async fn run_server() -> anyhow::Result<()> {
             routes_file.clone(),
         ));
 
-        // Start conf-agent background task
+        // Start conf-agent background task, clients are accepted once it
+        // fetched the routes or gave up and fell back to the routes file
         let agent_clone = agent.clone();
+        let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
         tokio::spawn(async move {
-            if let Err(e) = agent_clone.start().await {
+            if let Err(e) = agent_clone.start(ready_tx).await {
                 tracing::error!("Conf-agent error: {e:?}");
             }
         });
+        let _ = ready_rx.await;
     }
 
     let mut server = Server::new(
@@ -54,7 +109,17 @@ pub async fn run_server() -> anyhow::Result<()> {
         client_manager,
         connection_manager.clone(),
         Arc::new(block),
-    );
+    )
+    .with_cluster_blocks(cluster_blocks)
+    .with_magic(magic);
+    if let Some(access_log) = &cfg.server_config.access_log {
+        tracing::info!("Logging routed flows to {}", access_log.path);
+        server = server.with_access_log(AccessLog::open(access_log)?);
+    }
+    if let Some(auth_config) = &cfg.auth {
+        tracing::info!("Authenticating clients with {}", auth_config.url);
+        server = server.with_auth_backend(Arc::new(HttpAuthBackend::new(auth_config)));
+    }
     if let Err(e) = server.run().await {
         anyhow::bail!("Server error: {e}");
     }
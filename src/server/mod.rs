@@ -1,6 +1,14 @@
+pub mod access_log;
+pub mod auth;
 mod client_manager;
 pub mod conf_agent;
 pub mod config;
 mod config_watcher;
 mod handler;
+pub mod http;
 pub mod main;
+mod resumption;
+pub mod router;
+
+pub use client_manager::{ClientConfig, ClientManager};
+pub use handler::Server;
@@ -0,0 +1,94 @@
+//! Session resumption for clients reconnecting shortly after a drop
+//!
+//! Every handshake reply carries a fresh token. When the connection ends
+//! the server keeps the peer list the client last had under that token for
+//! `ttl`; a client handshaking again with it only gets told what changed.
+
+use crate::codec::frame::PeerDetail;
+use chacha20poly1305::aead::{OsRng, rand_core::RngCore};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A session that ended and may still be resumed
+#[derive(Debug)]
+struct Suspended {
+    cluster: String,
+    identity: String,
+    /// Peer list the client had when the session ended
+    peers: Vec<PeerDetail>,
+    expires_at: Instant,
+}
+
+/// Sessions that may be resumed, by token
+#[derive(Debug)]
+pub struct ResumptionStore {
+    ttl: Duration,
+    sessions: Mutex<HashMap<String, Suspended>>,
+}
+
+impl ResumptionStore {
+    /// Store keeping ended sessions for `ttl`, resumption is disabled if zero
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A new token for a session, `None` if resumption is disabled
+    pub fn issue(&self) -> Option<String> {
+        if self.ttl.is_zero() {
+            return None;
+        }
+        let mut token = [0u8; 16];
+        OsRng.fill_bytes(&mut token);
+        Some(token.iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    /// Keep the session of `token` resumable once it ended
+    ///
+    /// # Arguments
+    /// - `peers` - Peer list the client had at the end of the session
+    pub fn suspend(&self, token: String, cluster: &str, identity: &str, peers: Vec<PeerDetail>) {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.retain(|_, session| session.expires_at > now);
+        sessions.insert(
+            token,
+            Suspended {
+                cluster: cluster.to_string(),
+                identity: identity.to_string(),
+                peers,
+                expires_at: now + self.ttl,
+            },
+        );
+    }
+
+    /// Take the session of `token` back up
+    ///
+    /// A token is only good once and only for the client it was issued to.
+    ///
+    /// # Returns
+    /// - `Some(peers)` - Peer list the client had when the session ended
+    /// - `None` - Unknown, foreign or expired token
+    pub fn resume(&self, token: &str, cluster: &str, identity: &str) -> Option<Vec<PeerDetail>> {
+        let session = self
+            .sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(token)?;
+        if session.expires_at <= Instant::now() {
+            tracing::debug!("resume token of {identity} expired");
+            return None;
+        }
+        if session.cluster != cluster || session.identity != identity {
+            tracing::warn!(
+                "{identity} presented a resume token issued to {}",
+                session.identity
+            );
+            return None;
+        }
+        Some(session.peers)
+    }
+}
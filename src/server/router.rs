@@ -0,0 +1,95 @@
+//! Packet routing strategies
+//!
+//! The handler asks a `Router` which client a tunneled IP packet goes to.
+//! `DefaultRouter` keeps tenants apart: the longest prefix match within the
+//! sender's cluster, across clusters only for global CIDRs and their
+//! answers to the cluster that sent. Embedders supply their own for policy based, weighted or geo
+//! routing, the `ConnectionManager` stays the store of connected clients.
+
+use crate::network::connection_manager::ConnectionManager;
+use crate::server::client_manager::ClientConfig;
+use std::sync::Arc;
+
+/// Picks the client a tunneled packet is delivered to
+pub trait Router: Send + Sync {
+    /// Route a packet sent by a client
+    ///
+    /// # Arguments
+    /// - `sender` - Configuration of the sending client, its cluster included
+    /// - `src` - Source address of the packet
+    /// - `dst` - Destination address of the packet
+    /// - `packet` - The whole IP packet
+    ///
+    /// # Returns
+    /// - `Some((cluster, identity))` - Deliver to the client with this
+    ///   identity in this cluster
+    /// - `None` - No route, the packet is buffered for an offline client of
+    ///   the cluster if it has one
+    fn route(
+        &self,
+        sender: &ClientConfig,
+        src: &str,
+        dst: &str,
+        packet: &[u8],
+    ) -> Option<(String, String)>;
+}
+
+/// Cluster scoped longest prefix routing over the connected clients
+pub struct DefaultRouter {
+    connections: Arc<ConnectionManager>,
+}
+
+impl DefaultRouter {
+    pub fn new(connections: Arc<ConnectionManager>) -> Self {
+        Self { connections }
+    }
+
+    /// Whether `src` is a global address `sender` owns, so its packets may
+    /// leave the cluster
+    fn answers_from_global(&self, sender: &ClientConfig, src: &str) -> bool {
+        self.connections.is_global(src)
+            && (sender.private_ip == src
+                || src.parse::<std::net::IpAddr>().is_ok_and(|ip| {
+                    sender
+                        .ciders
+                        .iter()
+                        .filter_map(|cidr| cidr.parse::<ipnet::IpNet>().ok())
+                        .any(|cidr| cidr.contains(&ip))
+                }))
+    }
+}
+
+impl Router for DefaultRouter {
+    fn route(
+        &self,
+        sender: &ClientConfig,
+        src: &str,
+        dst: &str,
+        packet: &[u8],
+    ) -> Option<(String, String)> {
+        // shared services and their answers cross clusters, answers only
+        // into the cluster the flow came from
+        let conn = if self.connections.is_global(dst) {
+            let conn = self.connections.get_global_connection(dst);
+            if conn
+                .as_ref()
+                .is_some_and(|conn| conn.cluster != sender.cluster)
+            {
+                self.connections
+                    .record_global_flow(&sender.cluster, src, dst);
+            }
+            conn
+        } else {
+            self.connections
+                .get_flow_connection(&sender.cluster, dst, packet)
+                .or_else(|| {
+                    if !self.answers_from_global(sender, src) {
+                        return None;
+                    }
+                    let cluster = self.connections.global_flow_cluster(src, dst)?;
+                    self.connections.get_connection(&cluster, dst)
+                })
+        };
+        conn.map(|conn| (conn.cluster, conn.identity))
+    }
+}
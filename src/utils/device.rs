@@ -1,15 +1,288 @@
-use crate::codec::frame::{HandshakeReplyFrame, PeerDetail};
-use crate::utils::sys_route::SysRoute;
+use crate::codec::frame::{DataFrame, HandshakeReplyFrame, IpValidation, PeerDetail, TunnelMode};
+use crate::utils::shaper::Shaper;
+use crate::utils::sys_route::{DnsBlock, RouteTable, SysRoute};
 #[allow(unused_imports)]
 use crate::utils::sys_route::{ip_to_network, mask_to_prefix_length};
-use std::collections::{HashMap, HashSet};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::{mpsc, oneshot};
+use crate::utils::vpn_dns::VpnDns;
+use ipnet::IpNet;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Notify, mpsc, oneshot};
+#[cfg(target_os = "linux")]
+use tokio::task::JoinSet;
 #[allow(unused_imports)]
 use tun::AbstractDevice;
 
 const DEFAULT_MTU: u16 = 1430;
 
+/// Packets queued each way between the TUN device and the event loop
+pub const DEFAULT_QUEUE_SIZE: usize = 1000;
+
+/// Packet given up when the queue to the TUN device is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DropPolicy {
+    /// The arriving packet
+    #[default]
+    Newest,
+    /// The longest queued packet, making room for the arriving one
+    Oldest,
+}
+
+/// Bounded queue of packets to the TUN device
+///
+/// Unlike a channel, queueing never waits for the device to catch up: a
+/// slow TUN write side costs packets instead of stalling the event loop.
+#[derive(Clone)]
+struct DeviceQueue {
+    inner: Arc<QueueInner>,
+}
+
+struct QueueInner {
+    packets: Mutex<VecDeque<Vec<u8>>>,
+    ready: Notify,
+    capacity: usize,
+    policy: DropPolicy,
+}
+
+impl DeviceQueue {
+    fn new(capacity: usize, policy: DropPolicy) -> Self {
+        Self {
+            inner: Arc::new(QueueInner {
+                packets: Mutex::new(VecDeque::with_capacity(capacity)),
+                ready: Notify::new(),
+                capacity: capacity.max(1),
+                policy,
+            }),
+        }
+    }
+
+    /// Queue `packet`, dropping one per the policy if the queue is full
+    ///
+    /// # Returns
+    /// `false` if a packet was dropped
+    fn push(&self, packet: Vec<u8>) -> bool {
+        let mut packets = self.inner.packets.lock().unwrap_or_else(|e| e.into_inner());
+        let room = packets.len() < self.inner.capacity;
+        match (room, self.inner.policy) {
+            (false, DropPolicy::Newest) => return false,
+            (false, DropPolicy::Oldest) => {
+                packets.pop_front();
+            }
+            (true, _) => {}
+        }
+        packets.push_back(packet);
+        drop(packets);
+        self.inner.ready.notify_one();
+        room
+    }
+
+    /// Wait for the next packet
+    async fn pop(&self) -> Vec<u8> {
+        loop {
+            let next = self
+                .inner
+                .packets
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .pop_front();
+            if let Some(packet) = next {
+                return packet;
+            }
+            self.inner.ready.notified().await;
+        }
+    }
+}
+
+/// Multiqueue TUN interfaces (Linux only)
+///
+/// Every queue is a file descriptor of its own, the kernel spreads the flows
+/// of the interface over them by hash.
+#[cfg(target_os = "linux")]
+mod multiqueue {
+    use std::io;
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    /// Create an interface named `name` with `count` queues, the kernel
+    /// picks the name if empty
+    ///
+    /// # Returns
+    /// The interface name and one descriptor per queue
+    pub(super) fn open(name: &str, count: usize, tap: bool) -> io::Result<(String, Vec<OwnedFd>)> {
+        let mut name = name.to_string();
+        let mut queues = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (queue, attached) = attach(&name, tap)?;
+            queues.push(queue);
+            name = attached;
+        }
+        Ok((name, queues))
+    }
+
+    /// Attach a queue to interface `name`, a new one if empty
+    fn attach(name: &str, tap: bool) -> io::Result<(OwnedFd, String)> {
+        // SAFETY: the path is a NUL-terminated C string literal and the
+        // result is checked before use.
+        let fd = unsafe { libc::open(c"/dev/net/tun".as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and nothing else owns it, so the
+        // `OwnedFd` is its only owner and closes it once.
+        let queue = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: `ifreq` is a plain C struct for which all zero bytes is a
+        // valid value, an empty name and no flags.
+        let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+        for (dst, src) in req
+            .ifr_name
+            .iter_mut()
+            .zip(name.bytes().take(libc::IFNAMSIZ - 1))
+        {
+            *dst = src as libc::c_char;
+        }
+        let kind = if tap { libc::IFF_TAP } else { libc::IFF_TUN };
+        req.ifr_ifru.ifru_flags = (kind | libc::IFF_NO_PI | libc::IFF_MULTI_QUEUE) as libc::c_short;
+        // SAFETY: `fd` is an open TUN control descriptor and TUNSETIFF
+        // reads and writes a `struct ifreq`, which `req` is and outlives the
+        // call. The name is NUL-terminated since at most IFNAMSIZ - 1 bytes
+        // were copied into the zeroed buffer.
+        if unsafe { libc::ioctl(fd, libc::TUNSETIFF, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let name = req
+            .ifr_name
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8 as char)
+            .collect();
+        Ok((queue, name))
+    }
+}
+
+/// Split a default route into two halves
+///
+/// Each half is more specific than the physical default route, so the
+/// tunnel wins without the original route being replaced or deleted.
+fn split_default_route(net: IpNet) -> [IpNet; 2] {
+    match net {
+        IpNet::V4(_) => ["0.0.0.0/1".parse().unwrap(), "128.0.0.0/1".parse().unwrap()],
+        IpNet::V6(_) => ["::/1".parse().unwrap(), "8000::/1".parse().unwrap()],
+    }
+}
+
+/// Host route (`/32` or `/128`) for a single address
+fn host_route(ip: IpAddr) -> String {
+    IpNet::from(ip).to_string()
+}
+
+/// Whether any of `cidrs` would capture traffic to `ip`
+fn covers(cidrs: &HashSet<String>, ip: IpAddr) -> bool {
+    cidrs
+        .iter()
+        .filter_map(|cidr| cidr.parse::<IpNet>().ok())
+        .any(|net| net.contains(&ip))
+}
+
+/// System routes wanted for the CIDRs advertised by peers
+///
+/// Default routes (`0.0.0.0/0`, `::/0`) are only honoured in full-tunnel
+/// mode, and then installed as their two halves.
+fn route_cidrs(peers: &[PeerDetail], full_tunnel: bool) -> HashSet<String> {
+    let mut cidrs = HashSet::new();
+    for cidr in peers.iter().flat_map(|peer| &peer.ciders) {
+        match cidr.parse::<IpNet>() {
+            Ok(net) if net.prefix_len() == 0 => {
+                if !full_tunnel {
+                    tracing::debug!("ignoring default route {cidr}, full tunnel disabled");
+                    continue;
+                }
+                cidrs.extend(split_default_route(net).iter().map(IpNet::to_string));
+            }
+            _ => {
+                cidrs.insert(cidr.clone());
+            }
+        }
+    }
+    cidrs
+}
+
+/// Routes to delete and to add to go from `old` to `new`, each sorted
+///
+/// `protected` routes are never touched, even when a peer advertises or
+/// withdraws the same CIDR.
+fn diff_routes(
+    old: &HashSet<String>,
+    new: &HashSet<String>,
+    protected: &HashSet<String>,
+) -> (Vec<String>, Vec<String>) {
+    let unprotected = |cidr: &&String| !protected.contains(*cidr);
+    let mut to_delete: Vec<String> = old.difference(new).filter(unprotected).cloned().collect();
+    let mut to_add: Vec<String> = new.difference(old).filter(unprotected).cloned().collect();
+    to_delete.sort();
+    to_add.sort();
+    (to_delete, to_add)
+}
+
+/// Initial backoff after a transient device read error
+const READ_ERROR_BACKOFF_MIN: Duration = Duration::from_millis(10);
+/// Maximum backoff between retries of transient device read errors
+const READ_ERROR_BACKOFF_MAX: Duration = Duration::from_secs(1);
+/// Consecutive transient read errors after which the device is considered lost
+const MAX_CONSECUTIVE_READ_ERRORS: u32 = 100;
+
+/// Status reported by the device task to its `DeviceHandler`
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceStatus {
+    /// The device can no longer be used (e.g., the interface was removed)
+    Fatal(String),
+}
+
+/// Check whether a device I/O error means the interface is gone
+///
+/// Errors such as ENODEV/ENXIO/EBADF will never recover on the same file
+/// descriptor, so reading again would only spin.
+fn is_fatal_device_error(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        // ENXIO(6), EBADF(9), ENODEV(19) share these values on Linux and macOS
+        const ENXIO: i32 = 6;
+        const EBADF: i32 = 9;
+        const ENODEV: i32 = 19;
+        if let Some(code) = e.raw_os_error()
+            && matches!(code, ENXIO | EBADF | ENODEV)
+        {
+            return true;
+        }
+    }
+
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe | io::ErrorKind::NotConnected | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Whether the interface may be named `name` on this platform
+///
+/// macOS only takes `utun<N>`, mobile platforms get their interface from the
+/// system.
+fn tun_name_supported(name: &str) -> bool {
+    if cfg!(any(target_os = "android", target_os = "ios")) {
+        return false;
+    }
+    if cfg!(target_os = "macos") {
+        return name
+            .strip_prefix("utun")
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+    }
+    // Linux takes up to IFNAMSIZ - 1 bytes
+    !name.is_empty() && name.len() < 16
+}
+
 #[derive(Clone)]
 pub struct DeviceConfig {
     pub ip: String,
@@ -18,48 +291,83 @@ pub struct DeviceConfig {
     pub mtu: u16,
 }
 
+#[derive(Clone)]
 pub struct Device {
     ip: String,
     mask: String,
     mtu: u16,
+    /// Interface name, picked by the system if not set
+    name: Option<String>,
+    /// Queues of a multiqueue TUN device, each served by its own task
+    queues: usize,
+    /// TUN (IP packets) or TAP (Ethernet frames) device
+    mode: TunnelMode,
+    /// Answers peer name queries read from the device (disabled if not set)
+    vpn_dns: Option<VpnDns>,
     inbound_tx: mpsc::Sender<Vec<u8>>,
-    outbound_rx: mpsc::Receiver<Vec<u8>>,
+    outbound_rx: DeviceQueue,
 }
 
 impl Device {
-    pub fn new(
+    fn new(
         ip: String,
         mask: String,
         mtu: u16,
         inbound_tx: mpsc::Sender<Vec<u8>>,
-        outbound_rx: mpsc::Receiver<Vec<u8>>,
+        outbound_rx: DeviceQueue,
     ) -> Self {
         Self {
             ip,
             mask,
             mtu,
+            name: None,
+            queues: 1,
+            mode: TunnelMode::default(),
+            vpn_dns: None,
             inbound_tx,
             outbound_rx,
         }
     }
 
+    /// Create a TAP device instead of a TUN device in `TunnelMode::Tap`
+    fn with_mode(mut self, mode: TunnelMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Name the interface `name` where the platform supports it
+    fn with_name(mut self, name: Option<String>) -> Self {
+        self.name = name;
+        self
+    }
+
+    /// Serve the device over `queues` queues (Linux only)
+    fn with_queues(mut self, queues: usize) -> Self {
+        self.queues = queues.max(1);
+        self
+    }
+
+    /// Answer DNS queries for peer names instead of forwarding them
+    fn with_vpn_dns(mut self, vpn_dns: Option<VpnDns>) -> Self {
+        self.vpn_dns = vpn_dns;
+        self
+    }
+
     pub async fn run(
         &mut self,
         ready: oneshot::Sender<Option<i32>>,
         name: oneshot::Sender<Option<String>>,
     ) -> anyhow::Result<()> {
-        let mut config = tun::Configuration::default();
-        config
-            .address(self.ip.clone())
-            .netmask(self.mask.clone())
-            // .destination(self.config.gateway.clone())
-            .mtu(self.mtu)
-            .up();
+        let config = self.tun_config();
 
         #[cfg(target_os = "linux")]
-        config.platform_config(|config| {
-            config.ensure_root_privileges(true);
-        });
+        if self.queues > 1 {
+            return self.run_multiqueue(&config, ready, name).await;
+        }
+        #[cfg(not(target_os = "linux"))]
+        if self.queues > 1 {
+            tracing::warn!("multiqueue TUN is Linux only, serving one queue");
+        }
 
         let mut dev = match tun::create_as_async(&config) {
             Ok(dev) => dev,
@@ -86,28 +394,152 @@ impl Device {
         let _ = name.send(None);
 
         let _ = ready.send(tun_index);
+        self.serve(&mut dev).await
+    }
+
+    /// Configuration of the interface, without creating it
+    fn tun_config(&self) -> tun::Configuration {
+        let mut config = tun::Configuration::default();
+        config
+            .address(self.ip.clone())
+            .netmask(self.mask.clone())
+            // .destination(self.config.gateway.clone())
+            .mtu(self.mtu)
+            .up();
+        if self.mode == TunnelMode::Tap {
+            config.layer(tun::Layer::L2);
+        }
+        if let Some(name) = self.name.as_deref() {
+            if tun_name_supported(name) {
+                config.tun_name(name);
+            } else {
+                tracing::warn!("interface name {name} not supported here, letting the system pick");
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        config.platform_config(|config| {
+            config.ensure_root_privileges(true);
+        });
+        config
+    }
+
+    /// Serve a multiqueue device, one reader and writer task per queue
+    ///
+    /// The tasks share the channels, so one slow queue does not hold up the
+    /// others. The first queue to fail takes the device down.
+    #[cfg(target_os = "linux")]
+    async fn run_multiqueue(
+        &self,
+        config: &tun::Configuration,
+        ready: oneshot::Sender<Option<i32>>,
+        name: oneshot::Sender<Option<String>>,
+    ) -> anyhow::Result<()> {
+        let (interface_name, queues) = self.open_queues(config)?;
+        tracing::info!("serving {interface_name} over {} queues", queues.len());
+        let _ = name.send(Some(interface_name));
+        let _ = ready.send(None);
+
+        let mut tasks = JoinSet::new();
+        for mut queue in queues {
+            let device = self.clone();
+            tasks.spawn(async move { device.serve(&mut queue).await });
+        }
+        match tasks.join_next().await {
+            Some(Ok(res)) => res,
+            Some(Err(e)) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Create the multiqueue interface configured per `config`
+    #[cfg(target_os = "linux")]
+    fn open_queues(
+        &self,
+        config: &tun::Configuration,
+    ) -> anyhow::Result<(String, Vec<tun::AsyncDevice>)> {
+        use std::os::fd::IntoRawFd;
+
+        let (interface_name, fds) = multiqueue::open(
+            self.name
+                .as_deref()
+                .filter(|name| tun_name_supported(name))
+                .unwrap_or_default(),
+            self.queues,
+            self.mode == TunnelMode::Tap,
+        )?;
+        let mut queues = Vec::with_capacity(fds.len());
+        for fd in fds {
+            let mut queue = config.clone();
+            queue.raw_fd(fd.into_raw_fd()).tun_name(&interface_name);
+            queues.push(tun::create_as_async(&queue)?);
+        }
+        // addresses belong to the interface, set once through any queue
+        queues[0].configure(config)?;
+        Ok((interface_name, queues))
+    }
+
+    /// Forward packets between the device and the channels
+    ///
+    /// Transient read errors are retried with exponential backoff, packets
+    /// to the device keep being written meanwhile. Fatal errors (interface
+    /// removed), the device closing or too many consecutive transient errors
+    /// end the loop with an error instead of spinning on a dead device.
+    async fn serve<D>(&self, dev: &mut D) -> anyhow::Result<()>
+    where
+        D: AsyncRead + AsyncWrite + Unpin,
+    {
         let mut buf = vec![0; 2048];
+        let mut read_errors: u32 = 0;
+        let mut backoff = READ_ERROR_BACKOFF_MIN;
+        // reads are paused until then after a transient error
+        let mut resume_at: Option<tokio::time::Instant> = None;
         loop {
             tokio::select! {
-                amount = dev.read(&mut buf) => {
+                amount = dev.read(&mut buf), if resume_at.is_none() => {
                     let amount = match amount {
+                        Ok(0) => {
+                            tracing::error!("read device fail, device closed");
+                            anyhow::bail!("device closed");
+                        }
                         Ok(amount) => amount,
+                        Err(e) if is_fatal_device_error(&e) => {
+                            tracing::error!("read device fail, device lost: {e:?}");
+                            return Err(e.into());
+                        }
                         Err(e) => {
-                            tracing::error!("read device fail: {e:?}");
+                            read_errors += 1;
+                            if read_errors >= MAX_CONSECUTIVE_READ_ERRORS {
+                                tracing::error!("read device fail {read_errors} times in a row: {e:?}");
+                                return Err(e.into());
+                            }
+                            tracing::warn!("read device fail, retrying in {backoff:?}: {e:?}");
+                            resume_at = Some(tokio::time::Instant::now() + backoff);
+                            backoff = (backoff * 2).min(READ_ERROR_BACKOFF_MAX);
                             continue;
                         }
                     };
+                    read_errors = 0;
+                    backoff = READ_ERROR_BACKOFF_MIN;
+                    if let Some(reply) = self.vpn_dns.as_ref().and_then(|dns| dns.answer(&buf[0..amount])) {
+                        if let Err(e) = dev.write(reply.as_slice()).await {
+                            tracing::error!("write DNS reply fail: {e:?}");
+                        }
+                        continue;
+                    }
                     if let Err(e) = self.inbound_tx.send(buf[0..amount].to_vec()).await {
                         tracing::error!("device => server fail: {e}");
                     }
                 }
-                packet = self.outbound_rx.recv() => {
-                    if let Some(packet) = packet {
-                        tracing::debug!("server => device {} bytes", packet.len());
-                        let result = dev.write(packet.as_slice()).await;
-                        if let Err(e) = result {
-                            tracing::error!("write device fail: {e:?}");
-                        }
+                () = tokio::time::sleep_until(resume_at.unwrap_or_else(tokio::time::Instant::now)),
+                    if resume_at.is_some() => {
+                    resume_at = None;
+                }
+                packet = self.outbound_rx.pop() => {
+                    tracing::debug!("server => device {} bytes", packet.len());
+                    let result = dev.write(packet.as_slice()).await;
+                    if let Err(e) = result {
+                        tracing::error!("write device fail: {e:?}");
                     }
                 }
             }
@@ -121,11 +553,54 @@ pub struct DeviceHandler {
     mask: String,
     local_ciders: Vec<String>,
     tun_index: Option<i32>,
+    /// Route all traffic through a peer advertising a default route
+    full_tunnel: bool,
+    /// Hosts that must stay reachable outside the tunnel (relay, STUN)
+    protected_hosts: Vec<IpAddr>,
+    /// Host routes installed for `protected_hosts`, never deleted
+    protected_routes: HashSet<String>,
+    /// Log route and NAT changes instead of applying them
+    route_dry_run: bool,
     interface_name: Option<String>,
+    /// Checks packets must pass to be written to the device
+    ip_validation: IpValidation,
+    /// Packets queued each way
+    queue_size: usize,
+    /// Packet dropped once the queue to the device is full
+    drop_policy: DropPolicy,
+    /// Queues of a multiqueue TUN device (Linux only)
+    tun_queues: usize,
+    /// Name asked for the interface, picked by the system if not set
+    iface_name: Option<String>,
+    /// Drops packets to the device over the download limit
+    download: Option<Shaper>,
+    /// TUN or TAP device, TAP frames are not IP validated
+    tunnel_mode: TunnelMode,
+    /// Embedded resolver for peer names (disabled if not set)
+    vpn_dns: Option<VpnDns>,
+    /// Resolvers DNS may reach besides `vpn_dns` while the device is up,
+    /// DNS to any other blocked (not blocked if not set)
+    dns_kill_switch: Option<Vec<Ipv4Addr>>,
+    /// Firewall rules of the DNS kill switch, while they are installed
+    dns_blocked: Option<DnsBlock>,
+    /// How long the relay may be unreachable before the tunnel routes are
+    /// swapped for blackhole routes (never if not set)
+    fail_closed: Option<Duration>,
+    /// When the relay was last seen unreachable, while it still is
+    relay_down_since: Option<Instant>,
+    /// Tunnel routes currently replaced by blackhole routes
+    blackholed: Vec<String>,
     inbound_rx: Option<mpsc::Receiver<Vec<u8>>>,
-    outbound_tx: Option<mpsc::Sender<Vec<u8>>>,
+    outbound_tx: Option<DeviceQueue>,
+    status_rx: Option<mpsc::Receiver<DeviceStatus>>,
     pub rx_bytes: usize,
     pub tx_bytes: usize,
+    /// Packets dropped for failing `ip_validation`
+    pub invalid_packets: usize,
+    /// Packets dropped because the queue to the device was full
+    pub dropped_to_device: usize,
+    /// Packets dropped over the download limit
+    pub dropped_over_limit: usize,
 }
 
 impl DeviceHandler {
@@ -136,11 +611,32 @@ impl DeviceHandler {
             mask: String::new(),
             local_ciders: vec![],
             tun_index: None,
+            full_tunnel: false,
+            protected_hosts: vec![],
+            protected_routes: HashSet::new(),
+            route_dry_run: false,
             interface_name: None,
+            ip_validation: IpValidation::default(),
+            queue_size: DEFAULT_QUEUE_SIZE,
+            drop_policy: DropPolicy::default(),
+            tun_queues: 1,
+            iface_name: None,
+            download: None,
+            tunnel_mode: TunnelMode::default(),
+            vpn_dns: None,
+            dns_kill_switch: None,
+            dns_blocked: None,
+            fail_closed: None,
+            relay_down_since: None,
+            blackholed: vec![],
             inbound_rx: None,
             outbound_tx: None,
+            status_rx: None,
             rx_bytes: 0,
             tx_bytes: 0,
+            invalid_packets: 0,
+            dropped_to_device: 0,
+            dropped_over_limit: 0,
         }
     }
 
@@ -149,10 +645,10 @@ impl DeviceHandler {
         cfg: &HandshakeReplyFrame,
         enable_masq: bool,
     ) -> anyhow::Result<Option<i32>> {
-        let (inbound_tx, inbound_rx) = mpsc::channel(1000);
-        let (outbound_tx, outbound_rx) = mpsc::channel(1000);
+        let (inbound_tx, inbound_rx) = mpsc::channel(self.queue_size);
+        let outbound = DeviceQueue::new(self.queue_size, self.drop_policy);
         self.inbound_rx = Some(inbound_rx);
-        self.outbound_tx = Some(outbound_tx);
+        self.outbound_tx = Some(outbound.clone());
         self.private_ip = cfg.private_ip.clone();
         self.mask = cfg.mask.clone();
         self.local_ciders = cfg.ciders.clone();
@@ -162,16 +658,19 @@ impl DeviceHandler {
             cfg.mask.clone(),
             DEFAULT_MTU,
             inbound_tx,
-            outbound_rx,
-        );
+            outbound,
+        )
+        .with_mode(self.tunnel_mode)
+        .with_name(self.iface_name.clone())
+        .with_queues(self.tun_queues)
+        .with_vpn_dns(self.vpn_dns.clone());
         let (ready_tx, ready_rx) = oneshot::channel();
         let (name_tx, name_rx) = oneshot::channel();
+        let (status_tx, status_rx) = mpsc::channel(1);
+        self.status_rx = Some(status_rx);
         tokio::spawn(async move {
             let res = dev.run(ready_tx, name_tx).await;
-            match res {
-                Ok(_) => (),
-                Err(e) => tracing::error!("device handler fail: {e:?}"),
-            }
+            report_device_exit(res, &status_tx).await;
         });
 
         let tun_index = ready_rx.await.unwrap_or(None);
@@ -181,6 +680,11 @@ impl DeviceHandler {
             self.interface_name = Some(name);
         }
 
+        // fail closed, there is no point running with lookups leaking
+        if let Some(upstream) = self.dns_kill_switch.clone() {
+            self.block_dns(&upstream)?;
+        }
+
         if enable_masq {
             if let Err(e) = self.enable_masquerade() {
                 tracing::error!("Failed to enable MASQUERADE: {e:?}");
@@ -193,10 +697,267 @@ impl DeviceHandler {
         Ok(tun_index)
     }
 
+    /// Honour default routes advertised by peers (full-tunnel mode)
+    pub fn set_full_tunnel(&mut self, full_tunnel: bool) {
+        self.full_tunnel = full_tunnel;
+    }
+
+    /// Log intended route and NAT changes without applying them
+    pub fn set_route_dry_run(&mut self, dry_run: bool) {
+        self.route_dry_run = dry_run;
+    }
+
+    /// Drop packets failing `level` instead of writing them to the device
+    pub fn set_ip_validation(&mut self, level: IpValidation) {
+        self.ip_validation = level;
+    }
+
+    /// Queue up to `size` packets each way, a full queue to the device
+    /// dropping the packet `policy` picks
+    ///
+    /// Must be called before `run`.
+    pub fn set_queue(&mut self, size: usize, policy: DropPolicy) {
+        self.queue_size = size.max(1);
+        self.drop_policy = policy;
+    }
+
+    /// Serve the device over `queues` multiqueue TUN queues, spreading
+    /// packet processing across cores (Linux only)
+    ///
+    /// Must be called before `run`.
+    pub fn set_tun_queues(&mut self, queues: usize) {
+        self.tun_queues = queues.max(1);
+    }
+
+    /// Name the interface `name` instead of letting the system pick, logged
+    /// and ignored where the platform does not support the name
+    ///
+    /// Must be called before `run`.
+    pub fn set_iface_name(&mut self, name: Option<String>) {
+        self.iface_name = name;
+    }
+
+    /// Write at most `mbps` megabits per second to the device, relay and
+    /// P2P traffic alike (unlimited if not set)
+    ///
+    /// `send` drops packets over the limit rather than holding up the
+    /// event loop, the senders' congestion control then backs off.
+    /// Dropped packets are counted in `dropped_over_limit`.
+    pub fn set_download_limit(&mut self, mbps: Option<f64>) {
+        self.download = mbps.map(Shaper::new);
+    }
+
+    /// Create a TAP device carrying Ethernet frames in `TunnelMode::Tap`
+    ///
+    /// Must be called before `run`.
+    pub fn set_tunnel_mode(&mut self, mode: TunnelMode) {
+        self.tunnel_mode = mode;
+    }
+
+    /// Answer `<identity>.vpn` queries sent to `resolver` with the peer's
+    /// private IP, TUN mode only
+    ///
+    /// Must be called before `run`, after `set_tunnel_mode`.
+    pub fn set_vpn_dns(&mut self, resolver: Option<Ipv4Addr>) {
+        self.vpn_dns = match (resolver, self.tunnel_mode) {
+            (Some(resolver), TunnelMode::Tun) => Some(VpnDns::new(resolver)),
+            (Some(_), TunnelMode::Tap) => {
+                tracing::warn!("VPN DNS needs a TUN device, disabled in TAP mode");
+                None
+            }
+            (None, _) => None,
+        };
+    }
+
+    /// Block DNS queries to resolvers other than the one of `set_vpn_dns`
+    /// and `upstream` while the device is up, so no lookup leaks to the
+    /// physical network's resolver
+    ///
+    /// `upstream` are the resolvers names outside `.vpn` still go to. Must
+    /// be called before `run`, which fails if the rules cannot be installed;
+    /// they are removed by `lift_dns_kill_switch`.
+    pub fn set_dns_kill_switch(&mut self, upstream: Option<Vec<Ipv4Addr>>) {
+        self.dns_kill_switch = upstream;
+    }
+
+    fn block_dns(&mut self, upstream: &[Ipv4Addr]) -> anyhow::Result<()> {
+        let Some(resolver) = self.vpn_dns.as_ref().map(VpnDns::resolver) else {
+            anyhow::bail!("DNS kill switch needs VPN DNS in TUN mode");
+        };
+        let mut allowed = vec![resolver];
+        allowed.extend(upstream);
+        let block = self
+            .sys_route()
+            .block_dns(&allowed)
+            .map_err(|e| anyhow::anyhow!("Failed to enable DNS kill switch: {e}"))?;
+        self.dns_blocked = Some(block);
+        Ok(())
+    }
+
+    /// Remove the firewall rules of the DNS kill switch, e.g. when the
+    /// client exits
+    pub fn lift_dns_kill_switch(&mut self) {
+        let Some(block) = self.dns_blocked.take() else {
+            return;
+        };
+        if let Err(e) = self.sys_route().unblock_dns(&block) {
+            tracing::error!("Failed to disable DNS kill switch: {e}");
+        }
+    }
+
+    /// Stop traffic to the peer CIDRs from leaking onto the physical
+    /// network once the relay has been unreachable for `grace`
+    ///
+    /// See `update_relay_state`.
+    pub fn set_fail_closed(&mut self, grace: Option<Duration>) {
+        self.fail_closed = grace;
+    }
+
+    /// Whether the tunnel routes are swapped for blackhole routes when the
+    /// relay stays unreachable
+    pub fn fails_closed(&self) -> bool {
+        self.fail_closed.is_some()
+    }
+
+    /// Whether traffic to the peer CIDRs is currently dropped
+    pub fn is_fail_closed(&self) -> bool {
+        !self.blackholed.is_empty()
+    }
+
+    /// Take in whether the relay is reachable at `now`
+    ///
+    /// Past the grace period of `set_fail_closed` the tunnel routes are
+    /// replaced by blackhole routes, restored once the relay is back.
+    pub fn update_relay_state(&mut self, connected: bool, now: Instant) {
+        let sys_route = self.sys_route();
+        self.apply_relay_state(&sys_route, connected, now);
+    }
+
+    fn apply_relay_state(&mut self, table: &dyn RouteTable, connected: bool, now: Instant) {
+        let Some(grace) = self.fail_closed else {
+            return;
+        };
+        if connected {
+            self.relay_down_since = None;
+            self.open_routes(table);
+            return;
+        }
+        let since = *self.relay_down_since.get_or_insert(now);
+        if now.duration_since(since) >= grace && self.blackholed.is_empty() {
+            self.close_routes(table);
+        }
+    }
+
+    /// Swap the tunnel routes for blackhole routes
+    ///
+    /// A route whose blackhole cannot be added is put back, leaving the
+    /// tunnel route is safer than leaking onto the default route.
+    fn close_routes(&mut self, table: &dyn RouteTable) {
+        let mut cidrs: Vec<String> = route_cidrs(&self.peer_details, self.full_tunnel)
+            .into_iter()
+            .filter(|cidr| !self.protected_routes.contains(cidr))
+            .collect();
+        cidrs.sort();
+        tracing::warn!("relay unreachable, blocking {} routes", cidrs.len());
+        for cidr in cidrs {
+            let gateway = self.private_ip.clone();
+            if let Err(e) = table.del(vec![cidr.clone()], gateway.clone(), self.tun_index) {
+                tracing::warn!("Failed to delete route {cidr}: {e}");
+            }
+            match table.add_blackhole(vec![cidr.clone()]) {
+                Ok(()) => self.blackholed.push(cidr),
+                Err(e) => {
+                    tracing::error!("Failed to block {cidr}: {e}");
+                    if let Err(e) = table.add(vec![cidr.clone()], gateway, self.tun_index) {
+                        tracing::error!("Failed to restore route {cidr}: {e}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Put back the tunnel routes `close_routes` blocked
+    fn open_routes(&mut self, table: &dyn RouteTable) {
+        if self.blackholed.is_empty() {
+            return;
+        }
+        tracing::info!(
+            "relay reachable, restoring {} routes",
+            self.blackholed.len()
+        );
+        for cidr in std::mem::take(&mut self.blackholed) {
+            if let Err(e) = table.del_blackhole(vec![cidr.clone()]) {
+                tracing::error!("Failed to unblock {cidr}: {e}");
+            }
+            if let Err(e) = table.add(vec![cidr.clone()], self.private_ip.clone(), self.tun_index) {
+                tracing::error!("Failed to restore route {cidr}: {e}");
+            }
+        }
+    }
+
+    /// Remove the blackhole routes, e.g. when the client exits
+    pub fn lift_fail_closed(&mut self) {
+        let sys_route = self.sys_route();
+        self.relay_down_since = None;
+        self.open_routes(&sys_route);
+    }
+
+    fn sys_route(&self) -> SysRoute {
+        SysRoute::new().with_dry_run(self.route_dry_run)
+    }
+
+    /// Keep `hosts` reachable through the physical network
+    ///
+    /// Before a tunnel route covering one of them is installed, a host
+    /// route via the current physical gateway is added so e.g. the relay
+    /// connection's own packets never enter the tunnel. Must be called
+    /// before the first `reload_route`.
+    pub fn set_protected_hosts(&mut self, hosts: Vec<IpAddr>) {
+        self.protected_hosts = hosts;
+    }
+
+    /// Install host routes for protected hosts covered by `cidrs`
+    fn protect_covered_hosts(&mut self, sys_route: &SysRoute, cidrs: &HashSet<String>) {
+        for &host in &self.protected_hosts {
+            let route = host_route(host);
+            if self.protected_routes.contains(&route) || !covers(cidrs, host) {
+                continue;
+            }
+            match sys_route.physical_gateway(&host.to_string()) {
+                Ok(Some(gateway)) => {
+                    tracing::info!("Protecting route: {route} via {gateway}");
+                    match sys_route.add(vec![route.clone()], gateway, None) {
+                        Ok(_) => {
+                            self.protected_routes.insert(route);
+                        }
+                        Err(e) => tracing::error!("Failed to add protective route {route}: {e}"),
+                    }
+                }
+                Ok(None) => tracing::debug!("{host} is on-link, no protective route needed"),
+                Err(e) => tracing::warn!("Failed to find physical gateway for {host}: {e}"),
+            }
+        }
+    }
+
+    /// TUN interface index (Windows only)
+    pub fn tun_index(&self) -> Option<i32> {
+        self.tun_index
+    }
+
     pub fn get_dev_inbound(&mut self) -> Option<mpsc::Receiver<Vec<u8>>> {
         self.inbound_rx.take()
     }
 
+    /// Wait for a status change reported by the device task
+    ///
+    /// Never resolves if the device has not been started.
+    pub async fn recv_status(&mut self) -> Option<DeviceStatus> {
+        match self.status_rx.as_mut() {
+            Some(rx) => rx.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+
     pub async fn recv(&mut self) -> Option<Vec<u8>> {
         let inbound_rx = match self.inbound_rx.as_mut() {
             Some(rx) => rx,
@@ -220,13 +981,32 @@ impl DeviceHandler {
                 return Err(anyhow::anyhow!("device handler send none"));
             }
         };
-        self.tx_bytes += packet.len();
-        tracing::debug!("device => server outbound tx len: {}", packet.len());
-        let result = outbound_tx.send(packet).await;
-        match result {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.into()),
+        let frame = DataFrame {
+            payload: packet,
+            seq: None,
+        };
+        if self.tunnel_mode == TunnelMode::Tun && !frame.validate_ip_packet(self.ip_validation) {
+            self.invalid_packets += 1;
+            tracing::debug!("drop packet failing {:?} validation", self.ip_validation);
+            return Ok(());
+        }
+        if let Some(download) = self.download.as_mut()
+            && !download.admit(frame.payload.len())
+        {
+            self.dropped_over_limit += 1;
+            tracing::debug!("drop packet over the download limit");
+            return Ok(());
+        }
+        self.tx_bytes += frame.payload.len();
+        tracing::debug!("device => server outbound tx len: {}", frame.payload.len());
+        if !outbound_tx.push(frame.payload) {
+            self.dropped_to_device += 1;
+            tracing::debug!(
+                "device queue full, dropped the {:?} packet",
+                self.drop_policy
+            );
         }
+        Ok(())
     }
 
     /// Get current peer details list
@@ -235,21 +1015,13 @@ impl DeviceHandler {
     }
 
     pub async fn reload_route(&mut self, new_routes: Vec<PeerDetail>) {
-        let sys_route = SysRoute::new();
+        let sys_route = self.sys_route();
+        // routes only arrive from the relay, it is back
+        self.relay_down_since = None;
+        self.open_routes(&sys_route);
 
-        let mut old_ciders: HashSet<String> = HashSet::new();
-        for route in &self.peer_details {
-            for cidr in &route.ciders {
-                old_ciders.insert(cidr.clone());
-            }
-        }
-
-        let mut new_ciders: HashSet<String> = HashSet::new();
-        for route in &new_routes {
-            for cidr in &route.ciders {
-                new_ciders.insert(cidr.clone());
-            }
-        }
+        let old_ciders = route_cidrs(&self.peer_details, self.full_tunnel);
+        let new_ciders = route_cidrs(&new_routes, self.full_tunnel);
 
         tracing::info!(
             "Reloading routes: old={}, new={}",
@@ -257,11 +1029,11 @@ impl DeviceHandler {
             new_ciders.len()
         );
 
-        // Find routes to delete (in old but not in new)
-        let to_delete: Vec<String> = old_ciders.difference(&new_ciders).cloned().collect();
+        // Protective host routes go in before any tunnel route covering them
+        self.protect_covered_hosts(&sys_route, &new_ciders);
 
-        // Find routes to add (in new but not in old)
-        let to_add: Vec<String> = new_ciders.difference(&old_ciders).cloned().collect();
+        // Routes in old but not in new are deleted, in new but not in old added
+        let (to_delete, to_add) = diff_routes(&old_ciders, &new_ciders, &self.protected_routes);
 
         // Delete old routes
         for cidr in to_delete {
@@ -283,6 +1055,10 @@ impl DeviceHandler {
             }
         }
 
+        if let Some(dns) = &self.vpn_dns {
+            dns.update(&new_routes);
+        }
+
         // Update stored routes
         self.peer_details = new_routes;
 
@@ -294,7 +1070,7 @@ impl DeviceHandler {
     pub fn enable_masquerade(&mut self) -> anyhow::Result<()> {
         let cidr = self.ip_mask_to_cidr(&self.private_ip, &self.mask)?;
 
-        let sys_route = SysRoute::new();
+        let sys_route = self.sys_route();
         sys_route.enable_masquerade_by_source(&cidr)?;
         Ok(())
     }
@@ -303,7 +1079,7 @@ impl DeviceHandler {
     pub fn disable_masquerade(&mut self) -> anyhow::Result<()> {
         let cidr = self.ip_mask_to_cidr(&self.private_ip, &self.mask)?;
 
-        let sys_route = SysRoute::new();
+        let sys_route = self.sys_route();
         sys_route.disable_masquerade_by_source(&cidr)?;
         Ok(())
     }
@@ -319,7 +1095,7 @@ impl DeviceHandler {
     /// Enable SNAT for local network segments to use virtual IP (Linux only)
     /// This makes packets from local ciders appear as coming from virtual IP
     pub fn enable_snat(&mut self) -> anyhow::Result<()> {
-        let sys_route = SysRoute::new();
+        let sys_route = self.sys_route();
 
         for cidr in &self.local_ciders {
             sys_route.enable_snat_for_local_network(cidr, "", &self.private_ip)?;
@@ -333,7 +1109,7 @@ impl DeviceHandler {
 
     /// Disable SNAT for local network segments (Linux only)
     pub fn disable_snat(&mut self) -> anyhow::Result<()> {
-        let sys_route = SysRoute::new();
+        let sys_route = self.sys_route();
 
         for cidr in &self.local_ciders {
             sys_route.disable_snat_for_local_network(cidr, "", &self.private_ip)?;
@@ -349,7 +1125,7 @@ impl DeviceHandler {
         &mut self,
         cidr_mapping: &HashMap<String, String>,
     ) -> anyhow::Result<()> {
-        let sys_route = SysRoute::new();
+        let sys_route = self.sys_route();
 
         for (mapped_cidr, real_cidr) in cidr_mapping {
             // Add DNAT rule (iptables will check if it already exists)
@@ -373,8 +1149,378 @@ impl DeviceHandler {
     }
 }
 
+/// Report the end of the device task to its handler
+async fn report_device_exit(res: anyhow::Result<()>, status_tx: &mpsc::Sender<DeviceStatus>) {
+    if let Err(e) = res {
+        tracing::error!("device handler fail: {e:?}");
+        let _ = status_tx.send(DeviceStatus::Fatal(e.to_string())).await;
+    }
+}
+
 impl Default for DeviceHandler {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_device() -> (Device, mpsc::Receiver<Vec<u8>>, DeviceQueue) {
+        let (inbound_tx, inbound_rx) = mpsc::channel(10);
+        let outbound = DeviceQueue::new(10, DropPolicy::Newest);
+        let dev = Device::new(
+            "10.0.0.1".to_string(),
+            "255.255.255.0".to_string(),
+            DEFAULT_MTU,
+            inbound_tx,
+            outbound.clone(),
+        );
+        (dev, inbound_rx, outbound)
+    }
+
+    /// Handler whose device never writes, with 2 packets of room
+    fn stalled_handler(policy: DropPolicy) -> (DeviceHandler, DeviceQueue) {
+        let mut handler = DeviceHandler::new();
+        handler.set_ip_validation(IpValidation::None);
+        let queue = DeviceQueue::new(2, policy);
+        handler.outbound_tx = Some(queue.clone());
+        (handler, queue)
+    }
+
+    #[tokio::test]
+    async fn test_full_device_queue_drops_per_policy() {
+        for (policy, kept) in [
+            (DropPolicy::Newest, [b"1", b"2"]),
+            (DropPolicy::Oldest, [b"2", b"3"]),
+        ] {
+            let (mut handler, queue) = stalled_handler(policy);
+            for packet in [b"1", b"2", b"3"] {
+                tokio::time::timeout(Duration::from_secs(1), handler.send(packet.to_vec()))
+                    .await
+                    .expect("a full device queue must not block the sender")
+                    .unwrap();
+            }
+            assert_eq!(handler.dropped_to_device, 1);
+            assert_eq!(queue.pop().await, kept[0]);
+            assert_eq!(queue.pop().await, kept[1]);
+            assert!(queue.inner.packets.lock().unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_device_writes_queued_packets() {
+        let (dev, _inbound_rx, outbound) = test_device();
+        assert!(outbound.push(b"to device".to_vec()));
+        let mut mock = tokio_test::io::Builder::new()
+            .write(b"to device")
+            .read_error(io::Error::from_raw_os_error(19))
+            .build();
+        let result = tokio::time::timeout(Duration::from_secs(1), dev.serve(&mut mock))
+            .await
+            .unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_iface_name() {
+        if cfg!(target_os = "macos") {
+            assert!(tun_name_supported("utun9"));
+            assert!(!tun_name_supported("rustun0"));
+            assert!(!tun_name_supported("utun"));
+        } else if cfg!(any(target_os = "android", target_os = "ios")) {
+            assert!(!tun_name_supported("rustun0"));
+        } else {
+            assert!(tun_name_supported("rustun0"));
+            assert!(!tun_name_supported("a-name-longer-than-ifnamsiz"));
+        }
+        assert!(!tun_name_supported(""));
+
+        let (dev, _inbound_rx, _outbound) = test_device();
+        let dev = dev.with_name(Some("rustun0".to_string()));
+        assert_eq!(dev.name.as_deref(), Some("rustun0"));
+        let dev = dev.with_name(None);
+        assert_eq!(dev.name, None);
+    }
+
+    #[test]
+    fn test_fatal_error_classification() {
+        assert!(is_fatal_device_error(&io::Error::from_raw_os_error(19)));
+        assert!(is_fatal_device_error(&io::Error::from(
+            io::ErrorKind::BrokenPipe
+        )));
+        assert!(!is_fatal_device_error(&io::Error::from(
+            io::ErrorKind::Interrupted
+        )));
+        assert!(!is_fatal_device_error(&io::Error::from(
+            io::ErrorKind::WouldBlock
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_serve_stops_on_fatal_error() {
+        let (dev, mut inbound_rx, _outbound_tx) = test_device();
+        let mut mock = tokio_test::io::Builder::new()
+            .read(b"packet")
+            .read_error(io::Error::from(io::ErrorKind::Interrupted))
+            .read_error(io::Error::from_raw_os_error(19))
+            .build();
+
+        let result = tokio::time::timeout(Duration::from_secs(1), dev.serve(&mut mock))
+            .await
+            .expect("serve should terminate on a fatal error");
+        assert!(result.is_err());
+        assert_eq!(inbound_rx.recv().await.unwrap(), b"packet".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_serve_stops_when_device_closes() {
+        let (dev, _inbound_rx, _outbound_tx) = test_device();
+        let mut mock = tokio_test::io::Builder::new().build();
+
+        let result = tokio::time::timeout(Duration::from_secs(1), dev.serve(&mut mock))
+            .await
+            .expect("serve should not spin on a closed device");
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fatal_error_is_reported_to_handler() {
+        let (dev, _inbound_rx, _outbound_tx) = test_device();
+        let mut handler = DeviceHandler::new();
+        let (status_tx, status_rx) = mpsc::channel(1);
+        handler.status_rx = Some(status_rx);
+
+        tokio::spawn(async move {
+            let mut mock = tokio_test::io::Builder::new()
+                .read_error(io::Error::from_raw_os_error(19))
+                .build();
+            let res = dev.serve(&mut mock).await;
+            report_device_exit(res, &status_tx).await;
+        });
+
+        let status = tokio::time::timeout(Duration::from_secs(1), handler.recv_status())
+            .await
+            .unwrap();
+        assert!(matches!(status, Some(DeviceStatus::Fatal(_))));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    #[ignore] // Requires CAP_NET_ADMIN
+    async fn test_multiqueue_spreads_flows_over_queues() {
+        let (dev, _inbound_rx, _outbound) = test_device();
+        let dev = dev.with_queues(2);
+        let mut config = tun::Configuration::default();
+        config
+            .address("10.251.0.1")
+            .netmask("255.255.255.0")
+            .mtu(DEFAULT_MTU)
+            .up();
+        let (_, queues) = dev.open_queues(&config).unwrap();
+        assert_eq!(queues.len(), 2);
+
+        let mut readers = JoinSet::new();
+        for (index, mut queue) in queues.into_iter().enumerate() {
+            readers.spawn(async move {
+                let mut buf = vec![0; 2048];
+                let mut received = 0;
+                while let Ok(Ok(amount)) =
+                    tokio::time::timeout(Duration::from_millis(500), queue.read(&mut buf)).await
+                {
+                    // UDP over IPv4 to the peer address
+                    if amount >= 28
+                        && buf[0] >> 4 == 4
+                        && buf[9] == 17
+                        && buf[16..20] == [10, 251, 0, 2]
+                    {
+                        received += 1;
+                    }
+                }
+                (index, received)
+            });
+        }
+        // the kernel picks the queue by flow hash, one flow per source port
+        for _ in 0..64 {
+            let socket = std::net::UdpSocket::bind("10.251.0.1:0").unwrap();
+            socket.send_to(b"flow", "10.251.0.2:9").unwrap();
+        }
+
+        let mut received = [0; 2];
+        while let Some(reader) = readers.join_next().await {
+            let (index, count) = reader.unwrap();
+            received[index] = count;
+        }
+        assert!(received.iter().all(|&count| count > 0), "{received:?}");
+        assert_eq!(received.iter().sum::<usize>(), 64);
+    }
+
+    fn peer(ciders: &[&str]) -> PeerDetail {
+        PeerDetail {
+            name: "gateway".to_string(),
+            identity: "gateway".to_string(),
+            private_ip: "10.0.0.2".to_string(),
+            ciders: ciders.iter().map(|c| c.to_string()).collect(),
+            ipv6: vec![],
+            port: 0,
+            stun_ip: String::new(),
+            stun_port: 0,
+            nat_type: 0,
+            last_active: 0,
+        }
+    }
+
+    /// Routing table recording calls instead of running commands
+    #[derive(Default)]
+    struct RecordingTable(Mutex<Vec<String>>);
+
+    impl RecordingTable {
+        fn record(&self, call: String) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push(call);
+            Ok(())
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+
+    impl RouteTable for RecordingTable {
+        fn add(&self, dsts: Vec<String>, gateway: String, _: Option<i32>) -> anyhow::Result<()> {
+            self.record(format!("add {} via {gateway}", dsts.join(",")))
+        }
+
+        fn del(&self, dsts: Vec<String>, gateway: String, _: Option<i32>) -> anyhow::Result<()> {
+            self.record(format!("del {} via {gateway}", dsts.join(",")))
+        }
+
+        fn add_blackhole(&self, dsts: Vec<String>) -> anyhow::Result<()> {
+            self.record(format!("add blackhole {}", dsts.join(",")))
+        }
+
+        fn del_blackhole(&self, dsts: Vec<String>) -> anyhow::Result<()> {
+            self.record(format!("del blackhole {}", dsts.join(",")))
+        }
+    }
+
+    #[test]
+    fn test_fail_closed_blocks_routes_past_grace() {
+        let grace = Duration::from_secs(10);
+        let mut handler = DeviceHandler::new();
+        handler.set_fail_closed(Some(grace));
+        handler.private_ip = "10.0.0.1".to_string();
+        handler.peer_details = vec![peer(&["192.168.1.0/24", "192.168.2.0/24"])];
+        let table = RecordingTable::default();
+        let start = Instant::now();
+
+        handler.apply_relay_state(&table, true, start);
+        handler.apply_relay_state(&table, false, start);
+        handler.apply_relay_state(&table, false, start + grace / 2);
+        assert!(table.take().is_empty());
+        assert!(!handler.is_fail_closed());
+
+        handler.apply_relay_state(&table, false, start + grace);
+        assert_eq!(
+            table.take(),
+            [
+                "del 192.168.1.0/24 via 10.0.0.1",
+                "add blackhole 192.168.1.0/24",
+                "del 192.168.2.0/24 via 10.0.0.1",
+                "add blackhole 192.168.2.0/24",
+            ]
+        );
+        assert!(handler.is_fail_closed());
+        handler.apply_relay_state(&table, false, start + grace * 2);
+        assert!(table.take().is_empty());
+
+        handler.apply_relay_state(&table, true, start + grace * 3);
+        assert_eq!(
+            table.take(),
+            [
+                "del blackhole 192.168.1.0/24",
+                "add 192.168.1.0/24 via 10.0.0.1",
+                "del blackhole 192.168.2.0/24",
+                "add 192.168.2.0/24 via 10.0.0.1",
+            ]
+        );
+        assert!(!handler.is_fail_closed());
+        // a new outage waits out its own grace period
+        handler.apply_relay_state(&table, false, start + grace * 4);
+        assert!(table.take().is_empty());
+    }
+
+    #[test]
+    fn test_fail_open_keeps_routes() {
+        let mut handler = DeviceHandler::new();
+        handler.peer_details = vec![peer(&["192.168.1.0/24"])];
+        let table = RecordingTable::default();
+        let start = Instant::now();
+        handler.apply_relay_state(&table, false, start);
+        handler.apply_relay_state(&table, false, start + Duration::from_secs(3600));
+        assert!(table.take().is_empty());
+    }
+
+    fn sorted(cidrs: HashSet<String>) -> Vec<String> {
+        let mut cidrs: Vec<_> = cidrs.into_iter().collect();
+        cidrs.sort();
+        cidrs
+    }
+
+    #[test]
+    fn test_default_route_split_in_full_tunnel() {
+        let peers = [peer(&["0.0.0.0/0", "::/0", "192.168.1.0/24"])];
+
+        assert_eq!(
+            sorted(route_cidrs(&peers, true)),
+            vec![
+                "0.0.0.0/1",
+                "128.0.0.0/1",
+                "192.168.1.0/24",
+                "8000::/1",
+                "::/1"
+            ]
+        );
+        // without the opt-in default routes are ignored
+        assert_eq!(sorted(route_cidrs(&peers, false)), vec!["192.168.1.0/24"]);
+    }
+
+    #[test]
+    fn test_full_tunnel_never_touches_physical_default_route() {
+        let specific = route_cidrs(&[peer(&["192.168.1.0/24"])], true);
+        let full = route_cidrs(&[peer(&["0.0.0.0/0", "192.168.1.0/24"])], true);
+
+        let none = HashSet::new();
+        let (to_delete, to_add) = diff_routes(&specific, &full, &none);
+        assert!(to_delete.is_empty());
+        assert_eq!(to_add, vec!["0.0.0.0/1", "128.0.0.0/1"]);
+
+        let (to_delete, to_add) = diff_routes(&full, &specific, &none);
+        assert_eq!(to_delete, vec!["0.0.0.0/1", "128.0.0.0/1"]);
+        assert!(to_add.is_empty());
+    }
+
+    #[test]
+    fn test_server_route_protected_from_diff() {
+        let server: IpAddr = "203.0.113.10".parse().unwrap();
+        let protected = HashSet::from([host_route(server)]);
+        assert!(protected.contains("203.0.113.10/32"));
+
+        // the full tunnel would capture the relay connection
+        let full = route_cidrs(&[peer(&["0.0.0.0/0"])], true);
+        assert!(covers(&full, server));
+        assert!(!covers(
+            &route_cidrs(&[peer(&["192.168.1.0/24"])], true),
+            server
+        ));
+
+        // a peer advertising, then withdrawing, the server's own address
+        // never touches the protective route
+        let with_server = route_cidrs(&[peer(&["0.0.0.0/0", "203.0.113.10/32"])], true);
+        let (to_delete, to_add) = diff_routes(&HashSet::new(), &with_server, &protected);
+        assert!(!to_add.contains(&"203.0.113.10/32".to_string()));
+        assert!(to_delete.is_empty());
+        let (to_delete, _) = diff_routes(&with_server, &HashSet::new(), &protected);
+        assert_eq!(to_delete, vec!["0.0.0.0/1", "128.0.0.0/1"]);
+    }
+}
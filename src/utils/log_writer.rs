@@ -0,0 +1,142 @@
+//! Append-only log files written off the packet path
+//!
+//! Lines are handed over a bounded channel to a thread of their own, which
+//! does the blocking writes. A line finding the channel full is dropped and
+//! counted rather than holding up the sender; the thread reports the drops
+//! every `DROP_REPORT_INTERVAL` at most.
+
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, mpsc};
+use std::time::{Duration, Instant};
+
+/// Lines waiting for the writer thread at most
+const QUEUE_LINES: usize = 4096;
+/// Dropped lines are reported at most this often
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+enum Message {
+    Line(Vec<u8>),
+    /// Answered once everything queued before is written
+    Flush(mpsc::Sender<()>),
+}
+
+/// Sender side of a log file
+///
+/// Cheap to clone; all clones append to the same file. The writer thread
+/// ends once the last clone is dropped.
+#[derive(Clone)]
+pub struct LogWriter {
+    tx: mpsc::SyncSender<Message>,
+    /// Lines dropped because the writer fell behind
+    dropped: Arc<AtomicU64>,
+}
+
+impl LogWriter {
+    /// Open `path`, appending to an existing file, and start its writer
+    /// thread
+    ///
+    /// `name` names the thread and the log in messages.
+    pub fn open(path: impl AsRef<Path>, name: &str) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.as_ref())?;
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LINES);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let thread_dropped = dropped.clone();
+        let name = name.to_string();
+        std::thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || write_lines(BufWriter::new(file), rx, &name, &thread_dropped))?;
+        Ok(Self { tx, dropped })
+    }
+
+    /// Queue `line`, newline included, dropping it if the writer is behind
+    pub fn write(&self, line: Vec<u8>) {
+        if self.tx.try_send(Message::Line(line)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Lines dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Wait until the lines queued so far are written
+    pub fn flush(&self) {
+        let (done_tx, done_rx) = mpsc::channel();
+        if self.tx.send(Message::Flush(done_tx)).is_ok() {
+            let _ = done_rx.recv();
+        }
+    }
+}
+
+/// Writer thread: write lines as they come, flush whenever the queue runs
+/// dry
+fn write_lines(mut out: impl Write, rx: mpsc::Receiver<Message>, name: &str, dropped: &AtomicU64) {
+    let mut reported = 0;
+    let mut last_report: Option<Instant> = None;
+    while let Ok(message) = rx.recv() {
+        let mut next = Some(message);
+        while let Some(message) = next.take() {
+            match message {
+                Message::Line(line) => {
+                    if let Err(e) = out.write_all(&line) {
+                        tracing::debug!("{name} write failed: {e}");
+                    }
+                }
+                Message::Flush(done) => {
+                    let _ = out.flush();
+                    let _ = done.send(());
+                }
+            }
+            next = rx.try_recv().ok();
+        }
+        if let Err(e) = out.flush() {
+            tracing::debug!("{name} flush failed: {e}");
+        }
+
+        let total = dropped.load(Ordering::Relaxed);
+        if total > reported && last_report.is_none_or(|at| at.elapsed() >= DROP_REPORT_INTERVAL) {
+            tracing::warn!("{name} fell behind, {} lines dropped", total - reported);
+            reported = total;
+            last_report = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lines_written_and_overflow_dropped() {
+        let path =
+            std::env::temp_dir().join(format!("rustun-log-writer-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let writer = LogWriter::open(&path, "test log").unwrap();
+        for n in 0..3 {
+            writer.write(format!("{n}\n").into_bytes());
+        }
+        writer.flush();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written, "0\n1\n2\n");
+        assert_eq!(writer.dropped(), 0);
+        let _ = std::fs::remove_file(&path);
+
+        // a writer that cannot keep up drops instead of blocking
+        let (tx, _rx) = mpsc::sync_channel(1);
+        let stalled = LogWriter {
+            tx,
+            dropped: Default::default(),
+        };
+        for n in 0..3 {
+            stalled.write(format!("{n}\n").into_bytes());
+        }
+        assert_eq!(stalled.dropped(), 2);
+    }
+}
@@ -6,12 +6,18 @@ use tracing::level_filters::LevelFilter;
 use tracing_subscriber::EnvFilter;
 
 pub mod device;
+pub mod log_writer;
+pub mod shaper;
+pub mod supervisor;
 pub mod sys_route;
+pub mod vpn_dns;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct StunAddr {
     pub ip: String,
     pub port: u16,
+    /// NAT type code detected with this mapping (0 = unknown)
+    pub nat_type: u8,
 }
 impl std::fmt::Display for StunAddr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -43,21 +49,76 @@ pub fn init_tracing() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Outcome of looking up our public IPv6 address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv6Lookup {
+    /// An address peers can probe
+    Global(Ipv6Addr),
+    /// Only addresses peers could not reach were reported, see
+    /// `is_p2p_usable`
+    NonGlobal,
+    /// No API answered
+    Failed,
+}
+
+impl Ipv6Lookup {
+    /// Address to advertise after this lookup, `current` until now
+    ///
+    /// A failed lookup keeps the address, a non-global answer drops it.
+    pub fn apply(self, current: Option<Ipv6Addr>) -> Option<Ipv6Addr> {
+        match self {
+            Ipv6Lookup::Global(ipv6) => Some(ipv6),
+            Ipv6Lookup::NonGlobal => None,
+            Ipv6Lookup::Failed => current,
+        }
+    }
+}
+
 /// Get public IPv6 address from external API
+///
+/// An address peers could not reach, see `is_p2p_usable`, is not
+/// returned.
 pub async fn get_ipv6() -> Option<Ipv6Addr> {
+    lookup_ipv6().await.apply(None)
+}
+
+/// Look up our public IPv6 address with external APIs
+///
+/// An API reporting an address peers could not reach is skipped for the
+/// next one.
+pub async fn lookup_ipv6() -> Ipv6Lookup {
     let apis = [
         "https://api64.ipify.org",
         "https://ifconfig.co",
         "https://ipv6.icanhazip.com",
     ];
 
+    let mut lookup = Ipv6Lookup::Failed;
     for api in &apis {
         if let Ok(ipv6) = fetch_ipv6_from_url(api).await {
-            return Some(ipv6);
+            if is_p2p_usable(ipv6) {
+                return Ipv6Lookup::Global(ipv6);
+            }
+            tracing::debug!("{api} reported {ipv6}, not a global address");
+            lookup = Ipv6Lookup::NonGlobal;
         }
     }
 
-    None
+    lookup
+}
+
+/// Whether peers can probe `addr`, a global unicast address (2000::/3)
+///
+/// Link-local (fe80::/10), unique local (fc00::/7), deprecated site-local
+/// (fec0::/10), loopback, multicast and documentation (2001:db8::/32 and
+/// 3fff::/20, RFC 9637) addresses are of no use to a peer on another
+/// network.
+pub fn is_p2p_usable(addr: Ipv6Addr) -> bool {
+    let segments = addr.segments();
+    let global_unicast = segments[0] & 0xe000 == 0x2000;
+    let documentation = (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        || (segments[0] == 0x3fff && segments[1] < 0x1000);
+    global_unicast && !documentation
 }
 
 async fn fetch_ipv6_from_url(url: &str) -> anyhow::Result<Ipv6Addr> {
@@ -69,3 +130,45 @@ async fn fetch_ipv6_from_url(url: &str) -> anyhow::Result<Ipv6Addr> {
     let ipv6_str = response.trim();
     Ok(ipv6_str.parse::<Ipv6Addr>()?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_p2p_usable_accepts_only_global_unicast() {
+        for addr in [
+            "2a01:4f8:1c1c:abcd::1",
+            "2400:cb00::1",
+            "3ffe:1::1",
+            "3fff:1000::1",
+        ] {
+            assert!(is_p2p_usable(addr.parse().unwrap()), "{addr}");
+        }
+        for addr in [
+            "fe80::1",
+            "fd12:3456:789a::1",
+            "fc00::1",
+            "fec0::1",
+            "::1",
+            "::",
+            "ff02::1",
+            "::ffff:192.0.2.1",
+            "2001:db8::1",
+            "3fff::1",
+            "3fff:fff::1",
+        ] {
+            assert!(!is_p2p_usable(addr.parse().unwrap()), "{addr}");
+        }
+    }
+
+    #[test]
+    fn test_ipv6_lookup_apply() {
+        let old: Ipv6Addr = "2a01:4f8::1".parse().unwrap();
+        let new: Ipv6Addr = "2a01:4f8::2".parse().unwrap();
+        assert_eq!(Ipv6Lookup::Global(new).apply(Some(old)), Some(new));
+        assert_eq!(Ipv6Lookup::NonGlobal.apply(Some(old)), None);
+        assert_eq!(Ipv6Lookup::Failed.apply(Some(old)), Some(old));
+        assert_eq!(Ipv6Lookup::Failed.apply(None), None);
+    }
+}
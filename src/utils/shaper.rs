@@ -0,0 +1,136 @@
+//! Bandwidth limit of tunneled traffic
+//!
+//! A token bucket filled at the configured rate. Each packet takes tokens
+//! for its bytes, a packet finding the bucket short either waits until the
+//! rate has paid for it (`wait`) or is dropped (`admit`). The bucket holds `BURST` worth of traffic, so a burst
+//! after an idle spell passes at once and then settles to the rate.
+
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Traffic the bucket holds at most, in time at the configured rate
+const BURST: Duration = Duration::from_millis(50);
+/// Bucket size at low rates, one full size packet passes unhindered
+const MIN_BURST_BYTES: f64 = 65_536.0;
+
+/// Token bucket limiting a stream of packets to a rate
+#[derive(Debug)]
+pub struct Shaper {
+    /// Bytes per second
+    rate: f64,
+    /// Bytes the bucket holds at most
+    burst: f64,
+    /// Bytes that may pass right now, negative while a packet waits
+    tokens: f64,
+    last: Instant,
+}
+
+impl Shaper {
+    /// Limit to `mbps` megabits per second
+    pub fn new(mbps: f64) -> Self {
+        let rate = mbps * 1_000_000.0 / 8.0;
+        let burst = (rate * BURST.as_secs_f64()).max(MIN_BURST_BYTES);
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take `bytes` from the bucket, waiting until the rate allows them
+    pub async fn wait(&mut self, bytes: usize) {
+        if let Some(delay) = self.take(bytes, Instant::now()) {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Take `bytes` if the bucket holds them, without waiting
+    ///
+    /// # Returns
+    /// `false` if the packet is over the limit and must be dropped
+    pub fn admit(&mut self, bytes: usize) -> bool {
+        self.admit_at(bytes, Instant::now())
+    }
+
+    fn admit_at(&mut self, bytes: usize, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < bytes as f64 {
+            return false;
+        }
+        self.tokens -= bytes as f64;
+        true
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+    }
+
+    /// Take `bytes` at `now`, returns how long to wait before sending them
+    fn take(&mut self, bytes: usize, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / self.rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throughput_stays_within_limit() {
+        // 8 Mbps, one megabyte per second
+        let mut shaper = Shaper::new(8.0);
+        let start = Instant::now();
+        let window = Duration::from_secs(2);
+        let mut sent = 0;
+        while start.elapsed() < window {
+            shaper.wait(1400).await;
+            sent += 1400;
+        }
+        let allowed = 1_000_000.0 * window.as_secs_f64() + MIN_BURST_BYTES + 1400.0;
+        assert!(f64::from(sent) <= allowed, "{sent} bytes in {window:?}");
+        // the limit is not undershot either
+        assert!(f64::from(sent) >= 1_000_000.0 * window.as_secs_f64());
+    }
+
+    #[test]
+    fn test_admit_drops_over_limit() {
+        // 8 Mbps, one megabyte per second
+        let mut shaper = Shaper::new(8.0);
+        let start = Instant::now();
+        let mut admitted = 0;
+        for ms in 0..2000 {
+            let now = start + Duration::from_millis(ms);
+            // 2.8MB per second offered
+            for _ in 0..2 {
+                if shaper.admit_at(1400, now) {
+                    admitted += 1400;
+                }
+            }
+        }
+        assert!(f64::from(admitted) <= 2_000_000.0 + MIN_BURST_BYTES);
+        assert!(f64::from(admitted) >= 1_900_000.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_burst_after_idle_passes() {
+        let mut shaper = Shaper::new(8.0);
+        let start = Instant::now();
+        shaper.wait(MIN_BURST_BYTES as usize).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        shaper.wait(100_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // an idle spell fills the bucket, it does not bank more than it holds
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let idle = Instant::now();
+        shaper.wait(MIN_BURST_BYTES as usize).await;
+        assert_eq!(idle.elapsed(), Duration::ZERO);
+        shaper.wait(1000).await;
+        assert!(idle.elapsed() > Duration::ZERO);
+    }
+}
@@ -0,0 +1,114 @@
+//! Supervision of long-running spawned tasks
+//!
+//! A panic in a plain `tokio::spawn` only ends that task, nothing relying
+//! on it notices. Supervised tasks have their panic logged with the task's
+//! name and are either restarted or torn down by their owner.
+
+use futures::FutureExt;
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Run `task` to completion, catching a panic instead of unwinding
+///
+/// # Returns
+/// - `Ok(output)` - The task completed
+/// - `Err(message)` - The task panicked
+pub async fn catch_panic<T>(task: impl Future<Output = T>) -> Result<T, String> {
+    AssertUnwindSafe(task)
+        .catch_unwind()
+        .await
+        .map_err(|payload| panic_message(payload.as_ref()))
+}
+
+/// Spawn the task `make` builds, building it again `delay` after each panic
+///
+/// Supervision ends once a run returns; aborting the returned handle
+/// aborts the current run.
+pub fn supervise<F, Fut>(name: &'static str, delay: Duration, mut make: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        while let Err(panic) = catch_panic(make()).await {
+            tracing::error!("{name} task panicked, restarting in {delay:?}: {panic}");
+            tokio::time::sleep(delay).await;
+        }
+    })
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Log output written by the test's subscriber
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_supervise_restarts_panicked_task() {
+        let logs = Captured::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let task = supervise("flaky", Duration::from_millis(1), move || {
+            let run = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if run < 2 {
+                    panic!("run {run} failed");
+                }
+            }
+        });
+        tokio::time::timeout(Duration::from_secs(2), task)
+            .await
+            .expect("supervision should end once a run returns")
+            .unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("flaky task panicked, restarting in 1ms: run 0 failed"));
+        assert!(logs.contains("run 1 failed"));
+    }
+
+    #[tokio::test]
+    async fn test_catch_panic() {
+        assert_eq!(catch_panic(async { 7 }).await, Ok(7));
+        let message = format!("lost {}", "peer");
+        assert_eq!(
+            catch_panic(async move { panic!("{message}") }).await,
+            Err::<(), _>("lost peer".to_string())
+        );
+    }
+}
@@ -1,8 +1,88 @@
 use ipnet::Ipv4Net;
+use std::io;
 use std::net::Ipv4Addr;
-use std::process::Command;
+use std::process::{Command, ExitStatus, Output};
+use std::sync::{Arc, Mutex};
 
-pub struct SysRoute;
+/// Executes the external commands (`ip`, `route`, `iptables`) behind
+/// route and NAT management
+pub trait CommandRunner: Send + Sync {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<Output>;
+}
+
+/// Runs commands on the host
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+        Command::new(program).args(args).output()
+    }
+}
+
+/// Route command dialect
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Platform {
+    Linux,
+    MacOs,
+    Windows,
+    Unsupported,
+}
+
+impl Platform {
+    fn current() -> Self {
+        if cfg!(target_os = "linux") {
+            Self::Linux
+        } else if cfg!(target_os = "macos") {
+            Self::MacOs
+        } else if cfg!(target_os = "windows") {
+            Self::Windows
+        } else {
+            Self::Unsupported
+        }
+    }
+}
+
+pub struct SysRoute {
+    runner: Arc<dyn CommandRunner>,
+    /// Dialect of the route commands issued
+    platform: Platform,
+    /// Log and record changes instead of applying them
+    dry_run: bool,
+    /// Commands skipped in dry-run mode
+    planned: Mutex<Vec<String>>,
+}
+
+/// System routing table operations
+///
+/// Implemented by `SysRoute`; lets route management be tested without
+/// touching the host routing table.
+pub trait RouteTable: Send + Sync {
+    /// Add routes for `dsts` via `gateway`
+    fn add(
+        &self,
+        dsts: Vec<String>,
+        gateway: String,
+        interface_idx: Option<i32>,
+    ) -> anyhow::Result<()>;
+
+    /// Delete routes for `dsts` via `gateway`
+    fn del(
+        &self,
+        dsts: Vec<String>,
+        gateway: String,
+        interface_idx: Option<i32>,
+    ) -> anyhow::Result<()>;
+
+    /// Add routes dropping all traffic to `dsts`
+    fn add_blackhole(&self, _dsts: Vec<String>) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("blackhole routes are not supported"))
+    }
+
+    /// Delete the blackhole routes of `dsts`
+    fn del_blackhole(&self, _dsts: Vec<String>) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("blackhole routes are not supported"))
+    }
+}
 
 /// Convert subnet mask to prefix length
 /// Example: "255.255.255.0" -> 24
@@ -36,9 +116,133 @@ pub(crate) fn ip_to_network(ip: &str, mask: &str) -> anyhow::Result<String> {
     Ok(net.network().to_string())
 }
 
+/// Extract the address following `keyword` in route lookup output
+///
+/// Handles `ip route get` ("8.8.8.8 via 192.168.1.1 dev eth0") and
+/// `route -n get` ("gateway: 192.168.1.1") output.
+#[allow(unused)]
+fn parse_gateway(output: &str, keyword: &str) -> Option<String> {
+    let mut words = output.split_whitespace();
+    words.find(|word| *word == keyword)?;
+    words
+        .next()
+        .filter(|gateway| gateway.parse::<std::net::IpAddr>().is_ok())
+        .map(str::to_string)
+}
+
+/// pf anchor of the DNS rules, under `com.apple/` which the stock macOS
+/// ruleset evaluates
+const PF_DNS_ANCHOR: &str = "com.apple/rustun.dns";
+/// Windows firewall rule name of the DNS rules
+const WINDOWS_DNS_RULE: &str = "rustun-dns-block";
+/// iptables and ip6tables chain the DNS queries are sent through
+const DNS_CHAIN: &str = "RUSTUN_DNS";
+
+/// Firewall rules installed by `SysRoute::block_dns`, handed back to
+/// `SysRoute::unblock_dns` to remove them
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsBlock {
+    /// Resolvers DNS queries may still reach
+    allowed: Vec<Ipv4Addr>,
+    /// pf was disabled before and enabled for the rules (macOS)
+    enabled_pf: bool,
+}
+
+/// pf rules of `SysRoute::block_dns`
+fn dns_rules_pf(allowed: &[Ipv4Addr]) -> Vec<String> {
+    let mut rules = Vec::new();
+    if !allowed.is_empty() {
+        let allowed: Vec<String> = allowed.iter().map(Ipv4Addr::to_string).collect();
+        rules.push(format!(
+            "pass out quick inet proto {{ udp tcp }} to {{ {} }} port 53",
+            allowed.join(" ")
+        ));
+    }
+    rules.push("block drop out quick inet proto { udp tcp } to any port 53".to_string());
+    rules.push("block drop out quick inet6 proto { udp tcp } to any port 53".to_string());
+    rules
+}
+
+/// IPv4 ranges covering every address but `allowed`, as Windows firewall
+/// `remoteip` lists them
+fn ipv4_ranges_except(allowed: &[Ipv4Addr]) -> String {
+    let mut allowed: Vec<u32> = allowed.iter().map(|ip| u32::from(*ip)).collect();
+    allowed.sort_unstable();
+    allowed.dedup();
+    let mut ranges = Vec::new();
+    let mut start = Some(0u32);
+    for ip in allowed {
+        if let Some(from) = start
+            && from < ip
+        {
+            ranges.push(format!(
+                "{}-{}",
+                Ipv4Addr::from(from),
+                Ipv4Addr::from(ip - 1)
+            ));
+        }
+        start = ip.checked_add(1);
+    }
+    if let Some(from) = start {
+        ranges.push(format!("{}-255.255.255.255", Ipv4Addr::from(from)));
+    }
+    ranges.join(",")
+}
+
 impl SysRoute {
     pub fn new() -> Self {
-        Self
+        Self::new_with_runner(Arc::new(SystemRunner))
+    }
+
+    pub fn new_with_runner(runner: Arc<dyn CommandRunner>) -> Self {
+        Self {
+            runner,
+            platform: Platform::current(),
+            dry_run: false,
+            planned: Mutex::new(vec![]),
+        }
+    }
+
+    /// Only log the commands that would change routes or NAT rules
+    ///
+    /// Read-only lookups (gateway queries, iptables `-C` checks) still run.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    #[cfg(test)]
+    fn with_platform(mut self, platform: Platform) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    /// Commands skipped so far in dry-run mode, in order
+    pub fn planned_commands(&self) -> Vec<String> {
+        self.planned.lock().unwrap().clone()
+    }
+
+    /// Run a command that changes system state, honouring dry-run mode
+    fn exec(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+        if !self.dry_run {
+            return self.runner.run(program, args);
+        }
+        let command = std::iter::once(program)
+            .chain(args.iter().copied())
+            .collect::<Vec<_>>()
+            .join(" ");
+        tracing::info!("[dry-run] {command}");
+        self.planned.lock().unwrap().push(command);
+        Ok(Output {
+            status: ExitStatus::default(),
+            stdout: vec![],
+            stderr: vec![],
+        })
+    }
+
+    /// Run a read-only command, also in dry-run mode
+    fn query(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+        self.runner.run(program, args)
     }
 
     /// Check if iptables command is available (Linux only)
@@ -64,6 +268,54 @@ impl SysRoute {
         Ok(())
     }
 
+    /// Find the gateway currently used to reach `dst`
+    ///
+    /// Queried before tunnel routes are installed, this is the physical
+    /// gateway (e.g. for protecting the relay server's route).
+    ///
+    /// # Returns
+    /// - `Ok(Some(gateway))` - `dst` is reached through `gateway`
+    /// - `Ok(None)` - `dst` is on-link, no gateway involved
+    /// - `Err` - Lookup failed or is unsupported on this platform
+    #[cfg(target_os = "linux")]
+    pub fn physical_gateway(&self, dst: &str) -> anyhow::Result<Option<String>> {
+        let output = self
+            .query("ip", &["route", "get", dst])
+            .map_err(|e| anyhow::anyhow!("Failed to execute ip command: {e}"))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow::anyhow!("Failed to look up route: {}", stderr));
+        }
+        Ok(parse_gateway(
+            &String::from_utf8_lossy(&output.stdout),
+            "via",
+        ))
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn physical_gateway(&self, dst: &str) -> anyhow::Result<Option<String>> {
+        let output = self
+            .query("route", &["-n", "get", dst])
+            .map_err(|e| anyhow::anyhow!("Failed to execute route command: {e}"))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow::anyhow!("Failed to look up route: {}", stderr));
+        }
+        Ok(parse_gateway(
+            &String::from_utf8_lossy(&output.stdout),
+            "gateway:",
+        ))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub fn physical_gateway(&self, _dst: &str) -> anyhow::Result<Option<String>> {
+        Err(anyhow::anyhow!(
+            "Gateway lookup is not supported on this platform"
+        ))
+    }
+
     /// Add routes to the system routing table
     /// - dsts: destination CIDR addresses (e.g., ["192.168.1.0/24", "10.0.0.0/8"])
     /// - gateway: gateway IP address
@@ -97,16 +349,254 @@ impl SysRoute {
         Ok(())
     }
 
-    #[cfg(target_os = "linux")]
+    /// Whether `add_blackhole` works on this platform
+    pub fn supports_blackhole() -> bool {
+        matches!(Platform::current(), Platform::Linux | Platform::MacOs)
+    }
+
+    /// Add routes dropping all traffic to `dsts` (Linux and macOS only)
+    pub fn add_blackhole(&self, dsts: Vec<String>) -> anyhow::Result<()> {
+        for dst in dsts {
+            let args: &[&str] = match self.platform {
+                Platform::Linux => &["route", "add", "blackhole", &dst],
+                Platform::MacOs => &["-n", "add", "-net", &dst, "127.0.0.1", "-blackhole"],
+                Platform::Windows | Platform::Unsupported => {
+                    anyhow::bail!("Blackhole routes are not supported on this platform")
+                }
+            };
+            self.exec_route(args, "add blackhole route")?;
+        }
+        Ok(())
+    }
+
+    /// Delete the blackhole routes of `dsts` (Linux and macOS only)
+    pub fn del_blackhole(&self, dsts: Vec<String>) -> anyhow::Result<()> {
+        for dst in dsts {
+            let args: &[&str] = match self.platform {
+                Platform::Linux => &["route", "del", "blackhole", &dst],
+                Platform::MacOs => &["-n", "delete", "-net", &dst, "127.0.0.1"],
+                Platform::Windows | Platform::Unsupported => {
+                    anyhow::bail!("Blackhole routes are not supported on this platform")
+                }
+            };
+            self.exec_route(args, "delete blackhole route")?;
+        }
+        Ok(())
+    }
+
+    /// Drop DNS queries, UDP and TCP port 53, to any resolver but
+    /// `allowed` (Linux, macOS and Windows)
+    ///
+    /// Counterpart of blackhole routes for name lookups: nothing reaches the
+    /// resolver of the physical network while the tunnel is up. Linux and
+    /// macOS block IPv6 resolvers too, Windows only IPv4 ones. Rules of a
+    /// step that fails are removed again, nothing is left half installed.
+    pub fn block_dns(&self, allowed: &[Ipv4Addr]) -> anyhow::Result<DnsBlock> {
+        let mut block = DnsBlock {
+            allowed: allowed.to_vec(),
+            enabled_pf: false,
+        };
+        let installed = match self.platform {
+            Platform::Linux => self.block_dns_linux(allowed),
+            Platform::MacOs => self.block_dns_pf(&mut block),
+            Platform::Windows => self.block_dns_windows(allowed),
+            Platform::Unsupported => {
+                anyhow::bail!("Blocking DNS is not supported on this platform")
+            }
+        };
+        if let Err(e) = installed {
+            if let Err(cleanup) = self.unblock_dns(&block) {
+                tracing::debug!("DNS rules partly in place, removing them failed: {cleanup}");
+            }
+            return Err(e);
+        }
+        tracing::info!("Blocked DNS to resolvers other than {allowed:?}");
+        Ok(block)
+    }
+
+    /// Send DNS queries through a chain of their own, returning for allowed
+    /// resolvers and dropping the rest
+    ///
+    /// A chain left over by an unclean exit is reused, flushed first.
+    fn block_dns_linux(&self, allowed: &[Ipv4Addr]) -> anyhow::Result<()> {
+        for program in ["iptables", "ip6tables"] {
+            // fails if the chain exists already
+            let _ = self.exec(program, &["-N", DNS_CHAIN]);
+            self.exec_checked(program, &["-F", DNS_CHAIN], "block DNS")?;
+            if program == "iptables" {
+                for resolver in allowed {
+                    let resolver = resolver.to_string();
+                    self.exec_checked(
+                        program,
+                        &["-A", DNS_CHAIN, "-d", &resolver, "-j", "RETURN"],
+                        "block DNS",
+                    )?;
+                }
+            }
+            self.exec_checked(program, &["-A", DNS_CHAIN, "-j", "DROP"], "block DNS")?;
+            for proto in ["udp", "tcp"] {
+                let jump = ["OUTPUT", "-p", proto, "--dport", "53", "-j", DNS_CHAIN];
+                let exists = self
+                    .query(program, &[&["-C"], &jump[..]].concat())
+                    .is_ok_and(|output| output.status.success());
+                if !exists {
+                    self.exec_checked(program, &[&["-I"], &jump[..]].concat(), "block DNS")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the rules into their anchor and enable pf if it is not yet,
+    /// noting so in `block` to disable it again
+    fn block_dns_pf(&self, block: &mut DnsBlock) -> anyhow::Result<()> {
+        let script = format!(
+            "printf '%s\\n' {} | pfctl -a {PF_DNS_ANCHOR} -f -",
+            dns_rules_pf(&block.allowed)
+                .iter()
+                .map(|rule| format!("'{rule}'"))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+        self.exec_checked("sh", &["-c", &script], "block DNS")?;
+        let enabled = self.query("pfctl", &["-s", "info"]).is_ok_and(|output| {
+            String::from_utf8_lossy(&output.stdout).contains("Status: Enabled")
+        });
+        if !enabled {
+            self.exec_checked("pfctl", &["-e"], "enable pf")?;
+            block.enabled_pf = true;
+        }
+        Ok(())
+    }
+
+    fn block_dns_windows(&self, allowed: &[Ipv4Addr]) -> anyhow::Result<()> {
+        let name = format!("name={WINDOWS_DNS_RULE}");
+        let remoteip = format!("remoteip={}", ipv4_ranges_except(allowed));
+        for protocol in ["protocol=UDP", "protocol=TCP"] {
+            #[rustfmt::skip]
+            self.exec_checked("netsh", &[
+                "advfirewall", "firewall", "add", "rule", &name, "dir=out", "action=block",
+                protocol, "remoteport=53", &remoteip,
+            ], "block DNS")?;
+        }
+        Ok(())
+    }
+
+    /// Remove the rules of `block_dns`, restoring pf to how it was
+    ///
+    /// Every rule is attempted, the first failure is returned.
+    pub fn unblock_dns(&self, block: &DnsBlock) -> anyhow::Result<()> {
+        let mut results = Vec::new();
+        match self.platform {
+            Platform::Linux => {
+                for program in ["iptables", "ip6tables"] {
+                    for proto in ["udp", "tcp"] {
+                        #[rustfmt::skip]
+                        results.push(self.exec_checked(program, &[
+                            "-D", "OUTPUT", "-p", proto, "--dport", "53", "-j", DNS_CHAIN,
+                        ], "unblock DNS"));
+                    }
+                    results.push(self.exec_checked(program, &["-F", DNS_CHAIN], "unblock DNS"));
+                    results.push(self.exec_checked(program, &["-X", DNS_CHAIN], "unblock DNS"));
+                }
+            }
+            Platform::MacOs => {
+                results.push(self.exec_checked(
+                    "pfctl",
+                    &["-a", PF_DNS_ANCHOR, "-F", "rules"],
+                    "unblock DNS",
+                ));
+                if block.enabled_pf {
+                    results.push(self.exec_checked("pfctl", &["-d"], "disable pf"));
+                }
+            }
+            Platform::Windows => {
+                let name = format!("name={WINDOWS_DNS_RULE}");
+                results.push(self.exec_checked(
+                    "netsh",
+                    &["advfirewall", "firewall", "delete", "rule", &name],
+                    "unblock DNS",
+                ));
+            }
+            Platform::Unsupported => {
+                anyhow::bail!("Blocking DNS is not supported on this platform")
+            }
+        }
+        results.into_iter().collect::<anyhow::Result<Vec<()>>>()?;
+        tracing::info!("Unblocked DNS");
+        Ok(())
+    }
+
+    /// Run a command changing system state, failing with `what` unless it
+    /// succeeds
+    fn exec_checked(&self, program: &str, args: &[&str], what: &str) -> anyhow::Result<()> {
+        let output = self
+            .exec(program, args)
+            .map_err(|e| anyhow::anyhow!("Failed to execute {program} command: {e}"))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow::anyhow!("Failed to {what}: {stderr}"));
+        }
+        Ok(())
+    }
+
+    /// Run `ip` on Linux or `route` elsewhere, failing with `what` unless
+    /// it succeeds
+    fn exec_route(&self, args: &[&str], what: &str) -> anyhow::Result<()> {
+        let program = match self.platform {
+            Platform::Linux => "ip",
+            _ => "route",
+        };
+        let output = self
+            .exec(program, args)
+            .map_err(|e| anyhow::anyhow!("Failed to execute {program} command: {e}"))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow::anyhow!("Failed to {what}: {stderr}"));
+        }
+        Ok(())
+    }
+
     fn add_route(
+        &self,
+        dst: &str,
+        gateway: &str,
+        interface_idx: Option<i32>,
+    ) -> anyhow::Result<()> {
+        match self.platform {
+            Platform::Linux => self.add_route_linux(dst, gateway, interface_idx),
+            Platform::MacOs => self.add_route_macos(dst, gateway, interface_idx),
+            Platform::Windows => self.add_route_windows(dst, gateway, interface_idx),
+            Platform::Unsupported => Err(anyhow::anyhow!(
+                "Route management is not supported on this platform"
+            )),
+        }
+    }
+
+    fn del_route(
+        &self,
+        dst: &str,
+        gateway: &str,
+        interface_idx: Option<i32>,
+    ) -> anyhow::Result<()> {
+        match self.platform {
+            Platform::Linux => self.del_route_linux(dst, gateway, interface_idx),
+            Platform::MacOs => self.del_route_macos(dst, gateway, interface_idx),
+            Platform::Windows => self.del_route_windows(dst, gateway, interface_idx),
+            Platform::Unsupported => Err(anyhow::anyhow!(
+                "Route management is not supported on this platform"
+            )),
+        }
+    }
+
+    fn add_route_linux(
         &self,
         dst: &str,
         gateway: &str,
         _interface_idx: Option<i32>,
     ) -> anyhow::Result<()> {
-        let output = Command::new("ip")
-            .args(["route", "add", dst, "via", gateway])
-            .output()
+        let output = self
+            .exec("ip", &["route", "add", dst, "via", gateway])
             .map_err(|e| anyhow::anyhow!("Failed to execute ip command: {e}"))?;
 
         if !output.status.success() {
@@ -116,16 +606,14 @@ impl SysRoute {
         Ok(())
     }
 
-    #[cfg(target_os = "linux")]
-    fn del_route(
+    fn del_route_linux(
         &self,
         dst: &str,
         gateway: &str,
         _interface_idx: Option<i32>,
     ) -> anyhow::Result<()> {
-        let output = Command::new("ip")
-            .args(["route", "del", dst, "via", gateway])
-            .output()
+        let output = self
+            .exec("ip", &["route", "del", dst, "via", gateway])
             .map_err(|e| anyhow::anyhow!("Failed to execute ip command: {e}"))?;
 
         if !output.status.success() {
@@ -135,16 +623,14 @@ impl SysRoute {
         Ok(())
     }
 
-    #[cfg(target_os = "macos")]
-    fn add_route(
+    fn add_route_macos(
         &self,
         dst: &str,
         gateway: &str,
         _interface_idx: Option<i32>,
     ) -> anyhow::Result<()> {
-        let output = Command::new("route")
-            .args(["-n", "add", "-net", dst, gateway])
-            .output()
+        let output = self
+            .exec("route", &["-n", "add", "-net", dst, gateway])
             .map_err(|e| anyhow::anyhow!("Failed to execute route command: {e}"))?;
 
         if !output.status.success() {
@@ -154,16 +640,14 @@ impl SysRoute {
         Ok(())
     }
 
-    #[cfg(target_os = "macos")]
-    fn del_route(
+    fn del_route_macos(
         &self,
         dst: &str,
         gateway: &str,
         _interface_idx: Option<i32>,
     ) -> anyhow::Result<()> {
-        let output = Command::new("route")
-            .args(["-n", "delete", "-net", dst, gateway])
-            .output()
+        let output = self
+            .exec("route", &["-n", "delete", "-net", dst, gateway])
             .map_err(|e| anyhow::anyhow!("Failed to execute route command: {e}"))?;
 
         if !output.status.success() {
@@ -173,8 +657,7 @@ impl SysRoute {
         Ok(())
     }
 
-    #[cfg(target_os = "windows")]
-    fn add_route(
+    fn add_route_windows(
         &self,
         dst: &str,
         gateway: &str,
@@ -197,9 +680,8 @@ impl SysRoute {
         args.push("metric");
         args.push("1");
 
-        let output = Command::new("route")
-            .args(&args)
-            .output()
+        let output = self
+            .exec("route", &args)
             .map_err(|e| anyhow::anyhow!("Failed to execute route command: {e}"))?;
 
         if !output.status.success() {
@@ -221,8 +703,7 @@ impl SysRoute {
         Ok(())
     }
 
-    #[cfg(target_os = "windows")]
-    fn del_route(
+    fn del_route_windows(
         &self,
         dst: &str,
         _gateway: &str,
@@ -230,9 +711,8 @@ impl SysRoute {
     ) -> anyhow::Result<()> {
         let (network, mask) = self.parse_cidr(dst)?;
 
-        let output = Command::new("route")
-            .args(&["delete", &network, "mask", &mask])
-            .output()
+        let output = self
+            .exec("route", &["delete", &network, "mask", &mask])
             .map_err(|e| anyhow::anyhow!("Failed to execute route command: {e}"))?;
 
         if !output.status.success() {
@@ -247,7 +727,6 @@ impl SysRoute {
         Ok(())
     }
 
-    #[allow(unused)]
     fn parse_cidr(&self, cidr: &str) -> anyhow::Result<(String, String)> {
         let parts: Vec<&str> = cidr.split('/').collect();
         if parts.len() != 2 {
@@ -283,38 +762,26 @@ impl SysRoute {
         ))
     }
 
-    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
-    fn add_route(&self, _dst: &str, _gateway: &str) -> anyhow::Result<()> {
-        Err(anyhow::anyhow!(
-            "Route management is not supported on this platform"
-        ))
-    }
-
-    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
-    fn del_route(&self, _dst: &str, _gateway: &str) -> anyhow::Result<()> {
-        Err(anyhow::anyhow!(
-            "Route management is not supported on this platform"
-        ))
-    }
-
     /// Enable MASQUERADE (NAT) for VPN interface using source network address (Linux only)
     /// This allows VPN clients to access external networks through the VPN gateway
     /// Uses source network CIDR instead of interface name for better reliability
     #[cfg(target_os = "linux")]
     pub fn enable_masquerade_by_source(&self, source_cidr: &str) -> anyhow::Result<()> {
         // Check if rule already exists: iptables -t nat -C POSTROUTING -s <source_cidr> -j MASQUERADE
-        let check_output = Command::new("iptables")
-            .args([
-                "-t",
-                "nat",
-                "-C",
-                "POSTROUTING",
-                "-s",
-                source_cidr,
-                "-j",
-                "MASQUERADE",
-            ])
-            .output()
+        let check_output = self
+            .query(
+                "iptables",
+                &[
+                    "-t",
+                    "nat",
+                    "-C",
+                    "POSTROUTING",
+                    "-s",
+                    source_cidr,
+                    "-j",
+                    "MASQUERADE",
+                ],
+            )
             .map_err(|e| anyhow::anyhow!("Failed to execute iptables check command: {e}"))?;
 
         if check_output.status.success() {
@@ -324,14 +791,12 @@ impl SysRoute {
 
         #[rustfmt::skip]
         // Add iptables rule: iptables -t nat -A POSTROUTING -s <source_cidr> -j MASQUERADE
-        let output = Command::new("iptables")
-            .args([
+        let output = self.exec("iptables", &[
                 "-t", "nat",
                 "-A", "POSTROUTING",
                 "-s", source_cidr,
                 "-j", "MASQUERADE",
             ])
-            .output()
             .map_err(|e| anyhow::anyhow!("Failed to execute iptables command: {e}"))?;
 
         if !output.status.success() {
@@ -348,14 +813,12 @@ impl SysRoute {
     pub fn disable_masquerade_by_source(&self, source_cidr: &str) -> anyhow::Result<()> {
         #[rustfmt::skip]
         // Remove iptables rule: iptables -t nat -D POSTROUTING -s <source_cidr> -j MASQUERADE
-        let output = Command::new("iptables")
-            .args([
+        let output = self.exec("iptables", &[
                 "-t", "nat",
                 "-D", "POSTROUTING",
                 "-s", source_cidr,
                 "-j", "MASQUERADE",
             ])
-            .output()
             .map_err(|e| anyhow::anyhow!("Failed to execute iptables command: {e}"))?;
 
         if !output.status.success() {
@@ -388,20 +851,22 @@ impl SysRoute {
         virtual_ip: &str,
     ) -> anyhow::Result<()> {
         // Check if rule already exists: iptables -t nat -C POSTROUTING -s <local_cidr> -j SNAT --to-source <virtual_ip>
-        let check_output = Command::new("iptables")
-            .args([
-                "-t",
-                "nat",
-                "-C",
-                "POSTROUTING",
-                "-s",
-                local_cidr,
-                "-j",
-                "SNAT",
-                "--to-source",
-                virtual_ip,
-            ])
-            .output()
+        let check_output = self
+            .query(
+                "iptables",
+                &[
+                    "-t",
+                    "nat",
+                    "-C",
+                    "POSTROUTING",
+                    "-s",
+                    local_cidr,
+                    "-j",
+                    "SNAT",
+                    "--to-source",
+                    virtual_ip,
+                ],
+            )
             .map_err(|e| anyhow::anyhow!("Failed to execute iptables check command: {e}"))?;
 
         if check_output.status.success() {
@@ -414,20 +879,22 @@ impl SysRoute {
         }
 
         // Add iptables rule: iptables -t nat -A POSTROUTING -s <local_cidr> -j SNAT --to-source <virtual_ip>
-        let output = Command::new("iptables")
-            .args([
-                "-t",
-                "nat",
-                "-A",
-                "POSTROUTING",
-                "-s",
-                local_cidr,
-                "-j",
-                "SNAT",
-                "--to-source",
-                virtual_ip,
-            ])
-            .output()
+        let output = self
+            .exec(
+                "iptables",
+                &[
+                    "-t",
+                    "nat",
+                    "-A",
+                    "POSTROUTING",
+                    "-s",
+                    local_cidr,
+                    "-j",
+                    "SNAT",
+                    "--to-source",
+                    virtual_ip,
+                ],
+            )
             .map_err(|e| anyhow::anyhow!("Failed to execute iptables command: {e}"))?;
 
         if !output.status.success() {
@@ -448,15 +915,13 @@ impl SysRoute {
         virtual_ip: &str,
     ) -> anyhow::Result<()> {
         #[rustfmt::skip]
-        let output = Command::new("iptables")
-            .args([
+        let output = self.exec("iptables", &[
                 "-t",          "nat",
                 "-D",          "POSTROUTING",
                 "-s",          local_cidr,
                 "-j",          "SNAT",
                 "--to-source", virtual_ip,
             ])
-            .output()
             .map_err(|e| anyhow::anyhow!("Failed to execute iptables command: {e}"))?;
 
         if !output.status.success() {
@@ -503,15 +968,13 @@ impl SysRoute {
     pub fn enable_cidr_dnat(&self, mapped_cidr: &str, real_cidr: &str) -> anyhow::Result<()> {
         #[rustfmt::skip]
         // Check if NETMAP rule already exists: iptables -t nat -C PREROUTING -d <mapped_cidr> -j NETMAP --to <real_cidr>
-        let check_output = Command::new("iptables")
-            .args([
+        let check_output = self.query("iptables", &[
                 "-t",   "nat",
                 "-C",   "PREROUTING",
                 "-d",   mapped_cidr,
                 "-j",   "NETMAP",
                 "--to", real_cidr,
-            ])
-            .output();
+            ]);
 
         match check_output {
             Ok(output) if output.status.success() => {
@@ -523,15 +986,13 @@ impl SysRoute {
 
         #[rustfmt::skip]
         // Add NETMAP rule: iptables -t nat -A PREROUTING -d <mapped_cidr> -j NETMAP --to <real_cidr>
-        let output = Command::new("iptables")
-            .args([
+        let output = self.exec("iptables", &[
                 "-t",   "nat",
                 "-A",   "PREROUTING",
                 "-d",   mapped_cidr,
                 "-j",   "NETMAP",
                 "--to", real_cidr,
             ])
-            .output()
             .map_err(|e| {
                 if e.kind() == std::io::ErrorKind::NotFound {
                     anyhow::anyhow!("iptables command not found. CIDR mapping requires iptables with NETMAP support.\n\
@@ -569,15 +1030,13 @@ impl SysRoute {
     #[cfg(target_os = "linux")]
     pub fn disable_cidr_dnat(&self, mapped_cidr: &str, real_cidr: &str) -> anyhow::Result<()> {
         #[rustfmt::skip]
-        let output = Command::new("iptables")
-            .args([
+        let output = self.exec("iptables", &[
                 "-t",   "nat",
                 "-D",   "PREROUTING",
                 "-d",   mapped_cidr,
                 "-j",   "NETMAP",
                 "--to", real_cidr,
             ])
-            .output()
             .map_err(|e| anyhow::anyhow!("Failed to execute iptables command: {e}"))?;
 
         if !output.status.success() {
@@ -616,8 +1075,517 @@ impl SysRoute {
     }
 }
 
+impl RouteTable for SysRoute {
+    fn add(
+        &self,
+        dsts: Vec<String>,
+        gateway: String,
+        interface_idx: Option<i32>,
+    ) -> anyhow::Result<()> {
+        SysRoute::add(self, dsts, gateway, interface_idx)
+    }
+
+    fn del(
+        &self,
+        dsts: Vec<String>,
+        gateway: String,
+        interface_idx: Option<i32>,
+    ) -> anyhow::Result<()> {
+        SysRoute::del(self, dsts, gateway, interface_idx)
+    }
+
+    fn add_blackhole(&self, dsts: Vec<String>) -> anyhow::Result<()> {
+        SysRoute::add_blackhole(self, dsts)
+    }
+
+    fn del_blackhole(&self, dsts: Vec<String>) -> anyhow::Result<()> {
+        SysRoute::del_blackhole(self, dsts)
+    }
+}
+
 impl Default for SysRoute {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    fn exit_failure() -> ExitStatus {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            ExitStatus::from_raw(1 << 8)
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::ExitStatusExt;
+            ExitStatus::from_raw(1)
+        }
+    }
+
+    /// Records every command and answers with queued responses,
+    /// succeeding silently once the queue is empty
+    #[derive(Default)]
+    struct MockRunner {
+        calls: Mutex<Vec<String>>,
+        responses: Mutex<VecDeque<Output>>,
+    }
+
+    impl MockRunner {
+        /// Queue a failed exit with `stderr` as the next response
+        fn fail_next(&self, stderr: &str) {
+            self.responses.lock().unwrap().push_back(Output {
+                status: exit_failure(),
+                stdout: vec![],
+                stderr: stderr.as_bytes().to_vec(),
+            });
+        }
+
+        /// Queue a successful exit as the next response, for ahead of a
+        /// failure
+        fn succeed_next(&self) {
+            self.responses.lock().unwrap().push_back(Output {
+                status: ExitStatus::default(),
+                stdout: vec![],
+                stderr: vec![],
+            });
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl CommandRunner for MockRunner {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+            let command = std::iter::once(program)
+                .chain(args.iter().copied())
+                .collect::<Vec<_>>()
+                .join(" ");
+            self.calls.lock().unwrap().push(command);
+            Ok(self
+                .responses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or(Output {
+                    status: ExitStatus::default(),
+                    stdout: vec![],
+                    stderr: vec![],
+                }))
+        }
+    }
+
+    fn mock_route(platform: Platform) -> (Arc<MockRunner>, SysRoute) {
+        let runner = Arc::new(MockRunner::default());
+        let sys_route = SysRoute::new_with_runner(runner.clone()).with_platform(platform);
+        (runner, sys_route)
+    }
+
+    fn add_del(sys_route: &SysRoute, interface_idx: Option<i32>) {
+        sys_route
+            .add(
+                vec!["192.168.10.0/24".to_string()],
+                "10.0.0.2".to_string(),
+                interface_idx,
+            )
+            .unwrap();
+        sys_route
+            .del(
+                vec!["192.168.10.0/24".to_string()],
+                "10.0.0.2".to_string(),
+                interface_idx,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_linux_route_args() {
+        let (runner, sys_route) = mock_route(Platform::Linux);
+        add_del(&sys_route, Some(7));
+        assert_eq!(
+            runner.calls(),
+            vec![
+                "ip route add 192.168.10.0/24 via 10.0.0.2",
+                "ip route del 192.168.10.0/24 via 10.0.0.2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_blackhole_route_args() {
+        let dsts = || vec!["192.168.10.0/24".to_string()];
+        for (platform, expected) in [
+            (
+                Platform::Linux,
+                [
+                    "ip route add blackhole 192.168.10.0/24",
+                    "ip route del blackhole 192.168.10.0/24",
+                ],
+            ),
+            (
+                Platform::MacOs,
+                [
+                    "route -n add -net 192.168.10.0/24 127.0.0.1 -blackhole",
+                    "route -n delete -net 192.168.10.0/24 127.0.0.1",
+                ],
+            ),
+        ] {
+            let (runner, sys_route) = mock_route(platform);
+            sys_route.add_blackhole(dsts()).unwrap();
+            sys_route.del_blackhole(dsts()).unwrap();
+            assert_eq!(runner.calls(), expected);
+        }
+
+        let (runner, sys_route) = mock_route(Platform::Windows);
+        assert!(sys_route.add_blackhole(dsts()).is_err());
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn test_dns_block_rules_per_platform() {
+        let allowed: Vec<Ipv4Addr> = vec!["10.0.0.53".parse().unwrap(), "1.1.1.1".parse().unwrap()];
+        let (runner, sys_route) = mock_route(Platform::Linux);
+        let block = sys_route.block_dns(&allowed).unwrap();
+        sys_route.unblock_dns(&block).unwrap();
+        let jumps = |command: &str, program: &str| {
+            ["udp", "tcp"].map(|proto| {
+                format!("{program} {command} OUTPUT -p {proto} --dport 53 -j RUSTUN_DNS")
+            })
+        };
+        let mut expected = vec![
+            "iptables -N RUSTUN_DNS".to_string(),
+            "iptables -F RUSTUN_DNS".to_string(),
+            "iptables -A RUSTUN_DNS -d 10.0.0.53 -j RETURN".to_string(),
+            "iptables -A RUSTUN_DNS -d 1.1.1.1 -j RETURN".to_string(),
+            "iptables -A RUSTUN_DNS -j DROP".to_string(),
+        ];
+        // the jumps are in place already, the check succeeds
+        expected.extend(jumps("-C", "iptables"));
+        expected.extend([
+            "ip6tables -N RUSTUN_DNS".to_string(),
+            "ip6tables -F RUSTUN_DNS".to_string(),
+            "ip6tables -A RUSTUN_DNS -j DROP".to_string(),
+        ]);
+        expected.extend(jumps("-C", "ip6tables"));
+        for program in ["iptables", "ip6tables"] {
+            expected.extend(jumps("-D", program));
+            expected.push(format!("{program} -F RUSTUN_DNS"));
+            expected.push(format!("{program} -X RUSTUN_DNS"));
+        }
+        assert_eq!(runner.calls(), expected);
+
+        let (runner, sys_route) = mock_route(Platform::MacOs);
+        runner.succeed_next();
+        runner.responses.lock().unwrap().push_back(Output {
+            status: ExitStatus::default(),
+            stdout: b"Status: Enabled for 0 days 00:10:00".to_vec(),
+            stderr: vec![],
+        });
+        let block = sys_route.block_dns(&allowed).unwrap();
+        // pf was enabled already, it stays so
+        assert!(!block.enabled_pf);
+        sys_route.unblock_dns(&block).unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                "sh -c printf '%s\\n' 'pass out quick inet proto { udp tcp } to { 10.0.0.53 1.1.1.1 } port 53' \
+                 'block drop out quick inet proto { udp tcp } to any port 53' \
+                 'block drop out quick inet6 proto { udp tcp } to any port 53' \
+                 | pfctl -a com.apple/rustun.dns -f -",
+                "pfctl -s info",
+                "pfctl -a com.apple/rustun.dns -F rules",
+            ]
+        );
+
+        let (runner, sys_route) = mock_route(Platform::Windows);
+        let block = sys_route.block_dns(&allowed).unwrap();
+        sys_route.unblock_dns(&block).unwrap();
+        let remoteip = "remoteip=0.0.0.0-1.1.1.0,1.1.1.2-10.0.0.52,10.0.0.54-255.255.255.255";
+        assert_eq!(
+            runner.calls(),
+            vec![
+                format!(
+                    "netsh advfirewall firewall add rule name=rustun-dns-block dir=out \
+                     action=block protocol=UDP remoteport=53 {remoteip}"
+                ),
+                format!(
+                    "netsh advfirewall firewall add rule name=rustun-dns-block dir=out \
+                     action=block protocol=TCP remoteport=53 {remoteip}"
+                ),
+                "netsh advfirewall firewall delete rule name=rustun-dns-block".to_string(),
+            ]
+        );
+
+        let (runner, sys_route) = mock_route(Platform::Unsupported);
+        assert!(sys_route.block_dns(&allowed).is_err());
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn test_dns_block_inserts_missing_jumps() {
+        let allowed: Vec<Ipv4Addr> = vec!["10.0.0.53".parse().unwrap()];
+        let (runner, sys_route) = mock_route(Platform::Linux);
+        // -N, -F, -A RETURN, -A DROP, then the UDP jump is missing
+        for _ in 0..4 {
+            runner.succeed_next();
+        }
+        runner.fail_next("iptables: Bad rule");
+        sys_route.block_dns(&allowed).unwrap();
+        assert_eq!(
+            runner.calls()[4..7],
+            [
+                "iptables -C OUTPUT -p udp --dport 53 -j RUSTUN_DNS",
+                "iptables -I OUTPUT -p udp --dport 53 -j RUSTUN_DNS",
+                "iptables -C OUTPUT -p tcp --dport 53 -j RUSTUN_DNS",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dns_block_rolled_back_on_failure() {
+        let allowed: Vec<Ipv4Addr> = vec!["10.0.0.53".parse().unwrap()];
+        // the IPv4 rules are in, ip6tables fails
+        let (runner, sys_route) = mock_route(Platform::Linux);
+        for _ in 0..6 {
+            runner.succeed_next();
+        }
+        runner.fail_next("ip6tables: Permission denied");
+        runner.fail_next("ip6tables: Permission denied");
+        assert!(sys_route.block_dns(&allowed).is_err());
+        let calls = runner.calls();
+        assert_eq!(calls[7], "ip6tables -F RUSTUN_DNS");
+        let rollback = &calls[8..];
+        assert!(
+            rollback.contains(&"iptables -D OUTPUT -p udp --dport 53 -j RUSTUN_DNS".to_string())
+        );
+        assert!(rollback.contains(&"iptables -X RUSTUN_DNS".to_string()));
+        assert_eq!(rollback.len(), 8);
+
+        // the second Windows rule fails, the first is deleted again
+        let (runner, sys_route) = mock_route(Platform::Windows);
+        runner.succeed_next();
+        runner.fail_next("access denied");
+        assert!(sys_route.block_dns(&allowed).is_err());
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            "netsh advfirewall firewall delete rule name=rustun-dns-block"
+        );
+
+        // pf enabled for the rules is disabled again on unblock
+        let (runner, sys_route) = mock_route(Platform::MacOs);
+        runner.succeed_next();
+        runner.responses.lock().unwrap().push_back(Output {
+            status: ExitStatus::default(),
+            stdout: b"Status: Disabled for 0 days".to_vec(),
+            stderr: vec![],
+        });
+        let block = sys_route.block_dns(&allowed).unwrap();
+        assert!(block.enabled_pf);
+        sys_route.unblock_dns(&block).unwrap();
+        assert_eq!(
+            runner.calls()[2..],
+            [
+                "pfctl -e",
+                "pfctl -a com.apple/rustun.dns -F rules",
+                "pfctl -d"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ipv4_ranges_except_edges() {
+        let ip = |ip: &str| ip.parse::<Ipv4Addr>().unwrap();
+        assert_eq!(
+            ipv4_ranges_except(&[ip("0.0.0.0")]),
+            "0.0.0.1-255.255.255.255"
+        );
+        assert_eq!(
+            ipv4_ranges_except(&[ip("255.255.255.255")]),
+            "0.0.0.0-255.255.255.254"
+        );
+        assert_eq!(
+            ipv4_ranges_except(&[ip("10.0.0.2"), ip("10.0.0.1"), ip("10.0.0.2")]),
+            "0.0.0.0-10.0.0.0,10.0.0.3-255.255.255.255"
+        );
+        assert_eq!(ipv4_ranges_except(&[]), "0.0.0.0-255.255.255.255");
+    }
+
+    #[test]
+    fn test_macos_route_args() {
+        let (runner, sys_route) = mock_route(Platform::MacOs);
+        add_del(&sys_route, Some(7));
+        assert_eq!(
+            runner.calls(),
+            vec![
+                "route -n add -net 192.168.10.0/24 10.0.0.2",
+                "route -n delete -net 192.168.10.0/24 10.0.0.2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_windows_route_args() {
+        let (runner, sys_route) = mock_route(Platform::Windows);
+        add_del(&sys_route, Some(7));
+        add_del(&sys_route, None);
+        assert_eq!(
+            runner.calls(),
+            vec![
+                "route add 192.168.10.0 mask 255.255.255.0 10.0.0.2 if 7 metric 1",
+                "route delete 192.168.10.0 mask 255.255.255.0",
+                "route add 192.168.10.0 mask 255.255.255.0 10.0.0.2 metric 1",
+                "route delete 192.168.10.0 mask 255.255.255.0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_windows_tolerates_existing_and_missing_routes() {
+        let (runner, sys_route) = mock_route(Platform::Windows);
+        runner.fail_next("The route addition failed: The object already exists.");
+        runner.fail_next("The route deletion failed: Element not found.");
+        add_del(&sys_route, Some(7));
+
+        runner.fail_next("The route addition failed: access denied");
+        let err = sys_route
+            .add(
+                vec!["10.1.0.0/16".to_string()],
+                "10.0.0.2".to_string(),
+                None,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("access denied"));
+    }
+
+    #[test]
+    fn test_route_failure_reports_stderr() {
+        for platform in [Platform::Linux, Platform::MacOs] {
+            let (runner, sys_route) = mock_route(platform);
+            runner.fail_next("RTNETLINK answers: File exists");
+            let err = sys_route
+                .add(
+                    vec!["10.1.0.0/16".to_string()],
+                    "10.0.0.2".to_string(),
+                    None,
+                )
+                .unwrap_err();
+            assert!(err.to_string().contains("File exists"), "{platform:?}");
+        }
+
+        let (runner, sys_route) = mock_route(Platform::Unsupported);
+        assert!(
+            sys_route
+                .add(
+                    vec!["10.1.0.0/16".to_string()],
+                    "10.0.0.2".to_string(),
+                    None
+                )
+                .is_err()
+        );
+        assert!(runner.calls().is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dnat_rule_checks_and_tolerance() {
+        let (runner, sys_route) = mock_route(Platform::Linux);
+        // existing rule: the `-C` check succeeds, nothing is added
+        sys_route
+            .enable_cidr_dnat("192.168.11.0/24", "192.168.10.0/24")
+            .unwrap();
+        // already deleted rule is not an error
+        runner.fail_next("iptables: Bad rule (does a matching rule exist in that chain?). No rule");
+        sys_route
+            .disable_cidr_dnat("192.168.11.0/24", "192.168.10.0/24")
+            .unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                "iptables -t nat -C PREROUTING -d 192.168.11.0/24 -j NETMAP --to 192.168.10.0/24",
+                "iptables -t nat -D PREROUTING -d 192.168.11.0/24 -j NETMAP --to 192.168.10.0/24",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dry_run_records_without_running() {
+        let (runner, sys_route) = mock_route(Platform::Linux);
+        let sys_route = sys_route.with_dry_run(true);
+
+        sys_route
+            .add(
+                vec!["192.168.10.0/24".to_string(), "10.1.0.0/16".to_string()],
+                "10.0.0.2".to_string(),
+                Some(7),
+            )
+            .unwrap();
+        sys_route
+            .del(
+                vec!["192.168.10.0/24".to_string()],
+                "10.0.0.2".to_string(),
+                Some(7),
+            )
+            .unwrap();
+
+        assert!(runner.calls().is_empty());
+        assert_eq!(
+            sys_route.planned_commands(),
+            vec![
+                "ip route add 192.168.10.0/24 via 10.0.0.2",
+                "ip route add 10.1.0.0/16 via 10.0.0.2",
+                "ip route del 192.168.10.0/24 via 10.0.0.2",
+            ]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dry_run_still_runs_checks() {
+        let (runner, sys_route) = mock_route(Platform::Linux);
+        let sys_route = sys_route.with_dry_run(true);
+
+        // the failing `-C` lookup runs for real, the NETMAP insert is skipped
+        runner.fail_next("iptables: No chain/target/match by that name.");
+        sys_route
+            .enable_cidr_dnat("192.168.11.0/24", "192.168.10.0/24")
+            .unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec!["iptables -t nat -C PREROUTING -d 192.168.11.0/24 -j NETMAP --to 192.168.10.0/24"]
+        );
+        assert_eq!(
+            sys_route.planned_commands(),
+            vec!["iptables -t nat -A PREROUTING -d 192.168.11.0/24 -j NETMAP --to 192.168.10.0/24"]
+        );
+    }
+
+    #[test]
+    fn test_parse_gateway() {
+        assert_eq!(
+            parse_gateway(
+                "203.0.113.10 via 192.168.1.1 dev eth0 src 192.168.1.20 uid 0\n    cache",
+                "via"
+            )
+            .as_deref(),
+            Some("192.168.1.1")
+        );
+        // on-link destination
+        assert_eq!(
+            parse_gateway("192.168.1.7 dev eth0 src 192.168.1.20 uid 0", "via"),
+            None
+        );
+        assert_eq!(
+            parse_gateway(
+                "   route to: 203.0.113.10\ndestination: default\n    gateway: 192.168.1.1\n",
+                "gateway:"
+            )
+            .as_deref(),
+            Some("192.168.1.1")
+        );
+    }
+}
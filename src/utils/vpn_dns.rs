@@ -0,0 +1,326 @@
+//! Embedded DNS responder for peer names
+//!
+//! Queries read from the TUN device and sent to the configured resolver
+//! address are answered by the client itself: `<identity>.vpn` resolves to
+//! the peer's private IP. The names come from the peer list the server
+//! sends, so they follow joins, leaves and route reloads.
+
+use crate::codec::frame::{PeerDetail, ipv4_checksum, ipv4_packet};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, RwLock};
+
+/// Domain peer names are served under
+pub const VPN_DOMAIN: &str = "vpn";
+
+/// Seconds resolvers may cache an answer
+const ANSWER_TTL: u32 = 60;
+
+const IPV4_HDR_LEN: usize = 20;
+const UDP_HDR_LEN: usize = 8;
+const UDP_PROTO: u8 = 17;
+const DNS_PORT: u16 = 53;
+const DNS_HDR_LEN: usize = 12;
+
+const TYPE_A: u16 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_REFUSED: u8 = 5;
+
+/// Answers `<identity>.vpn` queries sent to `resolver`
+///
+/// Clones share the name table.
+#[derive(Debug, Clone)]
+pub struct VpnDns {
+    /// Address queries are intercepted for
+    resolver: Ipv4Addr,
+    /// Private IPv4 of each peer, by lowercase identity
+    names: Arc<RwLock<HashMap<String, Ipv4Addr>>>,
+}
+
+/// What a query name stands for
+enum Lookup {
+    /// A known peer, at this private IP
+    Answer(Ipv4Addr),
+    /// Under our domain but no such peer
+    NoRecord,
+    /// Not under our domain
+    NotOurs,
+}
+
+/// A parsed DNS query
+struct Query<'a> {
+    /// Whole DNS message
+    message: &'a [u8],
+    /// Query name, lowercase and without the trailing dot
+    name: String,
+    qtype: u16,
+    /// End of the question section in `message`
+    question_end: usize,
+}
+
+impl VpnDns {
+    pub fn new(resolver: Ipv4Addr) -> Self {
+        Self {
+            resolver,
+            names: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Address queries are intercepted for
+    pub fn resolver(&self) -> Ipv4Addr {
+        self.resolver
+    }
+
+    /// Replace the name table with the server's latest peer list
+    pub fn update(&self, peer_details: &[PeerDetail]) {
+        let names = peer_details
+            .iter()
+            .filter_map(|peer| {
+                let ip = peer.private_ip.parse().ok()?;
+                Some((peer.identity.to_lowercase(), ip))
+            })
+            .collect();
+        *self.names.write().unwrap_or_else(|e| e.into_inner()) = names;
+    }
+
+    /// Private IP of the peer `name` stands for, if it is one of ours
+    fn resolve(&self, name: &str) -> Lookup {
+        let Some(identity) = name
+            .strip_suffix(VPN_DOMAIN)
+            .and_then(|name| name.strip_suffix('.'))
+        else {
+            return Lookup::NotOurs;
+        };
+        let names = self.names.read().unwrap_or_else(|e| e.into_inner());
+        match names.get(identity) {
+            Some(ip) => Lookup::Answer(*ip),
+            None => Lookup::NoRecord,
+        }
+    }
+
+    /// Answer `packet` if it is a DNS query to the resolver address
+    ///
+    /// # Returns
+    /// - `Some(reply)` - IP packet to write back to the device
+    /// - `None` - Not a query for us, the packet goes on as usual
+    pub fn answer(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        if packet.len() < IPV4_HDR_LEN || packet[0] >> 4 != 4 || packet[9] != UDP_PROTO {
+            return None;
+        }
+        let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+        // fragments are left alone, queries fit in one packet
+        let fragmented = u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0;
+        if dst != self.resolver || fragmented {
+            return None;
+        }
+        let ihl = ((packet[0] & 0x0f) as usize) * 4;
+        let total_len = (u16::from_be_bytes([packet[2], packet[3]]) as usize).min(packet.len());
+        let udp = packet.get(ihl..total_len)?;
+        if udp.len() < UDP_HDR_LEN || u16::from_be_bytes([udp[2], udp[3]]) != DNS_PORT {
+            return None;
+        }
+        let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+        let src_port = u16::from_be_bytes([udp[0], udp[1]]);
+
+        let query = Query::parse(&udp[UDP_HDR_LEN..])?;
+        let dns = match self.resolve(&query.name) {
+            Lookup::Answer(ip) if query.qtype == TYPE_A => query.reply(0, Some(ip)),
+            // the name exists, just without records of that type
+            Lookup::Answer(_) => query.reply(0, None),
+            Lookup::NoRecord => query.reply(RCODE_NXDOMAIN, None),
+            Lookup::NotOurs => query.reply(RCODE_REFUSED, None),
+        };
+        tracing::debug!("answered DNS query for {} from {src}", query.name);
+        Some(udp_packet((self.resolver, DNS_PORT), (src, src_port), &dns))
+    }
+}
+
+impl<'a> Query<'a> {
+    /// Parse a standard query with a single question
+    fn parse(message: &'a [u8]) -> Option<Self> {
+        let header = message.get(..DNS_HDR_LEN)?;
+        // QR must be clear (a query) and the opcode QUERY
+        if header[2] & 0xf8 != 0 || u16::from_be_bytes([header[4], header[5]]) != 1 {
+            return None;
+        }
+        let mut labels = Vec::new();
+        let mut pos = DNS_HDR_LEN;
+        loop {
+            let len = *message.get(pos)? as usize;
+            pos += 1;
+            if len == 0 {
+                break;
+            }
+            // queries never compress their single name
+            if len & 0xc0 != 0 {
+                return None;
+            }
+            let label = message.get(pos..pos + len)?;
+            labels.push(String::from_utf8_lossy(label).to_lowercase());
+            pos += len;
+        }
+        let qtype = message.get(pos..pos + 4)?;
+        Some(Self {
+            message,
+            name: labels.join("."),
+            qtype: u16::from_be_bytes([qtype[0], qtype[1]]),
+            question_end: pos + 4,
+        })
+    }
+
+    /// Authoritative response with `rcode`, answering `ip` if given
+    fn reply(&self, rcode: u8, ip: Option<Ipv4Addr>) -> Vec<u8> {
+        let mut reply = Vec::with_capacity(self.question_end + 16);
+        reply.extend_from_slice(&self.message[..2]);
+        // QR and AA set, RD copied from the query
+        reply.push(0x84 | (self.message[2] & 0x01));
+        reply.push(rcode);
+        reply.extend_from_slice(&1u16.to_be_bytes());
+        reply.extend_from_slice(&(ip.is_some() as u16).to_be_bytes());
+        reply.extend_from_slice(&[0, 0, 0, 0]);
+        reply.extend_from_slice(&self.message[DNS_HDR_LEN..self.question_end]);
+        if let Some(ip) = ip {
+            // name is a pointer to the question's
+            reply.extend_from_slice(&[0xc0, DNS_HDR_LEN as u8]);
+            reply.extend_from_slice(&TYPE_A.to_be_bytes());
+            reply.extend_from_slice(&1u16.to_be_bytes());
+            reply.extend_from_slice(&ANSWER_TTL.to_be_bytes());
+            reply.extend_from_slice(&4u16.to_be_bytes());
+            reply.extend_from_slice(&ip.octets());
+        }
+        reply
+    }
+}
+
+/// IPv4 UDP packet carrying `payload` from `src` to `dst`
+fn udp_packet(src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16), payload: &[u8]) -> Vec<u8> {
+    let udp_len = UDP_HDR_LEN + payload.len();
+    let mut udp = vec![0u8; udp_len];
+    udp[0..2].copy_from_slice(&src.1.to_be_bytes());
+    udp[2..4].copy_from_slice(&dst.1.to_be_bytes());
+    udp[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    udp[UDP_HDR_LEN..].copy_from_slice(payload);
+
+    let mut pseudo = Vec::with_capacity(12 + udp_len);
+    pseudo.extend_from_slice(&src.0.octets());
+    pseudo.extend_from_slice(&dst.0.octets());
+    pseudo.extend_from_slice(&[0, UDP_PROTO]);
+    pseudo.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    pseudo.extend_from_slice(&udp);
+    // a zero UDP checksum means none was computed
+    let sum = match ipv4_checksum(&pseudo) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    udp[6..8].copy_from_slice(&sum.to_be_bytes());
+
+    ipv4_packet(src.0, dst.0, UDP_PROTO, &udp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESOLVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 53);
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    fn peer(identity: &str, private_ip: &str) -> PeerDetail {
+        PeerDetail {
+            name: identity.to_string(),
+            identity: identity.to_string(),
+            private_ip: private_ip.to_string(),
+            ciders: vec![],
+            ipv6: vec![],
+            port: 0,
+            stun_ip: String::new(),
+            stun_port: 0,
+            nat_type: 0,
+            last_active: 0,
+        }
+    }
+
+    fn dns() -> VpnDns {
+        let dns = VpnDns::new(RESOLVER);
+        dns.update(&[peer("peer-a", "10.0.0.2"), peer("Peer-B", "10.0.0.3")]);
+        dns
+    }
+
+    /// Query for `name` of `qtype` sent to `dst`
+    fn query(dst: Ipv4Addr, name: &str, qtype: u16) -> Vec<u8> {
+        let mut message = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            message.push(label.len() as u8);
+            message.extend_from_slice(label.as_bytes());
+        }
+        message.push(0);
+        message.extend_from_slice(&qtype.to_be_bytes());
+        message.extend_from_slice(&1u16.to_be_bytes());
+        udp_packet((CLIENT, 40000), (dst, DNS_PORT), &message)
+    }
+
+    /// DNS message of a reply, checking it goes back to the querier
+    fn dns_message(reply: &[u8]) -> &[u8] {
+        assert_eq!(ipv4_checksum(&reply[..IPV4_HDR_LEN]), 0);
+        assert_eq!(&reply[12..16], &RESOLVER.octets());
+        assert_eq!(&reply[16..20], &CLIENT.octets());
+        let udp = &reply[IPV4_HDR_LEN..];
+        assert_eq!(u16::from_be_bytes([udp[0], udp[1]]), DNS_PORT);
+        assert_eq!(u16::from_be_bytes([udp[2], udp[3]]), 40000);
+        &udp[UDP_HDR_LEN..]
+    }
+
+    #[test]
+    fn test_known_identity_resolves_to_private_ip() {
+        let reply = dns()
+            .answer(&query(RESOLVER, "peer-a.vpn", TYPE_A))
+            .unwrap();
+        let message = dns_message(&reply);
+        assert_eq!(&message[..2], &[0x12, 0x34]);
+        assert_eq!(message[2] & 0x80, 0x80);
+        assert_eq!(message[3] & 0x0f, 0);
+        assert_eq!(u16::from_be_bytes([message[6], message[7]]), 1);
+        let answer = &message[message.len() - 16..];
+        assert_eq!(&answer[2..4], &TYPE_A.to_be_bytes());
+        assert_eq!(&answer[10..12], &4u16.to_be_bytes());
+        assert_eq!(&answer[12..], &[10, 0, 0, 2]);
+
+        // names are case insensitive
+        let reply = dns()
+            .answer(&query(RESOLVER, "PEER-b.VPN", TYPE_A))
+            .unwrap();
+        assert!(dns_message(&reply).ends_with(&[10, 0, 0, 3]));
+    }
+
+    #[test]
+    fn test_unknown_names_and_types() {
+        let dns = dns();
+        let reply = dns.answer(&query(RESOLVER, "peer-z.vpn", TYPE_A)).unwrap();
+        assert_eq!(dns_message(&reply)[3] & 0x0f, RCODE_NXDOMAIN);
+
+        let reply = dns.answer(&query(RESOLVER, "example.com", TYPE_A)).unwrap();
+        assert_eq!(dns_message(&reply)[3] & 0x0f, RCODE_REFUSED);
+
+        // AAAA of a known peer: no error, no answer
+        let reply = dns.answer(&query(RESOLVER, "peer-a.vpn", 28)).unwrap();
+        let message = dns_message(&reply);
+        assert_eq!(message[3] & 0x0f, 0);
+        assert_eq!(u16::from_be_bytes([message[6], message[7]]), 0);
+
+        dns.update(&[]);
+        let reply = dns.answer(&query(RESOLVER, "peer-a.vpn", TYPE_A)).unwrap();
+        assert_eq!(dns_message(&reply)[3] & 0x0f, RCODE_NXDOMAIN);
+    }
+
+    #[test]
+    fn test_other_traffic_passes_through() {
+        let dns = dns();
+        assert!(
+            dns.answer(&query(Ipv4Addr::new(8, 8, 8, 8), "peer-a.vpn", TYPE_A))
+                .is_none()
+        );
+        let mut to_other_port = query(RESOLVER, "peer-a.vpn", TYPE_A);
+        to_other_port[IPV4_HDR_LEN + 3] = 54;
+        assert!(dns.answer(&to_other_port).is_none());
+        assert!(dns.answer(&[0x45; 10]).is_none());
+    }
+}
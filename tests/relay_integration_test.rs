@@ -0,0 +1,250 @@
+//! End-to-end relay tests: real `Server` and `RelayHandler` over loopback TCP
+
+use rustun::client::{RelayClientConfig, RelayHandler, RelayOutboundTx};
+use rustun::codec::frame::{DataFrame, Frame, HandshakeReplyFrame, ipv4_packet};
+use rustun::crypto::Block;
+use rustun::crypto::plain::PlainBlock;
+use rustun::crypto::rekey::RekeyPolicy;
+use rustun::network::connection_manager::ConnectionManager;
+use rustun::server::config::ServerConfig;
+use rustun::server::{ClientConfig, ClientManager, Server};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// A running test server, stopped with `shutdown`
+struct TestServer {
+    cancel: CancellationToken,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl TestServer {
+    /// Stop the server and wait for `run` to return
+    async fn shutdown(self) {
+        self.cancel.cancel();
+        self.task.await.unwrap().unwrap();
+    }
+}
+
+/// Frame magic of server and clients, not the default so a side left on it
+/// fails the tests
+const MAGIC: u32 = 0x1a2b3c4d;
+
+fn block() -> Arc<Box<dyn Block>> {
+    Arc::new(Box::new(PlainBlock::new()))
+}
+
+fn route(identity: &str, private_ip: &str, ciders: &[&str]) -> ClientConfig {
+    ClientConfig {
+        name: format!("{identity} host"),
+        cluster: "integration".to_string(),
+        identity: identity.to_string(),
+        private_ip: private_ip.to_string(),
+        mask: "255.255.255.0".to_string(),
+        gateway: "10.10.0.254".to_string(),
+        ciders: ciders.iter().map(|c| c.to_string()).collect(),
+        cider_mapping: Default::default(),
+    }
+}
+
+/// Start a `Server` for `routes` on `127.0.0.1:0`
+async fn spawn_test_server(routes: Vec<ClientConfig>) -> (SocketAddr, TestServer) {
+    let cfg = ServerConfig {
+        listen_addr: "127.0.0.1:0".to_string(),
+        listen_backlog: 16,
+        max_connections: None,
+        max_frame_size: 65543,
+        offline_buffer_size: 0,
+        offline_buffer_ttl: 5,
+        handshake_timeout: 5,
+        resume_ttl: 0,
+        global_cidrs: vec![],
+        peer_ttl: 0,
+        route_policy: Default::default(),
+        ip_validation: Default::default(),
+        allow_tap: false,
+        send_buffer_size: None,
+        recv_buffer_size: None,
+        http_port: None,
+        admin_token: None,
+        frame_magic: Default::default(),
+        access_log: None,
+    };
+    let client_manager = Arc::new(ClientManager::new());
+    client_manager.add_clients_config(routes);
+    let cancel = CancellationToken::new();
+    let mut server = Server::new(
+        cfg,
+        client_manager,
+        Arc::new(ConnectionManager::new()),
+        block(),
+    )
+    .with_magic(MAGIC)
+    .with_shutdown(cancel.clone());
+    let addr = server.bind().await.unwrap();
+    let task = tokio::spawn(async move { server.run().await });
+    (addr, TestServer { cancel, task })
+}
+
+/// Connect a relay client as `identity` and wait for the handshake reply
+async fn spawn_test_client(
+    addr: SocketAddr,
+    identity: &str,
+    rekey: RekeyPolicy,
+) -> (RelayHandler, HandshakeReplyFrame) {
+    let cfg = RelayClientConfig {
+        server_addr: addr.to_string(),
+        server_addrs: vec![],
+        keepalive_interval: Duration::from_secs(1),
+        outbound_buffer_size: 64,
+        inbound_buffer_size: 64,
+        keep_alive_thresh: 5,
+        identity: identity.to_string(),
+        token: None,
+        ipv6: None,
+        extra_ipv6: vec![],
+        refresh_ipv6: true,
+        port: 0,
+        stun: None,
+        stun_refresh: None,
+        tap: None,
+        timeouts: Default::default(),
+        socket_buffers: Default::default(),
+        cluster_block: None,
+        prefer_family: Default::default(),
+        bind_source: None,
+        mode: Default::default(),
+        pad: None,
+        reconnect_delay: Duration::from_millis(50),
+        max_handshake_failures: None,
+        rekey,
+        magic: MAGIC,
+    };
+    let mut handler = RelayHandler::new(block());
+    let (ready_tx, mut ready_rx) = mpsc::channel(1);
+    handler.run_client(cfg, ready_tx);
+    let reply = tokio::time::timeout(Duration::from_secs(5), ready_rx.recv())
+        .await
+        .expect("handshake should complete")
+        .expect("relay client stopped");
+    (handler, reply)
+}
+
+#[tokio::test]
+async fn test_data_routed_between_clients() {
+    let (addr, server) = spawn_test_server(vec![
+        route("alice", "10.10.0.1", &["192.168.1.0/24"]),
+        route("bob", "10.10.0.2", &[]),
+    ])
+    .await;
+
+    let (alice, alice_reply) = spawn_test_client(addr, "alice", RekeyPolicy::default()).await;
+    assert_eq!(alice_reply.name, "alice host");
+    assert_eq!(alice_reply.private_ip, "10.10.0.1");
+    assert_eq!(alice_reply.mask, "255.255.255.0");
+    assert_eq!(alice_reply.gateway, "10.10.0.254");
+    assert_eq!(alice_reply.peer_details.len(), 1);
+    assert_eq!(alice_reply.peer_details[0].identity, "bob");
+    assert_eq!(alice_reply.peer_details[0].private_ip, "10.10.0.2");
+
+    let (mut bob, bob_reply) = spawn_test_client(addr, "bob", RekeyPolicy::default()).await;
+    assert_eq!(bob_reply.private_ip, "10.10.0.2");
+    let peer = &bob_reply.peer_details[0];
+    assert_eq!(peer.identity, "alice");
+    assert_eq!(peer.ciders, vec!["192.168.1.0/24".to_string()]);
+
+    let packet = ipv4_packet([10, 10, 0, 1], [10, 10, 0, 2], 17, b"hello bob");
+    let outbound = alice.get_outbound_tx().unwrap();
+    assert_eq!(deliver_first(&outbound, &mut bob, &packet).await, packet);
+
+    server.shutdown().await;
+    assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+}
+
+#[tokio::test]
+async fn test_data_flows_across_rekeys() {
+    let (addr, server) = spawn_test_server(vec![
+        route("alice", "10.10.0.1", &[]),
+        route("bob", "10.10.0.2", &[]),
+    ])
+    .await;
+    let rekey = RekeyPolicy {
+        max_frames: Some(4),
+        max_age: None,
+    };
+    let (alice, _) = spawn_test_client(addr, "alice", rekey).await;
+    let (mut bob, _) = spawn_test_client(addr, "bob", rekey).await;
+
+    let outbound = alice.get_outbound_tx().unwrap();
+    let first = ipv4_packet([10, 10, 0, 1], [10, 10, 0, 2], 17, b"first");
+    deliver_first(&outbound, &mut bob, &first).await;
+
+    // a rekey that went wrong breaks the session and loses packets
+    let packets: Vec<_> = (0..12u8)
+        .map(|n| ipv4_packet([10, 10, 0, 1], [10, 10, 0, 2], 17, &[n]))
+        .collect();
+    for packet in &packets {
+        let frame = Frame::Data(DataFrame {
+            payload: packet.clone(),
+            seq: None,
+        });
+        RelayHandler::send_frame(&outbound, frame).unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let received = tokio::time::timeout(Duration::from_secs(5), async {
+        let mut received = Vec::new();
+        while received.len() < packets.len() {
+            if let Frame::Data(data) = bob.recv_frame().await.unwrap()
+                && data.payload != first
+            {
+                received.push(data.payload);
+            }
+        }
+        received
+    })
+    .await
+    .expect("every packet should reach bob");
+    assert_eq!(received, packets);
+
+    server.shutdown().await;
+}
+
+/// Send `packet` to `receiver` until it arrives
+///
+/// The receiver is routable once the server registered it, shortly after
+/// its handshake reply.
+async fn deliver_first(
+    outbound: &RelayOutboundTx,
+    receiver: &mut RelayHandler,
+    packet: &[u8],
+) -> Vec<u8> {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            RelayHandler::send_frame(
+                outbound,
+                Frame::Data(DataFrame {
+                    payload: packet.to_vec(),
+                    seq: None,
+                }),
+            )
+            .unwrap();
+            let wait = tokio::time::sleep(Duration::from_millis(100));
+            tokio::pin!(wait);
+            loop {
+                tokio::select! {
+                    frame = receiver.recv_frame() => {
+                        if let Frame::Data(data) = frame.unwrap() {
+                            return data.payload;
+                        }
+                    }
+                    _ = &mut wait => break,
+                }
+            }
+        }
+    })
+    .await
+    .expect("packet should reach the receiver")
+}